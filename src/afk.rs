@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::player_positions::{PlayerPosition, PlayerPositions};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::traits::t_server::TServer;
+use crate::types::InstanceUuid;
+
+/// Moving less than this many blocks between polls doesn't count as activity,
+/// so being pushed around by water or mobs is still AFK
+const MIN_MOVEMENT: f64 = 1.0;
+
+/// Activity of every online player, by instance and player name
+pub type PlayerActivity = HashMap<InstanceUuid, HashMap<String, ActivityStatus>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct ActivityStatus {
+    pub last_active: i64,
+    /// set while the player is AFK
+    pub afk_since: Option<i64>,
+    #[serde(skip)]
+    #[ts(skip)]
+    last_position: Option<PlayerPosition>,
+}
+
+impl ActivityStatus {
+    pub(crate) fn new(now: i64) -> Self {
+        Self {
+            last_active: now,
+            afk_since: None,
+            last_position: None,
+        }
+    }
+
+    pub fn is_afk(&self) -> bool {
+        self.afk_since.is_some()
+    }
+}
+
+/// Chat always counts as activity. Movement only does with position tracking enabled.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct AfkPolicy {
+    pub enabled: bool,
+    pub timeout_secs: u32,
+    /// told to the player when they are marked AFK
+    pub warn_message: Option<String>,
+    /// kick the longest AFK player when the server is full
+    pub kick_when_full: bool,
+    pub kick_message: String,
+}
+
+impl Default for AfkPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            timeout_secs: 300,
+            warn_message: Some("You are now AFK".to_string()),
+            kick_when_full: false,
+            kick_message: "Kicked for being AFK while the server is full".to_string(),
+        }
+    }
+}
+
+impl AfkPolicy {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_afk.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse AFK policy at {}", path.display()))?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_afk.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize AFK policy")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.timeout_secs < 30 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Timeout must be at least 30 seconds"),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn has_moved(from: &PlayerPosition, to: &PlayerPosition) -> bool {
+    from.dimension != to.dimension
+        || ((to.x - from.x).powi(2) + (to.y - from.y).powi(2) + (to.z - from.z).powi(2)).sqrt()
+            >= MIN_MOVEMENT
+}
+
+/// Updates activity from the latest positions and returns the players who just became AFK
+fn update_activity(
+    players: &mut HashMap<String, ActivityStatus>,
+    positions: Option<&HashMap<String, PlayerPosition>>,
+    timeout_secs: u32,
+    now: i64,
+) -> Vec<String> {
+    let mut newly_afk = Vec::new();
+    for (name, status) in players.iter_mut() {
+        if let Some(position) = positions.and_then(|positions| positions.get(name)) {
+            if status
+                .last_position
+                .as_ref()
+                .map_or(false, |last| has_moved(last, position))
+            {
+                status.last_active = now;
+                status.afk_since = None;
+            }
+            status.last_position = Some(position.clone());
+        }
+        if status.afk_since.is_none() && now - status.last_active >= timeout_secs as i64 {
+            status.afk_since = Some(now);
+            newly_afk.push(name.clone());
+        }
+    }
+    newly_afk
+}
+
+/// The player who has been AFK the longest
+pub fn longest_afk(players: &HashMap<String, ActivityStatus>) -> Option<String> {
+    players
+        .iter()
+        .filter_map(|(name, status)| status.afk_since.map(|since| (since, name)))
+        .min()
+        .map(|(_, name)| name.clone())
+}
+
+fn mark_active(player_activity: &mut PlayerActivity, uuid: &InstanceUuid, player: &str, now: i64) {
+    let status = player_activity
+        .entry(uuid.clone())
+        .or_default()
+        .entry(player.to_string())
+        .or_insert_with(|| ActivityStatus::new(now));
+    status.last_active = now;
+    status.afk_since = None;
+}
+
+async fn apply_policy(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    player_positions: &Mutex<PlayerPositions>,
+    player_activity: &Mutex<PlayerActivity>,
+    event_broadcaster: &EventBroadcaster,
+    uuid: &InstanceUuid,
+) -> Result<(), Error> {
+    let (path, name, player_count, max_players) = match instances.lock().await.get(uuid) {
+        Some(instance) => (
+            instance.path().await,
+            instance.name().await,
+            instance.get_player_count().await.unwrap_or(0),
+            instance.get_max_player_count().await.unwrap_or(u32::MAX),
+        ),
+        None => return Ok(()),
+    };
+    let policy = AfkPolicy::load(&path).await?;
+    if !policy.enabled {
+        return Ok(());
+    }
+    let now = chrono::Utc::now().timestamp();
+    let (newly_afk, to_kick) = {
+        let positions = player_positions.lock().await;
+        let mut player_activity = player_activity.lock().await;
+        let players = match player_activity.get_mut(uuid) {
+            Some(players) => players,
+            None => return Ok(()),
+        };
+        let newly_afk = update_activity(players, positions.get(uuid), policy.timeout_secs, now);
+        let to_kick = if policy.kick_when_full && player_count >= max_players {
+            longest_afk(players)
+        } else {
+            None
+        };
+        (newly_afk, to_kick)
+    };
+    let mut commands = Vec::new();
+    if let Some(warn_message) = &policy.warn_message {
+        for player in newly_afk.iter() {
+            if let Ok(command) = command_template::tellraw(player, warn_message) {
+                commands.push(command);
+            }
+        }
+    }
+    if let Some(player) = &to_kick {
+        commands.push(command_template::kick(player, &policy.kick_message));
+        event_broadcaster.send(Event::new_instance_warning(
+            uuid.clone(),
+            name,
+            format!("Kicked {} for being AFK while the server is full", player),
+        ));
+    }
+    if let Some(instance) = instances.lock().await.get(uuid) {
+        for command in commands {
+            instance.send_command(&command, CausedBy::System).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Tracks player activity of every instance and applies each instance's AFK policy
+pub async fn afk_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    player_positions: Arc<Mutex<PlayerPositions>>,
+    player_activity: Arc<Mutex<PlayerActivity>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        tokio::select! {
+            event = event_receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("AFK task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let (uuid, inner) = match event.event_inner {
+                    EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid,
+                        instance_event_inner,
+                        ..
+                    }) => (instance_uuid, instance_event_inner),
+                    _ => continue,
+                };
+                let now = chrono::Utc::now().timestamp();
+                let mut player_activity = player_activity.lock().await;
+                match inner {
+                    InstanceEventInner::PlayerMessage { player, .. } => {
+                        mark_active(&mut player_activity, &uuid, &player, now);
+                    }
+                    InstanceEventInner::PlayerChange {
+                        players_joined,
+                        players_left,
+                        ..
+                    } => {
+                        for player in players_joined {
+                            mark_active(&mut player_activity, &uuid, &player.get_name(), now);
+                        }
+                        if let Some(players) = player_activity.get_mut(&uuid) {
+                            for player in players_left {
+                                players.remove(&player.get_name());
+                            }
+                        }
+                    }
+                    InstanceEventInner::StateTransition { .. } => {
+                        player_activity.remove(&uuid);
+                    }
+                    _ => {}
+                }
+            }
+            _ = interval.tick() => {
+                let uuids: Vec<InstanceUuid> =
+                    player_activity.lock().await.keys().cloned().collect();
+                for uuid in uuids {
+                    if let Err(e) = apply_policy(
+                        &instances,
+                        &player_positions,
+                        &player_activity,
+                        &event_broadcaster,
+                        &uuid,
+                    )
+                    .await
+                    {
+                        error!("Failed to apply AFK policy of instance {}: {}", uuid, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_update_activity() {
+    let position = |x: f64| PlayerPosition {
+        x,
+        y: 64.0,
+        z: 0.0,
+        dimension: "minecraft:overworld".to_string(),
+        updated_at: 0,
+    };
+    let mut players = HashMap::new();
+    players.insert("Steve".to_string(), ActivityStatus::new(0));
+    players.insert("Alex".to_string(), ActivityStatus::new(0));
+    let mut positions = HashMap::new();
+    positions.insert("Steve".to_string(), position(0.0));
+    positions.insert("Alex".to_string(), position(0.0));
+    assert!(update_activity(&mut players, Some(&positions), 60, 30).is_empty());
+
+    // Steve walks away, Alex only drifts a bit
+    positions.insert("Steve".to_string(), position(10.0));
+    positions.insert("Alex".to_string(), position(0.5));
+    assert_eq!(
+        update_activity(&mut players, Some(&positions), 60, 60),
+        vec!["Alex".to_string()]
+    );
+    assert!(!players["Steve"].is_afk());
+    assert_eq!(players["Steve"].last_active, 60);
+    assert_eq!(longest_afk(&players), Some("Alex".to_string()));
+    // marked only once
+    assert!(update_activity(&mut players, Some(&positions), 60, 65).is_empty());
+}
@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use ringbuffer::RingBufferExt;
+use serde::{Deserialize, Serialize};
+use sysinfo::SystemExt;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::db::state::StateLocation;
+use crate::email::send_email;
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, EventInner, InstanceEvent, InstanceEventInner};
+use crate::implementations::minecraft::preflight::available_space;
+use crate::prelude::path_to_instances;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_macro::TMacro;
+use crate::traits::t_player::TPlayerManagement;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+use crate::AppState;
+
+/// how often the conditions that aren't events are checked
+const CHECK_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum AlertCondition {
+    InstanceCrashed,
+    /// memory used by the instance, as a share of the host's memory
+    MemoryAbove {
+        percent: f32,
+    },
+    PlayerCountAbove {
+        count: u32,
+    },
+    /// free space on the disk the instances are on, core wide
+    DiskFreeBelow {
+        bytes: u64,
+    },
+    /// entities in all worlds, needs a companion reporting
+    EntitiesAbove {
+        count: u64,
+    },
+    /// loaded chunks in all worlds, needs a companion reporting
+    LoadedChunksAbove {
+        count: u64,
+    },
+    /// entities in the densest chunk, needs a companion reporting hotspots
+    ChunkEntitiesAbove {
+        count: u32,
+    },
+}
+
+impl AlertCondition {
+    fn is_instance_condition(&self) -> bool {
+        !matches!(self, AlertCondition::DiskFreeBelow { .. })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum AlertAction {
+    /// the alert is posted as JSON
+    Webhook { url: String },
+    /// runs on the instance the alert is about
+    Macro {
+        macro_name: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// sent through the SMTP server in the global settings
+    Email { to: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct AlertRule {
+    pub id: String,
+    pub name: String,
+    pub enabled: bool,
+    /// every instance if `None`
+    pub instance_uuid: Option<InstanceUuid>,
+    pub condition: AlertCondition,
+    pub actions: Vec<AlertAction>,
+    /// the rule doesn't fire again for the same instance within this many seconds
+    pub cooldown_secs: u64,
+    pub created_by: String,
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NewAlertRule {
+    pub name: String,
+    pub enabled: bool,
+    pub instance_uuid: Option<InstanceUuid>,
+    pub condition: AlertCondition,
+    pub actions: Vec<AlertAction>,
+    pub cooldown_secs: u64,
+}
+
+impl NewAlertRule {
+    pub fn validate(&self) -> Result<(), Error> {
+        let bad_request = |message: &str| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(message.to_string()),
+        };
+        if self.name.trim().is_empty() || self.name.len() > 64 {
+            return Err(bad_request(
+                "Alert name must be between 1 and 64 characters",
+            ));
+        }
+        match self.condition {
+            AlertCondition::MemoryAbove { percent } if !(percent > 0.0 && percent < 100.0) => {
+                return Err(bad_request("Memory threshold must be between 0 and 100%"))
+            }
+            AlertCondition::DiskFreeBelow { bytes: 0 } => {
+                return Err(bad_request("Disk threshold must be above 0 bytes"))
+            }
+            _ => {}
+        }
+        if self.actions.is_empty() {
+            return Err(bad_request("An alert needs at least one action"));
+        }
+        for action in &self.actions {
+            match action {
+                AlertAction::Webhook { url } => {
+                    let url = url::Url::parse(url)
+                        .map_err(|_| bad_request("Webhook url is not a valid url"))?;
+                    if !matches!(url.scheme(), "http" | "https") {
+                        return Err(bad_request("Webhook url must be an http or https url"));
+                    }
+                }
+                AlertAction::Macro { macro_name, .. } => {
+                    if macro_name.is_empty() {
+                        return Err(bad_request("Macro name is empty"));
+                    }
+                    if !self.condition.is_instance_condition() && self.instance_uuid.is_none() {
+                        return Err(bad_request(
+                            "A core wide alert can only run a macro of a chosen instance",
+                        ));
+                    }
+                }
+                AlertAction::Email { to } => {
+                    if to.parse::<lettre::message::Mailbox>().is_err() {
+                        return Err(bad_request("Email recipient is not a valid address"));
+                    }
+                }
+            }
+        }
+        if self.cooldown_secs > 7 * 24 * 60 * 60 {
+            return Err(bad_request("Cooldown can be at most a week"));
+        }
+        Ok(())
+    }
+}
+
+/// A fired alert, as posted to webhooks
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct Alert {
+    pub rule_id: String,
+    pub rule_name: String,
+    pub instance_uuid: Option<InstanceUuid>,
+    pub instance_name: Option<String>,
+    pub message: String,
+    pub time: i64,
+}
+
+/// What a rule is about, an instance or the whole core
+type Subject = (String, Option<InstanceUuid>);
+
+/// Keeps alerts from firing on every check while their condition holds
+#[derive(Debug, Default)]
+pub struct AlertTracker {
+    /// subjects whose condition held on the last check
+    active: HashMap<Subject, bool>,
+    last_fired: HashMap<Subject, i64>,
+}
+
+impl AlertTracker {
+    /// Whether a rule should fire now that its condition is `met`. Fires when the
+    /// condition starts to hold, unless it fired within the cooldown.
+    pub fn check(
+        &mut self,
+        rule: &AlertRule,
+        instance_uuid: Option<&InstanceUuid>,
+        met: bool,
+        now: i64,
+    ) -> bool {
+        let subject = (rule.id.clone(), instance_uuid.cloned());
+        let was_met = self.active.insert(subject.clone(), met).unwrap_or(false);
+        met && !was_met && self.fire(subject, rule.cooldown_secs, now)
+    }
+
+    /// Whether a rule on an event should fire, only the cooldown applies
+    pub fn check_event(
+        &mut self,
+        rule: &AlertRule,
+        instance_uuid: Option<&InstanceUuid>,
+        now: i64,
+    ) -> bool {
+        self.fire(
+            (rule.id.clone(), instance_uuid.cloned()),
+            rule.cooldown_secs,
+            now,
+        )
+    }
+
+    fn fire(&mut self, subject: Subject, cooldown_secs: u64, now: i64) -> bool {
+        if let Some(last_fired) = self.last_fired.get(&subject) {
+            if now - last_fired < cooldown_secs as i64 {
+                return false;
+            }
+        }
+        self.last_fired.insert(subject, now);
+        true
+    }
+}
+
+pub struct AlertsManager {
+    location: StateLocation,
+    rules: HashMap<String, AlertRule>,
+}
+
+impl AlertsManager {
+    pub fn new(location: impl Into<StateLocation>) -> Self {
+        Self {
+            location: location.into(),
+            rules: HashMap::new(),
+        }
+    }
+
+    pub async fn load_from_file(&mut self) -> Result<(), Error> {
+        self.rules = self.location.read().await?.unwrap_or_default();
+        Ok(())
+    }
+
+    async fn write_to_file(&self) -> Result<(), Error> {
+        self.location.write(&self.rules).await
+    }
+
+    pub fn list(&self) -> Vec<AlertRule> {
+        let mut rules: Vec<AlertRule> = self.rules.values().cloned().collect();
+        rules.sort_by(|a, b| a.name.cmp(&b.name));
+        rules
+    }
+
+    pub fn get(&self, id: &str) -> Result<AlertRule, Error> {
+        self.rules.get(id).cloned().ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Alert not found"),
+        })
+    }
+
+    /// Adds the rule, or replaces the one with the same id
+    pub async fn set_rule(&mut self, rule: AlertRule) -> Result<AlertRule, Error> {
+        let old = self.rules.insert(rule.id.clone(), rule.clone());
+        if let Err(e) = self.write_to_file().await {
+            match old {
+                Some(old) => self.rules.insert(rule.id.clone(), old),
+                None => self.rules.remove(&rule.id),
+            };
+            return Err(e);
+        }
+        Ok(rule)
+    }
+
+    pub async fn remove_rule(&mut self, id: &str) -> Result<AlertRule, Error> {
+        let rule = self.rules.remove(id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Alert not found"),
+        })?;
+        if let Err(e) = self.write_to_file().await {
+            self.rules.insert(rule.id.clone(), rule);
+            return Err(e);
+        }
+        Ok(rule)
+    }
+}
+
+async fn run_action(state: &AppState, alert: &Alert, action: &AlertAction) -> Result<(), Error> {
+    match action {
+        AlertAction::Webhook { url } => {
+            reqwest::Client::new()
+                .post(url)
+                .timeout(Duration::from_secs(10))
+                .json(alert)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .context("Failed to post alert to webhook")?;
+        }
+        AlertAction::Macro { macro_name, args } => {
+            let uuid = alert.instance_uuid.as_ref().ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The alert isn't about an instance"),
+            })?;
+            let mut instance =
+                state
+                    .instances
+                    .lock()
+                    .await
+                    .get(uuid)
+                    .cloned()
+                    .ok_or_else(|| Error {
+                        kind: ErrorKind::NotFound,
+                        source: eyre!("Instance not found"),
+                    })?;
+            instance
+                .run_macro(macro_name, args.clone(), CausedBy::System)
+                .await?;
+        }
+        AlertAction::Email { to } => {
+            let settings = state.global_settings.lock().await.email();
+            send_email(
+                &settings,
+                to,
+                &format!("[Lodestone] {}", alert.rule_name),
+                alert.message.clone(),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the actions of `rule` in the background, failures are only logged
+fn fire(state: &AppState, rule: &AlertRule, alert: Alert) {
+    info!("Alert \"{}\" fired: {}", rule.name, alert.message);
+    let state = state.clone();
+    let actions = rule.actions.clone();
+    tokio::spawn(async move {
+        for action in &actions {
+            if let Err(e) = run_action(&state, &alert, action).await {
+                error!(
+                    "Failed to run action of alert \"{}\": {}",
+                    alert.rule_name, e
+                );
+            }
+        }
+    });
+}
+
+/// A server that stops without being asked to has crashed. Stops go through
+/// `Stopping` first.
+pub(crate) fn is_crash(previous: Option<State>, to: State) -> bool {
+    to == State::Error
+        || (to == State::Stopped && matches!(previous, Some(State::Running | State::Starting)))
+}
+
+fn applies_to(rule: &AlertRule, uuid: &InstanceUuid) -> bool {
+    rule.enabled
+        && rule.condition.is_instance_condition()
+        && rule
+            .instance_uuid
+            .as_ref()
+            .map_or(true, |rule_uuid| rule_uuid == uuid)
+}
+
+async fn check_rules(state: &AppState, tracker: &mut AlertTracker) {
+    let rules = state.alerts.lock().await.list();
+    if rules.iter().all(|rule| !rule.enabled) {
+        return;
+    }
+    let now = chrono::Utc::now().timestamp();
+    let (total_memory, disk_free) = {
+        let mut system = state.system.lock().await;
+        system.refresh_disks_list();
+        (
+            system.total_memory(),
+            available_space(&system, path_to_instances()),
+        )
+    };
+    for rule in rules.iter().filter(|rule| rule.enabled) {
+        if let AlertCondition::DiskFreeBelow { bytes } = rule.condition {
+            let disk_free = match disk_free {
+                Some(disk_free) => disk_free,
+                None => continue,
+            };
+            if tracker.check(rule, None, disk_free < bytes, now) {
+                fire(
+                    state,
+                    rule,
+                    Alert {
+                        rule_id: rule.id.clone(),
+                        rule_name: rule.name.clone(),
+                        instance_uuid: rule.instance_uuid.clone(),
+                        instance_name: None,
+                        message: format!("Only {} MB of disk space left", disk_free / 1024 / 1024),
+                        time: now,
+                    },
+                );
+            }
+        }
+    }
+    let instances = state.instances.lock().await.clone();
+    for (uuid, instance) in instances.iter() {
+        let rules: Vec<&AlertRule> = rules.iter().filter(|rule| applies_to(rule, uuid)).collect();
+        if rules.is_empty() {
+            continue;
+        }
+        let running = instance.state().await == State::Running;
+        let memory_usage = state
+            .monitor_buffer
+            .lock()
+            .await
+            .get(uuid)
+            .and_then(|buffer| buffer.iter().last().cloned())
+            .and_then(|report| report.memory_usage);
+        let player_count = if running {
+            instance.get_player_count().await.ok()
+        } else {
+            None
+        };
+        let diagnostics = state
+            .companion_reports
+            .lock()
+            .await
+            .latest(uuid, now)
+            .map(|telemetry| telemetry.entity_diagnostics(1));
+        for rule in rules {
+            let message = match rule.condition {
+                AlertCondition::MemoryAbove { percent } => {
+                    let used = memory_usage
+                        .filter(|_| running && total_memory > 0)
+                        .map(|bytes| bytes as f64 / total_memory as f64 * 100.0);
+                    match used {
+                        Some(used) if used > percent as f64 => {
+                            Some(format!("Using {:.1}% of the host's memory", used))
+                        }
+                        _ => None,
+                    }
+                }
+                AlertCondition::PlayerCountAbove { count } => match player_count {
+                    Some(players) if players > count => Some(format!("{} players online", players)),
+                    _ => None,
+                },
+                AlertCondition::EntitiesAbove { count } => match &diagnostics {
+                    Some(diagnostics) if diagnostics.entities > count => {
+                        Some(format!("{} entities loaded", diagnostics.entities))
+                    }
+                    _ => None,
+                },
+                AlertCondition::LoadedChunksAbove { count } => match &diagnostics {
+                    Some(diagnostics) if diagnostics.loaded_chunks > count => {
+                        Some(format!("{} chunks loaded", diagnostics.loaded_chunks))
+                    }
+                    _ => None,
+                },
+                AlertCondition::ChunkEntitiesAbove { count } => diagnostics
+                    .as_ref()
+                    .and_then(|diagnostics| diagnostics.hotspots.first())
+                    .filter(|hotspot| hotspot.hotspot.entities > count)
+                    .map(|hotspot| {
+                        format!(
+                            "{} entities in chunk {}, {} of {}",
+                            hotspot.hotspot.entities,
+                            hotspot.hotspot.x,
+                            hotspot.hotspot.z,
+                            hotspot.world
+                        )
+                    }),
+                _ => continue,
+            };
+            if tracker.check(rule, Some(uuid), message.is_some(), now) {
+                fire(
+                    state,
+                    rule,
+                    Alert {
+                        rule_id: rule.id.clone(),
+                        rule_name: rule.name.clone(),
+                        instance_uuid: Some(uuid.clone()),
+                        instance_name: Some(instance.name().await),
+                        message: message.unwrap_or_default(),
+                        time: now,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Evaluates the alert rules against the event stream, and the ones on usage
+/// every `CHECK_INTERVAL_SECS`
+pub async fn alerts_task(state: AppState) {
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut tracker = AlertTracker::default();
+    let mut last_states: HashMap<InstanceUuid, State> = HashMap::new();
+    let mut check_interval = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL_SECS));
+    loop {
+        tokio::select! {
+            _ = check_interval.tick() => {
+                check_rules(&state, &mut tracker).await;
+            }
+            event = event_receiver.recv() => {
+                let (uuid, instance_name, to) = match event {
+                    Ok(event) => match event.event_inner {
+                        EventInner::InstanceEvent(InstanceEvent {
+                            instance_uuid,
+                            instance_name,
+                            instance_event_inner: InstanceEventInner::StateTransition { to },
+                        }) => (instance_uuid, instance_name, to),
+                        _ => continue,
+                    },
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Alerts task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !is_crash(last_states.insert(uuid.clone(), to), to) {
+                    continue;
+                }
+                let now = chrono::Utc::now().timestamp();
+                for rule in state.alerts.lock().await.list() {
+                    if rule.condition == AlertCondition::InstanceCrashed
+                        && applies_to(&rule, &uuid)
+                        && tracker.check_event(&rule, Some(&uuid), now)
+                    {
+                        let alert = Alert {
+                            rule_id: rule.id.clone(),
+                            rule_name: rule.name.clone(),
+                            instance_uuid: Some(uuid.clone()),
+                            instance_name: Some(instance_name.clone()),
+                            message: format!("{} crashed", instance_name),
+                            time: now,
+                        };
+                        fire(&state, &rule, alert);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_alert_tracker() {
+    let rule = AlertRule {
+        id: "a".to_string(),
+        name: "Busy".to_string(),
+        enabled: true,
+        instance_uuid: None,
+        condition: AlertCondition::PlayerCountAbove { count: 10 },
+        actions: vec![AlertAction::Webhook {
+            url: "https://example.com/hook".to_string(),
+        }],
+        cooldown_secs: 100,
+        created_by: "owner".to_string(),
+    };
+    let uuid = InstanceUuid::from("i".to_string());
+    let mut tracker = AlertTracker::default();
+    assert!(!tracker.check(&rule, Some(&uuid), false, 0));
+    assert!(tracker.check(&rule, Some(&uuid), true, 10));
+    // still holds, doesn't fire again
+    assert!(!tracker.check(&rule, Some(&uuid), true, 20));
+    assert!(!tracker.check(&rule, Some(&uuid), false, 30));
+    // holds again, but within the cooldown
+    assert!(!tracker.check(&rule, Some(&uuid), true, 40));
+    assert!(!tracker.check(&rule, Some(&uuid), false, 150));
+    assert!(tracker.check(&rule, Some(&uuid), true, 160));
+
+    assert!(tracker.check_event(&rule, None, 0));
+    assert!(!tracker.check_event(&rule, None, 50));
+    assert!(is_crash(Some(State::Running), State::Stopped));
+    assert!(!is_crash(Some(State::Stopping), State::Stopped));
+
+    let new_rule = |condition, actions| NewAlertRule {
+        name: "Disk".to_string(),
+        enabled: true,
+        instance_uuid: None,
+        condition,
+        actions,
+        cooldown_secs: 0,
+    };
+    assert!(new_rule(
+        AlertCondition::DiskFreeBelow { bytes: 1 << 30 },
+        vec![AlertAction::Email {
+            to: "admin@example.com".to_string()
+        }]
+    )
+    .validate()
+    .is_ok());
+    assert!(new_rule(
+        AlertCondition::DiskFreeBelow { bytes: 1 << 30 },
+        vec![AlertAction::Macro {
+            macro_name: "cleanup".to_string(),
+            args: Vec::new()
+        }]
+    )
+    .validate()
+    .is_err());
+    assert!(new_rule(
+        AlertCondition::MemoryAbove { percent: 150.0 },
+        vec![AlertAction::Webhook {
+            url: "ftp://example.com".to_string()
+        }]
+    )
+    .validate()
+    .is_err());
+}
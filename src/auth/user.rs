@@ -1,14 +1,14 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::collections::HashMap;
 
 use argon2::{Argon2, PasswordVerifier};
-use color_eyre::eyre::{eyre, Context};
+use color_eyre::eyre::eyre;
 use jsonwebtoken::{Algorithm, Validation};
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
 use tracing::warn;
 use ts_rs::TS;
 
 use crate::{
+    db::state::StateLocation,
     error::{Error, ErrorKind},
     event_broadcaster::EventBroadcaster,
     events::{CausedBy, Event, EventInner, UserEvent, UserEventInner},
@@ -318,80 +318,55 @@ impl From<User> for PublicUser {
     }
 }
 
+/// Layout of the users file, the users are borrowed when writing it
+#[derive(Serialize, Deserialize)]
+struct UsersFile<T> {
+    #[serde(default)]
+    schema_version: u32,
+    users: T,
+}
+
 #[derive(Clone)]
 pub struct UsersManager {
     event_broadcaster: EventBroadcaster,
     users: HashMap<UserId, User>,
-    path_to_users: PathBuf,
+    location: StateLocation,
 }
 
 impl UsersManager {
     pub fn new(
         event_broadcaster: EventBroadcaster,
         users: HashMap<UserId, User>,
-        path_to_users: PathBuf,
+        location: impl Into<StateLocation>,
     ) -> Self {
         Self {
             event_broadcaster,
             users,
-            path_to_users,
+            location: location.into(),
         }
     }
     pub async fn load_users(&mut self) -> Result<(), Error> {
-        if tokio::fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .write(true)
-            .open(&self.path_to_users)
-            .await
-            .context(format!(
-                "Failed to open user file : {}",
-                &self.path_to_users.display()
-            ))?
-            .metadata()
-            .await
-            .context(format!(
-                "Failed to access metadata : {}",
-                &self.path_to_users.display()
-            ))?
-            .len()
-            == 0
+        match self
+            .location
+            .read::<UsersFile<HashMap<UserId, User>>>()
+            .await?
         {
-            warn!("No user file found, creating a new one");
-            self.users = HashMap::new();
-        } else {
-            let users: HashMap<UserId, User> = serde_json::from_reader(
-                tokio::fs::File::open(&self.path_to_users)
-                    .await
-                    .context(format!(
-                        "Failed to open user file : {}",
-                        &self.path_to_users.display()
-                    ))?
-                    .into_std()
-                    .await,
-            )
-            .context("Failed to deserialize user json")?;
-            self.users = users;
+            Some(users_file) => self.users = users_file.users,
+            None => {
+                warn!("No users found, starting without any");
+                self.users = HashMap::new();
+            }
         }
         Ok(())
     }
 
     async fn write_to_file(&self) -> Result<(), Error> {
-        let mut file = tokio::fs::File::create(&self.path_to_users)
+        self.location
+            .write(&UsersFile {
+                schema_version: crate::migration::schema::USERS.current_version(),
+                users: &self.users,
+            })
             .await
-            .context(format!(
-                "Failed to open/create json file {}",
-                &self.path_to_users.display()
-            ))?;
-
-        file.write_all(
-            serde_json::to_string(&self.users)
-                .context("Failed to deserialize user json")?
-                .as_bytes(),
-        )
-        .await
-        .context("Failed to write to user json".to_string())?;
-        Ok(())
     }
     pub fn get_user(&self, uid: impl AsRef<UserId>) -> Option<User> {
         self.users.get(uid.as_ref()).cloned()
@@ -652,6 +627,13 @@ impl UsersManager {
         Some(claimed_requester.to_owned())
     }
 
+    /// Like `try_auth`, also returning when the token expires as a unix timestamp
+    pub fn try_auth_with_expiry(&self, token: &str) -> Option<(User, i64)> {
+        let user = self.try_auth(token)?;
+        let claim = decode_claim_no_verify(token)?;
+        Some((user, claim.exp as i64))
+    }
+
     pub fn try_auth_or_err(&self, token: &str) -> Result<User, Error> {
         self.try_auth(token).ok_or_else(|| Error {
             kind: ErrorKind::Unauthorized,
@@ -693,6 +675,10 @@ fn decode_token(token: &str, jwt_secret: &UserSecret) -> Option<UserId> {
 }
 
 fn decode_no_verify(token: &str) -> Option<UserId> {
+    decode_claim_no_verify(token).map(|claim| claim.uid)
+}
+
+fn decode_claim_no_verify(token: &str) -> Option<Claim> {
     let mut no_verify = Validation::new(Algorithm::HS512);
     no_verify.insecure_disable_signature_validation();
     match jsonwebtoken::decode::<Claim>(
@@ -700,7 +686,7 @@ fn decode_no_verify(token: &str) -> Option<UserId> {
         &jsonwebtoken::DecodingKey::from_secret("noverify".as_bytes()),
         &no_verify,
     ) {
-        Ok(t) => Some(t.claims.uid),
+        Ok(t) => Some(t.claims),
         Err(_) => None,
     }
 }
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use color_eyre::eyre::{eyre, Context};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::Error;
+
+use super::crypto::{DecryptReader, EncryptWriter, MAGIC};
+
+/// Archives everything under `root` except the top level entries in `exclude`
+/// into a gzipped tarball at `dest`, returning its size. The archive is encrypted
+/// with a key derived from `passphrase` if one is given.
+pub fn create_archive(
+    root: &Path,
+    dest: &Path,
+    exclude: &[&str],
+    passphrase: Option<&str>,
+) -> Result<u64, Error> {
+    let file = File::create(dest).context(format!("Failed to create {}", dest.display()))?;
+    match passphrase {
+        Some(passphrase) => {
+            write_tar(root, exclude, None, EncryptWriter::new(file, passphrase)?)?
+                .finish()
+                .context("Failed to finish archive")?;
+        }
+        None => {
+            write_tar(root, exclude, None, file)?;
+        }
+    }
+    Ok(std::fs::metadata(dest)
+        .context(format!("Failed to read {}", dest.display()))?
+        .len())
+}
+
+/// Like [`create_archive`] without encryption, but only with the files and links
+/// modified at or after `since`. Directories are always included.
+pub fn create_archive_since(
+    root: &Path,
+    dest: &Path,
+    exclude: &[&str],
+    since: SystemTime,
+) -> Result<u64, Error> {
+    let file = File::create(dest).context(format!("Failed to create {}", dest.display()))?;
+    write_tar(root, exclude, Some(since), file)?;
+    Ok(std::fs::metadata(dest)
+        .context(format!("Failed to read {}", dest.display()))?
+        .len())
+}
+
+/// Files are read whole before being added, so one being written to by the server
+/// ends up in the archive as it was at that moment instead of corrupting it
+fn write_tar<W: Write>(
+    root: &Path,
+    exclude: &[&str],
+    since: Option<SystemTime>,
+    out: W,
+) -> Result<W, Error> {
+    let mut builder = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1 || !exclude.iter().any(|name| entry.file_name() == *name)
+        });
+    for entry in walker {
+        let entry = entry.context("Failed to walk instance directory")?;
+        let relative = entry
+            .path()
+            .strip_prefix(root)
+            .context("Entry outside of the instance directory")?;
+        let metadata = match entry.path().symlink_metadata() {
+            Ok(metadata) => metadata,
+            // removed since it was listed
+            Err(_) => continue,
+        };
+        if let Some(since) = since {
+            if !metadata.is_dir() && metadata.modified().map_or(false, |t| t < since) {
+                continue;
+            }
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        if metadata.is_dir() {
+            header.set_size(0);
+            builder
+                .append_data(&mut header, relative, std::io::empty())
+                .context(format!("Failed to archive {}", entry.path().display()))?;
+        } else if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(entry.path())
+                .context(format!("Failed to read link {}", entry.path().display()))?;
+            header.set_size(0);
+            builder
+                .append_link(&mut header, relative, target)
+                .context(format!("Failed to archive {}", entry.path().display()))?;
+        } else {
+            let data = match std::fs::read(entry.path()) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                result => result.context(format!("Failed to read {}", entry.path().display()))?,
+            };
+            header.set_size(data.len() as u64);
+            builder
+                .append_data(&mut header, relative, data.as_slice())
+                .context(format!("Failed to archive {}", entry.path().display()))?;
+        }
+    }
+    Ok(builder
+        .into_inner()
+        .context("Failed to finish archive")?
+        .finish()
+        .context("Failed to finish archive")?)
+}
+
+/// Opens an archive for reading, decrypting it if it was encrypted
+fn open_archive(archive: &Path, passphrase: Option<&str>) -> Result<Box<dyn Read>, Error> {
+    let mut file = File::open(archive).context(format!("Failed to open {}", archive.display()))?;
+    let mut magic = [0u8; 8];
+    let is_encrypted = match file.read_exact(&mut magic) {
+        Ok(_) => &magic == MAGIC,
+        Err(_) => false,
+    };
+    if is_encrypted {
+        let passphrase = passphrase.ok_or_else(|| eyre!("{} is encrypted", archive.display()))?;
+        return Ok(Box::new(GzDecoder::new(DecryptReader::new(
+            file, passphrase,
+        )?)));
+    }
+    file.seek(SeekFrom::Start(0))
+        .context(format!("Failed to read {}", archive.display()))?;
+    Ok(Box::new(GzDecoder::new(file)))
+}
+
+/// Unpacks an archive made by [`create_archive`] into `dest`
+pub fn extract_archive(archive: &Path, dest: &Path, passphrase: Option<&str>) -> Result<(), Error> {
+    let mut archive_reader = tar::Archive::new(open_archive(archive, passphrase)?);
+    archive_reader.set_preserve_permissions(true);
+    archive_reader
+        .unpack(dest)
+        .context(format!("Failed to extract {}", archive.display()))?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchivedFile {
+    pub size: u64,
+    pub modified: u64,
+}
+
+/// Regular files in an archive by path, read from the headers without extracting anything
+pub fn list_files(
+    archive: &Path,
+    passphrase: Option<&str>,
+) -> Result<HashMap<String, ArchivedFile>, Error> {
+    let mut archive_reader = tar::Archive::new(open_archive(archive, passphrase)?);
+    let mut ret = HashMap::new();
+    for entry in archive_reader
+        .entries()
+        .context(format!("Failed to read {}", archive.display()))?
+    {
+        let entry = entry.context(format!("Failed to read {}", archive.display()))?;
+        let header = entry.header();
+        if !header.entry_type().is_file() {
+            continue;
+        }
+        let path = entry
+            .path()
+            .context("Invalid path in archive")?
+            .to_string_lossy()
+            .replace('\\', "/");
+        ret.insert(
+            path,
+            ArchivedFile {
+                size: header.size().unwrap_or(0),
+                modified: header.mtime().unwrap_or(0),
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// How to restore a file that exists in the instance already
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum RestoreConflict {
+    Overwrite,
+    Skip,
+    /// restore next to the current file as `<name>.restored`
+    KeepBoth,
+}
+
+/// Whether `path` is one of `selected` or inside one of them
+pub(super) fn is_selected(path: &str, selected: &[String]) -> bool {
+    selected.iter().any(|s| {
+        path == s
+            || path
+                .strip_prefix(s.as_str())
+                .map_or(false, |rest| rest.starts_with('/'))
+    })
+}
+
+/// Extracts the files in or under the `selected` paths into `dest`, returning the
+/// paths restored and the ones skipped because of a conflict
+pub fn extract_selected(
+    archive: &Path,
+    dest: &Path,
+    selected: &[String],
+    conflict: RestoreConflict,
+    passphrase: Option<&str>,
+) -> Result<(Vec<String>, Vec<String>), Error> {
+    let mut archive_reader = tar::Archive::new(open_archive(archive, passphrase)?);
+    let mut restored = Vec::new();
+    let mut skipped = Vec::new();
+    for entry in archive_reader
+        .entries()
+        .context(format!("Failed to read {}", archive.display()))?
+    {
+        let mut entry = entry.context(format!("Failed to read {}", archive.display()))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .context("Invalid path in archive")?
+            .into_owned();
+        let path = relative.to_string_lossy().replace('\\', "/");
+        if !is_selected(&path, selected)
+            || !relative
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            continue;
+        }
+        let mut target = dest.join(&relative);
+        if target.exists() {
+            match conflict {
+                RestoreConflict::Overwrite => {}
+                RestoreConflict::Skip => {
+                    skipped.push(path);
+                    continue;
+                }
+                RestoreConflict::KeepBoth => {
+                    let mut name = target.file_name().unwrap_or_default().to_owned();
+                    name.push(".restored");
+                    target.set_file_name(name);
+                }
+            }
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        let mut out =
+            File::create(&target).context(format!("Failed to create {}", target.display()))?;
+        std::io::copy(&mut entry, &mut out)
+            .context(format!("Failed to restore {}", target.display()))?;
+        restored.push(path);
+    }
+    Ok((restored, skipped))
+}
+
+#[test]
+fn test_archive_round_trip() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(root.path().join("world/region")).unwrap();
+    std::fs::write(root.path().join("world/region/r.0.0.mca"), b"region").unwrap();
+    std::fs::write(root.path().join("server.properties"), b"motd=hi").unwrap();
+    std::fs::create_dir_all(root.path().join("backups")).unwrap();
+    std::fs::write(root.path().join("backups/old.tar.gz"), b"old").unwrap();
+
+    let out = tempfile::tempdir().unwrap();
+    let archive = out.path().join("backup.tar.gz");
+    assert!(create_archive(root.path(), &archive, &["backups"], None).unwrap() > 0);
+    let dest = out.path().join("restored");
+    extract_archive(&archive, &dest, None).unwrap();
+    assert_eq!(
+        std::fs::read(dest.join("world/region/r.0.0.mca")).unwrap(),
+        b"region"
+    );
+    assert!(dest.join("server.properties").is_file());
+    assert!(!dest.join("backups").exists());
+
+    let encrypted = out.path().join("encrypted.tar.gz");
+    create_archive(root.path(), &encrypted, &["backups"], Some("hunter22")).unwrap();
+    assert!(list_files(&encrypted, None).is_err());
+    assert_eq!(
+        list_files(&encrypted, Some("hunter22")).unwrap(),
+        list_files(&archive, None).unwrap()
+    );
+
+    let files = list_files(&archive, None).unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files["world/region/r.0.0.mca"].size, 6);
+
+    std::fs::write(root.path().join("server.properties"), b"motd=changed").unwrap();
+    std::fs::remove_file(root.path().join("world/region/r.0.0.mca")).unwrap();
+    let (restored, skipped) = extract_selected(
+        &archive,
+        root.path(),
+        &["world".to_string(), "server.properties".to_string()],
+        RestoreConflict::Skip,
+        None,
+    )
+    .unwrap();
+    assert_eq!(restored, vec!["world/region/r.0.0.mca".to_string()]);
+    assert_eq!(skipped, vec!["server.properties".to_string()]);
+    assert!(!is_selected(
+        "world_nether/level.dat",
+        &["world".to_string()]
+    ));
+}
@@ -0,0 +1,208 @@
+use std::io::{Read, Write};
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::aead::OsRng;
+use aes_gcm::{Aes256Gcm, KeyInit};
+use color_eyre::eyre::eyre;
+
+use crate::error::Error;
+
+/// Start of every encrypted archive, followed by the salt and the nonce prefix
+pub const MAGIC: &[u8; 8] = b"LSBKENC1";
+const SALT_LEN: usize = 16;
+/// 12 byte AES-GCM nonce minus the 4 byte counter and last chunk flag of STREAM
+const NONCE_LEN: usize = 7;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, Error> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| eyre!("Failed to derive backup key: {}", e))?;
+    Ok(Aes256Gcm::new(GenericArray::from_slice(&key)))
+}
+
+fn crypto_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Failed to decrypt backup, was the passphrase changed?",
+    )
+}
+
+/// Encrypts everything written to it in chunks, [`EncryptWriter::finish`] must be
+/// called to write the last chunk
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<Aes256Gcm>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(mut inner: W, passphrase: &str) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = derive_key(passphrase, &salt)?;
+        inner
+            .write_all(MAGIC)
+            .and_then(|_| inner.write_all(&salt))
+            .and_then(|_| inner.write_all(&nonce))
+            .map_err(|e| eyre!("Failed to write backup header: {}", e))?;
+        Ok(Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(
+                cipher,
+                GenericArray::from_slice(&nonce),
+            )),
+            buf: Vec::with_capacity(CHUNK_LEN * 2),
+        })
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let encryptor = self.encryptor.take().ok_or_else(crypto_error)?;
+        let chunk = encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(|_| crypto_error())?;
+        self.inner.write_all(&chunk)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        // a full chunk is only written once more data follows, so the last
+        // chunk is never empty unless the whole archive is
+        while self.buf.len() > CHUNK_LEN {
+            let encryptor = self.encryptor.as_mut().ok_or_else(crypto_error)?;
+            let chunk = encryptor
+                .encrypt_next(&self.buf[..CHUNK_LEN])
+                .map_err(|_| crypto_error())?;
+            self.inner.write_all(&chunk)?;
+            self.buf.drain(..CHUNK_LEN);
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads until `buf` is full or the end is reached, returning the bytes read
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Decrypts what an [`EncryptWriter`] wrote, the magic having been read already
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    /// the encrypted chunk after the one being read, to tell which is the last
+    next: Vec<u8>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, passphrase: &str) -> Result<Self, Error> {
+        let mut salt = [0u8; SALT_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        inner
+            .read_exact(&mut salt)
+            .and_then(|_| inner.read_exact(&mut nonce))
+            .map_err(|e| eyre!("Failed to read backup header: {}", e))?;
+        let cipher = derive_key(passphrase, &salt)?;
+        let mut next = vec![0u8; CHUNK_LEN + TAG_LEN];
+        let read =
+            read_up_to(&mut inner, &mut next).map_err(|e| eyre!("Failed to read backup: {}", e))?;
+        next.truncate(read);
+        Ok(Self {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(
+                cipher,
+                GenericArray::from_slice(&nonce),
+            )),
+            next,
+            plain: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Decrypts the next chunk into `plain`, leaving it empty at the end
+    fn fill(&mut self) -> std::io::Result<()> {
+        self.plain.clear();
+        self.pos = 0;
+        if self.decryptor.is_none() {
+            return Ok(());
+        }
+        let current = std::mem::take(&mut self.next);
+        let mut next = vec![0u8; CHUNK_LEN + TAG_LEN];
+        let read = read_up_to(&mut self.inner, &mut next)?;
+        next.truncate(read);
+        if next.is_empty() {
+            let decryptor = self.decryptor.take().ok_or_else(crypto_error)?;
+            self.plain = decryptor
+                .decrypt_last(current.as_slice())
+                .map_err(|_| crypto_error())?;
+        } else {
+            let decryptor = self.decryptor.as_mut().ok_or_else(crypto_error)?;
+            self.plain = decryptor
+                .decrypt_next(current.as_slice())
+                .map_err(|_| crypto_error())?;
+            self.next = next;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos >= self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[test]
+fn test_encrypt_round_trip() {
+    // spans several chunks and ends exactly on a chunk boundary
+    let data: Vec<u8> = (0..CHUNK_LEN * 3).map(|i| (i % 251) as u8).collect();
+    let mut writer = EncryptWriter::new(Vec::new(), "hunter2").unwrap();
+    writer.write_all(&data).unwrap();
+    let encrypted = writer.finish().unwrap();
+    assert_eq!(&encrypted[..MAGIC.len()], MAGIC);
+
+    let mut reader = DecryptReader::new(&encrypted[MAGIC.len()..], "hunter2").unwrap();
+    let mut decrypted = Vec::new();
+    reader.read_to_end(&mut decrypted).unwrap();
+    assert_eq!(decrypted, data);
+
+    let mut reader = DecryptReader::new(&encrypted[MAGIC.len()..], "wrong").unwrap();
+    assert!(reader.read_to_end(&mut Vec::new()).is_err());
+    // dropping the last chunk must not go unnoticed
+    let truncated = &encrypted[MAGIC.len()..encrypted.len() - 100];
+    let mut reader = DecryptReader::new(truncated, "hunter2").unwrap();
+    assert!(reader.read_to_end(&mut Vec::new()).is_err());
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+
+use crate::error::Error;
+
+use super::archive::{is_selected, ArchivedFile, RestoreConflict};
+
+/// Incremental backups are plain copies of the instance directory in here, named
+/// after the backup. Files unchanged since the previous one are hard links to its
+/// copy, so every backup is complete on its own and pruning one only frees the
+/// files no kept backup links to.
+pub fn path_to_trees(backups_dir: &Path) -> PathBuf {
+    backups_dir.join("incremental")
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TreeStats {
+    /// bytes copied because they weren't in the previous backup
+    pub stored: u64,
+    /// bytes linked from the previous backup
+    pub linked: u64,
+}
+
+fn walk<'a>(
+    root: &Path,
+    exclude: &'a [&'a str],
+) -> impl Iterator<Item = walkdir::Result<walkdir::DirEntry>> + 'a {
+    walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(move |entry| {
+            entry.depth() != 1 || !exclude.iter().any(|name| entry.file_name() == *name)
+        })
+}
+
+/// Compares two files of the same size byte by byte. Minecraft touches region
+/// files on save even when none of their chunks changed.
+fn same_content(a: &Path, b: &Path) -> Result<bool, Error> {
+    let mut a = BufReader::new(File::open(a).context(format!("Failed to open {}", a.display()))?);
+    let mut b = BufReader::new(File::open(b).context(format!("Failed to open {}", b.display()))?);
+    let mut buf_a = vec![0; 64 * 1024];
+    let mut buf_b = vec![0; 64 * 1024];
+    loop {
+        let read = a.read(&mut buf_a).context("Failed to read file")?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut buf_b[..read])
+            .context("Failed to read file")?;
+        if buf_a[..read] != buf_b[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Whether `file` can be linked from the previous backup's copy at `previous`
+fn unchanged(file: &Path, metadata: &std::fs::Metadata, previous: &Path) -> Result<bool, Error> {
+    let previous_metadata = match std::fs::symlink_metadata(previous) {
+        Ok(m) if m.is_file() => m,
+        _ => return Ok(false),
+    };
+    if previous_metadata.len() != metadata.len() {
+        return Ok(false);
+    }
+    if previous_metadata.modified().ok() == metadata.modified().ok() {
+        return Ok(true);
+    }
+    same_content(file, previous)
+}
+
+/// Copies everything under `root` except the top level entries in `exclude` into
+/// `dest`, hard linking the files that are the same in the `previous` tree
+pub fn create_tree(
+    root: &Path,
+    dest: &Path,
+    exclude: &[&str],
+    previous: Option<&Path>,
+) -> Result<TreeStats, Error> {
+    let mut stats = TreeStats::default();
+    std::fs::create_dir_all(dest).context(format!("Failed to create {}", dest.display()))?;
+    for entry in walk(root, exclude) {
+        let entry = entry.context(format!("Failed to read {}", root.display()))?;
+        let relative = entry
+            .path()
+            .strip_prefix(root)
+            .context("Entry outside of the instance")?;
+        let target = dest.join(relative);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)
+                .context(format!("Failed to create {}", target.display()))?;
+        } else if entry.file_type().is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(
+                std::fs::read_link(entry.path())
+                    .context(format!("Failed to read link {}", entry.path().display()))?,
+                &target,
+            )
+            .context(format!("Failed to create link {}", target.display()))?;
+        } else {
+            let metadata = entry
+                .metadata()
+                .context(format!("Failed to read {}", entry.path().display()))?;
+            if let Some(previous) = previous.map(|previous| previous.join(relative)) {
+                if unchanged(entry.path(), &metadata, &previous)? {
+                    std::fs::hard_link(&previous, &target)
+                        .context(format!("Failed to link {}", target.display()))?;
+                    stats.linked += metadata.len();
+                    continue;
+                }
+            }
+            // read whole first, like archives, so a file being written to is
+            // stored as it was at one moment
+            let data = std::fs::read(entry.path())
+                .context(format!("Failed to read {}", entry.path().display()))?;
+            std::fs::write(&target, &data)
+                .context(format!("Failed to write {}", target.display()))?;
+            // kept so the next backup can tell the file didn't change
+            if let Ok(modified) = metadata.modified() {
+                let _ = File::options()
+                    .write(true)
+                    .open(&target)
+                    .and_then(|f| f.set_modified(modified));
+            }
+            stats.stored += data.len() as u64;
+        }
+    }
+    Ok(stats)
+}
+
+/// Regular files of a tree by path
+pub fn list_files(tree: &Path) -> Result<HashMap<String, ArchivedFile>, Error> {
+    let mut ret = HashMap::new();
+    for entry in walk(tree, &[]) {
+        let entry = entry.context(format!("Failed to read {}", tree.display()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .context(format!("Failed to read {}", entry.path().display()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let path = entry
+            .path()
+            .strip_prefix(tree)
+            .context("Entry outside of the backup")?
+            .to_string_lossy()
+            .replace('\\', "/");
+        ret.insert(
+            path,
+            ArchivedFile {
+                size: metadata.len(),
+                modified,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// Copies the files in or under the `selected` paths of a tree into `dest`, never
+/// linking them since the server would then write into the backup. Returns the
+/// paths restored and the ones skipped because of a conflict.
+pub fn copy_selected(
+    tree: &Path,
+    dest: &Path,
+    selected: &[String],
+    conflict: RestoreConflict,
+) -> Result<(Vec<String>, Vec<String>), Error> {
+    let mut restored = Vec::new();
+    let mut skipped = Vec::new();
+    for entry in walk(tree, &[]) {
+        let entry = entry.context(format!("Failed to read {}", tree.display()))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(tree)
+            .context("Entry outside of the backup")?;
+        let path = relative.to_string_lossy().replace('\\', "/");
+        if !is_selected(&path, selected) {
+            continue;
+        }
+        let mut target = dest.join(relative);
+        if target.exists() {
+            match conflict {
+                RestoreConflict::Overwrite => {}
+                RestoreConflict::Skip => {
+                    skipped.push(path);
+                    continue;
+                }
+                RestoreConflict::KeepBoth => {
+                    let mut name = target.file_name().unwrap_or_default().to_owned();
+                    name.push(".restored");
+                    target.set_file_name(name);
+                }
+            }
+        }
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        std::fs::copy(entry.path(), &target)
+            .context(format!("Failed to restore {}", target.display()))?;
+        restored.push(path);
+    }
+    Ok((restored, skipped))
+}
+
+#[test]
+fn test_incremental_trees() {
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(root.path().join("world/region")).unwrap();
+    std::fs::write(root.path().join("world/region/r.0.0.mca"), b"region a").unwrap();
+    std::fs::write(root.path().join("world/region/r.0.1.mca"), b"region b").unwrap();
+    std::fs::create_dir_all(root.path().join("backups")).unwrap();
+
+    let trees = tempfile::tempdir().unwrap();
+    let first = trees.path().join("first");
+    let stats = create_tree(root.path(), &first, &["backups"], None).unwrap();
+    assert_eq!(
+        stats,
+        TreeStats {
+            stored: 16,
+            linked: 0
+        }
+    );
+    assert!(!first.join("backups").exists());
+
+    std::fs::write(root.path().join("world/region/r.0.1.mca"), b"region c").unwrap();
+    // timestamps can be too coarse to tell two quick writes apart
+    File::options()
+        .write(true)
+        .open(root.path().join("world/region/r.0.1.mca"))
+        .unwrap()
+        .set_modified(std::time::UNIX_EPOCH)
+        .unwrap();
+    std::fs::write(root.path().join("server.properties"), b"motd=hi").unwrap();
+    let second = trees.path().join("second");
+    let stats = create_tree(root.path(), &second, &["backups"], Some(&first)).unwrap();
+    assert_eq!(
+        stats,
+        TreeStats {
+            stored: 15,
+            linked: 8
+        }
+    );
+    assert_eq!(
+        std::fs::read(second.join("world/region/r.0.1.mca")).unwrap(),
+        b"region c"
+    );
+    assert_eq!(list_files(&second).unwrap().len(), 3);
+
+    // the first backup stays whole once its successor is gone
+    std::fs::remove_dir_all(&second).unwrap();
+    assert_eq!(
+        std::fs::read(first.join("world/region/r.0.0.mca")).unwrap(),
+        b"region a"
+    );
+
+    std::fs::remove_file(root.path().join("world/region/r.0.0.mca")).unwrap();
+    let (restored, skipped) = copy_selected(
+        &first,
+        root.path(),
+        &["world".to_string()],
+        RestoreConflict::Skip,
+    )
+    .unwrap();
+    assert_eq!(restored, vec!["world/region/r.0.0.mca".to_string()]);
+    assert_eq!(skipped, vec!["world/region/r.0.1.mca".to_string()]);
+}
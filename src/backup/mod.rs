@@ -0,0 +1,1596 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::db::state;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::external_db::{dump::dump_instance_databases, InstanceDatabases};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{InstanceOperation, State, TServer};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::util::rand_alphanumeric;
+
+use self::retention::BackupRetention;
+
+pub mod archive;
+pub mod crypto;
+pub mod incremental;
+pub mod pre_change;
+pub mod remote;
+pub mod retention;
+pub mod s3;
+pub mod scheduler;
+pub mod snapshot;
+
+/// Top level entries of the instance directory left out of backups
+pub(crate) const NOT_BACKED_UP: &[&str] = &["backups"];
+/// Top level entries of the instance directory a restore leaves in place
+const KEPT_ON_RESTORE: &[&str] = &["backups", ".lodestone_config"];
+
+lazy_static::lazy_static! {
+    /// Instances with a backup or restore in progress
+    static ref BUSY_INSTANCES: Mutex<HashSet<InstanceUuid>> = Mutex::new(HashSet::new());
+}
+
+pub fn path_to_backups(path_to_instance: &Path) -> PathBuf {
+    path_to_instance.join("backups")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct BackupEntry {
+    pub id: String,
+    /// archive in the instance's `backups` directory, directory under
+    /// `backups/incremental` for incremental backups, empty for snapshots
+    pub file_name: String,
+    pub created_at: i64,
+    /// 0 for snapshots, which share their data with the live files, only what
+    /// wasn't linked from the previous backup for incremental ones
+    pub size: u64,
+    pub label: Option<String>,
+    /// pinned backups are never pruned
+    pub pinned: bool,
+    /// name of the user who made the backup, `None` for automatic ones
+    pub created_by: Option<String>,
+    #[serde(default)]
+    pub encrypted: bool,
+    /// id of the key an encrypted backup was made with, `None` for backups made
+    /// before key ids were recorded
+    #[serde(default)]
+    pub key_id: Option<String>,
+    /// id of the remote a copy was uploaded to
+    #[serde(default)]
+    pub uploaded_to: Option<String>,
+    /// set if the backup is a filesystem snapshot rather than an archive
+    #[serde(default)]
+    pub snapshot: Option<snapshot::SnapshotRef>,
+    /// a copy of the instance directory sharing unchanged files with the
+    /// previous incremental backup through hard links
+    #[serde(default)]
+    pub incremental: bool,
+}
+
+impl BackupEntry {
+    /// e.g. `backup "before 1.21 upgrade" from 2024-06-13 18:02 UTC`
+    pub fn describe(&self) -> String {
+        let created_at = chrono::NaiveDateTime::from_timestamp_opt(self.created_at, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M UTC").to_string())
+            .unwrap_or_else(|| self.created_at.to_string());
+        match &self.label {
+            Some(label) => format!("backup \"{}\" from {}", label, created_at),
+            None => format!("backup from {}", created_at),
+        }
+    }
+}
+
+/// Label and pin of a backup, the only parts of it that can be edited
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct BackupMetadata {
+    pub label: Option<String>,
+    pub pinned: bool,
+}
+
+impl BackupMetadata {
+    pub fn validate(&mut self) -> Result<(), Error> {
+        if let Some(label) = &self.label {
+            let label = label.trim();
+            if label.chars().count() > 100 || label.chars().any(|c| c.is_control()) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Label must be a single line of at most 100 characters"),
+                });
+            }
+            self.label = if label.is_empty() {
+                None
+            } else {
+                Some(label.to_string())
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Encryption of new backups. Every passphrase backups were made with is kept in
+/// the state store, so older backups stay readable after the passphrase changes.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct BackupEncryption {
+    pub enabled: bool,
+    /// use the key generated by the core instead of `passphrase`
+    pub use_core_key: bool,
+    /// never written to the instance directory or sent to clients, sending it back
+    /// empty keeps the stored one
+    #[serde(default)]
+    pub passphrase: String,
+    /// id of the stored passphrase, set by the core
+    #[serde(default)]
+    pub key_id: Option<String>,
+}
+
+impl Default for BackupEncryption {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            use_core_key: true,
+            passphrase: String::new(),
+            key_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub enum BackupMode {
+    /// a compressed copy of the instance directory
+    #[default]
+    Archive,
+    /// an instant ZFS or btrfs snapshot, archives are made instead on other filesystems
+    Snapshot,
+    /// an uncompressed copy where only files changed since the previous backup take
+    /// up space, for worlds too large to archive whole every time. Older backups are
+    /// pruned like any other, deleting one only frees the files no newer backup
+    /// still shares.
+    Incremental,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct BackupPolicy {
+    /// number of unpinned backups kept, older ones are deleted after each new backup.
+    /// Ignored if `retention` is set.
+    pub keep: u32,
+    /// keeps backups spread over days, weeks and months instead of the newest `keep`,
+    /// applied after each new backup and by the scheduler when the policy changes
+    #[serde(default)]
+    pub retention: Option<BackupRetention>,
+    #[serde(default)]
+    pub encryption: BackupEncryption,
+    /// id of one of the core's backup remotes new backups are uploaded to
+    #[serde(default)]
+    pub remote: Option<String>,
+    #[serde(default)]
+    pub mode: BackupMode,
+    /// cron expression with a seconds field, e.g. `0 0 */6 * * *` for every six
+    /// hours, in UTC. No scheduled backups if `None`.
+    #[serde(default)]
+    pub schedule: Option<String>,
+}
+
+impl Default for BackupPolicy {
+    fn default() -> Self {
+        Self {
+            keep: 10,
+            retention: None,
+            encryption: BackupEncryption::default(),
+            remote: None,
+            mode: BackupMode::default(),
+            schedule: None,
+        }
+    }
+}
+
+impl BackupPolicy {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_backup_policy.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let mut policy: Self =
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse backup policy at {}", path.display()),
+            )?;
+        // written by an older core, which kept the passphrase in the file
+        if !policy.encryption.passphrase.is_empty() {
+            policy.save(path_to_instance).await?;
+            info!("Moved the backup passphrase out of {}", path.display());
+        }
+        Ok(policy)
+    }
+
+    /// A new passphrase is moved into the state store, only its id is written
+    pub async fn save(&mut self, path_to_instance: &Path) -> Result<(), Error> {
+        if !self.encryption.passphrase.is_empty() {
+            self.encryption.key_id = Some(store_key(&self.encryption.passphrase).await?);
+            self.encryption.passphrase.clear();
+        }
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_backup_policy.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize backup policy")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.keep == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("At least one backup must be kept"),
+            });
+        }
+        if let Some(retention) = &self.retention {
+            retention.validate()?;
+        }
+        if self.encryption.enabled && !self.encryption.use_core_key {
+            if self.encryption.passphrase.is_empty() && self.encryption.key_id.is_none() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("A backup passphrase is required"),
+                });
+            }
+            if !self.encryption.passphrase.is_empty()
+                && self.encryption.passphrase.chars().count() < 8
+            {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Backup passphrase must be at least 8 characters long"),
+                });
+            }
+        }
+        self.parsed_schedule()?;
+        if self.encryption.enabled && self.mode == BackupMode::Snapshot {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Snapshots can't be encrypted, use archives instead"),
+            });
+        }
+        if self.encryption.enabled && self.mode == BackupMode::Incremental {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Incremental backups can't be encrypted, use archives instead"),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn parsed_schedule(&self) -> Result<Option<cron::Schedule>, Error> {
+        self.schedule
+            .as_deref()
+            .map(|schedule| {
+                cron::Schedule::from_str(schedule).map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid backup schedule \"{}\": {}", schedule, e),
+                })
+            })
+            .transpose()
+    }
+
+    /// The policy with the passphrase left out, to be sent to clients
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        ret.encryption.passphrase.clear();
+        ret
+    }
+}
+
+/// Key shared by every instance that encrypts its backups with the core key,
+/// generated on first use. Losing it makes those backups unreadable.
+async fn core_key() -> Result<String, Error> {
+    let path = crate::prelude::path_to_stores().join("backup.key");
+    if path.is_file() {
+        return Ok(crate::util::fs::read_to_string(&path)
+            .await?
+            .trim()
+            .to_string());
+    }
+    let key = rand_alphanumeric(48);
+    crate::util::fs::write_all(&path, &key).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) =
+            tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await
+        {
+            warn!(
+                "Failed to restrict permissions of {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    Ok(key)
+}
+
+/// First 16 hex digits of the SHA-256 of a key, recorded on the backups made with it
+fn key_id(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Keeps a passphrase for as long as the core, returns its id
+async fn store_key(key: &str) -> Result<String, Error> {
+    let store = state::state_store();
+    let id = key_id(key);
+    let mut keys: HashMap<String, String> =
+        store.get(state::BACKUP_KEYS).await?.unwrap_or_default();
+    if !keys.contains_key(&id) {
+        keys.insert(id.clone(), key.to_string());
+        store.set(state::BACKUP_KEYS, &keys).await?;
+    }
+    Ok(id)
+}
+
+/// One of the stored passphrases or the core key
+async fn find_key(id: &str) -> Result<String, Error> {
+    let keys: HashMap<String, String> = state::state_store()
+        .get(state::BACKUP_KEYS)
+        .await?
+        .unwrap_or_default();
+    if let Some(key) = keys.get(id) {
+        return Ok(key.clone());
+    }
+    let core_key = core_key().await?;
+    if key_id(&core_key) == id {
+        return Ok(core_key);
+    }
+    Err(Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("The backup key {} is no longer known", id),
+    })
+}
+
+/// Id and passphrase new backups are encrypted with, `None` if encryption is off
+async fn encryption_passphrase(policy: &BackupPolicy) -> Result<Option<(String, String)>, Error> {
+    if !policy.encryption.enabled {
+        return Ok(None);
+    }
+    let key = if policy.encryption.use_core_key {
+        core_key().await?
+    } else {
+        match &policy.encryption.key_id {
+            Some(id) => find_key(id).await?,
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Backup encryption is on but no passphrase is set"),
+                })
+            }
+        }
+    };
+    Ok(Some((key_id(&key), key)))
+}
+
+/// Passphrase to read `entry` with, the one it was made with if it was recorded
+async fn archive_passphrase(
+    path_to_instance: &Path,
+    entry: &BackupEntry,
+) -> Result<Option<String>, Error> {
+    if !entry.encrypted {
+        return Ok(None);
+    }
+    if let Some(id) = &entry.key_id {
+        return find_key(id).await.map(Some);
+    }
+    // older backups can only be assumed to use the current settings
+    let encryption = BackupPolicy::load(path_to_instance).await?.encryption;
+    match encryption.key_id.filter(|_| !encryption.use_core_key) {
+        Some(id) => find_key(&id).await.map(Some),
+        None => core_key().await.map(Some),
+    }
+}
+
+/// Kept next to the archives rather than in the instance root, so restoring a
+/// backup doesn't bring back the list of backups as it was back then
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct BackupIndex {
+    pub backups: Vec<BackupEntry>,
+}
+
+impl BackupIndex {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_backups(path_to_instance).join("index.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse backup index at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        let dir = path_to_backups(path_to_instance);
+        crate::util::fs::create_dir_all(&dir).await?;
+        crate::util::fs::write_all(
+            dir.join("index.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize backup index")?,
+        )
+        .await
+    }
+
+    pub fn get(&self, id: &str) -> Result<&BackupEntry, Error> {
+        self.backups
+            .iter()
+            .find(|backup| backup.id == id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Backup not found"),
+            })
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Result<&mut BackupEntry, Error> {
+        self.backups
+            .iter_mut()
+            .find(|backup| backup.id == id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Backup not found"),
+            })
+    }
+
+    /// Removes the unpinned backups past the newest `keep` ones and returns them.
+    /// Pinned backups are neither pruned nor counted.
+    pub fn prune(&mut self, keep: usize) -> Vec<BackupEntry> {
+        let mut unpinned: Vec<(i64, String)> = self
+            .backups
+            .iter()
+            .filter(|backup| !backup.pinned)
+            .map(|backup| (backup.created_at, backup.id.clone()))
+            .collect();
+        unpinned.sort_by(|a, b| b.cmp(a));
+        let to_prune: Vec<String> = unpinned.into_iter().skip(keep).map(|(_, id)| id).collect();
+        let (pruned, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.backups)
+            .into_iter()
+            .partition(|backup| to_prune.contains(&backup.id));
+        self.backups = kept;
+        pruned
+    }
+
+    /// Removes and returns the backups `policy` no longer keeps
+    pub fn apply_policy(&mut self, policy: &BackupPolicy) -> Vec<BackupEntry> {
+        let retention = match &policy.retention {
+            Some(retention) => retention,
+            None => return self.prune(policy.keep as usize),
+        };
+        let kept_ids = retention.kept(&self.backups);
+        let (pruned, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.backups)
+            .into_iter()
+            .partition(|backup| !backup.pinned && !kept_ids.contains(&backup.id));
+        self.backups = kept;
+        pruned
+    }
+}
+
+/// Deletes the files of backups already removed from the index, sending an event
+/// for each
+async fn remove_pruned(
+    instance: &GameInstance,
+    path_to_instance: &Path,
+    pruned: Vec<BackupEntry>,
+    event_broadcaster: &EventBroadcaster,
+) {
+    let instance_uuid = instance.uuid().await;
+    let instance_name = instance.name().await;
+    for backup in pruned {
+        if let Err(e) = remove_backup_data(path_to_instance, &backup).await {
+            error!("Failed to delete pruned {}: {}", backup.describe(), e);
+        }
+        info!("[{}] Pruned {}", instance_name, backup.describe());
+        event_broadcaster.send(Event {
+            details: format!("Pruned {}", backup.describe()),
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid: instance_uuid.clone(),
+                instance_name: instance_name.clone(),
+                instance_event_inner: InstanceEventInner::BackupPruned { backup },
+            }),
+            snowflake: Snowflake::default(),
+            caused_by: CausedBy::System,
+        });
+    }
+}
+
+/// Deletes the backups the instance's policy no longer keeps, for when the policy
+/// changed since the last backup. Returns the deleted ones.
+pub async fn prune_backups(
+    instance: &GameInstance,
+    _guard: BackupGuard,
+    event_broadcaster: &EventBroadcaster,
+) -> Result<Vec<BackupEntry>, Error> {
+    let path_to_instance = instance.path().await;
+    let policy = BackupPolicy::load(&path_to_instance).await?;
+    let mut index = BackupIndex::load(&path_to_instance).await?;
+    let pruned = index.apply_policy(&policy);
+    if pruned.is_empty() {
+        return Ok(pruned);
+    }
+    index.save(&path_to_instance).await?;
+    remove_pruned(
+        instance,
+        &path_to_instance,
+        pruned.clone(),
+        event_broadcaster,
+    )
+    .await;
+    Ok(pruned)
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct FileChange {
+    /// relative to the instance root
+    pub path: String,
+    /// size in the older backup, `None` if the file was added
+    pub old_size: Option<u64>,
+    /// size in the newer backup, `None` if the file was removed
+    pub new_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct BackupDiff {
+    pub from: BackupEntry,
+    pub to: BackupEntry,
+    pub added: Vec<FileChange>,
+    pub removed: Vec<FileChange>,
+    /// files whose size or modification time differ
+    pub changed: Vec<FileChange>,
+}
+
+/// Added, removed and changed files going from listing `a` to listing `b`, sorted by path
+fn diff_files(
+    a: &HashMap<String, archive::ArchivedFile>,
+    b: &HashMap<String, archive::ArchivedFile>,
+) -> (Vec<FileChange>, Vec<FileChange>, Vec<FileChange>) {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for (path, new) in b.iter() {
+        match a.get(path) {
+            None => added.push(FileChange {
+                path: path.clone(),
+                old_size: None,
+                new_size: Some(new.size),
+            }),
+            Some(old) if old != new => changed.push(FileChange {
+                path: path.clone(),
+                old_size: Some(old.size),
+                new_size: Some(new.size),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (path, old) in a.iter() {
+        if !b.contains_key(path) {
+            removed.push(FileChange {
+                path: path.clone(),
+                old_size: Some(old.size),
+                new_size: None,
+            });
+        }
+    }
+    for list in [&mut added, &mut removed, &mut changed] {
+        list.sort_by(|x, y| x.path.cmp(&y.path));
+    }
+    (added, removed, changed)
+}
+
+/// Files of a backup by path, from the archive's headers or the snapshot or
+/// incremental backup's directory
+fn backup_files(
+    backups_dir: &Path,
+    entry: &BackupEntry,
+    passphrase: Option<&str>,
+) -> Result<HashMap<String, archive::ArchivedFile>, Error> {
+    match &entry.snapshot {
+        Some(snapshot) => snapshot::list_files(snapshot, NOT_BACKED_UP),
+        None if entry.incremental => {
+            incremental::list_files(&incremental::path_to_trees(backups_dir).join(&entry.file_name))
+        }
+        None => archive::list_files(&backups_dir.join(&entry.file_name), passphrase),
+    }
+}
+
+/// Compares the files of two backups, the older one is always taken as the base
+pub async fn diff_backups(
+    path_to_instance: &Path,
+    backup_a: &str,
+    backup_b: &str,
+) -> Result<BackupDiff, Error> {
+    let index = BackupIndex::load(path_to_instance).await?;
+    let (from, to) = {
+        let a = index.get(backup_a)?.clone();
+        let b = index.get(backup_b)?.clone();
+        if a.created_at <= b.created_at {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    };
+    let backups_dir = path_to_backups(path_to_instance);
+    let from_passphrase = archive_passphrase(path_to_instance, &from).await?;
+    let to_passphrase = archive_passphrase(path_to_instance, &to).await?;
+    let (from_entry, to_entry) = (from.clone(), to.clone());
+    let (added, removed, changed) = tokio::task::spawn_blocking(move || {
+        Ok::<_, Error>(diff_files(
+            &backup_files(&backups_dir, &from_entry, from_passphrase.as_deref())?,
+            &backup_files(&backups_dir, &to_entry, to_passphrase.as_deref())?,
+        ))
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+    Ok(BackupDiff {
+        from,
+        to,
+        added,
+        removed,
+        changed,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct BackupFileEntry {
+    pub name: String,
+    /// relative to the instance root
+    pub path: String,
+    pub is_dir: bool,
+    /// total size of the files under it for directories
+    pub size: u64,
+}
+
+/// Turns a path from a client into the form used in archives, `""` being the root
+fn normalize_path(path: &str) -> Result<String, Error> {
+    let path = path.replace('\\', "/");
+    let parts: Vec<&str> = path
+        .split('/')
+        .filter(|p| !p.is_empty() && *p != ".")
+        .collect();
+    if parts.iter().any(|p| *p == "..") {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Path must not contain .."),
+        });
+    }
+    Ok(parts.join("/"))
+}
+
+/// Direct children of `dir` in a backup's file listing, directories first
+fn list_directory(
+    files: &HashMap<String, archive::ArchivedFile>,
+    dir: &str,
+) -> Vec<BackupFileEntry> {
+    let mut children: HashMap<String, BackupFileEntry> = HashMap::new();
+    for (path, file) in files.iter() {
+        let rest = if dir.is_empty() {
+            path.as_str()
+        } else {
+            match path
+                .strip_prefix(dir)
+                .and_then(|rest| rest.strip_prefix('/'))
+            {
+                Some(rest) => rest,
+                None => continue,
+            }
+        };
+        let (name, is_dir) = match rest.split_once('/') {
+            Some((name, _)) => (name, true),
+            None => (rest, false),
+        };
+        let child = children
+            .entry(name.to_string())
+            .or_insert_with(|| BackupFileEntry {
+                name: name.to_string(),
+                path: if dir.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{}/{}", dir, name)
+                },
+                is_dir,
+                size: 0,
+            });
+        child.size += file.size;
+    }
+    let mut ret: Vec<BackupFileEntry> = children.into_values().collect();
+    ret.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    ret
+}
+
+/// Lists a directory of a backup without extracting it
+pub async fn list_backup_files(
+    path_to_instance: &Path,
+    backup_id: &str,
+    dir: &str,
+) -> Result<Vec<BackupFileEntry>, Error> {
+    let entry = BackupIndex::load(path_to_instance)
+        .await?
+        .get(backup_id)?
+        .clone();
+    let dir = normalize_path(dir)?;
+    let backups_dir = path_to_backups(path_to_instance);
+    let passphrase = archive_passphrase(path_to_instance, &entry).await?;
+    tokio::task::spawn_blocking(move || {
+        Ok(list_directory(
+            &backup_files(&backups_dir, &entry, passphrase.as_deref())?,
+            &dir,
+        ))
+    })
+    .await
+    .context("Failed to spawn blocking task")?
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct FileRestoreReport {
+    pub restored: Vec<String>,
+    /// already present and left alone
+    pub skipped: Vec<String>,
+}
+
+/// Restores the given files and directories of a backup into the live instance.
+/// World files should only be restored while the server is stopped.
+pub async fn restore_backup_files(
+    path_to_instance: &Path,
+    _guard: BackupGuard,
+    backup_id: &str,
+    paths: &[String],
+    conflict: archive::RestoreConflict,
+) -> Result<FileRestoreReport, Error> {
+    let entry = BackupIndex::load(path_to_instance)
+        .await?
+        .get(backup_id)?
+        .clone();
+    if let Some(snapshot) = &entry.snapshot {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!(
+                "Single files can't be restored from snapshots, copy them from {} instead",
+                snapshot.path
+            ),
+        });
+    }
+    let mut selected = Vec::new();
+    for path in paths {
+        let path = normalize_path(path)?;
+        if path.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Restore the whole backup instead of its root directory"),
+            });
+        }
+        selected.push(path);
+    }
+    if selected.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("No files selected"),
+        });
+    }
+    let backups_dir = path_to_backups(path_to_instance);
+    let root = path_to_instance.to_owned();
+    let passphrase = archive_passphrase(path_to_instance, &entry).await?;
+    let (incremental, file_name) = (entry.incremental, entry.file_name.clone());
+    let (restored, skipped) = tokio::task::spawn_blocking(move || {
+        if incremental {
+            incremental::copy_selected(
+                &incremental::path_to_trees(&backups_dir).join(&file_name),
+                &root,
+                &selected,
+                conflict,
+            )
+        } else {
+            archive::extract_selected(
+                &backups_dir.join(&file_name),
+                &root,
+                &selected,
+                conflict,
+                passphrase.as_deref(),
+            )
+        }
+    })
+    .await
+    .context("Failed to spawn blocking task")??;
+    info!(
+        "Restored {} files from {}",
+        restored.len(),
+        entry.describe()
+    );
+    Ok(FileRestoreReport { restored, skipped })
+}
+
+/// Marks an instance as busy with a backup or restore until dropped
+pub struct BackupGuard(InstanceUuid);
+
+impl BackupGuard {
+    pub fn acquire(uuid: &InstanceUuid) -> Result<Self, Error> {
+        let mut busy = BUSY_INSTANCES.lock().unwrap();
+        if !busy.insert(uuid.clone()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A backup or restore is already in progress for this instance"),
+            });
+        }
+        Ok(Self(uuid.clone()))
+    }
+}
+
+impl Drop for BackupGuard {
+    fn drop(&mut self) {
+        BUSY_INSTANCES.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Turns off autosaving and flushes the world to disk, so the files don't change
+/// while they are archived. Only Minecraft servers that are running are paused.
+pub(crate) async fn pause_saving(instance: &GameInstance) -> bool {
+    if !matches!(instance, GameInstance::MinecraftInstance(_))
+        || instance.state().await != State::Running
+    {
+        return false;
+    }
+    for command in ["save-off", "save-all flush"] {
+        if let Err(e) = instance.send_command(command, CausedBy::System).await {
+            warn!("Failed to pause saving before backup: {}", e);
+            return false;
+        }
+    }
+    // the console doesn't tell when the flush is done
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    true
+}
+
+/// Deletes the archive, snapshot or directory of a backup
+async fn remove_backup_data(path_to_instance: &Path, entry: &BackupEntry) -> Result<(), Error> {
+    if let Some(snapshot) = &entry.snapshot {
+        return snapshot::delete_snapshot(snapshot).await;
+    }
+    if entry.incremental {
+        let path =
+            incremental::path_to_trees(&path_to_backups(path_to_instance)).join(&entry.file_name);
+        if path.is_dir() {
+            crate::util::fs::remove_dir_all(path).await?;
+        }
+        return Ok(());
+    }
+    let path = path_to_backups(path_to_instance).join(&entry.file_name);
+    if path.is_file() {
+        crate::util::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn resume_saving(instance: &GameInstance) {
+    if let Err(e) = instance.send_command("save-on", CausedBy::System).await {
+        error!("Failed to turn saving back on: {}", e);
+    }
+}
+
+async fn create_backup_inner(
+    instance: &GameInstance,
+    path_to_instance: &Path,
+    metadata: BackupMetadata,
+    created_by: Option<String>,
+    event_broadcaster: &EventBroadcaster,
+) -> Result<BackupEntry, Error> {
+    let uuid = instance.uuid().await;
+    let store = state::state_store();
+    let databases = InstanceDatabases::load(store, &uuid, path_to_instance).await?;
+    if !databases.connections.is_empty() {
+        if let Err(e) = dump_instance_databases(store, &uuid, path_to_instance).await {
+            warn!("Backing up without fresh database dumps: {}", e);
+        }
+    }
+    let backups_dir = path_to_backups(path_to_instance);
+    crate::util::fs::create_dir_all(&backups_dir).await?;
+    let created_at = chrono::Utc::now().timestamp();
+    let id = rand_alphanumeric(12);
+    let file_name = format!(
+        "{}-{}.tar.gz",
+        chrono::NaiveDateTime::from_timestamp_opt(created_at, 0)
+            .map(|t| t.format("%Y%m%d-%H%M%S").to_string())
+            .unwrap_or_else(|| created_at.to_string()),
+        id
+    );
+    let partial = backups_dir.join(format!("{}.partial", file_name));
+    let policy = BackupPolicy::load(path_to_instance).await?;
+    let (key_id, passphrase) = match encryption_passphrase(&policy).await? {
+        Some((key_id, passphrase)) => (Some(key_id), Some(passphrase)),
+        None => (None, None),
+    };
+
+    let trees = incremental::path_to_trees(&backups_dir);
+    // the newest incremental backup whose files are still around
+    let previous_tree = BackupIndex::load(path_to_instance)
+        .await?
+        .backups
+        .into_iter()
+        .filter(|backup| backup.incremental)
+        .max_by_key(|backup| backup.created_at)
+        .map(|backup| trees.join(backup.file_name))
+        .filter(|tree| tree.is_dir());
+
+    let paused = pause_saving(instance).await;
+    let snapshot = match policy.mode {
+        BackupMode::Snapshot => snapshot::take_snapshot(path_to_instance, &backups_dir, &id).await,
+        BackupMode::Archive | BackupMode::Incremental => Ok(None),
+    };
+    let result = match snapshot {
+        Ok(Some(snapshot)) => Ok((Some(snapshot), 0)),
+        Ok(None) if policy.mode == BackupMode::Incremental => {
+            let root = path_to_instance.to_owned();
+            let partial = trees.join(format!("{}.partial", id));
+            tokio::task::spawn_blocking(move || {
+                let stats = incremental::create_tree(
+                    &root,
+                    &partial,
+                    NOT_BACKED_UP,
+                    previous_tree.as_deref(),
+                );
+                if stats.is_err() {
+                    let _ = std::fs::remove_dir_all(&partial);
+                }
+                stats
+            })
+            .await
+            .context("Failed to spawn blocking task")?
+            .map(|stats| {
+                info!(
+                    "Incremental backup stored {}, {} unchanged",
+                    crate::util::format_byte(stats.stored),
+                    crate::util::format_byte(stats.linked)
+                );
+                (None, stats.stored)
+            })
+        }
+        Ok(None) => {
+            let root = path_to_instance.to_owned();
+            let partial = partial.clone();
+            tokio::task::spawn_blocking(move || {
+                archive::create_archive(&root, &partial, NOT_BACKED_UP, passphrase.as_deref())
+            })
+            .await
+            .context("Failed to spawn blocking task")?
+            .map(|size| (None, size))
+        }
+        Err(e) => Err(e),
+    };
+    if paused {
+        resume_saving(instance).await;
+    }
+    let (snapshot, size) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = crate::util::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+    };
+    let incremental = snapshot.is_none() && policy.mode == BackupMode::Incremental;
+    let encrypted = key_id.is_some() && snapshot.is_none() && !incremental;
+    let file_name = if snapshot.is_some() {
+        String::new()
+    } else if incremental {
+        crate::util::fs::rename(trees.join(format!("{}.partial", id)), trees.join(&id)).await?;
+        id.clone()
+    } else {
+        crate::util::fs::rename(&partial, backups_dir.join(&file_name)).await?;
+        file_name
+    };
+
+    let entry = BackupEntry {
+        id,
+        file_name,
+        created_at,
+        size,
+        label: metadata.label,
+        pinned: metadata.pinned,
+        created_by,
+        encrypted,
+        key_id: key_id.filter(|_| encrypted),
+        uploaded_to: None,
+        snapshot,
+        incremental,
+    };
+    let mut index = BackupIndex::load(path_to_instance).await?;
+    index.backups.push(entry.clone());
+    let pruned = index.apply_policy(&policy);
+    index.save(path_to_instance).await?;
+    remove_pruned(instance, path_to_instance, pruned, event_broadcaster).await;
+    Ok(entry)
+}
+
+/// Copies a backup to a remote, into a directory named after the instance
+async fn upload_backup(
+    instance: &GameInstance,
+    entry: &BackupEntry,
+    remote: &remote::BackupRemote,
+    event_broadcaster: &EventBroadcaster,
+    caused_by: CausedBy,
+) -> Result<(), Error> {
+    let path_to_instance = instance.path().await;
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Uploading {} to {}", entry.describe(), remote.name),
+        Some(entry.size as f64),
+        None,
+        caused_by,
+    );
+    event_broadcaster.send(progression_start_event);
+    let res = remote::upload(
+        remote,
+        &path_to_backups(&path_to_instance).join(&entry.file_name),
+        &instance.uuid().await.to_string(),
+        &entry.file_name,
+        event_broadcaster,
+        &event_id,
+    )
+    .await;
+    match &res {
+        Ok(_) => {
+            info!("Uploaded {} to {}", entry.describe(), remote.name);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(&format!("Uploaded {} to {}", entry.describe(), remote.name)),
+                None,
+            ));
+        }
+        Err(e) => {
+            error!("Failed to upload {}: {}", entry.describe(), e);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(&format!("Failed to upload {}: {}", entry.describe(), e)),
+                None,
+            ));
+        }
+    }
+    res?;
+    let mut index = BackupIndex::load(&path_to_instance).await?;
+    // pruned already if the remote took longer than the next backups
+    if let Ok(indexed) = index.get_mut(&entry.id) {
+        indexed.uploaded_to = Some(remote.id.clone());
+        index.save(&path_to_instance).await?;
+    }
+    Ok(())
+}
+
+/// Archives the whole instance directory into its `backups` directory and prunes
+/// old backups, then uploads the new one if the instance has a remote among
+/// `remotes`. Progress and the outcome are reported through progression events.
+#[tracing::instrument(skip_all, fields(instance = tracing::field::Empty))]
+pub async fn create_backup(
+    instance: &GameInstance,
+    _guard: BackupGuard,
+    metadata: BackupMetadata,
+    created_by: Option<String>,
+    remotes: &remote::BackupRemotesSettings,
+    event_broadcaster: &EventBroadcaster,
+    caused_by: CausedBy,
+) -> Result<BackupEntry, Error> {
+    let name = instance.name().await;
+    tracing::Span::current().record("instance", name.as_str());
+    let path_to_instance = instance.path().await;
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Backing up {}", name),
+        None,
+        None,
+        caused_by.clone(),
+    );
+    event_broadcaster.send(progression_start_event);
+    let res = create_backup_inner(
+        instance,
+        &path_to_instance,
+        metadata,
+        created_by,
+        event_broadcaster,
+    )
+    .await;
+    let (details, instance_event_inner) = match &res {
+        Ok(entry) => {
+            info!("[{}] Created {}", name, entry.describe());
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(&format!("Created {}", entry.describe())),
+                None,
+            ));
+            (
+                format!("Created {}", entry.describe()),
+                InstanceEventInner::BackupCreated {
+                    backup: entry.clone(),
+                },
+            )
+        }
+        Err(e) => {
+            error!("[{}] Failed to create backup: {}", name, e);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(&format!("Failed to create backup: {}", e)),
+                None,
+            ));
+            (
+                format!("Failed to create backup: {}", e),
+                InstanceEventInner::BackupFailed {
+                    message: e.to_string(),
+                },
+            )
+        }
+    };
+    event_broadcaster.send(Event {
+        details,
+        event_inner: EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid: instance.uuid().await,
+            instance_name: name.clone(),
+            instance_event_inner,
+        }),
+        snowflake: Snowflake::default(),
+        caused_by: caused_by.clone(),
+    });
+    let mut entry = res?;
+    // snapshots can only leave the filesystem through an export, and incremental
+    // backups aren't a single file to upload
+    if let Some(remote_id) = BackupPolicy::load(&path_to_instance)
+        .await?
+        .remote
+        .filter(|_| entry.snapshot.is_none() && !entry.incremental)
+    {
+        // the local backup is kept either way, so a failed upload is only reported
+        match remotes.get(&remote_id) {
+            Ok(remote) => {
+                if upload_backup(instance, &entry, remote, event_broadcaster, caused_by)
+                    .await
+                    .is_ok()
+                {
+                    entry.uploaded_to = Some(remote.id.clone());
+                }
+            }
+            Err(e) => warn!("[{}] Not uploading {}: {}", name, entry.describe(), e),
+        }
+    }
+    Ok(entry)
+}
+
+/// Moves the top level entries of `from` that aren't kept on restore into `to`,
+/// recording the names of those moved so far in `moved`
+fn move_entries(from: &Path, to: &Path, moved: &mut Vec<std::ffi::OsString>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(from)
+        .context(format!("Failed to read {}", from.display()))?
+        .filter_map(|entry| entry.ok())
+    {
+        let name = entry.file_name();
+        if KEPT_ON_RESTORE.iter().any(|kept| name == *kept) {
+            continue;
+        }
+        std::fs::rename(entry.path(), to.join(&name))
+            .context(format!("Failed to move {}", entry.path().display()))?;
+        moved.push(name);
+    }
+    Ok(())
+}
+
+/// Puts back what `move_entries` moved, as far as it can
+fn move_back(from: &Path, to: &Path, moved: Vec<std::ffi::OsString>) {
+    for name in moved {
+        if let Err(e) = std::fs::rename(from.join(&name), to.join(&name)) {
+            error!(
+                "Failed to move {} back to {}: {}",
+                from.join(&name).display(),
+                to.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Swaps the instance's files for the extracted ones. The current files are
+/// moved into `aside` rather than deleted, and moved back if anything fails,
+/// so a failed restore leaves the instance as it was. All three directories
+/// must be on the same filesystem for the moves to be renames.
+fn replace_instance_files(
+    path_to_instance: &Path,
+    extracted: &Path,
+    aside: &Path,
+) -> Result<(), Error> {
+    let mut old = Vec::new();
+    if let Err(e) = move_entries(path_to_instance, aside, &mut old) {
+        move_back(aside, path_to_instance, old);
+        return Err(e);
+    }
+    let mut new = Vec::new();
+    if let Err(e) = move_entries(extracted, path_to_instance, &mut new) {
+        move_back(path_to_instance, extracted, new);
+        move_back(aside, path_to_instance, old);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Replaces the instance's files with the contents of a backup. A running
+/// instance is stopped first and started again afterwards, even if the restore
+/// failed and its old files were put back. Its backups and `.lodestone_config`
+/// are left untouched.
+#[tracing::instrument(skip_all, fields(instance = tracing::field::Empty, backup_id = %backup_id))]
+pub async fn restore_backup(
+    instance: &mut GameInstance,
+    _guard: BackupGuard,
+    backup_id: &str,
+    event_broadcaster: &EventBroadcaster,
+    caused_by: CausedBy,
+) -> Result<BackupEntry, Error> {
+    let name = instance.name().await;
+    tracing::Span::current().record("instance", name.as_str());
+    let state = instance.state().await;
+    state.check(InstanceOperation::RestoreBackup)?;
+    let path_to_instance = instance.path().await;
+    let entry = BackupIndex::load(&path_to_instance)
+        .await?
+        .get(backup_id)?
+        .clone();
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Restoring {} of {}", entry.describe(), name),
+        Some(3.0),
+        None,
+        caused_by.clone(),
+    );
+    event_broadcaster.send(progression_start_event);
+
+    let was_running = state == State::Running;
+    let res: Result<(), Error> = async {
+        if was_running {
+            event_broadcaster.send(Event::new_progression_event_update(
+                &event_id,
+                "Stopping server",
+                1.0,
+            ));
+            instance.stop(caused_by.clone(), true).await?;
+        }
+        event_broadcaster.send(Event::new_progression_event_update(
+            &event_id,
+            "Restoring files",
+            1.0,
+        ));
+        let backups_dir = path_to_backups(&path_to_instance);
+        let archive_path = backups_dir.join(&entry.file_name);
+        let root = path_to_instance.clone();
+        let passphrase = archive_passphrase(&path_to_instance, &entry).await?;
+        let snapshot = entry.snapshot.clone();
+        let tree = incremental::path_to_trees(&backups_dir).join(&entry.file_name);
+        let is_incremental = entry.incremental;
+        let restored = tokio::task::spawn_blocking(move || {
+            // extracted next to the archives so moving the files in is a rename
+            let temp_dir = tempfile::tempdir_in(&backups_dir)
+                .context("Failed to create temporary directory")?;
+            let aside = tempfile::tempdir_in(&backups_dir)
+                .context("Failed to create temporary directory")?;
+            match &snapshot {
+                Some(snapshot) => {
+                    snapshot::copy_snapshot(snapshot, temp_dir.path(), KEPT_ON_RESTORE)?
+                }
+                // copied rather than linked, the server must not write into the backup
+                None if is_incremental => {
+                    incremental::create_tree(&tree, temp_dir.path(), KEPT_ON_RESTORE, None)?;
+                }
+                None => {
+                    archive::extract_archive(&archive_path, temp_dir.path(), passphrase.as_deref())?
+                }
+            }
+            replace_instance_files(&root, temp_dir.path(), aside.path())
+        })
+        .await
+        .context("Failed to spawn blocking task")
+        .map_err(Error::from)
+        .and_then(|res| res);
+        if was_running {
+            event_broadcaster.send(Event::new_progression_event_update(
+                &event_id,
+                "Starting server",
+                1.0,
+            ));
+            if let Err(e) = instance.start(caused_by, false).await {
+                // the restore outcome matters more, a failed start only shows in the logs then
+                if restored.is_ok() {
+                    return Err(e);
+                }
+                error!("[{}] Failed to start after a failed restore: {}", name, e);
+            }
+        }
+        restored
+    }
+    .await;
+
+    match &res {
+        Ok(_) => {
+            info!("[{}] Restored {}", name, entry.describe());
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(&format!("Restored {}", entry.describe())),
+                None,
+            ));
+        }
+        Err(e) => {
+            error!("[{}] Failed to restore {}: {}", name, entry.describe(), e);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(&format!("Failed to restore {}: {}", entry.describe(), e)),
+                None,
+            ));
+        }
+    }
+    res.map(|_| entry)
+}
+
+/// Writes a `zfs send` or `btrfs send` stream of a snapshot backup into the
+/// instance's `backups` directory, returning the stream's file name
+pub async fn export_snapshot_backup(
+    instance: &GameInstance,
+    _guard: BackupGuard,
+    backup_id: &str,
+    event_broadcaster: &EventBroadcaster,
+    caused_by: CausedBy,
+) -> Result<String, Error> {
+    let path_to_instance = instance.path().await;
+    let entry = BackupIndex::load(&path_to_instance)
+        .await?
+        .get(backup_id)?
+        .clone();
+    let snapshot = entry.snapshot.clone().ok_or_else(|| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Only snapshots can be exported, archives can be downloaded as they are"),
+    })?;
+    let file_name = format!(
+        "{}.{}",
+        entry.id,
+        match snapshot.filesystem {
+            snapshot::SnapshotFs::Zfs => "zfs",
+            snapshot::SnapshotFs::Btrfs => "btrfs",
+        }
+    );
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Exporting {}", entry.describe()),
+        None,
+        None,
+        caused_by,
+    );
+    event_broadcaster.send(progression_start_event);
+    let res = snapshot::export_snapshot(
+        &snapshot,
+        &path_to_backups(&path_to_instance).join(&file_name),
+    )
+    .await;
+    match &res {
+        Ok(_) => {
+            info!("Exported {} to {}", entry.describe(), file_name);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(&format!("Exported {} to {}", entry.describe(), file_name)),
+                None,
+            ));
+        }
+        Err(e) => {
+            error!("Failed to export {}: {}", entry.describe(), e);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(&format!("Failed to export {}: {}", entry.describe(), e)),
+                None,
+            ));
+        }
+    }
+    res.map(|_| file_name)
+}
+
+/// Deletes a backup, pinned backups have to be unpinned first
+pub async fn delete_backup(path_to_instance: &Path, backup_id: &str) -> Result<(), Error> {
+    let mut index = BackupIndex::load(path_to_instance).await?;
+    let entry = index.get(backup_id)?.clone();
+    if entry.pinned {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Unpin the {} before deleting it", entry.describe()),
+        });
+    }
+    // the snapshot goes first, a failure to destroy it would otherwise leave it
+    // taking up space with nothing pointing at it
+    remove_backup_data(path_to_instance, &entry).await?;
+    index.backups.retain(|backup| backup.id != backup_id);
+    index.save(path_to_instance).await?;
+    Ok(())
+}
+
+#[test]
+fn test_diff_files() {
+    let file = |size: u64, modified: u64| archive::ArchivedFile { size, modified };
+    let a = HashMap::from([
+        ("server.properties".to_string(), file(10, 1)),
+        ("world/level.dat".to_string(), file(100, 1)),
+        ("logs/old.log".to_string(), file(5, 1)),
+    ]);
+    let b = HashMap::from([
+        ("server.properties".to_string(), file(10, 1)),
+        ("world/level.dat".to_string(), file(100, 2)),
+        ("world/region/r.0.0.mca".to_string(), file(4096, 2)),
+    ]);
+    let (added, removed, changed) = diff_files(&a, &b);
+    assert_eq!(added[0].path, "world/region/r.0.0.mca");
+    assert_eq!(added[0].new_size, Some(4096));
+    assert_eq!(removed[0].path, "logs/old.log");
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].path, "world/level.dat");
+}
+
+#[test]
+fn test_list_directory() {
+    let file = |size: u64| archive::ArchivedFile { size, modified: 0 };
+    let files = HashMap::from([
+        ("server.properties".to_string(), file(10)),
+        ("world/level.dat".to_string(), file(100)),
+        ("world/region/r.0.0.mca".to_string(), file(4096)),
+    ]);
+    let root = list_directory(&files, "");
+    assert_eq!(root[0].name, "world");
+    assert!(root[0].is_dir);
+    assert_eq!(root[0].size, 4196);
+    assert_eq!(root[1].path, "server.properties");
+    let world = list_directory(&files, &normalize_path("/world/").unwrap());
+    assert_eq!(world[0].path, "world/region");
+    assert_eq!(world[1].path, "world/level.dat");
+    assert!(normalize_path("world/../..").is_err());
+}
+
+#[test]
+fn test_prune_keeps_pinned() {
+    let backup = |id: &str, created_at: i64, pinned: bool| BackupEntry {
+        id: id.to_string(),
+        file_name: format!("{}.tar.gz", id),
+        created_at,
+        size: 0,
+        label: None,
+        pinned,
+        created_by: None,
+        encrypted: false,
+        key_id: None,
+        uploaded_to: None,
+        snapshot: None,
+        incremental: false,
+    };
+    let mut index = BackupIndex {
+        backups: vec![
+            backup("oldest", 1, true),
+            backup("old", 2, false),
+            backup("new", 3, false),
+            backup("newest", 4, false),
+        ],
+    };
+    let pruned = index.prune(2);
+    assert_eq!(pruned.len(), 1);
+    assert_eq!(pruned[0].id, "old");
+    let kept: Vec<&str> = index.backups.iter().map(|b| b.id.as_str()).collect();
+    assert_eq!(kept, vec!["oldest", "new", "newest"]);
+}
+
+#[test]
+fn test_replace_instance_files() {
+    let root = tempfile::tempdir().unwrap();
+    let extracted = tempfile::tempdir().unwrap();
+    let aside = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("backups")).unwrap();
+    std::fs::write(root.path().join("server.properties"), "new").unwrap();
+    std::fs::create_dir(root.path().join("world_nether")).unwrap();
+    std::fs::write(extracted.path().join("server.properties"), "old").unwrap();
+    std::fs::create_dir(extracted.path().join("world")).unwrap();
+    replace_instance_files(root.path(), extracted.path(), aside.path()).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("server.properties")).unwrap(),
+        "old"
+    );
+    assert!(root.path().join("world").is_dir());
+    assert!(root.path().join("backups").is_dir());
+    assert!(!root.path().join("world_nether").exists());
+    assert!(aside.path().join("world_nether").is_dir());
+}
+
+#[tokio::test]
+async fn test_restore_after_passphrase_change() {
+    // every connection to memory opens a database of its own
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    state::init_state_store(state::StateStore::new(pool).await.unwrap());
+    let instance = tempfile::tempdir().unwrap();
+    let backups_dir = instance.path().join("backups");
+    std::fs::create_dir(&backups_dir).unwrap();
+    std::fs::write(instance.path().join("server.properties"), "motd=old").unwrap();
+
+    let mut policy = BackupPolicy::default();
+    policy.encryption.enabled = true;
+    policy.encryption.use_core_key = false;
+    policy.encryption.passphrase = "first passphrase".to_string();
+    policy.validate().unwrap();
+    policy.save(instance.path()).await.unwrap();
+    let saved =
+        std::fs::read_to_string(instance.path().join(".lodestone_backup_policy.json")).unwrap();
+    assert!(!saved.contains("first passphrase"));
+
+    let (key_id, passphrase) = encryption_passphrase(&policy).await.unwrap().unwrap();
+    archive::create_archive(
+        instance.path(),
+        &backups_dir.join("old.tar.gz"),
+        NOT_BACKED_UP,
+        Some(&passphrase),
+    )
+    .unwrap();
+    let entry = BackupEntry {
+        id: "old".to_string(),
+        file_name: "old.tar.gz".to_string(),
+        created_at: 1,
+        size: 0,
+        label: None,
+        pinned: false,
+        created_by: None,
+        encrypted: true,
+        key_id: Some(key_id),
+        uploaded_to: None,
+        snapshot: None,
+        incremental: false,
+    };
+
+    let mut policy = BackupPolicy::load(instance.path()).await.unwrap();
+    policy.encryption.key_id = None;
+    policy.encryption.passphrase = "second passphrase".to_string();
+    policy.save(instance.path()).await.unwrap();
+    let (new_key_id, _) = encryption_passphrase(&policy).await.unwrap().unwrap();
+    assert_ne!(Some(new_key_id), entry.key_id);
+
+    let passphrase = archive_passphrase(instance.path(), &entry)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(passphrase, "first passphrase");
+    let restored = tempfile::tempdir().unwrap();
+    archive::extract_archive(
+        &backups_dir.join("old.tar.gz"),
+        restored.path(),
+        Some(&passphrase),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(restored.path().join("server.properties")).unwrap(),
+        "motd=old"
+    );
+
+    // a policy without the core key needs a passphrase, new or stored
+    policy.encryption.key_id = None;
+    assert!(matches!(
+        policy.validate().unwrap_err().kind,
+        ErrorKind::BadRequest
+    ));
+}
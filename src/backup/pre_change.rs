@@ -0,0 +1,460 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, ProgressionEndValue, ProgressionEventID};
+use crate::implementations::minecraft::MinecraftInstance;
+use crate::prelude::GameInstance;
+use crate::traits::t_backup::TBackup;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::traits::{Capability, TInstance};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::AppState;
+
+use super::{archive::RestoreConflict, restore_backup_files, BackupGuard, BackupMetadata};
+
+/// changes that can be rolled back, kept in the instance directory
+const CHANGES_FILE_NAME: &str = ".lodestone_changes.json";
+
+const MAX_CHANGES_KEPT: usize = 20;
+
+/// Which operations back the instance up first, so a bad change can be rolled back
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct PreChangeBackupSettings {
+    pub mod_updates: bool,
+    pub version_changes: bool,
+    /// settings changed by applying instance definitions
+    pub definition_applies: bool,
+}
+
+impl Default for PreChangeBackupSettings {
+    fn default() -> Self {
+        Self {
+            mod_updates: true,
+            version_changes: true,
+            definition_applies: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RiskyChange {
+    ModUpdate,
+    VersionChange,
+    DefinitionApply,
+}
+
+impl RiskyChange {
+    fn enabled(&self, settings: &PreChangeBackupSettings) -> bool {
+        match self {
+            RiskyChange::ModUpdate => settings.mod_updates,
+            RiskyChange::VersionChange => settings.version_changes,
+            RiskyChange::DefinitionApply => settings.definition_applies,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            RiskyChange::ModUpdate => "Updating mods of",
+            RiskyChange::VersionChange => "Changing the version of",
+            RiskyChange::DefinitionApply => "Applying the definition of",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            RiskyChange::ModUpdate => "Before mod update",
+            RiskyChange::VersionChange => "Before version change",
+            RiskyChange::DefinitionApply => "Before definition apply",
+        }
+    }
+}
+
+/// A mod file replaced or added by an update, relative to the instance
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ModFileChange {
+    /// `None` for a dependency that was added
+    pub from_file: Option<String>,
+    pub to_file: String,
+}
+
+/// What a change did, enough to undo it from the backup taken before
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum ChangeDiff {
+    Version { from: String, to: String },
+    Mods { files: Vec<ModFileChange> },
+}
+
+impl ChangeDiff {
+    /// The files to take from the backup and the ones to delete afterwards
+    fn rollback_paths(&self) -> (Vec<String>, Vec<String>) {
+        match self {
+            ChangeDiff::Version { .. } => (vec!["server.jar".to_string()], Vec::new()),
+            ChangeDiff::Mods { files } => {
+                let restore: Vec<String> = files
+                    .iter()
+                    .filter_map(|file| file.from_file.clone())
+                    .collect();
+                let remove = files
+                    .iter()
+                    .map(|file| file.to_file.clone())
+                    .filter(|to_file| !restore.contains(to_file))
+                    .collect();
+                (restore, remove)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ChangeRecord {
+    pub id: Snowflake,
+    pub time: i64,
+    /// the backup taken before the change, a change without one can't be rolled back
+    pub backup_id: Option<String>,
+    pub diff: ChangeDiff,
+    pub rolled_back: bool,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct RollbackReport {
+    pub change: ChangeRecord,
+    pub restored: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// The last changes of an instance, oldest first
+struct ChangeHistory {
+    path_to_file: PathBuf,
+    changes: Vec<ChangeRecord>,
+}
+
+impl ChangeHistory {
+    async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path_to_file = path_to_instance.join(CHANGES_FILE_NAME);
+        let changes = if path_to_file.is_file() {
+            serde_json::from_str(&crate::util::fs::read_to_string(&path_to_file).await?).context(
+                format!("Failed to parse changes at {}", path_to_file.display()),
+            )?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path_to_file,
+            changes,
+        })
+    }
+
+    async fn save(&self) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            &self.path_to_file,
+            serde_json::to_string_pretty(&self.changes).context("Failed to serialize changes")?,
+        )
+        .await
+    }
+
+    async fn append(path_to_instance: &Path, record: ChangeRecord) -> Result<(), Error> {
+        let mut history = Self::load(path_to_instance).await?;
+        history.changes.push(record);
+        let excess = history.changes.len().saturating_sub(MAX_CHANGES_KEPT);
+        history.changes.drain(..excess);
+        history.save().await
+    }
+
+    /// The newest change not rolled back yet
+    fn last(&mut self) -> Option<&mut ChangeRecord> {
+        self.changes
+            .iter_mut()
+            .rev()
+            .find(|change| !change.rolled_back)
+    }
+}
+
+/// The change `rollback_last_change` would undo
+pub async fn last_change(path_to_instance: &Path) -> Result<Option<ChangeRecord>, Error> {
+    Ok(ChangeHistory::load(path_to_instance).await?.last().cloned())
+}
+
+/// Undoes the newest change not rolled back yet, taking the files it replaced
+/// from the backup made before it and deleting the ones it added. Rolling back
+/// again undoes the change before.
+pub async fn rollback_last_change(instance: &MinecraftInstance) -> Result<RollbackReport, Error> {
+    if instance.state().await != State::Stopped {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Stop the instance before rolling it back"),
+        });
+    }
+    let path_to_instance = instance.path().await;
+    let mut history = ChangeHistory::load(&path_to_instance).await?;
+    let change = history.last().ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("No change to roll back"),
+    })?;
+    let backup_id = change.backup_id.clone().ok_or_else(|| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("No backup was taken before the last change, it can't be rolled back"),
+    })?;
+    let guard = BackupGuard::acquire(&instance.uuid().await)?;
+    let (restore, remove) = change.diff.rollback_paths();
+    let restored = if restore.is_empty() {
+        Vec::new()
+    } else {
+        restore_backup_files(
+            &path_to_instance,
+            guard,
+            &backup_id,
+            &restore,
+            RestoreConflict::Overwrite,
+        )
+        .await?
+        .restored
+    };
+    let mut removed = Vec::new();
+    for path in remove {
+        let file = path_to_instance.join(&path);
+        if file.is_file() {
+            crate::util::fs::remove_file(&file).await?;
+            removed.push(path);
+        }
+    }
+    if let ChangeDiff::Version { from, .. } = &change.diff {
+        instance.restore_version(from.clone()).await?;
+    }
+    change.rolled_back = true;
+    let change = change.clone();
+    history.save().await?;
+    Ok(RollbackReport {
+        change,
+        restored,
+        removed,
+    })
+}
+
+/// A change in progress, announced by a progression event whose end carries the
+/// id of the backup taken before it
+pub struct PendingChange {
+    instance_uuid: InstanceUuid,
+    instance_name: String,
+    path_to_instance: PathBuf,
+    event_id: ProgressionEventID,
+    event_broadcaster: EventBroadcaster,
+    backup_id: Option<String>,
+}
+
+impl PendingChange {
+    /// Starts the progression event and backs the instance up if the settings ask
+    /// for it. The change should not go ahead if the backup fails, the event is
+    /// ended with the error then.
+    pub async fn begin(
+        state: &AppState,
+        instance: &GameInstance,
+        change: RiskyChange,
+        caused_by: CausedBy,
+    ) -> Result<Self, Error> {
+        let instance_name = instance.name().await;
+        let (progression_start_event, event_id) = Event::new_progression_event_start(
+            format!("{} {}", change.describe(), instance_name),
+            None,
+            None,
+            caused_by.clone(),
+        );
+        state.event_broadcaster.send(progression_start_event);
+        let mut pending = Self {
+            instance_uuid: instance.uuid().await,
+            instance_name,
+            path_to_instance: instance.path().await,
+            event_id,
+            event_broadcaster: state.event_broadcaster.clone(),
+            backup_id: None,
+        };
+        let settings = state.global_settings.lock().await.pre_change_backups();
+        if !change.enabled(&settings)
+            || instance
+                .require_capability(Capability::SupportsBackups)
+                .is_err()
+        {
+            return Ok(pending);
+        }
+        let backup = match BackupGuard::acquire(&pending.instance_uuid) {
+            Ok(guard) => {
+                let remotes = state.global_settings.lock().await.backup_remotes();
+                instance
+                    .create_backup(
+                        guard,
+                        BackupMetadata {
+                            label: Some(change.label().to_string()),
+                            pinned: false,
+                        },
+                        None,
+                        &remotes,
+                        caused_by,
+                    )
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match backup {
+            Ok(backup) => {
+                pending.backup_id = Some(backup.id);
+                Ok(pending)
+            }
+            Err(e) => {
+                let message = format!("Backing up {} first failed: {}", pending.instance_name, e);
+                pending.end(false, message);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn backup_id(&self) -> Option<&str> {
+        self.backup_id.as_deref()
+    }
+
+    /// Ends the progression event with the outcome of the change. A successful
+    /// change with a `diff` is recorded so it can be rolled back.
+    pub async fn finish<T>(self, result: &Result<T, Error>, diff: Option<ChangeDiff>) {
+        if let (Ok(_), Some(diff)) = (result, diff) {
+            let record = ChangeRecord {
+                id: Snowflake::default(),
+                time: chrono::Utc::now().timestamp(),
+                backup_id: self.backup_id.clone(),
+                diff,
+                rolled_back: false,
+            };
+            if let Err(e) = ChangeHistory::append(&self.path_to_instance, record).await {
+                warn!(
+                    "Failed to record the change of {}, it can't be rolled back: {}",
+                    self.instance_name, e
+                );
+            }
+        }
+        let message = match (result, &self.backup_id) {
+            (Ok(_), Some(backup_id)) => format!(
+                "Changed {}, backup {} holds the state before",
+                self.instance_name, backup_id
+            ),
+            (Ok(_), None) => format!("Changed {}", self.instance_name),
+            (Err(e), Some(backup_id)) => format!(
+                "Failed to change {}: {}. Backup {} holds the state before",
+                self.instance_name, e, backup_id
+            ),
+            (Err(e), None) => format!("Failed to change {}: {}", self.instance_name, e),
+        };
+        self.end(result.is_ok(), message);
+    }
+
+    fn end(self, success: bool, message: String) {
+        self.event_broadcaster
+            .send(Event::new_progression_event_end(
+                self.event_id,
+                success,
+                Some(message),
+                Some(ProgressionEndValue::InstanceChanged {
+                    instance_uuid: self.instance_uuid,
+                    backup_id: self.backup_id,
+                }),
+            ));
+    }
+}
+
+#[test]
+fn test_rollback_paths() {
+    let diff = ChangeDiff::Mods {
+        files: vec![
+            ModFileChange {
+                from_file: Some("mods/lithium-0.11.jar".to_string()),
+                to_file: "mods/lithium-0.12.jar".to_string(),
+            },
+            ModFileChange {
+                from_file: Some("mods/config-lib.jar".to_string()),
+                to_file: "mods/config-lib.jar".to_string(),
+            },
+            ModFileChange {
+                from_file: None,
+                to_file: "mods/fabric-api.jar".to_string(),
+            },
+        ],
+    };
+    assert_eq!(
+        diff.rollback_paths(),
+        (
+            vec![
+                "mods/lithium-0.11.jar".to_string(),
+                "mods/config-lib.jar".to_string()
+            ],
+            vec![
+                "mods/lithium-0.12.jar".to_string(),
+                "mods/fabric-api.jar".to_string()
+            ]
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_pending_change() {
+    use crate::events::{EventInner, ProgressionEventInner};
+
+    let settings = PreChangeBackupSettings::default();
+    assert!(RiskyChange::ModUpdate.enabled(&settings));
+    assert!(!RiskyChange::ModUpdate.enabled(&PreChangeBackupSettings {
+        mod_updates: false,
+        ..settings
+    }));
+
+    let instance = tempfile::tempdir().unwrap();
+    let (event_broadcaster, _) = EventBroadcaster::new(16);
+    let pending = |backup_id: Option<&str>| PendingChange {
+        instance_uuid: InstanceUuid::from("survival".to_string()),
+        instance_name: "Survival".to_string(),
+        path_to_instance: instance.path().to_path_buf(),
+        event_id: Event::new_progression_event_start(
+            "Updating mods of Survival",
+            None,
+            None,
+            CausedBy::System,
+        )
+        .1,
+        event_broadcaster: event_broadcaster.clone(),
+        backup_id: backup_id.map(str::to_string),
+    };
+    let mut receiver = event_broadcaster.subscribe();
+    let mut end = || match receiver.try_recv().unwrap().event_inner {
+        EventInner::ProgressionEvent(event) => match event.progression_event_inner() {
+            ProgressionEventInner::ProgressionEnd {
+                success, message, ..
+            } => (*success, message.clone().unwrap()),
+            inner => panic!("unexpected progression event {:?}", inner),
+        },
+        inner => panic!("unexpected event {:?}", inner),
+    };
+
+    pending(Some("1234"))
+        .finish(&Ok::<(), Error>(()), None)
+        .await;
+    let (success, message) = end();
+    assert!(success);
+    assert_eq!(
+        message,
+        "Changed Survival, backup 1234 holds the state before"
+    );
+
+    let failed: Result<(), Error> = Err(eyre!("no space left").into());
+    pending(None).finish(&failed, None).await;
+    let (success, message) = end();
+    assert!(!success);
+    assert!(message.starts_with("Failed to change Survival: "));
+    assert!(message.ends_with("no space left"));
+}
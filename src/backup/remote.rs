@@ -0,0 +1,562 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{Event, ProgressionEventID};
+use crate::util::{dont_spawn_terminal, format_byte_download, rand_alphanumeric};
+
+use super::s3::Bucket;
+
+/// Attempts of a whole upload, S3 uploads retry each of their requests instead
+const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// Where backups are copied to. Rclone, rsync and SFTP shell out to the tool of the
+/// same name, which has to be installed on the host and able to authenticate without
+/// a prompt.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum RemoteDestination {
+    /// an rclone remote and path, e.g. `b2:my-bucket/lodestone`, set up beforehand
+    /// with `rclone config` by the user the core runs as
+    Rclone { remote: String },
+    /// rsync over ssh to `user@host:/path`, needs rsync 3.2.3 or later on both ends
+    Rsync {
+        destination: String,
+        port: Option<u16>,
+        identity_file: Option<String>,
+    },
+    Sftp {
+        host: String,
+        port: u16,
+        user: String,
+        path: String,
+        identity_file: Option<String>,
+    },
+    /// any S3 compatible storage, e.g. AWS, MinIO, Backblaze B2 or Cloudflare R2
+    S3 {
+        /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://minio.lan:9000`
+        endpoint: String,
+        region: String,
+        bucket: String,
+        /// prepended to the keys of uploaded backups
+        #[serde(default)]
+        prefix: String,
+        access_key_id: String,
+        /// cleared when sent to clients, sending it back empty keeps the stored one
+        #[serde(default)]
+        secret_access_key: String,
+        /// needed by MinIO and most self-hosted storage
+        #[serde(default)]
+        path_style: bool,
+    },
+    /// a directory on the host, usually a mounted network share or another disk
+    Local { path: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct BackupRemote {
+    /// assigned by the core when left empty
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub destination: RemoteDestination,
+}
+
+/// Remote targets instances can upload their backups to. Each backup goes into a
+/// directory named after the instance's uuid, pruning only deletes local copies.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct BackupRemotesSettings {
+    pub remotes: Vec<BackupRemote>,
+}
+
+/// Values end up as arguments of external commands, so one starting with `-`
+/// would be taken as an option
+fn validate_argument(what: &str, value: &str) -> Result<(), Error> {
+    if value.trim().is_empty() || value.starts_with('-') || value.chars().any(|c| c.is_control()) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid {}: {:?}", what, value),
+        });
+    }
+    Ok(())
+}
+
+impl RemoteDestination {
+    fn validate(&self) -> Result<(), Error> {
+        let identity_file = match self {
+            RemoteDestination::Rclone { remote } => {
+                validate_argument("rclone remote", remote)?;
+                if !remote.contains(':') {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("rclone remote must look like name:path"),
+                    });
+                }
+                None
+            }
+            RemoteDestination::Rsync {
+                destination,
+                identity_file,
+                ..
+            } => {
+                validate_argument("rsync destination", destination)?;
+                identity_file
+            }
+            RemoteDestination::Sftp {
+                host,
+                user,
+                path,
+                identity_file,
+                ..
+            } => {
+                validate_argument("host", host)?;
+                validate_argument("user", user)?;
+                validate_argument("path", path)?;
+                if path.contains('"') {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("SFTP path must not contain quotes"),
+                    });
+                }
+                identity_file
+            }
+            RemoteDestination::S3 {
+                prefix,
+                secret_access_key,
+                ..
+            } => {
+                self.bucket().unwrap().validate()?;
+                if prefix.chars().any(|c| c.is_control()) {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Invalid S3 prefix: {:?}", prefix),
+                    });
+                }
+                if secret_access_key.is_empty() {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("S3 secret access key is required"),
+                    });
+                }
+                None
+            }
+            RemoteDestination::Local { path } => {
+                if !Path::new(path).is_absolute() {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("Local backup path must be absolute"),
+                    });
+                }
+                None
+            }
+        };
+        if let Some(identity_file) = identity_file {
+            validate_argument("identity file", identity_file)?;
+            // passed to rsync as part of the ssh command line
+            if identity_file.contains(char::is_whitespace) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Identity file path must not contain spaces"),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn bucket(&self) -> Option<Bucket> {
+        match self {
+            RemoteDestination::S3 {
+                endpoint,
+                region,
+                bucket,
+                access_key_id,
+                secret_access_key,
+                path_style,
+                ..
+            } => Some(Bucket {
+                endpoint,
+                region,
+                bucket,
+                access_key_id,
+                secret_access_key,
+                path_style: *path_style,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The command copying `local` to `<dir>/<file_name>` on the remote, and what to
+    /// write to its stdin, `None` for the destinations not using an external tool
+    fn upload_command(
+        &self,
+        local: &Path,
+        dir: &str,
+        file_name: &str,
+    ) -> Option<(Command, Option<String>)> {
+        Some(match self {
+            RemoteDestination::Rclone { remote } => {
+                let mut cmd = Command::new("rclone");
+                cmd.arg("copyto").arg(local).arg(format!(
+                    "{}/{}/{}",
+                    remote.trim_end_matches('/'),
+                    dir,
+                    file_name
+                ));
+                (cmd, None)
+            }
+            RemoteDestination::Rsync {
+                destination,
+                port,
+                identity_file,
+            } => {
+                let mut ssh = String::from("ssh -o BatchMode=yes");
+                if let Some(port) = port {
+                    ssh.push_str(&format!(" -p {}", port));
+                }
+                if let Some(identity_file) = identity_file {
+                    ssh.push_str(&format!(" -i {}", identity_file));
+                }
+                let mut cmd = Command::new("rsync");
+                cmd.args(["--partial", "--mkpath", "-e", ssh.as_str()])
+                    .arg(local)
+                    .arg(format!(
+                        "{}/{}/{}",
+                        destination.trim_end_matches('/'),
+                        dir,
+                        file_name
+                    ));
+                (cmd, None)
+            }
+            RemoteDestination::Sftp {
+                host,
+                port,
+                user,
+                path,
+                identity_file,
+            } => {
+                let mut cmd = Command::new("sftp");
+                cmd.args(["-o", "BatchMode=yes", "-P", &port.to_string()]);
+                if let Some(identity_file) = identity_file {
+                    cmd.args(["-i", identity_file.as_str()]);
+                }
+                cmd.args(["-b", "-", &format!("{}@{}", user, host)]);
+                let remote_dir = format!("{}/{}", path.trim_end_matches('/'), dir);
+                // a leading `-` lets the batch go on if the directory exists already
+                let batch = format!(
+                    "-mkdir \"{}\"\nput \"{}\" \"{}/{}\"\n",
+                    remote_dir,
+                    local.display(),
+                    remote_dir,
+                    file_name
+                );
+                (cmd, Some(batch))
+            }
+            RemoteDestination::S3 { .. } | RemoteDestination::Local { .. } => return None,
+        })
+    }
+
+    fn program(&self) -> &'static str {
+        match self {
+            RemoteDestination::Rclone { .. } => "rclone",
+            RemoteDestination::Rsync { .. } => "rsync",
+            RemoteDestination::Sftp { .. } => "sftp",
+            RemoteDestination::S3 { .. } => "s3",
+            RemoteDestination::Local { .. } => "local",
+        }
+    }
+}
+
+impl BackupRemotesSettings {
+    /// Validates the remotes, assigns ids to new ones and keeps the S3 secrets of
+    /// `old` for the ones sent back without a secret
+    pub fn prepare(&mut self, old: &BackupRemotesSettings) -> Result<(), Error> {
+        for remote in self.remotes.iter_mut() {
+            if remote.name.trim().is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Remote name is empty"),
+                });
+            }
+            if remote.id.is_empty() {
+                remote.id = rand_alphanumeric(8);
+            }
+            if let RemoteDestination::S3 {
+                secret_access_key, ..
+            } = &mut remote.destination
+            {
+                if secret_access_key.is_empty() {
+                    if let Ok(BackupRemote {
+                        destination:
+                            RemoteDestination::S3 {
+                                secret_access_key: old_secret,
+                                ..
+                            },
+                        ..
+                    }) = old.get(&remote.id)
+                    {
+                        *secret_access_key = old_secret.clone();
+                    }
+                }
+            }
+            remote.destination.validate()?;
+        }
+        let mut ids: Vec<&str> = self
+            .remotes
+            .iter()
+            .map(|remote| remote.id.as_str())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.len() != self.remotes.len() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Remote ids must be unique"),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<&BackupRemote, Error> {
+        self.remotes
+            .iter()
+            .find(|remote| remote.id == id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Backup remote {} not found", id),
+            })
+    }
+
+    /// The remotes with their secrets left out, to be sent to clients
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        for remote in ret.remotes.iter_mut() {
+            if let RemoteDestination::S3 {
+                secret_access_key, ..
+            } = &mut remote.destination
+            {
+                secret_access_key.clear();
+            }
+        }
+        ret
+    }
+}
+
+/// Copies a backup archive to `<dir>/<file_name>` on the remote, retrying failed
+/// attempts. Uploaded bytes are reported as progress of `event_id`, whose total
+/// should be the size of the archive.
+pub async fn upload(
+    remote: &BackupRemote,
+    local: &Path,
+    dir: &str,
+    file_name: &str,
+    event_broadcaster: &EventBroadcaster,
+    event_id: &ProgressionEventID,
+) -> Result<(), Error> {
+    let total = tokio::fs::metadata(local)
+        .await
+        .context(format!("Failed to read metadata of {}", local.display()))?
+        .len();
+    // a retried attempt only reports progress past what earlier ones got to
+    let reported = AtomicU64::new(0);
+    let mut attempt = 1;
+    loop {
+        let uploaded = AtomicU64::new(0);
+        let on_progress = |bytes: u64| {
+            let uploaded = uploaded.fetch_add(bytes, Ordering::Relaxed) + bytes;
+            let previous = reported.fetch_max(uploaded, Ordering::Relaxed);
+            if uploaded > previous {
+                event_broadcaster.send(Event::new_progression_event_update(
+                    event_id,
+                    format!(
+                        "Uploading to {}, {}",
+                        remote.name,
+                        format_byte_download(uploaded, total)
+                    ),
+                    (uploaded - previous) as f64,
+                ));
+            }
+        };
+        match upload_once(remote, local, dir, file_name, &on_progress).await {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < UPLOAD_ATTEMPTS => {
+                warn!(
+                    "Uploading to {} failed, retrying ({}/{}): {}",
+                    remote.name,
+                    attempt + 1,
+                    UPLOAD_ATTEMPTS,
+                    e
+                );
+                event_broadcaster.send(Event::new_progression_event_update(
+                    event_id,
+                    format!(
+                        "Uploading to {} failed, retrying ({}/{})",
+                        remote.name,
+                        attempt + 1,
+                        UPLOAD_ATTEMPTS
+                    ),
+                    0.0,
+                ));
+                tokio::time::sleep(Duration::from_secs(10 * attempt as u64)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn upload_once(
+    remote: &BackupRemote,
+    local: &Path,
+    dir: &str,
+    file_name: &str,
+    on_progress: &(dyn Fn(u64) + Send + Sync),
+) -> Result<(), Error> {
+    if let Some(bucket) = remote.destination.bucket() {
+        let prefix = match &remote.destination {
+            RemoteDestination::S3 { prefix, .. } if !prefix.is_empty() => {
+                format!("{}/", prefix.trim_matches('/'))
+            }
+            _ => String::new(),
+        };
+        return bucket
+            .upload(
+                local,
+                &format!("{}{}/{}", prefix, dir, file_name),
+                on_progress,
+            )
+            .await;
+    }
+    if let RemoteDestination::Local { path } = &remote.destination {
+        let dest_dir = Path::new(path).join(dir);
+        crate::util::fs::create_dir_all(&dest_dir).await?;
+        // copied under a temporary name so a half written archive is never mistaken
+        // for a backup
+        let partial = dest_dir.join(format!("{}.partial", file_name));
+        let copied = tokio::fs::copy(local, &partial)
+            .await
+            .context(format!("Failed to copy backup to {}", dest_dir.display()))?;
+        crate::util::fs::rename(&partial, &dest_dir.join(file_name)).await?;
+        on_progress(copied);
+        return Ok(());
+    }
+    let (mut cmd, stdin) = match remote.destination.upload_command(local, dir, file_name) {
+        Some(command) => command,
+        None => return Err(eyre!("No upload command for {}", remote.name).into()),
+    };
+    let mut child = dont_spawn_terminal(&mut cmd)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!(
+                        "{} is not installed on the host",
+                        remote.destination.program()
+                    ),
+                }
+            } else {
+                eyre!(e).wrap_err("Failed to run upload command").into()
+            }
+        })?;
+    if let (Some(batch), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+        child_stdin
+            .write_all(batch.as_bytes())
+            .await
+            .map_err(|e| eyre!(e).wrap_err("Failed to write to upload command"))?;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| eyre!(e).wrap_err("Failed to run upload command"))?;
+    if !output.status.success() {
+        return Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "Uploading to {} failed: {}",
+                remote.name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    on_progress(tokio::fs::metadata(local).await.map_or(0, |m| m.len()));
+    Ok(())
+}
+
+#[test]
+fn test_validate_remotes() {
+    let mut settings = BackupRemotesSettings {
+        remotes: vec![BackupRemote {
+            id: String::new(),
+            name: "offsite".to_string(),
+            destination: RemoteDestination::Rclone {
+                remote: "b2:bucket/lodestone".to_string(),
+            },
+        }],
+    };
+    settings.prepare(&BackupRemotesSettings::default()).unwrap();
+    assert_eq!(settings.remotes[0].id.len(), 8);
+
+    settings.remotes[0].destination = RemoteDestination::Rsync {
+        destination: "--rsync-path=evil".to_string(),
+        port: None,
+        identity_file: None,
+    };
+    assert!(settings.prepare(&BackupRemotesSettings::default()).is_err());
+    settings.remotes[0].destination = RemoteDestination::Sftp {
+        host: "backup.example.com".to_string(),
+        port: 22,
+        user: "lodestone".to_string(),
+        path: "/srv/backups".to_string(),
+        identity_file: Some("/home/lodestone/.ssh/id ed25519".to_string()),
+    };
+    assert!(settings.prepare(&BackupRemotesSettings::default()).is_err());
+
+    settings.remotes[0].destination = RemoteDestination::S3 {
+        endpoint: "http://minio.lan:9000".to_string(),
+        region: "us-east-1".to_string(),
+        bucket: "backups".to_string(),
+        prefix: String::new(),
+        access_key_id: "lodestone".to_string(),
+        secret_access_key: "hunter22".to_string(),
+        path_style: true,
+    };
+    settings.prepare(&BackupRemotesSettings::default()).unwrap();
+    let old = settings.clone();
+    let mut redacted = settings.redacted();
+    assert!(matches!(
+        &redacted.remotes[0].destination,
+        RemoteDestination::S3 { secret_access_key, .. } if secret_access_key.is_empty()
+    ));
+    // an empty secret is only accepted if one is stored already
+    assert!(redacted
+        .clone()
+        .prepare(&BackupRemotesSettings::default())
+        .is_err());
+    redacted.prepare(&old).unwrap();
+    assert_eq!(redacted, old);
+
+    settings.remotes[0].destination = RemoteDestination::Local {
+        path: "backups".to_string(),
+    };
+    assert!(settings.prepare(&BackupRemotesSettings::default()).is_err());
+}
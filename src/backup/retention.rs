@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+use super::BackupEntry;
+
+/// Which backups to keep, each rule keeping the newest backup of as many
+/// periods as it says, counting only periods that have a backup. A backup kept
+/// by any rule is kept, pinned ones are always kept and never counted. Periods
+/// are in UTC, weeks start on Monday.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct BackupRetention {
+    /// the newest backups regardless of when they were taken
+    #[serde(default)]
+    pub keep_last: u32,
+    #[serde(default)]
+    pub keep_daily: u32,
+    #[serde(default)]
+    pub keep_weekly: u32,
+    #[serde(default)]
+    pub keep_monthly: u32,
+}
+
+fn created_at(backup: &BackupEntry) -> DateTime<Utc> {
+    DateTime::from_utc(
+        NaiveDateTime::from_timestamp_opt(backup.created_at, 0).unwrap_or_default(),
+        Utc,
+    )
+}
+
+impl BackupRetention {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.keep_last + self.keep_daily + self.keep_weekly + self.keep_monthly == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A retention policy has to keep at least one backup"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Ids of the unpinned backups the policy keeps
+    pub fn kept(&self, backups: &[BackupEntry]) -> HashSet<String> {
+        let mut unpinned: Vec<&BackupEntry> =
+            backups.iter().filter(|backup| !backup.pinned).collect();
+        unpinned.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        let mut kept: HashSet<String> = unpinned
+            .iter()
+            .take(self.keep_last as usize)
+            .map(|backup| backup.id.clone())
+            .collect();
+        let rules: [(u32, &str); 3] = [
+            (self.keep_daily, "%Y-%m-%d"),
+            (self.keep_weekly, "%G-W%V"),
+            (self.keep_monthly, "%Y-%m"),
+        ];
+        for (count, period_format) in rules {
+            let mut periods = HashSet::new();
+            for backup in unpinned.iter() {
+                if periods.len() >= count as usize {
+                    break;
+                }
+                // the first backup seen of a period is its newest
+                if periods.insert(created_at(backup).format(period_format).to_string()) {
+                    kept.insert(backup.id.clone());
+                }
+            }
+        }
+        kept
+    }
+}
+
+#[test]
+fn test_retention() {
+    let backup = |id: &str, at: &str, pinned: bool| BackupEntry {
+        id: id.to_string(),
+        file_name: format!("{}.tar.gz", id),
+        created_at: DateTime::parse_from_rfc3339(at).unwrap().timestamp(),
+        size: 0,
+        label: None,
+        pinned,
+        created_by: None,
+        encrypted: false,
+        key_id: None,
+        uploaded_to: None,
+        snapshot: None,
+        incremental: false,
+    };
+    let backups = vec![
+        backup("jan", "2024-01-15T12:00:00Z", false),
+        backup("feb", "2024-02-10T12:00:00Z", false),
+        backup("mon-early", "2024-03-04T06:00:00Z", false),
+        backup("mon-late", "2024-03-04T18:00:00Z", false),
+        backup("tue", "2024-03-05T12:00:00Z", false),
+        backup("next-mon", "2024-03-11T12:00:00Z", false),
+        backup("pinned", "2024-03-11T13:00:00Z", true),
+    ];
+    let retention = BackupRetention {
+        keep_last: 1,
+        keep_daily: 3,
+        keep_weekly: 2,
+        keep_monthly: 3,
+    };
+    let mut kept: Vec<String> = retention.kept(&backups).into_iter().collect();
+    kept.sort();
+    assert_eq!(
+        kept,
+        vec!["feb", "jan", "mon-late", "next-mon", "tue"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+    );
+    assert!(BackupRetention::default().validate().is_err());
+}
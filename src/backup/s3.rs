@@ -0,0 +1,387 @@
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Context};
+use hmac::{Hmac, Mac};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tracing::warn;
+
+use crate::error::{Error, ErrorKind};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Parts of a multipart upload, S3 takes at most 10000 of them so this caps an
+/// archive at about 156 GiB
+const PART_SIZE: usize = 16 * 1024 * 1024;
+/// Attempts of every single request before the upload is given up on
+const REQUEST_ATTEMPTS: u32 = 4;
+
+/// An S3 compatible bucket, e.g. AWS, MinIO, Backblaze B2 or Cloudflare R2
+pub struct Bucket<'a> {
+    pub endpoint: &'a str,
+    pub region: &'a str,
+    pub bucket: &'a str,
+    pub access_key_id: &'a str,
+    pub secret_access_key: &'a str,
+    /// `endpoint/bucket/key` instead of `bucket.endpoint/key`, MinIO needs this
+    pub path_style: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but the characters unreserved by RFC 3986, as
+/// SigV4 expects
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                ret.push(b as char)
+            }
+            b'/' if !encode_slash => ret.push('/'),
+            _ => ret.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    ret
+}
+
+/// The text of the first `<tag>` in an XML response
+fn xml_tag<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = body[start..].find(&format!("</{}>", tag))? + start;
+    Some(&body[start..end])
+}
+
+impl Bucket<'_> {
+    pub fn validate(&self) -> Result<(), Error> {
+        let url = Url::parse(self.endpoint).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid S3 endpoint: {}", e),
+        })?;
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("S3 endpoint must be an http or https URL"),
+            });
+        }
+        if self.bucket.is_empty()
+            || !self
+                .bucket
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid bucket name: {:?}", self.bucket),
+            });
+        }
+        if self.region.trim().is_empty() || self.access_key_id.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("S3 region and access key id are required"),
+            });
+        }
+        Ok(())
+    }
+
+    fn url(&self, key: &str) -> Result<Url, Error> {
+        let mut url = Url::parse(self.endpoint).context("Invalid S3 endpoint")?;
+        let base = url.path().trim_end_matches('/').to_string();
+        if self.path_style {
+            url.set_path(&format!(
+                "{}/{}/{}",
+                base,
+                self.bucket,
+                uri_encode(key, false)
+            ));
+        } else {
+            let host = format!("{}.{}", self.bucket, url.host_str().unwrap_or_default());
+            url.set_host(Some(&host)).context("Invalid S3 endpoint")?;
+            url.set_path(&format!("{}/{}", base, uri_encode(key, false)));
+        }
+        Ok(url)
+    }
+
+    /// Signs and sends one request with AWS Signature Version 4, retrying it on
+    /// network errors and server side failures
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        method: Method,
+        key: &str,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response, Error> {
+        let mut url = self.url(key)?;
+        let mut query: Vec<(String, String)> = query
+            .iter()
+            .map(|(k, v)| (uri_encode(k, true), uri_encode(v, true)))
+            .collect();
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        url.set_query(if canonical_query.is_empty() {
+            None
+        } else {
+            Some(&canonical_query)
+        });
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let payload_hash = sha256_hex(&body);
+
+        let mut attempt = 1;
+        loop {
+            let now = Utc::now();
+            let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+            let date = now.format("%Y%m%d").to_string();
+            let canonical_request = format!(
+                "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+                method,
+                url.path(),
+                canonical_query,
+                host,
+                payload_hash,
+                amz_date,
+                payload_hash
+            );
+            let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                amz_date,
+                scope,
+                sha256_hex(canonical_request.as_bytes())
+            );
+            let signing_key = [self.region, "s3", "aws4_request"].iter().fold(
+                hmac(format!("AWS4{}", self.secret_access_key).as_bytes(), &date),
+                |key, part| hmac(&key, part),
+            );
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                self.access_key_id,
+                scope,
+                hex(&hmac(&signing_key, &string_to_sign))
+            );
+            let res = client
+                .request(method.clone(), url.clone())
+                .header("x-amz-date", &amz_date)
+                .header("x-amz-content-sha256", &payload_hash)
+                .header("authorization", authorization)
+                .body(body.clone())
+                .send()
+                .await;
+            let retryable = match res {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) => {
+                    let status = res.status();
+                    let body = res.text().await.unwrap_or_default();
+                    let message = xml_tag(&body, "Message")
+                        .or_else(|| xml_tag(&body, "Code"))
+                        .unwrap_or_else(|| status.canonical_reason().unwrap_or_default())
+                        .to_string();
+                    if !status.is_server_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+                    {
+                        return Err(Error {
+                            kind: ErrorKind::Internal,
+                            source: eyre!("S3 request failed with {}: {}", status, message),
+                        });
+                    }
+                    eyre!("S3 request failed with {}: {}", status, message)
+                }
+                Err(e) => eyre!(e).wrap_err("S3 request failed"),
+            };
+            if attempt >= REQUEST_ATTEMPTS {
+                return Err(retryable.into());
+            }
+            warn!(
+                "{}, retrying ({}/{})",
+                retryable,
+                attempt + 1,
+                REQUEST_ATTEMPTS
+            );
+            tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+            attempt += 1;
+        }
+    }
+
+    /// Uploads `local` to `key` in parts, calling `on_part` with the size of each
+    /// uploaded part. An unfinished upload is aborted so its parts aren't billed.
+    pub async fn upload(
+        &self,
+        local: &Path,
+        key: &str,
+        on_part: &(dyn Fn(u64) + Send + Sync),
+    ) -> Result<(), Error> {
+        let client = reqwest::Client::new();
+        let res = self
+            .send(&client, Method::POST, key, &[("uploads", "")], Vec::new())
+            .await?
+            .text()
+            .await
+            .context("Failed to read S3 response")?;
+        let upload_id = xml_tag(&res, "UploadId")
+            .ok_or_else(|| eyre!("S3 did not return an upload id"))?
+            .to_string();
+        let res = self
+            .upload_parts(&client, local, key, &upload_id, on_part)
+            .await;
+        if res.is_err() {
+            if let Err(e) = self
+                .send(
+                    &client,
+                    Method::DELETE,
+                    key,
+                    &[("uploadId", &upload_id)],
+                    Vec::new(),
+                )
+                .await
+            {
+                warn!("Failed to abort S3 upload of {}: {}", key, e);
+            }
+        }
+        res
+    }
+
+    async fn upload_parts(
+        &self,
+        client: &reqwest::Client,
+        local: &Path,
+        key: &str,
+        upload_id: &str,
+        on_part: &(dyn Fn(u64) + Send + Sync),
+    ) -> Result<(), Error> {
+        let mut file = tokio::fs::File::open(local)
+            .await
+            .context(format!("Failed to open {}", local.display()))?;
+        let mut etags = Vec::new();
+        loop {
+            let mut part = Vec::with_capacity(PART_SIZE);
+            (&mut file)
+                .take(PART_SIZE as u64)
+                .read_to_end(&mut part)
+                .await
+                .context(format!("Failed to read {}", local.display()))?;
+            // an empty file is still uploaded as one empty part
+            if part.is_empty() && !etags.is_empty() {
+                break;
+            }
+            let len = part.len() as u64;
+            let part_number = (etags.len() + 1).to_string();
+            let res = self
+                .send(
+                    client,
+                    Method::PUT,
+                    key,
+                    &[("partNumber", &part_number), ("uploadId", upload_id)],
+                    part,
+                )
+                .await?;
+            let etag = res
+                .headers()
+                .get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| eyre!("S3 did not return an ETag for part {}", part_number))?
+                .to_string();
+            etags.push(etag);
+            on_part(len);
+            if len < PART_SIZE as u64 {
+                break;
+            }
+        }
+        let complete = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            etags
+                .iter()
+                .enumerate()
+                .map(|(i, etag)| format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    i + 1,
+                    etag
+                ))
+                .collect::<String>()
+        );
+        let res = self
+            .send(
+                client,
+                Method::POST,
+                key,
+                &[("uploadId", upload_id)],
+                complete.into_bytes(),
+            )
+            .await?
+            .text()
+            .await
+            .context("Failed to read S3 response")?;
+        // completing can fail after S3 already answered 200
+        if res.contains("<Error>") {
+            return Err(eyre!(
+                "Failed to complete S3 upload: {}",
+                xml_tag(&res, "Message").unwrap_or(&res)
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_signing_helpers() {
+    assert_eq!(
+        uri_encode("lodestone/backup 1.tar.gz", false),
+        "lodestone/backup%201.tar.gz"
+    );
+    assert_eq!(uri_encode("a/b", true), "a%2Fb");
+    assert_eq!(
+        sha256_hex(b""),
+        "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+    );
+    assert_eq!(
+        xml_tag(
+            "<InitiateMultipartUploadResult><UploadId>abc</UploadId></InitiateMultipartUploadResult>",
+            "UploadId"
+        ),
+        Some("abc")
+    );
+
+    let bucket = Bucket {
+        endpoint: "http://localhost:9000",
+        region: "us-east-1",
+        bucket: "backups",
+        access_key_id: "minio",
+        secret_access_key: "minio123",
+        path_style: true,
+    };
+    bucket.validate().unwrap();
+    assert_eq!(
+        bucket.url("uuid/a b.tar.gz").unwrap().as_str(),
+        "http://localhost:9000/backups/uuid/a%20b.tar.gz"
+    );
+    let bucket = Bucket {
+        endpoint: "https://s3.eu-west-1.amazonaws.com",
+        path_style: false,
+        ..bucket
+    };
+    assert_eq!(
+        bucket.url("uuid/b.tar.gz").unwrap().as_str(),
+        "https://backups.s3.eu-west-1.amazonaws.com/uuid/b.tar.gz"
+    );
+}
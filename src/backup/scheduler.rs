@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use tokio::sync::Mutex;
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::events::CausedBy;
+use crate::global_settings::GlobalSettings;
+use crate::prelude::GameInstance;
+use crate::traits::t_backup::TBackup;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::{Capability, TInstance};
+use crate::types::InstanceUuid;
+
+use super::{BackupGuard, BackupMetadata};
+
+/// Schedules are checked once a minute, so a schedule firing more often than
+/// that still gets one backup per check
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When each instance's backup schedule was last checked
+#[derive(Default)]
+pub struct BackupScheduler {
+    last_checked: HashMap<InstanceUuid, DateTime<Utc>>,
+}
+
+impl BackupScheduler {
+    /// Whether `schedule` fired since the instance was last checked. The first
+    /// check only starts the clock, so a backup missed while the core was down
+    /// isn't taken the moment it comes back up.
+    pub fn is_due(&mut self, uuid: &InstanceUuid, schedule: &Schedule, now: DateTime<Utc>) -> bool {
+        match self.last_checked.insert(uuid.clone(), now) {
+            Some(last_checked) => schedule
+                .after(&last_checked)
+                .next()
+                .map_or(false, |next| next <= now),
+            None => false,
+        }
+    }
+
+    fn retain(&mut self, uuids: &[InstanceUuid]) {
+        self.last_checked.retain(|uuid, _| uuids.contains(uuid));
+    }
+}
+
+pub async fn backup_scheduler_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    scheduler: Arc<Mutex<BackupScheduler>>,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let instances: Vec<(InstanceUuid, GameInstance)> = instances
+            .lock()
+            .await
+            .iter()
+            .map(|(uuid, instance)| (uuid.clone(), instance.clone()))
+            .collect();
+        scheduler.lock().await.retain(
+            &instances
+                .iter()
+                .map(|(uuid, _)| uuid.clone())
+                .collect::<Vec<_>>(),
+        );
+        for (uuid, instance) in instances {
+            if instance
+                .capabilities()
+                .contains(&Capability::SupportsBackups)
+            {
+                // a busy instance gets pruned on a later check
+                if let Ok(guard) = BackupGuard::acquire(&uuid) {
+                    if let Err(e) = instance.prune_backups(guard).await {
+                        error!(
+                            "Failed to prune backups of {}: {}",
+                            instance.name().await,
+                            e
+                        );
+                    }
+                }
+            }
+            let schedule = match instance.backup_schedule().await {
+                Ok(Some(schedule)) => schedule,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!(
+                        "Failed to read backup schedule of {}: {}",
+                        instance.name().await,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if !scheduler.lock().await.is_due(&uuid, &schedule, now) {
+                continue;
+            }
+            let guard = match BackupGuard::acquire(&uuid) {
+                Ok(guard) => guard,
+                Err(_) => {
+                    warn!(
+                        "Skipping scheduled backup of {}, a backup or restore is in progress",
+                        instance.name().await
+                    );
+                    continue;
+                }
+            };
+            info!("Running scheduled backup for {}", instance.name().await);
+            let remotes = global_settings.lock().await.backup_remotes();
+            tokio::spawn(
+                async move {
+                    let _ = instance
+                        .create_backup(
+                            guard,
+                            BackupMetadata::default(),
+                            None,
+                            &remotes,
+                            CausedBy::System,
+                        )
+                        .await;
+                }
+                .instrument(info_span!("scheduled_backup", instance = %uuid)),
+            );
+        }
+    }
+}
+
+#[test]
+fn test_is_due() {
+    use std::str::FromStr;
+    let hourly = Schedule::from_str("0 0 * * * *").unwrap();
+    let uuid = InstanceUuid::default();
+    let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let mut scheduler = BackupScheduler::default();
+    assert!(!scheduler.is_due(&uuid, &hourly, at("2024-01-01T10:58:30Z")));
+    assert!(!scheduler.is_due(&uuid, &hourly, at("2024-01-01T10:59:30Z")));
+    assert!(scheduler.is_due(&uuid, &hourly, at("2024-01-01T11:00:30Z")));
+    assert!(!scheduler.is_due(&uuid, &hourly, at("2024-01-01T11:01:30Z")));
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::info;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::util::dont_spawn_terminal;
+
+use super::archive::ArchivedFile;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum SnapshotFs {
+    Zfs,
+    Btrfs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct SnapshotRef {
+    pub filesystem: SnapshotFs,
+    /// `dataset@snapshot` for ZFS, path of the read-only subvolume for btrfs
+    pub name: String,
+    /// where the snapshot's copy of the instance directory can be read
+    pub path: String,
+}
+
+/// Runs a command to completion, returning its stdout
+async fn run(program: &str, args: &[&str]) -> Result<String, Error> {
+    let output = dont_spawn_terminal(Command::new(program).args(args))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!("{} is not installed on the host", program),
+                }
+            } else {
+                eyre!(e)
+                    .wrap_err(format!("Failed to run {}", program))
+                    .into()
+            }
+        })?;
+    if !output.status.success() {
+        return Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "{} {} failed: {}",
+                program,
+                args.first().unwrap_or(&""),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The ZFS dataset `path` is in, as its name and mountpoint, from the output of
+/// `zfs list -H -o name,mountpoint`
+fn find_dataset(zfs_list: &str, path: &Path) -> Option<(String, PathBuf)> {
+    zfs_list
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, mountpoint)| (name.to_string(), PathBuf::from(mountpoint.trim())))
+        .filter(|(_, mountpoint)| mountpoint.is_absolute() && path.starts_with(mountpoint))
+        // the innermost dataset holds the files
+        .max_by_key(|(_, mountpoint)| mountpoint.components().count())
+}
+
+/// Snapshots the instance directory if it is on ZFS or is a btrfs subvolume,
+/// `None` if it is on any other filesystem. ZFS snapshots the whole dataset the
+/// instance is in, btrfs snapshots go into the instance's `backups` directory.
+pub async fn take_snapshot(
+    path_to_instance: &Path,
+    backups_dir: &Path,
+    id: &str,
+) -> Result<Option<SnapshotRef>, Error> {
+    let path = tokio::fs::canonicalize(path_to_instance)
+        .await
+        .context(format!("Failed to resolve {}", path_to_instance.display()))?;
+    let path_str = path.to_string_lossy().to_string();
+    // `stat -f` only exists on unix, anywhere else archives are used
+    let filesystem = match run("stat", &["-f", "-c", "%T", &path_str]).await {
+        Ok(filesystem) => filesystem.trim().to_string(),
+        Err(_) => return Ok(None),
+    };
+    let name = format!("lodestone-{}", id);
+    match filesystem.as_str() {
+        "zfs" => {
+            let datasets = run("zfs", &["list", "-H", "-o", "name,mountpoint"]).await?;
+            let (dataset, mountpoint) = find_dataset(&datasets, &path)
+                .ok_or_else(|| eyre!("No mounted ZFS dataset contains {}", path.display()))?;
+            let snapshot = format!("{}@{}", dataset, name);
+            run("zfs", &["snapshot", &snapshot]).await?;
+            let relative = path.strip_prefix(&mountpoint).unwrap_or(Path::new(""));
+            Ok(Some(SnapshotRef {
+                filesystem: SnapshotFs::Zfs,
+                name: snapshot,
+                path: mountpoint
+                    .join(".zfs/snapshot")
+                    .join(&name)
+                    .join(relative)
+                    .to_string_lossy()
+                    .to_string(),
+            }))
+        }
+        "btrfs" => {
+            if run("btrfs", &["subvolume", "show", &path_str])
+                .await
+                .is_err()
+            {
+                info!(
+                    "{} is not a btrfs subvolume, backing it up as an archive instead",
+                    path.display()
+                );
+                return Ok(None);
+            }
+            let dest = backups_dir.join(&name).to_string_lossy().to_string();
+            run("btrfs", &["subvolume", "snapshot", "-r", &path_str, &dest]).await?;
+            Ok(Some(SnapshotRef {
+                filesystem: SnapshotFs::Btrfs,
+                name: dest.clone(),
+                path: dest,
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub async fn delete_snapshot(snapshot: &SnapshotRef) -> Result<(), Error> {
+    match snapshot.filesystem {
+        SnapshotFs::Zfs => run("zfs", &["destroy", &snapshot.name]).await?,
+        SnapshotFs::Btrfs => run("btrfs", &["subvolume", "delete", &snapshot.name]).await?,
+    };
+    Ok(())
+}
+
+/// Writes a `zfs send` or `btrfs send` stream of the snapshot to `dest`, to be
+/// loaded elsewhere with `zfs receive` or `btrfs receive`
+pub async fn export_snapshot(snapshot: &SnapshotRef, dest: &Path) -> Result<(), Error> {
+    let dest = dest.to_string_lossy().to_string();
+    match snapshot.filesystem {
+        SnapshotFs::Zfs => {
+            let file =
+                std::fs::File::create(&dest).context(format!("Failed to create {}", dest))?;
+            let output = dont_spawn_terminal(Command::new("zfs").args(["send", &snapshot.name]))
+                .stdin(Stdio::null())
+                .stdout(Stdio::from(file))
+                .stderr(Stdio::piped())
+                .output()
+                .await
+                .context("Failed to run zfs send")?;
+            if !output.status.success() {
+                let _ = std::fs::remove_file(&dest);
+                return Err(Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!(
+                        "zfs send failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ),
+                });
+            }
+        }
+        SnapshotFs::Btrfs => {
+            run("btrfs", &["send", "-f", &dest, &snapshot.name]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Regular files of a snapshot by path, leaving out the top level entries in `exclude`
+pub fn list_files(
+    snapshot: &SnapshotRef,
+    exclude: &[&str],
+) -> Result<HashMap<String, ArchivedFile>, Error> {
+    let root = Path::new(&snapshot.path);
+    let mut ret = HashMap::new();
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1 || !exclude.iter().any(|name| entry.file_name() == *name)
+        });
+    for entry in walker {
+        let entry = entry.context(format!("Failed to read snapshot {}", snapshot.name))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let metadata = entry
+            .metadata()
+            .context(format!("Failed to read {}", entry.path().display()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        let path = entry
+            .path()
+            .strip_prefix(root)
+            .context("Entry outside of the snapshot")?
+            .to_string_lossy()
+            .replace('\\', "/");
+        ret.insert(
+            path,
+            ArchivedFile {
+                size: metadata.len(),
+                modified,
+            },
+        );
+    }
+    Ok(ret)
+}
+
+/// Copies the contents of a snapshot into `dest`, leaving out the top level
+/// entries in `exclude`
+pub fn copy_snapshot(snapshot: &SnapshotRef, dest: &Path, exclude: &[&str]) -> Result<(), Error> {
+    let root = Path::new(&snapshot.path);
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1 || !exclude.iter().any(|name| entry.file_name() == *name)
+        });
+    for entry in walker {
+        let entry = entry.context(format!("Failed to read snapshot {}", snapshot.name))?;
+        let target = dest.join(
+            entry
+                .path()
+                .strip_prefix(root)
+                .context("Entry outside of the snapshot")?,
+        );
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)
+                .context(format!("Failed to create {}", target.display()))?;
+        } else if entry.file_type().is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(
+                std::fs::read_link(entry.path())
+                    .context(format!("Failed to read link {}", entry.path().display()))?,
+                &target,
+            )
+            .context(format!("Failed to create link {}", target.display()))?;
+        } else {
+            std::fs::copy(entry.path(), &target)
+                .context(format!("Failed to copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_find_dataset() {
+    let zfs_list = "tank\t/tank\ntank/games\t/srv/games\ntank/games/lodestone\t/srv/games/lodestone\ntank/swap\tnone\n";
+    assert_eq!(
+        find_dataset(
+            zfs_list,
+            Path::new("/srv/games/lodestone/instances/survival")
+        ),
+        Some((
+            "tank/games/lodestone".to_string(),
+            PathBuf::from("/srv/games/lodestone")
+        ))
+    );
+    assert_eq!(find_dataset(zfs_list, Path::new("/srv/gamesaves")), None);
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_server::State;
+use crate::types::InstanceUuid;
+
+/// how long a badge is served from the cache, clients are told to cache it as long
+pub const BADGE_TTL_SECS: i64 = 60;
+
+/// Badges are public, so an instance only serves them once they're turned on
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct BadgeSettings {
+    pub enabled: bool,
+    /// shown on the left of the badge instead of the instance's name
+    pub label: Option<String>,
+}
+
+impl BadgeSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(label) = &self.label {
+            if label.is_empty() || label.chars().count() > 64 {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Badge label must be between 1 and 64 characters"),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_badges.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse badge settings at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_badges.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize badge settings")?,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadgeKind {
+    Status,
+    Players,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadgeFormat {
+    Svg,
+    Json,
+}
+
+/// Parses a badge file name like `status.svg` or `players.json`
+pub fn parse_badge_name(name: &str) -> Option<(BadgeKind, BadgeFormat)> {
+    let (kind, format) = name.split_once('.')?;
+    let kind = match kind {
+        "status" => BadgeKind::Status,
+        "players" => BadgeKind::Players,
+        _ => return None,
+    };
+    let format = match format {
+        "svg" => BadgeFormat::Svg,
+        "json" => BadgeFormat::Json,
+        _ => return None,
+    };
+    Some((kind, format))
+}
+
+/// What a badge shows of an instance, taken when the cache entry was filled
+#[derive(Debug, Clone, PartialEq)]
+pub struct BadgeSnapshot {
+    pub label: String,
+    pub state: State,
+    pub players: Option<u32>,
+    pub max_players: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct BadgeCache {
+    entries: HashMap<InstanceUuid, (i64, BadgeSnapshot)>,
+}
+
+impl BadgeCache {
+    pub fn get(&self, uuid: &InstanceUuid, now: i64) -> Option<BadgeSnapshot> {
+        self.entries
+            .get(uuid)
+            .filter(|(taken_at, _)| now - taken_at < BADGE_TTL_SECS)
+            .map(|(_, snapshot)| snapshot.clone())
+    }
+
+    pub fn insert(&mut self, uuid: InstanceUuid, now: i64, snapshot: BadgeSnapshot) {
+        self.entries
+            .retain(|_, (taken_at, _)| now - *taken_at < BADGE_TTL_SECS);
+        self.entries.insert(uuid, (now, snapshot));
+    }
+
+    pub fn remove(&mut self, uuid: &InstanceUuid) {
+        self.entries.remove(uuid);
+    }
+}
+
+/// Same fields as a shields.io endpoint badge, so it can be restyled there
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+#[ts(export)]
+pub struct BadgeContent {
+    pub schema_version: u32,
+    pub label: String,
+    pub message: String,
+    pub color: String,
+}
+
+impl BadgeContent {
+    pub fn new(snapshot: &BadgeSnapshot, kind: BadgeKind) -> Self {
+        let (message, color) = match kind {
+            BadgeKind::Status => match snapshot.state {
+                State::Running => ("online".to_string(), "#4c1"),
+                State::Starting => ("starting".to_string(), "#dfb317"),
+                State::Stopping => ("stopping".to_string(), "#dfb317"),
+                State::Stopped | State::Error => ("offline".to_string(), "#e05d44"),
+            },
+            BadgeKind::Players => match (snapshot.state, snapshot.players) {
+                (State::Running, Some(players)) => (
+                    match snapshot.max_players {
+                        Some(max_players) => format!("{}/{}", players, max_players),
+                        None => players.to_string(),
+                    },
+                    if players > 0 { "#4c1" } else { "#007ec6" },
+                ),
+                _ => ("offline".to_string(), "#9f9f9f"),
+            },
+        };
+        Self {
+            schema_version: 1,
+            label: match kind {
+                BadgeKind::Status => snapshot.label.clone(),
+                BadgeKind::Players => "players".to_string(),
+            },
+            message,
+            color: color.to_string(),
+        }
+    }
+
+    /// A flat badge in the shields.io style. Text widths are estimated, there's no
+    /// font to measure them with.
+    pub fn to_svg(&self) -> String {
+        let text_width = |text: &str| text.chars().count() as u32 * 7 + 10;
+        let label_width = text_width(&self.label);
+        let message_width = text_width(&self.message);
+        let width = label_width + message_width;
+        let label = escape_xml(&self.label);
+        let message = escape_xml(&self.message);
+        format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="20" role="img" aria-label="{label}: {message}"><title>{label}: {message}</title><linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient><clipPath id="r"><rect width="{width}" height="20" rx="3" fill="#fff"/></clipPath><g clip-path="url(#r)"><rect width="{label_width}" height="20" fill="#555"/><rect x="{label_width}" width="{message_width}" height="20" fill="{color}"/><rect width="{width}" height="20" fill="url(#s)"/></g><g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11"><text x="{label_x}" y="14">{label}</text><text x="{message_x}" y="14">{message}</text></g></svg>"##,
+            color = self.color,
+            label_x = label_width / 2,
+            message_x = label_width + message_width / 2,
+        )
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[test]
+fn test_badges() {
+    assert_eq!(
+        parse_badge_name("players.svg"),
+        Some((BadgeKind::Players, BadgeFormat::Svg))
+    );
+    assert_eq!(parse_badge_name("status.png"), None);
+
+    let snapshot = BadgeSnapshot {
+        label: "<SMP>".to_string(),
+        state: State::Running,
+        players: Some(3),
+        max_players: Some(20),
+    };
+    let status = BadgeContent::new(&snapshot, BadgeKind::Status);
+    assert_eq!(status.message, "online");
+    assert!(status.to_svg().contains(">&lt;SMP&gt;</text>"));
+    assert_eq!(
+        BadgeContent::new(&snapshot, BadgeKind::Players).message,
+        "3/20"
+    );
+
+    let mut cache = BadgeCache::default();
+    let uuid = InstanceUuid::from("a".to_string());
+    cache.insert(uuid.clone(), 0, snapshot.clone());
+    assert_eq!(cache.get(&uuid, BADGE_TTL_SECS - 1), Some(snapshot));
+    assert_eq!(cache.get(&uuid, BADGE_TTL_SECS), None);
+}
@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tracing::error;
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::db::state::StateLocation;
+use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
+use crate::player_sessions::PlayerSessionLog;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+/// `source` of the entries the core writes into an instance's ban files,
+/// entries with any other source are left alone
+const BAN_SOURCE: &str = "Lodestone";
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum BanTarget {
+    /// at least one of the two has to be set
+    Player {
+        name: Option<String>,
+        uuid: Option<String>,
+    },
+    Ip {
+        ip: IpAddr,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct GlobalBan {
+    pub id: String,
+    pub target: BanTarget,
+    pub reason: String,
+    pub created: i64,
+    /// permanent if `None`
+    pub expires: Option<i64>,
+    pub created_by: String,
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NewGlobalBan {
+    pub target: BanTarget,
+    pub reason: String,
+    pub expires: Option<i64>,
+}
+
+/// Mojang's API hands out uuids without dashes, ban files use them with dashes
+fn normalize_uuid(uuid: &str) -> String {
+    uuid.replace('-', "").to_lowercase()
+}
+
+fn hyphenate_uuid(uuid: &str) -> Option<String> {
+    let uuid = normalize_uuid(uuid);
+    if uuid.len() != 32 || !uuid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &uuid[0..8],
+        &uuid[8..12],
+        &uuid[12..16],
+        &uuid[16..20],
+        &uuid[20..32]
+    ))
+}
+
+/// The date format Minecraft uses in its ban files
+fn format_ban_date(timestamp: i64) -> String {
+    match chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0) {
+        Some(date) => date.format("%Y-%m-%d %H:%M:%S +0000").to_string(),
+        None => "forever".to_string(),
+    }
+}
+
+impl GlobalBan {
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires.map_or(true, |expires| expires > now)
+    }
+
+    pub fn matches(&self, name: &str, uuid: Option<&str>, ip: Option<IpAddr>) -> bool {
+        match &self.target {
+            BanTarget::Player {
+                name: ban_name,
+                uuid: ban_uuid,
+            } => {
+                let uuid_matches = match (ban_uuid, uuid) {
+                    (Some(ban_uuid), Some(uuid)) => {
+                        normalize_uuid(ban_uuid) == normalize_uuid(uuid)
+                    }
+                    _ => false,
+                };
+                uuid_matches
+                    || ban_name
+                        .as_ref()
+                        .map_or(false, |ban_name| ban_name.eq_ignore_ascii_case(name))
+            }
+            BanTarget::Ip { ip: ban_ip } => ip == Some(*ban_ip),
+        }
+    }
+
+    fn file_entry(&self) -> Option<(&'static str, Value)> {
+        let expires = match self.expires {
+            Some(expires) => format_ban_date(expires),
+            None => "forever".to_string(),
+        };
+        let mut entry = json!({
+            "created": format_ban_date(self.created),
+            "source": BAN_SOURCE,
+            "expires": expires,
+            "reason": self.reason,
+        });
+        match &self.target {
+            // the server ignores player entries without a uuid
+            BanTarget::Player { name, uuid } => {
+                entry["uuid"] = json!(hyphenate_uuid(uuid.as_ref()?)?);
+                entry["name"] = json!(name.clone().unwrap_or_default());
+                Some(("banned-players.json", entry))
+            }
+            BanTarget::Ip { ip } => {
+                entry["ip"] = json!(ip.to_string());
+                Some(("banned-ips.json", entry))
+            }
+        }
+    }
+}
+
+impl NewGlobalBan {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let BanTarget::Player {
+            name: None,
+            uuid: None,
+        } = self.target
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A player ban needs a name or uuid"),
+            });
+        }
+        // the reason is passed to the kick command verbatim
+        if self.reason.contains(['\n', '\r']) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Ban reason must be a single line"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Writes the active global bans into the instance's `banned-players.json` and
+/// `banned-ips.json`, replacing the ones written previously. The server only reads
+/// these on startup, so this is meant for stopped instances.
+pub async fn sync_ban_files(path_to_instance: &Path, bans: &[GlobalBan]) -> Result<(), Error> {
+    let mut entries: HashMap<&'static str, Vec<Value>> = HashMap::new();
+    for (file, entry) in bans.iter().filter_map(|ban| ban.file_entry()) {
+        entries.entry(file).or_default().push(entry);
+    }
+    for file in ["banned-players.json", "banned-ips.json"] {
+        let path = path_to_instance.join(file);
+        let mut existing: Vec<Value> = if path.is_file() {
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse {}", path.display()))?
+        } else {
+            Vec::new()
+        };
+        existing.retain(|entry| entry["source"] != BAN_SOURCE);
+        existing.extend(entries.remove(file).unwrap_or_default());
+        crate::util::fs::write_all(
+            &path,
+            serde_json::to_string_pretty(&existing).context("Failed to serialize ban list")?,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Syncs the ban files of stopped Minecraft instances and kicks players of running ones
+/// that `bans` apply to, going by their open sessions
+pub async fn apply_bans(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    bans: &[GlobalBan],
+) {
+    let minecraft_instances: Vec<(InstanceUuid, GameInstance)> = instances
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, instance)| matches!(instance, GameInstance::MinecraftInstance(_)))
+        .map(|(uuid, instance)| (uuid.clone(), instance.clone()))
+        .collect();
+    for (uuid, instance) in minecraft_instances {
+        let path = instance.path().await;
+        match instance.state().await {
+            State::Stopped | State::Error => {
+                if let Err(e) = sync_ban_files(&path, bans).await {
+                    error!("Failed to sync ban files of instance {}: {}", uuid, e);
+                }
+            }
+            State::Running => {
+                let log = match PlayerSessionLog::load(&path).await {
+                    Ok(log) => log,
+                    Err(e) => {
+                        error!("Failed to load player sessions of instance {}: {}", uuid, e);
+                        continue;
+                    }
+                };
+                for session in log.sessions.iter().filter(|s| s.left_at.is_none()) {
+                    if let Some(ban) = bans.iter().find(|ban| {
+                        ban.matches(
+                            &session.player_name,
+                            session.player_uuid.as_deref(),
+                            session.ip,
+                        )
+                    }) {
+                        let _ = instance
+                            .send_command(
+                                &command_template::kick(&session.player_name, &ban.reason),
+                                CausedBy::System,
+                            )
+                            .await;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Removes a lifted ban from the ban lists running servers loaded on startup
+pub async fn pardon_ban(instances: &Mutex<HashMap<InstanceUuid, GameInstance>>, ban: &GlobalBan) {
+    let command = match &ban.target {
+        BanTarget::Player {
+            name: Some(name), ..
+        } => format!("pardon {}", name),
+        BanTarget::Player { name: None, .. } => return,
+        BanTarget::Ip { ip } => format!("pardon-ip {}", ip),
+    };
+    let minecraft_instances: Vec<GameInstance> = instances
+        .lock()
+        .await
+        .values()
+        .filter(|instance| matches!(instance, GameInstance::MinecraftInstance(_)))
+        .cloned()
+        .collect();
+    for instance in minecraft_instances {
+        if instance.state().await == State::Running {
+            let _ = instance.send_command(&command, CausedBy::System).await;
+        }
+    }
+}
+
+pub struct BanListManager {
+    location: StateLocation,
+    bans: HashMap<String, GlobalBan>,
+}
+
+impl BanListManager {
+    pub fn new(location: impl Into<StateLocation>) -> Self {
+        Self {
+            location: location.into(),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub async fn load_from_file(&mut self) -> Result<(), Error> {
+        self.bans = self.location.read().await?.unwrap_or_default();
+        Ok(())
+    }
+
+    async fn write_to_file(&self) -> Result<(), Error> {
+        self.location.write(&self.bans).await
+    }
+
+    /// Bans that haven't expired, newest first
+    pub fn list(&self) -> Vec<GlobalBan> {
+        let now = chrono::Utc::now().timestamp();
+        let mut bans: Vec<GlobalBan> = self
+            .bans
+            .values()
+            .filter(|ban| ban.is_active(now))
+            .cloned()
+            .collect();
+        bans.sort_by(|a, b| b.created.cmp(&a.created));
+        bans
+    }
+
+    pub fn find_ban(
+        &self,
+        name: &str,
+        uuid: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Option<GlobalBan> {
+        let now = chrono::Utc::now().timestamp();
+        self.bans
+            .values()
+            .find(|ban| ban.is_active(now) && ban.matches(name, uuid, ip))
+            .cloned()
+    }
+
+    pub async fn add_ban(&mut self, ban: GlobalBan) -> Result<GlobalBan, Error> {
+        self.bans.insert(ban.id.clone(), ban.clone());
+        if let Err(e) = self.write_to_file().await {
+            self.bans.remove(&ban.id);
+            return Err(e);
+        }
+        Ok(ban)
+    }
+
+    pub async fn remove_ban(&mut self, id: &str) -> Result<GlobalBan, Error> {
+        let ban = self.bans.remove(id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Ban not found"),
+        })?;
+        if let Err(e) = self.write_to_file().await {
+            self.bans.insert(ban.id.clone(), ban);
+            return Err(e);
+        }
+        Ok(ban)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(target: BanTarget, expires: Option<i64>) -> GlobalBan {
+        GlobalBan {
+            id: "1".to_string(),
+            target,
+            reason: "griefing".to_string(),
+            created: 0,
+            expires,
+            created_by: "owner".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_ban_matches() {
+        let player_ban = ban(
+            BanTarget::Player {
+                name: Some("Steve".to_string()),
+                uuid: Some("069a79f4-44e9-4726-a5be-fca90e38aaf5".to_string()),
+            },
+            None,
+        );
+        assert!(player_ban.matches("steve", None, None));
+        assert!(player_ban.matches("Renamed", Some("069a79f444e94726a5befca90e38aaf5"), None));
+        assert!(!player_ban.matches("Alex", None, None));
+        let ip_ban = ban(
+            BanTarget::Ip {
+                ip: "10.0.0.1".parse().unwrap(),
+            },
+            Some(100),
+        );
+        assert!(ip_ban.matches("Alex", None, Some("10.0.0.1".parse().unwrap())));
+        assert!(ip_ban.is_active(99));
+        assert!(!ip_ban.is_active(100));
+    }
+
+    #[tokio::test]
+    async fn test_sync_ban_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            temp_dir.path().join("banned-players.json"),
+            r#"[{"uuid": "00000000-0000-0000-0000-000000000001", "name": "Local", "created": "2023-01-01 00:00:00 +0000", "source": "Server", "expires": "forever", "reason": "local"},
+                {"uuid": "00000000-0000-0000-0000-000000000002", "name": "Stale", "created": "2023-01-01 00:00:00 +0000", "source": "Lodestone", "expires": "forever", "reason": "lifted"}]"#,
+        )
+        .unwrap();
+        let bans = vec![
+            ban(
+                BanTarget::Player {
+                    name: Some("Steve".to_string()),
+                    uuid: Some("069a79f444e94726a5befca90e38aaf5".to_string()),
+                },
+                None,
+            ),
+            ban(
+                BanTarget::Ip {
+                    ip: "10.0.0.1".parse().unwrap(),
+                },
+                Some(86400),
+            ),
+        ];
+        sync_ban_files(temp_dir.path(), &bans).await.unwrap();
+
+        let players: Vec<Value> = serde_json::from_str(
+            &std::fs::read_to_string(temp_dir.path().join("banned-players.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(players.len(), 2);
+        assert_eq!(players[0]["name"], "Local");
+        assert_eq!(players[1]["uuid"], "069a79f4-44e9-4726-a5be-fca90e38aaf5");
+        let ips: Vec<Value> = serde_json::from_str(
+            &std::fs::read_to_string(temp_dir.path().join("banned-ips.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(ips[0]["ip"], "10.0.0.1");
+        assert_eq!(ips[0]["expires"], "1970-01-02 00:00:00 +0000");
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+
+/// cgroups v2 are mounted here on every distro running systemd
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CPU_PERIOD_USEC: u64 = 100_000;
+/// room the JVM needs on top of the heap for metaspace, threads and buffers
+const JVM_OVERHEAD_MB: u32 = 256;
+
+/// Caps on what an instance's process may use. Enforced with cgroups v2 on Linux,
+/// stored but ignored elsewhere.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, Default, PartialEq)]
+#[ts(export)]
+pub struct ResourceLimits {
+    /// in CPU cores, 1.5 is one and a half cores. `None` for no limit
+    pub cpu_limit: Option<f32>,
+    /// in MB for the whole process, not just the Java heap. `None` for no limit
+    pub memory_limit: Option<u32>,
+}
+
+impl ResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.cpu_limit.is_none() && self.memory_limit.is_none()
+    }
+
+    /// `max_ram` is the heap size in MB the instance starts with, if it has one
+    pub fn validate(&self, max_ram: Option<u32>) -> Result<(), Error> {
+        if let Some(cpu_limit) = self.cpu_limit {
+            if !(0.1..=1024.0).contains(&cpu_limit) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("CPU limit must be between 0.1 and 1024 cores"),
+                });
+            }
+        }
+        if let (Some(memory_limit), Some(max_ram)) = (self.memory_limit, max_ram) {
+            if memory_limit < max_ram + JVM_OVERHEAD_MB {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Memory limit must be at least {} MB, the maximum heap plus {} MB for the JVM",
+                        max_ram + JVM_OVERHEAD_MB,
+                        JVM_OVERHEAD_MB
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn cpu_max(&self) -> String {
+        match self.cpu_limit {
+            Some(cores) => format!(
+                "{} {}",
+                (cores as f64 * CPU_PERIOD_USEC as f64) as u64,
+                CPU_PERIOD_USEC
+            ),
+            None => format!("max {}", CPU_PERIOD_USEC),
+        }
+    }
+
+    fn memory_max(&self) -> String {
+        match self.memory_limit {
+            Some(mb) => (mb as u64 * 1024 * 1024).to_string(),
+            None => "max".to_string(),
+        }
+    }
+}
+
+/// What the kernel reports for an instance's cgroup
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CgroupUsage {
+    /// in bytes
+    pub memory_current: Option<u64>,
+    /// how long the process was held back by the CPU limit, in microseconds
+    pub throttled_usec: Option<u64>,
+}
+
+fn cgroup_dir(uuid: &InstanceUuid) -> PathBuf {
+    PathBuf::from(CGROUP_ROOT)
+        .join("lodestone")
+        .join(uuid.as_ref())
+}
+
+/// Whether limits can be enforced on this host
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+        && PathBuf::from(CGROUP_ROOT)
+            .join("cgroup.controllers")
+            .is_file()
+}
+
+/// Moves the process `pid` into the instance's cgroup with `limits` applied. The
+/// core needs write access to the cgroup hierarchy, usually by running as root.
+pub fn apply(uuid: &InstanceUuid, pid: u32, limits: &ResourceLimits) -> Result<(), Error> {
+    if !is_supported() {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Resource limits need cgroups v2, which this host doesn't have"),
+        });
+    }
+    let write = |path: PathBuf, value: &str| {
+        std::fs::write(&path, value).map_err(|e| Error {
+            kind: ErrorKind::Internal,
+            source: eyre!("Failed to write {} to {}: {}", value, path.display(), e),
+        })
+    };
+    let root = PathBuf::from(CGROUP_ROOT);
+    let dir = cgroup_dir(uuid);
+    std::fs::create_dir_all(&dir).map_err(|e| Error {
+        kind: ErrorKind::Internal,
+        source: eyre!("Failed to create cgroup at {}: {}", dir.display(), e),
+    })?;
+    // controllers have to be handed down level by level
+    write(root.join("cgroup.subtree_control"), "+cpu +memory")?;
+    write(
+        root.join("lodestone").join("cgroup.subtree_control"),
+        "+cpu +memory",
+    )?;
+    write(dir.join("cpu.max"), &limits.cpu_max())?;
+    write(dir.join("memory.max"), &limits.memory_max())?;
+    write(dir.join("cgroup.procs"), &pid.to_string())
+}
+
+pub fn usage(uuid: &InstanceUuid) -> CgroupUsage {
+    let dir = cgroup_dir(uuid);
+    let memory_current = std::fs::read_to_string(dir.join("memory.current"))
+        .ok()
+        .and_then(|s| s.trim().parse().ok());
+    let throttled_usec = std::fs::read_to_string(dir.join("cpu.stat"))
+        .ok()
+        .and_then(|stat| parse_throttled_usec(&stat));
+    CgroupUsage {
+        memory_current,
+        throttled_usec,
+    }
+}
+
+fn parse_throttled_usec(cpu_stat: &str) -> Option<u64> {
+    cpu_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("throttled_usec "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+#[test]
+fn test_resource_limits() {
+    let limits = ResourceLimits {
+        cpu_limit: Some(1.5),
+        memory_limit: Some(4096),
+    };
+    assert_eq!(limits.cpu_max(), "150000 100000");
+    assert_eq!(limits.memory_max(), "4294967296");
+    assert!(limits.validate(Some(2048)).is_ok());
+    assert!(limits.validate(Some(4000)).is_err());
+    assert_eq!(ResourceLimits::default().cpu_max(), "max 100000");
+    assert!(ResourceLimits {
+        cpu_limit: Some(0.0),
+        memory_limit: None
+    }
+    .validate(None)
+    .is_err());
+
+    assert_eq!(
+        parse_throttled_usec("usage_usec 100\nnr_throttled 2\nthrottled_usec 4200\n"),
+        Some(4200)
+    );
+}
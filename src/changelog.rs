@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use ts_rs::TS;
+
+use crate::{
+    auth::user_id::UserId,
+    error::{Error, ErrorKind},
+    types::Snowflake,
+};
+
+lazy_static::lazy_static! {
+    /// One lock per instance directory, held by every loaded `Changelog`
+    static ref CHANGELOG_LOCKS: std::sync::Mutex<HashMap<PathBuf, Arc<Mutex<()>>>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ChangelogEntry {
+    pub id: Snowflake,
+    pub title: String,
+    /// markdown
+    pub body: String,
+    pub time: i64,
+    pub author_id: UserId,
+    pub author_name: String,
+    /// announce the entry in-game the next time the instance starts
+    pub broadcast_on_start: bool,
+    pub broadcasted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct NewChangelogEntry {
+    pub title: String,
+    pub body: String,
+    pub time: Option<i64>,
+    #[serde(default)]
+    pub broadcast_on_start: bool,
+}
+
+impl NewChangelogEntry {
+    /// The title is announced in-game with `say`, where a newline would end the
+    /// command and run the rest as another one
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.title.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Changelog entry title cannot be empty"),
+            });
+        }
+        if self.title.chars().any(|c| c.is_control()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Changelog entry title must be a single line"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Changelog of an instance, persisted in the instance directory.
+///
+/// Entries are kept sorted by time, newest first. The changelog of an instance can
+/// only be loaded once at a time, so concurrent updates don't overwrite each other.
+pub struct Changelog {
+    path_to_file: PathBuf,
+    entries: Vec<ChangelogEntry>,
+    _guard: OwnedMutexGuard<()>,
+}
+
+impl Changelog {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let lock = CHANGELOG_LOCKS
+            .lock()
+            .unwrap()
+            .entry(path_to_instance.to_owned())
+            .or_default()
+            .clone();
+        let _guard = lock.lock_owned().await;
+        let path_to_file = path_to_instance.join(".lodestone_changelog.json");
+        let entries = if path_to_file.is_file() {
+            serde_json::from_str(&crate::util::fs::read_to_string(&path_to_file).await?).context(
+                format!("Failed to parse changelog at {}", path_to_file.display()),
+            )?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path_to_file,
+            entries,
+            _guard,
+        })
+    }
+
+    async fn write_to_file(&self) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            &self.path_to_file,
+            serde_json::to_string_pretty(&self.entries).context("Failed to serialize changelog")?,
+        )
+        .await
+    }
+
+    pub fn entries(&self) -> &[ChangelogEntry] {
+        &self.entries
+    }
+
+    pub async fn add_entry(&mut self, entry: ChangelogEntry) -> Result<(), Error> {
+        let idx = self
+            .entries
+            .iter()
+            .position(|e| e.time < entry.time)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(idx, entry);
+        self.write_to_file().await
+    }
+
+    /// Returns `None` if no entry with the given id exists
+    pub async fn remove_entry(&mut self, id: &Snowflake) -> Result<Option<ChangelogEntry>, Error> {
+        let idx = match self.entries.iter().position(|e| &e.id == id) {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let removed = self.entries.remove(idx);
+        self.write_to_file().await?;
+        Ok(Some(removed))
+    }
+
+    /// Marks all pending announcements as broadcasted and returns them, oldest first
+    pub async fn take_pending_announcements(&mut self) -> Result<Vec<ChangelogEntry>, Error> {
+        let mut pending = Vec::new();
+        for entry in self.entries.iter_mut().rev() {
+            if entry.broadcast_on_start && !entry.broadcasted {
+                entry.broadcasted = true;
+                pending.push(entry.clone());
+            }
+        }
+        if !pending.is_empty() {
+            self.write_to_file().await?;
+        }
+        Ok(pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(time: i64, broadcast_on_start: bool) -> ChangelogEntry {
+        ChangelogEntry {
+            id: Snowflake::new(),
+            title: format!("entry {}", time),
+            body: "".to_string(),
+            time,
+            author_id: UserId::from("owner".to_string()),
+            author_name: "owner".to_string(),
+            broadcast_on_start,
+            broadcasted: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changelog() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut changelog = Changelog::load(temp_dir.path()).await.unwrap();
+        changelog.add_entry(entry(2, true)).await.unwrap();
+        changelog.add_entry(entry(1, true)).await.unwrap();
+        changelog.add_entry(entry(3, false)).await.unwrap();
+        let times: Vec<i64> = changelog.entries().iter().map(|e| e.time).collect();
+        assert_eq!(times, vec![3, 2, 1]);
+
+        drop(changelog);
+        let mut changelog = Changelog::load(temp_dir.path()).await.unwrap();
+        assert_eq!(changelog.entries().len(), 3);
+        let pending = changelog.take_pending_announcements().await.unwrap();
+        let times: Vec<i64> = pending.iter().map(|e| e.time).collect();
+        assert_eq!(times, vec![1, 2]);
+        assert!(changelog
+            .take_pending_announcements()
+            .await
+            .unwrap()
+            .is_empty());
+
+        let id = changelog.entries()[0].id;
+        assert!(changelog.remove_entry(&id).await.unwrap().is_some());
+        assert!(changelog.remove_entry(&id).await.unwrap().is_none());
+        drop(changelog);
+        assert_eq!(
+            Changelog::load(temp_dir.path())
+                .await
+                .unwrap()
+                .entries()
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_changelog_updates_are_serialized() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().to_owned();
+        let tasks: Vec<_> = (0..10)
+            .map(|time| {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    let mut changelog = Changelog::load(&path).await.unwrap();
+                    tokio::task::yield_now().await;
+                    changelog.add_entry(entry(time, false)).await.unwrap();
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(Changelog::load(&path).await.unwrap().entries().len(), 10);
+    }
+
+    #[test]
+    fn test_new_entry_validation() {
+        let new_entry = |title: &str| NewChangelogEntry {
+            title: title.to_string(),
+            body: String::new(),
+            time: None,
+            broadcast_on_start: true,
+        };
+        assert!(new_entry("Updated to 1.21").validate().is_ok());
+        assert!(new_entry("  ").validate().is_err());
+        assert!(new_entry("Updated\nop attacker").validate().is_err());
+        assert!(new_entry("Updated\rop attacker").validate().is_err());
+    }
+}
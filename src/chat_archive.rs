@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::types::InstanceUuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ChatMessage {
+    pub timestamp: i64,
+    pub player: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ChatArchiveConfig {
+    pub enabled: bool,
+    /// days of chat kept, older days are deleted
+    pub retention_days: u32,
+}
+
+impl Default for ChatArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: 30,
+        }
+    }
+}
+
+impl ChatArchiveConfig {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_chat_archive.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse chat archive config at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_chat_archive.json"),
+            serde_json::to_string_pretty(self)
+                .context("Failed to serialize chat archive config")?,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ChatSearchQuery {
+    /// case-insensitive
+    pub player: Option<String>,
+    /// regex matched against the message
+    pub pattern: Option<String>,
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+pub fn path_to_chat_logs(path_to_instance: &Path) -> PathBuf {
+    path_to_instance.join("chat_logs")
+}
+
+/// One JSON lines file per UTC day, named after the day so retention and
+/// time range searches don't need to open every file
+fn day_of(timestamp: i64) -> Option<chrono::NaiveDate> {
+    chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0).map(|t| t.date())
+}
+
+fn day_of_file(path: &Path) -> Option<chrono::NaiveDate> {
+    let stem = path.file_stem()?.to_str()?;
+    chrono::NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok()
+}
+
+pub async fn append_message(path_to_instance: &Path, message: &ChatMessage) -> Result<(), Error> {
+    let day = day_of(message.timestamp).ok_or_else(|| eyre!("Invalid timestamp"))?;
+    let dir = path_to_chat_logs(path_to_instance);
+    crate::util::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("{}.jsonl", day.format("%Y-%m-%d")));
+    let mut line = serde_json::to_string(message).context("Failed to serialize chat message")?;
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .context(format!("Failed to open chat log {}", path.display()))?;
+    file.write_all(line.as_bytes())
+        .await
+        .context(format!("Failed to write chat log {}", path.display()))?;
+    Ok(())
+}
+
+/// Chat log files sorted oldest first
+fn list_chat_logs(path_to_instance: &Path) -> Vec<(chrono::NaiveDate, PathBuf)> {
+    let mut files: Vec<(chrono::NaiveDate, PathBuf)> =
+        match std::fs::read_dir(path_to_chat_logs(path_to_instance)) {
+            Ok(entries) => entries
+                .filter_map(|entry| {
+                    let path = entry.ok()?.path();
+                    Some((day_of_file(&path)?, path))
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+    files.sort();
+    files
+}
+
+pub async fn prune_chat_logs(
+    path_to_instance: &Path,
+    retention_days: u32,
+    now: i64,
+) -> Result<(), Error> {
+    let today = day_of(now).ok_or_else(|| eyre!("Invalid timestamp"))?;
+    let oldest_kept = today - chrono::Duration::days(retention_days as i64);
+    for (day, path) in list_chat_logs(path_to_instance) {
+        if day < oldest_kept {
+            crate::util::fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Matching messages, oldest first. With a limit, the most recent ones are kept.
+pub async fn search_chat(
+    path_to_instance: &Path,
+    query: &ChatSearchQuery,
+) -> Result<Vec<ChatMessage>, Error> {
+    let pattern = match &query.pattern {
+        Some(pattern) => Some(regex::Regex::new(pattern).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid pattern: {}", e),
+        })?),
+        None => None,
+    };
+    let start_day = query.start.and_then(day_of);
+    let end_day = query.end.and_then(day_of);
+    let mut messages = Vec::new();
+    for (day, path) in list_chat_logs(path_to_instance) {
+        if start_day.map_or(false, |start| day < start) || end_day.map_or(false, |end| day > end) {
+            continue;
+        }
+        let contents = crate::util::fs::read_to_string(&path).await?;
+        for line in contents.lines() {
+            let message: ChatMessage = match serde_json::from_str(line) {
+                Ok(message) => message,
+                // a partially written line from a crash
+                Err(_) => continue,
+            };
+            if query.start.map_or(false, |start| message.timestamp < start)
+                || query.end.map_or(false, |end| message.timestamp > end)
+            {
+                continue;
+            }
+            if let Some(player) = &query.player {
+                if !message.player.eq_ignore_ascii_case(player) {
+                    continue;
+                }
+            }
+            if let Some(pattern) = &pattern {
+                if !pattern.is_match(&message.message) {
+                    continue;
+                }
+            }
+            messages.push(message);
+        }
+    }
+    if let Some(limit) = query.limit {
+        if messages.len() > limit {
+            messages.drain(..messages.len() - limit);
+        }
+    }
+    Ok(messages)
+}
+
+/// Archives player chat of every instance and applies each instance's retention hourly
+pub async fn chat_archive_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut prune_interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            event = event_receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Chat archive task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let (uuid, player, message) = match event.event_inner {
+                    EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid,
+                        instance_event_inner:
+                            InstanceEventInner::PlayerMessage {
+                                player,
+                                player_message,
+                            },
+                        ..
+                    }) => (instance_uuid, player, player_message),
+                    _ => continue,
+                };
+                let path = match instances.lock().await.get(&uuid) {
+                    Some(instance) => instance.path().await,
+                    None => continue,
+                };
+                let result = match ChatArchiveConfig::load(&path).await {
+                    Ok(config) if config.enabled => {
+                        append_message(
+                            &path,
+                            &ChatMessage {
+                                timestamp: chrono::Utc::now().timestamp(),
+                                player,
+                                message,
+                            },
+                        )
+                        .await
+                    }
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("Failed to archive chat of instance {}: {}", uuid, e);
+                }
+            }
+            _ = prune_interval.tick() => {
+                let mut paths = Vec::new();
+                for (uuid, instance) in instances.lock().await.iter() {
+                    paths.push((uuid.clone(), instance.path().await));
+                }
+                let now = chrono::Utc::now().timestamp();
+                for (uuid, path) in paths {
+                    let result = match ChatArchiveConfig::load(&path).await {
+                        Ok(config) => prune_chat_logs(&path, config.retention_days, now).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = result {
+                        error!("Failed to prune chat logs of instance {}: {}", uuid, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chat_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let day = 24 * 60 * 60;
+        for (timestamp, player, message) in [
+            (10, "Steve", "hello"),
+            (day + 10, "Alex", "buy cheap gold at example.com"),
+            (2 * day + 10, "Steve", "who griefed my house"),
+            (2 * day + 20, "Alex", "not me"),
+        ] {
+            append_message(
+                temp_dir.path(),
+                &ChatMessage {
+                    timestamp,
+                    player: player.to_string(),
+                    message: message.to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let all = search_chat(temp_dir.path(), &ChatSearchQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 4);
+        let steve = ChatSearchQuery {
+            player: Some("steve".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(search_chat(temp_dir.path(), &steve).await.unwrap().len(), 2);
+        let spam = ChatSearchQuery {
+            pattern: Some(r"\w+\.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            search_chat(temp_dir.path(), &spam).await.unwrap()[0].player,
+            "Alex"
+        );
+        let recent = ChatSearchQuery {
+            start: Some(day),
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            search_chat(temp_dir.path(), &recent).await.unwrap()[0].message,
+            "not me"
+        );
+
+        prune_chat_logs(temp_dir.path(), 1, 2 * day + 30)
+            .await
+            .unwrap();
+        let kept = search_chat(temp_dir.path(), &ChatSearchQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(kept.len(), 3);
+        assert!(search_chat(
+            temp_dir.path(),
+            &ChatSearchQuery {
+                pattern: Some("(".to_string()),
+                ..Default::default()
+            }
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::TServer;
+use crate::types::InstanceUuid;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum ChatPattern {
+    Regex {
+        pattern: String,
+    },
+    /// whole words, case-insensitive
+    Words {
+        words: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum ChatRuleAction {
+    /// warning in the instance's event stream
+    Warn,
+    Kick {
+        message: String,
+    },
+    /// runs `mute <player> <duration>s`, needs a plugin providing it, e.g. EssentialsX
+    Mute {
+        duration_secs: u32,
+    },
+    /// any console command, `{player}` and `{message}` are substituted.
+    /// Useful for plugins that can delete chat messages.
+    Command {
+        command: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ChatRule {
+    /// assigned by the core
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub enabled: bool,
+    pub pattern: ChatPattern,
+    pub actions: Vec<ChatRuleAction>,
+    /// hit statistics, maintained by the core
+    #[serde(default)]
+    pub hits: u64,
+    #[serde(default)]
+    pub last_hit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ChatRules {
+    pub rules: Vec<ChatRule>,
+}
+
+enum CompiledPattern {
+    Regex(regex::Regex),
+    Words(Vec<String>),
+}
+
+impl ChatPattern {
+    fn compile(&self) -> Result<CompiledPattern, Error> {
+        match self {
+            ChatPattern::Regex { pattern } => regex::Regex::new(pattern)
+                .map(CompiledPattern::Regex)
+                .map_err(|e| Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid pattern: {}", e),
+                }),
+            ChatPattern::Words { words } => Ok(CompiledPattern::Words(
+                words.iter().map(|word| word.to_lowercase()).collect(),
+            )),
+        }
+    }
+}
+
+impl CompiledPattern {
+    fn is_match(&self, message: &str) -> bool {
+        match self {
+            CompiledPattern::Regex(regex) => regex.is_match(message),
+            CompiledPattern::Words(words) => message
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .any(|word| words.contains(&word.to_lowercase())),
+        }
+    }
+}
+
+impl ChatRule {
+    pub fn validate(&self) -> Result<(), Error> {
+        self.pattern.compile()?;
+        for action in self.actions.iter() {
+            let text = match action {
+                ChatRuleAction::Kick { message } => message,
+                ChatRuleAction::Command { command } => command,
+                _ => continue,
+            };
+            if text.contains(['\n', '\r']) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Kick messages and commands must be a single line"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ChatRules {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_chat_rules.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse chat rules at {}", path.display()))?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_chat_rules.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize chat rules")?,
+        )
+        .await
+    }
+
+    /// Ids of the enabled rules `message` matches, recording a hit on each
+    fn record_hits(&mut self, message: &str, now: i64) -> Vec<String> {
+        let mut matched = Vec::new();
+        for rule in self.rules.iter_mut().filter(|rule| rule.enabled) {
+            let is_match = match rule.pattern.compile() {
+                Ok(pattern) => pattern.is_match(message),
+                Err(_) => false,
+            };
+            if is_match {
+                rule.hits += 1;
+                rule.last_hit = Some(now);
+                matched.push(rule.id.clone());
+            }
+        }
+        matched
+    }
+}
+
+fn render_action(action: &ChatRuleAction, player: &str, message: &str) -> Option<String> {
+    match action {
+        ChatRuleAction::Warn => None,
+        ChatRuleAction::Kick { message: reason } => Some(command_template::kick(player, reason)),
+        ChatRuleAction::Mute { duration_secs } => Some(format!(
+            "mute {} {}s",
+            command_template::single_line(player),
+            duration_secs
+        )),
+        ChatRuleAction::Command { command } => Some(command_template::render(
+            command,
+            &[("player", player), ("message", message)],
+        )),
+    }
+}
+
+async fn apply_rules(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    event_broadcaster: &EventBroadcaster,
+    instance_uuid: &InstanceUuid,
+    instance_name: &str,
+    player: &str,
+    message: &str,
+) -> Result<(), Error> {
+    let path = match instances.lock().await.get(instance_uuid) {
+        Some(instance) => instance.path().await,
+        None => return Ok(()),
+    };
+    let mut rules = ChatRules::load(&path).await?;
+    let matched = rules.record_hits(message, chrono::Utc::now().timestamp());
+    if matched.is_empty() {
+        return Ok(());
+    }
+    rules.save(&path).await?;
+    for rule in rules.rules.iter().filter(|rule| matched.contains(&rule.id)) {
+        for action in rule.actions.iter() {
+            let command = match render_action(action, player, message) {
+                Some(command) => command,
+                None => {
+                    event_broadcaster.send(Event::new_instance_warning(
+                        instance_uuid.clone(),
+                        instance_name.to_string(),
+                        format!(
+                            "Chat rule \"{}\" matched a message from {}: {}",
+                            rule.name, player, message
+                        ),
+                    ));
+                    continue;
+                }
+            };
+            if let Some(instance) = instances.lock().await.get(instance_uuid) {
+                if let Err(e) = instance.send_command(&command, CausedBy::System).await {
+                    warn!(
+                        "Failed to run action of chat rule {} on instance {}: {}",
+                        rule.name, instance_uuid, e
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks player chat of every instance against the instance's chat rules
+pub async fn chat_filter_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Chat filter task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if let EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid,
+            instance_name,
+            instance_event_inner:
+                InstanceEventInner::PlayerMessage {
+                    player,
+                    player_message,
+                },
+        }) = event.event_inner
+        {
+            if let Err(e) = apply_rules(
+                &instances,
+                &event_broadcaster,
+                &instance_uuid,
+                &instance_name,
+                &player,
+                &player_message,
+            )
+            .await
+            {
+                error!(
+                    "Failed to apply chat rules of instance {}: {}",
+                    instance_uuid, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_rules() {
+        let rule = |id: &str, pattern| ChatRule {
+            id: id.to_string(),
+            name: id.to_string(),
+            enabled: true,
+            pattern,
+            actions: vec![ChatRuleAction::Warn],
+            hits: 0,
+            last_hit: None,
+        };
+        let mut rules = ChatRules {
+            rules: vec![
+                rule(
+                    "words",
+                    ChatPattern::Words {
+                        words: vec!["Heck".to_string()],
+                    },
+                ),
+                rule(
+                    "ads",
+                    ChatPattern::Regex {
+                        pattern: r"(?i)\b\w+\.(com|net)\b".to_string(),
+                    },
+                ),
+            ],
+        };
+        assert_eq!(rules.record_hits("what the HECK!", 1), vec!["words"]);
+        // only whole words
+        assert!(rules.record_hits("checking", 2).is_empty());
+        assert_eq!(
+            rules.record_hits("heck, join example.COM", 3),
+            vec!["words", "ads"]
+        );
+        assert_eq!(rules.rules[0].hits, 2);
+        assert_eq!(rules.rules[0].last_hit, Some(3));
+
+        assert_eq!(
+            render_action(
+                &ChatRuleAction::Command {
+                    command: "chatdelete {player} {message}".to_string()
+                },
+                "Steve",
+                "spam"
+            ),
+            Some("chatdelete Steve spam".to_string())
+        );
+        assert!(rule(
+            "invalid",
+            ChatPattern::Regex {
+                pattern: "(".to_string()
+            }
+        )
+        .validate()
+        .is_err());
+    }
+}
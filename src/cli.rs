@@ -0,0 +1,549 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Subcommand;
+use color_eyre::eyre::{eyre, Context};
+use reqwest::Method;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::auth::permission::UserPermission;
+use crate::auth::user::{PublicUser, User, UsersManager};
+use crate::backup::BackupIndex;
+use crate::db::state::{self, StateStore};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::CausedBy;
+use crate::prelude::{
+    init_paths, lodestone_path, path_to_instances, path_to_stores, path_to_users,
+};
+use crate::service::{install_service, InstallService};
+use crate::types::DotLodestoneConfig;
+
+/// How commands reach the running core. Commands that only read or edit files fall
+/// back to the data directory when the core doesn't answer.
+#[derive(Debug, clap::Args)]
+pub struct Remote {
+    #[arg(long, default_value = "http://localhost:16662")]
+    pub url: String,
+    /// token of a logged in user, read from LODESTONE_TOKEN if not given
+    #[arg(long)]
+    pub token: Option<String>,
+    /// logs in as this user if there's no token, the password is read from
+    /// LODESTONE_PASSWORD if not given
+    #[arg(long)]
+    pub username: Option<String>,
+    #[arg(long)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List, create, start and stop instances
+    Instance {
+        #[command(flatten)]
+        remote: Remote,
+        #[command(subcommand)]
+        command: InstanceCommand,
+    },
+    /// List and take backups
+    Backup {
+        #[command(flatten)]
+        remote: Remote,
+        #[command(subcommand)]
+        command: BackupCommand,
+    },
+    /// List and create users, reset passwords
+    User {
+        #[command(flatten)]
+        remote: Remote,
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Start the core at boot
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum InstanceCommand {
+    List,
+    /// Creates an instance from a setup value, as sent by the dashboard
+    Create {
+        /// e.g. MinecraftPaper, see GET /games
+        game_type: String,
+        /// JSON file with the setup value
+        setup_value: PathBuf,
+    },
+    Start {
+        uuid: String,
+    },
+    Stop {
+        uuid: String,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BackupCommand {
+    List {
+        uuid: String,
+    },
+    Create {
+        uuid: String,
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        pinned: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum UserCommand {
+    List,
+    Create {
+        username: String,
+        password: String,
+    },
+    /// Sets the password of a user, logging them out everywhere
+    Password {
+        username: String,
+        new_password: String,
+        /// needed when changing your own password through the core
+        #[arg(long)]
+        old_password: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServiceCommand {
+    /// Installs a systemd unit, launchd daemon or Windows task for this core, usually
+    /// as root or an administrator
+    Install {
+        /// the account the core runs as, the one running this command if not given
+        #[arg(long)]
+        user: Option<String>,
+        /// print the service without installing it
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+struct Client {
+    http: reqwest::Client,
+    url: String,
+    remote: Remote,
+    token: Option<String>,
+}
+
+impl Client {
+    fn new(remote: Remote) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url: format!("{}/api/v1", remote.url.trim_end_matches('/')),
+            token: remote
+                .token
+                .clone()
+                .or_else(|| std::env::var("LODESTONE_TOKEN").ok()),
+            remote,
+        }
+    }
+
+    /// Whether the core answers, commands editing files must not run next to it
+    async fn is_core_running(&self) -> bool {
+        self.http
+            .get(format!("{}/info", self.url))
+            .timeout(Duration::from_secs(3))
+            .send()
+            .await
+            .is_ok()
+    }
+
+    async fn token(&mut self) -> Result<String, Error> {
+        if let Some(token) = &self.token {
+            return Ok(token.clone());
+        }
+        let username = self.remote.username.clone().ok_or_else(|| Error {
+            kind: ErrorKind::Unauthorized,
+            source: eyre!("The core is running, log in with --token or --username"),
+        })?;
+        let password = self
+            .remote
+            .password
+            .clone()
+            .or_else(|| std::env::var("LODESTONE_PASSWORD").ok())
+            .ok_or_else(|| Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("No password given for {}", username),
+            })?;
+        let reply = Self::json(
+            self.http
+                .post(format!("{}/user/login", self.url))
+                .basic_auth(username, Some(password)),
+        )
+        .await?;
+        let token = reply["token"]
+            .as_str()
+            .ok_or_else(|| eyre!("The core replied to the login without a token"))?
+            .to_string();
+        self.token = Some(token.clone());
+        Ok(token)
+    }
+
+    async fn json(request: reqwest::RequestBuilder) -> Result<Value, Error> {
+        let response = request.send().await.context("Failed to reach the core")?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .context("Failed to read the core's response")?;
+        if !status.is_success() {
+            return Err(eyre!("The core replied {}: {}", status, body).into());
+        }
+        if body.is_empty() {
+            return Ok(Value::Null);
+        }
+        Ok(serde_json::from_str(&body).context("The core replied with invalid JSON")?)
+    }
+
+    async fn call(
+        &mut self,
+        method: Method,
+        route: &str,
+        body: Option<Value>,
+    ) -> Result<Value, Error> {
+        let token = self.token().await?;
+        let mut request = self
+            .http
+            .request(method, format!("{}{}", self.url, route))
+            .bearer_auth(token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        Self::json(request).await
+    }
+}
+
+fn print_json(value: &impl Serialize) -> Result<(), Error> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).context("Failed to serialize output")?
+    );
+    Ok(())
+}
+
+fn core_not_running(remote: &Remote) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(
+            "The core at {} isn't running, this command needs it",
+            remote.url
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct InstanceDir {
+    #[serde(flatten)]
+    config: DotLodestoneConfig,
+    path: PathBuf,
+}
+
+/// Instances as found in the data directory
+fn instance_dirs() -> Result<Vec<InstanceDir>, Error> {
+    let mut ret = Vec::new();
+    for entry in std::fs::read_dir(path_to_instances())
+        .context("Failed to read the instances directory")?
+        .filter_map(|entry| entry.ok())
+    {
+        let path = entry.path();
+        if let Ok(config) = std::fs::read_to_string(path.join(".lodestone_config")) {
+            if let Ok(config) = serde_json::from_str::<DotLodestoneConfig>(&config) {
+                ret.push(InstanceDir { config, path });
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn instance_dir(uuid: &str) -> Result<PathBuf, Error> {
+    instance_dirs()?
+        .into_iter()
+        .find(|instance| instance.config.uuid() == &uuid)
+        .map(|instance| instance.path)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No instance {} in {}", uuid, path_to_instances().display()),
+        })
+}
+
+/// The users as the core would load them, only to be used while it's stopped
+async fn offline_users_manager() -> Result<UsersManager, Error> {
+    let pool = sqlx::SqlitePool::connect_with(
+        sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(path_to_stores().join("data.db"))
+            .create_if_missing(true),
+    )
+    .await
+    .context("Failed to open the core's database")?;
+    let store = StateStore::new(pool).await?;
+    let location = store.location(state::USERS, path_to_users().clone()).await;
+    let (event_broadcaster, _) = EventBroadcaster::new(16);
+    let mut users_manager = UsersManager::new(event_broadcaster, HashMap::new(), location);
+    users_manager.load_users().await?;
+    Ok(users_manager)
+}
+
+async fn run_instance(mut client: Client, command: InstanceCommand) -> Result<(), Error> {
+    let running = client.is_core_running().await;
+    match command {
+        InstanceCommand::List if !running => {
+            print_json(&instance_dirs()?)?;
+        }
+        InstanceCommand::List => {
+            print_json(&client.call(Method::GET, "/instance/list", None).await?)?;
+        }
+        _ if !running => return Err(core_not_running(&client.remote)),
+        InstanceCommand::Create {
+            game_type,
+            setup_value,
+        } => {
+            let setup_value: Value = serde_json::from_str(
+                &std::fs::read_to_string(&setup_value)
+                    .context(format!("Failed to read {}", setup_value.display()))?,
+            )
+            .context("The setup value isn't valid JSON")?;
+            let uuid = client
+                .call(
+                    Method::POST,
+                    &format!("/instance/create/{}", game_type),
+                    Some(setup_value),
+                )
+                .await?;
+            print_json(&uuid)?;
+        }
+        InstanceCommand::Start { uuid } => {
+            client
+                .call(Method::PUT, &format!("/instance/{}/start", uuid), None)
+                .await?;
+        }
+        InstanceCommand::Stop { uuid } => {
+            client
+                .call(Method::PUT, &format!("/instance/{}/stop", uuid), None)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_backup(mut client: Client, command: BackupCommand) -> Result<(), Error> {
+    let running = client.is_core_running().await;
+    match command {
+        BackupCommand::List { uuid } if !running => {
+            let mut backups = BackupIndex::load(&instance_dir(&uuid)?).await?.backups;
+            backups.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+            print_json(&backups)?;
+        }
+        BackupCommand::List { uuid } => {
+            print_json(
+                &client
+                    .call(Method::GET, &format!("/instance/{}/backups", uuid), None)
+                    .await?,
+            )?;
+        }
+        BackupCommand::Create { .. } if !running => return Err(core_not_running(&client.remote)),
+        BackupCommand::Create {
+            uuid,
+            label,
+            pinned,
+        } => {
+            // taken in the background, the dashboard or `backup list` shows when it's done
+            client
+                .call(
+                    Method::POST,
+                    &format!("/instance/{}/backups", uuid),
+                    Some(json!({ "label": label, "pinned": pinned })),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_user(mut client: Client, command: UserCommand) -> Result<(), Error> {
+    if !client.is_core_running().await {
+        let mut users_manager = offline_users_manager().await?;
+        match command {
+            UserCommand::List => {
+                let users: Vec<PublicUser> = users_manager
+                    .as_ref()
+                    .values()
+                    .map(PublicUser::from)
+                    .collect();
+                print_json(&users)?;
+            }
+            UserCommand::Create { username, password } => {
+                if users_manager.get_user_by_username(&username).is_some() {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!("User {} already exists", username),
+                    });
+                }
+                let user = User::new(username, password, false, false, UserPermission::default());
+                users_manager.add_user(user, CausedBy::System).await?;
+            }
+            UserCommand::Password {
+                username,
+                new_password,
+                ..
+            } => {
+                let user = users_manager
+                    .get_user_by_username(&username)
+                    .ok_or_else(|| Error {
+                        kind: ErrorKind::NotFound,
+                        source: eyre!("No user named {}", username),
+                    })?;
+                users_manager
+                    .change_password(&user.uid, None::<&str>, new_password, CausedBy::System)
+                    .await?;
+            }
+        }
+        return Ok(());
+    }
+    match command {
+        UserCommand::List => {
+            print_json(&client.call(Method::GET, "/user/list", None).await?)?;
+        }
+        UserCommand::Create { username, password } => {
+            client
+                .call(
+                    Method::POST,
+                    "/user",
+                    Some(json!({ "username": username, "password": password })),
+                )
+                .await?;
+        }
+        UserCommand::Password {
+            username,
+            new_password,
+            old_password,
+        } => {
+            let users = client.call(Method::GET, "/user/list", None).await?;
+            let uid = users
+                .as_array()
+                .and_then(|users| {
+                    users
+                        .iter()
+                        .find(|user| user["username"].as_str() == Some(username.as_str()))
+                })
+                .map(|user| user["uid"].clone())
+                .ok_or_else(|| Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("No user named {}", username),
+                })?;
+            client
+                .call(
+                    Method::PUT,
+                    &format!("/user/{}/password", uid.as_str().unwrap_or_default()),
+                    Some(json!({
+                        "uid": uid,
+                        "old_password": old_password,
+                        "new_password": new_password,
+                    })),
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_service(command: ServiceCommand) -> Result<(), Error> {
+    match command {
+        ServiceCommand::Install { user, dry_run } => {
+            let installed =
+                install_service(lodestone_path(), InstallService { user, dry_run }).await?;
+            print_json(&installed)
+        }
+    }
+}
+
+/// Runs a command and returns the exit code of the process
+pub async fn run(command: Command, lodestone_path: PathBuf) -> i32 {
+    init_paths(lodestone_path);
+    let result = match command {
+        Command::Instance { remote, command } => run_instance(Client::new(remote), command).await,
+        Command::Backup { remote, command } => run_backup(Client::new(remote), command).await,
+        Command::User { remote, command } => run_user(Client::new(remote), command).await,
+        Command::Service { command } => run_service(command).await,
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(clap::Parser)]
+struct TestCli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[test]
+fn test_parse_commands() {
+    use clap::Parser;
+    let cli = TestCli::try_parse_from([
+        "lodestone",
+        "backup",
+        "--url",
+        "http://localhost:16663/",
+        "create",
+        "abc",
+        "--label",
+        "before update",
+        "--pinned",
+    ])
+    .unwrap();
+    match cli.command {
+        Command::Backup {
+            remote,
+            command:
+                BackupCommand::Create {
+                    uuid,
+                    label,
+                    pinned,
+                },
+        } => {
+            assert_eq!(uuid, "abc");
+            assert_eq!(label.as_deref(), Some("before update"));
+            assert!(pinned);
+            let client = Client::new(remote);
+            assert_eq!(client.url, "http://localhost:16663/api/v1");
+        }
+        _ => panic!("parsed into the wrong command"),
+    }
+    assert!(TestCli::try_parse_from(["lodestone", "backup", "create"]).is_err());
+    assert!(TestCli::try_parse_from(["lodestone", "instance", "restart", "abc"]).is_err());
+}
+
+#[tokio::test]
+async fn test_token_needs_credentials() {
+    let mut client = Client::new(Remote {
+        url: "http://localhost:16663".to_string(),
+        token: None,
+        username: None,
+        password: None,
+    });
+    client.token = None;
+    assert!(matches!(
+        client.token().await.unwrap_err().kind,
+        ErrorKind::Unauthorized
+    ));
+    client.token = Some("token".to_string());
+    assert_eq!(client.token().await.unwrap(), "token");
+}
@@ -0,0 +1,129 @@
+use color_eyre::eyre::eyre;
+use serde_json::json;
+
+use crate::error::{Error, ErrorKind};
+
+/// Replaces newlines and other control characters with spaces, a console command
+/// ends at the first newline and anything after it would run as a second command
+pub fn single_line(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Substitutes `{name}` placeholders in one pass, so a value containing a
+/// placeholder is never expanded again. Unknown placeholders are kept as is,
+/// values are made single line.
+pub fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut ret = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        ret.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                ret.push_str(&single_line(value));
+                rest = &after[end + 1..];
+            }
+            None => {
+                ret.push('{');
+                rest = after;
+            }
+        }
+    }
+    ret.push_str(rest);
+    single_line(&ret)
+}
+
+fn is_player_name(name: &str) -> bool {
+    // Bedrock players joining through Floodgate are prefixed with a dot
+    let name = name.strip_prefix('.').unwrap_or(name);
+    (1..=16).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Accepts a player name or a target selector such as `@a` or `@a[distance=..10]`
+pub fn validate_target(target: &str) -> Result<(), Error> {
+    let valid = if let Some(selector) = target.strip_prefix('@') {
+        let mut chars = selector.chars();
+        let variable_ok = matches!(chars.next(), Some('a' | 'e' | 'p' | 'r' | 's'));
+        let arguments = chars.as_str();
+        variable_ok
+            && (arguments.is_empty()
+                || (arguments.starts_with('[')
+                    && arguments.ends_with(']')
+                    && !arguments
+                        .chars()
+                        .any(|c| c.is_whitespace() || c.is_control())))
+    } else {
+        is_player_name(target)
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid player name or selector: {}", target),
+        })
+    }
+}
+
+/// `tellraw` showing `text` as plain text, JSON encoded so it can't break out of the
+/// text component
+pub fn tellraw(target: &str, text: &str) -> Result<String, Error> {
+    validate_target(target)?;
+    Ok(format!(
+        "tellraw {} {}",
+        target,
+        json!({ "text": single_line(text) })
+    ))
+}
+
+pub fn say(text: &str) -> String {
+    format!("say {}", single_line(text))
+}
+
+/// `player` is expected to come from the server itself, e.g. a join message
+pub fn kick(player: &str, reason: &str) -> String {
+    format!("kick {} {}", single_line(player), single_line(reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(
+                "[{instance}] <{player}> {message} {unknown}",
+                &[
+                    ("instance", "Survival"),
+                    ("player", "{message}"),
+                    ("message", "hi\nop Steve"),
+                ]
+            ),
+            "[Survival] <{message}> hi op Steve {unknown}"
+        );
+        assert_eq!(render("{ {a}}", &[("a", "b")]), "{ b}");
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            tellraw("@a", "say \"hi\"\nstop").unwrap(),
+            r#"tellraw @a {"text":"say \"hi\" stop"}"#
+        );
+        assert!(tellraw("@a[distance=..10]", "hi").is_ok());
+        assert!(tellraw("Steve", "hi").is_ok());
+        assert!(tellraw("@a {\"text\":\"\"}\nop", "hi").is_err());
+        assert!(tellraw("@x", "hi").is_err());
+        assert_eq!(kick("Steve", "bye\nop Alex"), "kick Steve bye op Alex");
+        assert_eq!(say("a\r\nb"), "say a  b");
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+
+/// Bumped on breaking changes to what the companion pushes
+pub const COMPANION_PROTOCOL_VERSION: u32 = 1;
+/// how often the companion is asked to push a report
+pub const REPORT_INTERVAL_SECS: u64 = 5;
+/// a report older than this is from a companion that's gone, or a stopped server
+const REPORT_STALE_SECS: i64 = 30;
+/// most entity dense chunks a companion may report per world
+const MAX_HOTSPOTS: usize = 100;
+
+/// Sent to a companion when it connects, so it knows what the core speaks
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct CompanionHello {
+    pub protocol_version: u32,
+    pub report_interval_secs: u64,
+}
+
+impl Default for CompanionHello {
+    fn default() -> Self {
+        Self {
+            protocol_version: COMPANION_PROTOCOL_VERSION,
+            report_interval_secs: REPORT_INTERVAL_SECS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct CompanionPlayer {
+    pub name: String,
+    pub uuid: Option<String>,
+    pub ping_ms: u32,
+}
+
+/// A chunk with many entities in it, by chunk coordinates
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct ChunkHotspot {
+    pub x: i32,
+    pub z: i32,
+    pub entities: u32,
+    /// the entity type there's most of, like `minecraft:item`
+    pub top_entity: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct CompanionWorld {
+    pub name: String,
+    pub entities: u32,
+    pub loaded_chunks: u32,
+    /// the most entity dense chunks, in any order
+    #[serde(default)]
+    pub hotspots: Vec<ChunkHotspot>,
+}
+
+/// What a companion plugin or mod pushes from inside the server, every
+/// `report_interval_secs`. Anything it can't measure is left out.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct CompanionReport {
+    pub protocol_version: u32,
+    pub tps: Option<f32>,
+    /// milliseconds per tick
+    pub mspt: Option<f32>,
+    #[serde(default)]
+    pub players: Vec<CompanionPlayer>,
+    #[serde(default)]
+    pub worlds: Vec<CompanionWorld>,
+}
+
+impl CompanionReport {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.protocol_version != COMPANION_PROTOCOL_VERSION {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Unsupported companion protocol version {}, this core speaks {}",
+                    self.protocol_version,
+                    COMPANION_PROTOCOL_VERSION
+                ),
+            });
+        }
+        if [self.tps, self.mspt]
+            .iter()
+            .flatten()
+            .any(|value| !value.is_finite() || *value < 0.0)
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("TPS and MSPT must be positive numbers"),
+            });
+        }
+        if self.players.len() > 10_000
+            || self.worlds.len() > 1000
+            || self
+                .worlds
+                .iter()
+                .any(|world| world.hotspots.len() > MAX_HOTSPOTS)
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Companion report is too large"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A report and when the core received it
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct CompanionTelemetry {
+    pub received_at: i64,
+    pub report: CompanionReport,
+}
+
+/// A hotspot and the world it's in
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct WorldHotspot {
+    pub world: String,
+    pub hotspot: ChunkHotspot,
+}
+
+/// Entity and chunk counts of an instance, for finding what makes it lag
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct EntityDiagnostics {
+    pub received_at: i64,
+    pub entities: u64,
+    pub loaded_chunks: u64,
+    pub worlds: Vec<CompanionWorld>,
+    /// the most entity dense chunks of all worlds, densest first
+    pub hotspots: Vec<WorldHotspot>,
+}
+
+impl CompanionTelemetry {
+    pub fn entity_diagnostics(&self, hotspot_limit: usize) -> EntityDiagnostics {
+        let worlds = self.report.worlds.clone();
+        let mut hotspots: Vec<WorldHotspot> = worlds
+            .iter()
+            .flat_map(|world| {
+                world.hotspots.iter().map(|hotspot| WorldHotspot {
+                    world: world.name.clone(),
+                    hotspot: hotspot.clone(),
+                })
+            })
+            .collect();
+        hotspots.sort_by(|a, b| b.hotspot.entities.cmp(&a.hotspot.entities));
+        hotspots.truncate(hotspot_limit);
+        EntityDiagnostics {
+            received_at: self.received_at,
+            entities: worlds.iter().map(|world| world.entities as u64).sum(),
+            loaded_chunks: worlds.iter().map(|world| world.loaded_chunks as u64).sum(),
+            worlds,
+            hotspots,
+        }
+    }
+}
+
+/// The latest report of each instance, kept in memory only
+#[derive(Default)]
+pub struct CompanionReports {
+    reports: HashMap<InstanceUuid, CompanionTelemetry>,
+}
+
+impl CompanionReports {
+    pub fn record(&mut self, uuid: InstanceUuid, report: CompanionReport, now: i64) {
+        self.reports.insert(
+            uuid,
+            CompanionTelemetry {
+                received_at: now,
+                report,
+            },
+        );
+    }
+
+    pub fn latest(&self, uuid: &InstanceUuid, now: i64) -> Option<CompanionTelemetry> {
+        self.reports
+            .get(uuid)
+            .filter(|telemetry| now - telemetry.received_at < REPORT_STALE_SECS)
+            .cloned()
+    }
+}
+
+#[test]
+fn test_companion_reports() {
+    let report = CompanionReport {
+        protocol_version: COMPANION_PROTOCOL_VERSION,
+        tps: Some(19.8),
+        mspt: Some(12.5),
+        players: vec![CompanionPlayer {
+            name: "Steve".to_string(),
+            uuid: None,
+            ping_ms: 40,
+        }],
+        worlds: Vec::new(),
+    };
+    assert!(report.validate().is_ok());
+    assert!(CompanionReport {
+        protocol_version: COMPANION_PROTOCOL_VERSION + 1,
+        ..report.clone()
+    }
+    .validate()
+    .is_err());
+    assert!(CompanionReport {
+        tps: Some(f32::NAN),
+        ..report.clone()
+    }
+    .validate()
+    .is_err());
+
+    let mut reports = CompanionReports::default();
+    let uuid = InstanceUuid::from("a".to_string());
+    reports.record(uuid.clone(), report.clone(), 100);
+    assert_eq!(reports.latest(&uuid, 110).unwrap().report, report);
+    assert!(reports.latest(&uuid, 100 + REPORT_STALE_SECS).is_none());
+}
+
+#[test]
+fn test_entity_diagnostics() {
+    let hotspot = |x, entities| ChunkHotspot {
+        x,
+        z: 0,
+        entities,
+        top_entity: None,
+    };
+    let telemetry = CompanionTelemetry {
+        received_at: 100,
+        report: CompanionReport {
+            protocol_version: COMPANION_PROTOCOL_VERSION,
+            tps: None,
+            mspt: None,
+            players: Vec::new(),
+            worlds: vec![
+                CompanionWorld {
+                    name: "world".to_string(),
+                    entities: 900,
+                    loaded_chunks: 400,
+                    hotspots: vec![hotspot(1, 50), hotspot(2, 300)],
+                },
+                CompanionWorld {
+                    name: "world_nether".to_string(),
+                    entities: 100,
+                    loaded_chunks: 50,
+                    hotspots: vec![hotspot(3, 80)],
+                },
+            ],
+        },
+    };
+    let diagnostics = telemetry.entity_diagnostics(2);
+    assert_eq!(diagnostics.entities, 1000);
+    assert_eq!(diagnostics.loaded_chunks, 450);
+    assert_eq!(
+        diagnostics
+            .hotspots
+            .iter()
+            .map(|hotspot| (hotspot.world.as_str(), hotspot.hotspot.x))
+            .collect::<Vec<_>>(),
+        vec![("world", 2), ("world_nether", 3)]
+    );
+}
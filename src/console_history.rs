@@ -0,0 +1,224 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::auth::user_id::UserId;
+use crate::error::{Error, ErrorKind};
+
+const MAX_HISTORY: usize = 500;
+const MAX_FAVORITES: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct HistoryEntry {
+    pub command: String,
+    pub time: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct FavoriteCommand {
+    /// assigned by the core
+    #[serde(default)]
+    pub id: String,
+    pub label: String,
+    pub command: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct UserConsole {
+    user_id: UserId,
+    /// oldest first
+    history: Vec<HistoryEntry>,
+    favorites: Vec<FavoriteCommand>,
+}
+
+/// Console history and favorites of every user of an instance
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ConsoleHistory {
+    users: Vec<UserConsole>,
+}
+
+impl FavoriteCommand {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.label.trim().is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Label must not be empty"),
+            });
+        }
+        crate::console_policy::validate_command(&self.command)?;
+        Ok(())
+    }
+}
+
+impl ConsoleHistory {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_console_history.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse console history at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_console_history.json"),
+            serde_json::to_string(self).context("Failed to serialize console history")?,
+        )
+        .await
+    }
+
+    fn user_mut(&mut self, user_id: &UserId) -> &mut UserConsole {
+        let index = match self.users.iter().position(|u| u.user_id == *user_id) {
+            Some(index) => index,
+            None => {
+                self.users.push(UserConsole {
+                    user_id: user_id.clone(),
+                    history: Vec::new(),
+                    favorites: Vec::new(),
+                });
+                self.users.len() - 1
+            }
+        };
+        &mut self.users[index]
+    }
+
+    fn user(&self, user_id: &UserId) -> Option<&UserConsole> {
+        self.users.iter().find(|u| u.user_id == *user_id)
+    }
+
+    /// Like a shell, running the previous command again doesn't add an entry
+    pub fn record(&mut self, user_id: &UserId, command: &str, time: i64) {
+        let history = &mut self.user_mut(user_id).history;
+        if let Some(last) = history.last_mut() {
+            if last.command == command {
+                last.time = time;
+                return;
+            }
+        }
+        history.push(HistoryEntry {
+            command: command.to_string(),
+            time,
+        });
+        if history.len() > MAX_HISTORY {
+            history.drain(..history.len() - MAX_HISTORY);
+        }
+    }
+
+    /// The most recent `limit` commands, oldest first
+    pub fn history(&self, user_id: &UserId, limit: Option<usize>) -> Vec<HistoryEntry> {
+        let history = match self.user(user_id) {
+            Some(user) => &user.history,
+            None => return Vec::new(),
+        };
+        let skip = limit.map_or(0, |limit| history.len().saturating_sub(limit));
+        history[skip..].to_vec()
+    }
+
+    pub fn clear_history(&mut self, user_id: &UserId) {
+        self.user_mut(user_id).history.clear();
+    }
+
+    pub fn favorites(&self, user_id: &UserId) -> Vec<FavoriteCommand> {
+        self.user(user_id)
+            .map(|user| user.favorites.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn add_favorite(
+        &mut self,
+        user_id: &UserId,
+        favorite: FavoriteCommand,
+    ) -> Result<(), Error> {
+        let favorites = &mut self.user_mut(user_id).favorites;
+        if favorites.len() >= MAX_FAVORITES {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("At most {} favorites can be saved", MAX_FAVORITES),
+            });
+        }
+        favorites.push(favorite);
+        Ok(())
+    }
+
+    pub fn remove_favorite(&mut self, user_id: &UserId, favorite_id: &str) -> Result<(), Error> {
+        let favorites = &mut self.user_mut(user_id).favorites;
+        let len = favorites.len();
+        favorites.retain(|f| f.id != favorite_id);
+        if favorites.len() == len {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Favorite not found"),
+            });
+        }
+        Ok(())
+    }
+}
+
+pub async fn record_command(
+    path_to_instance: &Path,
+    user_id: &UserId,
+    command: &str,
+) -> Result<(), Error> {
+    let mut console_history = ConsoleHistory::load(path_to_instance).await?;
+    console_history.record(user_id, command, chrono::Utc::now().timestamp());
+    console_history.save(path_to_instance).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_console_history() {
+        let steve = UserId::from("steve".to_string());
+        let alex = UserId::from("alex".to_string());
+        let mut console = ConsoleHistory::default();
+        console.record(&steve, "list", 1);
+        console.record(&steve, "list", 2);
+        console.record(&steve, "say hi", 3);
+        console.record(&alex, "time set day", 4);
+        assert_eq!(
+            console.history(&steve, None),
+            vec![
+                HistoryEntry {
+                    command: "list".to_string(),
+                    time: 2
+                },
+                HistoryEntry {
+                    command: "say hi".to_string(),
+                    time: 3
+                }
+            ]
+        );
+        assert_eq!(console.history(&steve, Some(1))[0].command, "say hi");
+        assert_eq!(console.history(&alex, None).len(), 1);
+
+        for i in 0..MAX_HISTORY + 10 {
+            console.record(&alex, &format!("say {}", i), i as i64);
+        }
+        assert_eq!(console.history(&alex, None).len(), MAX_HISTORY);
+
+        console
+            .add_favorite(
+                &steve,
+                FavoriteCommand {
+                    id: "a".to_string(),
+                    label: "Day".to_string(),
+                    command: "time set day".to_string(),
+                },
+            )
+            .unwrap();
+        assert!(console.favorites(&alex).is_empty());
+        assert!(console.remove_favorite(&alex, "a").is_err());
+        console.remove_favorite(&steve, "a").unwrap();
+        assert!(console.favorites(&steve).is_empty());
+    }
+}
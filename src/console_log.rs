@@ -0,0 +1,295 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::State;
+use crate::types::InstanceUuid;
+
+/// `console.log` is rotated to `console.1.log` once it reaches this size
+const MAX_FILE_SIZE: u64 = 5 * 1024 * 1024;
+/// rotated files kept besides `console.log`
+const MAX_ROTATED_FILES: u32 = 4;
+
+pub const DEFAULT_LINES: usize = 500;
+pub const MAX_LINES: usize = 5000;
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct ConsoleLogQuery {
+    /// the most recent matching lines returned
+    pub lines: Option<usize>,
+    /// case-insensitive substring, or a regex with `regex=true`
+    pub search: Option<String>,
+    #[serde(default)]
+    pub regex: bool,
+}
+
+pub fn path_to_console_logs(path_to_instance: &Path) -> PathBuf {
+    path_to_instance.join("console_logs")
+}
+
+fn log_file(dir: &Path, index: u32) -> PathBuf {
+    if index == 0 {
+        dir.join("console.log")
+    } else {
+        dir.join(format!("console.{}.log", index))
+    }
+}
+
+/// Appends the output of one instance, rotating the file as it grows
+struct ConsoleLogWriter {
+    dir: PathBuf,
+    file: tokio::fs::File,
+    size: u64,
+}
+
+impl ConsoleLogWriter {
+    async fn open(path_to_instance: &Path) -> Result<Self, Error> {
+        let dir = path_to_console_logs(path_to_instance);
+        crate::util::fs::create_dir_all(&dir).await?;
+        let path = log_file(&dir, 0);
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .context(format!("Failed to open console log {}", path.display()))?;
+        let size = file
+            .metadata()
+            .await
+            .context(format!("Failed to read console log {}", path.display()))?
+            .len();
+        Ok(Self { dir, file, size })
+    }
+
+    async fn rotate(&mut self) -> Result<(), Error> {
+        self.file
+            .flush()
+            .await
+            .context("Failed to flush console log")?;
+        let oldest = log_file(&self.dir, MAX_ROTATED_FILES);
+        if oldest.is_file() {
+            crate::util::fs::remove_file(&oldest).await?;
+        }
+        for index in (0..MAX_ROTATED_FILES).rev() {
+            let from = log_file(&self.dir, index);
+            if from.is_file() {
+                crate::util::fs::rename(&from, log_file(&self.dir, index + 1)).await?;
+            }
+        }
+        let path = log_file(&self.dir, 0);
+        self.file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .context(format!("Failed to open console log {}", path.display()))?;
+        self.size = 0;
+        Ok(())
+    }
+
+    async fn append(&mut self, timestamp: i64, message: &str) -> Result<(), Error> {
+        let time = chrono::NaiveDateTime::from_timestamp_opt(timestamp, 0)
+            .ok_or_else(|| eyre!("Invalid timestamp"))?;
+        let mut line = format!("[{}] ", time.format("%Y-%m-%d %H:%M:%S"));
+        // one entry per line, so a search never returns half a message
+        line.push_str(message.trim_end().replace('\n', " ").as_str());
+        line.push('\n');
+        if self.size > 0 && self.size + line.len() as u64 > MAX_FILE_SIZE {
+            self.rotate().await?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .await
+            .context("Failed to write console log")?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// The most recent lines matching the query, oldest first
+pub async fn search_console_log(
+    path_to_instance: &Path,
+    query: &ConsoleLogQuery,
+) -> Result<Vec<String>, Error> {
+    let limit = query.lines.unwrap_or(DEFAULT_LINES).clamp(1, MAX_LINES);
+    let regex = match &query.search {
+        Some(search) if query.regex => Some(regex::Regex::new(search).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid regex: {}", e),
+        })?),
+        _ => None,
+    };
+    let needle = query.search.as_ref().map(|search| search.to_lowercase());
+    let is_match = |line: &str| match (&regex, &needle) {
+        (Some(regex), _) => regex.is_match(line),
+        (None, Some(needle)) => line.to_lowercase().contains(needle.as_str()),
+        (None, None) => true,
+    };
+    let dir = path_to_console_logs(path_to_instance);
+    let mut lines = VecDeque::with_capacity(limit);
+    for index in (0..=MAX_ROTATED_FILES).rev() {
+        let path = log_file(&dir, index);
+        if !path.is_file() {
+            continue;
+        }
+        let contents = crate::util::fs::read_to_string(&path).await?;
+        for line in contents.lines().filter(|line| is_match(line)) {
+            if lines.len() == limit {
+                lines.pop_front();
+            }
+            lines.push_back(line.to_string());
+        }
+    }
+    Ok(lines.into())
+}
+
+/// Writes the output of every instance to its console log. A writer is closed
+/// once its instance stops so a deleted instance doesn't keep its file open.
+pub async fn console_log_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut writers: HashMap<InstanceUuid, ConsoleLogWriter> = HashMap::new();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Console log task lagged, {} lines are missing", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let (uuid, message) = match event.event_inner {
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid,
+                instance_event_inner: InstanceEventInner::InstanceOutput { message },
+                ..
+            }) => (instance_uuid, message),
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid,
+                instance_event_inner: InstanceEventInner::StateTransition { to: State::Stopped },
+                ..
+            }) => {
+                writers.remove(&instance_uuid);
+                continue;
+            }
+            _ => continue,
+        };
+        if !writers.contains_key(&uuid) {
+            let path = match instances.lock().await.get(&uuid) {
+                Some(instance) => instance.path().await,
+                None => continue,
+            };
+            match ConsoleLogWriter::open(&path).await {
+                Ok(writer) => {
+                    writers.insert(uuid.clone(), writer);
+                }
+                Err(e) => {
+                    error!("Failed to open console log of instance {}: {}", uuid, e);
+                    continue;
+                }
+            }
+        }
+        if let Some(writer) = writers.get_mut(&uuid) {
+            if let Err(e) = writer
+                .append(chrono::Utc::now().timestamp(), &message)
+                .await
+            {
+                error!("Failed to write console log of instance {}: {}", uuid, e);
+                writers.remove(&uuid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_console_log() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut writer = ConsoleLogWriter::open(temp_dir.path()).await.unwrap();
+        writer
+            .append(0, "[Server thread/INFO]: Done (3.2s)!")
+            .await
+            .unwrap();
+        writer
+            .append(60, "[Server thread/INFO]: Steve joined the game\n")
+            .await
+            .unwrap();
+        writer
+            .append(120, "[Server thread/WARN]: Can't keep up!")
+            .await
+            .unwrap();
+
+        let all = search_console_log(temp_dir.path(), &ConsoleLogQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(
+            all[1],
+            "[1970-01-01 00:01:00] [Server thread/INFO]: Steve joined the game"
+        );
+        let steve = ConsoleLogQuery {
+            search: Some("STEVE".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            search_console_log(temp_dir.path(), &steve)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        let warnings = ConsoleLogQuery {
+            search: Some(r"/(WARN|ERROR)\]".to_string()),
+            regex: true,
+            ..Default::default()
+        };
+        assert!(search_console_log(temp_dir.path(), &warnings)
+            .await
+            .unwrap()[0]
+            .ends_with("Can't keep up!"));
+        let last = ConsoleLogQuery {
+            lines: Some(1),
+            ..Default::default()
+        };
+        assert!(search_console_log(temp_dir.path(), &last).await.unwrap()[0]
+            .ends_with("Can't keep up!"));
+
+        // lines move to the rotated files in order
+        writer.rotate().await.unwrap();
+        writer.append(180, "Stopping server").await.unwrap();
+        let all = search_console_log(temp_dir.path(), &ConsoleLogQuery::default())
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 4);
+        assert!(all[3].ends_with("Stopping server"));
+        assert!(log_file(&path_to_console_logs(temp_dir.path()), 1).is_file());
+
+        assert!(search_console_log(
+            temp_dir.path(),
+            &ConsoleLogQuery {
+                search: Some("(".to_string()),
+                regex: true,
+                ..Default::default()
+            }
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::auth::user::User;
+use crate::error::{Error, ErrorKind};
+
+/// Command names, matched case-insensitively and without namespace,
+/// so `op` also covers `/minecraft:op`
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct CommandRules {
+    /// if not empty, only these commands may be sent
+    pub allowed: Vec<String>,
+    pub denied: Vec<String>,
+}
+
+/// Which console commands users may send through the API, by permission level.
+/// The owner is never restricted.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ConsoleCommandPolicy {
+    pub admin: CommandRules,
+    pub user: CommandRules,
+}
+
+impl Default for ConsoleCommandPolicy {
+    fn default() -> Self {
+        Self {
+            admin: CommandRules::default(),
+            user: CommandRules {
+                allowed: Vec::new(),
+                denied: vec!["op".to_string(), "deop".to_string(), "stop".to_string()],
+            },
+        }
+    }
+}
+
+/// Trims the command and rejects anything that isn't a single line, an embedded
+/// newline would make the server run whatever follows it as a second command
+pub fn validate_command(command: &str) -> Result<&str, Error> {
+    let command = command.trim();
+    if command.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Command is empty"),
+        });
+    }
+    if command.chars().any(|c| c.is_control()) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Command must be a single line without control characters"),
+        });
+    }
+    Ok(command)
+}
+
+fn normalize_name(word: &str) -> String {
+    let word = word.trim_start_matches('/');
+    let name = match word.rfind(':') {
+        Some(i) => &word[i + 1..],
+        None => word,
+    };
+    name.to_lowercase()
+}
+
+/// The command itself and every command nested in `execute ... run <command>`
+fn command_names(command: &str) -> Vec<String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let mut names = Vec::new();
+    if let Some(first) = words.first() {
+        names.push(normalize_name(first));
+    }
+    for pair in words.windows(2) {
+        if pair[0].eq_ignore_ascii_case("run") {
+            names.push(normalize_name(pair[1]));
+        }
+    }
+    names
+}
+
+impl CommandRules {
+    pub(crate) fn check(&self, command: &str) -> Result<(), Error> {
+        let is_listed = |list: &Vec<String>, name: &String| {
+            list.iter().any(|listed| normalize_name(listed) == *name)
+        };
+        for name in command_names(command) {
+            if is_listed(&self.denied, &name)
+                || (!self.allowed.is_empty() && !is_listed(&self.allowed, &name))
+            {
+                return Err(Error {
+                    kind: ErrorKind::PermissionDenied,
+                    source: eyre!("You are not allowed to run \"{}\"", name),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ConsoleCommandPolicy {
+    pub fn check(&self, user: &User, command: &str) -> Result<(), Error> {
+        if user.is_owner {
+            Ok(())
+        } else if user.is_admin {
+            self.admin.check(command)
+        } else {
+            self.user.check(command)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_rules() {
+        assert!(validate_command("say hi\nop Steve").is_err());
+        assert!(validate_command("  ").is_err());
+        assert_eq!(validate_command(" list\n").unwrap(), "list");
+
+        let rules = ConsoleCommandPolicy::default().user;
+        assert!(rules.check("list").is_ok());
+        assert!(rules.check("OP Steve").is_err());
+        assert!(rules.check("/minecraft:stop").is_err());
+        assert!(rules.check("execute as @a run minecraft:op Steve").is_err());
+
+        let rules = CommandRules {
+            allowed: vec!["say".to_string(), "execute".to_string()],
+            denied: Vec::new(),
+        };
+        assert!(rules.check("say hi").is_ok());
+        assert!(rules.check("time set day").is_err());
+        assert!(rules.check("execute run time set day").is_err());
+    }
+}
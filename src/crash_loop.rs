@@ -0,0 +1,190 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use ringbuffer::RingBufferExt;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+
+use crate::alerts::is_crash;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::AppState;
+
+/// restarts stop once an instance crashed this many times within `CRASH_WINDOW_SECS`
+const MAX_CRASHES: usize = 5;
+
+const CRASH_WINDOW_SECS: i64 = 10 * 60;
+
+const BASE_BACKOFF_SECS: u64 = 5;
+
+const MAX_BACKOFF_SECS: u64 = 5 * 60;
+
+/// console lines attached to an `InstanceCrashLoop` event
+const LOG_TAIL_LINES: usize = 100;
+
+#[derive(Debug, PartialEq, Eq)]
+enum CrashResponse {
+    Restart { after: Duration },
+    GiveUp { crashes: usize },
+}
+
+/// Recent crashes of each instance
+#[derive(Default)]
+struct CrashTracker {
+    crashes: HashMap<InstanceUuid, VecDeque<i64>>,
+}
+
+impl CrashTracker {
+    /// Waits twice as long before each restart, up to `MAX_BACKOFF_SECS`. Giving
+    /// up forgets the crashes, so a start by hand gets the full number of retries.
+    fn record(&mut self, uuid: &InstanceUuid, now: i64) -> CrashResponse {
+        let crashes = self.crashes.entry(uuid.clone()).or_default();
+        crashes.push_back(now);
+        while crashes
+            .front()
+            .map_or(false, |time| *time <= now - CRASH_WINDOW_SECS)
+        {
+            crashes.pop_front();
+        }
+        let count = crashes.len();
+        if count >= MAX_CRASHES {
+            crashes.clear();
+            return CrashResponse::GiveUp { crashes: count };
+        }
+        let backoff = BASE_BACKOFF_SECS
+            .saturating_mul(1 << (count - 1))
+            .min(MAX_BACKOFF_SECS);
+        CrashResponse::Restart {
+            after: Duration::from_secs(backoff),
+        }
+    }
+}
+
+async fn log_tail(state: &AppState, uuid: &InstanceUuid) -> Vec<String> {
+    let buffer = state.console_out_buffer.lock().await;
+    let lines: Vec<String> = match buffer.get(uuid) {
+        Some(events) => events
+            .iter()
+            .filter_map(|event| match &event.event_inner {
+                EventInner::InstanceEvent(InstanceEvent {
+                    instance_event_inner: InstanceEventInner::InstanceOutput { message },
+                    ..
+                }) => Some(message.clone()),
+                _ => None,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].to_vec()
+}
+
+/// Starts the instance again once the backoff passed, unless it was started by
+/// hand or `restart_on_crash` was turned off meanwhile
+async fn restart_later(state: AppState, uuid: InstanceUuid, after: Duration) {
+    tokio::time::sleep(after).await;
+    let mut instance = match state.instances.lock().await.get(&uuid).cloned() {
+        Some(instance) => instance,
+        None => return,
+    };
+    if !instance.restart_on_crash().await || instance.state().await != State::Stopped {
+        return;
+    }
+    info!("Restarting instance {} after it crashed", uuid);
+    if let Err(e) = instance.start(CausedBy::System, false).await {
+        error!("Failed to restart instance {} after a crash: {}", uuid, e);
+    }
+}
+
+/// Restarts instances with `restart_on_crash` set when they crash, backing off
+/// as they keep crashing. After `MAX_CRASHES` within the window the instance is
+/// left stopped and an `InstanceCrashLoop` event carries the end of its console.
+pub async fn restart_on_crash_task(state: AppState) {
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut tracker = CrashTracker::default();
+    let mut last_states: HashMap<InstanceUuid, State> = HashMap::new();
+    loop {
+        let (uuid, instance_name, to) = match event_receiver.recv().await {
+            Ok(event) => match event.event_inner {
+                EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid,
+                    instance_name,
+                    instance_event_inner: InstanceEventInner::StateTransition { to },
+                }) => (instance_uuid, instance_name, to),
+                _ => continue,
+            },
+            Err(RecvError::Lagged(_)) => {
+                warn!("Restart on crash task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if !is_crash(last_states.insert(uuid.clone(), to), to) {
+            continue;
+        }
+        let restart_on_crash = match state.instances.lock().await.get(&uuid) {
+            Some(instance) => instance.restart_on_crash().await,
+            None => false,
+        };
+        if !restart_on_crash {
+            continue;
+        }
+        match tracker.record(&uuid, chrono::Utc::now().timestamp()) {
+            CrashResponse::Restart { after } => {
+                info!(
+                    "Instance {} crashed, restarting in {}s",
+                    instance_name,
+                    after.as_secs()
+                );
+                tokio::spawn(restart_later(state.clone(), uuid, after));
+            }
+            CrashResponse::GiveUp { crashes } => {
+                warn!(
+                    "Instance {} crashed {} times in a row, not restarting it",
+                    instance_name, crashes
+                );
+                let log_tail = log_tail(&state, &uuid).await;
+                state.event_broadcaster.send(Event {
+                    details: format!(
+                        "Stopped restarting {} after {} crashes in {} minutes",
+                        instance_name,
+                        crashes,
+                        CRASH_WINDOW_SECS / 60
+                    ),
+                    snowflake: Snowflake::default(),
+                    event_inner: EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid: uuid,
+                        instance_name,
+                        instance_event_inner: InstanceEventInner::InstanceCrashLoop {
+                            crashes: crashes as u32,
+                            window_secs: CRASH_WINDOW_SECS as u64,
+                            log_tail,
+                        },
+                    }),
+                    caused_by: CausedBy::System,
+                });
+            }
+        }
+    }
+}
+
+#[test]
+fn test_crash_backoff() {
+    let uuid = InstanceUuid::from("i".to_string());
+    let mut tracker = CrashTracker::default();
+    let after = |secs| CrashResponse::Restart {
+        after: Duration::from_secs(secs),
+    };
+    assert_eq!(tracker.record(&uuid, 0), after(5));
+    assert_eq!(tracker.record(&uuid, 10), after(10));
+    assert_eq!(tracker.record(&uuid, 30), after(20));
+    // the first crash left the window
+    assert_eq!(tracker.record(&uuid, 600), after(20));
+    assert_eq!(tracker.record(&uuid, 620), after(40));
+    assert_eq!(
+        tracker.record(&uuid, 630),
+        CrashResponse::GiveUp { crashes: 5 }
+    );
+    assert_eq!(tracker.record(&uuid, 700), after(5));
+}
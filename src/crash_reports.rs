@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::alerts::is_crash;
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::implementations::minecraft::crash_analysis::{self, CulpritMod, ModIssue};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::State;
+use crate::types::{InstanceUuid, Snowflake};
+use crate::AppState;
+
+/// one directory per crash in the instance, holding the record and the files
+/// collected
+const CRASHES_DIR: &str = ".lodestone_crashes";
+
+const RECORD_FILE_NAME: &str = "crash.json";
+
+const CRASH_REPORT_FILE_NAME: &str = "crash-report.txt";
+
+const LOG_FILE_NAME: &str = "latest.log";
+
+/// only the end of a longer log is kept
+const MAX_LOG_BYTES: u64 = 512 * 1024;
+
+const MAX_CRASHES_KEPT: usize = 20;
+
+/// how far back a crash report may date when the start of the instance wasn't
+/// seen, e.g. when it was started before the core
+const UNKNOWN_START_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct CrashRecord {
+    pub id: String,
+    pub time: i64,
+    /// `Stopped` if the server exited by itself, `Error` if it couldn't be run
+    pub state: State,
+    /// name of the report in the instance's `crash-reports`, if the game wrote one
+    pub crash_report: Option<String>,
+    /// the `Description:` line of the crash report
+    pub description: Option<String>,
+    pub has_log: bool,
+    /// dependency errors, conflicts and failures of mods in the report and log
+    #[serde(default)]
+    pub mod_issues: Vec<ModIssue>,
+    /// the mods `mod_issues` blame, matched to their jars
+    #[serde(default)]
+    pub culprit_mods: Vec<CulpritMod>,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct CrashDetails {
+    #[serde(flatten)]
+    pub record: CrashRecord,
+    /// the text of the crash report
+    pub report: Option<String>,
+    /// the end of `logs/latest.log` at the time of the crash
+    pub log: Option<String>,
+}
+
+fn path_to_crashes(path_to_instance: &Path) -> PathBuf {
+    path_to_instance.join(CRASHES_DIR)
+}
+
+fn description(crash_report: &str) -> Option<String> {
+    crash_report
+        .lines()
+        .find_map(|line| line.strip_prefix("Description:"))
+        .map(|description| description.trim().to_string())
+        .filter(|description| !description.is_empty())
+}
+
+/// The newest report in `crash-reports` written since `since`
+fn newest_crash_report(path_to_instance: &Path, since: SystemTime) -> Option<PathBuf> {
+    std::fs::read_dir(path_to_instance.join("crash-reports"))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .path()
+                .extension()
+                .map_or(false, |extension| extension == "txt")
+        })
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .filter(|(modified, _)| *modified >= since)
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+/// The last `max_bytes` of the file, starting at a full line
+async fn read_tail(path: &Path, max_bytes: u64) -> Result<String, Error> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .context(format!("Failed to open {}", path.display()))?;
+    let len = file
+        .metadata()
+        .await
+        .context(format!("Failed to read metadata of {}", path.display()))?
+        .len();
+    let start = len.saturating_sub(max_bytes);
+    file.seek(SeekFrom::Start(start))
+        .await
+        .context(format!("Failed to seek in {}", path.display()))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .await
+        .context(format!("Failed to read {}", path.display()))?;
+    let text = String::from_utf8_lossy(&bytes);
+    Ok(match (start > 0, text.find('\n')) {
+        (true, Some(newline)) => text[newline + 1..].to_string(),
+        _ => text.to_string(),
+    })
+}
+
+/// Copies the crash report written since `started` and the end of the log into
+/// a new crash directory, dropping the oldest crashes past `MAX_CRASHES_KEPT`
+pub async fn capture_crash(
+    path_to_instance: &Path,
+    state: State,
+    started: Option<SystemTime>,
+) -> Result<CrashRecord, Error> {
+    let now = chrono::Utc::now();
+    let since = started.unwrap_or_else(|| SystemTime::now() - UNKNOWN_START_WINDOW);
+    let mut record = CrashRecord {
+        id: now.format("%Y%m%d-%H%M%S").to_string(),
+        time: now.timestamp(),
+        state,
+        crash_report: None,
+        description: None,
+        has_log: false,
+        mod_issues: Vec::new(),
+        culprit_mods: Vec::new(),
+    };
+    let dir = path_to_crashes(path_to_instance).join(&record.id);
+    crate::util::fs::create_dir_all(&dir).await?;
+    let mut report_text = None;
+    if let Some(report) = newest_crash_report(path_to_instance, since) {
+        let text = crate::util::fs::read_to_string(&report).await?;
+        record.description = description(&text);
+        record.crash_report = Some(report.file_name().unwrap().to_string_lossy().to_string());
+        crate::util::fs::write_all(dir.join(CRASH_REPORT_FILE_NAME), &text).await?;
+        report_text = Some(text);
+    }
+    let mut log_text = None;
+    let log = path_to_instance.join("logs").join("latest.log");
+    if log.is_file() {
+        let text = read_tail(&log, MAX_LOG_BYTES).await?;
+        crate::util::fs::write_all(dir.join(LOG_FILE_NAME), &text).await?;
+        record.has_log = true;
+        log_text = Some(text);
+    }
+    // dependency errors of Fabric only make it to the log
+    let texts: Vec<&str> = report_text
+        .iter()
+        .chain(&log_text)
+        .map(String::as_str)
+        .collect();
+    let (mod_issues, culprit_mods) = crash_analysis::analyze_crash(path_to_instance, &texts);
+    record.mod_issues = mod_issues;
+    record.culprit_mods = culprit_mods;
+    crate::util::fs::write_all(
+        dir.join(RECORD_FILE_NAME),
+        serde_json::to_string_pretty(&record).context("Failed to serialize crash record")?,
+    )
+    .await?;
+    for old in list_crashes(path_to_instance)
+        .await?
+        .iter()
+        .skip(MAX_CRASHES_KEPT)
+    {
+        crate::util::fs::remove_dir_all(path_to_crashes(path_to_instance).join(&old.id)).await?;
+    }
+    Ok(record)
+}
+
+/// Newest first. Directories without a readable record are skipped.
+pub async fn list_crashes(path_to_instance: &Path) -> Result<Vec<CrashRecord>, Error> {
+    let dir = path_to_crashes(path_to_instance);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut crashes = Vec::new();
+    for path in crate::util::list_dir(&dir, Some(true)).await? {
+        let text = match crate::util::fs::read_to_string(path.join(RECORD_FILE_NAME)).await {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        match serde_json::from_str::<CrashRecord>(&text) {
+            Ok(record) => crashes.push(record),
+            Err(e) => warn!("Skipping crash record in {}: {}", path.display(), e),
+        }
+    }
+    crashes.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.id.cmp(&a.id)));
+    Ok(crashes)
+}
+
+pub async fn get_crash(path_to_instance: &Path, id: &str) -> Result<CrashDetails, Error> {
+    let not_found = || Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Crash not found"),
+    };
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(not_found());
+    }
+    let dir = path_to_crashes(path_to_instance).join(id);
+    let record: CrashRecord =
+        match crate::util::fs::read_to_string(dir.join(RECORD_FILE_NAME)).await {
+            Ok(text) => serde_json::from_str(&text).context("Failed to parse crash record")?,
+            Err(_) => return Err(not_found()),
+        };
+    let read_if_exists = |name: &str| {
+        let path = dir.join(name);
+        async move {
+            if path.is_file() {
+                crate::util::fs::read_to_string(&path).await.map(Some)
+            } else {
+                Ok(None)
+            }
+        }
+    };
+    Ok(CrashDetails {
+        record,
+        report: read_if_exists(CRASH_REPORT_FILE_NAME).await?,
+        log: read_if_exists(LOG_FILE_NAME).await?,
+    })
+}
+
+/// Collects the crash report and log of Minecraft instances that exit
+/// abnormally, announcing each capture with a `CrashCaptured` event
+pub async fn crash_reports_task(state: AppState) {
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut last_states: HashMap<InstanceUuid, State> = HashMap::new();
+    let mut started: HashMap<InstanceUuid, SystemTime> = HashMap::new();
+    loop {
+        let (uuid, instance_name, to) = match event_receiver.recv().await {
+            Ok(event) => match event.event_inner {
+                EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid,
+                    instance_name,
+                    instance_event_inner: InstanceEventInner::StateTransition { to },
+                }) => (instance_uuid, instance_name, to),
+                _ => continue,
+            },
+            Err(RecvError::Lagged(_)) => {
+                warn!("Crash reports task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if to == State::Starting {
+            started.insert(uuid.clone(), SystemTime::now());
+        }
+        if !is_crash(last_states.insert(uuid.clone(), to), to) {
+            continue;
+        }
+        let path_to_instance = match state.instances.lock().await.get(&uuid) {
+            Some(instance @ GameInstance::MinecraftInstance(_)) => instance.path().await,
+            _ => continue,
+        };
+        let started = started.get(&uuid).copied();
+        let event_broadcaster = state.event_broadcaster.clone();
+        tokio::spawn(async move {
+            match capture_crash(&path_to_instance, to, started).await {
+                Ok(crash) => event_broadcaster.send(Event {
+                    details: {
+                        let mut details = match &crash.description {
+                            Some(description) => {
+                                format!("{} crashed: {}", instance_name, description)
+                            }
+                            None => format!("{} crashed", instance_name),
+                        };
+                        if !crash.culprit_mods.is_empty() {
+                            let ids: Vec<&str> = crash
+                                .culprit_mods
+                                .iter()
+                                .map(|culprit| culprit.mod_id.as_str())
+                                .collect();
+                            details.push_str(&format!(" (likely caused by {})", ids.join(", ")));
+                        }
+                        details
+                    },
+                    snowflake: Snowflake::default(),
+                    event_inner: EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid: uuid,
+                        instance_name,
+                        instance_event_inner: InstanceEventInner::CrashCaptured { crash },
+                    }),
+                    caused_by: CausedBy::System,
+                }),
+                Err(e) => error!("Failed to capture crash of instance {}: {}", uuid, e),
+            }
+        });
+    }
+}
+
+#[test]
+fn test_crash_report_description() {
+    let report = "---- Minecraft Crash Report ----\n// Who set us up the TNT?\n\nTime: 2024-01-01 04:00:00\nDescription: Exception in server tick loop\n\njava.lang.NullPointerException";
+    assert_eq!(
+        description(report),
+        Some("Exception in server tick loop".to_string())
+    );
+    assert_eq!(description("Description:   \n"), None);
+}
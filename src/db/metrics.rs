@@ -0,0 +1,220 @@
+use color_eyre::eyre::Context;
+use serde::Serialize;
+use sqlx::sqlite::SqlitePool;
+use ts_rs::TS;
+
+use crate::error::Error;
+use crate::types::InstanceUuid;
+
+/// One sample of an instance, as taken by the metrics history task
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSample {
+    pub instance_uuid: InstanceUuid,
+    pub time: i64,
+    pub cpu_usage: Option<f32>,
+    pub memory_usage: Option<u64>,
+    pub player_count: Option<u32>,
+    pub tps: Option<f32>,
+    pub mspt: Option<f32>,
+}
+
+/// The samples in one bucket of a query, averaged, with the peak player count
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct MetricPoint {
+    /// start of the bucket, in seconds
+    pub time: i64,
+    pub cpu_usage: Option<f64>,
+    /// in bytes
+    pub memory_usage: Option<u64>,
+    pub player_count: Option<u32>,
+    pub tps: Option<f64>,
+    pub mspt: Option<f64>,
+}
+
+/// Time series of instance metrics, in the same database as the events
+#[derive(Clone)]
+pub struct MetricsStore {
+    pool: SqlitePool,
+}
+
+impl MetricsStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, Error> {
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS InstanceMetrics (
+            instance_id         TEXT        NOT NULL,
+            time                BIGINT      NOT NULL,
+            cpu_usage           REAL,
+            memory_usage        BIGINT,
+            player_count        INTEGER,
+            tps                 REAL,
+            mspt                REAL
+        );
+        "#,
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to create metrics table")?;
+        // tick timings were added after the table was first created
+        let columns: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM pragma_table_info('InstanceMetrics')")
+                .fetch_all(&pool)
+                .await
+                .context("Failed to read metrics table")?;
+        for column in ["tps", "mspt"] {
+            if !columns.iter().any(|(name,)| name == column) {
+                sqlx::query(&format!(
+                    "ALTER TABLE InstanceMetrics ADD COLUMN {} REAL",
+                    column
+                ))
+                .execute(&pool)
+                .await
+                .context("Failed to migrate metrics table")?;
+            }
+        }
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS InstanceMetricsByTime ON InstanceMetrics (instance_id, time)",
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to create metrics index")?;
+        Ok(Self { pool })
+    }
+
+    pub async fn write(&self, samples: &[MetricSample]) -> Result<(), Error> {
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to start a transaction")?;
+        for sample in samples {
+            sqlx::query(
+                "INSERT INTO InstanceMetrics (instance_id, time, cpu_usage, memory_usage, player_count, tps, mspt) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )
+            .bind(sample.instance_uuid.as_ref())
+            .bind(sample.time)
+            .bind(sample.cpu_usage)
+            .bind(sample.memory_usage.map(|bytes| bytes as i64))
+            .bind(sample.player_count)
+            .bind(sample.tps)
+            .bind(sample.mspt)
+            .execute(&mut transaction)
+            .await
+            .context("Failed to write metrics")?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit metrics")?;
+        Ok(())
+    }
+
+    /// Samples of `uuid` between `from` and `to`, grouped into buckets of
+    /// `resolution` seconds. Buckets without samples are left out.
+    pub async fn query(
+        &self,
+        uuid: &InstanceUuid,
+        from: i64,
+        to: i64,
+        resolution: i64,
+    ) -> Result<Vec<MetricPoint>, Error> {
+        #[allow(clippy::type_complexity)]
+        let rows: Vec<(
+            i64,
+            Option<f64>,
+            Option<f64>,
+            Option<i64>,
+            Option<f64>,
+            Option<f64>,
+        )> = sqlx::query_as(
+            r#"
+SELECT
+(time / ?4) * ?4 AS bucket, AVG(cpu_usage), AVG(memory_usage), MAX(player_count), AVG(tps), AVG(mspt)
+FROM InstanceMetrics
+WHERE instance_id = ?1 AND time >= ?2 AND time <= ?3
+GROUP BY bucket
+ORDER BY bucket"#,
+        )
+        .bind(uuid.as_ref())
+        .bind(from)
+        .bind(to)
+        .bind(resolution)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to read metrics")?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(time, cpu_usage, memory_usage, player_count, tps, mspt)| MetricPoint {
+                    time,
+                    cpu_usage,
+                    memory_usage: memory_usage.map(|bytes| bytes as u64),
+                    player_count: player_count.map(|count| count as u32),
+                    tps,
+                    mspt,
+                },
+            )
+            .collect())
+    }
+
+    /// Drops samples older than `before`, and those of instances that are gone
+    pub async fn prune(&self, before: i64, instances: &[InstanceUuid]) -> Result<(), Error> {
+        sqlx::query("DELETE FROM InstanceMetrics WHERE time < ?1")
+            .bind(before)
+            .execute(&self.pool)
+            .await
+            .context("Failed to prune metrics")?;
+        let stored: Vec<(String,)> =
+            sqlx::query_as("SELECT DISTINCT instance_id FROM InstanceMetrics")
+                .fetch_all(&self.pool)
+                .await
+                .context("Failed to read metrics")?;
+        for (instance_id,) in stored {
+            if !instances.iter().any(|uuid| *uuid == instance_id) {
+                sqlx::query("DELETE FROM InstanceMetrics WHERE instance_id = ?1")
+                    .bind(&instance_id)
+                    .execute(&self.pool)
+                    .await
+                    .context("Failed to prune metrics")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_metrics_store() {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let store = MetricsStore::new(pool).await.unwrap();
+    let uuid = InstanceUuid::from("a".to_string());
+    let sample = |time, cpu_usage, player_count| MetricSample {
+        instance_uuid: uuid.clone(),
+        time,
+        cpu_usage: Some(cpu_usage),
+        memory_usage: Some(1024),
+        player_count: Some(player_count),
+        tps: Some(20.0),
+        mspt: None,
+    };
+    store
+        .write(&[sample(0, 10.0, 1), sample(30, 20.0, 4), sample(60, 5.0, 2)])
+        .await
+        .unwrap();
+    let points = store.query(&uuid, 0, 100, 60).await.unwrap();
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[0].cpu_usage, Some(15.0));
+    assert_eq!(points[0].player_count, Some(4));
+    assert_eq!(points[1].time, 60);
+    assert_eq!(points[1].tps, Some(20.0));
+    assert_eq!(points[1].mspt, None);
+
+    store.prune(30, &[uuid.clone()]).await.unwrap();
+    assert_eq!(store.query(&uuid, 0, 100, 1).await.unwrap().len(), 2);
+    store.prune(0, &[]).await.unwrap();
+    assert!(store.query(&uuid, 0, 100, 1).await.unwrap().is_empty());
+}
@@ -1,3 +1,5 @@
+pub mod metrics;
 pub mod read;
+pub mod state;
 pub mod types;
 pub mod write;
@@ -1,10 +1,13 @@
 use crate::{
-    error::Error, output_types::ClientEvent,
-    prelude::LODESTONE_EPOCH_MIL, events::EventQuery,
+    error::Error,
+    events::{EventQuery, EventType},
+    output_types::ClientEvent,
+    prelude::LODESTONE_EPOCH_MIL,
+    types::{InstanceUuid, Snowflake},
 };
 
 use color_eyre::eyre::Context;
-use sqlx::sqlite::SqlitePool;
+use sqlx::{sqlite::SqlitePool, QueryBuilder, Sqlite};
 use tracing::error;
 
 // TODO clean up all unwraps
@@ -69,6 +72,100 @@ FROM ClientEvents"#
     Ok(filtered)
 }
 
+/// Up to `limit` events newer than `cursor`, oldest first
+pub async fn events_after(
+    pool: &SqlitePool,
+    cursor: Snowflake,
+    limit: u32,
+) -> Result<Vec<ClientEvent>, Error> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to aquire connection to db")?;
+    let rows: Vec<(String,)> = sqlx::query_as(
+        r#"
+SELECT
+event_value
+FROM ClientEvents
+WHERE snowflake > ($1)
+ORDER BY snowflake
+LIMIT ($2)"#,
+    )
+    .bind(cursor)
+    .bind(limit)
+    .fetch_all(&mut connection)
+    .await
+    .context("Failed to fetch events")?;
+    let mut parsed_client_events: Vec<ClientEvent> = Vec::new();
+    for (event_value,) in rows {
+        if let Ok(client_event) = serde_json::from_str(&event_value) {
+            parsed_client_events.push(client_event);
+        } else {
+            error!("Failed to parse client event: {}", event_value);
+        }
+    }
+    Ok(parsed_client_events)
+}
+
+/// What `search_event_history` narrows the events down to, `None` matches all
+#[derive(Debug, Clone, Default)]
+pub struct EventHistoryQuery {
+    pub instance: Option<InstanceUuid>,
+    pub event_type: Option<EventType>,
+    /// only events newer than this
+    pub after: Option<Snowflake>,
+    /// only events older than this, the last event of the previous page
+    pub before: Option<Snowflake>,
+    pub limit: u32,
+}
+
+/// Up to `limit` events matching the query, newest first. Every condition is
+/// covered by one of the indices of `ClientEvents`.
+pub async fn search_event_history(
+    pool: &SqlitePool,
+    query: &EventHistoryQuery,
+) -> Result<Vec<ClientEvent>, Error> {
+    let mut connection = pool
+        .acquire()
+        .await
+        .context("Failed to aquire connection to db")?;
+    let mut builder: QueryBuilder<Sqlite> =
+        QueryBuilder::new("SELECT event_value FROM ClientEvents WHERE 1 = 1");
+    if let Some(instance) = &query.instance {
+        builder
+            .push(" AND instance_id = ")
+            .push_bind(instance.clone());
+    }
+    if let Some(event_type) = query.event_type {
+        builder
+            .push(" AND json_extract(event_value, '$.event_inner.type') = ")
+            .push_bind(format!("{:?}", event_type));
+    }
+    if let Some(after) = query.after {
+        builder.push(" AND snowflake > ").push_bind(after);
+    }
+    if let Some(before) = query.before {
+        builder.push(" AND snowflake < ").push_bind(before);
+    }
+    builder
+        .push(" ORDER BY snowflake DESC LIMIT ")
+        .push_bind(query.limit);
+    let rows: Vec<(String,)> = builder
+        .build_query_as()
+        .fetch_all(&mut connection)
+        .await
+        .context("Failed to fetch events")?;
+    let mut parsed_client_events: Vec<ClientEvent> = Vec::new();
+    for (event_value,) in rows {
+        if let Ok(client_event) = serde_json::from_str(&event_value) {
+            parsed_client_events.push(client_event);
+        } else {
+            error!("Failed to parse client event: {}", event_value);
+        }
+    }
+    Ok(parsed_client_events)
+}
+
 #[cfg(test)]
 #[allow(unused_imports)]
 mod tests {
@@ -77,8 +174,9 @@ mod tests {
     use sqlx::{sqlite::SqliteConnectOptions, Pool, Sqlite};
 
     use crate::{
-        db::write::init_client_events_table,
-        events::{CausedBy, EventInner, EventLevel, FSEvent, FSOperation, FSTarget},
+        db::write::{init_client_events_table, write_client_event},
+        events::{CausedBy, Event, EventInner, EventLevel, FSEvent, FSOperation, FSTarget},
+        traits::t_server::State,
         types::Snowflake,
     };
 
@@ -128,6 +226,110 @@ mod tests {
         // let row_1 = row_1_result.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_search_event_history() {
+        // one connection, every connection to memory opens a database of its own
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        init_client_events_table(&pool).await.unwrap();
+        let survival = InstanceUuid::from("survival".to_string());
+        let creative = InstanceUuid::from("creative".to_string());
+        let mut events = Vec::new();
+        for (uuid, state) in [
+            (&survival, State::Starting),
+            (&creative, State::Starting),
+            (&survival, State::Running),
+        ] {
+            let mut event =
+                Event::new_instance_state_transition(uuid.clone(), "name".to_string(), state);
+            event.snowflake = Snowflake::new();
+            events.push(ClientEvent::from(event));
+        }
+        events.push(ClientEvent {
+            event_inner: EventInner::FSEvent(FSEvent {
+                operation: FSOperation::Read,
+                target: FSTarget::File(PathBuf::from("/test")),
+            }),
+            details: "Dummy detail".to_string(),
+            snowflake: Snowflake::new(),
+            level: EventLevel::Info,
+            caused_by: CausedBy::System,
+        });
+        for event in &events {
+            write_client_event(&pool, event.clone()).await.unwrap();
+        }
+
+        let mut query = EventHistoryQuery {
+            instance: Some(survival.clone()),
+            limit: 1,
+            ..Default::default()
+        };
+        let first_page = search_event_history(&pool, &query).await.unwrap();
+        assert_eq!(first_page.len(), 1);
+        assert_eq!(first_page[0].snowflake, events[2].snowflake);
+        query.before = Some(first_page[0].snowflake);
+        let second_page = search_event_history(&pool, &query).await.unwrap();
+        assert_eq!(second_page[0].snowflake, events[0].snowflake);
+        query.before = Some(second_page[0].snowflake);
+        assert!(search_event_history(&pool, &query)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let by_type = EventHistoryQuery {
+            event_type: Some(EventType::FSEvent),
+            limit: 10,
+            ..Default::default()
+        };
+        let fs_events = search_event_history(&pool, &by_type).await.unwrap();
+        assert_eq!(fs_events.len(), 1);
+        assert_eq!(fs_events[0].snowflake, events[3].snowflake);
+
+        let after = EventHistoryQuery {
+            after: Some(events[1].snowflake),
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(search_event_history(&pool, &after).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_events_after() {
+        // one connection, every connection to memory opens a database of its own
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        assert!(events_after(&pool, Snowflake::new(), 10).await.is_err());
+        init_client_events_table(&pool).await.unwrap();
+        let uuid = InstanceUuid::from("survival".to_string());
+        let mut events = Vec::new();
+        for state in [State::Starting, State::Running, State::Stopping] {
+            let mut event =
+                Event::new_instance_state_transition(uuid.clone(), "name".to_string(), state);
+            event.snowflake = Snowflake::new();
+            events.push(ClientEvent::from(event));
+        }
+        for event in &events {
+            write_client_event(&pool, event.clone()).await.unwrap();
+        }
+
+        let after_first = events_after(&pool, events[0].snowflake, 10).await.unwrap();
+        let snowflakes: Vec<Snowflake> = after_first.iter().map(|e| e.snowflake).collect();
+        assert_eq!(snowflakes, vec![events[1].snowflake, events[2].snowflake]);
+        let limited = events_after(&pool, events[0].snowflake, 1).await.unwrap();
+        assert_eq!(limited.len(), 1);
+        assert_eq!(limited[0].snowflake, events[1].snowflake);
+        assert!(events_after(&pool, events[2].snowflake, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
     // TODO should properly implement tests, with dummy values
     // #[tokio::test]
     // async fn test_read() {
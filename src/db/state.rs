@@ -0,0 +1,394 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Context;
+use once_cell::sync::OnceCell;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use sqlx::sqlite::SqlitePool;
+use tracing::{error, info};
+
+use crate::error::Error;
+use crate::migration::schema::{self, VersionedFile};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::types::InstanceUuid;
+
+/// Names of the documents the core keeps in the state store
+pub const USERS: &str = "users";
+pub const GLOBAL_SETTINGS: &str = "global_settings";
+pub const DATABASE_HOSTS: &str = "database_hosts";
+pub const GLOBAL_BANS: &str = "global_bans";
+pub const ALERTS: &str = "alerts";
+pub const WEBHOOKS: &str = "webhooks";
+pub const SCHEDULED_TASKS: &str = "scheduled_tasks";
+/// uuid to path of the instances loaded on the last startup
+pub const INSTANCE_REGISTRY: &str = "instance_registry";
+/// passphrases backups were encrypted with, by key id
+pub const BACKUP_KEYS: &str = "backup_keys";
+/// kept per instance, see `instance_document`
+pub const INSTANCE_API_TOKENS: &str = "instance_api_tokens";
+pub const INSTANCE_DATABASES: &str = "instance_databases";
+pub const INSTANCE_DISCORD_WEBHOOKS: &str = "instance_discord_webhooks";
+pub const INSTANCE_DISCORD_BRIDGES: &str = "instance_discord_bridges";
+
+/// Name of the document `name` of one instance
+pub fn instance_document(name: &str, uuid: &InstanceUuid) -> String {
+    format!("{}/{}", name, uuid)
+}
+
+static STATE_STORE: OnceCell<StateStore> = OnceCell::new();
+
+/// The store for code that only has an instance's directory at hand, like backups
+pub fn state_store() -> &'static StateStore {
+    STATE_STORE.get().unwrap()
+}
+
+/// Should only be called once, at startup
+pub fn init_state_store(store: StateStore) {
+    let _ = STATE_STORE.set(store);
+}
+
+/// Core wide state kept as JSON documents in the core's database, like the events.
+/// A document is replaced in a single statement, so a crash mid write leaves the
+/// previous version instead of a truncated file. Per instance state stays in the
+/// instance directory so it moves with backups and migrations, except for secrets,
+/// which anyone with file access to the instance could read or plant there.
+#[derive(Clone)]
+pub struct StateStore {
+    pool: SqlitePool,
+}
+
+impl StateStore {
+    pub async fn new(pool: SqlitePool) -> Result<Self, Error> {
+        sqlx::query(
+            r#"
+        CREATE TABLE IF NOT EXISTS StateDocuments (
+            name                TEXT        PRIMARY KEY,
+            value               TEXT        NOT NULL,
+            updated_at          BIGINT      NOT NULL
+        );
+        "#,
+        )
+        .execute(&pool)
+        .await
+        .context("Failed to create state table")?;
+        Ok(Self { pool })
+    }
+
+    async fn get_value(&self, name: &str) -> Result<Option<Value>, Error> {
+        let row: Option<(String,)> =
+            sqlx::query_as("SELECT value FROM StateDocuments WHERE name = ?1")
+                .bind(name)
+                .fetch_optional(&self.pool)
+                .await
+                .context(format!("Failed to read {} from the state store", name))?;
+        match row {
+            Some((value,)) => Ok(Some(
+                serde_json::from_str(&value).context(format!("Failed to parse {}", name))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, Error> {
+        match self.get_value(name).await? {
+            Some(value) => Ok(Some(
+                serde_json::from_value(value).context(format!("Failed to parse {}", name))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set<T: Serialize>(&self, name: &str, value: &T) -> Result<(), Error> {
+        self.set_all(vec![(
+            name.to_string(),
+            serde_json::to_value(value).context(format!("Failed to serialize {}", name))?,
+        )])
+        .await
+    }
+
+    /// Replaces the documents together, either all of them are written or none
+    async fn set_all(&self, documents: Vec<(String, Value)>) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp();
+        let mut transaction = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to start a transaction")?;
+        for (name, value) in documents {
+            sqlx::query(
+                "INSERT OR REPLACE INTO StateDocuments (name, value, updated_at) VALUES (?1, ?2, ?3)",
+            )
+            .bind(&name)
+            .bind(value.to_string())
+            .bind(now)
+            .execute(&mut transaction)
+            .await
+            .context(format!("Failed to write {} to the state store", name))?;
+        }
+        transaction
+            .commit()
+            .await
+            .context("Failed to commit to the state store")?;
+        Ok(())
+    }
+
+    /// Every document, as written to a JSON file by the export route
+    pub async fn export(&self) -> Result<BTreeMap<String, Value>, Error> {
+        let rows: Vec<(String, String)> = sqlx::query_as("SELECT name, value FROM StateDocuments")
+            .fetch_all(&self.pool)
+            .await
+            .context("Failed to read the state store")?;
+        rows.into_iter()
+            .map(|(name, value)| {
+                let value =
+                    serde_json::from_str(&value).context(format!("Failed to parse {}", name))?;
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    /// Replaces the given documents in one transaction, leaving the others alone
+    pub async fn import(&self, documents: BTreeMap<String, Value>) -> Result<(), Error> {
+        self.set_all(documents.into_iter().collect()).await
+    }
+
+    /// Moves a JSON file written by an older core into the store, renaming it to
+    /// `<file name>.imported`. Nothing happens if the document exists already.
+    pub async fn adopt_file(&self, name: &str, path: &Path) -> Result<bool, Error> {
+        if self.get_value(name).await?.is_some() {
+            return Ok(false);
+        }
+        let value = match StateLocation::File(path.to_owned()).read::<Value>().await? {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        self.set(name, &value).await?;
+        let mut imported = path.as_os_str().to_owned();
+        imported.push(".imported");
+        crate::util::fs::rename(path, PathBuf::from(imported)).await?;
+        info!("Moved {} into the state store", path.display());
+        Ok(true)
+    }
+
+    /// The document `name` of an instance. A file an older core kept in the
+    /// instance directory is moved in and deleted, not renamed, so no copy of the
+    /// secrets in it is left behind.
+    pub async fn get_instance<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        uuid: &InstanceUuid,
+        legacy_file: &Path,
+    ) -> Result<Option<T>, Error> {
+        let name = instance_document(name, uuid);
+        if self.get_value(&name).await?.is_none() {
+            if let Some(value) = StateLocation::File(legacy_file.to_owned())
+                .read::<Value>()
+                .await?
+            {
+                self.set(&name, &value).await?;
+                crate::util::fs::remove_file(legacy_file).await?;
+                info!("Moved {} into the state store", legacy_file.display());
+            }
+        }
+        self.get(&name).await
+    }
+
+    pub async fn set_instance<T: Serialize>(
+        &self,
+        name: &str,
+        uuid: &InstanceUuid,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.set(&instance_document(name, uuid), value).await
+    }
+
+    /// Drops every document of a deleted instance
+    pub async fn remove_instance_documents(&self, uuid: &InstanceUuid) -> Result<(), Error> {
+        sqlx::query("DELETE FROM StateDocuments WHERE name LIKE '%/' || ?1")
+            .bind(uuid.to_string())
+            .execute(&self.pool)
+            .await
+            .context(format!("Failed to remove the state of instance {}", uuid))?;
+        Ok(())
+    }
+
+    /// Where the manager of `name` should keep its state. The legacy file is adopted
+    /// first, if that fails the manager keeps using it so nothing written there is lost.
+    pub async fn location(&self, name: &'static str, legacy_file: PathBuf) -> StateLocation {
+        match self.adopt_file(name, &legacy_file).await {
+            Ok(_) => StateLocation::Store {
+                store: self.clone(),
+                name,
+            },
+            Err(e) => {
+                error!(
+                    "Failed to move {} into the state store, it will keep being used: {}",
+                    legacy_file.display(),
+                    e
+                );
+                StateLocation::File(legacy_file)
+            }
+        }
+    }
+
+    /// The instances and their directories as of the last time they were recorded
+    pub async fn instance_registry(&self) -> Result<HashMap<InstanceUuid, PathBuf>, Error> {
+        Ok(self.get(INSTANCE_REGISTRY).await?.unwrap_or_default())
+    }
+
+    pub async fn record_instances(
+        &self,
+        instances: &HashMap<InstanceUuid, GameInstance>,
+    ) -> Result<(), Error> {
+        let mut registry = HashMap::new();
+        for (uuid, instance) in instances.iter() {
+            registry.insert(uuid.clone(), instance.path().await);
+        }
+        self.set(INSTANCE_REGISTRY, &registry).await
+    }
+
+    /// Upgrades a document like its file would be, keeping the old version as
+    /// `<name>.v<version>.bak`
+    async fn migrate_document(&self, name: &str, schema: &VersionedFile) -> Result<(), Error> {
+        let old = match self.get_value(name).await? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+        let (new, from) = schema.migrate(old.clone())?;
+        if from == schema.current_version() {
+            return Ok(());
+        }
+        self.set_all(vec![
+            (format!("{}.v{}.bak", name, from), old),
+            (name.to_string(), new),
+        ])
+        .await?;
+        info!(
+            "Migrated {} from version {} to {}",
+            name,
+            from,
+            schema.current_version()
+        );
+        Ok(())
+    }
+
+    /// Upgrades the versioned documents, the ones adopted from files are current
+    /// already but an import can bring older ones
+    pub async fn migrate_documents(&self) -> Result<(), Error> {
+        self.migrate_document(USERS, &schema::USERS).await?;
+        self.migrate_document(GLOBAL_SETTINGS, &schema::GLOBAL_SETTINGS)
+            .await
+    }
+}
+
+/// Where a manager keeps its state, a document in the state store or a JSON file
+#[derive(Clone)]
+pub enum StateLocation {
+    Store {
+        store: StateStore,
+        name: &'static str,
+    },
+    File(PathBuf),
+}
+
+impl From<PathBuf> for StateLocation {
+    fn from(path: PathBuf) -> Self {
+        StateLocation::File(path)
+    }
+}
+
+impl StateLocation {
+    /// `None` if nothing was written yet
+    pub async fn read<T: DeserializeOwned>(&self) -> Result<Option<T>, Error> {
+        match self {
+            StateLocation::Store { store, name } => store.get(name).await,
+            StateLocation::File(path) => {
+                if !path.is_file() {
+                    return Ok(None);
+                }
+                let content = crate::util::fs::read_to_string(path).await?;
+                if content.trim().is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(
+                    serde_json::from_str(&content)
+                        .context(format!("Failed to parse {}", path.display()))?,
+                ))
+            }
+        }
+    }
+
+    pub async fn write<T: Serialize>(&self, value: &T) -> Result<(), Error> {
+        match self {
+            StateLocation::Store { store, name } => store.set(name, value).await,
+            StateLocation::File(path) => {
+                // renamed into place so the file is never seen half written
+                let mut partial = path.as_os_str().to_owned();
+                partial.push(".partial");
+                let partial = PathBuf::from(partial);
+                crate::util::fs::write_all(
+                    &partial,
+                    serde_json::to_string_pretty(value)
+                        .context(format!("Failed to serialize {}", path.display()))?,
+                )
+                .await?;
+                crate::util::fs::rename(&partial, path).await
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_state_store() {
+    // every connection to memory opens a database of its own
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let store = StateStore::new(pool).await.unwrap();
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("global_bans.json");
+    std::fs::write(&path, r#"{"a":1}"#).unwrap();
+
+    assert!(store.adopt_file(GLOBAL_BANS, &path).await.unwrap());
+    assert!(!path.exists());
+    assert!(temp_dir.path().join("global_bans.json.imported").is_file());
+    let location = StateLocation::Store {
+        store: store.clone(),
+        name: GLOBAL_BANS,
+    };
+    assert_eq!(
+        location.read::<Value>().await.unwrap(),
+        Some(serde_json::json!({"a": 1}))
+    );
+
+    location.write(&serde_json::json!({"b": 2})).await.unwrap();
+    let exported = store.export().await.unwrap();
+    assert_eq!(exported.len(), 1);
+    assert_eq!(exported[GLOBAL_BANS], serde_json::json!({"b": 2}));
+    assert_eq!(store.get::<Value>(USERS).await.unwrap(), None);
+
+    let uuid = InstanceUuid::from("instance".to_string());
+    let legacy_file = temp_dir.path().join(".lodestone_api_tokens.json");
+    std::fs::write(&legacy_file, r#"{"tokens":[]}"#).unwrap();
+    assert_eq!(
+        store
+            .get_instance::<Value>(INSTANCE_API_TOKENS, &uuid, &legacy_file)
+            .await
+            .unwrap(),
+        Some(serde_json::json!({"tokens": []}))
+    );
+    assert!(!legacy_file.exists());
+    store.remove_instance_documents(&uuid).await.unwrap();
+    assert_eq!(
+        store
+            .get_instance::<Value>(INSTANCE_API_TOKENS, &uuid, &legacy_file)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(store.export().await.unwrap().len(), 1);
+}
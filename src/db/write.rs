@@ -49,7 +49,10 @@ pub async fn write_event_to_db_task(mut event_receiver: Receiver<Event>, sqlite_
     }
 }
 
-async fn write_client_event(pool: &SqlitePool, client_event: ClientEvent) -> Result<i64, Error> {
+pub(super) async fn write_client_event(
+    pool: &SqlitePool,
+    client_event: ClientEvent,
+) -> Result<i64, Error> {
     let mut connection = pool
         .acquire()
         .await
@@ -100,6 +103,18 @@ pub async fn init_client_events_table(pool: &SqlitePool) -> Result<(), Error> {
     .await
     .context("Failed to create table")?;
 
+    for index in [
+        "CREATE INDEX IF NOT EXISTS ClientEventsBySnowflake ON ClientEvents (snowflake)",
+        "CREATE INDEX IF NOT EXISTS ClientEventsByInstance ON ClientEvents (instance_id, snowflake)",
+        // what `search_event_history` filters the type by
+        "CREATE INDEX IF NOT EXISTS ClientEventsByType ON ClientEvents (json_extract(event_value, '$.event_inner.type'), snowflake)",
+    ] {
+        sqlx::query(index)
+            .execute(&mut connection)
+            .await
+            .context("Failed to create index")?;
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::db::state::{self, StateStore};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::TPlayer;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+const DISCORD_API: &str = "https://discord.com/api/v10";
+const POLL_INTERVAL: u64 = 3;
+/// longer Discord messages are cut off in game
+const MAX_IN_GAME_LENGTH: usize = 256;
+/// where the config of an instance was kept before it moved into the state store
+const LEGACY_FILE_NAME: &str = ".lodestone_discord_bridge.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct DiscordBridgeConfig {
+    pub enabled: bool,
+    /// needs the message content intent to read the channel.
+    /// Never sent to clients, an empty token in an update keeps the stored one
+    #[serde(default)]
+    pub bot_token: String,
+    pub channel_id: String,
+    /// if set, game chat is posted through the webhook under the player's name and avatar
+    pub webhook_url: Option<String>,
+    /// used when posting as the bot, `{player}` and `{message}` are substituted
+    pub game_to_discord_format: String,
+    /// `{user}` and `{message}` are substituted
+    pub discord_to_game_format: String,
+    pub relay_joins: bool,
+}
+
+impl Default for DiscordBridgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bot_token: String::new(),
+            channel_id: String::new(),
+            webhook_url: None,
+            game_to_discord_format: "**{player}**: {message}".to_string(),
+            discord_to_game_format: "[Discord] <{user}> {message}".to_string(),
+            relay_joins: true,
+        }
+    }
+}
+
+impl DiscordBridgeConfig {
+    /// The config of an instance. It's kept in the state store, out of reach of
+    /// anyone who can read the instance's files, since it holds the bot token.
+    pub async fn load(
+        store: &StateStore,
+        uuid: &InstanceUuid,
+        path_to_instance: &Path,
+    ) -> Result<Self, Error> {
+        Ok(store
+            .get_instance(
+                state::INSTANCE_DISCORD_BRIDGES,
+                uuid,
+                &path_to_instance.join(LEGACY_FILE_NAME),
+            )
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, store: &StateStore, uuid: &InstanceUuid) -> Result<(), Error> {
+        store
+            .set_instance(state::INSTANCE_DISCORD_BRIDGES, uuid, self)
+            .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.enabled && (self.bot_token.is_empty() || self.channel_id.is_empty()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A bot token and channel id are required"),
+            });
+        }
+        if !self.channel_id.chars().all(|c| c.is_ascii_digit()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Channel id must be numeric"),
+            });
+        }
+        if let Some(webhook_url) = &self.webhook_url {
+            if !webhook_url.starts_with("https://") {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Webhook url must be an https url"),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy of the config that is safe to hand out to clients
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        ret.bot_token.clear();
+        ret
+    }
+}
+
+#[derive(Deserialize)]
+struct DiscordAuthor {
+    username: String,
+    #[serde(default)]
+    bot: bool,
+}
+
+#[derive(Deserialize)]
+struct DiscordMessage {
+    id: String,
+    content: String,
+    author: DiscordAuthor,
+    /// set on messages posted through a webhook, including our own
+    webhook_id: Option<String>,
+}
+
+fn format_for_game(config: &DiscordBridgeConfig, user: &str, message: &str) -> String {
+    let message: String = message.chars().take(MAX_IN_GAME_LENGTH).collect();
+    command_template::render(
+        &config.discord_to_game_format,
+        &[("user", user), ("message", &message)],
+    )
+}
+
+async fn post_to_discord(
+    client: &reqwest::Client,
+    config: &DiscordBridgeConfig,
+    player: &str,
+    message: &str,
+) -> Result<(), Error> {
+    // never ping anyone from game chat
+    let allowed_mentions = json!({ "parse": [] });
+    let request = match &config.webhook_url {
+        Some(webhook_url) => client.post(webhook_url).json(&json!({
+            "content": message,
+            "username": player,
+            "avatar_url": format!("https://mc-heads.net/avatar/{}", player),
+            "allowed_mentions": allowed_mentions,
+        })),
+        None => client
+            .post(format!(
+                "{}/channels/{}/messages",
+                DISCORD_API, config.channel_id
+            ))
+            .header("Authorization", format!("Bot {}", config.bot_token))
+            .json(&json!({
+                "content": command_template::render(
+                    &config.game_to_discord_format,
+                    &[("player", player), ("message", message)],
+                ),
+                "allowed_mentions": allowed_mentions,
+            })),
+    };
+    request
+        .send()
+        .await
+        .context("Failed to reach Discord")?
+        .error_for_status()
+        .context("Discord rejected the message")?;
+    Ok(())
+}
+
+/// Messages after `after`, oldest first. Without `after` only the latest message is
+/// fetched, to find where to start.
+async fn fetch_messages(
+    client: &reqwest::Client,
+    config: &DiscordBridgeConfig,
+    after: Option<&str>,
+) -> Result<Vec<DiscordMessage>, Error> {
+    let query = match after {
+        Some(after) => format!("after={}&limit=50", after),
+        None => "limit=1".to_string(),
+    };
+    let mut messages: Vec<DiscordMessage> = client
+        .get(format!(
+            "{}/channels/{}/messages?{}",
+            DISCORD_API, config.channel_id, query
+        ))
+        .header("Authorization", format!("Bot {}", config.bot_token))
+        .send()
+        .await
+        .context("Failed to reach Discord")?
+        .error_for_status()
+        .context("Failed to read Discord channel")?
+        .json()
+        .await
+        .context("Failed to parse Discord messages")?;
+    messages.reverse();
+    Ok(messages)
+}
+
+async fn relay_from_discord(
+    client: &reqwest::Client,
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    state_store: &StateStore,
+    last_message_ids: &mut HashMap<InstanceUuid, String>,
+) {
+    let mut running = Vec::new();
+    for (uuid, instance) in instances.lock().await.iter() {
+        if instance.state().await == State::Running {
+            running.push((uuid.clone(), instance.path().await));
+        }
+    }
+    last_message_ids.retain(|uuid, _| running.iter().any(|(u, _)| u == uuid));
+    for (uuid, path) in running {
+        let config = match DiscordBridgeConfig::load(state_store, &uuid, &path).await {
+            Ok(config) if config.enabled => config,
+            Ok(_) => continue,
+            Err(e) => {
+                error!("Failed to load Discord bridge config of {}: {}", uuid, e);
+                continue;
+            }
+        };
+        let last_message_id = last_message_ids.get(&uuid).cloned();
+        let messages = match fetch_messages(client, &config, last_message_id.as_deref()).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                warn!("Discord bridge of instance {}: {}", uuid, e);
+                continue;
+            }
+        };
+        if let Some(latest) = messages.last() {
+            last_message_ids.insert(uuid.clone(), latest.id.clone());
+        }
+        // the first poll only finds the starting point
+        if last_message_id.is_none() {
+            continue;
+        }
+        for message in messages {
+            if message.author.bot || message.webhook_id.is_some() || message.content.is_empty() {
+                continue;
+            }
+            let command = match command_template::tellraw(
+                "@a",
+                &format_for_game(&config, &message.author.username, &message.content),
+            ) {
+                Ok(command) => command,
+                Err(_) => continue,
+            };
+            if let Some(instance) = instances.lock().await.get(&uuid) {
+                let _ = instance.send_command(&command, CausedBy::System).await;
+            }
+        }
+    }
+}
+
+/// Relays chat between every instance with an enabled bridge and its Discord channel
+pub async fn discord_bridge_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    state_store: StateStore,
+    event_broadcaster: EventBroadcaster,
+) {
+    let client = reqwest::Client::new();
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut poll_interval = tokio::time::interval(Duration::from_secs(POLL_INTERVAL));
+    let mut last_message_ids: HashMap<InstanceUuid, String> = HashMap::new();
+    loop {
+        tokio::select! {
+            event = event_receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Discord bridge task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let (uuid, inner) = match event.event_inner {
+                    EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid,
+                        instance_event_inner,
+                        ..
+                    }) => (instance_uuid, instance_event_inner),
+                    _ => continue,
+                };
+                // (player, message) pairs to post, and whether they are joins and leaves
+                let (outgoing, is_join): (Vec<(String, String)>, bool) = match inner {
+                    InstanceEventInner::PlayerMessage {
+                        player,
+                        player_message,
+                    } => (vec![(player, player_message)], false),
+                    InstanceEventInner::PlayerChange {
+                        players_joined,
+                        players_left,
+                        ..
+                    } => (
+                        players_joined
+                            .iter()
+                            .map(|p| (p.get_name(), "joined the game".to_string()))
+                            .chain(
+                                players_left
+                                    .iter()
+                                    .map(|p| (p.get_name(), "left the game".to_string())),
+                            )
+                            .collect(),
+                        true,
+                    ),
+                    _ => continue,
+                };
+                let path = match instances.lock().await.get(&uuid) {
+                    Some(instance) => instance.path().await,
+                    None => continue,
+                };
+                let config = match DiscordBridgeConfig::load(&state_store, &uuid, &path).await {
+                    Ok(config) if config.enabled => config,
+                    _ => continue,
+                };
+                if is_join && !config.relay_joins {
+                    continue;
+                }
+                for (player, message) in outgoing {
+                    if let Err(e) = post_to_discord(&client, &config, &player, &message).await {
+                        warn!("Discord bridge of instance {}: {}", uuid, e);
+                    }
+                }
+            }
+            _ = poll_interval.tick() => {
+                relay_from_discord(&client, &instances, &state_store, &mut last_message_ids).await;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_format_for_game() {
+    let config = DiscordBridgeConfig::default();
+    let text = format_for_game(&config, "Alex", "hi\nthere \"friend\"");
+    assert_eq!(text, "[Discord] <Alex> hi there \"friend\"");
+    assert_eq!(
+        command_template::tellraw("@a", &text).unwrap(),
+        r#"tellraw @a {"text":"[Discord] <Alex> hi there \"friend\""}"#
+    );
+}
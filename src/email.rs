@@ -0,0 +1,103 @@
+use color_eyre::eyre::{eyre, Context};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// SMTP server the core sends mail through, for alerts
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct EmailSettings {
+    /// mail is off while this is empty
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    /// TLS from the start, usually on port 465, instead of STARTTLS
+    pub implicit_tls: bool,
+    pub username: String,
+    /// never sent to clients, an empty password in an update keeps the stored one
+    #[serde(default)]
+    pub password: String,
+    pub from: String,
+}
+
+impl Default for EmailSettings {
+    fn default() -> Self {
+        Self {
+            smtp_host: String::new(),
+            smtp_port: 587,
+            implicit_tls: false,
+            username: String::new(),
+            password: String::new(),
+            from: String::new(),
+        }
+    }
+}
+
+impl EmailSettings {
+    pub fn is_configured(&self) -> bool {
+        !self.smtp_host.is_empty()
+    }
+
+    /// Validates the settings and keeps the password of `old` if none was sent
+    pub fn prepare(&mut self, old: &EmailSettings) -> Result<(), Error> {
+        if self.password.is_empty() && self.smtp_host == old.smtp_host {
+            self.password = old.password.clone();
+        }
+        if self.is_configured() && self.from.parse::<Mailbox>().is_err() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("\"{}\" is not a valid sender address", self.from),
+            });
+        }
+        Ok(())
+    }
+
+    /// Copy of the settings that is safe to hand out to clients
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        ret.password.clear();
+        ret
+    }
+}
+
+pub async fn send_email(
+    settings: &EmailSettings,
+    to: &str,
+    subject: &str,
+    body: String,
+) -> Result<(), Error> {
+    if !settings.is_configured() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("No SMTP server is configured"),
+        });
+    }
+    let message = Message::builder()
+        .from(settings.from.parse().context("Invalid sender address")?)
+        .to(to.parse().context("Invalid recipient address")?)
+        .subject(subject)
+        .body(body)
+        .context("Failed to build email")?;
+    let mut transport = if settings.implicit_tls {
+        AsyncSmtpTransport::<Tokio1Executor>::relay(&settings.smtp_host)
+    } else {
+        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&settings.smtp_host)
+    }
+    .context("Failed to set up SMTP transport")?
+    .port(settings.smtp_port);
+    if !settings.username.is_empty() {
+        transport = transport.credentials(Credentials::new(
+            settings.username.clone(),
+            settings.password.clone(),
+        ));
+    }
+    transport
+        .build()
+        .send(message)
+        .await
+        .context("Failed to send email")?;
+    Ok(())
+}
@@ -9,15 +9,25 @@ use serde_json::json;
 use thiserror::Error;
 use ts_rs::TS;
 
+use crate::traits::Capability;
+
 #[derive(Debug, Clone, Deserialize, Serialize, TS)]
 #[ts(export)]
 pub enum ErrorKind {
     NotFound,
     UnsupportedOperation,
+    /// the instance lacks a capability the request needs
+    OperationNotSupported {
+        capability: Capability,
+    },
     BadRequest,
     PermissionDenied,
     Unauthorized,
     Internal,
+    /// the core is in maintenance mode
+    Maintenance,
+    /// the Minecraft EULA of the instance hasn't been accepted
+    EulaNotAccepted,
 }
 
 #[derive(Error, Debug)]
@@ -32,10 +42,24 @@ impl Display for ErrorKind {
         match self {
             ErrorKind::NotFound => write!(f, "Not Found"),
             ErrorKind::UnsupportedOperation => write!(f, "Unsupported Operation"),
+            ErrorKind::OperationNotSupported { capability } => {
+                write!(f, "Operation Not Supported ({:?})", capability)
+            }
             ErrorKind::BadRequest => write!(f, "Bad Request"),
             ErrorKind::PermissionDenied => write!(f, "Permission Denied"),
             ErrorKind::Unauthorized => write!(f, "Unauthorized"),
             ErrorKind::Internal => write!(f, "Internal Error"),
+            ErrorKind::Maintenance => write!(f, "Maintenance"),
+            ErrorKind::EulaNotAccepted => write!(f, "EULA Not Accepted"),
+        }
+    }
+}
+
+impl Error {
+    pub fn not_supported(capability: Capability) -> Self {
+        Self {
+            kind: ErrorKind::OperationNotSupported { capability },
+            source: color_eyre::eyre::eyre!("This instance does not support {}", capability),
         }
     }
 }
@@ -61,6 +85,11 @@ fn test_error_serialization() {
     };
     let json = serde_json::to_string(&error).unwrap();
     assert_eq!(json, r#"{"kind":"NotFound","causes":["Test"]}"#);
+    let json = serde_json::to_string(&Error::not_supported(Capability::SupportsMacros)).unwrap();
+    assert_eq!(
+        json,
+        r#"{"kind":{"OperationNotSupported":{"capability":"SupportsMacros"}},"causes":["This instance does not support macros"]}"#
+    );
 }
 
 impl IntoResponse for Error {
@@ -68,10 +97,13 @@ impl IntoResponse for Error {
         let status = match self.kind {
             ErrorKind::NotFound => StatusCode::NOT_FOUND,
             ErrorKind::UnsupportedOperation => StatusCode::NOT_IMPLEMENTED,
+            ErrorKind::OperationNotSupported { .. } => StatusCode::NOT_IMPLEMENTED,
             ErrorKind::BadRequest => StatusCode::BAD_REQUEST,
             ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
             ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
             ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::EulaNotAccepted => StatusCode::CONFLICT,
         };
         (status, json!(self).to_string()).into_response()
     }
@@ -103,3 +135,19 @@ impl From<ErrorKind> for Error {
         }
     }
 }
+
+#[test]
+fn test_error_status() {
+    let status = |error: Error| error.into_response().status();
+    assert_eq!(
+        status(Error::not_supported(Capability::SupportsRcon)),
+        StatusCode::NOT_IMPLEMENTED
+    );
+    assert_eq!(
+        status(Error {
+            kind: ErrorKind::BadRequest,
+            source: Report::msg("Test"),
+        }),
+        StatusCode::BAD_REQUEST
+    );
+}
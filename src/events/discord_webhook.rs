@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::alerts::is_crash;
+use crate::command_template;
+use crate::db::state::{self, StateStore};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, InstanceEvent, InstanceEventInner};
+use crate::global_settings::GlobalSettings;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::TPlayer;
+use crate::traits::t_server::State;
+use crate::types::InstanceUuid;
+
+/// Discord rejects longer messages
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+/// where the config of an instance was kept before it moved into the state store
+const LEGACY_FILE_NAME: &str = ".lodestone_discord_webhook.json";
+
+/// Events that can be forwarded to Discord
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq, Hash)]
+#[ts(export)]
+pub enum NotificationKind {
+    InstanceStarted,
+    InstanceStopped,
+    InstanceCrashed,
+    PlayerJoined,
+    PlayerLeft,
+    BackupCreated,
+    BackupFailed,
+}
+
+impl NotificationKind {
+    fn default_template(&self) -> &'static str {
+        match self {
+            NotificationKind::InstanceStarted => ":green_circle: **{instance}** started",
+            NotificationKind::InstanceStopped => ":red_circle: **{instance}** stopped",
+            NotificationKind::InstanceCrashed => ":boom: **{instance}** crashed",
+            NotificationKind::PlayerJoined => "**{player}** joined {instance}",
+            NotificationKind::PlayerLeft => "**{player}** left {instance}",
+            NotificationKind::BackupCreated => ":floppy_disk: {details} of **{instance}**",
+            NotificationKind::BackupFailed => {
+                ":warning: Backup of **{instance}** failed: {details}"
+            }
+        }
+    }
+}
+
+/// Where and which events are posted. The config of an instance takes the place
+/// of the global one while it's enabled.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct DiscordWebhookConfig {
+    pub enabled: bool,
+    pub webhook_url: String,
+    /// posted under this name instead of the webhook's
+    pub username: Option<String>,
+    pub events: Vec<NotificationKind>,
+    /// replaces the default message of an event. `{instance}`, `{player}` and
+    /// `{details}` are substituted
+    #[serde(default)]
+    pub templates: HashMap<NotificationKind, String>,
+}
+
+impl Default for DiscordWebhookConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            webhook_url: String::new(),
+            username: None,
+            events: vec![
+                NotificationKind::InstanceCrashed,
+                NotificationKind::BackupFailed,
+            ],
+            templates: HashMap::new(),
+        }
+    }
+}
+
+impl DiscordWebhookConfig {
+    /// The config of an instance. It's kept in the state store since anyone with
+    /// the url can post to the channel.
+    pub async fn load(
+        store: &StateStore,
+        uuid: &InstanceUuid,
+        path_to_instance: &Path,
+    ) -> Result<Self, Error> {
+        Ok(store
+            .get_instance(
+                state::INSTANCE_DISCORD_WEBHOOKS,
+                uuid,
+                &path_to_instance.join(LEGACY_FILE_NAME),
+            )
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, store: &StateStore, uuid: &InstanceUuid) -> Result<(), Error> {
+        store
+            .set_instance(state::INSTANCE_DISCORD_WEBHOOKS, uuid, self)
+            .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.enabled && self.webhook_url.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A webhook url is required"),
+            });
+        }
+        if !self.webhook_url.is_empty() && !self.webhook_url.starts_with("https://") {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Webhook url must be an https url"),
+            });
+        }
+        if let Some(username) = &self.username {
+            if username.is_empty() || username.chars().count() > 80 {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Username must be between 1 and 80 characters"),
+                });
+            }
+        }
+        if self
+            .templates
+            .values()
+            .any(|template| template.is_empty() || template.len() > MAX_MESSAGE_LENGTH)
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Templates must be between 1 and {} characters",
+                    MAX_MESSAGE_LENGTH
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Copy of the config for users that can't change it, anyone with the url can post
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        ret.webhook_url.clear();
+        ret
+    }
+
+    fn render(&self, kind: NotificationKind, values: &[(&str, &str)]) -> String {
+        let template = self
+            .templates
+            .get(&kind)
+            .map(String::as_str)
+            .unwrap_or_else(|| kind.default_template());
+        command_template::render(template, values)
+            .chars()
+            .take(MAX_MESSAGE_LENGTH)
+            .collect()
+    }
+}
+
+/// What an event is posted as, `(kind, player, details)`. A stop is a crash if
+/// the instance didn't go through `Stopping`.
+fn notifications(
+    inner: &InstanceEventInner,
+    previous: Option<State>,
+) -> Vec<(NotificationKind, String, String)> {
+    match inner {
+        InstanceEventInner::StateTransition { to: State::Running } => {
+            vec![(
+                NotificationKind::InstanceStarted,
+                String::new(),
+                String::new(),
+            )]
+        }
+        InstanceEventInner::StateTransition { to } if is_crash(previous, *to) => {
+            vec![(
+                NotificationKind::InstanceCrashed,
+                String::new(),
+                String::new(),
+            )]
+        }
+        InstanceEventInner::StateTransition { to: State::Stopped } => {
+            vec![(
+                NotificationKind::InstanceStopped,
+                String::new(),
+                String::new(),
+            )]
+        }
+        InstanceEventInner::PlayerChange {
+            players_joined,
+            players_left,
+            ..
+        } => players_joined
+            .iter()
+            .map(|p| (NotificationKind::PlayerJoined, p.get_name(), String::new()))
+            .chain(
+                players_left
+                    .iter()
+                    .map(|p| (NotificationKind::PlayerLeft, p.get_name(), String::new())),
+            )
+            .collect(),
+        InstanceEventInner::BackupCreated { backup } => vec![(
+            NotificationKind::BackupCreated,
+            String::new(),
+            format!("Created {}", backup.describe()),
+        )],
+        InstanceEventInner::BackupFailed { message } => vec![(
+            NotificationKind::BackupFailed,
+            String::new(),
+            message.clone(),
+        )],
+        _ => Vec::new(),
+    }
+}
+
+async fn post_to_webhook(
+    client: &reqwest::Client,
+    config: &DiscordWebhookConfig,
+    content: String,
+) -> Result<(), Error> {
+    let mut body = json!({
+        "content": content,
+        // player names and templates must never ping anyone
+        "allowed_mentions": { "parse": [] },
+    });
+    if let Some(username) = &config.username {
+        body["username"] = json!(username);
+    }
+    client
+        .post(&config.webhook_url)
+        .timeout(Duration::from_secs(10))
+        .json(&body)
+        .send()
+        .await
+        .context("Failed to reach Discord")?
+        .error_for_status()
+        .context("Discord rejected the message")?;
+    Ok(())
+}
+
+/// Forwards the selected events of every instance to its Discord webhook, or the
+/// global one
+pub async fn discord_webhook_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    state_store: StateStore,
+    event_broadcaster: EventBroadcaster,
+) {
+    let client = reqwest::Client::new();
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut last_states: HashMap<InstanceUuid, State> = HashMap::new();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Discord webhook task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let (uuid, instance_name, inner) = match event.event_inner {
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid,
+                instance_name,
+                instance_event_inner,
+            }) => (instance_uuid, instance_name, instance_event_inner),
+            _ => continue,
+        };
+        let previous = match &inner {
+            InstanceEventInner::StateTransition { to } => last_states.insert(uuid.clone(), *to),
+            _ => None,
+        };
+        let outgoing = notifications(&inner, previous);
+        if outgoing.is_empty() {
+            continue;
+        }
+        let path = match instances.lock().await.get(&uuid) {
+            Some(instance) => instance.path().await,
+            None => continue,
+        };
+        let config = match DiscordWebhookConfig::load(&state_store, &uuid, &path).await {
+            Ok(config) if config.enabled => config,
+            Ok(_) => global_settings.lock().await.discord_webhook(),
+            Err(e) => {
+                warn!("Failed to load Discord webhook config of {}: {}", uuid, e);
+                continue;
+            }
+        };
+        if !config.enabled {
+            continue;
+        }
+        for (kind, player, details) in outgoing {
+            if !config.events.contains(&kind) {
+                continue;
+            }
+            let content = config.render(
+                kind,
+                &[
+                    ("instance", &instance_name),
+                    ("player", &player),
+                    ("details", &details),
+                ],
+            );
+            let client = client.clone();
+            let config = config.clone();
+            let uuid = uuid.clone();
+            tokio::spawn(async move {
+                if let Err(e) = post_to_webhook(&client, &config, content).await {
+                    warn!("Discord webhook of instance {}: {}", uuid, e);
+                }
+            });
+        }
+    }
+}
+
+#[test]
+fn test_notifications() {
+    let crashed = notifications(
+        &InstanceEventInner::StateTransition { to: State::Stopped },
+        Some(State::Running),
+    );
+    assert_eq!(crashed[0].0, NotificationKind::InstanceCrashed);
+    let stopped = notifications(
+        &InstanceEventInner::StateTransition { to: State::Stopped },
+        Some(State::Stopping),
+    );
+    assert_eq!(stopped[0].0, NotificationKind::InstanceStopped);
+
+    let mut config = DiscordWebhookConfig::default();
+    assert_eq!(
+        config.render(
+            NotificationKind::InstanceCrashed,
+            &[("instance", "Survival"), ("player", ""), ("details", "")]
+        ),
+        ":boom: **Survival** crashed"
+    );
+    config.templates.insert(
+        NotificationKind::PlayerJoined,
+        "{player} is on {instance} {unknown}".to_string(),
+    );
+    assert_eq!(
+        config.render(
+            NotificationKind::PlayerJoined,
+            &[
+                ("instance", "Survival"),
+                ("player", "Alex"),
+                ("details", "")
+            ]
+        ),
+        "Alex is on Survival {unknown}"
+    );
+}
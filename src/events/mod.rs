@@ -1,5 +1,8 @@
 #![allow(clippy::enum_variant_names)]
 
+pub mod discord_webhook;
+pub mod webhooks;
+
 use std::{collections::HashSet, path::PathBuf};
 
 use serde::{Deserialize, Serialize};
@@ -7,8 +10,12 @@ use ts_rs::TS;
 
 use crate::{
     auth::{permission::UserPermission, user_id::UserId},
+    backup::BackupEntry,
+    crash_reports::CrashRecord,
     macro_executor::MacroPID,
     output_types::ClientEvent,
+    player_sessions::PlayerSession,
+    startup_triage::StartupDiagnosis,
     traits::{t_macro::ExitStatus, t_player::Player, t_server::State, InstanceInfo},
     types::{InstanceUuid, Snowflake, TimeRange},
 };
@@ -17,7 +24,7 @@ pub trait EventFilter {
     fn filter(&mut self, event: impl AsRef<ClientEvent>) -> bool;
 }
 
-#[derive(Deserialize, Clone, Debug, TS)]
+#[derive(Deserialize, Clone, Debug, TS, PartialEq)]
 #[ts(export)]
 pub struct EventQuery {
     pub event_levels: Option<Vec<EventLevel>>,
@@ -120,6 +127,41 @@ pub enum InstanceEventInner {
         player: String,
         player_message: String,
     },
+    PlayerSessionStarted {
+        session: PlayerSession,
+    },
+    BackupCreated {
+        backup: BackupEntry,
+    },
+    BackupFailed {
+        message: String,
+    },
+    /// deleted to stay within the instance's backup policy
+    BackupPruned {
+        backup: BackupEntry,
+    },
+    /// TPS dropped below the configured threshold, sent again only after it recovered
+    PerformanceDegraded {
+        tps: f32,
+        threshold: f32,
+    },
+    /// the instance kept crashing and restart on crash gave up on it
+    InstanceCrashLoop {
+        crashes: u32,
+        window_secs: u64,
+        /// the last lines of its console, oldest first
+        log_tail: Vec<String>,
+    },
+    /// the crash report and log of a crash were saved
+    CrashCaptured {
+        crash: CrashRecord,
+    },
+    /// the instance stopped before it got running
+    StartupFailed {
+        /// empty if no known failure was found in the log
+        diagnoses: Vec<StartupDiagnosis>,
+        log_tail: Vec<String>,
+    },
 }
 
 impl AsRef<InstanceEventInner> for InstanceEventInner {
@@ -210,6 +252,11 @@ pub enum ProgressionEndValue {
         success: bool,
         message: String,
     },
+    /// a risky change, with the backup taken before it if there is one
+    InstanceChanged {
+        instance_uuid: InstanceUuid,
+        backup_id: Option<String>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, TS, PartialEq)]
@@ -331,9 +378,20 @@ fn event_type_export() {
 #[ts(export)]
 #[serde(tag = "type")]
 pub enum CausedBy {
-    User { user_id: UserId, user_name: String },
-    Instance { instance_uuid: InstanceUuid },
-    Macro { macro_pid: MacroPID },
+    User {
+        user_id: UserId,
+        user_name: String,
+    },
+    Instance {
+        instance_uuid: InstanceUuid,
+    },
+    Macro {
+        macro_pid: MacroPID,
+    },
+    ApiToken {
+        token_id: String,
+        token_name: String,
+    },
     System,
     Unknown,
 }
@@ -484,6 +542,40 @@ impl Event {
         }
     }
 
+    pub fn new_instance_warning(
+        instance_uuid: InstanceUuid,
+        instance_name: String,
+        message: String,
+    ) -> Event {
+        Event {
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid,
+                instance_name,
+                instance_event_inner: InstanceEventInner::InstanceWarning { message },
+            }),
+            caused_by: CausedBy::System,
+        }
+    }
+
+    pub fn new_instance_error(
+        instance_uuid: InstanceUuid,
+        instance_name: String,
+        message: String,
+    ) -> Event {
+        Event {
+            details: "".to_string(),
+            snowflake: Snowflake::default(),
+            event_inner: EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid,
+                instance_name,
+                instance_event_inner: InstanceEventInner::InstanceError { message },
+            }),
+            caused_by: CausedBy::System,
+        }
+    }
+
     pub fn new_instance_state_transition(
         instance_uuid: InstanceUuid,
         instance_name: String,
@@ -0,0 +1,424 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::db::state::StateLocation;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{Event, EventInner, EventType, InstanceEventKind};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::util::rand_alphanumeric;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// attempts at delivering an event, the first one included
+const MAX_ATTEMPTS: u32 = 6;
+/// deliveries kept per webhook, newest first
+const DELIVERY_HISTORY: usize = 50;
+
+/// An HTTP endpoint every matching event is posted to as JSON
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct Webhook {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    pub enabled: bool,
+    /// every type if empty
+    pub event_types: Vec<EventType>,
+    /// only these instance events are sent, every kind if empty
+    pub instance_event_types: Vec<InstanceEventKind>,
+    /// only events of these instances are sent, events of every instance and
+    /// those of no instance if empty
+    pub instances: Vec<InstanceUuid>,
+    /// key of the `X-Lodestone-Signature-256` header, only shown when the
+    /// webhook is created
+    pub secret: String,
+    pub created_by: String,
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NewWebhook {
+    pub name: String,
+    pub url: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub event_types: Vec<EventType>,
+    #[serde(default)]
+    pub instance_event_types: Vec<InstanceEventKind>,
+    #[serde(default)]
+    pub instances: Vec<InstanceUuid>,
+    /// a random one is made for a new webhook if empty, an update keeps the old one
+    #[serde(default)]
+    pub secret: String,
+}
+
+impl NewWebhook {
+    pub fn validate(&self) -> Result<(), Error> {
+        let bad_request = |message: &str| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(message.to_string()),
+        };
+        if self.name.trim().is_empty() || self.name.len() > 64 {
+            return Err(bad_request(
+                "Webhook name must be between 1 and 64 characters",
+            ));
+        }
+        let url = url::Url::parse(&self.url)
+            .map_err(|_| bad_request("Webhook url is not a valid url"))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(bad_request("Webhook url must be an http or https url"));
+        }
+        if !self.secret.is_empty() && self.secret.len() < 16 {
+            return Err(bad_request(
+                "Webhook secret must be at least 16 characters long",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Webhook {
+    /// The webhook made from `new`, keeping the id, creator and secret of `old`
+    pub fn from_new(new: NewWebhook, old: Option<&Webhook>, created_by: String) -> Self {
+        let secret = match (new.secret.is_empty(), old) {
+            (false, _) => new.secret,
+            (true, Some(old)) => old.secret.clone(),
+            (true, None) => rand_alphanumeric(32),
+        };
+        Self {
+            id: old
+                .map(|old| old.id.clone())
+                .unwrap_or_else(|| format!("WEBHOOK_{}", rand_alphanumeric(12))),
+            name: new.name,
+            url: new.url,
+            enabled: new.enabled,
+            event_types: new.event_types,
+            instance_event_types: new.instance_event_types,
+            instances: new.instances,
+            secret,
+            created_by: old.map(|old| old.created_by.clone()).unwrap_or(created_by),
+        }
+    }
+
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        ret.secret.clear();
+        ret
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if !self.event_types.is_empty()
+            && !self
+                .event_types
+                .contains(&event.event_inner.as_ref().into())
+        {
+            return false;
+        }
+        if !self.instance_event_types.is_empty() {
+            match &event.event_inner {
+                EventInner::InstanceEvent(instance_event) => {
+                    if !self
+                        .instance_event_types
+                        .contains(&instance_event.instance_event_inner.as_ref().into())
+                    {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
+        }
+        if !self.instances.is_empty() {
+            match event.get_instance_uuid() {
+                Some(uuid) if self.instances.contains(&uuid) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// An event posted to a webhook, updated after every attempt
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct WebhookDelivery {
+    /// also sent in the `X-Lodestone-Delivery` header, the same on every attempt
+    pub id: String,
+    pub event_snowflake: Snowflake,
+    pub event_type: EventType,
+    pub attempts: u32,
+    /// of the last response, `None` if there was none
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    /// of the first attempt
+    pub time: i64,
+}
+
+pub struct WebhooksManager {
+    location: StateLocation,
+    webhooks: HashMap<String, Webhook>,
+    /// not persisted
+    deliveries: HashMap<String, VecDeque<WebhookDelivery>>,
+}
+
+impl WebhooksManager {
+    pub fn new(location: impl Into<StateLocation>) -> Self {
+        Self {
+            location: location.into(),
+            webhooks: HashMap::new(),
+            deliveries: HashMap::new(),
+        }
+    }
+
+    pub async fn load_from_file(&mut self) -> Result<(), Error> {
+        self.webhooks = self.location.read().await?.unwrap_or_default();
+        Ok(())
+    }
+
+    async fn write_to_file(&self) -> Result<(), Error> {
+        self.location.write(&self.webhooks).await
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        let mut webhooks: Vec<Webhook> = self.webhooks.values().cloned().collect();
+        webhooks.sort_by(|a, b| a.name.cmp(&b.name));
+        webhooks
+    }
+
+    pub fn get(&self, id: &str) -> Result<Webhook, Error> {
+        self.webhooks.get(id).cloned().ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Webhook not found"),
+        })
+    }
+
+    /// Adds the webhook, or replaces the one with the same id
+    pub async fn set_webhook(&mut self, webhook: Webhook) -> Result<Webhook, Error> {
+        let old = self.webhooks.insert(webhook.id.clone(), webhook.clone());
+        if let Err(e) = self.write_to_file().await {
+            match old {
+                Some(old) => self.webhooks.insert(webhook.id.clone(), old),
+                None => self.webhooks.remove(&webhook.id),
+            };
+            return Err(e);
+        }
+        Ok(webhook)
+    }
+
+    pub async fn remove_webhook(&mut self, id: &str) -> Result<Webhook, Error> {
+        let webhook = self.webhooks.remove(id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Webhook not found"),
+        })?;
+        if let Err(e) = self.write_to_file().await {
+            self.webhooks.insert(webhook.id.clone(), webhook);
+            return Err(e);
+        }
+        self.deliveries.remove(id);
+        Ok(webhook)
+    }
+
+    /// Newest first
+    pub fn deliveries(&self, id: &str) -> Result<Vec<WebhookDelivery>, Error> {
+        self.get(id)?;
+        Ok(self
+            .deliveries
+            .get(id)
+            .map(|deliveries| deliveries.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn record_delivery(&mut self, webhook_id: &str, delivery: WebhookDelivery) {
+        if !self.webhooks.contains_key(webhook_id) {
+            return;
+        }
+        let deliveries = self.deliveries.entry(webhook_id.to_string()).or_default();
+        match deliveries.iter_mut().find(|d| d.id == delivery.id) {
+            Some(old) => *old = delivery,
+            None => {
+                deliveries.push_front(delivery);
+                deliveries.truncate(DELIVERY_HISTORY);
+            }
+        }
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of the body, sent as `sha256=<signature>`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// 10s, 20s, 40s and so on after the failed `attempt`
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(10 * 2u64.pow(attempt - 1))
+}
+
+/// Other client errors won't go away by sending the same event again
+fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+async fn deliver(
+    webhooks: Arc<Mutex<WebhooksManager>>,
+    client: reqwest::Client,
+    webhook: Webhook,
+    mut delivery: WebhookDelivery,
+    body: Arc<Vec<u8>>,
+) {
+    let signature = format!("sha256={}", sign(&webhook.secret, &body));
+    let event_type = format!("{:?}", delivery.event_type);
+    for attempt in 1..=MAX_ATTEMPTS {
+        delivery.attempts = attempt;
+        let retry = match client
+            .post(&webhook.url)
+            .timeout(Duration::from_secs(10))
+            .header("Content-Type", "application/json")
+            .header("X-Lodestone-Event", &event_type)
+            .header("X-Lodestone-Delivery", &delivery.id)
+            .header("X-Lodestone-Signature-256", &signature)
+            .body(body.as_ref().clone())
+            .send()
+            .await
+        {
+            Ok(response) => {
+                let status = response.status();
+                delivery.status_code = Some(status.as_u16());
+                delivery.delivered = status.is_success();
+                delivery.error = if delivery.delivered {
+                    None
+                } else {
+                    Some(format!("Receiver responded with {}", status))
+                };
+                is_retryable(status)
+            }
+            Err(e) => {
+                delivery.status_code = None;
+                delivery.error = Some(e.to_string());
+                true
+            }
+        };
+        webhooks
+            .lock()
+            .await
+            .record_delivery(&webhook.id, delivery.clone());
+        if delivery.delivered || !retry || attempt == MAX_ATTEMPTS {
+            break;
+        }
+        tokio::time::sleep(retry_delay(attempt)).await;
+    }
+    if !delivery.delivered {
+        warn!(
+            "Gave up delivering event to webhook {} after {} attempts: {}",
+            webhook.name,
+            delivery.attempts,
+            delivery.error.unwrap_or_default()
+        );
+    }
+}
+
+/// Posts every event to the enabled webhooks it matches
+pub async fn webhooks_task(
+    webhooks: Arc<Mutex<WebhooksManager>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let client = reqwest::Client::new();
+    let mut event_receiver = event_broadcaster.subscribe();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Webhooks task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let matching: Vec<Webhook> = webhooks
+            .lock()
+            .await
+            .webhooks
+            .values()
+            .filter(|webhook| webhook.enabled && webhook.matches(&event))
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            continue;
+        }
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => Arc::new(body),
+            Err(e) => {
+                warn!("Failed to serialize event for webhooks: {}", e);
+                continue;
+            }
+        };
+        for webhook in matching {
+            let delivery = WebhookDelivery {
+                id: rand_alphanumeric(16),
+                event_snowflake: event.snowflake,
+                event_type: event.event_inner.as_ref().into(),
+                attempts: 0,
+                status_code: None,
+                error: None,
+                delivered: false,
+                time: chrono::Utc::now().timestamp(),
+            };
+            tokio::spawn(deliver(
+                webhooks.clone(),
+                client.clone(),
+                webhook,
+                delivery,
+                body.clone(),
+            ));
+        }
+    }
+}
+
+#[test]
+fn test_webhook_signing_and_retries() {
+    assert_eq!(
+        sign("key", b"The quick brown fox jumps over the lazy dog"),
+        "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+    );
+    assert_eq!(retry_delay(1), Duration::from_secs(10));
+    assert_eq!(retry_delay(3), Duration::from_secs(40));
+    assert!(is_retryable(StatusCode::BAD_GATEWAY));
+    assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+    assert!(!is_retryable(StatusCode::NOT_FOUND));
+
+    let new = NewWebhook {
+        name: "CI".to_string(),
+        url: "https://example.com/hook".to_string(),
+        enabled: true,
+        event_types: vec![EventType::InstanceEvent],
+        instance_event_types: Vec::new(),
+        instances: Vec::new(),
+        secret: String::new(),
+    };
+    new.validate().unwrap();
+    let webhook = Webhook::from_new(new.clone(), None, "owner".to_string());
+    assert_eq!(webhook.secret.len(), 32);
+    let updated = Webhook::from_new(new, Some(&webhook), "someone else".to_string());
+    assert_eq!(updated.id, webhook.id);
+    assert_eq!(updated.secret, webhook.secret);
+    assert_eq!(updated.created_by, "owner");
+}
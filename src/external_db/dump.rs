@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, Context};
+use tokio::process::Command;
+use tracing::{error, info};
+
+use crate::db::state::StateStore;
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+use crate::util::dont_spawn_terminal;
+
+use super::{path_to_dumps, DatabaseConnection, DatabaseKind, InstanceDatabases};
+
+/// The database is passed so that it can't be taken for an option, even if a
+/// connection got past `DatabaseConnection::validate`
+fn dump_command(connection: &DatabaseConnection) -> Command {
+    let port = connection.port().to_string();
+    match connection.kind {
+        DatabaseKind::MySql => {
+            let mut cmd = Command::new("mysqldump");
+            cmd.args([
+                "--host",
+                connection.host.as_str(),
+                "--port",
+                port.as_str(),
+                "--user",
+                connection.username.as_str(),
+                "--single-transaction",
+                "--routines",
+                "--triggers",
+                "--",
+                connection.database.as_str(),
+            ])
+            .env("MYSQL_PWD", &connection.password);
+            cmd
+        }
+        DatabaseKind::Postgres => {
+            let mut cmd = Command::new("pg_dump");
+            cmd.args([
+                "--host",
+                connection.host.as_str(),
+                "--port",
+                port.as_str(),
+                "--username",
+                connection.username.as_str(),
+                "--no-password",
+                "--clean",
+                "--if-exists",
+                format!("--dbname={}", connection.database).as_str(),
+            ])
+            .env("PGPASSWORD", &connection.password);
+            cmd
+        }
+    }
+}
+
+/// Dumps a database as plain SQL into `dest_dir`, returns the path of the dump
+pub async fn dump_database(
+    connection: &DatabaseConnection,
+    dest_dir: &Path,
+) -> Result<PathBuf, Error> {
+    connection.validate()?;
+    crate::util::fs::create_dir_all(dest_dir).await?;
+    let dest = dest_dir.join(format!(
+        "{}-{}.sql",
+        connection.name,
+        chrono::Utc::now().timestamp()
+    ));
+    let file = std::fs::File::create(&dest)
+        .context(format!("Failed to create dump file at {}", dest.display()))?;
+    let mut cmd = dump_command(connection);
+    let output = dont_spawn_terminal(&mut cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(file))
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&dest);
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error {
+                    kind: ErrorKind::Internal,
+                    source: eyre!(
+                        "{} is not installed on the host",
+                        match connection.kind {
+                            DatabaseKind::MySql => "mysqldump",
+                            DatabaseKind::Postgres => "pg_dump",
+                        }
+                    ),
+                }
+            } else {
+                eyre!(e).wrap_err("Failed to run dump command").into()
+            }
+        })?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&dest);
+        return Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "Dumping database {} failed: {}",
+                connection.name,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(dest)
+}
+
+/// Removes the oldest dumps of a connection so at most `keep` remain
+async fn prune_dumps(dest_dir: &Path, connection_name: &str, keep: usize) -> Result<(), Error> {
+    let prefix = format!("{}-", connection_name);
+    let mut dumps: Vec<(i64, PathBuf)> = std::fs::read_dir(dest_dir)
+        .context(format!("Failed to read {}", dest_dir.display()))?
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let file_name = e.file_name().to_string_lossy().to_string();
+            let timestamp = file_name
+                .strip_prefix(&prefix)?
+                .strip_suffix(".sql")?
+                .parse()
+                .ok()?;
+            Some((timestamp, e.path()))
+        })
+        .collect();
+    dumps.sort_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+    for (_, path) in dumps.into_iter().skip(keep) {
+        crate::util::fs::remove_file(path).await?;
+    }
+    Ok(())
+}
+
+/// Dumps every database configured for the instance, keeping going if one of them fails
+pub async fn dump_instance_databases(
+    store: &StateStore,
+    uuid: &InstanceUuid,
+    path_to_instance: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut config = InstanceDatabases::load(store, uuid, path_to_instance).await?;
+    let dest_dir = path_to_dumps(path_to_instance);
+    let mut dumps = Vec::new();
+    let mut errors = Vec::new();
+    for connection in config.connections.iter() {
+        match dump_database(connection, &dest_dir).await {
+            Ok(dump) => {
+                info!("Dumped database {} to {}", connection.name, dump.display());
+                prune_dumps(&dest_dir, &connection.name, config.keep_dumps as usize).await?;
+                dumps.push(dump);
+            }
+            Err(e) => {
+                error!("Failed to dump database {}: {}", connection.name, e);
+                errors.push(connection.name.clone());
+            }
+        }
+    }
+    config.last_dump = Some(chrono::Utc::now().timestamp());
+    config.save(store, uuid).await?;
+    if !errors.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!("Failed to dump databases: {}", errors.join(", ")),
+        });
+    }
+    Ok(dumps)
+}
+
+#[tokio::test]
+async fn test_prune_dumps() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    for name in [
+        "economy-100.sql",
+        "economy-300.sql",
+        "economy-200.sql",
+        "perms-50.sql",
+        "economy-notes.txt",
+    ] {
+        std::fs::write(temp_dir.path().join(name), "").unwrap();
+    }
+    prune_dumps(temp_dir.path(), "economy", 2).await.unwrap();
+    let mut remaining: Vec<String> = std::fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    remaining.sort();
+    assert_eq!(
+        remaining,
+        vec![
+            "economy-200.sql",
+            "economy-300.sql",
+            "economy-notes.txt",
+            "perms-50.sql"
+        ]
+    );
+}
+
+#[test]
+fn test_dump_command_keeps_database_out_of_options() {
+    let connection = DatabaseConnection {
+        name: "economy".to_string(),
+        kind: DatabaseKind::Postgres,
+        host: "localhost".to_string(),
+        port: None,
+        database: "economy".to_string(),
+        username: "mc_server".to_string(),
+        password: String::new(),
+        host_id: None,
+    };
+    let args = |connection: &DatabaseConnection| -> Vec<String> {
+        dump_command(connection)
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    };
+    assert_eq!(args(&connection).last().unwrap(), "--dbname=economy");
+    let mysql = DatabaseConnection {
+        kind: DatabaseKind::MySql,
+        ..connection
+    };
+    assert_eq!(args(&mysql)[args(&mysql).len() - 2..], ["--", "economy"]);
+}
@@ -0,0 +1,199 @@
+pub mod dump;
+pub mod provision;
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::db::state::{self, StateStore};
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+
+/// where the config was kept before it moved into the state store
+const LEGACY_FILE_NAME: &str = ".lodestone_databases.json";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum DatabaseKind {
+    /// also covers MariaDB
+    MySql,
+    Postgres,
+}
+
+impl DatabaseKind {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            DatabaseKind::MySql => 3306,
+            DatabaseKind::Postgres => 5432,
+        }
+    }
+}
+
+/// A database used by plugins or mods of an instance
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct DatabaseConnection {
+    /// unique within the instance, used to name the dump files
+    pub name: String,
+    pub kind: DatabaseKind,
+    pub host: String,
+    pub port: Option<u16>,
+    pub database: String,
+    pub username: String,
+    /// never sent to clients, an empty password in an update keeps the stored one
+    /// unless the server or user changed
+    #[serde(default)]
+    pub password: String,
+    /// set if the database was provisioned by the core on one of its database hosts
+    #[serde(default)]
+    pub host_id: Option<String>,
+}
+
+impl DatabaseConnection {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.kind.default_port())
+    }
+
+    /// Whether a password given for `other` is meant for this connection too
+    pub fn same_server(&self, other: &DatabaseConnection) -> bool {
+        self.kind == other.kind
+            && self.host == other.host
+            && self.port() == other.port()
+            && self.username == other.username
+    }
+
+    /// The values end up as arguments of the dump tools, where anything starting
+    /// with `-` would be taken for an option such as `--result-file`
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.host.is_empty()
+            || self.host.starts_with('-')
+            || self
+                .host
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control())
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid host {} of database {}", self.host, self.name),
+            });
+        }
+        for (field, value) in [("Database", &self.database), ("User", &self.username)] {
+            if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "{} name of {} must be non-empty and only contain letters, digits and underscores",
+                        field,
+                        self.name
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Kept in the state store rather than the instance directory, the passwords would
+/// otherwise be readable through the file API and end up in every backup
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct InstanceDatabases {
+    pub connections: Vec<DatabaseConnection>,
+    /// seconds between scheduled dumps, dumps only run alongside backups if `None`
+    pub dump_period: Option<u32>,
+    /// number of dumps kept per connection
+    pub keep_dumps: u32,
+    pub last_dump: Option<i64>,
+}
+
+impl Default for InstanceDatabases {
+    fn default() -> Self {
+        Self {
+            connections: Vec::new(),
+            dump_period: None,
+            keep_dumps: 5,
+            last_dump: None,
+        }
+    }
+}
+
+impl InstanceDatabases {
+    pub async fn load(
+        store: &StateStore,
+        uuid: &InstanceUuid,
+        path_to_instance: &Path,
+    ) -> Result<Self, Error> {
+        Ok(store
+            .get_instance(
+                state::INSTANCE_DATABASES,
+                uuid,
+                &path_to_instance.join(LEGACY_FILE_NAME),
+            )
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, store: &StateStore, uuid: &InstanceUuid) -> Result<(), Error> {
+        store
+            .set_instance(state::INSTANCE_DATABASES, uuid, self)
+            .await
+    }
+
+    pub fn is_dump_due(&self, now: i64) -> bool {
+        match self.dump_period {
+            Some(period) if !self.connections.is_empty() => {
+                self.last_dump.map_or(true, |t| now - t >= period as i64)
+            }
+            _ => false,
+        }
+    }
+
+    /// Copy of the config that is safe to hand out to clients
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        for connection in ret.connections.iter_mut() {
+            connection.password.clear();
+        }
+        ret
+    }
+}
+
+pub fn path_to_dumps(path_to_instance: &Path) -> PathBuf {
+    path_to_instance.join("database_dumps")
+}
+
+#[test]
+fn test_validate_connection() {
+    let connection = DatabaseConnection {
+        name: "economy".to_string(),
+        kind: DatabaseKind::MySql,
+        host: "db.example.com".to_string(),
+        port: None,
+        database: "economy".to_string(),
+        username: "mc_server".to_string(),
+        password: "hunter22".to_string(),
+        host_id: None,
+    };
+    assert!(connection.validate().is_ok());
+    for injected in [
+        DatabaseConnection {
+            database: "--result-file=/root/.ssh/authorized_keys".to_string(),
+            ..connection.clone()
+        },
+        DatabaseConnection {
+            username: "-fplugin".to_string(),
+            ..connection.clone()
+        },
+        DatabaseConnection {
+            host: "--defaults-extra-file=/tmp/my.cnf".to_string(),
+            ..connection.clone()
+        },
+    ] {
+        assert!(matches!(
+            injected.validate().unwrap_err().kind,
+            ErrorKind::BadRequest
+        ));
+    }
+}
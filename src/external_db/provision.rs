@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::db::state::{StateLocation, StateStore};
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+use crate::util::{dont_spawn_terminal, rand_alphanumeric};
+
+use super::{DatabaseConnection, DatabaseKind, InstanceDatabases};
+
+/// A database server the core can create databases on
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct DatabaseHost {
+    pub id: String,
+    pub name: String,
+    pub kind: DatabaseKind,
+    pub host: String,
+    pub port: Option<u16>,
+    /// address instances should use to reach the database, defaults to `host`
+    pub connect_host: Option<String>,
+    pub admin_username: String,
+    /// never sent to clients
+    #[serde(default)]
+    pub admin_password: String,
+}
+
+impl DatabaseHost {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.kind.default_port())
+    }
+
+    fn redacted(&self) -> Self {
+        Self {
+            admin_password: String::new(),
+            ..self.clone()
+        }
+    }
+
+    /// Runs each statement against the host's maintenance database using the CLI client.
+    /// The statements go over stdin, as arguments any local user could read the
+    /// passwords in them from the process list.
+    async fn execute(&self, statements: &[String]) -> Result<(), Error> {
+        let port = self.port().to_string();
+        let mut cmd = match self.kind {
+            DatabaseKind::MySql => {
+                let mut cmd = Command::new("mysql");
+                cmd.args([
+                    "--host",
+                    self.host.as_str(),
+                    "--port",
+                    port.as_str(),
+                    "--user",
+                    self.admin_username.as_str(),
+                ])
+                .env("MYSQL_PWD", &self.admin_password);
+                cmd
+            }
+            DatabaseKind::Postgres => {
+                let mut cmd = Command::new("psql");
+                cmd.args([
+                    "--host",
+                    self.host.as_str(),
+                    "--port",
+                    port.as_str(),
+                    "--username",
+                    self.admin_username.as_str(),
+                    "--dbname",
+                    "postgres",
+                    "--no-password",
+                    "--set",
+                    "ON_ERROR_STOP=1",
+                ])
+                .env("PGPASSWORD", &self.admin_password);
+                cmd
+            }
+        };
+        let mut child = dont_spawn_terminal(&mut cmd)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context(format!(
+                "Failed to run database client for host {}, is it installed?",
+                self.name
+            ))?;
+        // psql runs a script statement by statement, so CREATE DATABASE isn't caught
+        // in a transaction like it would be in a multi statement --command
+        let mut stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| eyre!("Failed to open stdin"))?;
+        stdin
+            .write_all(statements.join("\n").as_bytes())
+            .await
+            .context("Failed to send statements to database client")?;
+        drop(stdin);
+        let output = child
+            .wait_with_output()
+            .await
+            .context("Failed to wait for database client")?;
+        if !output.status.success() {
+            return Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!(
+                    "Database host {} returned an error: {}",
+                    self.name,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    /// Creates a database and a user owning it, returns the connection instances should use.
+    /// Identifiers are generated from `[A-Za-z0-9_]` only, so interpolating them is safe.
+    pub async fn create_database(
+        &self,
+        instance_uuid: &InstanceUuid,
+        name: &str,
+    ) -> Result<DatabaseConnection, Error> {
+        let identifier = provisioned_identifier(instance_uuid, name);
+        let password = rand_alphanumeric(24);
+        let statements = match self.kind {
+            DatabaseKind::MySql => vec![
+                format!("CREATE DATABASE `{identifier}`;"),
+                format!("CREATE USER '{identifier}'@'%' IDENTIFIED BY '{password}';"),
+                format!("GRANT ALL PRIVILEGES ON `{identifier}`.* TO '{identifier}'@'%';"),
+                "FLUSH PRIVILEGES;".to_string(),
+            ],
+            DatabaseKind::Postgres => vec![
+                format!("CREATE ROLE \"{identifier}\" LOGIN PASSWORD '{password}';"),
+                format!("CREATE DATABASE \"{identifier}\" OWNER \"{identifier}\";"),
+            ],
+        };
+        self.execute(&statements).await?;
+        info!("Created database {} on host {}", identifier, self.name);
+        Ok(DatabaseConnection {
+            name: name.to_string(),
+            kind: self.kind,
+            host: self
+                .connect_host
+                .clone()
+                .unwrap_or_else(|| self.host.clone()),
+            port: self.port,
+            database: identifier.clone(),
+            username: identifier,
+            password,
+            host_id: Some(self.id.clone()),
+        })
+    }
+
+    /// Only drops what `create_database` made for the instance, the connection comes
+    /// from the instance's config and could otherwise name any database on the host
+    pub async fn drop_database(
+        &self,
+        instance_uuid: &InstanceUuid,
+        connection: &DatabaseConnection,
+    ) -> Result<(), Error> {
+        let identifier = check_provisioned(instance_uuid, connection)?;
+        let statements = match self.kind {
+            DatabaseKind::MySql => vec![
+                format!("DROP DATABASE IF EXISTS `{identifier}`;"),
+                format!("DROP USER IF EXISTS '{identifier}'@'%';"),
+            ],
+            DatabaseKind::Postgres => vec![
+                format!("DROP DATABASE IF EXISTS \"{identifier}\";"),
+                format!("DROP ROLE IF EXISTS \"{identifier}\";"),
+            ],
+        };
+        self.execute(&statements).await?;
+        info!("Dropped database {} on host {}", identifier, self.name);
+        Ok(())
+    }
+}
+
+fn is_safe_identifier(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `ls_<first 8 chars of the instance uuid>_<name>`, within MySQL's 32 character user name limit
+/// for names up to 20 characters
+fn provisioned_identifier(instance_uuid: &InstanceUuid, name: &str) -> String {
+    let uuid_part: String = instance_uuid
+        .no_prefix()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .take(8)
+        .collect();
+    format!("ls_{}_{}", uuid_part, name).to_lowercase()
+}
+
+/// The identifier of a database provisioned for the instance under the
+/// connection's name, if the connection is for that database and its user
+fn check_provisioned(
+    instance_uuid: &InstanceUuid,
+    connection: &DatabaseConnection,
+) -> Result<String, Error> {
+    let identifier = provisioned_identifier(instance_uuid, &connection.name);
+    if !is_safe_identifier(&identifier)
+        || connection.database != identifier
+        || connection.username != identifier
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Refusing to drop database {}, it wasn't provisioned for this instance as {}",
+                connection.database,
+                connection.name
+            ),
+        });
+    }
+    Ok(identifier)
+}
+
+pub struct DatabaseHostsManager {
+    location: StateLocation,
+    hosts: HashMap<String, DatabaseHost>,
+}
+
+impl DatabaseHostsManager {
+    pub fn new(location: impl Into<StateLocation>) -> Self {
+        Self {
+            location: location.into(),
+            hosts: HashMap::new(),
+        }
+    }
+
+    pub async fn load_from_file(&mut self) -> Result<(), Error> {
+        self.hosts = self.location.read().await?.unwrap_or_default();
+        Ok(())
+    }
+
+    async fn write_to_file(&self) -> Result<(), Error> {
+        self.location.write(&self.hosts).await
+    }
+
+    pub fn get(&self, id: &str) -> Option<&DatabaseHost> {
+        self.hosts.get(id)
+    }
+
+    /// Hosts with their admin passwords removed
+    pub fn list(&self) -> Vec<DatabaseHost> {
+        self.hosts.values().map(|h| h.redacted()).collect()
+    }
+
+    pub async fn add_host(&mut self, mut host: DatabaseHost) -> Result<DatabaseHost, Error> {
+        host.id = format!("DBHOST_{}", uuid::Uuid::new_v4());
+        self.hosts.insert(host.id.clone(), host.clone());
+        if let Err(e) = self.write_to_file().await {
+            self.hosts.remove(&host.id);
+            return Err(e);
+        }
+        Ok(host.redacted())
+    }
+
+    pub async fn remove_host(&mut self, id: &str) -> Result<(), Error> {
+        let host = self.hosts.remove(id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Database host not found"),
+        })?;
+        if let Err(e) = self.write_to_file().await {
+            self.hosts.insert(id.to_string(), host);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Drops every database provisioned for the instance, used when the instance is deleted
+pub async fn drop_instance_databases(
+    hosts: &DatabaseHostsManager,
+    store: &StateStore,
+    uuid: &InstanceUuid,
+    path_to_instance: &Path,
+) -> Result<(), Error> {
+    let config = InstanceDatabases::load(store, uuid, path_to_instance).await?;
+    for connection in config.connections.iter() {
+        let host = match connection.host_id.as_ref() {
+            Some(host_id) => match hosts.get(host_id) {
+                Some(host) => host,
+                None => {
+                    error!(
+                        "Database host of {} no longer exists, database has to be dropped manually",
+                        connection.name
+                    );
+                    continue;
+                }
+            },
+            None => continue,
+        };
+        host.drop_database(uuid, connection).await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_provisioned_identifier() {
+    let uuid = InstanceUuid::from("INSTANCE_1a2b-3C4d-5e6f".to_string());
+    let identifier = provisioned_identifier(&uuid, "LuckPerms");
+    assert_eq!(identifier, "ls_1a2b3c4d_luckperms");
+    assert!(is_safe_identifier(&identifier));
+    assert!(!is_safe_identifier("a`; DROP"));
+}
+
+#[test]
+fn test_check_provisioned() {
+    let uuid = InstanceUuid::from("INSTANCE_1a2b-3C4d-5e6f".to_string());
+    let connection = DatabaseConnection {
+        name: "luckperms".to_string(),
+        kind: DatabaseKind::MySql,
+        host: "localhost".to_string(),
+        port: None,
+        database: "ls_1a2b3c4d_luckperms".to_string(),
+        username: "ls_1a2b3c4d_luckperms".to_string(),
+        password: String::new(),
+        host_id: Some("DBHOST_1".to_string()),
+    };
+    assert_eq!(
+        check_provisioned(&uuid, &connection).unwrap(),
+        "ls_1a2b3c4d_luckperms"
+    );
+    // another instance's database, or one the core never made
+    let other = InstanceUuid::from("INSTANCE_9f8e-7d6c-5b4a".to_string());
+    assert!(check_provisioned(&other, &connection).is_err());
+    for connection in [
+        DatabaseConnection {
+            database: "mysql".to_string(),
+            ..connection.clone()
+        },
+        DatabaseConnection {
+            username: "root".to_string(),
+            ..connection.clone()
+        },
+    ] {
+        assert!(check_provisioned(&uuid, &connection).is_err());
+    }
+}
@@ -0,0 +1,651 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+use crate::util::dont_spawn_terminal;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum FirewallBackend {
+    Ufw,
+    /// rules are added to an existing chain, since an accept in a separate table
+    /// doesn't override a drop in the host's own ruleset
+    Nftables,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct FirewallSettings {
+    /// the host firewall is left alone if `None`
+    pub backend: Option<FirewallBackend>,
+    /// addresses or CIDR ranges allowed to reach instance ports, any source if empty
+    pub allowed_sources: Vec<String>,
+    /// nftables table and chain rules are added to, e.g. `inet filter` and `input`
+    pub nft_table: String,
+    pub nft_chain: String,
+}
+
+impl Default for FirewallSettings {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            allowed_sources: Vec::new(),
+            nft_table: "inet filter".to_string(),
+            nft_chain: "input".to_string(),
+        }
+    }
+}
+
+impl FirewallSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        for source in self.allowed_sources.iter() {
+            if parse_source(source).is_none() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{} is not an IP address or CIDR range", source),
+                });
+            }
+        }
+        let is_word = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        if !self.nft_table.split(' ').all(is_word) || !is_word(&self.nft_chain) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid nftables table or chain name"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Parses `1.2.3.4` or `1.2.3.0/24` style sources, returns the address and prefix length
+pub(crate) fn parse_source(source: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match source.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (source, None),
+    };
+    let address: IpAddr = address.parse().ok()?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.parse().ok().filter(|p| *p <= max_prefix)?,
+        None => max_prefix,
+    };
+    Some((address, prefix))
+}
+
+/// Whether `ip` is `source` or falls within it, `source` being an address or CIDR range
+pub(crate) fn source_contains(source: &str, ip: IpAddr) -> bool {
+    let (address, prefix) = match parse_source(source) {
+        Some(parsed) => parsed,
+        None => return false,
+    };
+    match (address, ip) {
+        (IpAddr::V4(address), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(address) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(address) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Per-instance source restrictions, applied through the firewall when the instance
+/// starts. Players joining from a rejected address are kicked as well, which is the
+/// only enforcement without a firewall backend and for blocked countries.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct IpAccessList {
+    /// replaces the global allowed sources for this instance if non-empty
+    pub allow: Vec<String>,
+    /// always rejected, even if they fall within an allowed range
+    pub deny: Vec<String>,
+    /// ISO 3166-1 alpha-2 codes, e.g. `DE`, requires a GeoIP country database
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+}
+
+/// An access list as returned by the API
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct IpAccessStatus {
+    pub access: IpAccessList,
+    /// whether the firewall rejects the allow and deny lists before connections reach
+    /// the server. If not, rejected players are only kicked once they join.
+    pub enforced: bool,
+}
+
+impl IpAccessList {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_ip_access.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse IP access list at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_ip_access.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize IP access list")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        for source in self.allow.iter().chain(self.deny.iter()) {
+            if parse_source(source).is_none() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{} is not an IP address or CIDR range", source),
+                });
+            }
+        }
+        for country in self.blocked_countries.iter() {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("{} is not a two letter country code such as DE", country),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Why a player connecting from `ip` isn't let in, `None` if they are
+    pub fn rejection(&self, ip: IpAddr, country_code: Option<&str>) -> Option<String> {
+        if self.deny.iter().any(|source| source_contains(source, ip)) {
+            return Some(format!("{} is on the deny list", ip));
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|source| source_contains(source, ip)) {
+            return Some(format!("{} is not on the allow list", ip));
+        }
+        match country_code {
+            Some(country) if self.blocked_countries.iter().any(|c| c == country) => {
+                Some(format!("connections from {} are blocked", country))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The rules opened for an instance, kept so they can be removed even if the settings change
+#[derive(Debug, Clone)]
+struct OpenedPort {
+    backend: FirewallBackend,
+    nft_table: String,
+    nft_chain: String,
+    port: u32,
+    allowed_sources: Vec<String>,
+    denied_sources: Vec<String>,
+}
+
+fn rule_comment(uuid: &InstanceUuid) -> String {
+    format!("lodestone:{}", uuid.no_prefix())
+}
+
+fn rate_limit_comment(uuid: &InstanceUuid) -> String {
+    format!("lodestone-limit:{}", uuid.no_prefix())
+}
+
+/// A temporary limit on new connections, added on top of the regular rules of a port
+#[derive(Debug, Clone)]
+struct RateLimit {
+    backend: FirewallBackend,
+    nft_table: String,
+    nft_chain: String,
+    port: u32,
+}
+
+async fn run(program: &str, args: &[String]) -> Result<String, Error> {
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    let output = dont_spawn_terminal(&mut cmd)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context(format!("Failed to run {}, is it installed?", program))?;
+    if !output.status.success() {
+        return Err(Error {
+            kind: ErrorKind::Internal,
+            source: eyre!(
+                "{} {} failed: {}",
+                program,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// `ufw` arguments to add or delete each rule, deny rules are inserted first so they take precedence
+fn ufw_rules(uuid: &InstanceUuid, opened: &OpenedPort, open: bool) -> Vec<Vec<String>> {
+    let allowed = if opened.allowed_sources.is_empty() {
+        vec!["any".to_string()]
+    } else {
+        opened.allowed_sources.clone()
+    };
+    let rules = opened
+        .denied_sources
+        .iter()
+        .map(|source| ("deny", source.clone()))
+        .chain(allowed.into_iter().map(|source| ("allow", source)));
+    rules
+        .map(|(action, source)| {
+            let mut args = match (open, action) {
+                // ufw matches rules to delete without their comment
+                (false, _) => vec!["delete".to_string()],
+                (true, "deny") => vec!["prepend".to_string()],
+                (true, _) => Vec::new(),
+            };
+            args.extend([
+                action.to_string(),
+                "from".to_string(),
+                source,
+                "to".to_string(),
+                "any".to_string(),
+                "port".to_string(),
+                opened.port.to_string(),
+            ]);
+            if open {
+                args.extend(["comment".to_string(), rule_comment(uuid)]);
+            }
+            args
+        })
+        .collect()
+}
+
+/// `nft` statements, one per source and protocol. Drops are inserted at the top of the chain
+fn nft_rules(uuid: &InstanceUuid, opened: &OpenedPort) -> Vec<String> {
+    let allowed: Vec<Option<(IpAddr, u8)>> = if opened.allowed_sources.is_empty() {
+        vec![None]
+    } else {
+        opened
+            .allowed_sources
+            .iter()
+            .filter_map(|s| parse_source(s).map(Some))
+            .collect()
+    };
+    let denied = opened
+        .denied_sources
+        .iter()
+        .filter_map(|s| parse_source(s).map(Some));
+    let rules = denied
+        .map(|source| ("insert", source, "drop"))
+        .chain(allowed.into_iter().map(|source| ("add", source, "accept")));
+    let mut ret = Vec::new();
+    for (command, source, verdict) in rules {
+        let source_match = match source {
+            Some((IpAddr::V4(address), prefix)) => format!("ip saddr {}/{} ", address, prefix),
+            Some((IpAddr::V6(address), prefix)) => format!("ip6 saddr {}/{} ", address, prefix),
+            None => String::new(),
+        };
+        for protocol in ["tcp", "udp"] {
+            ret.push(format!(
+                "{} rule {} {} {}{} dport {} {} comment \"{}\"",
+                command,
+                opened.nft_table,
+                opened.nft_chain,
+                source_match,
+                protocol,
+                opened.port,
+                verdict,
+                rule_comment(uuid)
+            ));
+        }
+    }
+    ret
+}
+
+/// Handles of the rules in `nft -a list chain` output that carry the comment
+fn nft_rule_handles(listing: &str, comment: &str) -> Vec<u64> {
+    let needle = format!("comment \"{}\"", comment);
+    listing
+        .lines()
+        .filter(|line| line.contains(&needle))
+        .filter_map(|line| line.rsplit_once("# handle ")?.1.trim().parse().ok())
+        .collect()
+}
+
+/// Removes every rule of the chain that carries the comment
+async fn nft_delete_by_comment(table: &str, chain: &str, comment: &str) -> Result<(), Error> {
+    let mut list_args = vec!["-a".to_string(), "list".to_string(), "chain".to_string()];
+    list_args.extend(table.split(' ').map(|s| s.to_string()));
+    list_args.push(chain.to_string());
+    let listing = run("nft", &list_args).await?;
+    for handle in nft_rule_handles(&listing, comment) {
+        run(
+            "nft",
+            &[format!("delete rule {} {} handle {}", table, chain, handle)],
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn apply(uuid: &InstanceUuid, opened: &OpenedPort, open: bool) -> Result<(), Error> {
+    match opened.backend {
+        FirewallBackend::Ufw => {
+            for rule in ufw_rules(uuid, opened, open) {
+                run("ufw", &rule).await?;
+            }
+        }
+        FirewallBackend::Nftables => {
+            if open {
+                for rule in nft_rules(uuid, opened) {
+                    run("nft", &[rule]).await?;
+                }
+            } else {
+                nft_delete_by_comment(&opened.nft_table, &opened.nft_chain, &rule_comment(uuid))
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct FirewallManager {
+    opened: HashMap<InstanceUuid, OpenedPort>,
+    rate_limited: HashMap<InstanceUuid, RateLimit>,
+}
+
+impl FirewallManager {
+    /// Opens the port of an instance, returns a description of what was done for the audit log.
+    /// Rules left over from a previous open are removed first.
+    pub async fn open_instance_port(
+        &mut self,
+        uuid: &InstanceUuid,
+        port: u32,
+        settings: &FirewallSettings,
+        access: &IpAccessList,
+    ) -> Result<Option<String>, Error> {
+        let backend = match settings.backend {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        self.close_instance_port(uuid).await?;
+        let opened = OpenedPort {
+            backend,
+            nft_table: settings.nft_table.clone(),
+            nft_chain: settings.nft_chain.clone(),
+            port,
+            allowed_sources: if access.allow.is_empty() {
+                settings.allowed_sources.clone()
+            } else {
+                access.allow.clone()
+            },
+            denied_sources: access.deny.clone(),
+        };
+        if let Err(e) = apply(uuid, &opened, true).await {
+            // don't leave half of the rules behind
+            let _ = apply(uuid, &opened, false).await;
+            return Err(e);
+        }
+        let mut message = format!(
+            "Opened port {} in {:?} for {}",
+            port,
+            backend,
+            if opened.allowed_sources.is_empty() {
+                "any source".to_string()
+            } else {
+                opened.allowed_sources.join(", ")
+            }
+        );
+        if !opened.denied_sources.is_empty() {
+            message.push_str(&format!(", denying {}", opened.denied_sources.join(", ")));
+        }
+        self.opened.insert(uuid.clone(), opened);
+        Ok(Some(message))
+    }
+
+    pub async fn close_instance_port(
+        &mut self,
+        uuid: &InstanceUuid,
+    ) -> Result<Option<String>, Error> {
+        let opened = match self.opened.remove(uuid) {
+            Some(opened) => opened,
+            None => return Ok(None),
+        };
+        if let Err(e) = apply(uuid, &opened, false).await {
+            self.opened.insert(uuid.clone(), opened);
+            return Err(e);
+        }
+        Ok(Some(format!(
+            "Closed port {} in {:?}",
+            opened.port, opened.backend
+        )))
+    }
+
+    /// Limits new connections to the port, ufw only supports its fixed per source limit
+    pub async fn add_rate_limit(
+        &mut self,
+        uuid: &InstanceUuid,
+        port: u32,
+        settings: &FirewallSettings,
+        per_second: u32,
+    ) -> Result<Option<String>, Error> {
+        let backend = match settings.backend {
+            Some(backend) => backend,
+            None => return Ok(None),
+        };
+        if self.rate_limited.contains_key(uuid) {
+            return Ok(None);
+        }
+        match backend {
+            FirewallBackend::Ufw => {
+                run(
+                    "ufw",
+                    &[
+                        "prepend".to_string(),
+                        "limit".to_string(),
+                        "proto".to_string(),
+                        "tcp".to_string(),
+                        "from".to_string(),
+                        "any".to_string(),
+                        "to".to_string(),
+                        "any".to_string(),
+                        "port".to_string(),
+                        port.to_string(),
+                        "comment".to_string(),
+                        rate_limit_comment(uuid),
+                    ],
+                )
+                .await?;
+            }
+            FirewallBackend::Nftables => {
+                run(
+                    "nft",
+                    &[format!(
+                        "insert rule {} {} tcp dport {} ct state new limit rate over {}/second drop comment \"{}\"",
+                        settings.nft_table,
+                        settings.nft_chain,
+                        port,
+                        per_second,
+                        rate_limit_comment(uuid)
+                    )],
+                )
+                .await?;
+            }
+        }
+        self.rate_limited.insert(
+            uuid.clone(),
+            RateLimit {
+                backend,
+                nft_table: settings.nft_table.clone(),
+                nft_chain: settings.nft_chain.clone(),
+                port,
+            },
+        );
+        Ok(Some(format!(
+            "Rate limited new connections to port {} in {:?}",
+            port, backend
+        )))
+    }
+
+    pub async fn remove_rate_limit(
+        &mut self,
+        uuid: &InstanceUuid,
+    ) -> Result<Option<String>, Error> {
+        let limit = match self.rate_limited.get(uuid) {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+        match limit.backend {
+            FirewallBackend::Ufw => {
+                run(
+                    "ufw",
+                    &[
+                        "delete".to_string(),
+                        "limit".to_string(),
+                        "proto".to_string(),
+                        "tcp".to_string(),
+                        "from".to_string(),
+                        "any".to_string(),
+                        "to".to_string(),
+                        "any".to_string(),
+                        "port".to_string(),
+                        limit.port.to_string(),
+                    ],
+                )
+                .await?;
+            }
+            FirewallBackend::Nftables => {
+                nft_delete_by_comment(
+                    &limit.nft_table,
+                    &limit.nft_chain,
+                    &rate_limit_comment(uuid),
+                )
+                .await?;
+            }
+        }
+        let message = format!(
+            "Removed the rate limit on port {} in {:?}",
+            limit.port, limit.backend
+        );
+        self.rate_limited.remove(uuid);
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_source() {
+        assert!(parse_source("10.0.0.0/8").is_some());
+        assert!(parse_source("2001:db8::/32").is_some());
+        assert_eq!(parse_source("1.2.3.4").map(|(_, p)| p), Some(32));
+        assert!(parse_source("1.2.3.4/33").is_none());
+        assert!(source_contains(
+            "10.0.0.0/8",
+            "10.20.30.40".parse().unwrap()
+        ));
+        assert!(!source_contains("10.0.0.0/8", "11.0.0.1".parse().unwrap()));
+        assert!(source_contains("0.0.0.0/0", "8.8.8.8".parse().unwrap()));
+        assert!(source_contains(
+            "2001:db8::/32",
+            "2001:db8::1".parse().unwrap()
+        ));
+        assert!(!source_contains(
+            "2001:db8::/32",
+            "10.0.0.1".parse().unwrap()
+        ));
+        assert!(parse_source("1.2.3.4; drop").is_none());
+        assert!(parse_source("any").is_none());
+    }
+
+    #[test]
+    fn test_ip_access_rejection() {
+        let access = IpAccessList {
+            allow: vec!["10.0.0.0/8".to_string()],
+            deny: vec!["10.0.0.66".to_string()],
+            blocked_countries: vec!["KP".to_string()],
+        };
+        assert!(access.validate().is_ok());
+        assert!(access
+            .rejection("10.1.2.3".parse().unwrap(), Some("DE"))
+            .is_none());
+        assert!(access
+            .rejection("10.0.0.66".parse().unwrap(), None)
+            .is_some());
+        assert!(access.rejection("8.8.8.8".parse().unwrap(), None).is_some());
+        assert!(access
+            .rejection("10.1.2.3".parse().unwrap(), Some("KP"))
+            .is_some());
+        let access = IpAccessList {
+            blocked_countries: vec!["kp".to_string()],
+            ..Default::default()
+        };
+        assert!(access.validate().is_err());
+    }
+
+    fn opened_port() -> OpenedPort {
+        OpenedPort {
+            backend: FirewallBackend::Nftables,
+            nft_table: "inet filter".to_string(),
+            nft_chain: "input".to_string(),
+            port: 25565,
+            allowed_sources: vec!["10.0.0.0/8".to_string(), "::1".to_string()],
+            denied_sources: vec!["10.0.0.66".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_nft_rules() {
+        let uuid = InstanceUuid::from("INSTANCE_abcd".to_string());
+        let rules = nft_rules(&uuid, &opened_port());
+        assert_eq!(rules.len(), 6);
+        assert_eq!(
+            rules[0],
+            "insert rule inet filter input ip saddr 10.0.0.66/32 tcp dport 25565 drop comment \"lodestone:abcd\""
+        );
+        assert_eq!(
+            rules[2],
+            "add rule inet filter input ip saddr 10.0.0.0/8 tcp dport 25565 accept comment \"lodestone:abcd\""
+        );
+        assert!(rules[5].contains("ip6 saddr ::1/128 udp dport 25565"));
+
+        let listing = "table inet filter {\n\tchain input { # handle 1\n\t\ttcp dport 25565 accept comment \"lodestone:abcd\" # handle 7\n\t\ttcp dport 22 accept # handle 8\n\t}\n}";
+        assert_eq!(nft_rule_handles(listing, "lodestone:abcd"), vec![7]);
+    }
+
+    #[test]
+    fn test_ufw_rules() {
+        let uuid = InstanceUuid::from("INSTANCE_abcd".to_string());
+        let opened = opened_port();
+        let open = ufw_rules(&uuid, &opened, true);
+        assert_eq!(
+            open[0].join(" "),
+            "prepend deny from 10.0.0.66 to any port 25565 comment lodestone:abcd"
+        );
+        assert_eq!(
+            open[1].join(" "),
+            "allow from 10.0.0.0/8 to any port 25565 comment lodestone:abcd"
+        );
+        let close = ufw_rules(&uuid, &opened, false);
+        assert_eq!(
+            close[0].join(" "),
+            "delete deny from 10.0.0.66 to any port 25565"
+        );
+    }
+}
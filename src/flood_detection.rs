@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::error;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::Event;
+use crate::firewall::FirewallManager;
+use crate::global_settings::GlobalSettings;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+const SAMPLE_INTERVAL: u64 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct FloodDetectionSettings {
+    pub enabled: bool,
+    /// new connections per second to a single instance port
+    pub warning_rate: u32,
+    pub critical_rate: u32,
+    /// add a temporary firewall rate limit when the critical rate is reached,
+    /// requires a firewall backend
+    pub rate_limit_on_critical: bool,
+    /// new connections per second let through while rate limited, ufw uses its fixed limit instead
+    pub rate_limit: u32,
+    /// seconds the rate limit stays in place
+    pub rate_limit_duration: u32,
+}
+
+impl Default for FloodDetectionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            warning_rate: 20,
+            critical_rate: 100,
+            rate_limit_on_critical: false,
+            rate_limit: 10,
+            rate_limit_duration: 600,
+        }
+    }
+}
+
+impl FloodDetectionSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.warning_rate == 0 || self.warning_rate > self.critical_rate {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Warning rate must be positive and at most the critical rate"),
+            });
+        }
+        if self.rate_limit == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Rate limit must be positive"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloodLevel {
+    Normal,
+    Warning,
+    Critical,
+}
+
+/// Remote endpoints of established and half-open connections to `port`,
+/// parsed from the contents of `/proc/net/tcp` or `/proc/net/tcp6`
+fn parse_proc_net_tcp(contents: &str, port: u16) -> HashSet<String> {
+    let port = format!("{:04X}", port);
+    contents
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let local = fields.next()?;
+            let remote = fields.next()?;
+            let state = fields.next()?;
+            // 01 is ESTABLISHED, 03 is SYN_RECV
+            if local.rsplit_once(':')?.1 == port && (state == "01" || state == "03") {
+                Some(remote.to_string())
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Always empty on platforms without procfs
+fn read_connections(port: u16) -> HashSet<String> {
+    ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .flat_map(|contents| parse_proc_net_tcp(&contents, port))
+        .collect()
+}
+
+struct PortSample {
+    connections: HashSet<String>,
+    level: FloodLevel,
+}
+
+/// Counts connections that weren't there in the previous sample. Connections opened and
+/// closed between two samples are missed, half-open ones from SYN floods linger long enough.
+#[derive(Default)]
+struct FloodDetector {
+    samples: HashMap<InstanceUuid, PortSample>,
+}
+
+impl FloodDetector {
+    /// Records a sample, returns the new level and the observed rate if the level changed
+    fn observe(
+        &mut self,
+        uuid: &InstanceUuid,
+        connections: HashSet<String>,
+        elapsed_secs: f64,
+        settings: &FloodDetectionSettings,
+    ) -> Option<(FloodLevel, f64)> {
+        let sample = match self.samples.get_mut(uuid) {
+            Some(sample) => sample,
+            None => {
+                self.samples.insert(
+                    uuid.clone(),
+                    PortSample {
+                        connections,
+                        level: FloodLevel::Normal,
+                    },
+                );
+                return None;
+            }
+        };
+        let new_connections = connections.difference(&sample.connections).count();
+        let rate = new_connections as f64 / elapsed_secs;
+        let level = if rate >= settings.critical_rate as f64 {
+            FloodLevel::Critical
+        } else if rate >= settings.warning_rate as f64 {
+            FloodLevel::Warning
+        } else {
+            FloodLevel::Normal
+        };
+        sample.connections = connections;
+        if level == sample.level {
+            return None;
+        }
+        sample.level = level;
+        Some((level, rate))
+    }
+}
+
+pub async fn flood_detection_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    firewall: Arc<Mutex<FirewallManager>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut detector = FloodDetector::default();
+    let mut rate_limited_until: HashMap<InstanceUuid, i64> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(SAMPLE_INTERVAL));
+    loop {
+        interval.tick().await;
+        let (settings, firewall_settings) = {
+            let global_settings = global_settings.lock().await;
+            (
+                global_settings.flood_detection(),
+                global_settings.firewall(),
+            )
+        };
+        let mut running = Vec::new();
+        if settings.enabled {
+            for (uuid, instance) in instances.lock().await.iter() {
+                if instance.state().await == State::Running {
+                    running.push((uuid.clone(), instance.name().await, instance.port().await));
+                }
+            }
+        }
+        detector
+            .samples
+            .retain(|uuid, _| running.iter().any(|(u, _, _)| u == uuid));
+
+        for (uuid, name, port) in running {
+            let connections = tokio::task::spawn_blocking(move || read_connections(port as u16))
+                .await
+                .unwrap_or_default();
+            let (level, rate) =
+                match detector.observe(&uuid, connections, SAMPLE_INTERVAL as f64, &settings) {
+                    Some(change) => change,
+                    None => continue,
+                };
+            match level {
+                FloodLevel::Normal => event_broadcaster.send(Event::new_system_message(
+                    uuid.clone(),
+                    name.clone(),
+                    format!("Connection rate on port {} is back to normal", port),
+                )),
+                FloodLevel::Warning => event_broadcaster.send(Event::new_instance_warning(
+                    uuid.clone(),
+                    name.clone(),
+                    format!(
+                        "High connection rate on port {}: {:.1} new connections per second",
+                        port, rate
+                    ),
+                )),
+                FloodLevel::Critical => {
+                    event_broadcaster.send(Event::new_instance_error(
+                        uuid.clone(),
+                        name.clone(),
+                        format!(
+                            "Possible connection flood on port {}: {:.1} new connections per second",
+                            port, rate
+                        ),
+                    ));
+                    if settings.rate_limit_on_critical && !rate_limited_until.contains_key(&uuid) {
+                        match firewall
+                            .lock()
+                            .await
+                            .add_rate_limit(&uuid, port, &firewall_settings, settings.rate_limit)
+                            .await
+                        {
+                            Ok(Some(message)) => {
+                                rate_limited_until.insert(
+                                    uuid.clone(),
+                                    chrono::Utc::now().timestamp()
+                                        + settings.rate_limit_duration as i64,
+                                );
+                                event_broadcaster.send(Event::new_system_message(
+                                    uuid.clone(),
+                                    name.clone(),
+                                    message,
+                                ));
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                error!("Failed to rate limit port {}: {}", port, e);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let expired: Vec<InstanceUuid> = rate_limited_until
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(uuid, _)| uuid.clone())
+            .collect();
+        for uuid in expired {
+            match firewall.lock().await.remove_rate_limit(&uuid).await {
+                Ok(Some(message)) => {
+                    let name = match instances.lock().await.get(&uuid) {
+                        Some(instance) => instance.name().await,
+                        None => String::new(),
+                    };
+                    event_broadcaster.send(Event::new_system_message(uuid.clone(), name, message));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // try again on the next tick
+                    error!("Failed to remove rate limit of instance {}: {}", uuid, e);
+                    continue;
+                }
+            }
+            rate_limited_until.remove(&uuid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_net_tcp() {
+        let contents = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 00000000:63DD 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 1 1
+   1: 0100007F:63DD 0100007F:D2F0 01 00000000:00000000 00:00000000 00000000  1000        0 2 1
+   2: 0100007F:63DD 0200007F:D2F1 03 00000000:00000000 00:00000000 00000000  1000        0 3 1
+   3: 0100007F:63DD 0300007F:D2F2 06 00000000:00000000 00:00000000 00000000  1000        0 4 1
+   4: 0100007F:0016 0400007F:D2F3 01 00000000:00000000 00:00000000 00000000  1000        0 5 1";
+        let connections = parse_proc_net_tcp(contents, 25565);
+        assert_eq!(connections.len(), 2);
+        assert!(connections.contains("0100007F:D2F0"));
+        assert!(connections.contains("0200007F:D2F1"));
+    }
+
+    #[test]
+    fn test_flood_detector() {
+        let uuid = InstanceUuid::from("INSTANCE_abcd".to_string());
+        let settings = FloodDetectionSettings {
+            warning_rate: 2,
+            critical_rate: 10,
+            ..Default::default()
+        };
+        let sample = |range: std::ops::Range<u32>| range.map(|i| i.to_string()).collect();
+        let mut detector = FloodDetector::default();
+        assert_eq!(detector.observe(&uuid, sample(0..5), 1.0, &settings), None);
+        assert_eq!(
+            detector.observe(&uuid, sample(0..8), 1.0, &settings),
+            Some((FloodLevel::Warning, 3.0))
+        );
+        // still at warning level, nothing to report
+        assert_eq!(detector.observe(&uuid, sample(0..11), 1.0, &settings), None);
+        assert_eq!(
+            detector.observe(&uuid, sample(0..30), 1.0, &settings),
+            Some((FloodLevel::Critical, 19.0))
+        );
+        assert_eq!(
+            detector.observe(&uuid, sample(0..30), 1.0, &settings),
+            Some((FloodLevel::Normal, 0.0))
+        );
+    }
+}
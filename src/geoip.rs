@@ -0,0 +1,137 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use color_eyre::eyre::eyre;
+use maxminddb::geoip2;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Paths to local MaxMind databases, e.g. GeoLite2-Country (or -City) and GeoLite2-ASN.
+/// Lookups are skipped for any database that isn't set.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct GeoIpSettings {
+    pub country_database: Option<PathBuf>,
+    pub asn_database: Option<PathBuf>,
+}
+
+impl GeoIpSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        for path in [&self.country_database, &self.asn_database]
+            .into_iter()
+            .flatten()
+        {
+            maxminddb::Reader::open_readfile(path).map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Failed to open GeoIP database {}: {}", path.display(), e),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 code
+    pub country_code: Option<String>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub as_organization: Option<String>,
+}
+
+impl std::fmt::Display for GeoInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(country) = self.country.as_ref().or(self.country_code.as_ref()) {
+            parts.push(country.clone());
+        }
+        match (self.asn, &self.as_organization) {
+            (Some(asn), Some(org)) => parts.push(format!("AS{} {}", asn, org)),
+            (Some(asn), None) => parts.push(format!("AS{}", asn)),
+            (None, Some(org)) => parts.push(org.clone()),
+            (None, None) => {}
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Keeps the databases open between lookups, reopening them when the settings change
+#[derive(Default)]
+pub struct GeoIpResolver {
+    settings: GeoIpSettings,
+    country_reader: Option<maxminddb::Reader<Vec<u8>>>,
+    asn_reader: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+fn open_reader(path: &Option<PathBuf>) -> Option<maxminddb::Reader<Vec<u8>>> {
+    let path = path.as_ref()?;
+    maxminddb::Reader::open_readfile(path)
+        .map_err(|e| {
+            tracing::error!("Failed to open GeoIP database {}: {}", path.display(), e);
+        })
+        .ok()
+}
+
+impl GeoIpResolver {
+    pub fn update_settings(&mut self, settings: &GeoIpSettings) {
+        if self.settings == *settings {
+            return;
+        }
+        self.country_reader = open_reader(&settings.country_database);
+        self.asn_reader = open_reader(&settings.asn_database);
+        self.settings = settings.clone();
+    }
+
+    /// `None` if no database is configured or the address isn't in any of them
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        let mut info = GeoInfo::default();
+        if let Some(country) = self
+            .country_reader
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::Country>(ip).ok())
+            .and_then(|record| record.country)
+        {
+            info.country_code = country.iso_code.map(|code| code.to_string());
+            info.country = country
+                .names
+                .and_then(|names| names.get("en").map(|name| name.to_string()));
+        }
+        if let Some(asn) = self
+            .asn_reader
+            .as_ref()
+            .and_then(|reader| reader.lookup::<geoip2::Asn>(ip).ok())
+        {
+            info.asn = asn.autonomous_system_number;
+            info.as_organization = asn
+                .autonomous_system_organization
+                .map(|org| org.to_string());
+        }
+        if info == GeoInfo::default() {
+            None
+        } else {
+            Some(info)
+        }
+    }
+}
+
+#[test]
+fn test_geo_info_display() {
+    let info = GeoInfo {
+        country_code: Some("DE".to_string()),
+        country: Some("Germany".to_string()),
+        asn: Some(3320),
+        as_organization: Some("Deutsche Telekom AG".to_string()),
+    };
+    assert_eq!(info.to_string(), "Germany, AS3320 Deutsche Telekom AG");
+    let info = GeoInfo {
+        country_code: Some("DE".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(info.to_string(), "DE");
+    assert!(GeoIpResolver::default()
+        .lookup("1.1.1.1".parse().unwrap())
+        .is_none());
+}
@@ -1,11 +1,20 @@
-use std::path::PathBuf;
-
-use color_eyre::eyre::Context;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
 use ts_rs::TS;
 
-use crate::{error::Error, event_broadcaster::EventBroadcaster};
+use crate::backup::pre_change::PreChangeBackupSettings;
+use crate::implementations::minecraft::jvm_presets::{self, JvmPreset};
+use crate::{
+    backup::remote::BackupRemotesSettings, console_policy::ConsoleCommandPolicy,
+    db::state::StateLocation, email::EmailSettings, error::Error,
+    event_broadcaster::EventBroadcaster, events::discord_webhook::DiscordWebhookConfig,
+    firewall::FirewallSettings, flood_detection::FloodDetectionSettings, geoip::GeoIpSettings,
+    instance_migration::PeerCoresSettings, maintenance::MaintenanceSettings,
+    metrics_history::MetricsHistorySettings, player_sessions::AltDetectionSettings,
+    prometheus::PrometheusSettings, request_metrics::SlowRequestSettings,
+    start_queue::StartQueueSettings, tick_monitor::TickMonitorSettings, votifier::VotifierSettings,
+    vpn_detection::VpnDetectionSettings, ws_sessions::WebsocketSettings,
+};
 
 #[derive(Serialize, Deserialize, Clone, TS)]
 #[ts(export)]
@@ -13,6 +22,53 @@ pub struct GlobalSettingsData {
     pub core_name: String,
     pub safe_mode: bool,
     pub domain: Option<String>,
+    #[serde(default)]
+    pub firewall: FirewallSettings,
+    #[serde(default)]
+    pub flood_detection: FloodDetectionSettings,
+    #[serde(default)]
+    pub geoip: GeoIpSettings,
+    #[serde(default)]
+    pub vpn_detection: VpnDetectionSettings,
+    #[serde(default)]
+    pub alt_detection: AltDetectionSettings,
+    #[serde(default)]
+    pub console_policy: ConsoleCommandPolicy,
+    #[serde(default)]
+    pub votifier: VotifierSettings,
+    #[serde(default)]
+    pub backup_remotes: BackupRemotesSettings,
+    #[serde(default)]
+    pub peer_cores: PeerCoresSettings,
+    #[serde(default)]
+    pub maintenance: MaintenanceSettings,
+    #[serde(default)]
+    pub slow_requests: SlowRequestSettings,
+    #[serde(default)]
+    pub websocket: WebsocketSettings,
+    #[serde(default)]
+    pub start_queue: StartQueueSettings,
+    #[serde(default)]
+    pub prometheus: PrometheusSettings,
+    #[serde(default)]
+    pub metrics_history: MetricsHistorySettings,
+    #[serde(default)]
+    pub tick_monitor: TickMonitorSettings,
+    #[serde(default)]
+    pub email: EmailSettings,
+    #[serde(default)]
+    pub discord_webhook: DiscordWebhookConfig,
+    #[serde(default)]
+    pub pre_change_backups: PreChangeBackupSettings,
+    /// custom templates offered next to the built-in JVM presets
+    #[serde(default)]
+    pub jvm_presets: Vec<JvmPreset>,
+    /// always the current version in memory, older files are migrated on startup
+    #[serde(
+        default = "crate::migration::schema::global_settings_version",
+        skip_deserializing
+    )]
+    pub schema_version: u32,
 }
 
 impl Default for GlobalSettingsData {
@@ -21,84 +77,100 @@ impl Default for GlobalSettingsData {
             core_name: format!("{}'s Lodestone Core", whoami::realname()),
             safe_mode: true,
             domain: None,
+            firewall: FirewallSettings::default(),
+            flood_detection: FloodDetectionSettings::default(),
+            geoip: GeoIpSettings::default(),
+            vpn_detection: VpnDetectionSettings::default(),
+            alt_detection: AltDetectionSettings::default(),
+            console_policy: ConsoleCommandPolicy::default(),
+            votifier: VotifierSettings::default(),
+            backup_remotes: BackupRemotesSettings::default(),
+            peer_cores: PeerCoresSettings::default(),
+            maintenance: MaintenanceSettings::default(),
+            slow_requests: SlowRequestSettings::default(),
+            websocket: WebsocketSettings::default(),
+            start_queue: StartQueueSettings::default(),
+            prometheus: PrometheusSettings::default(),
+            metrics_history: MetricsHistorySettings::default(),
+            tick_monitor: TickMonitorSettings::default(),
+            email: EmailSettings::default(),
+            discord_webhook: DiscordWebhookConfig::default(),
+            pre_change_backups: PreChangeBackupSettings::default(),
+            jvm_presets: Vec::new(),
+            schema_version: crate::migration::schema::global_settings_version(),
         }
     }
 }
 
+/// Settings only read when the core starts, changes to these wait for a restart.
+/// Everything else is read when it's used or watched through `subscribe`.
+const RESTART_REQUIRED: &[&str] = &["start_queue"];
+
 pub struct GlobalSettings {
-    path_to_global_settings: PathBuf,
+    location: StateLocation,
     _event_broadcaster: EventBroadcaster,
     global_settings_data: GlobalSettingsData,
+    /// what the core started with, to tell which changes wait for a restart
+    started_with: Option<GlobalSettingsData>,
+    changes: watch::Sender<GlobalSettingsData>,
 }
 
 impl GlobalSettings {
     pub fn new(
-        path_to_global_settings: PathBuf,
+        location: impl Into<StateLocation>,
         _event_broadcaster: EventBroadcaster,
         global_settings_data: GlobalSettingsData,
     ) -> Self {
+        let (changes, _) = watch::channel(global_settings_data.clone());
         Self {
-            path_to_global_settings,
+            location: location.into(),
             _event_broadcaster,
             global_settings_data,
+            started_with: None,
+            changes,
         }
     }
     pub async fn load_from_file(&mut self) -> Result<(), Error> {
-        if tokio::fs::OpenOptions::new()
-            .read(true)
-            .create(true)
-            .write(true)
-            .open(&self.path_to_global_settings)
-            .await
-            .context(format!(
-                "Failed to open global settings file at {}",
-                self.path_to_global_settings.display()
-            ))?
-            .metadata()
-            .await
-            .context(format!(
-                "Failed to get metadata for global settings file at {}",
-                self.path_to_global_settings.display()
-            ))?
-            .len()
-            == 0
-        {
-            self.global_settings_data = GlobalSettingsData::default();
-        } else {
-            self.global_settings_data = serde_json::from_slice(
-                &tokio::fs::read(&self.path_to_global_settings)
-                    .await
-                    .context(format!(
-                        "Failed to read global settings file at {}",
-                        self.path_to_global_settings.display()
-                    ))?,
-            )
-            .context(format!(
-                "Failed to parse global settings file at {}",
-                self.path_to_global_settings.display()
-            ))?;
+        self.global_settings_data = self.location.read().await?.unwrap_or_default();
+        if self.started_with.is_none() {
+            self.started_with = Some(self.global_settings_data.clone());
         }
+        jvm_presets::set_custom_presets(self.global_settings_data.jvm_presets.clone());
+        self.changes.send_replace(self.global_settings_data.clone());
         Ok(())
     }
     async fn write_to_file(&self) -> Result<(), Error> {
-        let mut file = tokio::fs::File::create(&self.path_to_global_settings)
-            .await
-            .context(format!(
-                "Failed to create global settings file at {}",
-                self.path_to_global_settings.display()
-            ))?;
-        file.write_all(
-            serde_json::to_string_pretty(&self.global_settings_data)
-                .context("Failed to serialize global settings data")?
-                .as_bytes(),
-        )
-        .await
-        .context(format!(
-            "Failed to write to global settings file at {}",
-            self.path_to_global_settings.display()
-        ))?;
+        self.location.write(&self.global_settings_data).await?;
+        self.changes.send_replace(self.global_settings_data.clone());
         Ok(())
     }
+
+    /// Sees the settings every time they are changed or reloaded, for subsystems
+    /// that hold on to them
+    pub fn subscribe(&self) -> watch::Receiver<GlobalSettingsData> {
+        self.changes.subscribe()
+    }
+
+    /// Names of the settings changed since the core started that only take effect
+    /// after a restart
+    pub fn pending_restart(&self) -> Vec<String> {
+        let started_with = match &self.started_with {
+            Some(started_with) => started_with,
+            None => return Vec::new(),
+        };
+        let (old, new) = match (
+            serde_json::to_value(started_with),
+            serde_json::to_value(&self.global_settings_data),
+        ) {
+            (Ok(old), Ok(new)) => (old, new),
+            _ => return Vec::new(),
+        };
+        RESTART_REQUIRED
+            .iter()
+            .filter(|name| old.get(name) != new.get(name))
+            .map(|name| name.to_string())
+            .collect()
+    }
     pub async fn set_core_name(&mut self, name: String) -> Result<(), Error> {
         let old_name = self.global_settings_data.core_name.clone();
         self.global_settings_data.core_name = name;
@@ -146,6 +218,359 @@ impl GlobalSettings {
     pub fn domain(&self) -> Option<String> {
         self.global_settings_data.domain.clone()
     }
+
+    pub async fn set_firewall(&mut self, firewall: FirewallSettings) -> Result<(), Error> {
+        let old_firewall = self.global_settings_data.firewall.clone();
+        self.global_settings_data.firewall = firewall;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.firewall = old_firewall;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn firewall(&self) -> FirewallSettings {
+        self.global_settings_data.firewall.clone()
+    }
+
+    pub async fn set_flood_detection(
+        &mut self,
+        flood_detection: FloodDetectionSettings,
+    ) -> Result<(), Error> {
+        let old_flood_detection = self.global_settings_data.flood_detection.clone();
+        self.global_settings_data.flood_detection = flood_detection;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.flood_detection = old_flood_detection;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn flood_detection(&self) -> FloodDetectionSettings {
+        self.global_settings_data.flood_detection.clone()
+    }
+
+    pub async fn set_geoip(&mut self, geoip: GeoIpSettings) -> Result<(), Error> {
+        let old_geoip = self.global_settings_data.geoip.clone();
+        self.global_settings_data.geoip = geoip;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.geoip = old_geoip;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn geoip(&self) -> GeoIpSettings {
+        self.global_settings_data.geoip.clone()
+    }
+
+    pub async fn set_vpn_detection(
+        &mut self,
+        vpn_detection: VpnDetectionSettings,
+    ) -> Result<(), Error> {
+        let old_vpn_detection = self.global_settings_data.vpn_detection.clone();
+        self.global_settings_data.vpn_detection = vpn_detection;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.vpn_detection = old_vpn_detection;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn vpn_detection(&self) -> VpnDetectionSettings {
+        self.global_settings_data.vpn_detection.clone()
+    }
+
+    pub async fn set_alt_detection(
+        &mut self,
+        alt_detection: AltDetectionSettings,
+    ) -> Result<(), Error> {
+        let old_alt_detection = self.global_settings_data.alt_detection.clone();
+        self.global_settings_data.alt_detection = alt_detection;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.alt_detection = old_alt_detection;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn alt_detection(&self) -> AltDetectionSettings {
+        self.global_settings_data.alt_detection.clone()
+    }
+
+    pub async fn set_console_policy(
+        &mut self,
+        console_policy: ConsoleCommandPolicy,
+    ) -> Result<(), Error> {
+        let old_console_policy = self.global_settings_data.console_policy.clone();
+        self.global_settings_data.console_policy = console_policy;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.console_policy = old_console_policy;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn console_policy(&self) -> ConsoleCommandPolicy {
+        self.global_settings_data.console_policy.clone()
+    }
+
+    pub async fn set_votifier(&mut self, votifier: VotifierSettings) -> Result<(), Error> {
+        let old_votifier = self.global_settings_data.votifier.clone();
+        self.global_settings_data.votifier = votifier;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.votifier = old_votifier;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn votifier(&self) -> VotifierSettings {
+        self.global_settings_data.votifier.clone()
+    }
+
+    pub async fn set_backup_remotes(
+        &mut self,
+        backup_remotes: BackupRemotesSettings,
+    ) -> Result<(), Error> {
+        let old_backup_remotes = self.global_settings_data.backup_remotes.clone();
+        self.global_settings_data.backup_remotes = backup_remotes;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.backup_remotes = old_backup_remotes;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn backup_remotes(&self) -> BackupRemotesSettings {
+        self.global_settings_data.backup_remotes.clone()
+    }
+
+    pub async fn set_peer_cores(&mut self, peer_cores: PeerCoresSettings) -> Result<(), Error> {
+        let old_peer_cores = self.global_settings_data.peer_cores.clone();
+        self.global_settings_data.peer_cores = peer_cores;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.peer_cores = old_peer_cores;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn peer_cores(&self) -> PeerCoresSettings {
+        self.global_settings_data.peer_cores.clone()
+    }
+
+    pub async fn set_maintenance(&mut self, maintenance: MaintenanceSettings) -> Result<(), Error> {
+        let old_maintenance = self.global_settings_data.maintenance.clone();
+        self.global_settings_data.maintenance = maintenance;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.maintenance = old_maintenance;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn maintenance(&self) -> MaintenanceSettings {
+        self.global_settings_data.maintenance.clone()
+    }
+
+    pub async fn set_slow_requests(
+        &mut self,
+        slow_requests: SlowRequestSettings,
+    ) -> Result<(), Error> {
+        let old_slow_requests = self.global_settings_data.slow_requests.clone();
+        self.global_settings_data.slow_requests = slow_requests;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.slow_requests = old_slow_requests;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn slow_requests(&self) -> SlowRequestSettings {
+        self.global_settings_data.slow_requests.clone()
+    }
+
+    pub async fn set_websocket(&mut self, websocket: WebsocketSettings) -> Result<(), Error> {
+        let old_websocket = self.global_settings_data.websocket.clone();
+        self.global_settings_data.websocket = websocket;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.websocket = old_websocket;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn websocket(&self) -> WebsocketSettings {
+        self.global_settings_data.websocket.clone()
+    }
+
+    pub async fn set_start_queue(&mut self, start_queue: StartQueueSettings) -> Result<(), Error> {
+        let old_start_queue = self.global_settings_data.start_queue.clone();
+        self.global_settings_data.start_queue = start_queue;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.start_queue = old_start_queue;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn start_queue(&self) -> StartQueueSettings {
+        self.global_settings_data.start_queue.clone()
+    }
+
+    pub async fn set_prometheus(&mut self, prometheus: PrometheusSettings) -> Result<(), Error> {
+        let old_prometheus = self.global_settings_data.prometheus.clone();
+        self.global_settings_data.prometheus = prometheus;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.prometheus = old_prometheus;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn prometheus(&self) -> PrometheusSettings {
+        self.global_settings_data.prometheus.clone()
+    }
+
+    pub async fn set_metrics_history(
+        &mut self,
+        metrics_history: MetricsHistorySettings,
+    ) -> Result<(), Error> {
+        let old_metrics_history = self.global_settings_data.metrics_history.clone();
+        self.global_settings_data.metrics_history = metrics_history;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.metrics_history = old_metrics_history;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn metrics_history(&self) -> MetricsHistorySettings {
+        self.global_settings_data.metrics_history.clone()
+    }
+
+    pub async fn set_tick_monitor(
+        &mut self,
+        tick_monitor: TickMonitorSettings,
+    ) -> Result<(), Error> {
+        let old_tick_monitor = self.global_settings_data.tick_monitor.clone();
+        self.global_settings_data.tick_monitor = tick_monitor;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.tick_monitor = old_tick_monitor;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn tick_monitor(&self) -> TickMonitorSettings {
+        self.global_settings_data.tick_monitor.clone()
+    }
+
+    pub async fn set_email(&mut self, email: EmailSettings) -> Result<(), Error> {
+        let old_email = self.global_settings_data.email.clone();
+        self.global_settings_data.email = email;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.email = old_email;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn email(&self) -> EmailSettings {
+        self.global_settings_data.email.clone()
+    }
+
+    pub async fn set_discord_webhook(
+        &mut self,
+        discord_webhook: DiscordWebhookConfig,
+    ) -> Result<(), Error> {
+        let old_discord_webhook = self.global_settings_data.discord_webhook.clone();
+        self.global_settings_data.discord_webhook = discord_webhook;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.discord_webhook = old_discord_webhook;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn discord_webhook(&self) -> DiscordWebhookConfig {
+        self.global_settings_data.discord_webhook.clone()
+    }
+
+    pub async fn set_pre_change_backups(
+        &mut self,
+        pre_change_backups: PreChangeBackupSettings,
+    ) -> Result<(), Error> {
+        let old_pre_change_backups = self.global_settings_data.pre_change_backups.clone();
+        self.global_settings_data.pre_change_backups = pre_change_backups;
+        match self.write_to_file().await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.global_settings_data.pre_change_backups = old_pre_change_backups;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn pre_change_backups(&self) -> PreChangeBackupSettings {
+        self.global_settings_data.pre_change_backups.clone()
+    }
+
+    pub async fn set_jvm_presets(&mut self, presets: Vec<JvmPreset>) -> Result<(), Error> {
+        let old_presets = self.global_settings_data.jvm_presets.clone();
+        self.global_settings_data.jvm_presets = presets;
+        match self.write_to_file().await {
+            Ok(_) => {
+                jvm_presets::set_custom_presets(self.global_settings_data.jvm_presets.clone());
+                Ok(())
+            }
+            Err(e) => {
+                self.global_settings_data.jvm_presets = old_presets;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn jvm_presets(&self) -> Vec<JvmPreset> {
+        self.global_settings_data.jvm_presets.clone()
+    }
 }
 
 impl AsRef<GlobalSettingsData> for GlobalSettings {
@@ -213,4 +638,33 @@ mod tests {
 
         assert_eq!(global_settings.core_name(), "test_core_name");
     }
+
+    #[tokio::test]
+    async fn test_global_settings_changes() {
+        use super::*;
+        use std::path::PathBuf;
+
+        let temp_dir = tempdir::TempDir::new("test_global_settings_changes").unwrap();
+        let (event_broadcaster, _) = EventBroadcaster::new(10);
+        let mut global_settings = GlobalSettings::new(
+            PathBuf::from(temp_dir.path()).join("global_settings.json"),
+            event_broadcaster,
+            GlobalSettingsData::default(),
+        );
+        global_settings.load_from_file().await.unwrap();
+        let mut changes = global_settings.subscribe();
+
+        global_settings
+            .set_core_name("renamed".to_string())
+            .await
+            .unwrap();
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(changes.borrow_and_update().core_name, "renamed");
+        assert!(global_settings.pending_restart().is_empty());
+
+        let mut start_queue = global_settings.start_queue();
+        start_queue.max_concurrent += 1;
+        global_settings.set_start_queue(start_queue).await.unwrap();
+        assert_eq!(global_settings.pending_restart(), vec!["start_queue"]);
+    }
 }
@@ -0,0 +1,110 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    alerts::{AlertRule, NewAlertRule},
+    auth::user::User,
+    error::{Error, ErrorKind},
+    util::rand_alphanumeric,
+    AppState,
+};
+
+fn require_owner(requester: &User) -> Result<(), Error> {
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage alerts"),
+        });
+    }
+    Ok(())
+}
+
+pub async fn get_alerts(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<AlertRule>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    Ok(Json(state.alerts.lock().await.list()))
+}
+
+pub async fn create_alert(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(new_rule): Json<NewAlertRule>,
+) -> Result<Json<AlertRule>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    new_rule.validate()?;
+    let rule = AlertRule {
+        id: format!("ALERT_{}", rand_alphanumeric(12)),
+        name: new_rule.name,
+        enabled: new_rule.enabled,
+        instance_uuid: new_rule.instance_uuid,
+        condition: new_rule.condition,
+        actions: new_rule.actions,
+        cooldown_secs: new_rule.cooldown_secs,
+        created_by: requester.username.clone(),
+    };
+    Ok(Json(state.alerts.lock().await.set_rule(rule).await?))
+}
+
+pub async fn get_alert(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(alert_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<AlertRule>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    Ok(Json(state.alerts.lock().await.get(&alert_id)?))
+}
+
+pub async fn update_alert(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(alert_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+    Json(new_rule): Json<NewAlertRule>,
+) -> Result<Json<AlertRule>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    new_rule.validate()?;
+    let mut alerts = state.alerts.lock().await;
+    let old = alerts.get(&alert_id)?;
+    let rule = AlertRule {
+        id: old.id,
+        name: new_rule.name,
+        enabled: new_rule.enabled,
+        instance_uuid: new_rule.instance_uuid,
+        condition: new_rule.condition,
+        actions: new_rule.actions,
+        cooldown_secs: new_rule.cooldown_secs,
+        created_by: old.created_by,
+    };
+    Ok(Json(alerts.set_rule(rule).await?))
+}
+
+pub async fn delete_alert(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(alert_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    state.alerts.lock().await.remove_rule(&alert_id).await?;
+    Ok(Json(()))
+}
+
+pub fn get_alerts_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/alerts", get(get_alerts).post(create_alert))
+        .route(
+            "/alerts/:alert_id",
+            put(update_alert).get(get_alert).delete(delete_alert),
+        )
+        .with_state(state)
+}
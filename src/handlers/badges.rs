@@ -0,0 +1,143 @@
+use axum::{
+    extract::Path,
+    http::header,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    badges::{
+        parse_badge_name, BadgeContent, BadgeFormat, BadgeSettings, BadgeSnapshot, BADGE_TTL_SECS,
+    },
+    error::{Error, ErrorKind},
+    traits::{t_configurable::TConfigurable, t_player::TPlayerManagement, t_server::TServer},
+    types::InstanceUuid,
+    AppState,
+};
+
+/// Instances without badges turned on look the same as missing ones
+fn badge_not_found() -> Error {
+    Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Badge not found"),
+    }
+}
+
+async fn badge_snapshot(state: &AppState, uuid: &InstanceUuid) -> Result<BadgeSnapshot, Error> {
+    let now = chrono::Utc::now().timestamp();
+    if let Some(snapshot) = state.badge_cache.lock().await.get(uuid, now) {
+        return Ok(snapshot);
+    }
+    let instance = state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .cloned()
+        .ok_or_else(badge_not_found)?;
+    let settings = BadgeSettings::load(&instance.path().await).await?;
+    if !settings.enabled {
+        return Err(badge_not_found());
+    }
+    let snapshot = BadgeSnapshot {
+        label: match settings.label {
+            Some(label) => label,
+            None => instance.name().await,
+        },
+        state: instance.state().await,
+        players: instance.get_player_count().await.ok(),
+        max_players: instance.get_max_player_count().await.ok(),
+    };
+    state
+        .badge_cache
+        .lock()
+        .await
+        .insert(uuid.clone(), now, snapshot.clone());
+    Ok(snapshot)
+}
+
+/// Public, for embedding in forum posts and READMEs. `badge` is one of
+/// `status.svg`, `status.json`, `players.svg` or `players.json`, the JSON ones
+/// work as shields.io endpoint badges.
+pub async fn get_badge(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, badge)): Path<(InstanceUuid, String)>,
+) -> Result<Response, Error> {
+    let (kind, format) = parse_badge_name(&badge).ok_or_else(badge_not_found)?;
+    let content = BadgeContent::new(&badge_snapshot(&state, &uuid).await?, kind);
+    let cache_control = (
+        header::CACHE_CONTROL,
+        format!("public, max-age={}", BADGE_TTL_SECS),
+    );
+    Ok(match format {
+        BadgeFormat::Svg => (
+            [
+                (header::CONTENT_TYPE, "image/svg+xml".to_string()),
+                cache_control,
+            ],
+            content.to_svg(),
+        )
+            .into_response(),
+        BadgeFormat::Json => ([cache_control], Json(content)).into_response(),
+    })
+}
+
+pub async fn get_badge_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BadgeSettings>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(BadgeSettings::load(&path).await?))
+}
+
+pub async fn set_badge_settings(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(settings): Json<BadgeSettings>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    settings.validate()?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    settings.save(&path).await?;
+    state.badge_cache.lock().await.remove(&uuid);
+    Ok(Json(()))
+}
+
+pub fn get_badges_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/badge/:uuid/:badge", get(get_badge))
+        .route(
+            "/instance/:uuid/badges",
+            get(get_badge_settings).put(set_badge_settings),
+        )
+        .with_state(state)
+}
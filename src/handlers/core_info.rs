@@ -1,8 +1,23 @@
+use std::collections::BTreeMap;
 use std::env;
 
-use crate::{prelude::VERSION, AppState};
-use axum::{routing::get, Json, Router};
+use crate::{
+    ban_list::apply_bans,
+    error::{Error, ErrorKind},
+    host::HostInfo,
+    prelude::{lodestone_path, VERSION},
+    self_check::CoreIssue,
+    service::{install_service, InstallService, InstalledService},
+    AppState,
+};
+use axum::{
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sysinfo::{CpuExt, DiskExt, System, SystemExt};
 
 #[derive(Serialize, Deserialize)]
@@ -19,12 +34,18 @@ pub struct CoreInfo {
     uuid: String,
     core_name: String,
     up_since: i64,
+    /// the architecture Java runtimes are downloaded for, `None` if there are none
+    /// for this host
+    jre_arch: Option<String>,
+    /// new instances get smaller defaults on a low power host
+    low_power: bool,
 }
 
 pub async fn get_core_info(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Json<CoreInfo> {
     let sys = System::new_all();
+    let host = HostInfo::detect();
     Json(CoreInfo {
         version: VERSION.with(|v| v.clone()),
         is_setup: state.first_time_setup_key.lock().await.is_none(),
@@ -50,11 +71,92 @@ pub async fn get_core_info(
         core_name: state.global_settings.lock().await.core_name(),
         uuid: state.uuid.clone(),
         up_since: state.up_since,
+        jre_arch: host.jre_arch,
+        low_power: host.low_power,
     })
 }
 
+/// What the self check found on startup, repaired issues included
+pub async fn get_core_issues(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CoreIssue>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner && !requester.is_admin {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to view core issues"),
+        });
+    }
+    Ok(Json(state.core_issues.as_ref().clone()))
+}
+
+/// Every document of the state store, to keep as a file or to move to another core
+pub async fn export_core_state(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BTreeMap<String, Value>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can export the core state"),
+        });
+    }
+    Ok(Json(state.state_store.export().await?))
+}
+
+/// Replaces the documents in the body, as exported, and reloads them. Documents
+/// left out are kept.
+pub async fn import_core_state(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(documents): Json<BTreeMap<String, Value>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can import the core state"),
+        });
+    }
+    state.state_store.import(documents).await?;
+    state.state_store.migrate_documents().await?;
+    state.users_manager.write().await.load_users().await?;
+    state.global_settings.lock().await.load_from_file().await?;
+    state.database_hosts.lock().await.load_from_file().await?;
+    state.alerts.lock().await.load_from_file().await?;
+    state.webhooks.lock().await.load_from_file().await?;
+    let bans = {
+        let mut ban_list = state.ban_list.lock().await;
+        ban_list.load_from_file().await?;
+        ban_list.list()
+    };
+    apply_bans(&state.instances, &bans).await;
+    Ok(Json(()))
+}
+
+/// Installs a service starting this core at boot, see `service::install_service`
+pub async fn post_install_service(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<InstallService>,
+) -> Result<Json<InstalledService>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can install the core as a service"),
+        });
+    }
+    Ok(Json(install_service(lodestone_path(), request).await?))
+}
+
 pub fn get_core_info_routes(state: AppState) -> Router {
     Router::new()
         .route("/info", get(get_core_info))
+        .route("/core/issues", get(get_core_issues))
+        .route("/core/state", get(export_core_state).put(import_core_state))
+        .route("/core/install_service", post(post_install_service))
         .with_state(state)
 }
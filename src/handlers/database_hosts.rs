@@ -0,0 +1,75 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    error::{Error, ErrorKind},
+    external_db::provision::DatabaseHost,
+    AppState,
+};
+
+pub async fn get_database_hosts(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<DatabaseHost>>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(state.database_hosts.lock().await.list()))
+}
+
+pub async fn add_database_host(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(host): Json<DatabaseHost>,
+) -> Result<Json<DatabaseHost>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage database hosts"),
+        });
+    }
+    if host.name.is_empty() || host.host.is_empty() || host.admin_username.is_empty() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Name, host and admin username are required"),
+        });
+    }
+    Ok(Json(
+        state.database_hosts.lock().await.add_host(host).await?,
+    ))
+}
+
+pub async fn remove_database_host(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(host_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage database hosts"),
+        });
+    }
+    state
+        .database_hosts
+        .lock()
+        .await
+        .remove_host(&host_id)
+        .await?;
+    Ok(Json(()))
+}
+
+pub fn get_database_hosts_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/database_hosts",
+            get(get_database_hosts).post(add_database_host),
+        )
+        .route("/database_hosts/:host_id", delete(remove_database_host))
+        .with_state(state)
+}
@@ -1,7 +1,12 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use axum::{
-    extract::{ws::WebSocket, Path, Query, WebSocketUpgrade},
+    extract::{
+        ws::{Message, WebSocket},
+        Path, Query, WebSocketUpgrade,
+    },
+    http::HeaderMap,
     response::Response,
     routing::get,
     Json, Router,
@@ -9,28 +14,36 @@ use axum::{
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::eyre;
-use futures::{SinkExt, StreamExt};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{Sink, SinkExt, StreamExt};
 use ringbuffer::{AllocRingBuffer, RingBufferExt};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
 use crate::output_types::ClientEvent;
-use crate::types::InstanceUuid;
+use crate::types::{InstanceUuid, Snowflake};
 use crate::{
-    auth::{user::UsersManager, user_id::UserId},
-    db::read::search_events,
+    auth::user::{User, UsersManager},
+    db::read::{events_after, search_event_history, EventHistoryQuery},
     error::{Error, ErrorKind},
-    events::EventQuery,
+    events::{EventQuery, EventType},
 };
 
 use crate::{
     events::{Event, EventInner, UserEventInner},
     AppState,
 };
-use serde::Deserialize;
-use tokio::sync::{broadcast::Receiver, RwLock};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    RwLock,
+};
+use tokio::time::Instant;
 use ts_rs::TS;
 
 use super::util::parse_bearer_token;
+use super::ws_auth::{token_from_protocols, AuthFrame, WsSession, WS_PROTOCOL};
+use super::ws_protocol::{ClientFrame, ServerFrame, StreamKind};
 
 #[derive(Deserialize, Clone, Debug, TS)]
 pub struct EventQueryWrapper {
@@ -73,30 +86,190 @@ pub async fn get_event_buffer(
     ))
 }
 
-// TODO implement me
+const DEFAULT_SEARCH_LIMIT: u32 = 100;
+const MAX_SEARCH_LIMIT: u32 = 500;
+
+#[derive(Deserialize)]
+pub struct EventSearchQuery {
+    instance: Option<InstanceUuid>,
+    #[serde(rename = "type")]
+    event_type: Option<EventType>,
+    /// only events newer than this snowflake
+    after: Option<Snowflake>,
+    /// `next_cursor` of the previous page
+    cursor: Option<Snowflake>,
+    limit: Option<u32>,
+    /// same filter as the other event endpoints, applied to each page
+    filter: Option<String>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct EventSearchPage {
+    /// newest first
+    events: Vec<ClientEvent>,
+    /// pass as `cursor` to get the next, older, page. `None` on the last page
+    next_cursor: Option<Snowflake>,
+}
+
+/// Stored events, newest first and a page at a time. A page can hold fewer
+/// events than `limit` when some are filtered out or not visible to the
+/// requester, only a missing `next_cursor` means there are no more.
 pub async fn get_event_search(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
-    query: Query<EventQueryWrapper>,
-) -> Result<Json<Vec<ClientEvent>>, Error> {
-    // deserialize query
-    let query: EventQuery = serde_json::from_str(&query.filter).map_err(|e| {
-        error!("Error deserializing event query: {}", e);
-        Error {
-            kind: ErrorKind::BadRequest,
-            source: e.into(),
+    Query(query): Query<EventSearchQuery>,
+) -> Result<Json<EventSearchPage>, Error> {
+    let filter: Option<EventQuery> = match &query.filter {
+        Some(filter) => Some(serde_json::from_str(filter).map_err(|e| {
+            error!("Error deserializing event query: {}", e);
+            Error {
+                kind: ErrorKind::BadRequest,
+                source: e.into(),
+            }
+        })?),
+        None => None,
+    };
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let stored = search_event_history(
+        &state.sqlite_pool,
+        &EventHistoryQuery {
+            instance: query.instance,
+            event_type: query.event_type,
+            after: query.after,
+            before: query.cursor,
+            limit,
+        },
+    )
+    .await?;
+    let next_cursor = if stored.len() as u32 >= limit {
+        stored.last().map(|event| event.snowflake)
+    } else {
+        None
+    };
+    let events = stored
+        .into_iter()
+        .filter(|client_event| {
+            filter
+                .as_ref()
+                .map_or(true, |filter| filter.filter(client_event))
+                && requester.can_view_event(&Event::from(client_event))
+        })
+        .collect();
+    Ok(Json(EventSearchPage {
+        events,
+        next_cursor,
+    }))
+}
+
+/// Events read from the database per poll, a poll that fills up returns right away
+const POLL_BATCH: u32 = 500;
+const DEFAULT_POLL_TIMEOUT: u64 = 30;
+const MAX_POLL_TIMEOUT: u64 = 60;
+
+#[derive(Deserialize)]
+pub struct PollQuery {
+    /// snowflake of the last event the client has seen, only events from now on
+    /// are returned without one
+    cursor: Option<Snowflake>,
+    /// seconds to wait for new events
+    timeout: Option<u64>,
+    /// same filter as the other event endpoints
+    filter: Option<String>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct EventPoll {
+    events: Vec<ClientEvent>,
+    /// pass as `cursor` to the next poll
+    cursor: Snowflake,
+}
+
+/// Long-polling fallback for clients that can't hold a websocket open. Returns
+/// the events after the cursor, or waits up to `timeout` seconds for one.
+pub async fn poll_events(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<PollQuery>,
+) -> Result<Json<EventPoll>, Error> {
+    let filter: Option<EventQuery> = match &query.filter {
+        Some(filter) => Some(serde_json::from_str(filter).map_err(|e| {
+            error!("Error deserializing event query: {}", e);
+            Error {
+                kind: ErrorKind::BadRequest,
+                source: e.into(),
+            }
+        })?),
+        None => None,
+    };
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let visible = |event: &Event| {
+        !event.is_event_console_message()
+            && filter
+                .as_ref()
+                .map_or(true, |filter| filter.filter(ClientEvent::from(event)))
+            && requester.can_view_event(event)
+    };
+    // subscribe before reading the database so nothing falls in between
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut cursor = match query.cursor {
+        Some(cursor) => cursor,
+        None => Snowflake::new(),
+    };
+
+    if query.cursor.is_some() {
+        let stored = events_after(&state.sqlite_pool, cursor, POLL_BATCH).await?;
+        let full = stored.len() as u32 >= POLL_BATCH;
+        if let Some(last) = stored.last() {
+            cursor = last.snowflake;
         }
-    })?;
-    let _requester = state
-        .users_manager
-        .read()
-        .await
-        .try_auth(&token)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::Unauthorized,
-            source: eyre!("Token error"),
-        })?;
-    search_events(&state.sqlite_pool, query).await.map(Json)
+        let events: Vec<ClientEvent> = stored
+            .into_iter()
+            .filter(|client_event| visible(&Event::from(client_event)))
+            .collect();
+        if !events.is_empty() || full {
+            return Ok(Json(EventPoll { events, cursor }));
+        }
+    }
+
+    let timeout = Duration::from_secs(
+        query
+            .timeout
+            .unwrap_or(DEFAULT_POLL_TIMEOUT)
+            .min(MAX_POLL_TIMEOUT),
+    );
+    let deadline = Instant::now() + timeout;
+    let mut events = Vec::new();
+    loop {
+        // once something arrived, only take what's already queued
+        let received = if events.is_empty() {
+            match tokio::time::timeout_at(deadline, event_receiver.recv()).await {
+                Ok(received) => received,
+                Err(_) => break,
+            }
+        } else {
+            match event_receiver.try_recv() {
+                Ok(event) => Ok(event),
+                Err(_) => break,
+            }
+        };
+        match received {
+            Ok(event) => {
+                if event.snowflake > cursor && visible(&event) {
+                    cursor = event.snowflake;
+                    events.push(ClientEvent::from(event));
+                }
+            }
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+    Ok(Json(EventPoll { events, cursor }))
 }
 
 pub async fn get_console_buffer(
@@ -135,155 +308,468 @@ pub async fn get_console_buffer(
 
 #[derive(Deserialize)]
 pub struct WebsocketQuery {
-    token: String,
+    /// deprecated, tokens in query strings end up in proxy logs. Offer the token
+    /// as a subprotocol or send it in the first frame instead.
+    token: Option<String>,
 }
 
-pub async fn event_stream(
-    ws: WebSocketUpgrade,
-    axum::extract::State(state): axum::extract::State<AppState>,
-    query: Query<EventQueryWrapper>,
-) -> Result<Response, Error> {
-    let query: EventQuery = serde_json::from_str(query.filter.as_str()).map_err(|e| {
-        error!("Error deserializing event query: {}", e);
-        Error {
-            kind: ErrorKind::BadRequest,
-            source: e.into(),
-        }
-    })?;
-    let token = query.bearer_token.clone().ok_or(Error {
-        kind: ErrorKind::BadRequest,
-        source: eyre!("Missing token"),
-    })?;
+#[derive(Deserialize)]
+pub struct ResumeQuery {
+    /// session id of a stream that dropped, to get the events missed since
+    resume: Option<String>,
+}
 
-    let user = state
-        .users_manager
-        .read()
-        .await
-        .try_auth(&token)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::Unauthorized,
-            source: eyre!("Token error"),
-        })?;
-    let event_receiver = state.event_broadcaster.subscribe();
+#[derive(Deserialize)]
+pub struct ReplayQuery {
+    /// snowflake of the last event the client has seen, the stored events after
+    /// it are sent before the live ones. Unlike `resume` this works after the
+    /// resume window and across restarts of the core.
+    replay_from: Option<Snowflake>,
+}
 
-    Ok(ws.on_upgrade(move |socket| {
-        event_stream_ws(socket, event_receiver, query, user.uid, state.users_manager)
-    }))
+/// Stored events a replay reads at most, older gaps have to be searched for
+const MAX_REPLAY: u32 = 5000;
+
+/// What a stream does with an event
+enum Forward {
+    Send(ServerFrame),
+    Skip,
+    Close,
+}
+
+/// Authenticates a websocket before the upgrade if the client offered a token,
+/// otherwise the client has to send one in its first frame
+async fn authenticate_upgrade(
+    state: &AppState,
+    token: Option<String>,
+) -> Result<Option<(User, i64)>, Error> {
+    match token {
+        Some(token) => state
+            .users_manager
+            .read()
+            .await
+            .try_auth_with_expiry(&token)
+            .map(Some)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("Token error"),
+            }),
+        None => Ok(None),
+    }
+}
+
+async fn start_session(
+    sender: &mut SplitSink<WebSocket, Message>,
+    receiver: &mut SplitStream<WebSocket>,
+    authed: Option<(User, i64)>,
+    users_manager: &Arc<RwLock<UsersManager>>,
+) -> Option<WsSession> {
+    match authed {
+        Some((user, expires_at)) => Some(WsSession::new(&user, expires_at)),
+        None => WsSession::from_first_frame(sender, receiver, users_manager).await,
+    }
 }
 
-async fn event_stream_ws(
+/// Sends the stored events after `after` that `forward` picks, oldest first, then
+/// a `Replayed` frame. Returns the snowflake of the last event read, `None` if
+/// the client went away.
+async fn replay(
+    sender: &mut (impl Sink<Message> + Unpin),
+    pool: &SqlitePool,
+    user: &User,
+    filter: Option<&EventQuery>,
+    after: Snowflake,
+    forward: &impl Fn(&Event, &User, Option<&EventQuery>) -> Forward,
+) -> Option<Snowflake> {
+    let mut cursor = after;
+    let mut count = 0;
+    let mut read = 0;
+    let complete = loop {
+        if read >= MAX_REPLAY {
+            break false;
+        }
+        let stored = match events_after(pool, cursor, POLL_BATCH).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                error!("Failed to read events to replay: {}", e);
+                break false;
+            }
+        };
+        let full = stored.len() as u32 >= POLL_BATCH;
+        read += stored.len() as u32;
+        for client_event in stored {
+            cursor = client_event.snowflake;
+            if let Forward::Send(frame) = forward(&Event::from(&client_event), user, filter) {
+                sender.send(frame.to_message()).await.ok()?;
+                count += 1;
+            }
+        }
+        if !full {
+            break true;
+        }
+    };
+    sender
+        .send(
+            ServerFrame::Replayed {
+                count,
+                cursor,
+                complete,
+            }
+            .to_message(),
+        )
+        .await
+        .ok()?;
+    Some(cursor)
+}
+
+/// Sends the events `forward` picks to the client until either side goes away,
+/// pinging the client and dropping it once it stops answering. A stream that
+/// drops without a close frame can be resumed for a while.
+async fn run_stream(
     stream: WebSocket,
-    mut event_receiver: Receiver<Event>,
-    query: EventQuery,
-    uid: UserId,
-    users_manager: Arc<RwLock<UsersManager>>,
+    event_receiver: Receiver<Event>,
+    authed: Option<(User, i64)>,
+    resume: Option<String>,
+    mut filter: Option<EventQuery>,
+    replay_from: Option<Snowflake>,
+    stream_kind: StreamKind,
+    state: AppState,
+    forward: impl Fn(&Event, &User, Option<&EventQuery>) -> Forward,
 ) {
     let (mut sender, mut receiver) = stream.split();
+    let mut session =
+        match start_session(&mut sender, &mut receiver, authed, &state.users_manager).await {
+            Some(session) => session,
+            None => return,
+        };
+    let uid = session.uid.clone();
+    let mut settings_changes = state.global_settings.lock().await.subscribe();
+    let settings = settings_changes.borrow_and_update().websocket.clone();
+    let mut window = Duration::from_secs(settings.resume_window_secs);
+    let resumed = match &resume {
+        Some(session_id) => state
+            .ws_sessions
+            .lock()
+            .await
+            .resume(session_id, &uid, window)
+            .map(|receiver| (session_id.clone(), receiver)),
+        None => None,
+    };
+    let (session_id, mut event_receiver, resumed) = match resumed {
+        Some((session_id, receiver)) => (session_id, receiver, true),
+        None => (uuid::Uuid::new_v4().to_string(), event_receiver, false),
+    };
+    if sender
+        .send(
+            ServerFrame::Subscribed {
+                session_id: session_id.clone(),
+                resumed,
+                heartbeat_interval_secs: settings.heartbeat_interval_secs,
+                stream: stream_kind.clone(),
+            }
+            .to_message(),
+        )
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    // live events up to here were already sent by a replay
+    let mut replayed_until = None;
+    if let Some(after) = replay_from {
+        let user = match state.users_manager.read().await.get_user(&uid) {
+            Some(user) => user,
+            None => return,
+        };
+        match replay(
+            &mut sender,
+            &state.sqlite_pool,
+            &user,
+            filter.as_ref(),
+            after,
+            &forward,
+        )
+        .await
+        {
+            Some(cursor) => replayed_until = Some(cursor),
+            None => return,
+        }
+    }
+
+    let mut heartbeat_interval_secs = settings.heartbeat_interval_secs;
+    let mut idle_timeout = Duration::from_secs(settings.idle_timeout_secs);
+    let mut heartbeat = tokio::time::interval_at(
+        Instant::now() + Duration::from_secs(settings.heartbeat_interval_secs),
+        Duration::from_secs(settings.heartbeat_interval_secs),
+    );
+    let mut last_seen = Instant::now();
+    // whether the client may come back for the events it misses
+    let mut resumable = false;
     loop {
         tokio::select! {
-            Ok(event) = event_receiver.recv() => {
-                if event.is_event_console_message() {
+            event = event_receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Websocket stream fell behind, skipped {} events", skipped);
+                        let frame = ServerFrame::Error {
+                            message: format!("Skipped {} events, the connection fell behind", skipped),
+                        };
+                        if sender.send(frame.to_message()).await.is_err() {
+                            resumable = true;
+                            break;
+                        }
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if replayed_until.map_or(false, |until| event.snowflake <= until) {
                     continue;
                 }
-                let user = match users_manager.read().await.get_user(&uid) {
+                let user = match state.users_manager.read().await.get_user(&uid) {
                     Some(user) => user,
-                    None => {
-                        break;
-                    }
+                    None => break,
                 };
-                if query.filter(ClientEvent::from(event.clone())) && user.can_view_event(&event) {
-                    if let Err(e) = sender.send(axum::extract::ws::Message::Text(serde_json::to_string(&event).unwrap())).await {
-                        error!("Error sending event to websocket: {}", e);
-                        break;
+                match forward(&event, &user, filter.as_ref()) {
+                    Forward::Send(frame) => {
+                        if let Err(e) = sender.send(frame.to_message()).await {
+                            error!("Error sending event to websocket: {}", e);
+                            resumable = true;
+                            break;
+                        }
                     }
+                    Forward::Skip => {}
+                    Forward::Close => break,
+                }
+            }
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > idle_timeout {
+                    debug!("Websocket idle for {}s, closing it", last_seen.elapsed().as_secs());
+                    resumable = true;
+                    break;
+                }
+                if sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    resumable = true;
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(session.next_check()) => {
+                if !session.tick(&mut sender).await {
+                    break;
                 }
             }
-            Some(Ok(ws_msg)) = receiver.next() => {
-                match sender.send(ws_msg).await {
-                    Ok(_) => debug!("Replied to ping"),
-                    Err(_) => {debug!("Websocket disconnected"); break},
+            Ok(()) = settings_changes.changed() => {
+                // the client keeps the heartbeat interval it was told, a shorter one
+                // only means more pings than it expects
+                let settings = settings_changes.borrow_and_update().websocket.clone();
+                window = Duration::from_secs(settings.resume_window_secs);
+                heartbeat_interval_secs = settings.heartbeat_interval_secs;
+                idle_timeout = Duration::from_secs(settings.idle_timeout_secs);
+                heartbeat = tokio::time::interval_at(
+                    Instant::now() + Duration::from_secs(settings.heartbeat_interval_secs),
+                    Duration::from_secs(settings.heartbeat_interval_secs),
+                );
+            }
+            ws_msg = receiver.next() => {
+                let ws_msg = match ws_msg {
+                    Some(Ok(ws_msg)) => ws_msg,
+                    _ => {
+                        debug!("Websocket disconnected");
+                        resumable = true;
+                        break;
+                    }
                 };
+                last_seen = Instant::now();
+                match ws_msg {
+                    Message::Text(text) => {
+                        match session.handle_frame(&text, &state.users_manager, &mut sender).await {
+                            AuthFrame::Accepted => continue,
+                            AuthFrame::Rejected => break,
+                            AuthFrame::NotAuth => {}
+                        }
+                        let reply = match serde_json::from_str(&text) {
+                            Ok(ClientFrame::Ping) => ServerFrame::Pong.to_message(),
+                            Ok(ClientFrame::Subscribe { .. })
+                                if stream_kind != StreamKind::Events =>
+                            {
+                                ServerFrame::Error {
+                                    message: "Only the event stream can be filtered".to_string(),
+                                }
+                                .to_message()
+                            }
+                            Ok(ClientFrame::Subscribe { filter: new_filter, replay_from }) => {
+                                filter = Some(new_filter);
+                                let ack = ServerFrame::Subscribed {
+                                    session_id: session_id.clone(),
+                                    resumed: false,
+                                    heartbeat_interval_secs,
+                                    stream: stream_kind.clone(),
+                                };
+                                if sender.send(ack.to_message()).await.is_err() {
+                                    resumable = true;
+                                    break;
+                                }
+                                if let Some(after) = replay_from {
+                                    let user = state.users_manager.read().await.get_user(&uid);
+                                    let user = match user {
+                                        Some(user) => user,
+                                        None => break,
+                                    };
+                                    let replayed = replay(
+                                        &mut sender,
+                                        &state.sqlite_pool,
+                                        &user,
+                                        filter.as_ref(),
+                                        after,
+                                        &forward,
+                                    )
+                                    .await;
+                                    match replayed {
+                                        Some(cursor) => replayed_until = Some(cursor),
+                                        None => {
+                                            resumable = true;
+                                            break;
+                                        }
+                                    }
+                                }
+                                continue;
+                            }
+                            _ => Message::Text(text),
+                        };
+                        if sender.send(reply).await.is_err() {
+                            resumable = true;
+                            break;
+                        }
+                    }
+                    // answered by the websocket implementation
+                    Message::Ping(_) | Message::Pong(_) => {}
+                    Message::Close(_) => break,
+                    Message::Binary(data) => {
+                        if sender.send(Message::Binary(data)).await.is_err() {
+                            resumable = true;
+                            break;
+                        }
+                    }
+                }
             }
         }
     }
+    if resumable {
+        state
+            .ws_sessions
+            .lock()
+            .await
+            .detach(session_id, uid, event_receiver, window);
+    }
+}
+
+pub async fn event_stream(
+    ws: WebSocketUpgrade,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    headers: HeaderMap,
+    query: Query<EventQueryWrapper>,
+    Query(resume): Query<ResumeQuery>,
+    Query(replay_query): Query<ReplayQuery>,
+) -> Result<Response, Error> {
+    let query: EventQuery = serde_json::from_str(query.filter.as_str()).map_err(|e| {
+        error!("Error deserializing event query: {}", e);
+        Error {
+            kind: ErrorKind::BadRequest,
+            source: e.into(),
+        }
+    })?;
+    let token = query
+        .bearer_token
+        .clone()
+        .or_else(|| token_from_protocols(&headers));
+    let authed = authenticate_upgrade(&state, token).await?;
+    let event_receiver = state.event_broadcaster.subscribe();
+
+    Ok(ws.protocols([WS_PROTOCOL]).on_upgrade(move |socket| {
+        run_stream(
+            socket,
+            event_receiver,
+            authed,
+            resume.resume,
+            Some(query),
+            replay_query.replay_from,
+            StreamKind::Events,
+            state,
+            forward_event,
+        )
+    }))
+}
+
+/// What the event stream sends: everything but console lines that the filter
+/// and the user's permissions let through
+fn forward_event(event: &Event, user: &User, filter: Option<&EventQuery>) -> Forward {
+    let client_event = ClientEvent::from(event);
+    if !event.is_event_console_message()
+        && filter.map_or(true, |filter| filter.filter(&client_event))
+        && user.can_view_event(event)
+    {
+        Forward::Send(ServerFrame::Event(client_event))
+    } else {
+        Forward::Skip
+    }
 }
 
 pub async fn console_stream(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
+    headers: HeaderMap,
     query: Query<WebsocketQuery>,
+    Query(resume): Query<ResumeQuery>,
     Path(uuid): Path<InstanceUuid>,
 ) -> Result<Response, Error> {
-    let users_manager = state.users_manager.read().await;
-
-    let user = parse_bearer_token(query.token.as_str())
-        .and_then(|token| users_manager.try_auth(&token))
-        .ok_or_else(|| Error {
+    let token = match &query.token {
+        Some(token) => Some(parse_bearer_token(token).ok_or_else(|| Error {
             kind: ErrorKind::Unauthorized,
             source: eyre!("Token error"),
-        })?;
-    drop(users_manager);
+        })?),
+        None => token_from_protocols(&headers),
+    };
+    let authed = authenticate_upgrade(&state, token).await?;
     let event_receiver = state.event_broadcaster.subscribe();
 
-    Ok(ws.on_upgrade(move |socket| {
-        console_stream_ws(socket, event_receiver, user.uid, uuid, state.users_manager)
-    }))
-}
-
-async fn console_stream_ws(
-    stream: WebSocket,
-    mut event_receiver: Receiver<Event>,
-    uid: UserId,
-    uuid: InstanceUuid,
-    users_manager: Arc<RwLock<UsersManager>>,
-) {
-    let (mut sender, mut receiver) = stream.split();
-    loop {
-        tokio::select! {
-            Ok(event) = event_receiver.recv() => {
-                match &event.event_inner {
-                    EventInner::InstanceEvent(instance_event) => {
-                        let user = match users_manager.read().await.get_user(&uid) {
-                            Some(user) => user,
-                            None => break,
-                        };
-                        if event.is_event_console_message() && (instance_event.instance_uuid == uuid || uuid == "all")
-                            && user.can_view_event(&event)
-                        {
-                            if let Err(e) = sender
-                                .send(axum::extract::ws::Message::Text(
-                                    serde_json::to_string(&event).unwrap(),
-                                ))
-                                .await
-                            {
-                                error!("Failed to send event: {}", e);
-                                break;
-                            }
-                        }
+    Ok(ws.protocols([WS_PROTOCOL]).on_upgrade(move |socket| {
+        run_stream(
+            socket,
+            event_receiver,
+            authed,
+            resume.resume,
+            None,
+            None,
+            StreamKind::Console {
+                instance_uuid: uuid.clone(),
+            },
+            state,
+            move |event, user, _| match &event.event_inner {
+                EventInner::InstanceEvent(instance_event) => {
+                    if event.is_event_console_message()
+                        && (instance_event.instance_uuid == uuid || uuid == "all")
+                        && user.can_view_event(event)
+                    {
+                        Forward::Send(ServerFrame::ConsoleLine(ClientEvent::from(event)))
+                    } else {
+                        Forward::Skip
                     }
-                    EventInner::UserEvent(user_event) => {
-                        match user_event.user_event_inner {
-                            UserEventInner::UserLoggedOut | UserEventInner::UserDeleted => {
-                                if user_event.user_id == uid {
-                                    break;
-                                }
-                            },
-                            _ => {}
-                        }
-                    },
-                    EventInner::MacroEvent(_) => continue,
-                    EventInner::ProgressionEvent(_) => continue,
-                    EventInner::FSEvent(_) => continue,
                 }
-            }
-            Some(Ok(ws_msg)) = receiver.next() => {
-                match sender.send(ws_msg).await {
-                    Ok(_) => debug!("Replied to ping"),
-                    Err(_) => break,
-                };
-            }
-        }
-    }
+                EventInner::UserEvent(user_event) => match user_event.user_event_inner {
+                    UserEventInner::UserLoggedOut | UserEventInner::UserDeleted
+                        if user_event.user_id == user.uid =>
+                    {
+                        Forward::Close
+                    }
+                    _ => Forward::Skip,
+                },
+                EventInner::MacroEvent(_) => Forward::Skip,
+                EventInner::ProgressionEvent(_) => Forward::Skip,
+                EventInner::FSEvent(_) => Forward::Skip,
+            },
+        )
+    }))
 }
 
 pub fn get_events_routes(state: AppState) -> Router {
@@ -291,7 +777,82 @@ pub fn get_events_routes(state: AppState) -> Router {
         .route("/events/:uuid/stream", get(event_stream))
         .route("/events/:uuid/buffer", get(get_event_buffer))
         .route("/events/search", get(get_event_search))
+        .route("/events/poll", get(poll_events))
         .route("/instance/:uuid/console/stream", get(console_stream))
         .route("/instance/:uuid/console/buffer", get(get_console_buffer))
         .with_state(state)
 }
+
+#[tokio::test]
+async fn test_replay() {
+    use crate::auth::permission::UserPermission;
+    use crate::db::write::{init_client_events_table, write_client_event};
+    use crate::traits::t_server::State;
+
+    // one connection, every connection to memory opens a database of its own
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    init_client_events_table(&pool).await.unwrap();
+    let survival = InstanceUuid::from("survival".to_string());
+    let creative = InstanceUuid::from("creative".to_string());
+    let mut snowflakes = Vec::new();
+    for uuid in [&survival, &creative, &survival, &creative] {
+        let mut event =
+            Event::new_instance_state_transition(uuid.clone(), "name".to_string(), State::Running);
+        event.snowflake = Snowflake::new();
+        snowflakes.push(event.snowflake);
+        write_client_event(&pool, ClientEvent::from(event))
+            .await
+            .unwrap();
+    }
+    let owner = User::new(
+        "owner".to_string(),
+        "password",
+        true,
+        false,
+        UserPermission::default(),
+    );
+    let filter: EventQuery =
+        serde_json::from_str(r#"{"event_instance_ids": ["survival"]}"#).unwrap();
+
+    let (mut sender, mut receiver) = futures::channel::mpsc::unbounded();
+    let cursor = replay(
+        &mut sender,
+        &pool,
+        &owner,
+        Some(&filter),
+        snowflakes[0],
+        &forward_event,
+    )
+    .await;
+    // the cursor passes the events the filter skipped
+    assert_eq!(cursor, Some(snowflakes[3]));
+    let mut frames = Vec::new();
+    while let Ok(Some(Message::Text(text))) = receiver.try_next() {
+        frames.push(serde_json::from_str::<serde_json::Value>(&text).unwrap());
+    }
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["type"], "event");
+    assert_eq!(frames[0]["snowflake"], snowflakes[2].to_string());
+    assert_eq!(frames[1]["type"], "replayed");
+    assert_eq!(frames[1]["count"], 1);
+    assert_eq!(frames[1]["complete"], true);
+
+    // a client that went away ends the replay
+    drop(receiver);
+    assert_eq!(
+        replay(
+            &mut sender,
+            &pool,
+            &owner,
+            None,
+            snowflakes[0],
+            &forward_event
+        )
+        .await,
+        None
+    );
+}
@@ -0,0 +1,99 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    ban_list::{apply_bans, pardon_ban, BanTarget, GlobalBan, NewGlobalBan},
+    error::{Error, ErrorKind},
+    implementations::minecraft::util::name_to_uuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+pub async fn get_global_bans(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<GlobalBan>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage the global ban list"),
+        });
+    }
+    Ok(Json(state.ban_list.lock().await.list()))
+}
+
+/// Applies to every Minecraft instance: written into the ban files of stopped instances,
+/// online players it applies to are kicked and joining ones are kicked on join
+pub async fn add_global_ban(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(new_ban): Json<NewGlobalBan>,
+) -> Result<Json<GlobalBan>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage the global ban list"),
+        });
+    }
+    new_ban.validate()?;
+    let target = match new_ban.target {
+        // ban files need the uuid
+        BanTarget::Player {
+            name: Some(name),
+            uuid: None,
+        } => BanTarget::Player {
+            uuid: name_to_uuid(&name).await,
+            name: Some(name),
+        },
+        target => target,
+    };
+    let ban = state
+        .ban_list
+        .lock()
+        .await
+        .add_ban(GlobalBan {
+            id: format!("BAN_{}", rand_alphanumeric(12)),
+            target,
+            reason: new_ban.reason,
+            created: chrono::Utc::now().timestamp(),
+            expires: new_ban.expires,
+            created_by: requester.username.clone(),
+        })
+        .await?;
+    let bans = state.ban_list.lock().await.list();
+    apply_bans(&state.instances, &bans).await;
+    Ok(Json(ban))
+}
+
+pub async fn remove_global_ban(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(ban_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage the global ban list"),
+        });
+    }
+    let ban = state.ban_list.lock().await.remove_ban(&ban_id).await?;
+    pardon_ban(&state.instances, &ban).await;
+    let bans = state.ban_list.lock().await.list();
+    apply_bans(&state.instances, &bans).await;
+    Ok(Json(()))
+}
+
+pub fn get_global_bans_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/bans", get(get_global_bans).post(add_global_ban))
+        .route("/bans/:ban_id", delete(remove_global_ban))
+        .with_state(state)
+}
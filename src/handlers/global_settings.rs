@@ -5,13 +5,25 @@ use axum::{
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 
-use crate::{error::ErrorKind, AppState, Error, GlobalSettingsData};
+use crate::backup::pre_change::PreChangeBackupSettings;
+use crate::implementations::minecraft::jvm_presets::{self, JvmPreset};
+use crate::{
+    backup::remote::BackupRemotesSettings, console_policy::ConsoleCommandPolicy,
+    email::EmailSettings, error::ErrorKind, events::discord_webhook::DiscordWebhookConfig,
+    firewall::FirewallSettings, flood_detection::FloodDetectionSettings, geoip::GeoIpSettings,
+    instance_migration::PeerCoresSettings, maintenance::MaintenanceSettings,
+    metrics_history::MetricsHistorySettings, player_sessions::AltDetectionSettings,
+    prometheus::PrometheusSettings, request_metrics::SlowRequestSettings,
+    start_queue::StartQueueSettings, tick_monitor::TickMonitorSettings, votifier::VotifierSettings,
+    vpn_detection::VpnDetectionSettings, ws_sessions::WebsocketSettings, AppState, Error,
+    GlobalSettingsData,
+};
 
 pub async fn get_core_settings(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
 ) -> Result<Json<GlobalSettingsData>, Error> {
-    state
+    let requester = state
         .users_manager
         .read()
         .await
@@ -21,7 +33,25 @@ pub async fn get_core_settings(
             source: eyre!("Token error"),
         })?;
 
-    Ok(Json(state.global_settings.lock().await.as_ref().clone()))
+    let mut settings = state.global_settings.lock().await.as_ref().clone();
+    if !requester.is_owner {
+        settings.prometheus = settings.prometheus.redacted();
+        settings.discord_webhook = settings.discord_webhook.redacted();
+    }
+    settings.peer_cores = settings.peer_cores.redacted();
+    settings.backup_remotes = settings.backup_remotes.redacted();
+    settings.email = settings.email.redacted();
+    Ok(Json(settings))
+}
+
+/// Settings changed since the core started that only take effect after a restart,
+/// the others are applied as soon as they are changed
+pub async fn get_pending_restart(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<String>>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(state.global_settings.lock().await.pending_restart()))
 }
 
 pub async fn change_core_name(
@@ -111,11 +141,492 @@ pub async fn change_domain(
     Ok(())
 }
 
+/// Only affects ports opened from now on, running instances keep their current rules
+pub async fn change_firewall(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(firewall): Json<FirewallSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change firewall settings"),
+        });
+    }
+    firewall.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_firewall(firewall)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_flood_detection(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(flood_detection): Json<FloodDetectionSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change flood detection settings"),
+        });
+    }
+    flood_detection.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_flood_detection(flood_detection)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_geoip(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(geoip): Json<GeoIpSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change GeoIP settings"),
+        });
+    }
+    geoip.validate()?;
+    state.global_settings.lock().await.set_geoip(geoip).await?;
+    Ok(())
+}
+
+pub async fn change_vpn_detection(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(vpn_detection): Json<VpnDetectionSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change VPN detection settings"),
+        });
+    }
+    vpn_detection.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_vpn_detection(vpn_detection)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_alt_detection(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(alt_detection): Json<AltDetectionSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change alt detection settings"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_alt_detection(alt_detection)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_console_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(console_policy): Json<ConsoleCommandPolicy>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the console command policy"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_console_policy(console_policy)
+        .await?;
+    Ok(())
+}
+
+/// The listener picks up a new port within a few seconds
+pub async fn change_votifier(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(votifier): Json<VotifierSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change Votifier settings"),
+        });
+    }
+    votifier.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_votifier(votifier)
+        .await?;
+    Ok(())
+}
+
+/// Returns the remotes with the ids assigned to new ones, without their secrets
+pub async fn change_backup_remotes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(mut backup_remotes): Json<BackupRemotesSettings>,
+) -> Result<Json<BackupRemotesSettings>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change backup remotes"),
+        });
+    }
+    let mut global_settings = state.global_settings.lock().await;
+    backup_remotes.prepare(&global_settings.backup_remotes())?;
+    global_settings
+        .set_backup_remotes(backup_remotes.clone())
+        .await?;
+    Ok(Json(backup_remotes.redacted()))
+}
+
+/// Returns the cores with the ids assigned to new ones, without their tokens
+pub async fn change_peer_cores(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(mut peer_cores): Json<PeerCoresSettings>,
+) -> Result<Json<PeerCoresSettings>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change peer cores"),
+        });
+    }
+    let mut global_settings = state.global_settings.lock().await;
+    peer_cores.prepare(&global_settings.peer_cores())?;
+    global_settings.set_peer_cores(peer_cores.clone()).await?;
+    Ok(Json(peer_cores.redacted()))
+}
+
+/// Admins keep full access while maintenance is on, so they can turn it off again
+pub async fn change_maintenance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(maintenance): Json<MaintenanceSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner && !requester.is_admin {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change maintenance mode"),
+        });
+    }
+    maintenance.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_maintenance(maintenance)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_slow_requests(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(slow_requests): Json<SlowRequestSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the slow request log"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_slow_requests(slow_requests)
+        .await?;
+    Ok(())
+}
+
+/// Applies to websockets opened after the change
+pub async fn change_websocket(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(websocket): Json<WebsocketSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change websocket settings"),
+        });
+    }
+    websocket.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_websocket(websocket)
+        .await?;
+    Ok(())
+}
+
+/// Used the next time the core boots
+pub async fn change_start_queue(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(start_queue): Json<StartQueueSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the start queue"),
+        });
+    }
+    start_queue.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_start_queue(start_queue)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_prometheus(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(prometheus): Json<PrometheusSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the metrics exporter"),
+        });
+    }
+    prometheus.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_prometheus(prometheus)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_metrics_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(metrics_history): Json<MetricsHistorySettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the metrics history"),
+        });
+    }
+    metrics_history.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_metrics_history(metrics_history)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_tick_monitor(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(tick_monitor): Json<TickMonitorSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the tick monitor"),
+        });
+    }
+    tick_monitor.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_tick_monitor(tick_monitor)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_email(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(mut email): Json<EmailSettings>,
+) -> Result<Json<EmailSettings>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change email settings"),
+        });
+    }
+    let mut global_settings = state.global_settings.lock().await;
+    email.prepare(&global_settings.email())?;
+    global_settings.set_email(email.clone()).await?;
+    Ok(Json(email.redacted()))
+}
+
+pub async fn change_discord_webhook(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(discord_webhook): Json<DiscordWebhookConfig>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the Discord webhook"),
+        });
+    }
+    discord_webhook.validate()?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_discord_webhook(discord_webhook)
+        .await?;
+    Ok(())
+}
+
+pub async fn change_pre_change_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(pre_change_backups): Json<PreChangeBackupSettings>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the backups taken before changes"),
+        });
+    }
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_pre_change_backups(pre_change_backups)
+        .await?;
+    Ok(())
+}
+
+/// The built-in presets followed by the custom templates
+pub async fn get_jvm_presets(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<JvmPreset>>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(jvm_presets::presets()))
+}
+
+/// Replaces the custom templates. Instances on a removed template start with
+/// their own arguments only.
+pub async fn change_jvm_presets(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(presets): Json<Vec<JvmPreset>>,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to change the JVM presets"),
+        });
+    }
+    jvm_presets::validate_custom(&presets)?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .set_jvm_presets(presets)
+        .await?;
+    Ok(())
+}
+
 pub fn get_global_settings_routes(state: AppState) -> Router {
     Router::new()
         .route("/global_settings", get(get_core_settings))
+        .route("/global_settings/pending_restart", get(get_pending_restart))
         .route("/global_settings/name", put(change_core_name))
         .route("/global_settings/safe_mode", put(change_core_safe_mode))
         .route("/global_settings/domain", put(change_domain))
+        .route("/global_settings/firewall", put(change_firewall))
+        .route(
+            "/global_settings/flood_detection",
+            put(change_flood_detection),
+        )
+        .route("/global_settings/geoip", put(change_geoip))
+        .route("/global_settings/vpn_detection", put(change_vpn_detection))
+        .route("/global_settings/alt_detection", put(change_alt_detection))
+        .route("/global_settings/votifier", put(change_votifier))
+        .route(
+            "/global_settings/console_policy",
+            put(change_console_policy),
+        )
+        .route(
+            "/global_settings/backup_remotes",
+            put(change_backup_remotes),
+        )
+        .route("/global_settings/peer_cores", put(change_peer_cores))
+        .route("/global_settings/maintenance", put(change_maintenance))
+        .route("/global_settings/slow_requests", put(change_slow_requests))
+        .route("/global_settings/websocket", put(change_websocket))
+        .route("/global_settings/start_queue", put(change_start_queue))
+        .route("/global_settings/prometheus", put(change_prometheus))
+        .route(
+            "/global_settings/metrics_history",
+            put(change_metrics_history),
+        )
+        .route("/global_settings/tick_monitor", put(change_tick_monitor))
+        .route("/global_settings/email", put(change_email))
+        .route(
+            "/global_settings/discord_webhook",
+            put(change_discord_webhook),
+        )
+        .route(
+            "/global_settings/pre_change_backups",
+            put(change_pre_change_backups),
+        )
+        .route(
+            "/global_settings/jvm_presets",
+            get(get_jvm_presets).put(change_jvm_presets),
+        )
         .with_state(state)
 }
@@ -1,29 +1,36 @@
 use axum::routing::{delete, get, post};
 use axum::Router;
-use axum::{extract::Path, Json};
+use axum::{
+    extract::{Path, Query},
+    Json,
+};
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::{eyre, Context};
 use serde::Deserialize;
+use tokio::task::JoinHandle;
 use tracing::error;
 
-use crate::auth::user::UserAction;
+use crate::auth::user::{User, UserAction};
 use crate::error::{Error, ErrorKind};
 use crate::events::{CausedBy, Event, ProgressionEndValue, ProgressionStartValue};
+use crate::external_db::provision::drop_instance_databases;
 
 use crate::implementations::generic;
 use crate::traits::t_configurable::GameType;
 
 
+use crate::implementations::minecraft::preflight::{preflight, PreflightReport};
 use crate::implementations::minecraft::MinecraftInstance;
 use crate::prelude::{path_to_instances, GameInstance};
 use crate::traits::t_configurable::manifest::SetupValue;
 use crate::traits::{t_configurable::TConfigurable, t_server::TServer, InstanceInfo, TInstance};
 
 use crate::types::{DotLodestoneConfig, InstanceUuid};
-use crate::{implementations::minecraft, traits::t_server::State, AppState};
+use crate::{implementations::minecraft, traits::t_server::InstanceOperation, AppState};
 
 use super::instance_setup_configs::HandlerGameType;
+use super::presets::find_preset;
 
 pub async fn get_instance_list(
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -33,9 +40,18 @@ pub async fn get_instance_list(
     let mut list_of_configs: Vec<InstanceInfo> = Vec::new();
 
     let instances = state.instances.lock().await;
+    let start_queue = state.start_queue.lock().await;
+    let tick_monitor = state.tick_monitor.lock().await;
+    let now = chrono::Utc::now().timestamp();
     for instance in instances.values() {
         if requester.can_perform_action(&UserAction::ViewInstance(instance.uuid().await)) {
-            list_of_configs.push(instance.get_instance_info().await);
+            let mut info = instance.get_instance_info().await;
+            info.start_queue_position = start_queue.position(&info.uuid);
+            if let Some(stats) = tick_monitor.latest(&info.uuid, now) {
+                info.tps = stats.tps;
+                info.mspt = stats.mspt;
+            }
+            list_of_configs.push(info);
         }
     }
 
@@ -59,17 +75,152 @@ pub async fn get_instance_info(
     })?;
 
     requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
-    Ok(Json(instance.get_instance_info().await))
+    let mut info = instance.get_instance_info().await;
+    info.start_queue_position = state.start_queue.lock().await.position(&uuid);
+    if let Some(stats) = state
+        .tick_monitor
+        .lock()
+        .await
+        .latest(&uuid, chrono::Utc::now().timestamp())
+    {
+        info.tps = stats.tps;
+        info.mspt = stats.mspt;
+    }
+    Ok(Json(info))
+}
+
+lazy_static::lazy_static! {
+    /// Held from looking up a client id until the instance claiming it is on disk,
+    /// so two requests with the same id can't both create one
+    static ref CREATION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateInstanceQuery {
+    /// stable id picked by the client. Creating an instance with the id of an
+    /// existing one returns that instance instead, so retries are safe.
+    client_id: Option<String>,
+    /// id of a preset from `/presets` the instance starts from
+    preset: Option<String>,
+}
+
+fn validate_client_id(client_id: &str) -> Result<(), Error> {
+    if client_id.is_empty()
+        || client_id.len() > 128
+        || !client_id.chars().all(|c| c.is_ascii_graphic())
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Client id must be 1 to 128 printable ASCII characters without spaces"),
+        });
+    }
+    Ok(())
+}
+
+/// The config of the instance created with `client_id`, found by its directory so
+/// instances that are still being set up count too
+async fn find_by_client_id(client_id: &str) -> Result<Option<DotLodestoneConfig>, Error> {
+    let mut entries = match tokio::fs::read_dir(path_to_instances()).await {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .context("Failed to read instances directory")?
+    {
+        let config = match tokio::fs::read_to_string(entry.path().join(".lodestone_config")).await {
+            Ok(config) => config,
+            Err(_) => continue,
+        };
+        if let Ok(config) = serde_json::from_str::<DotLodestoneConfig>(&config) {
+            if config.client_id() == Some(client_id) {
+                return Ok(Some(config));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The existing instance created with the client id of the query, if any
+async fn existing_instance(
+    query: &CreateInstanceQuery,
+    game_type: GameType,
+) -> Result<Option<InstanceUuid>, Error> {
+    let client_id = match &query.client_id {
+        Some(client_id) => client_id,
+        None => return Ok(None),
+    };
+    validate_client_id(client_id)?;
+    match find_by_client_id(client_id).await? {
+        Some(config) if config.game_type() != &game_type => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Client id {} belongs to an instance of another game type",
+                client_id
+            ),
+        }),
+        Some(config) => Ok(Some(config.uuid().clone())),
+        None => Ok(None),
+    }
 }
 
 pub async fn create_minecraft_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
     Path(game_type): Path<HandlerGameType>,
-    Json(manifest_value): Json<SetupValue>,
+    Query(query): Query<CreateInstanceQuery>,
+    Json(mut manifest_value): Json<SetupValue>,
 ) -> Result<Json<InstanceUuid>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::CreateInstance)?;
+    let preset = match &query.preset {
+        Some(preset_id) => Some(find_preset(preset_id)?),
+        None => None,
+    };
+    if let Some(preset) = preset {
+        preset.apply_to_setup(game_type, &mut manifest_value)?;
+    }
+    let _creation = CREATION_LOCK.lock().await;
+    if let Some(instance_uuid) = existing_instance(&query, game_type.into()).await? {
+        return Ok(Json(instance_uuid));
+    }
+    let (instance_uuid, setup) = setup_minecraft_instance(
+        state.clone(),
+        requester,
+        game_type,
+        manifest_value,
+        query.client_id,
+    )
+    .await?;
+    if let Some(preset) = preset {
+        // mods are picked for the version, so they're downloaded once the instance is set up
+        let uuid = instance_uuid.clone();
+        tokio::spawn(async move {
+            if setup.await.is_err() || !state.instances.lock().await.contains_key(&uuid) {
+                return;
+            }
+            if let Err(e) = preset.install(&state, &uuid).await {
+                error!(
+                    "Failed to apply preset {} to instance {}: {}",
+                    preset.id, uuid, e
+                );
+            }
+        });
+    }
+    Ok(Json(instance_uuid))
+}
+
+/// Creates the directory of a new Minecraft instance and sets it up in the
+/// background, reporting progress through progression events. The returned task
+/// finishes once the instance is set up, or its directory removed if that failed.
+pub(crate) async fn setup_minecraft_instance(
+    state: AppState,
+    requester: User,
+    game_type: HandlerGameType,
+    manifest_value: SetupValue,
+    client_id: Option<String>,
+) -> Result<(InstanceUuid, JoinHandle<()>), Error> {
     let mut perm = requester.permissions;
 
     let mut instance_uuid = InstanceUuid::default();
@@ -98,7 +249,8 @@ pub async fn create_minecraft_instance(
         .await
         .context("Failed to create instance directory")?;
 
-    let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), game_type.into());
+    let dot_lodestone_config =
+        DotLodestoneConfig::new(instance_uuid.clone(), game_type.into()).with_client_id(client_id);
 
     // write dot lodestone config
 
@@ -109,7 +261,7 @@ pub async fn create_minecraft_instance(
     .await
     .context("Failed to write .lodestone_config file")?;
 
-    tokio::task::spawn({
+    let setup = tokio::task::spawn({
         let uuid = instance_uuid.clone();
         let instance_name = setup_config.name.clone();
         let event_broadcaster = state.event_broadcaster.clone();
@@ -186,14 +338,35 @@ pub async fn create_minecraft_instance(
                     error!("Failed to update permissions: {:?}", e);
                     e
                 });
-            state
-                .instances
-                .lock()
-                .await
-                .insert(uuid.clone(), minecraft_instance.into());
+            let mut instances = state.instances.lock().await;
+            instances.insert(uuid.clone(), minecraft_instance.into());
+            if let Err(e) = state.state_store.record_instances(&instances).await {
+                error!("Failed to record the instances: {}", e);
+            }
         }
     });
-    Ok(Json(instance_uuid))
+    Ok((instance_uuid, setup))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PreflightRequest {
+    game_type: HandlerGameType,
+    setup_value: SetupValue,
+}
+
+/// Runs the checks instance creation would trip over, without creating anything
+pub async fn preflight_minecraft_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<PreflightRequest>,
+) -> Result<Json<PreflightReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
+    let flavour = request.game_type.try_into()?;
+    let setup_config =
+        MinecraftInstance::construct_setup_config(request.setup_value, flavour).await?;
+    let port_status = state.port_manager.lock().await.port_status(setup_config.port);
+    Ok(Json(preflight(&setup_config, port_status, &state.system).await))
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -205,10 +378,18 @@ pub struct GenericSetupConfig {
 pub async fn create_generic_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     AuthBearer(token): AuthBearer,
+    Query(query): Query<CreateInstanceQuery>,
     Json(setup_config): Json<GenericSetupConfig>,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::CreateInstance)?;
+    let _creation = CREATION_LOCK.lock().await;
+    if existing_instance(&query, GameType::Generic)
+        .await?
+        .is_some()
+    {
+        return Ok(Json(()));
+    }
     let mut instance_uuid = InstanceUuid::default();
     for uuid in state.instances.lock().await.keys() {
         if let Some(uuid) = uuid.as_ref().get(0..8) {
@@ -230,7 +411,8 @@ pub async fn create_generic_instance(
         .await
         .context("Failed to create instance directory")?;
 
-    let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), GameType::Generic);
+    let dot_lodestone_config = DotLodestoneConfig::new(instance_uuid.clone(), GameType::Generic)
+        .with_client_id(query.client_id);
 
     // write dot lodestone config
 
@@ -251,11 +433,11 @@ pub async fn create_generic_instance(
     )
     .await?;
 
-    state
-        .instances
-        .lock()
-        .await
-        .insert(instance_uuid.clone(), instance.into());
+    let mut instances = state.instances.lock().await;
+    instances.insert(instance_uuid.clone(), instance.into());
+    if let Err(e) = state.state_store.record_instances(&instances).await {
+        error!("Failed to record the instances: {}", e);
+    }
     Ok(Json(()))
 }
 
@@ -272,12 +454,9 @@ pub async fn delete_instance(
         user_name: requester.username.clone(),
     };
     if let Some(instance) = instances.remove(&uuid) {
-        if !(instance.state().await == State::Stopped) {
+        if let Err(e) = instance.state().await.check(InstanceOperation::Delete) {
             instances.insert(uuid.clone(), instance);
-            Err(Error {
-                kind: ErrorKind::BadRequest,
-                source: eyre!("Instance must be stopped before deletion"),
-            })
+            Err(e)
         } else {
             let (progression_event_start, event_id) = Event::new_progression_event_start(
                 format!("Deleting instance {}", instance.name().await),
@@ -308,10 +487,29 @@ pub async fn delete_instance(
                 .await
                 .deallocate(instance.port().await);
             let instance_path = instance.path().await;
+            if let Err(e) = drop_instance_databases(
+                &*state.database_hosts.lock().await,
+                &state.state_store,
+                &uuid,
+                &instance_path,
+            )
+            .await
+            {
+                error!("Failed to drop databases of instance {}: {}", uuid, e);
+            }
+            if let Err(e) = state.firewall.lock().await.close_instance_port(&uuid).await {
+                error!("Failed to close firewall port of instance {}: {}", uuid, e);
+            }
             // if instance is generic
             if let GameInstance::GenericInstance(i) = instance {
                 i.destruct().await;
             };
+            if let Err(e) = state.state_store.record_instances(&instances).await {
+                error!("Failed to record the instances: {}", e);
+            }
+            if let Err(e) = state.state_store.remove_instance_documents(&uuid).await {
+                error!("Failed to remove the state of instance {}: {}", uuid, e);
+            }
             drop(instances);
             let res = crate::util::fs::remove_dir_all(instance_path).await;
             match &res {
@@ -352,6 +550,10 @@ pub fn get_instance_routes(state: AppState) -> Router {
             post(create_minecraft_instance),
         )
         .route("/instance/create_generic", post(create_generic_instance))
+        .route(
+            "/instance/minecraft/preflight",
+            post(preflight_minecraft_instance),
+        )
         .route("/instance/:uuid", delete(delete_instance))
         .route("/instance/:uuid/info", get(get_instance_info))
         .with_state(state)
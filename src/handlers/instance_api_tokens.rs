@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+
+use axum::{
+    extract::Path,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use tracing::warn;
+
+use crate::{
+    auth::user::UserAction,
+    companion::{CompanionHello, CompanionReport},
+    console_policy::{validate_command, CommandRules},
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    instance_tokens::{
+        lock_tokens, CreatedInstanceApiToken, InstanceApiToken, InstanceApiTokenInfo,
+        InstanceApiTokens, NewInstanceApiToken, TokenScope,
+    },
+    prelude::GameInstance,
+    tick_monitor::{record_tick_sample, TickSample},
+    traits::{
+        t_configurable::TConfigurable, t_player::Player, t_player::TPlayerManagement,
+        t_server::State, t_server::TServer, Capability, TInstance,
+    },
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn get_instance(state: &AppState, uuid: &InstanceUuid) -> Result<GameInstance, Error> {
+    state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })
+}
+
+/// The instance and the token, if `token` is one of its tokens with `scope`
+async fn authenticate(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    token: &str,
+    scope: TokenScope,
+) -> Result<(GameInstance, InstanceApiToken), Error> {
+    let instance = get_instance(state, uuid).await?;
+    let path = instance.path().await;
+    let _guard = lock_tokens(uuid).await;
+    let mut tokens = InstanceApiTokens::load(&state.state_store, uuid, &path).await?;
+    let (api_token, used) = tokens.authenticate(token, scope, chrono::Utc::now().timestamp())?;
+    if used {
+        if let Err(e) = tokens.save(&state.state_store, uuid).await {
+            warn!("Failed to save API token use of instance {}: {}", uuid, e);
+        }
+    }
+    Ok((instance, api_token))
+}
+
+pub async fn list_api_tokens(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<InstanceApiTokenInfo>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    Ok(Json(
+        InstanceApiTokens::load(&state.state_store, &uuid, &path)
+            .await?
+            .tokens
+            .iter()
+            .map(InstanceApiTokenInfo::from)
+            .collect(),
+    ))
+}
+
+/// The token is only ever returned here. A token that sends commands can't send
+/// any the requester couldn't send themselves.
+pub async fn create_api_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(new_token): Json<NewInstanceApiToken>,
+) -> Result<Json<CreatedInstanceApiToken>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    new_token.validate()?;
+    if new_token.scopes.contains(&TokenScope::SendCommands) {
+        requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+        let console_policy = state.global_settings.lock().await.console_policy();
+        for command in &new_token.commands {
+            console_policy.check(&requester, command)?;
+        }
+    }
+    let path = get_instance(&state, &uuid).await?.path().await;
+    let _guard = lock_tokens(&uuid).await;
+    let mut tokens = InstanceApiTokens::load(&state.state_store, &uuid, &path).await?;
+    let created = tokens.create(new_token, chrono::Utc::now().timestamp())?;
+    tokens.save(&state.state_store, &uuid).await?;
+    Ok(Json(created))
+}
+
+pub async fn revoke_api_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, token_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    let _guard = lock_tokens(&uuid).await;
+    let mut tokens = InstanceApiTokens::load(&state.state_store, &uuid, &path).await?;
+    tokens.revoke(&token_id)?;
+    tokens.save(&state.state_store, &uuid).await?;
+    Ok(Json(()))
+}
+
+/// For companion plugins and mods, authenticated with an instance API token
+pub async fn get_integration_state(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<State>, Error> {
+    let (instance, _) = authenticate(&state, &uuid, &token, TokenScope::ReadState).await?;
+    Ok(Json(instance.state().await))
+}
+
+pub async fn get_integration_players(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<HashSet<Player>>, Error> {
+    let (instance, _) = authenticate(&state, &uuid, &token, TokenScope::ReadPlayers).await?;
+    instance.require_capability(Capability::SupportsPlayerList)?;
+    instance.get_player_list().await.map(Json)
+}
+
+pub async fn send_integration_command(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(command): Json<String>,
+) -> Result<Json<()>, Error> {
+    let (instance, api_token) =
+        authenticate(&state, &uuid, &token, TokenScope::SendCommands).await?;
+    let command = validate_command(&command)?;
+    CommandRules {
+        allowed: api_token.commands,
+        denied: Vec::new(),
+    }
+    .check(command)?;
+    instance
+        .send_command(
+            command,
+            CausedBy::ApiToken {
+                token_id: api_token.id,
+                token_name: api_token.name,
+            },
+        )
+        .await?;
+    Ok(Json(()))
+}
+
+/// The handshake of the companion protocol, done before the first report
+pub async fn get_companion_hello(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<CompanionHello>, Error> {
+    authenticate(&state, &uuid, &token, TokenScope::PushTelemetry).await?;
+    Ok(Json(CompanionHello::default()))
+}
+
+pub async fn push_companion_report(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(report): Json<CompanionReport>,
+) -> Result<Json<()>, Error> {
+    authenticate(&state, &uuid, &token, TokenScope::PushTelemetry).await?;
+    report.validate()?;
+    let sample = TickSample {
+        tps: report.tps,
+        mspt: report.mspt,
+    };
+    state.companion_reports.lock().await.record(
+        uuid.clone(),
+        report,
+        chrono::Utc::now().timestamp(),
+    );
+    if sample.tps.is_some() || sample.mspt.is_some() {
+        record_tick_sample(&state, &uuid, sample).await;
+    }
+    Ok(Json(()))
+}
+
+pub fn get_instance_api_tokens_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/api_tokens",
+            get(list_api_tokens).post(create_api_token),
+        )
+        .route(
+            "/instance/:uuid/api_tokens/:token_id",
+            delete(revoke_api_token),
+        )
+        .route(
+            "/instance/:uuid/integration/state",
+            get(get_integration_state),
+        )
+        .route(
+            "/instance/:uuid/integration/players",
+            get(get_integration_players),
+        )
+        .route(
+            "/instance/:uuid/integration/console",
+            post(send_integration_command),
+        )
+        .route(
+            "/instance/:uuid/integration/companion",
+            get(get_companion_hello).post(push_companion_report),
+        )
+        .with_state(state)
+}
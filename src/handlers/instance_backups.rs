@@ -0,0 +1,346 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{get, post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use tracing::Instrument;
+
+use crate::{
+    auth::user::UserAction,
+    backup::{
+        archive::RestoreConflict,
+        diff_backups, export_snapshot_backup, list_backup_files,
+        pre_change::{last_change, rollback_last_change, ChangeRecord, RollbackReport},
+        restore_backup_files, BackupDiff, BackupEntry, BackupFileEntry, BackupGuard, BackupIndex,
+        BackupMetadata, BackupPolicy, FileRestoreReport,
+    },
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    prelude::GameInstance,
+    traits::{t_backup::TBackup, t_configurable::TConfigurable, Capability, TInstance},
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn get_instance(state: &AppState, uuid: &InstanceUuid) -> Result<GameInstance, Error> {
+    let instance = state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    instance.require_capability(Capability::SupportsBackups)?;
+    Ok(instance)
+}
+
+/// Newest first
+pub async fn list_backups(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<BackupEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    Ok(Json(
+        get_instance(&state, &uuid).await?.list_backups().await?,
+    ))
+}
+
+/// Runs in the background, the new backup is announced by the end of its
+/// progression event
+pub async fn new_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut metadata): Json<BackupMetadata>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    metadata.validate()?;
+    let instance = get_instance(&state, &uuid).await?;
+    let guard = BackupGuard::acquire(&uuid)?;
+    let remotes = state.global_settings.lock().await.backup_remotes();
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    tokio::task::spawn(
+        async move {
+            let _ = instance
+                .create_backup(
+                    guard,
+                    metadata,
+                    Some(requester.username.clone()),
+                    &remotes,
+                    caused_by,
+                )
+                .await;
+        }
+        .in_current_span(),
+    );
+    Ok(Json(()))
+}
+
+pub async fn update_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(mut metadata): Json<BackupMetadata>,
+) -> Result<Json<BackupEntry>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    metadata.validate()?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    let mut index = BackupIndex::load(&path).await?;
+    let entry = index.get_mut(&backup_id)?;
+    entry.label = metadata.label;
+    entry.pinned = metadata.pinned;
+    let entry = entry.clone();
+    index.save(&path).await?;
+    Ok(Json(entry))
+}
+
+pub async fn remove_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = get_instance(&state, &uuid).await?;
+    let guard = BackupGuard::acquire(&uuid)?;
+    instance.delete_backup(guard, &backup_id).await?;
+    Ok(Json(()))
+}
+
+/// Returns the backup being restored, so clients can confirm it by its label.
+/// The restore itself runs in the background, stopping a running instance for
+/// the duration, with its steps reported through a progression event.
+pub async fn restore_from_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BackupEntry>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = get_instance(&state, &uuid).await?;
+    let entry = BackupIndex::load(&instance.path().await)
+        .await?
+        .get(&backup_id)?
+        .clone();
+    let guard = BackupGuard::acquire(&uuid)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    tokio::task::spawn(
+        async move {
+            let _ = instance.restore_backup(guard, &backup_id, caused_by).await;
+        }
+        .in_current_span(),
+    );
+    Ok(Json(entry))
+}
+
+/// Runs in the background, the stream's file name is given by the end of its
+/// progression event
+pub async fn export_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = get_instance(&state, &uuid).await?;
+    let guard = BackupGuard::acquire(&uuid)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    tokio::task::spawn(
+        async move {
+            let _ = export_snapshot_backup(
+                &instance,
+                guard,
+                &backup_id,
+                &state.event_broadcaster,
+                caused_by,
+            )
+            .await;
+        }
+        .in_current_span(),
+    );
+    Ok(Json(()))
+}
+
+pub async fn get_backup_diff(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_a, backup_b)): Path<(InstanceUuid, String, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BackupDiff>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    Ok(Json(diff_backups(&path, &backup_a, &backup_b).await?))
+}
+
+#[derive(Deserialize)]
+pub struct BackupFilesQuery {
+    /// directory to list, the instance root by default
+    #[serde(default)]
+    path: String,
+}
+
+pub async fn get_backup_files(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<BackupFilesQuery>,
+) -> Result<Json<Vec<BackupFileEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    Ok(Json(
+        list_backup_files(&path, &backup_id, &query.path).await?,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct RestoreFilesRequest {
+    /// files and directories relative to the instance root
+    paths: Vec<String>,
+    conflict: RestoreConflict,
+}
+
+pub async fn restore_files_from_backup(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backup_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<RestoreFilesRequest>,
+) -> Result<Json<FileRestoreReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    let guard = BackupGuard::acquire(&uuid)?;
+    Ok(Json(
+        restore_backup_files(&path, guard, &backup_id, &request.paths, request.conflict).await?,
+    ))
+}
+
+pub async fn get_backup_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<BackupPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    Ok(Json(BackupPolicy::load(&path).await?.redacted()))
+}
+
+/// Applies from the next backup on
+pub async fn set_backup_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut policy): Json<BackupPolicy>,
+) -> Result<Json<BackupPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    // only the core sets the key id, from the passphrase sent or the stored one
+    policy.encryption.key_id = if policy.encryption.passphrase.is_empty() {
+        BackupPolicy::load(&path).await?.encryption.key_id
+    } else {
+        None
+    };
+    policy.validate()?;
+    if let Some(remote) = &policy.remote {
+        state
+            .global_settings
+            .lock()
+            .await
+            .backup_remotes()
+            .get(remote)?;
+    }
+    policy.save(&path).await?;
+    Ok(Json(policy.redacted()))
+}
+
+/// The mod update or version change a rollback would undo
+pub async fn get_rollback(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<ChangeRecord>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let path = get_instance(&state, &uuid).await?.path().await;
+    Ok(Json(last_change(&path).await?))
+}
+
+/// Undoes the last mod update or version change of a stopped instance with the
+/// backup taken before it
+pub async fn rollback(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<RollbackReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    match get_instance(&state, &uuid).await? {
+        GameInstance::MinecraftInstance(instance) => {
+            Ok(Json(rollback_last_change(&instance).await?))
+        }
+        _ => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Rollbacks are only supported for Minecraft instances"),
+        }),
+    }
+}
+
+pub fn get_instance_backups_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/backups",
+            get(list_backups).post(new_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:backup_id",
+            put(update_backup).delete(remove_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:backup_id/restore",
+            post(restore_from_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:backup_id/export",
+            post(export_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:backup_id/files",
+            get(get_backup_files),
+        )
+        .route(
+            "/instance/:uuid/backups/:backup_id/files/restore",
+            post(restore_files_from_backup),
+        )
+        .route(
+            "/instance/:uuid/backups/:backup_id/diff/:other_backup_id",
+            get(get_backup_diff),
+        )
+        .route("/instance/:uuid/rollback", get(get_rollback).post(rollback))
+        .route(
+            "/instance/:uuid/backup_policy",
+            get(get_backup_policy).put(set_backup_policy),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,118 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    changelog::{Changelog, ChangelogEntry, NewChangelogEntry},
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::{InstanceUuid, Snowflake},
+    AppState,
+};
+
+pub async fn get_instance_changelog(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ChangelogEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(Changelog::load(&path).await?.entries().to_vec()))
+}
+
+pub async fn post_instance_changelog_entry(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(new_entry): Json<NewChangelogEntry>,
+) -> Result<Json<ChangelogEntry>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    new_entry.validate()?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    let entry = ChangelogEntry {
+        id: Snowflake::new(),
+        title: new_entry.title,
+        body: new_entry.body,
+        time: new_entry
+            .time
+            .unwrap_or_else(|| chrono::Utc::now().timestamp()),
+        author_id: requester.uid,
+        author_name: requester.username,
+        broadcast_on_start: new_entry.broadcast_on_start,
+        broadcasted: false,
+    };
+    Changelog::load(&path)
+        .await?
+        .add_entry(entry.clone())
+        .await?;
+    Ok(Json(entry))
+}
+
+pub async fn delete_instance_changelog_entry(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, entry_id)): Path<(InstanceUuid, Snowflake)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Changelog::load(&path)
+        .await?
+        .remove_entry(&entry_id)
+        .await?
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Changelog entry not found"),
+        })?;
+    Ok(Json(()))
+}
+
+pub fn get_instance_changelog_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/changelog",
+            get(get_instance_changelog).post(post_instance_changelog_entry),
+        )
+        .route(
+            "/instance/:uuid/changelog/:entry_id",
+            delete(delete_instance_changelog_entry),
+        )
+        .with_state(state)
+}
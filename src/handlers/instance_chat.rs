@@ -0,0 +1,245 @@
+use axum::{
+    extract::{Path, Query},
+    http::header,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    chat_archive::{search_chat, ChatArchiveConfig, ChatMessage, ChatSearchQuery},
+    chat_filter::{ChatRule, ChatRules},
+    discord_bridge::DiscordBridgeConfig,
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+pub async fn search_chat_archive(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<ChatSearchQuery>,
+) -> Result<Json<Vec<ChatMessage>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(search_chat(&path, &query).await?))
+}
+
+/// Same filters as the search, as a plain text chat log
+pub async fn export_chat_archive(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<ChatSearchQuery>,
+) -> Result<([(header::HeaderName, String); 2], String), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut log = String::new();
+    for message in search_chat(&path, &query).await? {
+        let time = chrono::NaiveDateTime::from_timestamp_opt(message.timestamp, 0)
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        log.push_str(&format!(
+            "[{} UTC] <{}> {}\n",
+            time, message.player, message.message
+        ));
+    }
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; charset=utf-8".to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"chat_{}.log\"", uuid.no_prefix()),
+            ),
+        ],
+        log,
+    ))
+}
+
+pub async fn get_chat_archive_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ChatArchiveConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(ChatArchiveConfig::load(&path).await?))
+}
+
+pub async fn set_chat_archive_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<ChatArchiveConfig>,
+) -> Result<Json<ChatArchiveConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+pub async fn get_chat_rules(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ChatRule>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(ChatRules::load(&path).await?.rules))
+}
+
+pub async fn create_chat_rule(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut rule): Json<ChatRule>,
+) -> Result<Json<ChatRule>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    rule.validate()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut rules = ChatRules::load(&path).await?;
+    rule.id = rand_alphanumeric(12);
+    rule.hits = 0;
+    rule.last_hit = None;
+    rules.rules.push(rule.clone());
+    rules.save(&path).await?;
+    Ok(Json(rule))
+}
+
+/// Hit statistics are kept, whatever the request says
+pub async fn update_chat_rule(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, rule_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(mut rule): Json<ChatRule>,
+) -> Result<Json<ChatRule>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    rule.validate()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut rules = ChatRules::load(&path).await?;
+    let existing = rules
+        .rules
+        .iter_mut()
+        .find(|r| r.id == rule_id)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Chat rule not found"),
+        })?;
+    rule.id = rule_id;
+    rule.hits = existing.hits;
+    rule.last_hit = existing.last_hit;
+    *existing = rule.clone();
+    rules.save(&path).await?;
+    Ok(Json(rule))
+}
+
+pub async fn delete_chat_rule(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, rule_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut rules = ChatRules::load(&path).await?;
+    let len = rules.rules.len();
+    rules.rules.retain(|r| r.id != rule_id);
+    if rules.rules.len() == len {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Chat rule not found"),
+        });
+    }
+    rules.save(&path).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_discord_bridge_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DiscordBridgeConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(
+        DiscordBridgeConfig::load(&state.state_store, &uuid, &path)
+            .await?
+            .redacted(),
+    ))
+}
+
+/// An empty bot token keeps the stored one
+pub async fn set_discord_bridge_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut config): Json<DiscordBridgeConfig>,
+) -> Result<Json<DiscordBridgeConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    if config.bot_token.is_empty() {
+        config.bot_token = DiscordBridgeConfig::load(&state.state_store, &uuid, &path)
+            .await?
+            .bot_token;
+    }
+    config.validate()?;
+    config.save(&state.state_store, &uuid).await?;
+    Ok(Json(config.redacted()))
+}
+
+pub fn get_instance_chat_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/chat", get(search_chat_archive))
+        .route("/instance/:uuid/chat/export", get(export_chat_archive))
+        .route(
+            "/instance/:uuid/chat/archive",
+            get(get_chat_archive_config).put(set_chat_archive_config),
+        )
+        .route(
+            "/instance/:uuid/chat/rules",
+            get(get_chat_rules).post(create_chat_rule),
+        )
+        .route(
+            "/instance/:uuid/chat/rules/:rule_id",
+            put(update_chat_rule).delete(delete_chat_rule),
+        )
+        .route(
+            "/instance/:uuid/chat/discord",
+            get(get_discord_bridge_config).put(set_discord_bridge_config),
+        )
+        .with_state(state)
+}
@@ -5,13 +5,27 @@ use axum::{
 };
 use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
+use serde::Deserialize;
 
 use crate::{
     auth::user::UserAction,
+    backup::{
+        pre_change::{ChangeDiff, PendingChange, RiskyChange},
+        retention::BackupRetention,
+        BackupPolicy,
+    },
+    cgroup::ResourceLimits,
     error::{Error, ErrorKind},
-    traits::t_configurable::{
-        manifest::{ConfigurableManifest, ConfigurableValue},
-        TConfigurable,
+    events::{discord_webhook::DiscordWebhookConfig, CausedBy},
+    implementations::minecraft::{java::InstanceJava, MinecraftInstance},
+    prelude::GameInstance,
+    start_queue::AutoStartPriority,
+    traits::{
+        t_configurable::{
+            manifest::{ConfigurableManifest, ConfigurableValue},
+            TConfigurable,
+        },
+        Capability, TInstance,
     },
     types::InstanceUuid,
     AppState,
@@ -116,6 +130,114 @@ pub async fn change_version(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path((uuid, new_version)): Path<(InstanceUuid, String)>,
     AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let mut instance = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    let old_version = instance.version().await;
+    if old_version == new_version {
+        return Ok(Json(()));
+    }
+    let change = PendingChange::begin(
+        &state,
+        &instance,
+        RiskyChange::VersionChange,
+        CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        },
+    )
+    .await?;
+    let res = instance.change_version(new_version.clone()).await;
+    change
+        .finish(
+            &res,
+            Some(ChangeDiff::Version {
+                from: old_version,
+                to: new_version,
+            }),
+        )
+        .await;
+    res?;
+    Ok(Json(()))
+}
+
+/// Path of an instance that keeps backups
+async fn backed_up_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    let instances = state.instances.lock().await;
+    let instance = instances.get(uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    instance.require_capability(Capability::SupportsBackups)?;
+    Ok(instance.path().await)
+}
+
+pub async fn get_backup_retention(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<BackupRetention>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = backed_up_instance_path(&state, &uuid).await?;
+    Ok(Json(BackupPolicy::load(&path).await?.retention))
+}
+
+/// `None` goes back to keeping the newest `keep` backups. Backups the new
+/// retention doesn't keep are pruned by the backup scheduler within a minute.
+pub async fn set_backup_retention(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(retention): Json<Option<BackupRetention>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = backed_up_instance_path(&state, &uuid).await?;
+    let mut policy = BackupPolicy::load(&path).await?;
+    policy.retention = retention;
+    policy.validate()?;
+    policy.save(&path).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_resource_limits(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ResourceLimits>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instances = state.instances.lock().await;
+    let instance = instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    Ok(Json(ResourceLimits {
+        cpu_limit: instance.cpu_limit().await,
+        memory_limit: instance.memory_limit().await,
+    }))
+}
+
+/// Limits are enforced from the next start, and only on Linux hosts with cgroups v2
+pub async fn set_resource_limits(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(limits): Json<ResourceLimits>,
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
@@ -128,11 +250,216 @@ pub async fn change_version(
             kind: ErrorKind::NotFound,
             source: eyre!("Instance not found"),
         })?
-        .change_version(new_version)
+        .set_resource_limits(limits)
         .await?;
     Ok(Json(()))
 }
 
+pub async fn get_jvm_preset(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<String>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instances = state.instances.lock().await;
+    let instance = instances.get(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    Ok(Json(instance.jvm_preset().await))
+}
+
+/// Takes effect from the next start, `null` drops the preset
+pub async fn set_jvm_preset(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(jvm_preset): Json<Option<String>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    state
+        .instances
+        .lock()
+        .await
+        .get_mut(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .set_jvm_preset(jvm_preset)
+        .await?;
+    Ok(Json(()))
+}
+
+pub async fn get_auto_start_priority(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<AutoStartPriority>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(AutoStartPriority::load(&path).await?))
+}
+
+/// Takes effect the next time the core starts and queues instances to auto start
+pub async fn set_auto_start_priority(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(priority): Json<AutoStartPriority>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    priority.validate()?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    priority.save(&path).await?;
+    Ok(Json(()))
+}
+
+async fn instance_path(state: &AppState, uuid: &InstanceUuid) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+pub async fn get_discord_webhook_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<DiscordWebhookConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = instance_path(&state, &uuid).await?;
+    Ok(Json(
+        DiscordWebhookConfig::load(&state.state_store, &uuid, &path)
+            .await?
+            .redacted(),
+    ))
+}
+
+/// While enabled, the instance's events are posted to this webhook instead of
+/// the global one. An empty webhook url keeps the stored one.
+pub async fn set_discord_webhook_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut config): Json<DiscordWebhookConfig>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = instance_path(&state, &uuid).await?;
+    if config.webhook_url.is_empty() {
+        config.webhook_url = DiscordWebhookConfig::load(&state.state_store, &uuid, &path)
+            .await?
+            .webhook_url;
+    }
+    config.validate()?;
+    config.save(&state.state_store, &uuid).await?;
+    Ok(Json(()))
+}
+
+async fn get_minecraft_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<MinecraftInstance, Error> {
+    match state.instances.lock().await.get(uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => Ok(instance.clone()),
+        Some(_) => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Only Minecraft instances have this setting"),
+        }),
+        None => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        }),
+    }
+}
+
+pub async fn get_instance_java(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<InstanceJava>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.java_info().await))
+}
+
+#[derive(Deserialize)]
+pub struct JavaPin {
+    /// the `java` executable, `None` to follow the instance's version again
+    java: Option<String>,
+}
+
+pub async fn set_instance_java(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(pin): Json<JavaPin>,
+) -> Result<Json<InstanceJava>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.pin_java(pin.java).await?))
+}
+
+pub async fn get_instance_eula(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<bool>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.eula_accepted().await))
+}
+
+/// `true` accepts the Minecraft EULA for the instance, which it needs to start
+pub async fn set_instance_eula(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(accepted): Json<bool>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    instance.set_eula_accepted(accepted).await?;
+    Ok(Json(()))
+}
+
 pub fn get_instance_config_routes(state: AppState) -> Router {
     Router::new()
         .route(
@@ -140,6 +467,14 @@ pub fn get_instance_config_routes(state: AppState) -> Router {
             get(get_instance_configurable_manifest),
         )
         .route("/instance/:uuid/version/:new_version", put(change_version))
+        .route(
+            "/instance/:uuid/java",
+            get(get_instance_java).put(set_instance_java),
+        )
+        .route(
+            "/instance/:uuid/eula",
+            get(get_instance_eula).put(set_instance_eula),
+        )
         .route("/instance/:uuid/settings", get(get_instance_settings))
         .route(
             "/instance/:uuid/settings/:section_id/:setting_id",
@@ -147,5 +482,25 @@ pub fn get_instance_config_routes(state: AppState) -> Router {
         )
         .route("/instance/:uuid/name", put(set_instance_name))
         .route("/instance/:uuid/description", put(set_instance_description))
+        .route(
+            "/instance/:uuid/backup_retention",
+            get(get_backup_retention).put(set_backup_retention),
+        )
+        .route(
+            "/instance/:uuid/resource_limits",
+            get(get_resource_limits).put(set_resource_limits),
+        )
+        .route(
+            "/instance/:uuid/jvm_preset",
+            get(get_jvm_preset).put(set_jvm_preset),
+        )
+        .route(
+            "/instance/:uuid/auto_start_priority",
+            get(get_auto_start_priority).put(set_auto_start_priority),
+        )
+        .route(
+            "/instance/:uuid/discord_webhook",
+            get(get_discord_webhook_config).put(set_discord_webhook_config),
+        )
         .with_state(state)
 }
@@ -0,0 +1,196 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+
+use crate::{
+    auth::user::UserAction,
+    console_history::{ConsoleHistory, FavoriteCommand, HistoryEntry},
+    console_log::{search_console_log, ConsoleLogQuery},
+    error::{Error, ErrorKind},
+    implementations::minecraft::{commands::CommandInfo, MinecraftInstance},
+    prelude::GameInstance,
+    traits::{t_configurable::TConfigurable, Capability},
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+async fn get_minecraft_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<MinecraftInstance, Error> {
+    match state.instances.lock().await.get(uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => Ok(instance.clone()),
+        Some(_) => Err(Error::not_supported(Capability::SupportsCommandMetadata)),
+        None => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+/// The requester's own history, oldest first
+pub async fn get_console_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<HistoryEntry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(
+        ConsoleHistory::load(&path)
+            .await?
+            .history(&requester.uid, query.limit),
+    ))
+}
+
+/// Past output of the instance from its console log, oldest first. Unlike
+/// `/console/buffer` this covers output from before the last restart of the core.
+pub async fn get_console_output(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<ConsoleLogQuery>,
+) -> Result<Json<Vec<String>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(search_console_log(&path, &query).await?))
+}
+
+pub async fn clear_console_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut console_history = ConsoleHistory::load(&path).await?;
+    console_history.clear_history(&requester.uid);
+    console_history.save(&path).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_console_favorites(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<FavoriteCommand>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(
+        ConsoleHistory::load(&path).await?.favorites(&requester.uid),
+    ))
+}
+
+pub async fn add_console_favorite(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut favorite): Json<FavoriteCommand>,
+) -> Result<Json<FavoriteCommand>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    favorite.validate()?;
+    favorite.id = rand_alphanumeric(12);
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut console_history = ConsoleHistory::load(&path).await?;
+    console_history.add_favorite(&requester.uid, favorite.clone())?;
+    console_history.save(&path).await?;
+    Ok(Json(favorite))
+}
+
+pub async fn remove_console_favorite(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, favorite_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut console_history = ConsoleHistory::load(&path).await?;
+    console_history.remove_favorite(&requester.uid, &favorite_id)?;
+    console_history.save(&path).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_console_commands(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CommandInfo>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.command_list().await?))
+}
+
+/// Downloads the vanilla server and runs its data generator, this can take a minute
+pub async fn generate_command_report(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    instance.generate_command_report().await?;
+    Ok(Json(()))
+}
+
+pub fn get_instance_console_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/console/history",
+            get(get_console_history).delete(clear_console_history),
+        )
+        .route("/instance/:uuid/console/output", get(get_console_output))
+        .route(
+            "/instance/:uuid/console/favorites",
+            get(get_console_favorites).post(add_console_favorite),
+        )
+        .route(
+            "/instance/:uuid/console/favorites/:favorite_id",
+            delete(remove_console_favorite),
+        )
+        .route(
+            "/instance/:uuid/console/commands",
+            get(get_console_commands),
+        )
+        .route(
+            "/instance/:uuid/console/commands/report",
+            post(generate_command_report),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,60 @@
+use axum::{extract::Path, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    crash_reports::{get_crash, list_crashes, CrashDetails, CrashRecord},
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+/// Crashes captured for the instance, newest first
+pub async fn get_crashes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<CrashRecord>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(list_crashes(&path).await?))
+}
+
+/// A crash with its crash report and the end of the log
+pub async fn get_crash_details(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, crash_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<CrashDetails>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(get_crash(&path, &crash_id).await?))
+}
+
+pub fn get_instance_crashes_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/crashes", get(get_crashes))
+        .route("/instance/:uuid/crashes/:crash_id", get(get_crash_details))
+        .with_state(state)
+}
@@ -0,0 +1,272 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    external_db::{dump::dump_instance_databases, DatabaseConnection, InstanceDatabases},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+pub async fn get_instance_databases(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<InstanceDatabases>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(
+        InstanceDatabases::load(&state.state_store, &uuid, &path)
+            .await?
+            .redacted(),
+    ))
+}
+
+pub async fn set_instance_databases(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut new_config): Json<InstanceDatabases>,
+) -> Result<Json<InstanceDatabases>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    for (i, connection) in new_config.connections.iter().enumerate() {
+        if connection.name.is_empty()
+            || !connection
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Database name {} must be non-empty and only contain letters, digits and underscores",
+                    connection.name
+                ),
+            });
+        }
+        if new_config.connections[..i]
+            .iter()
+            .any(|c| c.name == connection.name)
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Duplicate database name {}", connection.name),
+            });
+        }
+    }
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    let old_config = InstanceDatabases::load(&state.state_store, &uuid, &path).await?;
+    for connection in new_config.connections.iter_mut() {
+        let old = old_config
+            .connections
+            .iter()
+            .find(|c| c.name == connection.name);
+        match old {
+            // provisioned databases are managed by the core and can't be edited
+            Some(old) if old.host_id.is_some() => *connection = old.clone(),
+            // the stored password is only kept for the server it was given for,
+            // it would otherwise be sent to whatever host the connection now names
+            Some(old) if connection.password.is_empty() && old.same_server(connection) => {
+                connection.password = old.password.clone();
+                connection.host_id = None;
+            }
+            _ => connection.host_id = None,
+        }
+        connection.validate()?;
+    }
+    // provisioned databases can only be removed through their own endpoint
+    for old in old_config.connections.iter() {
+        if old.host_id.is_some() && !new_config.connections.iter().any(|c| c.name == old.name) {
+            new_config.connections.push(old.clone());
+        }
+    }
+    new_config.last_dump = old_config.last_dump;
+    new_config.save(&state.state_store, &uuid).await?;
+    Ok(Json(new_config.redacted()))
+}
+
+pub async fn dump_databases(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<String>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    let dumps = dump_instance_databases(&state.state_store, &uuid, &path).await?;
+    Ok(Json(
+        dumps
+            .iter()
+            .map(|p| {
+                p.strip_prefix(&path)
+                    .unwrap_or(p)
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct ProvisionDatabase {
+    host_id: String,
+    name: String,
+}
+
+/// Creates a database on one of the core's database hosts and adds it to the instance.
+/// This is the only response that includes the generated password.
+pub async fn provision_database(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(body): Json<ProvisionDatabase>,
+) -> Result<Json<DatabaseConnection>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    if body.name.is_empty()
+        || body.name.len() > 20
+        || !body
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Database name must be 1 to 20 letters, digits or underscores"),
+        });
+    }
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    let mut config = InstanceDatabases::load(&state.state_store, &uuid, &path).await?;
+    if config.connections.iter().any(|c| c.name == body.name) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance already has a database named {}", body.name),
+        });
+    }
+    let host = state
+        .database_hosts
+        .lock()
+        .await
+        .get(&body.host_id)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Database host not found"),
+        })?;
+    let connection = host.create_database(&uuid, &body.name).await?;
+    config.connections.push(connection.clone());
+    if let Err(e) = config.save(&state.state_store, &uuid).await {
+        let _ = host.drop_database(&uuid, &connection).await;
+        return Err(e);
+    }
+    Ok(Json(connection))
+}
+
+pub async fn delete_database(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, name)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    let mut config = InstanceDatabases::load(&state.state_store, &uuid, &path).await?;
+    let idx = config
+        .connections
+        .iter()
+        .position(|c| c.name == name)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Database not found"),
+        })?;
+    if let Some(host_id) = &config.connections[idx].host_id {
+        let host = state.database_hosts.lock().await.get(host_id).cloned();
+        match host {
+            Some(host) => host.drop_database(&uuid, &config.connections[idx]).await?,
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::NotFound,
+                    source: eyre!("The database host of {} no longer exists", name),
+                })
+            }
+        }
+    }
+    config.connections.remove(idx);
+    config.save(&state.state_store, &uuid).await?;
+    Ok(Json(()))
+}
+
+pub fn get_instance_databases_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/databases",
+            get(get_instance_databases).put(set_instance_databases),
+        )
+        .route("/instance/:uuid/databases/dump", post(dump_databases))
+        .route(
+            "/instance/:uuid/databases/provision",
+            post(provision_database),
+        )
+        .route("/instance/:uuid/databases/:name", delete(delete_database))
+        .with_state(state)
+}
@@ -0,0 +1,829 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use axum::{extract::Query, routing::post, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::error;
+use ts_rs::TS;
+
+use crate::{
+    auth::user::{User, UserAction},
+    backup::{
+        pre_change::{PendingChange, RiskyChange},
+        BackupPolicy,
+    },
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    handlers::{instance::setup_minecraft_instance, instance_setup_configs::HandlerGameType},
+    host::HostInfo,
+    implementations::minecraft::{Flavour, FlavourKind},
+    prelude::GameInstance,
+    traits::t_configurable::{
+        manifest::{ConfigurableValue, SetupValue},
+        Game, TConfigurable,
+    },
+    types::InstanceUuid,
+    AppState,
+};
+
+const CMD_ARGS_SECTION: &str = "cmd_args_section";
+const SERVER_PROPERTIES_SECTION: &str = "server_properties_section";
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefinitionFormat {
+    /// JSON is valid YAML, so it's accepted as well
+    Yaml,
+    Toml,
+}
+
+/// Value of a server property as it's written in a definition
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyValue::Boolean(b) => write!(f, "{}", b),
+            PropertyValue::Integer(i) => write!(f, "{}", i),
+            PropertyValue::Float(x) => write!(f, "{}", x),
+            PropertyValue::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// A mod or plugin, downloaded unless a file of the same name is already there
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ModFile {
+    pub file_name: String,
+    pub url: String,
+}
+
+/// Desired state of an instance, matched to an existing one by name. Settings
+/// left out aren't managed and keep whatever value they have.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceDefinition {
+    pub name: String,
+    pub game_type: HandlerGameType,
+    pub version: String,
+    pub port: u32,
+    pub description: Option<String>,
+    /// in MB
+    pub min_ram: Option<u32>,
+    /// in MB
+    pub max_ram: Option<u32>,
+    /// space separated JVM arguments
+    pub cmd_args: Option<String>,
+    pub auto_start: Option<bool>,
+    pub restart_on_crash: Option<bool>,
+    /// keys of `server.properties`, e.g. `max-players`
+    #[serde(default)]
+    pub server_properties: BTreeMap<String, PropertyValue>,
+    /// put in `mods/` or `plugins/`, whichever the game type loads. Files not
+    /// listed are left alone.
+    #[serde(default)]
+    pub mods: Vec<ModFile>,
+    /// cron expression of the backup policy, an empty one turns scheduled backups off
+    pub backup_schedule: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceDefinitions {
+    #[serde(default)]
+    pub instances: Vec<InstanceDefinition>,
+}
+
+fn bad_request(message: String) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!(message),
+    }
+}
+
+/// `mods/` or `plugins/`, `None` if the game type loads neither
+pub(crate) fn mods_dir(game_type: HandlerGameType) -> Option<&'static str> {
+    let capabilities = game_type.capabilities();
+    if capabilities.mods {
+        Some("mods")
+    } else if capabilities.plugins || capabilities.proxy {
+        Some("plugins")
+    } else {
+        None
+    }
+}
+
+impl InstanceDefinitions {
+    pub fn parse(text: &str, format: DefinitionFormat) -> Result<Self, Error> {
+        match format {
+            DefinitionFormat::Yaml => serde_yaml::from_str(text)
+                .map_err(|e| bad_request(format!("Invalid definitions: {}", e))),
+            DefinitionFormat::Toml => {
+                toml::from_str(text).map_err(|e| bad_request(format!("Invalid definitions: {}", e)))
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut names = HashSet::new();
+        let mut ports = HashSet::new();
+        for definition in &self.instances {
+            if definition.name.trim().is_empty() {
+                return Err(bad_request("Instance names can't be empty".to_string()));
+            }
+            if !names.insert(definition.name.as_str()) {
+                return Err(bad_request(format!(
+                    "Instance \"{}\" is defined more than once",
+                    definition.name
+                )));
+            }
+            if !ports.insert(definition.port) {
+                return Err(bad_request(format!(
+                    "Port {} is used by more than one instance",
+                    definition.port
+                )));
+            }
+            if let (Some(min_ram), Some(max_ram)) = (definition.min_ram, definition.max_ram) {
+                if min_ram > max_ram {
+                    return Err(bad_request(format!(
+                        "Minimum RAM of \"{}\" is above its maximum",
+                        definition.name
+                    )));
+                }
+            }
+            if let Some(schedule) = definition
+                .backup_schedule
+                .as_ref()
+                .filter(|s| !s.is_empty())
+            {
+                BackupPolicy {
+                    schedule: Some(schedule.clone()),
+                    ..Default::default()
+                }
+                .parsed_schedule()?;
+            }
+            if !definition.mods.is_empty() && mods_dir(definition.game_type).is_none() {
+                return Err(bad_request(format!(
+                    "\"{}\" doesn't load mods or plugins",
+                    definition.name
+                )));
+            }
+            for mod_file in &definition.mods {
+                if !mod_file.file_name.ends_with(".jar")
+                    || mod_file.file_name.starts_with('.')
+                    || mod_file.file_name.contains(['/', '\\'])
+                {
+                    return Err(bad_request(format!(
+                        "\"{}\" is not a valid jar file name",
+                        mod_file.file_name
+                    )));
+                }
+                if !mod_file.url.starts_with("https://") {
+                    return Err(bad_request(format!(
+                        "Mod url {} must be an https url",
+                        mod_file.url
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A setting of an existing instance that differs from its definition
+#[derive(Debug, Clone, PartialEq)]
+enum Change {
+    Version(String),
+    Port(u32),
+    Description(String),
+    MinRam(u32),
+    MaxRam(u32),
+    CmdArgs(String),
+    AutoStart(bool),
+    RestartOnCrash(bool),
+    ServerProperty { key: String, value: String },
+    Mod { dir: &'static str, file: ModFile },
+    BackupSchedule(Option<String>),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Version(version) => write!(f, "change version to {}", version),
+            Change::Port(port) => write!(f, "set port to {}", port),
+            Change::Description(description) => {
+                write!(f, "set description to \"{}\"", description)
+            }
+            Change::MinRam(ram) => write!(f, "set minimum RAM to {} MB", ram),
+            Change::MaxRam(ram) => write!(f, "set maximum RAM to {} MB", ram),
+            Change::CmdArgs(args) => write!(f, "set JVM arguments to \"{}\"", args),
+            Change::AutoStart(auto_start) => write!(f, "set auto start to {}", auto_start),
+            Change::RestartOnCrash(restart) => write!(f, "set restart on crash to {}", restart),
+            Change::ServerProperty { key, value } => write!(f, "set {} to {}", key, value),
+            Change::Mod { dir, file } => write!(f, "download {}/{}", dir, file.file_name),
+            Change::BackupSchedule(Some(schedule)) => {
+                write!(f, "schedule backups at \"{}\"", schedule)
+            }
+            Change::BackupSchedule(None) => write!(f, "turn off scheduled backups"),
+        }
+    }
+}
+
+/// The settings of an instance a definition is compared against
+#[derive(Debug, Clone, Default)]
+struct CurrentConfig {
+    version: String,
+    port: u32,
+    description: String,
+    min_ram: Option<u32>,
+    max_ram: Option<u32>,
+    cmd_args: Option<String>,
+    auto_start: bool,
+    restart_on_crash: bool,
+    /// only the properties named in the definition
+    server_properties: BTreeMap<String, String>,
+    mod_files: HashSet<String>,
+    backup_schedule: Option<String>,
+}
+
+fn plan(current: &CurrentConfig, definition: &InstanceDefinition) -> Vec<Change> {
+    let mut changes = Vec::new();
+    if current.version != definition.version {
+        changes.push(Change::Version(definition.version.clone()));
+    }
+    if current.port != definition.port {
+        changes.push(Change::Port(definition.port));
+    }
+    if let Some(description) = &definition.description {
+        if &current.description != description {
+            changes.push(Change::Description(description.clone()));
+        }
+    }
+    if let Some(min_ram) = definition.min_ram {
+        if current.min_ram != Some(min_ram) {
+            changes.push(Change::MinRam(min_ram));
+        }
+    }
+    if let Some(max_ram) = definition.max_ram {
+        if current.max_ram != Some(max_ram) {
+            changes.push(Change::MaxRam(max_ram));
+        }
+    }
+    if let Some(cmd_args) = &definition.cmd_args {
+        if current.cmd_args.as_ref() != Some(cmd_args) {
+            changes.push(Change::CmdArgs(cmd_args.clone()));
+        }
+    }
+    if let Some(auto_start) = definition.auto_start {
+        if current.auto_start != auto_start {
+            changes.push(Change::AutoStart(auto_start));
+        }
+    }
+    if let Some(restart_on_crash) = definition.restart_on_crash {
+        if current.restart_on_crash != restart_on_crash {
+            changes.push(Change::RestartOnCrash(restart_on_crash));
+        }
+    }
+    for (key, value) in &definition.server_properties {
+        let value = value.to_string();
+        if current.server_properties.get(key) != Some(&value) {
+            changes.push(Change::ServerProperty {
+                key: key.clone(),
+                value,
+            });
+        }
+    }
+    if let Some(dir) = mods_dir(definition.game_type) {
+        for file in &definition.mods {
+            if !current.mod_files.contains(&file.file_name) {
+                changes.push(Change::Mod {
+                    dir,
+                    file: file.clone(),
+                });
+            }
+        }
+    }
+    if let Some(schedule) = &definition.backup_schedule {
+        let schedule = Some(schedule.clone()).filter(|s| !s.is_empty());
+        if current.backup_schedule != schedule {
+            changes.push(Change::BackupSchedule(schedule));
+        }
+    }
+    changes
+}
+
+fn value_to_string(value: &ConfigurableValue) -> String {
+    match value {
+        ConfigurableValue::String(s) | ConfigurableValue::Enum(s) => s.clone(),
+        ConfigurableValue::Integer(i) => i.to_string(),
+        ConfigurableValue::UnsignedInteger(u) => u.to_string(),
+        ConfigurableValue::Float(f) => f.to_string(),
+        ConfigurableValue::Boolean(b) => b.to_string(),
+    }
+}
+
+/// `value` parsed as the same type as the setting's current value
+fn typed_value(current: &ConfigurableValue, value: &str) -> Result<ConfigurableValue, Error> {
+    let invalid = || {
+        bad_request(format!(
+            "\"{}\" is not a valid value for this setting",
+            value
+        ))
+    };
+    Ok(match current {
+        ConfigurableValue::String(_) => ConfigurableValue::String(value.to_string()),
+        ConfigurableValue::Enum(_) => ConfigurableValue::Enum(value.to_string()),
+        ConfigurableValue::Integer(_) => {
+            ConfigurableValue::Integer(value.parse().map_err(|_| invalid())?)
+        }
+        ConfigurableValue::UnsignedInteger(_) => {
+            ConfigurableValue::UnsignedInteger(value.parse().map_err(|_| invalid())?)
+        }
+        ConfigurableValue::Float(_) => {
+            ConfigurableValue::Float(value.parse().map_err(|_| invalid())?)
+        }
+        ConfigurableValue::Boolean(_) => {
+            ConfigurableValue::Boolean(value.parse().map_err(|_| invalid())?)
+        }
+    })
+}
+
+async fn file_names(dir: &Path) -> HashSet<String> {
+    let mut names = HashSet::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            names.insert(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names
+}
+
+async fn current_config(
+    instance: &mut GameInstance,
+    definition: &InstanceDefinition,
+) -> Result<CurrentConfig, Error> {
+    let path = instance.path().await;
+    let manifest = instance.configurable_manifest().await;
+    let value = |section: &str, setting: &str| {
+        manifest
+            .get_setting(section, setting)
+            .and_then(|setting| setting.get_value())
+            .cloned()
+    };
+    let ram = |setting: &str| match value(CMD_ARGS_SECTION, setting) {
+        Some(ConfigurableValue::UnsignedInteger(ram)) => Some(ram),
+        _ => None,
+    };
+    Ok(CurrentConfig {
+        version: instance.version().await,
+        port: instance.port().await,
+        description: instance.description().await,
+        min_ram: ram("min_ram"),
+        max_ram: ram("max_ram"),
+        cmd_args: value(CMD_ARGS_SECTION, "cmd_args").map(|v| value_to_string(&v)),
+        auto_start: instance.auto_start().await,
+        restart_on_crash: instance.restart_on_crash().await,
+        server_properties: definition
+            .server_properties
+            .keys()
+            .filter_map(|key| {
+                value(SERVER_PROPERTIES_SECTION, key).map(|v| (key.clone(), value_to_string(&v)))
+            })
+            .collect(),
+        mod_files: match mods_dir(definition.game_type) {
+            Some(dir) => file_names(&path.join(dir)).await,
+            None => HashSet::new(),
+        },
+        backup_schedule: BackupPolicy::load(&path).await?.schedule,
+    })
+}
+
+async fn apply_change(instance: &mut GameInstance, change: &Change) -> Result<(), Error> {
+    match change {
+        Change::Version(version) => instance.change_version(version.clone()).await,
+        Change::Port(port) => instance.set_port(*port).await,
+        Change::Description(description) => instance.set_description(description.clone()).await,
+        Change::MinRam(ram) => {
+            instance
+                .update_configurable(
+                    CMD_ARGS_SECTION,
+                    "min_ram",
+                    ConfigurableValue::UnsignedInteger(*ram),
+                )
+                .await
+        }
+        Change::MaxRam(ram) => {
+            instance
+                .update_configurable(
+                    CMD_ARGS_SECTION,
+                    "max_ram",
+                    ConfigurableValue::UnsignedInteger(*ram),
+                )
+                .await
+        }
+        Change::CmdArgs(args) => {
+            instance
+                .update_configurable(
+                    CMD_ARGS_SECTION,
+                    "cmd_args",
+                    ConfigurableValue::String(args.clone()),
+                )
+                .await
+        }
+        Change::AutoStart(auto_start) => instance.set_auto_start(*auto_start).await,
+        Change::RestartOnCrash(restart) => instance.set_restart_on_crash(*restart).await,
+        Change::ServerProperty { key, value } => {
+            let current = instance
+                .configurable_manifest()
+                .await
+                .get_setting(SERVER_PROPERTIES_SECTION, key)
+                .and_then(|setting| setting.get_value())
+                .cloned()
+                .ok_or_else(|| bad_request(format!("Unknown server property \"{}\"", key)))?;
+            instance
+                .update_configurable(
+                    SERVER_PROPERTIES_SECTION,
+                    key,
+                    typed_value(&current, value)?,
+                )
+                .await
+        }
+        Change::Mod { dir, file } => {
+            crate::util::download_file(
+                &file.url,
+                &instance.path().await.join(dir),
+                Some(&file.file_name),
+                &|_| {},
+                false,
+            )
+            .await?;
+            Ok(())
+        }
+        Change::BackupSchedule(schedule) => {
+            let path = instance.path().await;
+            let mut policy = BackupPolicy::load(&path).await?;
+            policy.schedule = schedule.clone();
+            policy.validate()?;
+            policy.save(&path).await
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum DefinitionAction {
+    Create,
+    Update,
+    Unchanged,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct DefinitionOutcome {
+    pub name: String,
+    pub uuid: Option<InstanceUuid>,
+    pub action: DefinitionAction,
+    /// what was changed, or would be on a dry run
+    pub changes: Vec<String>,
+    pub errors: Vec<String>,
+    /// taken before an existing instance was changed, to roll back to
+    pub backup_id: Option<String>,
+}
+
+impl DefinitionOutcome {
+    fn fail(mut self, error: Error) -> Self {
+        self.action = DefinitionAction::Failed;
+        self.errors.push(error.source.to_string());
+        self
+    }
+}
+
+async fn find_instance(state: &AppState, name: &str) -> Result<Option<GameInstance>, Error> {
+    let instances = state.instances.lock().await.clone();
+    let mut found = Vec::new();
+    for instance in instances.values() {
+        if instance.name().await == name {
+            found.push(instance.clone());
+        }
+    }
+    if found.len() > 1 {
+        return Err(bad_request(format!(
+            "More than one instance is named \"{}\"",
+            name
+        )));
+    }
+    Ok(found.pop())
+}
+
+fn setup_value(definition: &InstanceDefinition) -> Result<SetupValue, Error> {
+    let host = HostInfo::detect();
+    let (default_min_ram, default_max_ram) = host.default_ram();
+    let min_ram = definition.min_ram.unwrap_or(default_min_ram);
+    let max_ram = definition.max_ram.unwrap_or(default_max_ram);
+    let cmd_args = definition
+        .cmd_args
+        .clone()
+        .unwrap_or_else(|| host.default_jvm_args().join(" "));
+    // no build_version, so Paper, Purpur and Velocity get their newest build
+    let section_2 = json!({
+        "min_ram": { "value": { "type": "UnsignedInteger", "value": min_ram } },
+        "max_ram": { "value": { "type": "UnsignedInteger", "value": max_ram } },
+        "cmd_args": { "value": { "type": "String", "value": cmd_args } },
+    });
+    Ok(serde_json::from_value(json!({
+        "name": definition.name,
+        "description": definition.description,
+        "auto_start": definition.auto_start.unwrap_or(false),
+        "restart_on_crash": definition.restart_on_crash.unwrap_or(false),
+        "setting_sections": {
+            "section_1": {
+                "settings": {
+                    "version": { "value": { "type": "Enum", "value": definition.version } },
+                    "port": { "value": { "type": "UnsignedInteger", "value": definition.port } },
+                }
+            },
+            "section_2": { "settings": section_2 },
+        },
+    }))
+    .context("Failed to build setup value from definition")?)
+}
+
+/// Sets up a missing instance, then brings what setup doesn't cover in line once
+/// it's done
+async fn create_instance(
+    state: &AppState,
+    requester: &User,
+    definition: &InstanceDefinition,
+) -> Result<InstanceUuid, Error> {
+    let (uuid, setup) = setup_minecraft_instance(
+        state.clone(),
+        requester.clone(),
+        definition.game_type,
+        setup_value(definition)?,
+        None,
+    )
+    .await?;
+    tokio::spawn({
+        let state = state.clone();
+        let definition = definition.clone();
+        let uuid = uuid.clone();
+        async move {
+            if setup.await.is_err() {
+                return;
+            }
+            let instance = state.instances.lock().await.get(&uuid).cloned();
+            // not there if the setup failed
+            let mut instance = match instance {
+                Some(instance) => instance,
+                None => return,
+            };
+            let changes = match current_config(&mut instance, &definition).await {
+                Ok(current) => plan(&current, &definition),
+                Err(e) => {
+                    error!("Failed to read config of new instance {}: {}", uuid, e);
+                    return;
+                }
+            };
+            for change in changes {
+                if let Err(e) = apply_change(&mut instance, &change).await {
+                    error!("Failed to {} on new instance {}: {}", change, uuid, e);
+                }
+            }
+        }
+    });
+    Ok(uuid)
+}
+
+async fn apply_definition(
+    state: &AppState,
+    requester: &User,
+    definition: &InstanceDefinition,
+    dry_run: bool,
+) -> DefinitionOutcome {
+    let mut outcome = DefinitionOutcome {
+        name: definition.name.clone(),
+        uuid: None,
+        action: DefinitionAction::Unchanged,
+        changes: Vec::new(),
+        errors: Vec::new(),
+        backup_id: None,
+    };
+    let instance = match find_instance(state, &definition.name).await {
+        Ok(instance) => instance,
+        Err(e) => return outcome.fail(e),
+    };
+    let mut instance = match instance {
+        Some(instance) => instance,
+        None => {
+            if let Err(e) = requester.try_action(&UserAction::CreateInstance) {
+                return outcome.fail(e);
+            }
+            outcome.action = DefinitionAction::Create;
+            outcome.changes.push(format!(
+                "create a {:?} {} instance on port {}",
+                definition.game_type, definition.version, definition.port
+            ));
+            if !dry_run {
+                match create_instance(state, requester, definition).await {
+                    Ok(uuid) => outcome.uuid = Some(uuid),
+                    Err(e) => return outcome.fail(e),
+                }
+            }
+            return outcome;
+        }
+    };
+    let uuid = instance.uuid().await;
+    outcome.uuid = Some(uuid.clone());
+    if let Err(e) = requester.try_action(&UserAction::AccessSetting(uuid)) {
+        return outcome.fail(e);
+    }
+    let expected_game = FlavourKind::try_from(definition.game_type)
+        .map(|kind| Game::from(Flavour::from(kind)))
+        .ok();
+    if expected_game.as_ref() != Some(&instance.game_type().await) {
+        return outcome.fail(bad_request(format!(
+            "\"{}\" exists but isn't a {:?} instance, it has to be recreated by hand",
+            definition.name, definition.game_type
+        )));
+    }
+    let changes = match current_config(&mut instance, definition).await {
+        Ok(current) => plan(&current, definition),
+        Err(e) => return outcome.fail(e),
+    };
+    if changes.is_empty() {
+        return outcome;
+    }
+    outcome.action = DefinitionAction::Update;
+    let pending = if dry_run {
+        None
+    } else {
+        let caused_by = CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        };
+        match PendingChange::begin(state, &instance, RiskyChange::DefinitionApply, caused_by).await
+        {
+            Ok(pending) => Some(pending),
+            Err(e) => return outcome.fail(e),
+        }
+    };
+    outcome.backup_id = pending
+        .as_ref()
+        .and_then(|pending| pending.backup_id().map(str::to_string));
+    for change in changes {
+        if !dry_run {
+            if let Err(e) = apply_change(&mut instance, &change).await {
+                outcome.action = DefinitionAction::Failed;
+                outcome
+                    .errors
+                    .push(format!("Failed to {}: {}", change, e.source));
+                continue;
+            }
+        }
+        outcome.changes.push(change.to_string());
+    }
+    if let Some(pending) = pending {
+        let result = if outcome.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!(outcome.errors.join(", ")),
+            })
+        };
+        pending.finish(&result, None).await;
+    }
+    outcome
+}
+
+#[derive(Deserialize)]
+pub struct ApplyQuery {
+    #[serde(default)]
+    pub format: Option<DefinitionFormat>,
+    /// only report what would change
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Creates the defined instances that are missing and updates the settings of
+/// existing ones that drifted from their definition. Instances that aren't
+/// defined are never touched, so applying the same definitions again is a no-op.
+pub async fn apply_instance_definitions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<ApplyQuery>,
+    body: String,
+) -> Result<Json<Vec<DefinitionOutcome>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let definitions =
+        InstanceDefinitions::parse(&body, query.format.unwrap_or(DefinitionFormat::Yaml))?;
+    definitions.validate()?;
+    let mut outcomes = Vec::new();
+    for definition in &definitions.instances {
+        outcomes.push(apply_definition(&state, &requester, definition, query.dry_run).await);
+    }
+    Ok(Json(outcomes))
+}
+
+pub fn get_instance_definitions_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/apply", post(apply_instance_definitions))
+        .with_state(state)
+}
+
+#[test]
+fn test_plan_instance_definition() {
+    let yaml = r#"
+instances:
+  - name: Survival
+    game_type: MinecraftFabric
+    version: 1.20.4
+    port: 25565
+    max_ram: 4096
+    server_properties:
+      max-players: 40
+      pvp: false
+    mods:
+      - file_name: lithium.jar
+        url: https://cdn.modrinth.com/lithium.jar
+    backup_schedule: "0 0 */6 * * *"
+"#;
+    let definitions = InstanceDefinitions::parse(yaml, DefinitionFormat::Yaml).unwrap();
+    definitions.validate().unwrap();
+    let definition = &definitions.instances[0];
+    let toml = r#"
+[[instances]]
+name = "Survival"
+game_type = "MinecraftFabric"
+version = "1.20.4"
+port = 25565
+max_ram = 4096
+backup_schedule = "0 0 */6 * * *"
+
+[instances.server_properties]
+max-players = 40
+pvp = false
+
+[[instances.mods]]
+file_name = "lithium.jar"
+url = "https://cdn.modrinth.com/lithium.jar"
+"#;
+    assert_eq!(
+        &InstanceDefinitions::parse(toml, DefinitionFormat::Toml)
+            .unwrap()
+            .instances[0],
+        definition
+    );
+
+    let mut current = CurrentConfig {
+        version: "1.20.4".to_string(),
+        port: 25565,
+        max_ram: Some(2048),
+        ..Default::default()
+    };
+    current
+        .server_properties
+        .insert("pvp".to_string(), "false".to_string());
+    assert_eq!(
+        plan(&current, definition),
+        vec![
+            Change::MaxRam(4096),
+            Change::ServerProperty {
+                key: "max-players".to_string(),
+                value: "40".to_string()
+            },
+            Change::Mod {
+                dir: "mods",
+                file: definition.mods[0].clone()
+            },
+            Change::BackupSchedule(Some("0 0 */6 * * *".to_string())),
+        ]
+    );
+
+    current.max_ram = Some(4096);
+    current
+        .server_properties
+        .insert("max-players".to_string(), "40".to_string());
+    current.mod_files.insert("lithium.jar".to_string());
+    current.backup_schedule = definition.backup_schedule.clone();
+    // description and the settings left out aren't managed
+    current.description = "made by hand".to_string();
+    assert!(plan(&current, definition).is_empty());
+
+    let mut duplicate = definitions.clone();
+    duplicate.instances.push(definition.clone());
+    assert!(duplicate.validate().is_err());
+}
@@ -49,7 +49,16 @@ static PROTECTED_DIR_NAME: [&str; 1] = ["mods"];
 
 fn is_path_protected(path: impl AsRef<std::path::Path>) -> bool {
     let path = path.as_ref();
-    if path.is_dir() {
+    // the core's own state in the instance, which it trusts when reading it back
+    let core_state = path.components().any(|component| {
+        component
+            .as_os_str()
+            .to_str()
+            .map_or(false, |name| name.starts_with(".lodestone_"))
+    });
+    if core_state {
+        true
+    } else if path.is_dir() {
         path.file_name()
             .and_then(|s| s.to_str().map(|s| PROTECTED_DIR_NAME.contains(&s)))
             .unwrap_or(true)
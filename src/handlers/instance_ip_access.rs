@@ -0,0 +1,90 @@
+use axum::{extract::Path, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    firewall::{IpAccessList, IpAccessStatus},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+pub async fn get_ip_access(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<IpAccessStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(IpAccessStatus {
+        access: IpAccessList::load(&path).await?,
+        enforced: state
+            .global_settings
+            .lock()
+            .await
+            .firewall()
+            .backend
+            .is_some(),
+    }))
+}
+
+/// Players joining from a rejected address are kicked right away. The firewall
+/// rules, if a backend is configured, are updated the next time the instance starts.
+pub async fn set_ip_access(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(access): Json<IpAccessList>,
+) -> Result<Json<IpAccessStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    access.validate()?;
+    let (firewall, geoip) = {
+        let global_settings = state.global_settings.lock().await;
+        (global_settings.firewall(), global_settings.geoip())
+    };
+    if !access.blocked_countries.is_empty() && geoip.country_database.is_none() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Blocking countries requires a GeoIP country database"),
+        });
+    }
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    access.save(&path).await?;
+    Ok(Json(IpAccessStatus {
+        access,
+        enforced: firewall.backend.is_some(),
+    }))
+}
+
+pub fn get_instance_ip_access_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/ip_access",
+            get(get_ip_access).put(set_ip_access),
+        )
+        .with_state(state)
+}
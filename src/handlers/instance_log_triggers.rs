@@ -0,0 +1,156 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::{User, UserAction},
+    console_policy::validate_command,
+    error::{Error, ErrorKind},
+    log_triggers::{LogTrigger, LogTriggerAction, LogTriggers},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+/// Commands of a trigger run as the core, so the requester must be allowed to
+/// send each of them themselves
+async fn check_commands(
+    state: &AppState,
+    requester: &User,
+    uuid: &InstanceUuid,
+    trigger: &LogTrigger,
+) -> Result<(), Error> {
+    let commands: Vec<&String> = trigger
+        .actions
+        .iter()
+        .filter_map(|action| match action {
+            LogTriggerAction::Command { command } => Some(command),
+            _ => None,
+        })
+        .collect();
+    if commands.is_empty() {
+        return Ok(());
+    }
+    requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let console_policy = state.global_settings.lock().await.console_policy();
+    for command in commands {
+        console_policy.check(requester, validate_command(command)?)?;
+    }
+    Ok(())
+}
+
+pub async fn get_log_triggers(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<LogTrigger>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(LogTriggers::load(&path).await?.triggers))
+}
+
+pub async fn create_log_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut trigger): Json<LogTrigger>,
+) -> Result<Json<LogTrigger>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    trigger.validate()?;
+    check_commands(&state, &requester, &uuid, &trigger).await?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut triggers = LogTriggers::load(&path).await?;
+    trigger.id = rand_alphanumeric(12);
+    trigger.hits = 0;
+    trigger.last_hit = None;
+    triggers.triggers.push(trigger.clone());
+    triggers.save(&path).await?;
+    Ok(Json(trigger))
+}
+
+/// Hit statistics are kept, whatever the request says
+pub async fn update_log_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, trigger_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(mut trigger): Json<LogTrigger>,
+) -> Result<Json<LogTrigger>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    trigger.validate()?;
+    check_commands(&state, &requester, &uuid, &trigger).await?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut triggers = LogTriggers::load(&path).await?;
+    let existing = triggers
+        .triggers
+        .iter_mut()
+        .find(|t| t.id == trigger_id)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Log trigger not found"),
+        })?;
+    trigger.id = trigger_id;
+    trigger.hits = existing.hits;
+    trigger.last_hit = existing.last_hit;
+    *existing = trigger.clone();
+    triggers.save(&path).await?;
+    Ok(Json(trigger))
+}
+
+pub async fn delete_log_trigger(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, trigger_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut triggers = LogTriggers::load(&path).await?;
+    let len = triggers.triggers.len();
+    triggers.triggers.retain(|t| t.id != trigger_id);
+    if triggers.triggers.len() == len {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Log trigger not found"),
+        });
+    }
+    triggers.save(&path).await?;
+    Ok(Json(()))
+}
+
+pub fn get_instance_log_triggers_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/log_triggers",
+            get(get_log_triggers).post(create_log_trigger),
+        )
+        .route(
+            "/instance/:uuid/log_triggers/:trigger_id",
+            put(update_log_trigger).delete(delete_log_trigger),
+        )
+        .with_state(state)
+}
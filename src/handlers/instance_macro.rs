@@ -12,7 +12,10 @@ use crate::{
     error::{Error, ErrorKind},
     events::CausedBy,
     macro_executor::MacroPID,
-    traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
+    traits::{
+        t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry},
+        Capability, TInstance,
+    },
     types::InstanceUuid,
     AppState,
 };
@@ -29,6 +32,7 @@ pub async fn get_instance_task_list(
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
+    instance.require_capability(Capability::SupportsMacros)?;
     let tasks = instance.get_task_list().await?;
     Ok(Json(tasks))
 }
@@ -45,6 +49,7 @@ pub async fn get_instance_macro_list(
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
+    instance.require_capability(Capability::SupportsMacros)?;
     let macros = instance.get_macro_list().await?;
     Ok(Json(macros))
 }
@@ -61,6 +66,7 @@ pub async fn get_instance_history_list(
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
+    instance.require_capability(Capability::SupportsMacros)?;
     let history = instance.get_history_list().await?;
     Ok(Json(history))
 }
@@ -78,6 +84,7 @@ pub async fn run_macro(
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
+    instance.require_capability(Capability::SupportsMacros)?;
     instance
         .run_macro(
             &macro_name,
@@ -103,6 +110,7 @@ pub async fn kill_macro(
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
     })?;
+    instance.require_capability(Capability::SupportsMacros)?;
     instance.kill_macro(pid).await?;
     Ok(Json(()))
 }
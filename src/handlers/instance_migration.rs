@@ -0,0 +1,297 @@
+use axum::{
+    extract::{BodyStream, DefaultBodyLimit, Path},
+    routing::{post, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tracing::{error, Instrument};
+
+use crate::{
+    auth::user::UserAction,
+    backup::{archive, BackupGuard},
+    error::{Error, ErrorKind},
+    events::{CausedBy, Event},
+    implementations::minecraft::MinecraftInstance,
+    instance_migration::{
+        migrate_instance, path_to_staging, prune_staged, validate_dir_name, MigrationFinish,
+    },
+    prelude::{path_to_instances, path_to_tmp, GameInstance},
+    traits::{t_configurable::GameType, t_configurable::TConfigurable, t_server::TServer},
+    types::{DotLodestoneConfig, InstanceUuid},
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct MigrateRequest {
+    /// id of one of the core's peer cores
+    peer_id: String,
+    #[serde(default)]
+    live: bool,
+    /// live migrations always start the instance on the target
+    #[serde(default)]
+    start_on_target: bool,
+}
+
+/// Runs in the background, the outcome is reported by the end of its progression event
+pub async fn migrate(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(request): Json<MigrateRequest>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can migrate instances to other cores"),
+        });
+    }
+    let peer = state
+        .global_settings
+        .lock()
+        .await
+        .peer_cores()
+        .get(&request.peer_id)?
+        .clone();
+    let instance = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    let guard = BackupGuard::acquire(&uuid)?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    tokio::task::spawn(
+        async move {
+            let _ = migrate_instance(
+                instance,
+                guard,
+                peer,
+                request.live,
+                request.start_on_target,
+                &state.event_broadcaster,
+                caused_by,
+            )
+            .await;
+        }
+        .in_current_span(),
+    );
+    Ok(Json(()))
+}
+
+/// Writes a request body to a temporary archive and extracts it over `dest`
+async fn receive_archive(mut body: BodyStream, dest: &std::path::Path) -> Result<(), Error> {
+    let temp_dir =
+        tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary directory")?;
+    let archive_path = temp_dir.path().join("migration.tar.gz");
+    let mut file = crate::util::fs::create(&archive_path).await?;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.context("Failed to receive archive")?;
+        file.write_all(&chunk)
+            .await
+            .context("Failed to write archive")?;
+    }
+    file.flush().await.context("Failed to write archive")?;
+    drop(file);
+    crate::util::fs::create_dir_all(dest).await?;
+    let dest = dest.to_owned();
+    tokio::task::spawn_blocking(move || archive::extract_archive(&archive_path, &dest, None))
+        .await
+        .context("Failed to spawn blocking task")?
+}
+
+async fn receive(
+    state: &AppState,
+    uuid: &InstanceUuid,
+    token: &str,
+    body: BodyStream,
+    fresh: bool,
+) -> Result<(), Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
+    if state.instances.lock().await.contains_key(uuid) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance {} already exists on this core", uuid),
+        });
+    }
+    let staging = path_to_staging(uuid);
+    if fresh {
+        if staging.exists() {
+            crate::util::fs::remove_dir_all(&staging).await?;
+        }
+    } else if !staging.is_dir() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Nothing was staged for instance {}", uuid),
+        });
+    }
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Receiving instance {} from another core", uuid),
+        None,
+        None,
+        CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        },
+    );
+    state.event_broadcaster.send(progression_start_event);
+    let res = receive_archive(body, &staging).await;
+    state
+        .event_broadcaster
+        .send(Event::new_progression_event_end(
+            event_id,
+            res.is_ok(),
+            Some(&match &res {
+                Ok(_) => format!("Received instance {}", uuid),
+                Err(e) => format!("Failed to receive instance {}: {}", uuid, e),
+            }),
+            None,
+        ));
+    res
+}
+
+/// First transfer of a migration, replacing anything staged before
+pub async fn stage_migration(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    body: BodyStream,
+) -> Result<Json<()>, Error> {
+    receive(&state, &uuid, &token, body, true).await?;
+    Ok(Json(()))
+}
+
+/// Files changed on the migrating core since the first transfer
+pub async fn receive_migration_delta(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    body: BodyStream,
+) -> Result<Json<()>, Error> {
+    receive(&state, &uuid, &token, body, false).await?;
+    Ok(Json(()))
+}
+
+/// Moves the staged instance into the instances directory and loads it
+pub async fn finish_migration(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(finish): Json<MigrationFinish>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
+    validate_dir_name(&finish.dir_name)?;
+    let staging = path_to_staging(&uuid);
+    if !staging.is_dir() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Nothing was staged for instance {}", uuid),
+        });
+    }
+    let dest = path_to_instances().join(&finish.dir_name);
+    if dest.exists() {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("{} already exists on this core", dest.display()),
+        });
+    }
+    {
+        let staging = staging.clone();
+        let files = finish.files.clone();
+        tokio::task::spawn_blocking(move || prune_staged(&staging, &files))
+            .await
+            .context("Failed to spawn blocking task")??;
+    }
+    // the source core may be older than this one
+    crate::migration::schema::DOT_LODESTONE_CONFIG
+        .migrate_file(&staging.join(".lodestone_config"))?;
+    let dot_lodestone_config: DotLodestoneConfig = serde_json::from_str(
+        &crate::util::fs::read_to_string(staging.join(".lodestone_config")).await?,
+    )
+    .context("Failed to parse .lodestone_config of the migrated instance")?;
+    if dot_lodestone_config.uuid() != &uuid {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The staged instance is not {}", uuid),
+        });
+    }
+    if !matches!(dot_lodestone_config.game_type(), GameType::MinecraftJava) {
+        return Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Only Minecraft instances can be migrated"),
+        });
+    }
+
+    let mut instances = state.instances.lock().await;
+    if instances.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Instance {} already exists on this core", uuid),
+        });
+    }
+    crate::util::fs::rename(&staging, &dest).await?;
+    let mut instance: GameInstance = MinecraftInstance::restore(
+        dest.clone(),
+        dot_lodestone_config,
+        state.event_broadcaster.clone(),
+        state.macro_executor.clone(),
+    )
+    .await?
+    .into();
+    let port = instance.port().await;
+    let port_in_use = {
+        let mut port_manager = state.port_manager.lock().await;
+        port_manager.add_port(port);
+        port_manager.port_status(port).is_in_use
+    };
+    instances.insert(uuid.clone(), instance.clone());
+    if let Err(e) = state.state_store.record_instances(&instances).await {
+        error!("Failed to record the instances: {}", e);
+    }
+    drop(instances);
+    if finish.start {
+        if port_in_use {
+            return Err(Error {
+                kind: ErrorKind::Internal,
+                source: eyre!(
+                    "Migrated instance {} but port {} is in use, change it before starting",
+                    uuid,
+                    port
+                ),
+            });
+        }
+        instance
+            .start(
+                CausedBy::User {
+                    user_id: requester.uid.clone(),
+                    user_name: requester.username.clone(),
+                },
+                false,
+            )
+            .await?;
+    }
+    Ok(Json(()))
+}
+
+pub fn get_instance_migration_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/migrate", post(migrate))
+        .route("/migration/:uuid/stage", put(stage_migration))
+        .route("/migration/:uuid/delta", put(receive_migration_delta))
+        .route("/migration/:uuid/finish", post(finish_migration))
+        .layer(DefaultBodyLimit::disable())
+        .with_state(state)
+}
@@ -0,0 +1,214 @@
+use axum::{
+    extract::Path,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use ts_rs::TS;
+
+use crate::{
+    auth::user::{User, UserAction},
+    backup::pre_change::{PendingChange, RiskyChange},
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    implementations::minecraft::{
+        mod_updates::{ModPin, ModUpdatePlan, StagedModUpdates},
+        MinecraftInstance,
+    },
+    prelude::GameInstance,
+    traits::Capability,
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn get_minecraft_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<MinecraftInstance, Error> {
+    match state.instances.lock().await.get(uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => Ok(instance.clone()),
+        Some(_) => Err(Error::not_supported(Capability::SupportsMods)),
+        None => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        }),
+    }
+}
+
+/// Backs the instance up first if the settings ask for it
+async fn begin_update(
+    state: &AppState,
+    requester: &User,
+    instance: &MinecraftInstance,
+) -> Result<PendingChange, Error> {
+    instance.check_stopped().await?;
+    PendingChange::begin(
+        state,
+        &GameInstance::from(instance.clone()),
+        RiskyChange::ModUpdate,
+        CausedBy::User {
+            user_id: requester.uid.clone(),
+            user_name: requester.username.clone(),
+        },
+    )
+    .await
+}
+
+/// The updates available for the installed mods, the dependencies they pull in
+/// and the ones held back because they would break something
+pub async fn get_mod_update_plan(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ModUpdatePlan>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.mod_update_plan().await?))
+}
+
+/// Resolves the plan again and applies it, returning what was done
+pub async fn apply_mod_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ModUpdatePlan>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    let change = begin_update(&state, &requester, &instance).await?;
+    let res = instance.update_mods().await;
+    let diff = match &res {
+        Ok(plan) => instance.mod_change_diff(plan).await,
+        Err(_) => None,
+    };
+    change.finish(&res, diff).await;
+    Ok(Json(res?))
+}
+
+pub async fn get_staged_mod_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<StagedModUpdates>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.staged_mod_updates().await?))
+}
+
+/// Downloads the updates so they can be applied later, replacing what was staged
+/// before
+pub async fn stage_mod_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<StagedModUpdates>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.stage_mod_updates().await?))
+}
+
+pub async fn apply_staged_mod_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ModUpdatePlan>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    if instance.staged_mod_updates().await?.is_none() {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No mod updates are staged"),
+        });
+    }
+    let change = begin_update(&state, &requester, &instance).await?;
+    let res = instance.apply_staged_mod_updates().await;
+    let diff = match &res {
+        Ok(plan) => instance.mod_change_diff(plan).await,
+        Err(_) => None,
+    };
+    change.finish(&res, diff).await;
+    Ok(Json(res?))
+}
+
+pub async fn discard_staged_mod_updates(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    instance.discard_staged_mod_updates().await?;
+    Ok(Json(()))
+}
+
+#[derive(Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct NewModPin {
+    /// the installed file, pinned at its current version
+    pub file: String,
+}
+
+pub async fn get_mod_pins(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ModPin>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.mod_pins().await?))
+}
+
+pub async fn pin_mod(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(new_pin): Json<NewModPin>,
+) -> Result<Json<ModPin>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.pin_mod(&new_pin.file).await?))
+}
+
+pub async fn unpin_mod(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, project_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ModPin>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.unpin_mod(&project_id).await?))
+}
+
+pub fn get_instance_mods_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/mods/updates",
+            get(get_mod_update_plan).post(apply_mod_updates),
+        )
+        .route(
+            "/instance/:uuid/mods/updates/staged",
+            get(get_staged_mod_updates)
+                .post(stage_mod_updates)
+                .delete(discard_staged_mod_updates),
+        )
+        .route(
+            "/instance/:uuid/mods/updates/staged/apply",
+            post(apply_staged_mod_updates),
+        )
+        .route("/instance/:uuid/mods/pins", get(get_mod_pins).post(pin_mod))
+        .route("/instance/:uuid/mods/pins/:project_id", delete(unpin_mod))
+        .with_state(state)
+}
@@ -0,0 +1,91 @@
+use axum::{extract::Path, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    instance_notes::{InstanceNotes, NoteRevision},
+    prelude::GameInstance,
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+/// The notes of the instance, moving them into its config first if an older core
+/// left them in a file of their own
+async fn load_notes(instance: &mut GameInstance) -> Result<InstanceNotes, Error> {
+    if let Some(notes) = InstanceNotes::take_legacy_file(&instance.path().await).await? {
+        instance.set_notes(notes).await?;
+    }
+    Ok(instance.notes().await)
+}
+
+pub async fn get_instance_notes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<InstanceNotes>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let mut instances = state.instances.lock().await;
+    let instance = instances.get_mut(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    load_notes(instance).await.map(Json)
+}
+
+pub async fn set_instance_notes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(content): Json<String>,
+) -> Result<Json<InstanceNotes>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    // held until the notes are saved, so concurrent edits don't lose revisions
+    let mut instances = state.instances.lock().await;
+    let instance = instances.get_mut(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    let mut notes = load_notes(instance).await?;
+    if notes.edit(
+        content,
+        requester.uid,
+        requester.username,
+        chrono::Utc::now().timestamp(),
+    )? {
+        instance.set_notes(notes.clone()).await?;
+    }
+    Ok(Json(notes))
+}
+
+pub async fn get_instance_notes_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<NoteRevision>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let mut instances = state.instances.lock().await;
+    let instance = instances.get_mut(&uuid).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance not found"),
+    })?;
+    Ok(Json(load_notes(instance).await?.history))
+}
+
+pub fn get_instance_notes_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/notes",
+            get(get_instance_notes).put(set_instance_notes),
+        )
+        .route(
+            "/instance/:uuid/notes/history",
+            get(get_instance_notes_history),
+        )
+        .with_state(state)
+}
@@ -1,28 +1,52 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
-use axum::{extract::Path, routing::get, Json, Router};
+use axum::{
+    extract::{Path, Query},
+    routing::get,
+    Json, Router,
+};
+use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
+use serde::Deserialize;
 
 use crate::{
+    afk::{ActivityStatus, AfkPolicy},
+    auth::user::UserAction,
     error::{Error, ErrorKind},
+    player_positions::{PlayerPosition, PositionTrackingConfig},
+    player_sessions::{PlayerSession, PlayerSessionLog},
+    playtime_ranks::{playtime, Playtime, PlaytimeRanksConfig},
+    prelude::GameInstance,
+    reserved_slots::ReservedSlotsConfig,
+    traits::t_configurable::TConfigurable,
     traits::t_player::{Player, TPlayerManagement},
+    traits::{Capability, TInstance},
     types::InstanceUuid,
     AppState,
 };
 
-pub async fn get_player_count(
-    axum::extract::State(state): axum::extract::State<AppState>,
-    Path(uuid): Path<InstanceUuid>,
-) -> Result<Json<u32>, Error> {
-    state
+/// The instance, if it keeps track of its players
+async fn get_instance(state: &AppState, uuid: &InstanceUuid) -> Result<GameInstance, Error> {
+    let instance = state
         .instances
         .lock()
         .await
-        .get(&uuid)
+        .get(uuid)
+        .cloned()
         .ok_or_else(|| Error {
             kind: ErrorKind::NotFound,
             source: eyre!("Instance not found"),
-        })?
+        })?;
+    instance.require_capability(Capability::SupportsPlayerList)?;
+    Ok(instance)
+}
+
+pub async fn get_player_count(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+) -> Result<Json<u32>, Error> {
+    get_instance(&state, &uuid)
+        .await?
         .get_player_count()
         .await
         .map(Json)
@@ -32,15 +56,8 @@ pub async fn get_max_player_count(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
 ) -> Result<Json<u32>, Error> {
-    state
-        .instances
-        .lock()
-        .await
-        .get(&uuid)
-        .ok_or_else(|| Error {
-            kind: ErrorKind::NotFound,
-            source: eyre!("Instance not found"),
-        })?
+    get_instance(&state, &uuid)
+        .await?
         .get_max_player_count()
         .await
         .map(Json)
@@ -51,36 +68,233 @@ pub async fn set_max_player_count(
     Path(uuid): Path<InstanceUuid>,
     Json(count): Json<u32>,
 ) -> Result<Json<()>, Error> {
-    state
+    get_instance(&state, &uuid)
+        .await?
+        .set_max_player_count(count)
+        .await
+        .map(Json)
+}
+
+pub async fn get_player_list(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+) -> Result<Json<HashSet<Player>>, Error> {
+    get_instance(&state, &uuid)
+        .await?
+        .get_player_list()
+        .await
+        .map(Json)
+}
+
+#[derive(Deserialize)]
+pub struct PlayerSessionQuery {
+    pub player: Option<String>,
+}
+
+/// Most recent first. Includes player addresses, hence the settings permission
+pub async fn get_player_sessions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<PlayerSessionQuery>,
+) -> Result<Json<Vec<PlayerSession>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
         .instances
         .lock()
         .await
-        .get_mut(&uuid)
+        .get(&uuid)
         .ok_or_else(|| Error {
             kind: ErrorKind::NotFound,
             source: eyre!("Instance not found"),
         })?
-        .set_max_player_count(count)
-        .await
-        .map(Json)
+        .path()
+        .await;
+    Ok(Json(
+        PlayerSessionLog::load(&path)
+            .await?
+            .sessions
+            .into_iter()
+            .rev()
+            .filter(|session| match &query.player {
+                Some(player) => session.player_name.eq_ignore_ascii_case(player),
+                None => true,
+            })
+            .collect(),
+    ))
 }
 
-pub async fn get_player_list(
+/// Empty unless position tracking is enabled for the instance
+pub async fn get_player_positions(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
-) -> Result<Json<HashSet<Player>>, Error> {
-    state
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<HashMap<String, PlayerPosition>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    Ok(Json(
+        state
+            .player_positions
+            .lock()
+            .await
+            .get(&uuid)
+            .cloned()
+            .unwrap_or_default(),
+    ))
+}
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
         .instances
         .lock()
         .await
-        .get_mut(&uuid)
+        .get(uuid)
         .ok_or_else(|| Error {
             kind: ErrorKind::NotFound,
             source: eyre!("Instance not found"),
         })?
-        .get_player_list()
-        .await
-        .map(Json)
+        .path()
+        .await)
+}
+
+pub async fn get_position_tracking_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PositionTrackingConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(PositionTrackingConfig::load(&path).await?))
+}
+
+pub async fn set_position_tracking_config(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<PositionTrackingConfig>,
+) -> Result<Json<PositionTrackingConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    config.validate()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+/// Activity of every online player, players are only marked AFK while the AFK policy is enabled
+pub async fn get_player_activity(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<HashMap<String, ActivityStatus>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    Ok(Json(
+        state
+            .player_activity
+            .lock()
+            .await
+            .get(&uuid)
+            .cloned()
+            .unwrap_or_default(),
+    ))
+}
+
+pub async fn get_afk_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<AfkPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(AfkPolicy::load(&path).await?))
+}
+
+pub async fn set_afk_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(policy): Json<AfkPolicy>,
+) -> Result<Json<AfkPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    policy.validate()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    policy.save(&path).await?;
+    Ok(Json(policy))
+}
+
+pub async fn get_reserved_slots(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ReservedSlotsConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(ReservedSlotsConfig::load(&path).await?))
+}
+
+pub async fn set_reserved_slots(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<ReservedSlotsConfig>,
+) -> Result<Json<ReservedSlotsConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    config.validate()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+/// Playtime of every player with a recorded session, longest first
+pub async fn get_playtime(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Playtime>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(playtime(
+        &PlayerSessionLog::load(&path).await?.sessions,
+        chrono::Utc::now().timestamp(),
+    )))
+}
+
+pub async fn get_playtime_ranks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PlaytimeRanksConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(PlaytimeRanksConfig::load(&path).await?))
+}
+
+/// Ranks keep who they were granted to by id, a rank sent without its id is a new
+/// rank and is granted again to everyone who qualifies
+pub async fn set_playtime_ranks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut config): Json<PlaytimeRanksConfig>,
+) -> Result<Json<PlaytimeRanksConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    config.prepare()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    config.save(&path).await?;
+    Ok(Json(config))
 }
 
 pub fn get_instance_players_routes(state: AppState) -> Router {
@@ -91,5 +305,28 @@ pub fn get_instance_players_routes(state: AppState) -> Router {
             get(get_max_player_count).put(set_max_player_count),
         )
         .route("/instance/:uuid/players", get(get_player_list))
+        .route("/instance/:uuid/players/sessions", get(get_player_sessions))
+        .route(
+            "/instance/:uuid/players/positions",
+            get(get_player_positions),
+        )
+        .route(
+            "/instance/:uuid/players/position_tracking",
+            get(get_position_tracking_config).put(set_position_tracking_config),
+        )
+        .route("/instance/:uuid/players/activity", get(get_player_activity))
+        .route(
+            "/instance/:uuid/players/afk_policy",
+            get(get_afk_policy).put(set_afk_policy),
+        )
+        .route(
+            "/instance/:uuid/players/reserved_slots",
+            get(get_reserved_slots).put(set_reserved_slots),
+        )
+        .route("/instance/:uuid/players/playtime", get(get_playtime))
+        .route(
+            "/instance/:uuid/players/playtime_ranks",
+            get(get_playtime_ranks).put(set_playtime_ranks),
+        )
         .with_state(state)
 }
@@ -0,0 +1,128 @@
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    implementations::minecraft::{
+        proxy::{backend_address, server_key, ProxyBackend},
+        MinecraftInstance,
+    },
+    prelude::GameInstance,
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn get_proxy(state: &AppState, uuid: &InstanceUuid) -> Result<MinecraftInstance, Error> {
+    let instance = match state.instances.lock().await.get(uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => Some(instance.clone()),
+        Some(_) => None,
+        None => {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Instance not found"),
+            })
+        }
+    };
+    match instance {
+        Some(proxy) if proxy.is_proxy().await => Ok(proxy),
+        _ => Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Only Velocity and BungeeCord instances have backends"),
+        }),
+    }
+}
+
+pub async fn get_links(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ProxyBackend>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let proxy = get_proxy(&state, &uuid).await?;
+    Ok(Json(proxy.proxy_backends().await?))
+}
+
+/// Adds another Java server on this core to the proxy's servers, tried after the
+/// ones linked before it. Backends have to run in offline mode, or have the
+/// proxy's player forwarding set up, for players to get through.
+pub async fn link_backend(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backend_uuid)): Path<(InstanceUuid, InstanceUuid)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ProxyBackend>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    requester.try_action(&UserAction::ViewInstance(backend_uuid.clone()))?;
+    let proxy = get_proxy(&state, &uuid).await?;
+    let backend = match state.instances.lock().await.get(&backend_uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => instance.clone(),
+        Some(_) => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Only Minecraft Java servers can be linked to a proxy"),
+            })
+        }
+        None => {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Backend instance not found"),
+            })
+        }
+    };
+    if backend.is_proxy().await {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("A proxy can't be linked to another proxy"),
+        });
+    }
+    let mut backends = proxy.proxy_backends().await?;
+    if backends.iter().any(|linked| linked.uuid == backend_uuid) {
+        return Ok(Json(backends));
+    }
+    backends.push(ProxyBackend {
+        name: server_key(&backend.name().await, &backends),
+        address: backend_address(backend.port().await),
+        uuid: backend_uuid,
+    });
+    proxy.set_proxy_backends(&backends).await?;
+    Ok(Json(backends))
+}
+
+pub async fn unlink_backend(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, backend_uuid)): Path<(InstanceUuid, InstanceUuid)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ProxyBackend>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let proxy = get_proxy(&state, &uuid).await?;
+    let mut backends = proxy.proxy_backends().await?;
+    let linked = backends.len();
+    backends.retain(|backend| backend.uuid != backend_uuid);
+    if backends.len() == linked {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("The instance isn't linked to this proxy"),
+        });
+    }
+    proxy.set_proxy_backends(&backends).await?;
+    Ok(Json(backends))
+}
+
+pub fn get_instance_proxy_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/links", get(get_links))
+        .route(
+            "/instance/:uuid/link/:backend_uuid",
+            post(link_backend).delete(unlink_backend),
+        )
+        .with_state(state)
+}
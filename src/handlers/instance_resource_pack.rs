@@ -0,0 +1,205 @@
+use axum::{
+    body::StreamBody,
+    extract::{DefaultBodyLimit, Multipart, Path, Query},
+    http,
+    routing::get,
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use headers::HeaderName;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    implementations::minecraft::{resource_pack::ResourcePackInfo, MinecraftInstance},
+    prelude::GameInstance,
+    traits::Capability,
+    types::InstanceUuid,
+    AppState,
+};
+
+// the limit enforced by the client since 1.18
+const MAX_RESOURCE_PACK_SIZE: u64 = 250 * 1024 * 1024;
+
+async fn get_minecraft_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<MinecraftInstance, Error> {
+    match state.instances.lock().await.get(uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => Ok(instance.clone()),
+        Some(_) => Err(Error::not_supported(Capability::SupportsResourcePacks)),
+        None => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UploadResourcePackQuery {
+    /// address players reach the core at, e.g. `https://mc.example.com:16662`,
+    /// defaults to the core's domain
+    public_url: Option<String>,
+}
+
+pub async fn get_resource_pack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<ResourcePackInfo>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.resource_pack_info().await?))
+}
+
+/// Replaces the hosted pack with the first file of the form and points server.properties at it
+pub async fn upload_resource_pack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<UploadResourcePackQuery>,
+    AuthBearer(token): AuthBearer,
+    mut multipart: Multipart,
+) -> Result<Json<ResourcePackInfo>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let base_url = match query.public_url {
+        Some(public_url) => public_url,
+        None => match state.global_settings.lock().await.domain() {
+            Some(domain) => format!("http://{}:16662", domain),
+            None => {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "No public url given and the core has no domain set, players wouldn't be able to download the pack"
+                    ),
+                })
+            }
+        },
+    };
+    if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Public url must start with http:// or https://"),
+        });
+    }
+    let mut instance = get_minecraft_instance(&state, &uuid).await?;
+
+    let mut field = match multipart.next_field().await {
+        Ok(Some(field)) => field,
+        _ => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Missing resource pack file"),
+            })
+        }
+    };
+    let dest = instance.path_to_resource_pack();
+    let part = dest.with_extension("zip.part");
+    let mut file = crate::util::fs::create(&part).await?;
+    let mut hasher = Sha1::new();
+    let mut size = 0_u64;
+    loop {
+        let chunk = match field.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                tokio::fs::remove_file(&part).await.ok();
+                return Err(eyre!(e).wrap_err("Failed to read chunk").into());
+            }
+        };
+        size += chunk.len() as u64;
+        if size > MAX_RESOURCE_PACK_SIZE {
+            tokio::fs::remove_file(&part).await.ok();
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Resource packs can be at most 250 MiB"),
+            });
+        }
+        hasher.update(&chunk);
+        if let Err(e) = file.write_all(&chunk).await {
+            tokio::fs::remove_file(&part).await.ok();
+            return Err(eyre!(e).wrap_err("Failed to write resource pack").into());
+        }
+    }
+    file.flush()
+        .await
+        .context("Failed to write resource pack")?;
+    drop(file);
+    crate::util::fs::rename(&part, &dest).await?;
+
+    let sha1 = format!("{:x}", hasher.finalize());
+    let url = format!(
+        "{}/api/v1/instance/{}/resource_pack/pack.zip",
+        base_url.trim_end_matches('/'),
+        uuid
+    );
+    instance
+        .set_resource_pack_properties(url.clone(), sha1.clone())
+        .await?;
+    Ok(Json(ResourcePackInfo { sha1, size, url }))
+}
+
+pub async fn remove_resource_pack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let mut instance = get_minecraft_instance(&state, &uuid).await?;
+    instance.remove_resource_pack().await?;
+    Ok(Json(()))
+}
+
+/// Unauthenticated since the game client downloads the pack itself
+pub async fn download_resource_pack(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+) -> Result<
+    (
+        [(HeaderName, String); 2],
+        StreamBody<ReaderStream<tokio::fs::File>>,
+    ),
+    Error,
+> {
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    let path = instance.path_to_resource_pack();
+    let file = tokio::fs::File::open(&path).await.map_err(|_| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Instance has no hosted resource pack"),
+    })?;
+    let len = file
+        .metadata()
+        .await
+        .context("Failed to read resource pack metadata")?
+        .len();
+    let headers = [
+        (http::header::CONTENT_TYPE, "application/zip".to_string()),
+        (http::header::CONTENT_LENGTH, len.to_string()),
+    ];
+    Ok((headers, StreamBody::new(ReaderStream::new(file))))
+}
+
+pub fn get_instance_resource_pack_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/resource_pack",
+            get(get_resource_pack)
+                .put(upload_resource_pack)
+                .delete(remove_resource_pack),
+        )
+        .layer(DefaultBodyLimit::disable())
+        .route(
+            "/instance/:uuid/resource_pack/pack.zip",
+            get(download_resource_pack),
+        )
+        .with_state(state)
+}
@@ -4,24 +4,88 @@ use axum::{
     Router,
 };
 
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
 use axum::Json;
 use axum_auth::AuthBearer;
 
 use color_eyre::eyre::eyre;
+use serde::Deserialize;
 use serde_json::{json, Value};
+use tracing::warn;
+use ts_rs::TS;
 
 use crate::{
     auth::user::UserAction,
+    console_history::record_command,
+    console_policy::validate_command,
     error::{Error, ErrorKind},
-    events::CausedBy,
+    event_broadcaster::EventBroadcaster,
+    events::{CausedBy, Event, ProgressionEventID},
+    prelude::GameInstance,
+    request_metrics::timed_lock,
+    scheduler::{countdown, validate_warnings},
     types::InstanceUuid,
+    uptime::{UptimeLog, UptimeReport},
 };
 
 use crate::{
-    traits::{t_configurable::TConfigurable, t_server::TServer},
+    traits::{
+        t_configurable::TConfigurable,
+        t_server::{State, TServer},
+    },
     AppState,
 };
 
+lazy_static::lazy_static! {
+    /// Instances counting down to a graceful restart
+    static ref RESTARTING_INSTANCES: Mutex<HashSet<InstanceUuid>> = Mutex::new(HashSet::new());
+}
+
+/// Keeps a second graceful restart from starting while one counts down
+struct RestartGuard(InstanceUuid);
+
+impl RestartGuard {
+    fn acquire(uuid: &InstanceUuid) -> Result<Self, Error> {
+        if !RESTARTING_INSTANCES.lock().unwrap().insert(uuid.clone()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The instance is already restarting"),
+            });
+        }
+        Ok(Self(uuid.clone()))
+    }
+}
+
+impl Drop for RestartGuard {
+    fn drop(&mut self) {
+        RESTARTING_INSTANCES.lock().unwrap().remove(&self.0);
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, TS)]
+#[ts(export)]
+pub struct GracefulRestart {
+    /// `say Restarting in 5m` goes out each of these many seconds before the
+    /// server stops
+    #[serde(default = "default_restart_warnings")]
+    pub warn_before_secs: Vec<u64>,
+}
+
+fn default_restart_warnings() -> Vec<u64> {
+    vec![300, 60, 10]
+}
+
+impl Default for GracefulRestart {
+    fn default() -> Self {
+        Self {
+            warn_before_secs: default_restart_warnings(),
+        }
+    }
+}
+
 pub async fn start_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -33,7 +97,7 @@ pub async fn start_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    let mut instance_list = state.instances.lock().await;
+    let mut instance_list = timed_lock("instances", &state.instances).await;
     let instance = instance_list.get_mut(&uuid).ok_or_else(|| Error {
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
@@ -48,6 +112,8 @@ pub async fn start_instance(
     }
 
     instance.start(caused_by, false).await?;
+    // started by hand, it no longer waits for its turn to auto start
+    state.start_queue.lock().await.remove(&uuid);
     Ok(Json(()))
 }
 
@@ -62,9 +128,7 @@ pub async fn stop_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    state
-        .instances
-        .lock()
+    timed_lock("instances", &state.instances)
         .await
         .get_mut(&uuid)
         .ok_or_else(|| Error {
@@ -89,7 +153,7 @@ pub async fn restart_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    let mut instance_list = state.instances.lock().await;
+    let mut instance_list = timed_lock("instances", &state.instances).await;
     let instance = instance_list.get_mut(&uuid).ok_or_else(|| Error {
         kind: ErrorKind::NotFound,
         source: eyre!("Instance not found"),
@@ -99,6 +163,124 @@ pub async fn restart_instance(
     Ok(Json(()))
 }
 
+/// Counts down in chat, then stops and starts the server. Returns once the
+/// countdown began, the progression event follows the rest.
+pub async fn graceful_restart_instance(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    restart: Option<Json<GracefulRestart>>,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester
+        .try_action(&UserAction::StopInstance(uuid.clone()))
+        .and_then(|_x| requester.try_action(&UserAction::StartInstance(uuid.clone())))?;
+    let mut warn_before_secs = restart
+        .map(|Json(r)| r)
+        .unwrap_or_default()
+        .warn_before_secs;
+    validate_warnings(&warn_before_secs)?;
+    warn_before_secs.sort_unstable_by(|a, b| b.cmp(a));
+    warn_before_secs.dedup();
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let instance = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    if instance.state().await != State::Running {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The instance is not running"),
+        });
+    }
+    let guard = RestartGuard::acquire(&uuid)?;
+    let name = instance.name().await;
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Restarting {}", name),
+        Some((warn_before_secs.len() + 2) as f64),
+        None,
+        caused_by.clone(),
+    );
+    state.event_broadcaster.send(progression_start_event);
+    tokio::spawn(async move {
+        let _guard = guard;
+        let res = graceful_restart(
+            instance,
+            &warn_before_secs,
+            caused_by,
+            &state.event_broadcaster,
+            &event_id,
+        )
+        .await;
+        if let Err(e) = &res {
+            warn!("Graceful restart of instance {} failed: {}", uuid, e);
+        }
+        state
+            .event_broadcaster
+            .send(Event::new_progression_event_end(
+                event_id,
+                res.is_ok(),
+                Some(&match &res {
+                    Ok(_) => format!("Restarted {}", name),
+                    Err(e) => format!("Failed to restart {}: {}", name, e),
+                }),
+                None,
+            ));
+    });
+    Ok(Json(()))
+}
+
+/// `warn_before_secs` is sorted longest first
+async fn graceful_restart(
+    mut instance: GameInstance,
+    warn_before_secs: &[u64],
+    caused_by: CausedBy,
+    event_broadcaster: &EventBroadcaster,
+    event_id: &ProgressionEventID,
+) -> Result<(), Error> {
+    let not_running = || Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("The instance stopped before the restart"),
+    };
+    let mut ahead = warn_before_secs.first().copied().unwrap_or(0);
+    for secs in warn_before_secs {
+        tokio::time::sleep(Duration::from_secs(ahead - secs)).await;
+        ahead = *secs;
+        if instance.state().await != State::Running {
+            return Err(not_running());
+        }
+        let warning = format!("Restarting in {}", countdown(*secs));
+        instance
+            .send_command(&format!("say {}", warning), CausedBy::System)
+            .await?;
+        event_broadcaster.send(Event::new_progression_event_update(event_id, warning, 1.0));
+    }
+    tokio::time::sleep(Duration::from_secs(ahead)).await;
+    if instance.state().await != State::Running {
+        return Err(not_running());
+    }
+    instance.stop(caused_by.clone(), true).await?;
+    event_broadcaster.send(Event::new_progression_event_update(
+        event_id,
+        "Stopped, starting again",
+        1.0,
+    ));
+    instance.start(caused_by, true).await?;
+    event_broadcaster.send(Event::new_progression_event_update(
+        event_id, "Started", 1.0,
+    ));
+    Ok(())
+}
+
 pub async fn kill_instance(
     axum::extract::State(state): axum::extract::State<AppState>,
     Path(uuid): Path<InstanceUuid>,
@@ -110,9 +292,7 @@ pub async fn kill_instance(
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    state
-        .instances
-        .lock()
+    timed_lock("instances", &state.instances)
         .await
         .get_mut(&uuid)
         .ok_or_else(|| Error {
@@ -132,22 +312,34 @@ pub async fn send_command(
 ) -> Result<Json<()>, Error> {
     let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
     requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+    let command = validate_command(&command)?;
+    state
+        .global_settings
+        .lock()
+        .await
+        .console_policy()
+        .check(&requester, command)?;
     let caused_by = CausedBy::User {
         user_id: requester.uid.clone(),
         user_name: requester.username.clone(),
     };
-    state
-        .instances
-        .lock()
-        .await
-        .get_mut(&uuid)
-        .ok_or_else(|| Error {
+    let path = {
+        let mut instances = timed_lock("instances", &state.instances).await;
+        let instance = instances.get_mut(&uuid).ok_or_else(|| Error {
             kind: ErrorKind::NotFound,
             source: eyre!("Instance not found"),
-        })?
-        .send_command(&command, caused_by)
-        .await
-        .map(|_| Json(()))
+        })?;
+        instance.send_command(command, caused_by).await?;
+        instance.path().await
+    };
+    // the command already ran, a history that can't be written isn't worth failing over
+    if let Err(e) = record_command(&path, &requester.uid, command).await {
+        warn!(
+            "Failed to record console history of instance {}: {}",
+            uuid, e
+        );
+    }
+    Ok(Json(()))
 }
 
 pub async fn get_instance_state(
@@ -177,13 +369,58 @@ pub async fn get_instance_state(
     )))
 }
 
+/// Availability over the last day, week and month, from the instance's state changes
+pub async fn get_instance_uptime(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<UptimeReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    let log = UptimeLog::load(&path).await?;
+    Ok(Json(log.report(chrono::Utc::now().timestamp())))
+}
+
 pub fn get_instance_server_routes(state: AppState) -> Router {
     Router::new()
         .route("/instance/:uuid/start", put(start_instance))
         .route("/instance/:uuid/stop", put(stop_instance))
-        .route("/instance/:uuid/restart", put(restart_instance))
+        .route(
+            "/instance/:uuid/restart",
+            put(restart_instance).post(graceful_restart_instance),
+        )
         .route("/instance/:uuid/kill", put(kill_instance))
         .route("/instance/:uuid/console", post(send_command))
         .route("/instance/:uuid/state", get(get_instance_state))
+        .route("/instance/:uuid/uptime", get(get_instance_uptime))
         .with_state(state)
 }
+
+#[test]
+fn test_graceful_restart() {
+    let uuid = InstanceUuid::from("graceful_restart".to_string());
+    let guard = RestartGuard::acquire(&uuid).unwrap();
+    assert!(matches!(
+        RestartGuard::acquire(&uuid).unwrap_err().kind,
+        ErrorKind::BadRequest
+    ));
+    drop(guard);
+    assert!(RestartGuard::acquire(&uuid).is_ok());
+
+    let restart: GracefulRestart = serde_json::from_str("{}").unwrap();
+    assert_eq!(restart.warn_before_secs, vec![300, 60, 10]);
+    assert!(validate_warnings(&restart.warn_before_secs).is_ok());
+    assert!(validate_warnings(&[0]).is_err());
+    assert!(validate_warnings(&[60 * 60 + 1]).is_err());
+}
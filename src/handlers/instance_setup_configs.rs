@@ -1,5 +1,6 @@
 use crate::error::Error;
 use crate::error::ErrorKind;
+use crate::host::{self, HostInfo};
 use crate::implementations::generic;
 use crate::implementations::minecraft;
 use crate::minecraft::FlavourKind;
@@ -17,13 +18,17 @@ use serde::Serialize;
 use ts_rs::TS;
 
 #[allow(clippy::enum_variant_names)]
-#[derive(Serialize, Deserialize, TS, Clone, Copy)]
+#[derive(Debug, Serialize, Deserialize, TS, Clone, Copy, PartialEq, Eq)]
 #[ts(export)]
 pub enum HandlerGameType {
     MinecraftJavaVanilla,
     MinecraftFabric,
     MinecraftForge,
+    MinecraftNeoForge,
     MinecraftPaper,
+    MinecraftPurpur,
+    MinecraftVelocity,
+    MinecraftBungeeCord,
     MinecraftBedrock,
 }
 
@@ -33,7 +38,11 @@ impl From<HandlerGameType> for GameType {
             HandlerGameType::MinecraftJavaVanilla => Self::MinecraftJava,
             HandlerGameType::MinecraftFabric => Self::MinecraftJava,
             HandlerGameType::MinecraftForge => Self::MinecraftJava,
+            HandlerGameType::MinecraftNeoForge => Self::MinecraftJava,
             HandlerGameType::MinecraftPaper => Self::MinecraftJava,
+            HandlerGameType::MinecraftPurpur => Self::MinecraftJava,
+            HandlerGameType::MinecraftVelocity => Self::MinecraftJava,
+            HandlerGameType::MinecraftBungeeCord => Self::MinecraftJava,
             HandlerGameType::MinecraftBedrock => Self::MinecraftBedrock,
         }
     }
@@ -47,7 +56,11 @@ impl TryFrom<HandlerGameType> for FlavourKind {
             HandlerGameType::MinecraftJavaVanilla => Self::Vanilla,
             HandlerGameType::MinecraftFabric => Self::Fabric,
             HandlerGameType::MinecraftForge => Self::Forge,
+            HandlerGameType::MinecraftNeoForge => Self::NeoForge,
             HandlerGameType::MinecraftPaper => Self::Paper,
+            HandlerGameType::MinecraftPurpur => Self::Purpur,
+            HandlerGameType::MinecraftVelocity => Self::Velocity,
+            HandlerGameType::MinecraftBungeeCord => Self::BungeeCord,
             HandlerGameType::MinecraftBedrock => {
                 return Err(Error {
                     kind: ErrorKind::BadRequest,
@@ -58,13 +71,99 @@ impl TryFrom<HandlerGameType> for FlavourKind {
     }
 }
 
+const AVAILABLE_GAMES: [HandlerGameType; 8] = [
+    HandlerGameType::MinecraftJavaVanilla,
+    HandlerGameType::MinecraftFabric,
+    HandlerGameType::MinecraftForge,
+    HandlerGameType::MinecraftNeoForge,
+    HandlerGameType::MinecraftPaper,
+    HandlerGameType::MinecraftPurpur,
+    HandlerGameType::MinecraftVelocity,
+    HandlerGameType::MinecraftBungeeCord,
+];
+
+/// What an instance of a game type supports, so the frontend can tell which
+/// pages and settings apply to it
+#[derive(Serialize, TS, Clone)]
+#[ts(export)]
+pub struct GameTypeCapabilities {
+    pub game_type: HandlerGameType,
+    pub game: GameType,
+    /// loads mods from `mods/`
+    pub mods: bool,
+    /// loads Bukkit plugins from `plugins/`
+    pub plugins: bool,
+    pub rcon: bool,
+    /// answers GameSpy4 queries when `enable-query` is set
+    pub query: bool,
+    /// Geyser can be installed, as a plugin or a mod, to let Bedrock clients join
+    pub geyser: bool,
+    /// settings are read from and written to `server.properties`
+    pub server_properties: bool,
+    /// sends players on to other instances linked with `/instance/:uuid/link`
+    pub proxy: bool,
+    /// the server, or the Java runtime it needs, can be downloaded for this host
+    pub supported_on_host: bool,
+    /// route of the setup manifest describing the config of a new instance
+    pub setup_manifest: String,
+}
+
+impl HandlerGameType {
+    pub fn capabilities(self) -> GameTypeCapabilities {
+        let (mods, plugins, geyser) = match self {
+            HandlerGameType::MinecraftJavaVanilla => (false, false, false),
+            HandlerGameType::MinecraftFabric => (true, false, true),
+            HandlerGameType::MinecraftForge => (true, false, false),
+            HandlerGameType::MinecraftNeoForge => (true, false, false),
+            HandlerGameType::MinecraftPaper => (false, true, true),
+            HandlerGameType::MinecraftPurpur => (false, true, true),
+            // proxy plugins aren't Bukkit plugins
+            HandlerGameType::MinecraftVelocity => (false, false, false),
+            HandlerGameType::MinecraftBungeeCord => (false, false, false),
+            HandlerGameType::MinecraftBedrock => (false, false, false),
+        };
+        let proxy = matches!(
+            self,
+            HandlerGameType::MinecraftVelocity | HandlerGameType::MinecraftBungeeCord
+        );
+        let java = matches!(GameType::from(self), GameType::MinecraftJava);
+        GameTypeCapabilities {
+            game_type: self,
+            game: self.into(),
+            mods,
+            plugins,
+            rcon: java && !proxy,
+            query: java && !proxy,
+            geyser,
+            server_properties: !proxy,
+            proxy,
+            supported_on_host: if java {
+                host::jre_arch().is_some()
+            } else {
+                HostInfo::detect().runs_bedrock()
+            },
+            setup_manifest: format!(
+                "/setup_manifest/{}",
+                serde_json::to_value(self)
+                    .ok()
+                    .and_then(|v| v.as_str().map(str::to_string))
+                    .unwrap_or_default()
+            ),
+        }
+    }
+}
+
 pub async fn get_available_games() -> Json<Vec<HandlerGameType>> {
-    Json(vec![
-        HandlerGameType::MinecraftJavaVanilla,
-        HandlerGameType::MinecraftFabric,
-        HandlerGameType::MinecraftForge,
-        HandlerGameType::MinecraftPaper,
-    ])
+    Json(AVAILABLE_GAMES.to_vec())
+}
+
+pub async fn get_game_type_capabilities() -> Json<Vec<GameTypeCapabilities>> {
+    Json(
+        AVAILABLE_GAMES
+            .iter()
+            .map(|game_type| game_type.capabilities())
+            .collect(),
+    )
 }
 
 pub async fn get_setup_manifest(
@@ -92,7 +191,31 @@ pub async fn get_generic_setup_manifest(
 pub fn get_instance_setup_config_routes(appstate: AppState) -> Router {
     Router::new()
         .route("/games", get(get_available_games))
+        .route("/gametypes", get(get_game_type_capabilities))
         .route("/setup_manifest/:game_type", get(get_setup_manifest))
         .route("/generic_setup_manifest", put(get_generic_setup_manifest))
         .with_state(appstate)
 }
+
+#[test]
+fn test_capabilities() {
+    let paper = HandlerGameType::MinecraftPaper.capabilities();
+    assert!(paper.plugins && !paper.mods && paper.geyser);
+    assert_eq!(paper.setup_manifest, "/setup_manifest/MinecraftPaper");
+    let purpur = HandlerGameType::MinecraftPurpur.capabilities();
+    assert!(
+        purpur.plugins
+            && matches!(
+                FlavourKind::try_from(purpur.game_type),
+                Ok(FlavourKind::Purpur)
+            )
+    );
+    let neoforge = HandlerGameType::MinecraftNeoForge.capabilities();
+    assert!(neoforge.mods && !neoforge.plugins);
+    let velocity = HandlerGameType::MinecraftVelocity.capabilities();
+    assert!(velocity.proxy && !velocity.rcon && !velocity.server_properties);
+    let bedrock = HandlerGameType::MinecraftBedrock.capabilities();
+    assert!(!bedrock.rcon && !bedrock.query && !bedrock.proxy);
+    #[cfg(target_arch = "x86_64")]
+    assert!(paper.supported_on_host);
+}
@@ -0,0 +1,143 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    votifier::{vote_stats, Vote, VoteLog, VoteRewardsConfig, VoteStats},
+    AppState,
+};
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+pub async fn get_vote_rewards(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<VoteRewardsConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(VoteRewardsConfig::load_or_init(&path).await?))
+}
+
+pub async fn set_vote_rewards(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut config): Json<VoteRewardsConfig>,
+) -> Result<Json<VoteRewardsConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    config.validate()?;
+    let path = get_instance_path(&state, &uuid).await?;
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+/// Voting sites have to be given the new token
+pub async fn regenerate_vote_token(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<VoteRewardsConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let mut config = VoteRewardsConfig::load_or_init(&path).await?;
+    config.token = rand_alphanumeric(26);
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+#[derive(Deserialize)]
+pub struct VotesQuery {
+    limit: Option<usize>,
+}
+
+/// Most recent votes first
+pub async fn get_votes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<VotesQuery>,
+) -> Result<Json<Vec<Vote>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(
+        VoteLog::load(&path)
+            .await?
+            .votes
+            .into_iter()
+            .rev()
+            .take(query.limit.unwrap_or(100))
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct VoteStatsQuery {
+    /// only count the last this many days, all recorded votes by default
+    days: Option<u32>,
+    top: Option<usize>,
+}
+
+pub async fn get_vote_stats(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<VoteStatsQuery>,
+) -> Result<Json<VoteStats>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    let since = match query.days {
+        Some(days) => chrono::Utc::now().timestamp() - days as i64 * 86400,
+        None => i64::MIN,
+    };
+    Ok(Json(vote_stats(
+        &VoteLog::load(&path).await?.votes,
+        since,
+        query.top.unwrap_or(10),
+    )))
+}
+
+pub fn get_instance_votes_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/votes", get(get_votes))
+        .route("/instance/:uuid/votes/stats", get(get_vote_stats))
+        .route(
+            "/instance/:uuid/votes/rewards",
+            get(get_vote_rewards).put(set_vote_rewards),
+        )
+        .route(
+            "/instance/:uuid/votes/rewards/token",
+            post(regenerate_vote_token),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,67 @@
+use axum::{extract::Path, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    vpn_detection::VpnCheckConfig,
+    AppState,
+};
+
+pub async fn get_vpn_check(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<VpnCheckConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    Ok(Json(VpnCheckConfig::load(&path).await?))
+}
+
+/// Applies to players joining from now on
+pub async fn set_vpn_check(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<VpnCheckConfig>,
+) -> Result<Json<VpnCheckConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    config.validate()?;
+    let path = state
+        .instances
+        .lock()
+        .await
+        .get(&uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await;
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+pub fn get_instance_vpn_check_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/vpn_check",
+            get(get_vpn_check).put(set_vpn_check),
+        )
+        .with_state(state)
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+
+use axum::{
+    body::{Bytes, StreamBody},
+    extract::{Path, RawQuery},
+    http::{header, HeaderMap, Method},
+    response::Response,
+    routing::{any, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::{eyre, Context};
+use serde::Serialize;
+use ts_rs::TS;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    traits::t_configurable::TConfigurable,
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    web_map::{allowed_port, detect_web_map_port, WebMapConfig},
+    AppState,
+};
+
+/// how long a session key handed to the dashboard stays valid, in seconds
+const SESSION_DURATION: i64 = 12 * 60 * 60;
+
+// headers that only apply to a single hop and must not be forwarded
+const HOP_BY_HOP_HEADERS: [header::HeaderName; 8] = [
+    header::CONNECTION,
+    header::HOST,
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct WebMapStatus {
+    pub config: WebMapConfig,
+    pub detected_port: Option<u16>,
+}
+
+async fn get_instance_path(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<std::path::PathBuf, Error> {
+    Ok(state
+        .instances
+        .lock()
+        .await
+        .get(uuid)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?
+        .path()
+        .await)
+}
+
+pub async fn get_web_map(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<WebMapStatus>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    Ok(Json(WebMapStatus {
+        config: WebMapConfig::load(&path).await?,
+        detected_port: detect_web_map_port(&path),
+    }))
+}
+
+pub async fn set_web_map(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(config): Json<WebMapConfig>,
+) -> Result<Json<WebMapConfig>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let path = get_instance_path(&state, &uuid).await?;
+    if config.port.is_some() {
+        let taken = state.port_manager.lock().await.allocated_ports();
+        allowed_port(config.port, detect_web_map_port(&path), &taken)?;
+    }
+    config.save(&path).await?;
+    Ok(Json(config))
+}
+
+/// Hands out a key to embed in the map url, since the browser loads the map's assets
+/// without the bearer token
+pub async fn create_web_map_session(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<String, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    get_instance_path(&state, &uuid).await?;
+    let key = rand_alphanumeric(32);
+    let now = chrono::Utc::now().timestamp();
+    let mut sessions = state.web_map_sessions.lock().await;
+    sessions.retain(|_, (_, expires)| *expires > now);
+    sessions.insert(key.clone(), (uuid, now + SESSION_DURATION));
+    Ok(key)
+}
+
+/// Forwards the request to the map plugin's web server on localhost.
+/// `key` is either a session key or `public` if the instance opted into a public map
+pub async fn proxy_web_map(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(params): Path<HashMap<String, String>>,
+    method: Method,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    body: Bytes,
+) -> Result<Response, Error> {
+    let (uuid, key) = match (params.get("uuid"), params.get("key")) {
+        (Some(uuid), Some(key)) => (InstanceUuid::from(uuid.clone()), key.clone()),
+        _ => {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Missing path parameters"),
+            })
+        }
+    };
+    // the root route has no wildcard
+    let path = params.get("path").cloned().unwrap_or_default();
+    let path_to_instance = get_instance_path(&state, &uuid).await?;
+    let config = WebMapConfig::load(&path_to_instance).await?;
+    if !config.enabled {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Web map is not enabled for this instance"),
+        });
+    }
+    let authorized = if key == "public" {
+        // nobody is accountable for what a public request does, so it can only read
+        config.public && (method == Method::GET || method == Method::HEAD)
+    } else {
+        matches!(
+            state.web_map_sessions.lock().await.get(&key),
+            Some((session_uuid, expires))
+                if *session_uuid == uuid && *expires > chrono::Utc::now().timestamp()
+        )
+    };
+    if !authorized {
+        return Err(Error {
+            kind: ErrorKind::Unauthorized,
+            source: eyre!("Invalid or expired web map session"),
+        });
+    }
+    // checked on every request, the plugin's config may have changed since
+    let taken = state.port_manager.lock().await.allocated_ports();
+    let port = allowed_port(config.port, detect_web_map_port(&path_to_instance), &taken)?;
+
+    let mut url = format!("http://127.0.0.1:{}/{}", port, path);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(&query);
+    }
+    let mut forwarded_headers = headers;
+    for name in HOP_BY_HOP_HEADERS.iter() {
+        forwarded_headers.remove(name);
+    }
+    forwarded_headers.remove(header::AUTHORIZATION);
+    forwarded_headers.remove(header::CONTENT_LENGTH);
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .context("Failed to build http client")?;
+    let response = client
+        .request(method, &url)
+        .headers(forwarded_headers)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| Error {
+            kind: ErrorKind::Internal,
+            source: eyre!("Failed to reach the web map on port {}: {}", port, e),
+        })?;
+
+    let mut builder = Response::builder().status(response.status());
+    for (name, value) in response.headers().iter() {
+        if HOP_BY_HOP_HEADERS.contains(name) {
+            continue;
+        }
+        // keep absolute redirects from the map inside the proxied prefix
+        if *name == header::LOCATION {
+            if let Some(location) = value.to_str().ok().and_then(|v| v.strip_prefix('/')) {
+                builder = builder.header(
+                    name,
+                    format!("/api/v1/instance/{}/map/{}/{}", uuid, key, location),
+                );
+                continue;
+            }
+        }
+        builder = builder.header(name, value);
+    }
+    Ok(builder
+        .body(axum::body::boxed(StreamBody::new(response.bytes_stream())))
+        .context("Failed to build web map response")?)
+}
+
+pub fn get_instance_web_map_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/instance/:uuid/map", get(get_web_map).put(set_web_map))
+        .route("/instance/:uuid/map/session", post(create_web_map_session))
+        .route("/instance/:uuid/map/:key/", any(proxy_web_map))
+        .route("/instance/:uuid/map/:key/*path", any(proxy_web_map))
+        .with_state(state)
+}
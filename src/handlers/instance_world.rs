@@ -0,0 +1,161 @@
+use axum::{
+    extract::{Path, Query},
+    routing::{delete, get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    events::CausedBy,
+    implementations::minecraft::{
+        world::{
+            Dimension, RegionPruneOptions, RegionPruneReport, WorldResetPolicy, WorldScanReport,
+        },
+        MinecraftInstance,
+    },
+    prelude::GameInstance,
+    traits::Capability,
+    types::InstanceUuid,
+    AppState,
+};
+
+async fn get_minecraft_instance(
+    state: &AppState,
+    uuid: &InstanceUuid,
+) -> Result<MinecraftInstance, Error> {
+    match state.instances.lock().await.get(uuid) {
+        Some(GameInstance::MinecraftInstance(instance)) => Ok(instance.clone()),
+        Some(_) => Err(Error::not_supported(Capability::SupportsWorlds)),
+        None => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        }),
+    }
+}
+
+pub async fn get_world_reset_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<WorldResetPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.world_reset_policy().await?))
+}
+
+pub async fn set_world_reset_policy(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut policy): Json<WorldResetPolicy>,
+) -> Result<Json<WorldResetPolicy>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    // last_reset is managed by the core
+    policy.last_reset = instance.world_reset_policy().await?.last_reset;
+    instance.set_world_reset_policy(&policy).await?;
+    Ok(Json(policy))
+}
+
+pub async fn reset_world(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let caused_by = CausedBy::User {
+        user_id: requester.uid.clone(),
+        user_name: requester.username.clone(),
+    };
+    let mut instance = get_minecraft_instance(&state, &uuid).await?;
+    tokio::task::spawn(async move {
+        // the outcome is reported through progression events
+        let _ = instance.reset_world(caused_by).await;
+    });
+    Ok(Json(()))
+}
+
+pub async fn list_dimensions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Dimension>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.list_dimensions().await?))
+}
+
+pub async fn reset_dimension(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, dimension_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    instance.reset_dimension(&dimension_id).await?;
+    Ok(Json(()))
+}
+
+pub async fn prune_regions(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(options): Json<RegionPruneOptions>,
+) -> Result<Json<RegionPruneReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if options.dry_run {
+        requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    } else {
+        requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    }
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.prune_regions(options).await?))
+}
+
+#[derive(Deserialize)]
+pub struct ScanWorldQuery {
+    #[serde(default)]
+    quarantine: bool,
+}
+
+pub async fn scan_world(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    Query(query): Query<ScanWorldQuery>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<WorldScanReport>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if query.quarantine {
+        requester.try_action(&UserAction::WriteInstanceFile(uuid.clone()))?;
+    } else {
+        requester.try_action(&UserAction::ReadInstanceFile(uuid.clone()))?;
+    }
+    let instance = get_minecraft_instance(&state, &uuid).await?;
+    Ok(Json(instance.scan_world(query.quarantine).await?))
+}
+
+pub fn get_instance_world_routes(state: AppState) -> Router {
+    Router::new()
+        .route(
+            "/instance/:uuid/world/reset_policy",
+            get(get_world_reset_policy).put(set_world_reset_policy),
+        )
+        .route("/instance/:uuid/world/reset", post(reset_world))
+        .route("/instance/:uuid/world/dimensions", get(list_dimensions))
+        .route(
+            "/instance/:uuid/world/dimensions/:dimension_id",
+            delete(reset_dimension),
+        )
+        .route("/instance/:uuid/world/prune", post(prune_regions))
+        .route("/instance/:uuid/world/scan", post(scan_world))
+        .with_state(state)
+}
@@ -0,0 +1,57 @@
+use axum::{
+    extract::Path,
+    routing::{get, post},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    java_manager::{self, JavaRuntime, RuntimeVendor},
+    AppState,
+};
+
+/// Every runtime found on the host, newest Java first
+pub async fn get_java_runtimes(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<JavaRuntime>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
+    Ok(Json(java_manager::detect_runtimes().await))
+}
+
+#[derive(Deserialize)]
+pub struct RuntimeDownload {
+    vendor: Option<RuntimeVendor>,
+}
+
+/// Downloads a runtime for the major Java version, unless there already is one
+pub async fn download_java_runtime(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(major): Path<u64>,
+    AuthBearer(token): AuthBearer,
+    download: Option<Json<RuntimeDownload>>,
+) -> Result<Json<JavaRuntime>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::CreateInstance)?;
+    if !(8..=99).contains(&major) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("There is no Java {}", major),
+        });
+    }
+    let vendor = download.and_then(|Json(download)| download.vendor);
+    let java = java_manager::ensure_runtime(major, vendor, &|_| {}).await?;
+    Ok(Json(java_manager::probe(&java).await?))
+}
+
+pub fn get_java_runtimes_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/java/runtimes", get(get_java_runtimes))
+        .route("/java/runtimes/:major", post(download_java_runtime))
+        .with_state(state)
+}
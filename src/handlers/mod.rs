@@ -1,21 +1,51 @@
 // pub mod jar;
 // pub mod instance;
+pub mod instance_backups;
 // pub mod users;
+pub mod alerts;
+pub mod badges;
 pub mod checks;
 pub mod core_info;
+pub mod database_hosts;
 pub mod events;
 pub mod gateway;
+pub mod global_bans;
 pub mod global_fs;
 pub mod global_settings;
 pub mod instance;
+pub mod instance_api_tokens;
+pub mod instance_changelog;
+pub mod instance_chat;
 pub mod instance_config;
+pub mod instance_console;
+pub mod instance_crashes;
+pub mod instance_databases;
+pub mod instance_definitions;
 pub mod instance_fs;
+pub mod instance_ip_access;
+pub mod instance_log_triggers;
 pub mod instance_macro;
+pub mod instance_migration;
+pub mod instance_mods;
+pub mod instance_notes;
 pub mod instance_players;
+pub mod instance_proxy;
+pub mod instance_resource_pack;
 pub mod instance_server;
 pub mod instance_setup_configs;
+pub mod instance_votes;
+pub mod instance_vpn_check;
+pub mod instance_web_map;
+pub mod instance_world;
+pub mod java_runtimes;
 pub mod monitor;
+pub mod players;
+pub mod presets;
+pub mod scheduled_tasks;
 pub mod setup;
 pub mod system;
 pub mod users;
 mod util;
+pub mod webhooks;
+mod ws_auth;
+mod ws_protocol;
@@ -1,25 +1,33 @@
 use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-    extract::{ws::WebSocket, Path, WebSocketUpgrade},
+    extract::{ws::WebSocket, Path, Query, WebSocketUpgrade},
     response::Response,
     routing::get,
-    Router,
+    Json, Router,
 };
+use axum_auth::AuthBearer;
 use color_eyre::eyre::eyre;
 use futures::{SinkExt, StreamExt};
 use ringbuffer::{AllocRingBuffer, RingBufferExt};
+use serde::Deserialize;
 use tokio::sync::Mutex;
 use tracing::error;
 
 use crate::{
-    error::Error,
+    auth::user::UserAction,
+    companion::{CompanionTelemetry, EntityDiagnostics},
+    db::metrics::MetricPoint,
+    error::{Error, ErrorKind},
+    metrics_history::resolution_for,
     prelude::GameInstance,
     traits::{t_server::MonitorReport, t_server::TServer},
     types::InstanceUuid,
     AppState,
 };
 
+use super::ws_protocol::ServerFrame;
+
 pub async fn monitor(
     ws: WebSocketUpgrade,
     axum::extract::State(state): axum::extract::State<AppState>,
@@ -55,9 +63,7 @@ async fn monitor_ws(
     if let Some(buffer) = monitor_buffer.lock().await.get(&uuid) {
         for report in buffer.iter() {
             if let Err(e) = tx
-                .send(axum::extract::ws::Message::Text(
-                    serde_json::to_string(&report).unwrap(),
-                ))
+                .send(ServerFrame::MonitorReport(report.clone()).to_message())
                 .await
             {
                 error!("1 Error sending monitor report: {}", e);
@@ -71,9 +77,7 @@ async fn monitor_ws(
             _ = interval.tick() => {
                 let monitor = instance.monitor().await;
                 if let Err(e) = tx
-                    .send(axum::extract::ws::Message::Text(
-                        serde_json::to_string(&monitor).unwrap(),
-                    ))
+                    .send(ServerFrame::MonitorReport(monitor).to_message())
                     .await
                 {
                     error!("2 Error sending monitor report: {}", e);
@@ -89,8 +93,102 @@ async fn monitor_ws(
     }
 }
 
+#[derive(Deserialize)]
+pub struct MetricsQuery {
+    /// unix seconds, an hour ago if left out
+    pub from: Option<i64>,
+    /// unix seconds, now if left out
+    pub to: Option<i64>,
+    /// seconds per point, picked from the range if left out
+    pub resolution: Option<i64>,
+}
+
+/// Recorded CPU, memory and player count of an instance over time, for graphs.
+/// Times the instance wasn't running have no points.
+pub async fn get_metrics_history(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<MetricsQuery>,
+) -> Result<Json<Vec<MetricPoint>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    if !state.instances.lock().await.contains_key(&uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().timestamp());
+    let from = query.from.unwrap_or(to - 60 * 60);
+    let interval_secs = state
+        .global_settings
+        .lock()
+        .await
+        .metrics_history()
+        .interval_secs;
+    let resolution = resolution_for(from, to, query.resolution, interval_secs)?;
+    Ok(Json(
+        state
+            .metrics_store
+            .query(&uuid, from, to, resolution)
+            .await?,
+    ))
+}
+
+/// The latest report of the instance's companion plugin or mod, `None` if it
+/// has none or it stopped reporting
+pub async fn get_companion_telemetry(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Option<CompanionTelemetry>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    Ok(Json(
+        state
+            .companion_reports
+            .lock()
+            .await
+            .latest(&uuid, chrono::Utc::now().timestamp()),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct EntityDiagnosticsQuery {
+    /// how many hotspots to return, 10 if left out
+    pub limit: Option<usize>,
+}
+
+/// Entity and chunk counts per world and the most entity dense chunks, as
+/// reported by the companion. `None` if the instance has no companion reporting.
+pub async fn get_entity_diagnostics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<EntityDiagnosticsQuery>,
+) -> Result<Json<Option<EntityDiagnostics>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::ViewInstance(uuid.clone()))?;
+    let limit = query.limit.unwrap_or(10).min(100);
+    Ok(Json(
+        state
+            .companion_reports
+            .lock()
+            .await
+            .latest(&uuid, chrono::Utc::now().timestamp())
+            .map(|telemetry| telemetry.entity_diagnostics(limit)),
+    ))
+}
+
 pub fn get_monitor_routes(state: AppState) -> Router {
     Router::new()
         .route("/monitor/:uuid", get(monitor))
+        .route("/instance/:uuid/metrics", get(get_metrics_history))
+        .route("/instance/:uuid/companion", get(get_companion_telemetry))
+        .route(
+            "/instance/:uuid/diagnostics/entities",
+            get(get_entity_diagnostics),
+        )
         .with_state(state)
 }
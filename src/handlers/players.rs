@@ -0,0 +1,41 @@
+use axum::{extract::Query, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use serde::Deserialize;
+
+use crate::{
+    auth::user::UserAction,
+    error::Error,
+    player_sessions::{find_alt_accounts, load_sessions, AltAccount},
+    traits::t_configurable::TConfigurable,
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub struct AltAccountQuery {
+    /// name or uuid
+    pub player: String,
+}
+
+/// Only sessions of instances whose settings the requester can access are considered,
+/// since they expose player addresses
+pub async fn get_alt_accounts(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Query(query): Query<AltAccountQuery>,
+) -> Result<Json<Vec<AltAccount>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let mut paths = Vec::new();
+    for (uuid, instance) in state.instances.lock().await.iter() {
+        if requester.can_perform_action(&UserAction::AccessSetting(uuid.clone())) {
+            paths.push((uuid.clone(), instance.path().await));
+        }
+    }
+    let sessions = load_sessions(paths).await;
+    Ok(Json(find_alt_accounts(&sessions, &query.player)))
+}
+
+pub fn get_players_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/players/alts", get(get_alt_accounts))
+        .with_state(state)
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+
+use axum::{routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use ts_rs::TS;
+
+use crate::{
+    backup::BackupPolicy,
+    error::{Error, ErrorKind},
+    handlers::{instance_definitions::mods_dir, instance_setup_configs::HandlerGameType},
+    implementations::minecraft::{modrinth, FlavourKind},
+    traits::t_configurable::{
+        manifest::{ConfigurableValue, SetupValue},
+        TConfigurable,
+    },
+    types::InstanceUuid,
+    AppState,
+};
+
+/// A server archetype a new instance can start from
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+#[ts(export)]
+pub struct Preset {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub game_type: HandlerGameType,
+    /// added to the JVM arguments of the setup request, unless it sets the same flag
+    #[serde(default)]
+    pub jvm_args: Vec<String>,
+    /// `server.properties` entries, the ones of the setup request win
+    #[serde(default)]
+    pub server_properties: BTreeMap<String, String>,
+    /// Modrinth projects, the newest release for the instance's version and
+    /// loader is downloaded once it's set up
+    #[serde(default)]
+    pub mods: Vec<String>,
+    /// cron expression of the backup policy
+    pub backup_schedule: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref PRESETS: Vec<Preset> = [
+        include_str!("presets/vanilla_smp.yaml"),
+        include_str!("presets/fabric_performance.yaml"),
+        include_str!("presets/paper_minigames.yaml"),
+    ]
+    .iter()
+    .map(|preset| serde_yaml::from_str(preset).expect("Invalid preset"))
+    .collect();
+}
+
+pub fn presets() -> &'static [Preset] {
+    &PRESETS
+}
+
+pub fn find_preset(id: &str) -> Result<&'static Preset, Error> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.id == id)
+        .ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("There is no preset {}", id),
+        })
+}
+
+/// `-XX:+AlwaysPreTouch` and `-XX:-AlwaysPreTouch` set the same flag
+fn flag_name(arg: &str) -> &str {
+    let name = arg.split('=').next().unwrap_or(arg);
+    match name.strip_prefix("-XX:") {
+        Some(flag) => flag.trim_start_matches(['+', '-']),
+        None => name,
+    }
+}
+
+fn merge_jvm_args(requested: &str, preset: &[String]) -> String {
+    let mut args: Vec<&str> = requested.split_whitespace().collect();
+    for arg in preset {
+        if !args.iter().any(|a| flag_name(a) == flag_name(arg)) {
+            args.push(arg);
+        }
+    }
+    args.join(" ")
+}
+
+impl Preset {
+    /// Adds the JVM flags and server properties of the preset to a setup request
+    pub fn apply_to_setup(
+        &self,
+        game_type: HandlerGameType,
+        setup_value: &mut SetupValue,
+    ) -> Result<(), Error> {
+        if game_type != self.game_type {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Preset {} sets up {:?} instances, not {:?}",
+                    self.id,
+                    self.game_type,
+                    game_type
+                ),
+            });
+        }
+        let string_setting = |id: &str| {
+            setup_value
+                .get_unique_setting(id)
+                .and_then(|setting| setting.get_value())
+                .and_then(|value| value.try_as_string().ok())
+                .cloned()
+                .unwrap_or_default()
+        };
+        let cmd_args = merge_jvm_args(&string_setting("cmd_args"), &self.jvm_args);
+        // later lines win, so the request's own properties go last
+        let mut server_properties: Vec<String> = self
+            .server_properties
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        server_properties.push(string_setting("server_properties"));
+        setup_value.set_unique_setting(
+            "section_2",
+            "cmd_args",
+            ConfigurableValue::String(cmd_args),
+        );
+        if !self.server_properties.is_empty() {
+            setup_value.set_unique_setting(
+                "section_2",
+                "server_properties",
+                ConfigurableValue::String(server_properties.join("\n").trim().to_string()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Downloads the mods of the preset and sets its backup schedule on an instance
+    /// that was just set up. A mod that can't be found doesn't stop the others.
+    pub async fn install(&self, state: &AppState, uuid: &InstanceUuid) -> Result<(), Error> {
+        let instance = state.instances.lock().await.get(uuid).cloned();
+        let instance = instance.ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+        let path = instance.path().await;
+        let version = instance.version().await;
+        let flavour = FlavourKind::try_from(self.game_type)?;
+        if let (Some(dir), Some(loader)) = (mods_dir(self.game_type), modrinth::loader_of(&flavour))
+        {
+            for (project, e) in
+                modrinth::install_projects(&self.mods, loader, &version, &path.join(dir)).await
+            {
+                error!("Failed to install {} on instance {}: {}", project, uuid, e);
+            }
+        }
+        if let Some(schedule) = &self.backup_schedule {
+            let mut policy = BackupPolicy::load(&path).await?;
+            policy.schedule = Some(schedule.clone());
+            policy.validate()?;
+            policy.save(&path).await?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn get_presets(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Preset>>, Error> {
+    state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(presets().to_vec()))
+}
+
+pub fn get_presets_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/presets", get(get_presets))
+        .with_state(state)
+}
+
+#[test]
+fn test_presets() {
+    assert_eq!(presets().len(), 3);
+    for preset in presets() {
+        assert!(FlavourKind::try_from(preset.game_type).is_ok());
+        let policy = BackupPolicy {
+            schedule: preset.backup_schedule.clone(),
+            ..Default::default()
+        };
+        policy.validate().unwrap();
+    }
+    assert!(find_preset("nope").is_err());
+
+    assert_eq!(
+        merge_jvm_args(
+            "-XX:+UseG1GC -XX:-AlwaysPreTouch -XX:G1ReservePercent=10",
+            &[
+                "-XX:+AlwaysPreTouch".to_string(),
+                "-XX:G1ReservePercent=20".to_string(),
+                "-XX:+DisableExplicitGC".to_string(),
+            ]
+        ),
+        "-XX:+UseG1GC -XX:-AlwaysPreTouch -XX:G1ReservePercent=10 -XX:+DisableExplicitGC"
+    );
+
+    let mut setup_value: SetupValue = serde_json::from_value(serde_json::json!({
+        "name": "Lobby",
+        "description": null,
+        "auto_start": false,
+        "restart_on_crash": false,
+        "setting_sections": {
+            "section_2": {
+                "settings": {
+                    "server_properties": { "value": { "type": "String", "value": "max-players=80" } },
+                }
+            },
+        },
+    }))
+    .unwrap();
+    let preset = find_preset("paper_minigames").unwrap();
+    assert!(preset
+        .apply_to_setup(HandlerGameType::MinecraftFabric, &mut setup_value.clone())
+        .is_err());
+    preset
+        .apply_to_setup(HandlerGameType::MinecraftPaper, &mut setup_value)
+        .unwrap();
+    let properties = setup_value
+        .get_unique_setting("server_properties")
+        .and_then(|setting| setting.get_value())
+        .unwrap()
+        .try_as_string()
+        .unwrap()
+        .clone();
+    // the request's value comes last, so it wins
+    assert!(properties.ends_with("max-players=80"));
+    assert!(properties.contains("allow-nether=false"));
+    assert!(setup_value.get_unique_setting("cmd_args").is_some());
+}
@@ -0,0 +1,263 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::{User, UserAction},
+    console_policy::validate_command,
+    error::{Error, ErrorKind},
+    scheduler::{NewScheduledTask, ScheduledAction, ScheduledTask},
+    types::InstanceUuid,
+    util::rand_alphanumeric,
+    AppState,
+};
+
+async fn check_instance_exists(state: &AppState, uuid: &InstanceUuid) -> Result<(), Error> {
+    if !state.instances.lock().await.contains_key(uuid) {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        });
+    }
+    Ok(())
+}
+
+/// A command task runs as the core, so the requester must be allowed to send
+/// the command themselves
+async fn check_command(
+    state: &AppState,
+    requester: &User,
+    uuid: &InstanceUuid,
+    new_task: &NewScheduledTask,
+) -> Result<(), Error> {
+    if let ScheduledAction::Command { command } = &new_task.action {
+        requester.try_action(&UserAction::AccessConsole(uuid.clone()))?;
+        state
+            .global_settings
+            .lock()
+            .await
+            .console_policy()
+            .check(requester, validate_command(command)?)?;
+    }
+    Ok(())
+}
+
+/// The task, if the requester can change the settings of its instance
+async fn get_owned_task(
+    state: &AppState,
+    requester: &User,
+    task_id: &str,
+) -> Result<ScheduledTask, Error> {
+    let task = state.scheduler.lock().await.get(task_id)?;
+    requester.try_action(&UserAction::AccessSetting(task.instance_uuid.clone()))?;
+    Ok(task)
+}
+
+async fn create_task(
+    state: &AppState,
+    requester: &User,
+    uuid: InstanceUuid,
+    new_task: NewScheduledTask,
+) -> Result<ScheduledTask, Error> {
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    new_task.validate()?;
+    check_instance_exists(state, &uuid).await?;
+    check_command(state, requester, &uuid, &new_task).await?;
+    let task = ScheduledTask::from_new(
+        new_task,
+        format!("TASK_{}", rand_alphanumeric(12)),
+        uuid,
+        requester.username.clone(),
+    );
+    state.scheduler.lock().await.set_task(task).await
+}
+
+/// A task moves to the instance in the request, if it names one. The run history
+/// is kept.
+async fn update_task(
+    state: &AppState,
+    requester: &User,
+    old: ScheduledTask,
+    new_task: NewScheduledTask,
+) -> Result<ScheduledTask, Error> {
+    new_task.validate()?;
+    let uuid = new_task
+        .instance_uuid
+        .clone()
+        .unwrap_or_else(|| old.instance_uuid.clone());
+    if uuid != old.instance_uuid {
+        requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+        check_instance_exists(state, &uuid).await?;
+    }
+    check_command(state, requester, &uuid, &new_task).await?;
+    let task = ScheduledTask {
+        last_run: old.last_run,
+        last_error: old.last_error,
+        ..ScheduledTask::from_new(new_task, old.id, uuid, old.created_by)
+    };
+    state.scheduler.lock().await.set_task(task).await
+}
+
+/// Tasks of every instance whose settings the requester can change, soonest first
+pub async fn get_tasks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ScheduledTask>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(
+        state
+            .scheduler
+            .lock()
+            .await
+            .list()
+            .into_iter()
+            .filter(|task| {
+                requester.can_perform_action(&UserAction::AccessSetting(task.instance_uuid.clone()))
+            })
+            .collect(),
+    ))
+}
+
+pub async fn create_core_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(new_task): Json<NewScheduledTask>,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let uuid = new_task.instance_uuid.clone().ok_or_else(|| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("A task needs an instance"),
+    })?;
+    Ok(Json(create_task(&state, &requester, uuid, new_task).await?))
+}
+
+pub async fn get_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(task_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    Ok(Json(get_owned_task(&state, &requester, &task_id).await?))
+}
+
+pub async fn update_core_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(task_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+    Json(new_task): Json<NewScheduledTask>,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let old = get_owned_task(&state, &requester, &task_id).await?;
+    Ok(Json(update_task(&state, &requester, old, new_task).await?))
+}
+
+pub async fn delete_core_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(task_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    get_owned_task(&state, &requester, &task_id).await?;
+    state.scheduler.lock().await.remove_task(&task_id).await?;
+    Ok(Json(()))
+}
+
+pub async fn get_instance_tasks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<ScheduledTask>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    Ok(Json(
+        state
+            .scheduler
+            .lock()
+            .await
+            .list()
+            .into_iter()
+            .filter(|task| task.instance_uuid == uuid)
+            .collect(),
+    ))
+}
+
+pub async fn create_instance_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(uuid): Path<InstanceUuid>,
+    AuthBearer(token): AuthBearer,
+    Json(mut new_task): Json<NewScheduledTask>,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if new_task
+        .instance_uuid
+        .take()
+        .map_or(false, |other| other != uuid)
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The task is for another instance"),
+        });
+    }
+    Ok(Json(create_task(&state, &requester, uuid, new_task).await?))
+}
+
+/// The task of another instance is not found, even if it exists
+async fn get_instance_task(
+    state: &AppState,
+    requester: &User,
+    uuid: &InstanceUuid,
+    task_id: &str,
+) -> Result<ScheduledTask, Error> {
+    requester.try_action(&UserAction::AccessSetting(uuid.clone()))?;
+    match state.scheduler.lock().await.get(task_id) {
+        Ok(task) if task.instance_uuid == *uuid => Ok(task),
+        _ => Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Scheduled task not found"),
+        }),
+    }
+}
+
+pub async fn update_instance_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, task_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+    Json(new_task): Json<NewScheduledTask>,
+) -> Result<Json<ScheduledTask>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let old = get_instance_task(&state, &requester, &uuid, &task_id).await?;
+    Ok(Json(update_task(&state, &requester, old, new_task).await?))
+}
+
+pub async fn delete_instance_task(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path((uuid, task_id)): Path<(InstanceUuid, String)>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    get_instance_task(&state, &requester, &uuid, &task_id).await?;
+    state.scheduler.lock().await.remove_task(&task_id).await?;
+    Ok(Json(()))
+}
+
+pub fn get_scheduled_tasks_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/tasks", get(get_tasks).post(create_core_task))
+        .route(
+            "/tasks/:task_id",
+            put(update_core_task).get(get_task).delete(delete_core_task),
+        )
+        .route(
+            "/instance/:uuid/tasks",
+            get(get_instance_tasks).post(create_instance_task),
+        )
+        .route(
+            "/instance/:uuid/tasks/:task_id",
+            put(update_instance_task).delete(delete_instance_task),
+        )
+        .with_state(state)
+}
@@ -1,10 +1,24 @@
-use axum::{routing::get, Json, Router};
+use std::collections::HashMap;
+use std::time::Duration;
+
+use axum::{http::header, routing::get, Json, Router};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
 use serde::{Deserialize, Serialize};
 use sysinfo::{CpuExt, CpuRefreshKind, DiskExt, SystemExt};
 
 use tokio::time::sleep;
+use ts_rs::TS;
 
-use crate::AppState;
+use crate::{
+    auth::user::UserAction,
+    error::{Error, ErrorKind},
+    prometheus,
+    request_metrics::RouteStats,
+    traits::{t_configurable::TConfigurable, t_server::State, t_server::TServer},
+    types::InstanceUuid,
+    AppState,
+};
 
 // Since MemInfo is not serializable, we need to create a new struct that is serializable.
 #[derive(Serialize, Deserialize)]
@@ -63,10 +77,250 @@ pub async fn get_cpu_info(
     })
 }
 
+#[derive(Serialize, TS)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum PortOwner {
+    Core,
+    Instance {
+        uuid: InstanceUuid,
+        name: String,
+        state: State,
+    },
+}
+
+#[derive(Serialize, TS, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum PortIssue {
+    /// the instance is running but nothing accepts connections on its port
+    NotListening,
+    /// the port is open locally but the public address doesn't reach it,
+    /// usually a firewall rule or a missing port forward
+    NotPubliclyReachable,
+    /// the instance is stopped but another process holds its port
+    TakenByOtherProcess,
+    /// another instance is configured with the same port
+    SharedWith { uuid: InstanceUuid },
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct PortAuditEntry {
+    pub port: u32,
+    pub owner: PortOwner,
+    pub listening: bool,
+    /// `None` if there is no public address to probe or nothing listens locally
+    pub publicly_reachable: Option<bool>,
+    pub issues: Vec<PortIssue>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export)]
+pub struct PortAudit {
+    /// the core's configured domain, ports are probed against it
+    pub public_address: Option<String>,
+    pub ports: Vec<PortAuditEntry>,
+}
+
+/// Only TCP is probed. Routers without NAT loopback report every port as unreachable
+/// when the core probes its own public address, so treat a fully red report with suspicion.
+async fn probe_tcp(address: &str, port: u32) -> bool {
+    matches!(
+        tokio::time::timeout(
+            Duration::from_secs(3),
+            tokio::net::TcpStream::connect((address, port as u16)),
+        )
+        .await,
+        Ok(Ok(_))
+    )
+}
+
+/// `sharing_port` holds every instance configured with the owner's port
+fn port_issues(
+    owner: &PortOwner,
+    listening: bool,
+    publicly_reachable: Option<bool>,
+    sharing_port: &[InstanceUuid],
+) -> Vec<PortIssue> {
+    let mut issues = Vec::new();
+    if let PortOwner::Instance { uuid, state, .. } = owner {
+        match state {
+            State::Running if !listening => issues.push(PortIssue::NotListening),
+            State::Stopped if listening => issues.push(PortIssue::TakenByOtherProcess),
+            _ => {}
+        }
+        for other in sharing_port {
+            if other != uuid {
+                issues.push(PortIssue::SharedWith {
+                    uuid: other.clone(),
+                });
+            }
+        }
+    }
+    if publicly_reachable == Some(false) {
+        issues.push(PortIssue::NotPubliclyReachable);
+    }
+    issues
+}
+
+pub async fn get_port_audit(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<PortAudit>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    let public_address = state.global_settings.lock().await.domain();
+
+    let mut owners = vec![(16_662_u32, PortOwner::Core)];
+    for (uuid, instance) in state.instances.lock().await.iter() {
+        if !requester.can_perform_action(&UserAction::ViewInstance(uuid.clone())) {
+            continue;
+        }
+        owners.push((
+            instance.port().await,
+            PortOwner::Instance {
+                uuid: uuid.clone(),
+                name: instance.name().await,
+                state: instance.state().await,
+            },
+        ));
+    }
+
+    let mut uuids_by_port: HashMap<u32, Vec<InstanceUuid>> = HashMap::new();
+    for (port, owner) in owners.iter() {
+        if let PortOwner::Instance { uuid, .. } = owner {
+            uuids_by_port.entry(*port).or_default().push(uuid.clone());
+        }
+    }
+
+    let ports = futures::future::join_all(owners.into_iter().map(|(port, owner)| {
+        let public_address = public_address.clone();
+        let uuids_by_port = &uuids_by_port;
+        async move {
+            let listening = !port_scanner::local_port_available(port as u16);
+            let publicly_reachable = match &public_address {
+                Some(address) if listening => Some(probe_tcp(address, port).await),
+                _ => None,
+            };
+            let issues = port_issues(
+                &owner,
+                listening,
+                publicly_reachable,
+                uuids_by_port
+                    .get(&port)
+                    .map(Vec::as_slice)
+                    .unwrap_or_default(),
+            );
+            PortAuditEntry {
+                port,
+                owner,
+                listening,
+                publicly_reachable,
+                issues,
+            }
+        }
+    }))
+    .await;
+
+    Ok(Json(PortAudit {
+        public_address,
+        ports,
+    }))
+}
+
+/// Latency and status counts by route since the core started
+pub async fn get_request_metrics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<RouteStats>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    if !requester.is_owner && !requester.is_admin {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Not authorized to view request metrics"),
+        });
+    }
+    Ok(Json(state.request_metrics.lock().await.snapshot()))
+}
+
+/// Core and instance metrics for Prometheus to scrape, once turned on in the
+/// global settings. Scrapers authenticate with the metrics token, not a user's.
+pub async fn get_metrics(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    bearer: Option<AuthBearer>,
+) -> Result<([(header::HeaderName, String); 1], String), Error> {
+    let settings = state.global_settings.lock().await.prometheus();
+    if !settings.enabled {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("The metrics exporter is turned off"),
+        });
+    }
+    if let Some(token) = &settings.token {
+        if bearer.map(|AuthBearer(bearer)| bearer).as_ref() != Some(token) {
+            return Err(Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("Missing or wrong metrics token"),
+            });
+        }
+    }
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8".to_string(),
+        )],
+        prometheus::render(&state).await,
+    ))
+}
+
 pub fn get_system_routes(state: AppState) -> Router {
     Router::new()
         .route("/system/ram", get(get_ram))
         .route("/system/disk", get(get_disk))
         .route("/system/cpu", get(get_cpu_info))
+        .route("/system/ports", get(get_port_audit))
+        .route("/system/request_metrics", get(get_request_metrics))
+        .route("/metrics", get(get_metrics))
         .with_state(state)
 }
+
+#[test]
+fn test_port_issues() {
+    let a = InstanceUuid::from("a".to_string());
+    let b = InstanceUuid::from("b".to_string());
+    let instance = |state| PortOwner::Instance {
+        uuid: a.clone(),
+        name: "a".to_string(),
+        state,
+    };
+    assert!(port_issues(&instance(State::Running), true, Some(true), &[a.clone()]).is_empty());
+    assert!(
+        port_issues(&instance(State::Running), false, None, &[a.clone()])
+            == vec![PortIssue::NotListening]
+    );
+    assert!(
+        port_issues(
+            &instance(State::Stopped),
+            true,
+            Some(false),
+            &[a.clone(), b.clone()]
+        ) == vec![
+            PortIssue::TakenByOtherProcess,
+            PortIssue::SharedWith { uuid: b },
+            PortIssue::NotPubliclyReachable,
+        ]
+    );
+    assert!(
+        port_issues(&PortOwner::Core, true, Some(false), &[])
+            == vec![PortIssue::NotPubliclyReachable]
+    );
+}
+
+#[tokio::test]
+async fn test_probe_tcp() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port() as u32;
+    assert!(probe_tcp("127.0.0.1", port).await);
+    drop(listener);
+    assert!(!probe_tcp("127.0.0.1", port).await);
+}
@@ -0,0 +1,124 @@
+use axum::{
+    extract::Path,
+    routing::{get, put},
+    Json, Router,
+};
+use axum_auth::AuthBearer;
+use color_eyre::eyre::eyre;
+
+use crate::{
+    auth::user::User,
+    error::{Error, ErrorKind},
+    events::webhooks::{NewWebhook, Webhook, WebhookDelivery},
+    AppState,
+};
+
+fn require_owner(requester: &User) -> Result<(), Error> {
+    if !requester.is_owner {
+        return Err(Error {
+            kind: ErrorKind::PermissionDenied,
+            source: eyre!("Only the owner can manage webhooks"),
+        });
+    }
+    Ok(())
+}
+
+pub async fn get_webhooks(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<Webhook>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    Ok(Json(
+        state
+            .webhooks
+            .lock()
+            .await
+            .list()
+            .iter()
+            .map(Webhook::redacted)
+            .collect(),
+    ))
+}
+
+/// The response is the only time the secret is sent back
+pub async fn create_webhook(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    AuthBearer(token): AuthBearer,
+    Json(new_webhook): Json<NewWebhook>,
+) -> Result<Json<Webhook>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    new_webhook.validate()?;
+    let webhook = Webhook::from_new(new_webhook, None, requester.username.clone());
+    Ok(Json(
+        state.webhooks.lock().await.set_webhook(webhook).await?,
+    ))
+}
+
+pub async fn get_webhook(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(webhook_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Webhook>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    Ok(Json(
+        state.webhooks.lock().await.get(&webhook_id)?.redacted(),
+    ))
+}
+
+pub async fn update_webhook(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(webhook_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+    Json(new_webhook): Json<NewWebhook>,
+) -> Result<Json<Webhook>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    new_webhook.validate()?;
+    let mut webhooks = state.webhooks.lock().await;
+    let old = webhooks.get(&webhook_id)?;
+    let webhook = Webhook::from_new(new_webhook, Some(&old), requester.username.clone());
+    Ok(Json(webhooks.set_webhook(webhook).await?.redacted()))
+}
+
+pub async fn delete_webhook(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(webhook_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<()>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    state
+        .webhooks
+        .lock()
+        .await
+        .remove_webhook(&webhook_id)
+        .await?;
+    Ok(Json(()))
+}
+
+pub async fn get_webhook_deliveries(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Path(webhook_id): Path<String>,
+    AuthBearer(token): AuthBearer,
+) -> Result<Json<Vec<WebhookDelivery>>, Error> {
+    let requester = state.users_manager.read().await.try_auth_or_err(&token)?;
+    require_owner(&requester)?;
+    Ok(Json(state.webhooks.lock().await.deliveries(&webhook_id)?))
+}
+
+pub fn get_webhooks_routes(state: AppState) -> Router {
+    Router::new()
+        .route("/settings/webhooks", get(get_webhooks).post(create_webhook))
+        .route(
+            "/settings/webhooks/:webhook_id",
+            put(update_webhook).get(get_webhook).delete(delete_webhook),
+        )
+        .route(
+            "/settings/webhooks/:webhook_id/deliveries",
+            get(get_webhook_deliveries),
+        )
+        .with_state(state)
+}
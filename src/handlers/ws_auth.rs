@@ -0,0 +1,193 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket};
+use axum::http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+
+use crate::auth::{
+    user::{User, UsersManager},
+    user_id::UserId,
+};
+
+use super::ws_protocol::{ClientFrame, ServerFrame};
+
+/// Subprotocol the websockets speak. Browsers require the server to pick one of
+/// the protocols they offer, so clients offer this one next to their token.
+pub const WS_PROTOCOL: &str = "lodestone";
+/// Prefix of the subprotocol carrying the token, browsers can't set headers on
+/// websockets and query strings end up in proxy access logs
+const TOKEN_PROTOCOL_PREFIX: &str = "lodestone.bearer.";
+/// How long a client connecting without a token has to send its auth frame
+const AUTH_FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+/// Seconds before its token expires that a client is asked for a new one
+const REAUTH_WINDOW: i64 = 5 * 60;
+/// Close code for connections without a valid token, in the range left to applications
+const CLOSE_UNAUTHORIZED: u16 = 4001;
+
+/// The token offered as a `lodestone.bearer.<token>` subprotocol, if any
+pub fn token_from_protocols(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX))
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_string())
+}
+
+pub enum AuthFrame {
+    /// not an auth frame, the caller handles it as before
+    NotAuth,
+    Accepted,
+    Rejected,
+}
+
+/// Keeps track of the token a websocket was authenticated with
+pub struct WsSession {
+    pub uid: UserId,
+    expires_at: i64,
+    warned: bool,
+}
+
+async fn send_frame(sender: &mut SplitSink<WebSocket, Message>, frame: &ServerFrame) -> bool {
+    sender.send(frame.to_message()).await.is_ok()
+}
+
+async fn close_unauthorized(sender: &mut SplitSink<WebSocket, Message>, reason: &'static str) {
+    let _ = sender
+        .send(Message::Close(Some(CloseFrame {
+            code: CLOSE_UNAUTHORIZED,
+            reason: Cow::Borrowed(reason),
+        })))
+        .await;
+}
+
+impl WsSession {
+    pub fn new(user: &User, expires_at: i64) -> Self {
+        Self {
+            uid: user.uid.clone(),
+            expires_at,
+            warned: false,
+        }
+    }
+
+    /// Waits for the auth frame of a connection opened without a token, closing
+    /// it if none comes or the token is invalid
+    pub async fn from_first_frame(
+        sender: &mut SplitSink<WebSocket, Message>,
+        receiver: &mut SplitStream<WebSocket>,
+        users_manager: &Arc<RwLock<UsersManager>>,
+    ) -> Option<Self> {
+        let token = match tokio::time::timeout(AUTH_FRAME_TIMEOUT, receiver.next()).await {
+            Ok(Some(Ok(Message::Text(text)))) => match serde_json::from_str(&text) {
+                Ok(ClientFrame::Auth { token }) => Some(token),
+                _ => None,
+            },
+            _ => None,
+        };
+        let authed = match token {
+            Some(token) => users_manager.read().await.try_auth_with_expiry(&token),
+            None => None,
+        };
+        match authed {
+            Some((user, expires_at)) => {
+                let session = Self::new(&user, expires_at);
+                if !send_frame(sender, &ServerFrame::Authenticated { expires_at }).await {
+                    return None;
+                }
+                Some(session)
+            }
+            None => {
+                close_unauthorized(sender, "Expected an auth frame with a valid token").await;
+                None
+            }
+        }
+    }
+
+    /// When `tick` should be called next
+    pub fn next_check(&self) -> Instant {
+        let now = chrono::Utc::now().timestamp();
+        let at = if self.warned {
+            self.expires_at
+        } else {
+            self.expires_at - REAUTH_WINDOW
+        };
+        Instant::now() + Duration::from_secs((at - now).max(0) as u64)
+    }
+
+    /// Asks the client for a fresh token once the current one is about to
+    /// expire, and closes the connection once it has. Returns whether the
+    /// connection is still open.
+    pub async fn tick(&mut self, sender: &mut SplitSink<WebSocket, Message>) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        if now >= self.expires_at {
+            close_unauthorized(sender, "Token expired").await;
+            return false;
+        }
+        if !self.warned && now >= self.expires_at - REAUTH_WINDOW {
+            self.warned = true;
+            return send_frame(
+                sender,
+                &ServerFrame::ReauthRequired {
+                    expires_at: self.expires_at,
+                },
+            )
+            .await;
+        }
+        true
+    }
+
+    /// Re-authenticates the connection if `text` is an auth frame. The new token
+    /// has to belong to the same user.
+    pub async fn handle_frame(
+        &mut self,
+        text: &str,
+        users_manager: &Arc<RwLock<UsersManager>>,
+        sender: &mut SplitSink<WebSocket, Message>,
+    ) -> AuthFrame {
+        let token = match serde_json::from_str(text) {
+            Ok(ClientFrame::Auth { token }) => token,
+            _ => return AuthFrame::NotAuth,
+        };
+        match users_manager.read().await.try_auth_with_expiry(&token) {
+            Some((user, expires_at)) if user.uid == self.uid => {
+                self.expires_at = expires_at;
+                self.warned = false;
+                if send_frame(sender, &ServerFrame::Authenticated { expires_at }).await {
+                    AuthFrame::Accepted
+                } else {
+                    AuthFrame::Rejected
+                }
+            }
+            _ => {
+                close_unauthorized(sender, "Invalid token").await;
+                AuthFrame::Rejected
+            }
+        }
+    }
+}
+
+#[test]
+fn test_token_from_protocols() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(token_from_protocols(&headers), None);
+    headers.insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        "lodestone, lodestone.bearer.abc.def-ghi".parse().unwrap(),
+    );
+    assert_eq!(
+        token_from_protocols(&headers),
+        Some("abc.def-ghi".to_string())
+    );
+    headers.insert(
+        SEC_WEBSOCKET_PROTOCOL,
+        "lodestone, lodestone.bearer.".parse().unwrap(),
+    );
+    assert_eq!(token_from_protocols(&headers), None);
+}
@@ -0,0 +1,128 @@
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::events::EventQuery;
+use crate::output_types::ClientEvent;
+use crate::traits::t_server::MonitorReport;
+use crate::types::{InstanceUuid, Snowflake};
+
+/// Frames clients send over the websockets
+#[derive(Deserialize, TS, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum ClientFrame {
+    /// First frame of a connection opened without a token, or a fresh token
+    /// for one whose token is about to expire
+    Auth { token: String },
+    /// answered with a pong frame, for clients that can't send websocket pings
+    Ping,
+    /// Replaces the filter of an event stream. The stored events after
+    /// `replay_from` that match it are sent first, the snowflake of the last
+    /// event seen before a reconnect.
+    Subscribe {
+        filter: EventQuery,
+        replay_from: Option<Snowflake>,
+    },
+}
+
+#[derive(Serialize, TS, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[ts(export)]
+pub enum StreamKind {
+    Events,
+    Console { instance_uuid: InstanceUuid },
+    Monitor { instance_uuid: InstanceUuid },
+}
+
+/// Frames the core sends over the websockets. Events, console lines and
+/// monitor reports keep their fields at the top level next to `type`.
+#[derive(Serialize, TS, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[ts(export)]
+pub enum ServerFrame {
+    Authenticated {
+        expires_at: i64,
+    },
+    /// the connection is closed at `expires_at` unless a fresh token is sent
+    ReauthRequired {
+        expires_at: i64,
+    },
+    /// Subscription ack, sent once the connection is authenticated. Reconnect
+    /// with `?resume=<session_id>` within the resume window to continue where
+    /// the stream left off.
+    Subscribed {
+        session_id: String,
+        resumed: bool,
+        heartbeat_interval_secs: u64,
+        stream: StreamKind,
+    },
+    Event(ClientEvent),
+    ConsoleLine(ClientEvent),
+    MonitorReport(MonitorReport),
+    /// The stored events of a replay were sent, live events follow. `complete`
+    /// is false when there were more than a replay sends, `/events/search` has
+    /// the ones in between.
+    Replayed {
+        count: u32,
+        cursor: Snowflake,
+        complete: bool,
+    },
+    Pong,
+    /// something went wrong without closing the connection, e.g. events were
+    /// dropped because the client couldn't keep up
+    Error {
+        message: String,
+    },
+}
+
+impl ServerFrame {
+    pub fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap())
+    }
+}
+
+#[test]
+fn test_frames() {
+    assert_eq!(
+        serde_json::from_str::<ClientFrame>(r#"{"type": "auth", "token": "abc"}"#).unwrap(),
+        ClientFrame::Auth {
+            token: "abc".to_string()
+        }
+    );
+    assert_eq!(
+        serde_json::to_value(ServerFrame::MonitorReport(MonitorReport {
+            cpu_usage: Some(1.0),
+            ..Default::default()
+        }))
+        .unwrap()["type"],
+        "monitor_report"
+    );
+    assert_eq!(
+        serde_json::to_value(ServerFrame::Subscribed {
+            session_id: "abc".to_string(),
+            resumed: false,
+            heartbeat_interval_secs: 30,
+            stream: StreamKind::Events,
+        })
+        .unwrap()["stream"]["kind"],
+        "events"
+    );
+    match serde_json::from_str::<ClientFrame>(
+        r#"{"type": "subscribe", "filter": {"event_instance_ids": ["abc"]}, "replay_from": null}"#,
+    )
+    .unwrap()
+    {
+        ClientFrame::Subscribe {
+            filter,
+            replay_from,
+        } => {
+            assert_eq!(
+                filter.event_instance_ids,
+                Some(vec![InstanceUuid::from("abc".to_string())])
+            );
+            assert_eq!(replay_from, None);
+        }
+        frame => panic!("unexpected frame {:?}", frame),
+    }
+}
@@ -0,0 +1,125 @@
+use serde::Serialize;
+use sysinfo::SystemExt;
+use ts_rs::TS;
+
+/// Below this much memory the host is treated as low power whatever its CPU
+const LOW_MEMORY_MB: u64 = 4096;
+/// ARM boards like the Raspberry Pi are low power up to this much memory
+const LOW_MEMORY_ARM_MB: u64 = 8192;
+
+/// What the core knows of the machine it runs on, to pick runtimes and defaults
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct HostInfo {
+    /// as reported by Rust, e.g. `x86_64` or `aarch64`
+    pub arch: String,
+    /// the architecture Java runtimes are downloaded for, `None` if Adoptium has
+    /// no builds for this host
+    pub jre_arch: Option<String>,
+    pub cpu_count: u32,
+    pub total_memory_mb: u64,
+    /// a small ARM board or a machine with little memory, new instances get
+    /// smaller defaults on it
+    pub low_power: bool,
+}
+
+/// Adoptium's name for the architecture of this host
+pub fn jre_arch() -> Option<&'static str> {
+    match std::env::consts::ARCH {
+        "x86_64" => Some("x64"),
+        "x86" => Some("x32"),
+        "aarch64" => Some("aarch64"),
+        "arm" => Some("arm"),
+        "powerpc64" if cfg!(target_endian = "little") => Some("ppc64le"),
+        "powerpc64" => Some("ppc64"),
+        "s390x" => Some("s390x"),
+        "riscv64" => Some("riscv64"),
+        _ => None,
+    }
+}
+
+/// The architecture to download Java `major` for. Adoptium has no Java 8 for Apple
+/// silicon, the x64 build runs under Rosetta.
+pub fn jre_arch_for(major: u64) -> Option<&'static str> {
+    match (std::env::consts::OS, jre_arch()) {
+        ("macos", Some("aarch64")) if major < 11 => Some("x64"),
+        (_, arch) => arch,
+    }
+}
+
+fn is_low_power(arch: &str, total_memory_mb: u64) -> bool {
+    total_memory_mb < LOW_MEMORY_MB
+        || (matches!(arch, "aarch64" | "arm") && total_memory_mb <= LOW_MEMORY_ARM_MB)
+}
+
+impl HostInfo {
+    pub fn detect() -> Self {
+        let mut sys = sysinfo::System::new();
+        sys.refresh_memory();
+        let total_memory_mb = sys.total_memory() / 1024 / 1024;
+        let arch = std::env::consts::ARCH.to_string();
+        Self {
+            jre_arch: jre_arch().map(str::to_string),
+            cpu_count: std::thread::available_parallelism()
+                .map(|n| n.get() as u32)
+                .unwrap_or(1),
+            low_power: is_low_power(&arch, total_memory_mb),
+            arch,
+            total_memory_mb,
+        }
+    }
+
+    /// Minimum and maximum heap of a new Minecraft server in MB. Low power hosts
+    /// get at most half their memory so the OS and the core have room left.
+    pub fn default_ram(&self) -> (u32, u32) {
+        if !self.low_power || self.total_memory_mb == 0 {
+            return (1024, 2048);
+        }
+        let max = (self.total_memory_mb / 2).clamp(512, 2048) as u32;
+        (max / 2, max)
+    }
+
+    /// JVM flags suggested for a new Minecraft server. The serial collector has
+    /// the least overhead on small heaps and few cores, where G1's concurrent
+    /// threads compete with the server thread.
+    pub fn default_jvm_args(&self) -> Vec<String> {
+        if self.low_power && self.cpu_count <= 4 {
+            vec!["-XX:+UseSerialGC".to_string()]
+        } else {
+            vec![
+                "-XX:+UseG1GC".to_string(),
+                "-XX:MaxGCPauseMillis=200".to_string(),
+            ]
+        }
+    }
+
+    /// The official Bedrock server is only built for x86_64 Linux and Windows
+    pub fn runs_bedrock(&self) -> bool {
+        self.arch == "x86_64" && matches!(std::env::consts::OS, "linux" | "windows")
+    }
+}
+
+#[test]
+fn test_low_power_defaults() {
+    let pi = HostInfo {
+        arch: "aarch64".to_string(),
+        jre_arch: Some("aarch64".to_string()),
+        cpu_count: 4,
+        total_memory_mb: 3906,
+        low_power: is_low_power("aarch64", 3906),
+    };
+    assert!(pi.low_power);
+    assert_eq!(pi.default_ram(), (976, 1953));
+    assert_eq!(pi.default_jvm_args(), vec!["-XX:+UseSerialGC".to_string()]);
+
+    let server = HostInfo {
+        arch: "x86_64".to_string(),
+        jre_arch: Some("x64".to_string()),
+        cpu_count: 16,
+        total_memory_mb: 65536,
+        low_power: is_low_power("x86_64", 65536),
+    };
+    assert!(!server.low_power);
+    assert_eq!(server.default_ram(), (1024, 2048));
+    assert!(is_low_power("x86_64", 2048));
+}
@@ -6,6 +6,7 @@ use crate::error::Error;
 use crate::events::CausedBy;
 use crate::macro_executor::{self, WorkerOptionGenerator};
 use crate::traits::t_macro::{HistoryEntry, MacroEntry, TMacro, TaskEntry};
+use crate::traits::Capability;
 
 use super::bridge::procedure_call::{
     emit_result, next_procedure, proc_bridge_ready, ProcedureBridge,
@@ -49,19 +50,19 @@ impl WorkerOptionGenerator for GenericMainWorkerGenerator {
 #[async_trait]
 impl TMacro for GenericInstance {
     async fn get_macro_list(&self) -> Result<Vec<MacroEntry>, Error> {
-        unimplemented!()
+        Err(Error::not_supported(Capability::SupportsMacros))
     }
     async fn get_task_list(&self) -> Result<Vec<TaskEntry>, Error> {
-        unimplemented!()
+        Err(Error::not_supported(Capability::SupportsMacros))
     }
     async fn get_history_list(&self) -> Result<Vec<HistoryEntry>, Error> {
-        unimplemented!()
+        Err(Error::not_supported(Capability::SupportsMacros))
     }
     async fn delete_macro(&mut self, _name: &str) -> Result<(), Error> {
-        unimplemented!()
+        Err(Error::not_supported(Capability::SupportsMacros))
     }
     async fn create_macro(&mut self, _name: &str, _content: &str) -> Result<(), Error> {
-        unimplemented!()
+        Err(Error::not_supported(Capability::SupportsMacros))
     }
     async fn run_macro(
         &mut self,
@@ -69,6 +70,6 @@ impl TMacro for GenericInstance {
         _args: Vec<String>,
         _caused_by: CausedBy,
     ) -> Result<TaskEntry, Error> {
-        unimplemented!()
+        Err(Error::not_supported(Capability::SupportsMacros))
     }
 }
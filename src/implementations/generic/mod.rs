@@ -1,4 +1,4 @@
-use std::{path::PathBuf, rc::Rc};
+use std::{collections::HashSet, path::PathBuf, rc::Rc};
 
 use async_trait::async_trait;
 use color_eyre::eyre::Context;
@@ -15,13 +15,14 @@ use crate::{
     events::CausedBy,
     macro_executor::{self, MacroExecutor, MacroPID, SpawnResult, WorkerOptionGenerator},
     traits::{
+        t_backup::TBackup,
         t_configurable::{
             manifest::{SetupManifest, SetupValue},
             TConfigurable,
         },
         t_player::TPlayerManagement,
         t_server::TServer,
-        InstanceInfo, TInstance,
+        Capability, InstanceInfo, TInstance,
     },
     types::DotLodestoneConfig,
 };
@@ -260,6 +261,8 @@ impl Drop for GenericInstance {
     }
 }
 
+impl TBackup for GenericInstance {}
+
 #[async_trait]
 impl TInstance for GenericInstance {
     async fn get_instance_info(&self) -> InstanceInfo {
@@ -278,8 +281,17 @@ impl TInstance for GenericInstance {
             player_count: self.get_player_count().await.ok(),
             max_player_count: self.get_max_player_count().await.ok(),
             player_list: self.get_player_list().await.ok(),
+            capabilities: self.capabilities(),
+            start_queue_position: None,
+            tps: None,
+            mspt: None,
         }
     }
+
+    /// player counts and lists come from the instance's script
+    fn capabilities(&self) -> HashSet<Capability> {
+        HashSet::from([Capability::SupportsPlayerList])
+    }
 }
 
 // #[cfg(test)]
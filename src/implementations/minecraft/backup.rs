@@ -0,0 +1,76 @@
+use async_trait::async_trait;
+
+use crate::backup::{
+    self, remote::BackupRemotesSettings, BackupEntry, BackupGuard, BackupIndex, BackupMetadata,
+    BackupPolicy,
+};
+use crate::error::Error;
+use crate::events::CausedBy;
+use crate::prelude::GameInstance;
+use crate::traits::t_backup::TBackup;
+
+use super::MinecraftInstance;
+
+#[async_trait]
+impl TBackup for MinecraftInstance {
+    async fn list_backups(&self) -> Result<Vec<BackupEntry>, Error> {
+        let mut backups = BackupIndex::load(&self.path_to_instance).await?.backups;
+        backups.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(backups)
+    }
+
+    async fn create_backup(
+        &self,
+        guard: BackupGuard,
+        metadata: BackupMetadata,
+        created_by: Option<String>,
+        remotes: &BackupRemotesSettings,
+        caused_by: CausedBy,
+    ) -> Result<BackupEntry, Error> {
+        backup::create_backup(
+            &GameInstance::from(self.clone()),
+            guard,
+            metadata,
+            created_by,
+            remotes,
+            &self.event_broadcaster,
+            caused_by,
+        )
+        .await
+    }
+
+    async fn delete_backup(&self, _guard: BackupGuard, backup_id: &str) -> Result<(), Error> {
+        backup::delete_backup(&self.path_to_instance, backup_id).await
+    }
+
+    async fn restore_backup(
+        &self,
+        guard: BackupGuard,
+        backup_id: &str,
+        caused_by: CausedBy,
+    ) -> Result<BackupEntry, Error> {
+        backup::restore_backup(
+            &mut GameInstance::from(self.clone()),
+            guard,
+            backup_id,
+            &self.event_broadcaster,
+            caused_by,
+        )
+        .await
+    }
+
+    async fn prune_backups(&self, guard: BackupGuard) -> Result<Vec<BackupEntry>, Error> {
+        backup::prune_backups(
+            &GameInstance::from(self.clone()),
+            guard,
+            &self.event_broadcaster,
+        )
+        .await
+    }
+
+    async fn backup_schedule(&self) -> Result<Option<cron::Schedule>, Error> {
+        BackupPolicy::load(&self.path_to_instance)
+            .await?
+            .parsed_schedule()
+    }
+}
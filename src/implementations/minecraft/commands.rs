@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::prelude::{path_to_stores, path_to_tmp};
+use crate::util::{dont_spawn_terminal, download_file};
+
+use super::util::get_vanilla_jar_url;
+use super::MinecraftInstance;
+
+/// Used until a command report has been generated for the instance's version
+const VANILLA_COMMANDS: &[&str] = &[
+    "advancement",
+    "attribute",
+    "ban",
+    "ban-ip",
+    "banlist",
+    "bossbar",
+    "clear",
+    "clone",
+    "damage",
+    "data",
+    "datapack",
+    "debug",
+    "defaultgamemode",
+    "deop",
+    "difficulty",
+    "effect",
+    "enchant",
+    "execute",
+    "experience",
+    "fill",
+    "fillbiome",
+    "forceload",
+    "function",
+    "gamemode",
+    "gamerule",
+    "give",
+    "help",
+    "item",
+    "jfr",
+    "kick",
+    "kill",
+    "list",
+    "locate",
+    "loot",
+    "me",
+    "msg",
+    "op",
+    "pardon",
+    "pardon-ip",
+    "particle",
+    "perf",
+    "place",
+    "playsound",
+    "recipe",
+    "reload",
+    "ride",
+    "save-all",
+    "save-off",
+    "save-on",
+    "say",
+    "schedule",
+    "scoreboard",
+    "seed",
+    "setblock",
+    "setidletimeout",
+    "setworldspawn",
+    "spawnpoint",
+    "spectate",
+    "spreadplayers",
+    "stop",
+    "stopsound",
+    "summon",
+    "tag",
+    "team",
+    "teammsg",
+    "teleport",
+    "tell",
+    "tellraw",
+    "time",
+    "title",
+    "tp",
+    "trigger",
+    "w",
+    "weather",
+    "whitelist",
+    "worldborder",
+    "xp",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct CommandInfo {
+    pub name: String,
+    /// "minecraft" or the name of the plugin providing the command
+    pub source: String,
+    pub description: Option<String>,
+    pub usage: Option<String>,
+    pub aliases: Vec<String>,
+    /// Brigadier node of the command from the server's command report, with
+    /// `literal` and `argument` children describing every argument
+    #[ts(type = "unknown")]
+    pub syntax: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct PluginDescription {
+    name: String,
+    #[serde(default)]
+    commands: HashMap<String, Option<PluginCommand>>,
+}
+
+#[derive(Deserialize)]
+struct PluginCommand {
+    description: Option<String>,
+    usage: Option<String>,
+    /// a single alias or a list of them
+    aliases: Option<serde_yaml::Value>,
+}
+
+fn path_to_command_report(version: &str) -> PathBuf {
+    path_to_stores()
+        .join("command_reports")
+        .join(format!("{}.json", version))
+}
+
+fn parse_command_report(report: &serde_json::Value) -> Vec<CommandInfo> {
+    let children = match report.get("children").and_then(|c| c.as_object()) {
+        Some(children) => children,
+        None => return Vec::new(),
+    };
+    children
+        .iter()
+        .map(|(name, node)| CommandInfo {
+            name: name.clone(),
+            source: "minecraft".to_string(),
+            description: None,
+            usage: None,
+            aliases: Vec::new(),
+            syntax: Some(node.clone()),
+        })
+        .collect()
+}
+
+fn parse_plugin_description(description: &str) -> Result<Vec<CommandInfo>, Error> {
+    let description: PluginDescription =
+        serde_yaml::from_str(description).context("Failed to parse plugin.yml")?;
+    Ok(description
+        .commands
+        .into_iter()
+        .map(|(name, command)| {
+            let (description_text, usage, aliases) = match command {
+                Some(command) => (
+                    command.description,
+                    command.usage,
+                    match command.aliases {
+                        Some(serde_yaml::Value::String(alias)) => vec![alias],
+                        Some(serde_yaml::Value::Sequence(aliases)) => aliases
+                            .into_iter()
+                            .filter_map(|alias| alias.as_str().map(str::to_string))
+                            .collect(),
+                        _ => Vec::new(),
+                    },
+                ),
+                None => (None, None, Vec::new()),
+            };
+            CommandInfo {
+                name,
+                source: description.name.clone(),
+                description: description_text,
+                usage,
+                aliases,
+                syntax: None,
+            }
+        })
+        .collect())
+}
+
+/// Commands declared in the plugin.yml of every jar in `plugins/`.
+/// Unreadable jars are skipped.
+fn read_plugin_commands(path_to_plugins: &Path) -> Vec<CommandInfo> {
+    let entries = match std::fs::read_dir(path_to_plugins) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut commands = Vec::new();
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.extension().map_or(true, |ext| ext != "jar") {
+            continue;
+        }
+        let mut archive = match std::fs::File::open(&path)
+            .ok()
+            .and_then(|file| zip::ZipArchive::new(file).ok())
+        {
+            Some(archive) => archive,
+            None => continue,
+        };
+        let mut description = String::new();
+        match archive.by_name("plugin.yml") {
+            Ok(mut file) => {
+                if file.read_to_string(&mut description).is_err() {
+                    continue;
+                }
+            }
+            Err(_) => continue,
+        }
+        if let Ok(plugin_commands) = parse_plugin_description(&description) {
+            commands.extend(plugin_commands);
+        }
+    }
+    commands
+}
+
+impl MinecraftInstance {
+    /// Known commands with whatever metadata is available: the full syntax tree of
+    /// vanilla commands once a report is generated, names otherwise, and the
+    /// commands plugins declare. Mod commands are only known at runtime and not listed.
+    pub async fn command_list(&self) -> Result<Vec<CommandInfo>, Error> {
+        let version = self.config.lock().await.version.clone();
+        let report_path = path_to_command_report(&version);
+        let mut commands = if report_path.is_file() {
+            let report: serde_json::Value =
+                serde_json::from_str(&crate::util::fs::read_to_string(&report_path).await?)
+                    .context("Failed to parse command report")?;
+            parse_command_report(&report)
+        } else {
+            VANILLA_COMMANDS
+                .iter()
+                .map(|name| CommandInfo {
+                    name: name.to_string(),
+                    source: "minecraft".to_string(),
+                    description: None,
+                    usage: None,
+                    aliases: Vec::new(),
+                    syntax: None,
+                })
+                .collect()
+        };
+        let path_to_plugins = self.path_to_instance.join("plugins");
+        commands.extend(
+            tokio::task::spawn_blocking(move || read_plugin_commands(&path_to_plugins))
+                .await
+                .context("Failed to read plugin commands")?,
+        );
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(commands)
+    }
+
+    /// Runs the vanilla data generator for the instance's version to get the
+    /// Brigadier command tree. Reports are shared by every instance of a version.
+    pub async fn generate_command_report(&self) -> Result<(), Error> {
+        let version = self.config.lock().await.version.clone();
+        let report_path = path_to_command_report(&version);
+        if report_path.is_file() {
+            return Ok(());
+        }
+        // the data generator and Brigadier came with 1.13, the bundler with 1.18
+        let minor_version: Option<u32> = version.split('.').nth(1).and_then(|v| v.parse().ok());
+        if minor_version.map_or(false, |minor| minor < 13) {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Command reports need Minecraft 1.13 or newer"),
+            });
+        }
+        let (url, _) = get_vanilla_jar_url(&version)
+            .await
+            .ok_or_else(|| eyre!("Failed to find the vanilla server jar for {}", version))?;
+        crate::util::fs::create_dir_all(path_to_tmp()).await?;
+        let work_dir =
+            tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary directory")?;
+        let jar = download_file(&url, work_dir.path(), Some("server.jar"), &|_| {}, true).await?;
+
+        let java = self.java().await?;
+        let mut command = Command::new(java);
+        if minor_version.map_or(true, |minor| minor >= 18) {
+            command
+                .arg("-DbundlerMainClass=net.minecraft.data.Main")
+                .arg("-jar")
+                .arg(&jar);
+        } else {
+            command.arg("-cp").arg(&jar).arg("net.minecraft.data.Main");
+        }
+        let output = dont_spawn_terminal(
+            command
+                .arg("--reports")
+                .arg("--output")
+                .arg(work_dir.path().join("generated"))
+                .current_dir(work_dir.path())
+                .stdin(Stdio::null()),
+        )
+        .output()
+        .await
+        .context("Failed to run the data generator")?;
+        if !output.status.success() {
+            return Err(eyre!(
+                "Data generator exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        let report = crate::util::fs::read_to_string(
+            work_dir
+                .path()
+                .join("generated")
+                .join("reports")
+                .join("commands.json"),
+        )
+        .await?;
+        crate::util::fs::create_dir_all(path_to_stores().join("command_reports")).await?;
+        crate::util::fs::write_all(&report_path, report).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command_sources() {
+        let report = serde_json::json!({
+            "type": "root",
+            "children": {
+                "seed": {"type": "literal", "executable": true},
+                "kick": {"type": "literal", "children": {
+                    "targets": {"type": "argument", "parser": "minecraft:entity", "executable": true}
+                }}
+            }
+        });
+        let commands = parse_command_report(&report);
+        assert_eq!(commands.len(), 2);
+        assert!(commands.iter().any(|c| c.name == "kick"
+            && c.syntax.as_ref().unwrap()["children"]["targets"]["type"] == "argument"));
+
+        let plugin = "name: Essentials\ncommands:\n  home:\n    description: Go home\n    usage: /<command> [name]\n    aliases: [ehome, homes]\n  afk:\n    aliases: away\n  nick:\n";
+        let mut commands = parse_plugin_description(plugin).unwrap();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(commands[0].name, "afk");
+        assert_eq!(commands[0].aliases, vec!["away"]);
+        assert_eq!(commands[1].description.as_deref(), Some("Go home"));
+        assert_eq!(commands[1].aliases, vec!["ehome", "homes"]);
+        assert_eq!(commands[2].source, "Essentials");
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use indexmap::IndexMap;
+
+use crate::error::{Error, ErrorKind};
+use crate::host::HostInfo;
+
+use super::{FlavourKind, SetupConfig};
+
+/// Setup value of `config_template` that leaves the defaults of the server itself
+pub const NO_TEMPLATE: &str = "none";
+
+/// Defaults written to a new instance, kept as data files in `config_templates/`
+#[derive(Debug)]
+pub struct ConfigTemplate {
+    pub id: &'static str,
+    pub description: &'static str,
+    /// the first template of a flavour is the one used if none is chosen
+    flavours: &'static [FlavourKind],
+    /// `server.properties` entries
+    properties: &'static str,
+    /// other files, relative to the instance
+    files: &'static [(&'static str, &'static str)],
+}
+
+const TEMPLATES: &[ConfigTemplate] = &[
+    ConfigTemplate {
+        id: "vanilla",
+        description: "Vanilla defaults with a shorter simulation distance",
+        flavours: &[FlavourKind::Vanilla],
+        properties: include_str!("config_templates/vanilla.properties"),
+        files: &[],
+    },
+    ConfigTemplate {
+        id: "paper",
+        description: "Shorter simulation and entity ranges, Spigot's activation ranges tightened",
+        flavours: &[FlavourKind::Paper, FlavourKind::Purpur],
+        properties: include_str!("config_templates/paper.properties"),
+        files: &[(
+            "spigot.yml",
+            include_str!("config_templates/paper_spigot.yml"),
+        )],
+    },
+    ConfigTemplate {
+        id: "fabric",
+        description: "Vanilla defaults without waiting on chunk writes",
+        flavours: &[FlavourKind::Fabric],
+        properties: include_str!("config_templates/fabric.properties"),
+        files: &[],
+    },
+    ConfigTemplate {
+        id: "modded",
+        description: "No tick watchdog and flight allowed, as most modpacks need",
+        flavours: &[FlavourKind::Forge, FlavourKind::NeoForge],
+        properties: include_str!("config_templates/modded.properties"),
+        files: &[],
+    },
+];
+
+/// The templates offered for a flavour, the default first. Proxies have none.
+pub fn templates_for(flavour: &FlavourKind) -> Vec<&'static ConfigTemplate> {
+    TEMPLATES
+        .iter()
+        .filter(|template| template.flavours.contains(flavour))
+        .collect()
+}
+
+/// The template `id` stands for, `None` for `NO_TEMPLATE`. A missing id picks the
+/// flavour's default.
+pub fn resolve(
+    flavour: &FlavourKind,
+    id: Option<&str>,
+) -> Result<Option<&'static ConfigTemplate>, Error> {
+    let templates = templates_for(flavour);
+    match id {
+        None => Ok(templates.first().copied()),
+        Some(NO_TEMPLATE) => Ok(None),
+        Some(id) => templates
+            .into_iter()
+            .find(|template| template.id == id)
+            .map(Some)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "There is no config template {} for {}",
+                    id,
+                    flavour.to_string()
+                ),
+            }),
+    }
+}
+
+/// The template a setup config was resolved to
+fn template_of(config: &SetupConfig) -> Result<Option<&'static ConfigTemplate>, Error> {
+    match &config.config_template {
+        Some(id) => resolve(&FlavourKind::from(&config.flavour), Some(id)),
+        None => Ok(None),
+    }
+}
+
+fn parse_properties(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+}
+
+/// `key=value` lines of the setup request, written over the template
+pub fn parse_overrides(text: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut overrides = Vec::new();
+    for line in text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        let (key, value) = line.split_once('=').ok_or_else(|| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("\"{}\" is not a key=value server property", line),
+        })?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("\"{}\" is not a valid server property name", key),
+            });
+        }
+        if key == "server-port" {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The port is set with the port setting, not as a server property"),
+            });
+        }
+        overrides.push((key.to_string(), value.trim().to_string()));
+    }
+    Ok(overrides)
+}
+
+/// Lower on small heaps, every chunk in view of a player is kept in memory
+pub fn view_distance_for_ram(max_ram_mb: u32) -> u32 {
+    match max_ram_mb {
+        0..=2047 => 6,
+        2048..=4095 => 8,
+        4096..=8191 => 10,
+        _ => 12,
+    }
+}
+
+/// `server.properties` of a new instance, later entries win: the port, the view
+/// distance for its RAM, the template, then the overrides of the setup request
+pub fn server_properties(config: &SetupConfig) -> Result<String, Error> {
+    let mut properties: IndexMap<String, String> = IndexMap::new();
+    properties.insert("server-port".to_string(), config.port.to_string());
+    if !config.flavour.is_proxy() {
+        let max_ram = config
+            .max_ram
+            .unwrap_or_else(|| HostInfo::detect().default_ram().1);
+        properties.insert(
+            "view-distance".to_string(),
+            view_distance_for_ram(max_ram).to_string(),
+        );
+    }
+    if let Some(template) = template_of(config)? {
+        for (key, value) in parse_properties(template.properties) {
+            properties.insert(key.to_string(), value.to_string());
+        }
+    }
+    for (key, value) in &config.server_properties {
+        properties.insert(key.clone(), value.clone());
+    }
+    let mut ret = String::from("#generated by Lodestone\n");
+    for (key, value) in properties {
+        ret.push_str(&format!("{}={}\n", key, value));
+    }
+    Ok(ret)
+}
+
+/// Writes the files of the template besides `server.properties`
+pub async fn write_template_files(
+    config: &SetupConfig,
+    path_to_instance: &Path,
+) -> Result<(), Error> {
+    let template = match template_of(config)? {
+        Some(template) => template,
+        None => return Ok(()),
+    };
+    for (path, content) in template.files {
+        tokio::fs::write(path_to_instance.join(path), content)
+            .await
+            .context(format!("Failed to write {} of the config template", path))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_server_properties_template() {
+    use super::Flavour;
+
+    let mut config = SetupConfig {
+        name: "Survival".to_string(),
+        version: "1.20.4".to_string(),
+        flavour: Flavour::Paper {
+            build_version: None,
+        },
+        port: 25570,
+        cmd_args: Vec::new(),
+        description: None,
+        min_ram: Some(1024),
+        max_ram: Some(4096),
+        auto_start: None,
+        restart_on_crash: None,
+        backup_period: None,
+        config_template: Some("paper".to_string()),
+        server_properties: parse_overrides("# ours\nsimulation-distance = 4\nmotd=Hi=there\n")
+            .unwrap(),
+        performance_mods: false,
+        jvm_preset: None,
+        accept_eula: true,
+    };
+    let properties = server_properties(&config).unwrap();
+    let entries: Vec<(&str, &str)> = parse_properties(&properties).collect();
+    assert!(entries.contains(&("server-port", "25570")));
+    assert!(entries.contains(&("view-distance", "10")));
+    assert!(entries.contains(&("sync-chunk-writes", "false")));
+    // the overrides win over the template
+    assert!(entries.contains(&("simulation-distance", "4")));
+    assert!(entries.contains(&("motd", "Hi=there")));
+
+    config.config_template = None;
+    let properties = server_properties(&config).unwrap();
+    assert!(!properties.contains("sync-chunk-writes"));
+
+    config.config_template = Some("modded".to_string());
+    assert!(server_properties(&config).is_err());
+    assert!(parse_overrides("server-port=25565").is_err());
+    assert!(parse_overrides("no value").is_err());
+    // every template file parses
+    for template in TEMPLATES {
+        assert!(parse_properties(template.properties).count() > 0);
+    }
+}
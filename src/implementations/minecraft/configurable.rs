@@ -4,19 +4,24 @@ use std::sync::atomic;
 use async_trait::async_trait;
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 
+use crate::cgroup::ResourceLimits;
 use crate::error::{Error, ErrorKind};
+use crate::instance_notes::InstanceNotes;
 use crate::prelude::path_to_tmp;
 use crate::traits::t_configurable::manifest::{
     ConfigurableManifest, ConfigurableValue, ConfigurableValueType, SettingManifest,
 };
 use crate::traits::t_configurable::{Game, TConfigurable};
-use crate::traits::t_server::State;
+use crate::traits::t_server::InstanceOperation;
 
 use crate::types::InstanceUuid;
 use crate::util::download_file;
 
-use super::util::{get_fabric_jar_url, get_paper_jar_url, get_vanilla_jar_url};
-use super::MinecraftInstance;
+use super::util::{
+    get_fabric_jar_url, get_paper_jar_url, get_purpur_jar_url, get_vanilla_jar_url,
+    get_velocity_jar_url,
+};
+use super::{jvm_presets, MinecraftInstance};
 
 #[async_trait]
 impl TConfigurable for MinecraftInstance {
@@ -60,6 +65,22 @@ impl TConfigurable for MinecraftInstance {
         self.config.lock().await.restart_on_crash
     }
 
+    async fn cpu_limit(&self) -> Option<f32> {
+        self.config.lock().await.cpu_limit
+    }
+
+    async fn memory_limit(&self) -> Option<u32> {
+        self.config.lock().await.memory_limit
+    }
+
+    async fn jvm_preset(&self) -> Option<String> {
+        self.config.lock().await.jvm_preset.clone()
+    }
+
+    async fn notes(&self) -> InstanceNotes {
+        self.config.lock().await.notes.clone()
+    }
+
     async fn set_name(&mut self, name: String) -> Result<(), Error> {
         if name.is_empty() {
             return Err(Error {
@@ -109,13 +130,36 @@ impl TConfigurable for MinecraftInstance {
         self.write_config_to_file().await
     }
 
-    async fn change_version(&mut self, version: String) -> Result<(), Error> {
-        if *self.state.lock().await != State::Stopped {
-            return Err(Error {
-                kind: ErrorKind::BadRequest,
-                source: eyre!("Cannot change version while server is running"),
-            });
+    async fn set_resource_limits(&mut self, limits: ResourceLimits) -> Result<(), Error> {
+        {
+            let mut config = self.config.lock().await;
+            limits.validate(Some(config.max_ram))?;
+            config.cpu_limit = limits.cpu_limit;
+            config.memory_limit = limits.memory_limit;
         }
+        self.write_config_to_file().await
+    }
+
+    async fn set_jvm_preset(&mut self, jvm_preset: Option<String>) -> Result<(), Error> {
+        {
+            let mut config = self.config.lock().await;
+            config.jvm_preset =
+                jvm_presets::resolve(jvm_preset.as_deref(), config.jre_major_version)?
+                    .map(|preset| preset.id);
+        }
+        self.write_config_to_file().await
+    }
+
+    async fn set_notes(&mut self, notes: InstanceNotes) -> Result<(), Error> {
+        self.config.lock().await.notes = notes;
+        self.write_config_to_file().await
+    }
+
+    async fn change_version(&mut self, version: String) -> Result<(), Error> {
+        self.state
+            .lock()
+            .await
+            .check(InstanceOperation::ChangeVersion)?;
         if version == self.config.lock().await.version {
             return Ok(());
         }
@@ -148,13 +192,52 @@ impl TConfigurable for MinecraftInstance {
                     }
                 })?
             }
-            super::Flavour::Spigot => todo!(),
+            super::Flavour::Purpur { .. } => {
+                get_purpur_jar_url(&version, &None).await.ok_or_else(|| {
+                    let error_msg =
+                        format!("Cannot get the purpur jar version for version {}", version);
+                    Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!(error_msg),
+                    }
+                })?
+            }
+            super::Flavour::Spigot => {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!("Changing versions is unsupported for spigot servers"),
+                })
+            }
             super::Flavour::Forge { .. } => {
                 return Err(Error {
                     kind: ErrorKind::UnsupportedOperation,
                     source: eyre!("Changing versions is unsupported for forge servers"),
                 })
             }
+            super::Flavour::NeoForge { .. } => {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!("Changing versions is unsupported for neoforge servers"),
+                })
+            }
+            super::Flavour::Velocity { .. } => {
+                get_velocity_jar_url(&version, &None).await.ok_or_else(|| {
+                    let error_msg = format!(
+                        "Cannot get the velocity jar version for version {}",
+                        version
+                    );
+                    Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!(error_msg),
+                    }
+                })?
+            }
+            super::Flavour::BungeeCord => {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!("BungeeCord only has its latest build"),
+                })
+            }
         };
         let lodestone_tmp = path_to_tmp().clone();
         let temp_dir = tempfile::tempdir_in(lodestone_tmp).context("Failed to create temp dir")?;
@@ -168,6 +251,7 @@ impl TConfigurable for MinecraftInstance {
         .await?;
         let jar_path = temp_dir.path().join("server.jar");
         crate::util::fs::rename(jar_path, self.path().await.join("server.jar")).await?;
+        self.follow_java_of(&version).await?;
         self.config.lock().await.version = version;
         self.write_config_to_file().await
     }
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ModIssueKind {
+    MissingDependency,
+    WrongDependencyVersion,
+    Incompatible,
+    FailedToLoad,
+    MixinFailed,
+}
+
+/// A problem with a mod found in a crash report or log
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ModIssue {
+    pub kind: ModIssueKind,
+    /// the mod the loader blames
+    pub mod_id: String,
+    /// the dependency or the mod it conflicts with
+    pub other_mod_id: Option<String>,
+    pub evidence: String,
+}
+
+/// A mod named by the issues of a crash, with its jar if it's installed
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct CulpritMod {
+    pub mod_id: String,
+    pub name: Option<String>,
+    pub version: Option<String>,
+    /// in the instance's `mods`, `None` if no jar declares the id
+    pub file: Option<String>,
+}
+
+/// What a jar in `mods/` declares about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ModMetadata {
+    name: Option<String>,
+    version: Option<String>,
+    file: String,
+}
+
+lazy_static! {
+    /// Fabric: `Mod 'Lithium' (lithium) 0.11.2 requires version 0.88 or later of mod 'Fabric API' (fabric-api), which is missing!`
+    static ref FABRIC_REQUIRES: Regex = Regex::new(
+        r"Mod '[^']*' \(([\w.-]+)\) \S+ requires .*?(?:\(([\w.-]+)\)|of (?:mod )?'?([\w.-]+)'?),? (which is missing|but only the wrong version)"
+    )
+    .unwrap();
+    /// Fabric: `Mod 'Sodium' (sodium) 0.5.3 is incompatible with any version of mod 'OptiFabric' (optifabric), but ...`
+    static ref FABRIC_INCOMPATIBLE: Regex = Regex::new(
+        r"Mod '[^']*' \(([\w.-]+)\) \S+ is incompatible with [^(]*\(([\w.-]+)\)"
+    )
+    .unwrap();
+    /// Forge: `Mod ID: 'geckolib', Requested by: 'mowziesmobs', Expected range: '[4.2,)', Actual version: '[MISSING]'`
+    static ref FORGE_REQUIRES: Regex = Regex::new(
+        r"Mod ID: '([\w.-]+)', Requested by: '([\w.-]+)', Expected range: '[^']*', Actual version: '([^']*)'"
+    )
+    .unwrap();
+    /// Forge names every mod that failed in a section of its own
+    static ref FORGE_MOD_SECTION: Regex = Regex::new(r"^-- MOD ([\w.-]+) --").unwrap();
+    static ref MIXIN: Regex = Regex::new(
+        r"(?i)mixin apply for mod ([\w.-]+) failed|mixin .*? from mod ([\w.-]+) failed"
+    )
+    .unwrap();
+}
+
+fn issue(kind: ModIssueKind, mod_id: &str, other_mod_id: Option<&str>, line: &str) -> ModIssue {
+    ModIssue {
+        kind,
+        mod_id: mod_id.to_string(),
+        other_mod_id: other_mod_id.map(str::to_string),
+        evidence: line.trim().to_string(),
+    }
+}
+
+fn line_issue(line: &str) -> Option<ModIssue> {
+    if let Some(captures) = FABRIC_REQUIRES.captures(line) {
+        let other = captures.get(2).or_else(|| captures.get(3))?.as_str();
+        let kind = if &captures[4] == "which is missing" {
+            ModIssueKind::MissingDependency
+        } else {
+            ModIssueKind::WrongDependencyVersion
+        };
+        return Some(issue(kind, &captures[1], Some(other), line));
+    }
+    if let Some(captures) = FABRIC_INCOMPATIBLE.captures(line) {
+        return Some(issue(
+            ModIssueKind::Incompatible,
+            &captures[1],
+            Some(&captures[2]),
+            line,
+        ));
+    }
+    if let Some(captures) = FORGE_REQUIRES.captures(line) {
+        let kind = if &captures[3] == "[MISSING]" {
+            ModIssueKind::MissingDependency
+        } else {
+            ModIssueKind::WrongDependencyVersion
+        };
+        return Some(issue(kind, &captures[2], Some(&captures[1]), line));
+    }
+    if let Some(captures) = FORGE_MOD_SECTION.captures(line.trim()) {
+        return Some(issue(ModIssueKind::FailedToLoad, &captures[1], None, line));
+    }
+    if let Some(captures) = MIXIN.captures(line) {
+        let mod_id = captures.get(1).or_else(|| captures.get(2))?.as_str();
+        return Some(issue(ModIssueKind::MixinFailed, mod_id, None, line));
+    }
+    None
+}
+
+/// Every mod issue in the texts, once each
+pub fn mod_issues(texts: &[&str]) -> Vec<ModIssue> {
+    let mut issues: Vec<ModIssue> = Vec::new();
+    for issue in texts
+        .iter()
+        .flat_map(|text| text.lines())
+        .filter_map(line_issue)
+    {
+        let seen = issues.iter().any(|seen| {
+            seen.kind == issue.kind
+                && seen.mod_id == issue.mod_id
+                && seen.other_mod_id == issue.other_mod_id
+        });
+        if !seen {
+            issues.push(issue);
+        }
+    }
+    issues
+}
+
+fn string_field(value: &toml::Value, key: &str) -> Option<String> {
+    value
+        .get(key)
+        .and_then(|field| field.as_str())
+        // Forge fills `${file.jarVersion}` and the like in when loading
+        .filter(|field| !field.contains("${"))
+        .map(str::to_string)
+}
+
+/// The ids the jar declares in `fabric.mod.json`, `quilt.mod.json` or
+/// `META-INF/mods.toml`, with their name and version
+fn read_mod_ids(path: &Path) -> Vec<(String, Option<String>, Option<String>)> {
+    let mut archive = match std::fs::File::open(path)
+        .ok()
+        .and_then(|file| zip::ZipArchive::new(file).ok())
+    {
+        Some(archive) => archive,
+        None => return Vec::new(),
+    };
+    let mut read_entry = |name: &str| {
+        let mut text = String::new();
+        archive.by_name(name).ok()?.read_to_string(&mut text).ok()?;
+        Some(text)
+    };
+    if let Some(metadata) = read_entry("fabric.mod.json")
+        .and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok())
+    {
+        if let Some(id) = metadata["id"].as_str() {
+            return vec![(
+                id.to_string(),
+                metadata["name"].as_str().map(str::to_string),
+                metadata["version"].as_str().map(str::to_string),
+            )];
+        }
+    }
+    if let Some(metadata) = read_entry("quilt.mod.json")
+        .and_then(|text| serde_json::from_str::<serde_json::Value>(&text).ok())
+    {
+        let loader = &metadata["quilt_loader"];
+        if let Some(id) = loader["id"].as_str() {
+            return vec![(
+                id.to_string(),
+                loader["metadata"]["name"].as_str().map(str::to_string),
+                loader["version"].as_str().map(str::to_string),
+            )];
+        }
+    }
+    let mods_toml = read_entry("META-INF/mods.toml")
+        .or_else(|| read_entry("META-INF/neoforge.mods.toml"))
+        .and_then(|text| text.parse::<toml::Value>().ok());
+    match mods_toml
+        .as_ref()
+        .and_then(|mods_toml| mods_toml.get("mods"))
+        .and_then(|mods| mods.as_array())
+    {
+        Some(mods) => mods
+            .iter()
+            .filter_map(|entry| {
+                Some((
+                    string_field(entry, "modId")?,
+                    string_field(entry, "displayName"),
+                    string_field(entry, "version"),
+                ))
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The installed mods by id. Jars that can't be read are skipped.
+fn installed_mods(path_to_mods: &Path) -> HashMap<String, ModMetadata> {
+    let entries = match std::fs::read_dir(path_to_mods) {
+        Ok(entries) => entries,
+        Err(_) => return HashMap::new(),
+    };
+    let mut mods = HashMap::new();
+    for path in entries.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        if path.extension().map_or(true, |ext| ext != "jar") {
+            continue;
+        }
+        let file = path.file_name().unwrap().to_string_lossy().to_string();
+        for (id, name, version) in read_mod_ids(&path) {
+            mods.insert(
+                id,
+                ModMetadata {
+                    name,
+                    version,
+                    file: file.clone(),
+                },
+            );
+        }
+    }
+    mods
+}
+
+/// The mods the issues blame, in the order they come up. A missing dependency
+/// is blamed on the mod that needs it, a conflict on both mods.
+fn culprits(issues: &[ModIssue], installed: &HashMap<String, ModMetadata>) -> Vec<CulpritMod> {
+    let mut culprits: Vec<CulpritMod> = Vec::new();
+    for issue in issues {
+        let mut ids = vec![issue.mod_id.as_str()];
+        if issue.kind == ModIssueKind::Incompatible {
+            ids.extend(issue.other_mod_id.as_deref());
+        }
+        for id in ids {
+            if culprits.iter().any(|culprit| culprit.mod_id == id) {
+                continue;
+            }
+            let metadata = installed.get(id);
+            culprits.push(CulpritMod {
+                mod_id: id.to_string(),
+                name: metadata.and_then(|metadata| metadata.name.clone()),
+                version: metadata.and_then(|metadata| metadata.version.clone()),
+                file: metadata.map(|metadata| metadata.file.clone()),
+            });
+        }
+    }
+    culprits
+}
+
+/// The mod issues in the crash report and log of an instance, and the installed
+/// mods they point at
+pub fn analyze_crash(path_to_instance: &Path, texts: &[&str]) -> (Vec<ModIssue>, Vec<CulpritMod>) {
+    let issues = mod_issues(texts);
+    if issues.is_empty() {
+        return (issues, Vec::new());
+    }
+    let installed = installed_mods(&path_to_instance.join("mods"));
+    let culprits = culprits(&issues, &installed);
+    (issues, culprits)
+}
+
+#[test]
+fn test_crash_mod_issues() {
+    let log = "\
+[main/ERROR]: Incompatible mod set!
+ - Mod 'Lithium' (lithium) 0.11.2 requires version 0.88 or later of mod 'Fabric API' (fabric-api), which is missing!
+ - Mod 'Sodium Extra' (sodium-extra) 0.5.1 requires version 0.5 or later of mod 'Sodium' (sodium), but only the wrong version is present: 0.4.10!
+ - Mod 'Sodium' (sodium) 0.4.10 is incompatible with any version of mod 'OptiFabric' (optifabric), but version 1.13.24 is present!
+ - Mod 'Lithium' (lithium) 0.11.2 requires version 0.88 or later of mod 'Fabric API' (fabric-api), which is missing!";
+    let report = "\
+\tMod ID: 'geckolib', Requested by: 'mowziesmobs', Expected range: '[4.2,)', Actual version: '[MISSING]'
+-- MOD create --
+Details:
+\tMod File: /srv/mods/create-1.20.1-0.5.1.jar
+Caused by: org.spongepowered.asm.mixin.transformer.throwables.MixinTransformerError: Mixin apply for mod iris failed iris.mixins.json:MixinLevelRenderer";
+    let issues = mod_issues(&[report, log]);
+    let summary: Vec<(ModIssueKind, &str, Option<&str>)> = issues
+        .iter()
+        .map(|issue| {
+            (
+                issue.kind,
+                issue.mod_id.as_str(),
+                issue.other_mod_id.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                ModIssueKind::MissingDependency,
+                "mowziesmobs",
+                Some("geckolib")
+            ),
+            (ModIssueKind::FailedToLoad, "create", None),
+            (ModIssueKind::MixinFailed, "iris", None),
+            (
+                ModIssueKind::MissingDependency,
+                "lithium",
+                Some("fabric-api")
+            ),
+            (
+                ModIssueKind::WrongDependencyVersion,
+                "sodium-extra",
+                Some("sodium")
+            ),
+            (ModIssueKind::Incompatible, "sodium", Some("optifabric")),
+        ]
+    );
+
+    let installed = HashMap::from([(
+        "sodium".to_string(),
+        ModMetadata {
+            name: Some("Sodium".to_string()),
+            version: Some("0.4.10".to_string()),
+            file: "sodium-fabric-mc1.20.1-0.4.10.jar".to_string(),
+        },
+    )]);
+    let culprits = culprits(&issues, &installed);
+    let ids: Vec<&str> = culprits
+        .iter()
+        .map(|culprit| culprit.mod_id.as_str())
+        .collect();
+    assert_eq!(
+        ids,
+        vec![
+            "mowziesmobs",
+            "create",
+            "iris",
+            "lithium",
+            "sodium-extra",
+            "sodium",
+            "optifabric"
+        ]
+    );
+    assert_eq!(
+        culprits[5].file.as_deref(),
+        Some("sodium-fabric-mc1.20.1-0.4.10.jar")
+    );
+    assert_eq!(culprits[0].file, None);
+}
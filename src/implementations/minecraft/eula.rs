@@ -0,0 +1,78 @@
+use color_eyre::eyre::eyre;
+
+use crate::error::{Error, ErrorKind};
+
+use super::MinecraftInstance;
+
+pub const EULA_URL: &str = "https://aka.ms/MinecraftEULA";
+
+const EULA_FILE_NAME: &str = "eula.txt";
+
+/// `eula.txt` with the line the server looks for
+pub(crate) fn eula_file(accepted: bool) -> String {
+    format!(
+        "#generated by Lodestone\n#Accepting means agreeing to the Minecraft EULA ({})\neula={}\n",
+        EULA_URL, accepted
+    )
+}
+
+/// The server reads the `eula` key of the properties file, in any case
+fn is_accepted(eula_file: &str) -> bool {
+    eula_file.lines().any(|line| {
+        line.split_once('=').map_or(false, |(key, value)| {
+            key.trim() == "eula" && value.trim().eq_ignore_ascii_case("true")
+        })
+    })
+}
+
+impl MinecraftInstance {
+    /// Proxies have no EULA to accept
+    pub async fn eula_accepted(&self) -> bool {
+        if self.is_proxy().await {
+            return true;
+        }
+        match crate::util::fs::read_to_string(self.path_to_instance.join(EULA_FILE_NAME)).await {
+            Ok(text) => is_accepted(&text),
+            Err(_) => false,
+        }
+    }
+
+    pub async fn set_eula_accepted(&self, accepted: bool) -> Result<(), Error> {
+        if self.is_proxy().await {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Proxies have no EULA to accept"),
+            });
+        }
+        crate::util::fs::write_all(
+            self.path_to_instance.join(EULA_FILE_NAME),
+            eula_file(accepted),
+        )
+        .await
+    }
+
+    /// Refuses to start a server whose EULA hasn't been accepted, which would
+    /// only exit right away
+    pub(crate) async fn check_eula(&self) -> Result<(), Error> {
+        if self.eula_accepted().await {
+            return Ok(());
+        }
+        Err(Error {
+            kind: ErrorKind::EulaNotAccepted,
+            source: eyre!(
+                "The Minecraft EULA ({}) has to be accepted before the server can start",
+                EULA_URL
+            ),
+        })
+    }
+}
+
+#[test]
+fn test_eula_accepted() {
+    assert!(is_accepted(&eula_file(true)));
+    assert!(!is_accepted(&eula_file(false)));
+    assert!(is_accepted(
+        "#By changing the setting below to TRUE you are indicating your agreement to our EULA (https://aka.ms/MinecraftEULA).\n#Mon Jan 01 00:00:00 UTC 2024\neula=TRUE\n"
+    ));
+    assert!(!is_accepted(""));
+}
@@ -1,8 +1,12 @@
-use color_eyre::eyre::Context;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
 use indexmap::IndexMap;
 use serde_json::Value;
 
 use crate::error::Error;
+use crate::util::list_dir;
 
 pub async fn get_forge_minecraft_versions() -> Result<Vec<String>, Error> {
     let http = reqwest::Client::new();
@@ -21,10 +25,144 @@ pub async fn get_forge_minecraft_versions() -> Result<Vec<String>, Error> {
     Ok(response.into_iter().map(|(k, _)| k).rev().collect())
 }
 
+/// The argument file referenced by a `run.sh` or `run.bat` written by the installer,
+/// which launches with `java @user_jvm_args.txt @libraries/.../unix_args.txt`
+fn args_file_in_script(script: &str) -> Option<&str> {
+    script
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#') && !line.trim_start().starts_with("REM"))
+        .flat_map(|line| line.split_whitespace())
+        .filter_map(|arg| arg.strip_prefix('@'))
+        .find(|arg| arg.ends_with("unix_args.txt") || arg.ends_with("win_args.txt"))
+}
+
+/// Looks for `<loader>/<build>/<file_name>` under the installer's `libraries`
+async fn find_args_file(path_to_instance: &Path, file_name: &str) -> Option<PathBuf> {
+    for loader in [
+        ["net", "minecraftforge", "forge"],
+        ["net", "neoforged", "neoforge"],
+    ] {
+        let loader_dir = loader
+            .iter()
+            .fold(path_to_instance.join("libraries"), |path, part| {
+                path.join(part)
+            });
+        if let Ok(builds) = list_dir(&loader_dir, Some(true)).await {
+            if let Some(args_file) = builds
+                .into_iter()
+                .map(|build| build.join(file_name))
+                .find(|args_file| args_file.is_file())
+            {
+                return Some(args_file);
+            }
+        }
+    }
+    None
+}
+
+/// How to launch a server set up by the Forge or NeoForge installer. Since 1.17 the
+/// installer writes run scripts passing an argument file to java instead of
+/// producing a runnable jar, older versions leave `forge-<version>-<build>.jar`,
+/// or `minecraftforge*.jar` before 1.6.
+pub async fn launch_args(path_to_instance: &Path, version: &str) -> Result<Vec<OsString>, Error> {
+    let (script, args_file) = match std::env::consts::OS {
+        "windows" => ("run.bat", "win_args.txt"),
+        _ => ("run.sh", "unix_args.txt"),
+    };
+    let from_script = tokio::fs::read_to_string(path_to_instance.join(script))
+        .await
+        .ok()
+        .and_then(|script| args_file_in_script(&script).map(|path| path_to_instance.join(path)))
+        .filter(|path| path.is_file());
+    let args_file = match from_script {
+        Some(path) => Some(path),
+        None => find_args_file(path_to_instance, args_file).await,
+    };
+    if let Some(args_file) = args_file {
+        let mut arg = OsString::from("@");
+        arg.push(args_file);
+        return Ok(vec![arg]);
+    }
+
+    let jars: Vec<PathBuf> = list_dir(path_to_instance, Some(false))
+        .await
+        .context("Failed to find the forge server jar")?
+        .into_iter()
+        .filter(|p| p.extension().unwrap_or_default() == "jar")
+        .collect();
+    let file_name = |p: &PathBuf| {
+        p.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    };
+    // 1.5 doesn't work due to JRE issues
+    // 1.4 doesn't work since forge doesn't provide an installer
+    let jar = jars
+        .iter()
+        .find(|p| file_name(p).starts_with(format!("forge-{}-", version).as_str()))
+        .or_else(|| {
+            jars.iter()
+                .find(|p| file_name(p).starts_with("minecraftforge"))
+        })
+        .ok_or_else(|| {
+            eyre!(
+                "Failed to find how to launch the server, neither {} nor a forge jar is in the instance",
+                script
+            )
+        })?;
+    Ok(vec![OsString::from("-jar"), jar.clone().into_os_string()])
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_args_file_in_script() {
+        let unix = "#!/usr/bin/env sh\n# Forge requires a configured set of both JVM and program arguments.\n# Add custom JVM arguments to the user_jvm_args.txt\njava @user_jvm_args.txt @libraries/net/minecraftforge/forge/1.20.1-47.2.0/unix_args.txt \"$@\"\n";
+        assert_eq!(
+            args_file_in_script(unix),
+            Some("libraries/net/minecraftforge/forge/1.20.1-47.2.0/unix_args.txt")
+        );
+        let windows = "@echo off\r\nREM Forge requires a configured set of both JVM and program arguments.\r\njava @user_jvm_args.txt @libraries/net/neoforged/neoforge/20.4.237/win_args.txt %*\r\npause\r\n";
+        assert_eq!(
+            args_file_in_script(windows),
+            Some("libraries/net/neoforged/neoforge/20.4.237/win_args.txt")
+        );
+        assert_eq!(args_file_in_script("java -jar server.jar"), None);
+    }
+
+    #[tokio::test]
+    async fn test_launch_args() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("forge-1.12.2-14.23.5.2860.jar"), "").unwrap();
+        std::fs::write(dir.path().join("minecraft_server.1.12.2.jar"), "").unwrap();
+        assert_eq!(
+            launch_args(dir.path(), "1.12.2").await.unwrap(),
+            vec![
+                OsString::from("-jar"),
+                dir.path()
+                    .join("forge-1.12.2-14.23.5.2860.jar")
+                    .into_os_string()
+            ]
+        );
+
+        let args_file = match std::env::consts::OS {
+            "windows" => "win_args.txt",
+            _ => "unix_args.txt",
+        };
+        let build_dir = dir.path().join("libraries/net/neoforged/neoforge/20.4.237");
+        std::fs::create_dir_all(&build_dir).unwrap();
+        std::fs::write(build_dir.join(args_file), "").unwrap();
+        let mut expected = OsString::from("@");
+        expected.push(build_dir.join(args_file));
+        assert_eq!(
+            launch_args(dir.path(), "1.20.4").await.unwrap(),
+            vec![expected]
+        );
+    }
+
     #[tokio::test]
     async fn test_get_forge_minecraft_versions() {
         let versions = get_forge_minecraft_versions().await.unwrap();
@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::eyre;
+use serde::Serialize;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::java_manager::{self, JavaRuntime};
+use crate::traits::t_configurable::manifest::ConfigurableValue;
+
+use super::configurable::CmdArgSetting;
+use super::util::get_java_major;
+use super::{MinecraftInstance, RestoreConfig};
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct InstanceJava {
+    /// the major Java version the instance's version runs on
+    pub required_major: u64,
+    pub java_cmd: Option<String>,
+    /// the runtime `java_cmd` points to, `None` until a managed one is downloaded
+    pub runtime: Option<JavaRuntime>,
+    /// kept when the version changes instead of following it
+    pub pinned: bool,
+}
+
+impl RestoreConfig {
+    /// A `java_cmd` outside of the managed runtimes was set by hand, which pins
+    /// it as well
+    pub fn java_pinned(&self) -> bool {
+        self.java_pinned
+            || self.java_cmd.as_ref().map_or(false, |java_cmd| {
+                !java_manager::is_managed(Path::new(java_cmd))
+            })
+    }
+}
+
+impl MinecraftInstance {
+    /// The `java` to start the server with. An instance that isn't pinned runs
+    /// on the managed runtime of its Java version, downloaded on first use.
+    pub(crate) async fn java(&self) -> Result<PathBuf, Error> {
+        let config = self.config.lock().await.clone();
+        match &config.java_cmd {
+            Some(java_cmd) if config.java_pinned() => Ok(PathBuf::from(java_cmd)),
+            _ => java_manager::ensure_runtime(config.jre_major_version, None, &|_| {}).await,
+        }
+    }
+
+    pub async fn java_info(&self) -> InstanceJava {
+        let config = self.config.lock().await.clone();
+        let runtime = match &config.java_cmd {
+            Some(java_cmd) => java_manager::probe(Path::new(java_cmd)).await.ok(),
+            None => None,
+        };
+        InstanceJava {
+            required_major: config.jre_major_version,
+            pinned: config.java_pinned(),
+            java_cmd: config.java_cmd,
+            runtime,
+        }
+    }
+
+    async fn set_java_cmd(&self, java: &Path, pinned: bool) -> Result<(), Error> {
+        self.configurable_manifest
+            .lock()
+            .await
+            .update_setting_value(
+                CmdArgSetting::get_section_id(),
+                CmdArgSetting::JavaCmd(Default::default()).get_identifier(),
+                ConfigurableValue::String(java.to_string_lossy().to_string()),
+            )?;
+        self.sync_configurable_to_restore_config().await;
+        self.config.lock().await.java_pinned = pinned;
+        self.write_config_to_file().await
+    }
+
+    /// Pins the instance to the runtime at `java`, which has to be new enough for
+    /// its version. `None` goes back to the managed runtime of the version,
+    /// downloading it if needed.
+    pub async fn pin_java(&self, java: Option<String>) -> Result<InstanceJava, Error> {
+        let required_major = self.config.lock().await.jre_major_version;
+        match java {
+            Some(java) => {
+                let runtime = java_manager::probe(Path::new(&java)).await?;
+                if runtime.major < required_major {
+                    return Err(Error {
+                        kind: ErrorKind::BadRequest,
+                        source: eyre!(
+                            "The instance needs Java {}, {} is Java {}",
+                            required_major,
+                            java,
+                            runtime.major
+                        ),
+                    });
+                }
+                self.set_java_cmd(Path::new(&java), true).await?;
+            }
+            None => {
+                let java = java_manager::ensure_runtime(required_major, None, &|_| {}).await?;
+                self.set_java_cmd(&java, false).await?;
+            }
+        }
+        Ok(self.java_info().await)
+    }
+
+    /// Moves an instance that isn't pinned to the runtime `version` needs. The
+    /// runtime itself is downloaded on the next start.
+    pub(crate) async fn follow_java_of(&self, version: &str) -> Result<(), Error> {
+        if self.config.lock().await.flavour.is_proxy() {
+            return Ok(());
+        }
+        let major = match get_java_major(version).await {
+            Some(major) => major,
+            None => {
+                warn!(
+                    "Could not get the Java version of Minecraft {}, keeping the runtime",
+                    version
+                );
+                return Ok(());
+            }
+        };
+        self.config.lock().await.jre_major_version = major;
+        if !self.config.lock().await.java_pinned() {
+            self.set_java_cmd(&java_manager::managed_java(major), false)
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+/// Setup value of `jvm_preset` that only passes the instance's own arguments
+pub const NO_PRESET: &str = "none";
+
+const MIN_RAM_VARIABLE: &str = "{min_ram}";
+const MAX_RAM_VARIABLE: &str = "{max_ram}";
+
+/// Aikar recommends larger young generations from 12 GB of heap on
+const AIKAR_LARGE_HEAP_MB: u32 = 12 * 1024;
+
+/// JVM arguments passed before the instance's own `cmd_args`
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct JvmPreset {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    /// `{min_ram}` and `{max_ram}` are replaced with the heap sizes in MB
+    pub args: Vec<String>,
+    /// oldest Java the arguments are accepted by
+    #[serde(default)]
+    pub min_java_major: Option<u64>,
+}
+
+lazy_static::lazy_static! {
+    /// The templates of the global settings, kept in sync by `GlobalSettings`
+    static ref CUSTOM_PRESETS: RwLock<Vec<JvmPreset>> = RwLock::new(Vec::new());
+}
+
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn aikar_args(large_heap: bool) -> Vec<String> {
+    let (new_size, max_new_size, region_size, reserve, occupancy) = if large_heap {
+        ("40", "50", "16M", "15", "20")
+    } else {
+        ("30", "40", "8M", "20", "15")
+    };
+    vec![
+        "-XX:+UseG1GC".to_string(),
+        "-XX:+ParallelRefProcEnabled".to_string(),
+        "-XX:MaxGCPauseMillis=200".to_string(),
+        "-XX:+UnlockExperimentalVMOptions".to_string(),
+        "-XX:+DisableExplicitGC".to_string(),
+        "-XX:+AlwaysPreTouch".to_string(),
+        format!("-XX:G1NewSizePercent={}", new_size),
+        format!("-XX:G1MaxNewSizePercent={}", max_new_size),
+        format!("-XX:G1HeapRegionSize={}", region_size),
+        format!("-XX:G1ReservePercent={}", reserve),
+        "-XX:G1HeapWastePercent=5".to_string(),
+        "-XX:G1MixedGCCountTarget=4".to_string(),
+        format!("-XX:InitiatingHeapOccupancyPercent={}", occupancy),
+        "-XX:G1MixedGCLiveThresholdPercent=90".to_string(),
+        "-XX:G1RSetUpdatingPauseTimePercent=5".to_string(),
+        "-XX:SurvivorRatio=32".to_string(),
+        "-XX:+PerfDisableSharedMem".to_string(),
+        "-XX:MaxTenuringThreshold=1".to_string(),
+        "-Dusing.aikars.flags=https://mcflags.emc.gs".to_string(),
+        "-Daikars.new.flags=true".to_string(),
+    ]
+}
+
+/// The presets that come with the core. Aikar's flags are listed with their
+/// small heap values, the large heap ones are picked when rendering.
+pub fn builtin_presets() -> Vec<JvmPreset> {
+    vec![
+        JvmPreset {
+            id: "aikar".to_string(),
+            name: "Aikar's Flags".to_string(),
+            description:
+                "G1 tuned for Minecraft's allocation pattern, the usual choice for most servers"
+                    .to_string(),
+            args: aikar_args(false),
+            min_java_major: None,
+        },
+        JvmPreset {
+            id: "g1_tuned".to_string(),
+            name: "G1 Tuned".to_string(),
+            description: "G1 with shorter pauses, for modded servers with many small allocations"
+                .to_string(),
+            args: to_args(&[
+                "-XX:+UseG1GC",
+                "-XX:+UnlockExperimentalVMOptions",
+                "-XX:MaxGCPauseMillis=130",
+                "-XX:G1NewSizePercent=28",
+                "-XX:G1HeapRegionSize=16M",
+                "-XX:G1ReservePercent=20",
+                "-XX:G1MixedGCCountTarget=3",
+                "-XX:InitiatingHeapOccupancyPercent=10",
+                "-XX:G1MixedGCLiveThresholdPercent=90",
+                "-XX:+ParallelRefProcEnabled",
+                "-XX:+DisableExplicitGC",
+                "-XX:+PerfDisableSharedMem",
+            ]),
+            min_java_major: None,
+        },
+        JvmPreset {
+            id: "zgc".to_string(),
+            name: "ZGC".to_string(),
+            description:
+                "Pauses under a millisecond at some throughput cost, for heaps of 16 GB and more"
+                    .to_string(),
+            args: to_args(&[
+                "-XX:+UseZGC",
+                "-XX:+AlwaysPreTouch",
+                "-XX:+DisableExplicitGC",
+                "-XX:+PerfDisableSharedMem",
+                "-XX:+ParallelRefProcEnabled",
+            ]),
+            min_java_major: Some(17),
+        },
+    ]
+}
+
+pub fn set_custom_presets(presets: Vec<JvmPreset>) {
+    *CUSTOM_PRESETS.write().unwrap() = presets;
+}
+
+/// Built-in presets first, then the custom templates
+pub fn presets() -> Vec<JvmPreset> {
+    let mut presets = builtin_presets();
+    presets.extend(CUSTOM_PRESETS.read().unwrap().iter().cloned());
+    presets
+}
+
+pub fn get(id: &str) -> Option<JvmPreset> {
+    presets().into_iter().find(|preset| preset.id == id)
+}
+
+/// Looks up a preset chosen for an instance, `None` and `NO_PRESET` both
+/// meaning no preset
+pub fn resolve(id: Option<&str>, jre_major_version: u64) -> Result<Option<JvmPreset>, Error> {
+    let id = match id {
+        Some(id) if id != NO_PRESET => id,
+        _ => return Ok(None),
+    };
+    let preset = get(id).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("No JVM preset with id {}", id),
+    })?;
+    if let Some(min_java_major) = preset.min_java_major {
+        if jre_major_version < min_java_major {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "{} needs Java {} or newer, the instance runs on Java {}",
+                    preset.name,
+                    min_java_major,
+                    jre_major_version
+                ),
+            });
+        }
+    }
+    Ok(Some(preset))
+}
+
+fn selects_collector(arg: &str) -> bool {
+    arg.starts_with("-XX:+Use") && arg.ends_with("GC")
+}
+
+/// The arguments of `preset` with the heap sizes filled in, followed by
+/// `cmd_args` so the instance's own flags win. A collector picked in `cmd_args`
+/// is dropped if the preset picks one, the JVM refuses to start with two.
+pub fn render(preset: &JvmPreset, min_ram: u32, max_ram: u32, cmd_args: &[String]) -> Vec<String> {
+    let preset_args = if preset.id == "aikar" {
+        aikar_args(max_ram >= AIKAR_LARGE_HEAP_MB)
+    } else {
+        preset.args.clone()
+    };
+    let picks_collector = preset_args.iter().any(|arg| selects_collector(arg));
+    preset_args
+        .iter()
+        .map(|arg| {
+            arg.replace(MIN_RAM_VARIABLE, &min_ram.to_string())
+                .replace(MAX_RAM_VARIABLE, &max_ram.to_string())
+        })
+        .chain(
+            cmd_args
+                .iter()
+                .filter(|arg| !(picks_collector && selects_collector(arg)))
+                .cloned(),
+        )
+        .collect()
+}
+
+/// Custom templates need unique ids of their own and can't set the heap size,
+/// which comes from the instance's RAM settings
+pub fn validate_custom(presets: &[JvmPreset]) -> Result<(), Error> {
+    let builtin_ids: HashSet<String> = builtin_presets()
+        .into_iter()
+        .map(|preset| preset.id)
+        .collect();
+    let mut ids = HashSet::new();
+    for preset in presets {
+        if preset.id.is_empty()
+            || !preset
+                .id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "JVM preset id '{}' can only have letters, digits, _ and -",
+                    preset.id
+                ),
+            });
+        }
+        if preset.id == NO_PRESET || builtin_ids.contains(&preset.id) || !ids.insert(&preset.id) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("JVM preset id {} is already taken", preset.id),
+            });
+        }
+        for arg in &preset.args {
+            if arg.starts_with("-Xmx") || arg.starts_with("-Xms") {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "JVM preset {} sets the heap size, which comes from the instance's RAM settings",
+                        preset.id
+                    ),
+                });
+            }
+            let unknown_variable = arg
+                .replace(MIN_RAM_VARIABLE, "")
+                .replace(MAX_RAM_VARIABLE, "")
+                .contains('{');
+            if unknown_variable {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "Argument {} of JVM preset {} has an unknown variable, only {} and {} are replaced",
+                        arg,
+                        preset.id,
+                        MIN_RAM_VARIABLE,
+                        MAX_RAM_VARIABLE
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_render_jvm_presets() {
+    let custom = JvmPreset {
+        id: "shenandoah".to_string(),
+        name: "Shenandoah".to_string(),
+        description: String::new(),
+        args: to_args(&["-XX:+UseShenandoahGC", "-XX:SoftMaxHeapSize={max_ram}M"]),
+        min_java_major: None,
+    };
+    let cmd_args = to_args(&["-XX:+UseG1GC", "-Dlog4j2.formatMsgNoLookups=true"]);
+    assert_eq!(
+        render(&custom, 1024, 4096, &cmd_args),
+        to_args(&[
+            "-XX:+UseShenandoahGC",
+            "-XX:SoftMaxHeapSize=4096M",
+            "-Dlog4j2.formatMsgNoLookups=true"
+        ])
+    );
+
+    let aikar = builtin_presets().remove(0);
+    assert!(render(&aikar, 1024, 8192, &[]).contains(&"-XX:G1HeapRegionSize=8M".to_string()));
+    assert!(render(&aikar, 1024, 16384, &[]).contains(&"-XX:G1HeapRegionSize=16M".to_string()));
+
+    assert!(validate_custom(&[custom.clone()]).is_ok());
+    assert!(validate_custom(&[custom.clone(), custom.clone()]).is_err());
+    let taken = JvmPreset {
+        id: "zgc".to_string(),
+        ..custom.clone()
+    };
+    assert!(validate_custom(&[taken]).is_err());
+    let heap = JvmPreset {
+        args: to_args(&["-Xmx{max_ram}M"]),
+        ..custom.clone()
+    };
+    assert!(validate_custom(&[heap]).is_err());
+    let unknown = JvmPreset {
+        args: to_args(&["-XX:ConcGCThreads={cores}"]),
+        ..custom
+    };
+    assert!(validate_custom(&[unknown]).is_err());
+
+    assert!(resolve(Some(NO_PRESET), 17).unwrap().is_none());
+    assert!(resolve(Some("zgc"), 8).is_err());
+    assert!(resolve(Some("missing"), 17).is_err());
+}
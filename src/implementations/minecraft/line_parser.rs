@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 use fancy_regex::Regex;
 use lazy_static::lazy_static;
 
@@ -69,7 +71,57 @@ pub fn parse_player_left(system_msg: &str) -> Option<String> {
 
 pub fn parse_server_started(system_msg: &str) -> bool {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r#"Done \(.+\)!"#).unwrap();
+        // BungeeCord only says where it listens
+        static ref RE: Regex = Regex::new(r#"Done \(.+\)!|Listening on /"#).unwrap();
     }
     RE.is_match(system_msg).unwrap()
 }
+
+/// Name and address from the "logged in with entity id" line the server prints
+/// right before "joined the game"
+pub fn parse_player_login(system_msg: &str) -> Option<(String, IpAddr)> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"^(\S+)\[/(.+)\] logged in with entity id").unwrap();
+    }
+    let cap = RE.captures(system_msg).ok()??;
+    let name = cap.get(1)?.as_str().to_string();
+    // e.g. 127.0.0.1:54321 or 0:0:0:0:0:0:0:1%0:54321
+    let (address, _port) = cap.get(2)?.as_str().rsplit_once(':')?;
+    let address = address.split('%').next()?;
+    let ip = address
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()?;
+    Some((name, ip))
+}
+
+#[test]
+fn test_parse_player_login() {
+    assert_eq!(
+        parse_player_login(
+            "Steve[/192.168.1.20:51234] logged in with entity id 187 at (8.5, 64.0, -3.5)"
+        ),
+        Some(("Steve".to_string(), "192.168.1.20".parse().unwrap()))
+    );
+    assert_eq!(
+        parse_player_login(
+            "Alex[/0:0:0:0:0:0:0:1%0:40000] logged in with entity id 2 at (0.0, 0.0, 0.0)"
+        ),
+        Some(("Alex".to_string(), "::1".parse().unwrap()))
+    );
+    assert_eq!(parse_player_login("Steve joined the game"), None);
+}
+
+#[test]
+fn test_parse_server_started() {
+    assert!(parse_server_started(
+        "[12:00:00 INFO]: Done (3.14s)! For help, type \"help\""
+    ));
+    assert!(parse_server_started(
+        "12:00:00 [INFO] Listening on /0.0.0.0:25577"
+    ));
+    assert!(!parse_server_started(
+        "[12:00:00 INFO]: Starting minecraft server version 1.20.4"
+    ));
+}
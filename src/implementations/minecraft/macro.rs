@@ -9,6 +9,7 @@ use color_eyre::eyre::{eyre, Context};
 use deno_core::{anyhow, op, OpState};
 
 use crate::{
+    command_template,
     error::Error,
     events::{CausedBy, EventInner},
     macro_executor::{self, MacroPID, SpawnResult, WorkerOptionGenerator},
@@ -34,6 +35,32 @@ async fn send_rcon(state: Rc<RefCell<OpState>>, cmd: String) -> Result<String, a
     Ok(ret)
 }
 
+/// `tellraw` with user text, escaped so macros can't produce a malformed command
+#[op]
+async fn send_tellraw(
+    state: Rc<RefCell<OpState>>,
+    target: String,
+    text: String,
+) -> Result<(), anyhow::Error> {
+    let instance = state.borrow().borrow::<MinecraftInstance>().clone();
+    instance
+        .send_command(
+            &command_template::tellraw(&target, &text)?,
+            CausedBy::Unknown,
+        )
+        .await?;
+    Ok(())
+}
+
+#[op]
+async fn send_say(state: Rc<RefCell<OpState>>, text: String) -> Result<(), anyhow::Error> {
+    let instance = state.borrow().borrow::<MinecraftInstance>().clone();
+    instance
+        .send_command(&command_template::say(&text), CausedBy::Unknown)
+        .await?;
+    Ok(())
+}
+
 #[op]
 async fn on_event(
     state: Rc<RefCell<OpState>>,
@@ -157,6 +184,8 @@ impl WorkerOptionGenerator for MinecraftMainWorkerGenerator {
             .ops(vec![
                 send_stdin::decl(),
                 send_rcon::decl(),
+                send_tellraw::decl(),
+                send_say::decl(),
                 on_event::decl(),
             ])
             .state({
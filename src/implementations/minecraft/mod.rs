@@ -1,22 +1,39 @@
+mod backup;
+pub mod commands;
+pub mod config_template;
 pub mod configurable;
+pub mod crash_analysis;
+pub mod eula;
 pub mod fabric;
 mod forge;
-mod line_parser;
+pub mod java;
+pub mod jvm_presets;
+pub mod line_parser;
 pub mod r#macro;
+pub mod mod_updates;
+pub mod modrinth;
+mod nbt;
+mod neoforge;
 mod paper;
 pub mod player;
 mod players_manager;
+pub mod preflight;
+pub mod proxy;
+mod purpur;
+pub mod region;
 pub mod resource;
+pub mod resource_pack;
 pub mod server;
 pub mod util;
 mod vanilla;
 pub mod versions;
+pub mod world;
 
 use color_eyre::eyre::{eyre, Context, ContextCompat};
 use enum_kinds::EnumKind;
 use indexmap::IndexMap;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::process::Stdio;
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
@@ -29,16 +46,19 @@ use tokio::sync::Mutex;
 use ::serde::{Deserialize, Serialize};
 use serde_json::to_string_pretty;
 
-use tracing::error;
+use tracing::{error, warn};
 
 use tokio;
 use ts_rs::TS;
 
-use crate::error::Error;
+use crate::changelog::Changelog;
+use crate::command_template;
+use crate::error::{Error, ErrorKind};
 use crate::event_broadcaster::EventBroadcaster;
-use crate::events::{Event, ProgressionEventID};
+use crate::events::{CausedBy, Event, ProgressionEventID};
+use crate::host::HostInfo;
+use crate::instance_notes::InstanceNotes;
 use crate::macro_executor::{MacroExecutor, MacroPID};
-use crate::prelude::path_to_binaries;
 use crate::traits::t_configurable::PathBuf;
 
 use crate::traits::t_configurable::manifest::{
@@ -47,20 +67,20 @@ use crate::traits::t_configurable::manifest::{
 };
 
 use crate::traits::t_macro::TaskEntry;
-use crate::traits::t_server::State;
-use crate::traits::TInstance;
+use crate::traits::t_server::{State, TServer};
+use crate::traits::{Capability, TInstance};
 use crate::types::{DotLodestoneConfig, InstanceUuid};
-use crate::util::{
-    dont_spawn_terminal, download_file, format_byte, format_byte_download, unzip_file_async,
-    UnzipOption,
-};
+use crate::util::{dont_spawn_terminal, download_file, format_byte, format_byte_download};
 
 use self::configurable::{CmdArgSetting, ServerPropertySetting};
 use self::fabric::get_fabric_minecraft_versions;
 use self::forge::get_forge_minecraft_versions;
+use self::neoforge::get_neoforge_minecraft_versions;
 use self::paper::get_paper_minecraft_versions;
 use self::players_manager::PlayersManager;
-use self::util::{get_jre_url, get_server_jar_url, read_properties_from_path};
+use self::proxy::get_velocity_versions;
+use self::purpur::get_purpur_minecraft_versions;
+use self::util::{get_java_major, get_server_jar_url, read_properties_from_path};
 use self::vanilla::get_vanilla_minecraft_versions;
 
 #[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
@@ -74,7 +94,16 @@ pub struct FabricInstallerVersion(String);
 pub struct PaperBuildVersion(i64);
 #[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
 #[ts(export)]
+pub struct PurpurBuildVersion(i64);
+#[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
+#[ts(export)]
 pub struct ForgeBuildVersion(String);
+#[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
+#[ts(export)]
+pub struct NeoForgeBuildVersion(String);
+#[derive(Debug, Clone, TS, Serialize, Deserialize, PartialEq)]
+#[ts(export)]
+pub struct VelocityBuildVersion(i64);
 
 /// A parameter for constructor of `MinecraftInstance`
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, EnumKind)]
@@ -89,10 +118,36 @@ pub enum Flavour {
     Paper {
         build_version: Option<PaperBuildVersion>,
     },
+    Purpur {
+        build_version: Option<PurpurBuildVersion>,
+    },
     Spigot,
     Forge {
         build_version: Option<ForgeBuildVersion>,
     },
+    NeoForge {
+        build_version: Option<NeoForgeBuildVersion>,
+    },
+    /// a proxy in front of other instances, its version is Velocity's own
+    Velocity {
+        build_version: Option<VelocityBuildVersion>,
+    },
+    /// a proxy in front of other instances, only its latest build is available
+    BungeeCord,
+}
+
+impl Flavour {
+    pub fn is_proxy(&self) -> bool {
+        matches!(self, Flavour::Velocity { .. } | Flavour::BungeeCord)
+    }
+
+    /// BungeeCord has no `stop` command
+    pub fn stop_command(&self) -> &'static str {
+        match self {
+            Flavour::BungeeCord => "end",
+            _ => "stop",
+        }
+    }
 }
 
 impl From<FlavourKind> for Flavour {
@@ -106,10 +161,20 @@ impl From<FlavourKind> for Flavour {
             FlavourKind::Paper => Flavour::Paper {
                 build_version: None,
             },
+            FlavourKind::Purpur => Flavour::Purpur {
+                build_version: None,
+            },
             FlavourKind::Spigot => Flavour::Spigot,
             FlavourKind::Forge => Flavour::Forge {
                 build_version: None,
             },
+            FlavourKind::NeoForge => Flavour::NeoForge {
+                build_version: None,
+            },
+            FlavourKind::Velocity => Flavour::Velocity {
+                build_version: None,
+            },
+            FlavourKind::BungeeCord => Flavour::BungeeCord,
         }
     }
 }
@@ -120,8 +185,12 @@ impl ToString for Flavour {
             Flavour::Vanilla => "vanilla".to_string(),
             Flavour::Fabric { .. } => "fabric".to_string(),
             Flavour::Paper { .. } => "paper".to_string(),
+            Flavour::Purpur { .. } => "purpur".to_string(),
             Flavour::Spigot => "spigot".to_string(),
             Flavour::Forge { .. } => "forge".to_string(),
+            Flavour::NeoForge { .. } => "neoforge".to_string(),
+            Flavour::Velocity { .. } => "velocity".to_string(),
+            Flavour::BungeeCord => "bungeecord".to_string(),
         }
     }
 }
@@ -132,8 +201,12 @@ impl ToString for FlavourKind {
             FlavourKind::Vanilla => "vanilla".to_string(),
             FlavourKind::Fabric => "fabric".to_string(),
             FlavourKind::Paper => "paper".to_string(),
+            FlavourKind::Purpur => "purpur".to_string(),
             FlavourKind::Spigot => "spigot".to_string(),
             FlavourKind::Forge => "forge".to_string(),
+            FlavourKind::NeoForge => "neoforge".to_string(),
+            FlavourKind::Velocity => "velocity".to_string(),
+            FlavourKind::BungeeCord => "bungeecord".to_string(),
         }
     }
 }
@@ -151,6 +224,21 @@ pub struct SetupConfig {
     pub auto_start: Option<bool>,
     pub restart_on_crash: Option<bool>,
     pub backup_period: Option<u32>,
+    /// id of the config template, `None` leaves the server's own defaults
+    #[serde(default)]
+    pub config_template: Option<String>,
+    /// written over the template's `server.properties`
+    #[serde(default)]
+    pub server_properties: Vec<(String, String)>,
+    /// install `modrinth::performance_mods` of the flavour for the instance's version
+    #[serde(default)]
+    pub performance_mods: bool,
+    /// id of a `jvm_presets` preset, `None` passes only `cmd_args`
+    #[serde(default)]
+    pub jvm_preset: Option<String>,
+    /// written to `eula.txt`, the server won't start until it's accepted
+    #[serde(default)]
+    pub accept_eula: bool,
 }
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RestoreConfig {
@@ -167,7 +255,21 @@ pub struct RestoreConfig {
     pub restart_on_crash: bool,
     pub backup_period: Option<u32>,
     pub jre_major_version: u64,
+    /// `java_cmd` stays when the version changes
+    #[serde(default)]
+    pub java_pinned: bool,
+    /// id of the JVM preset passed before `cmd_args`
+    #[serde(default)]
+    pub jvm_preset: Option<String>,
+    #[serde(default)]
+    pub notes: InstanceNotes,
     pub has_started: bool,
+    /// in CPU cores
+    #[serde(default)]
+    pub cpu_limit: Option<f32>,
+    /// in MB
+    #[serde(default)]
+    pub memory_limit: Option<u32>,
 }
 
 #[derive(Clone)]
@@ -185,7 +287,6 @@ pub struct MinecraftInstance {
     // directory paths
     path_to_macros: PathBuf,
     path_to_resources: PathBuf,
-    path_to_runtimes: PathBuf,
 
     // variables which can be changed at runtime
     auto_start: Arc<AtomicBool>,
@@ -217,8 +318,19 @@ impl MinecraftInstance {
             FlavourKind::Vanilla => get_vanilla_minecraft_versions().await,
             FlavourKind::Fabric => get_fabric_minecraft_versions().await,
             FlavourKind::Paper => get_paper_minecraft_versions().await,
-            FlavourKind::Spigot => todo!(),
+            FlavourKind::Purpur => get_purpur_minecraft_versions().await,
+            FlavourKind::Spigot => {
+                return Err(Error {
+                    kind: ErrorKind::UnsupportedOperation,
+                    source: eyre!(
+                        "Spigot has to be built with BuildTools, Lodestone can't set it up"
+                    ),
+                })
+            }
             FlavourKind::Forge => get_forge_minecraft_versions().await,
+            FlavourKind::NeoForge => get_neoforge_minecraft_versions().await,
+            FlavourKind::Velocity => get_velocity_versions().await,
+            FlavourKind::BungeeCord => Ok(vec!["latest".to_string()]),
         }
         .context("Failed to get minecraft versions")?;
 
@@ -247,12 +359,16 @@ impl MinecraftInstance {
             true,
         );
 
+        // smaller heaps and a lighter collector on a Raspberry Pi and the like
+        let host = HostInfo::detect();
+        let (min_ram, max_ram) = host.default_ram();
+
         let min_ram_setting = SettingManifest::new_required_value(
             "min_ram".to_string(),
             "Minimum RAM".to_string(),
             "The minimum amount of RAM to allocate to the server".to_string(),
-            ConfigurableValue::UnsignedInteger(1024),
-            Some(ConfigurableValue::UnsignedInteger(1024)),
+            ConfigurableValue::UnsignedInteger(min_ram),
+            Some(ConfigurableValue::UnsignedInteger(min_ram)),
             false,
             true,
         );
@@ -261,8 +377,8 @@ impl MinecraftInstance {
             "max_ram".to_string(),
             "Maximum RAM".to_string(),
             "The maximum amount of RAM to allocate to the server".to_string(),
-            ConfigurableValue::UnsignedInteger(2048),
-            Some(ConfigurableValue::UnsignedInteger(2048)),
+            ConfigurableValue::UnsignedInteger(max_ram),
+            Some(ConfigurableValue::UnsignedInteger(max_ram)),
             false,
             true,
         );
@@ -271,7 +387,7 @@ impl MinecraftInstance {
             "cmd_args".to_string(),
             "Command Line Arguments".to_string(),
             "Command line arguments to pass to the server".to_string(),
-            None,
+            Some(ConfigurableValue::String(host.default_jvm_args().join(" "))),
             ConfigurableValueType::String { regex: None },
             None,
             false,
@@ -283,6 +399,23 @@ impl MinecraftInstance {
         section_1_map.insert("version".to_string(), version_setting);
         section_1_map.insert("port".to_string(), port_setting);
 
+        if !matches!(flavour, FlavourKind::Velocity | FlavourKind::BungeeCord) {
+            let eula_setting = SettingManifest::new_optional_value(
+                "accept_eula".to_string(),
+                "Accept the EULA".to_string(),
+                format!(
+                    "I agree to the Minecraft EULA ({}). The server can't start until it's accepted",
+                    eula::EULA_URL
+                ),
+                Some(ConfigurableValue::Boolean(false)),
+                ConfigurableValueType::Boolean,
+                None,
+                false,
+                true,
+            );
+            section_1_map.insert("accept_eula".to_string(), eula_setting);
+        }
+
         let mut section_2_map = IndexMap::new();
 
         section_2_map.insert("min_ram".to_string(), min_ram_setting);
@@ -291,6 +424,109 @@ impl MinecraftInstance {
 
         section_2_map.insert("cmd_args".to_string(), command_line_args_setting);
 
+        let presets = jvm_presets::presets();
+        let mut preset_options: Vec<String> = presets.iter().map(|p| p.id.clone()).collect();
+        preset_options.push(jvm_presets::NO_PRESET.to_string());
+        let preset_descriptions: Vec<String> = presets
+            .iter()
+            .map(|p| format!("{}: {}", p.id, p.description))
+            .collect();
+        let jvm_preset_setting = SettingManifest::new_optional_value(
+            "jvm_preset".to_string(),
+            "JVM Preset".to_string(),
+            format!(
+                "Garbage collector flags passed before the command line arguments, {} for none. {}",
+                jvm_presets::NO_PRESET,
+                preset_descriptions.join(". ")
+            ),
+            Some(ConfigurableValue::Enum(jvm_presets::NO_PRESET.to_string())),
+            ConfigurableValueType::Enum {
+                options: preset_options,
+            },
+            None,
+            false,
+            true,
+        );
+        section_2_map.insert("jvm_preset".to_string(), jvm_preset_setting);
+
+        if matches!(
+            flavour,
+            FlavourKind::Paper | FlavourKind::Purpur | FlavourKind::Velocity
+        ) {
+            let build_setting = SettingManifest::new_optional_value(
+                "build_version".to_string(),
+                "Build".to_string(),
+                format!(
+                    "The {} build to download, the newest stable build if left empty",
+                    flavour.to_string()
+                ),
+                None,
+                ConfigurableValueType::UnsignedInteger {
+                    min: Some(1),
+                    max: None,
+                },
+                None,
+                false,
+                true,
+            );
+            section_2_map.insert("build_version".to_string(), build_setting);
+        }
+
+        let templates = config_template::templates_for(flavour);
+        if let Some(default_template) = templates.first() {
+            let mut options: Vec<String> = templates.iter().map(|t| t.id.to_string()).collect();
+            options.push(config_template::NO_TEMPLATE.to_string());
+            let descriptions: Vec<String> = templates
+                .iter()
+                .map(|t| format!("{}: {}", t.id, t.description))
+                .collect();
+            let template_setting = SettingManifest::new_optional_value(
+                "config_template".to_string(),
+                "Config Template".to_string(),
+                format!(
+                    "Defaults written to the server's config files, {} to keep the server's own. {}",
+                    config_template::NO_TEMPLATE,
+                    descriptions.join(". ")
+                ),
+                Some(ConfigurableValue::Enum(default_template.id.to_string())),
+                ConfigurableValueType::Enum { options },
+                None,
+                false,
+                true,
+            );
+            section_2_map.insert("config_template".to_string(), template_setting);
+
+            let server_properties_setting = SettingManifest::new_optional_value(
+                "server_properties".to_string(),
+                "Server Properties".to_string(),
+                "key=value lines written to server.properties over the template, e.g. view-distance=12"
+                    .to_string(),
+                None,
+                ConfigurableValueType::String { regex: None },
+                None,
+                false,
+                true,
+            );
+            section_2_map.insert("server_properties".to_string(), server_properties_setting);
+        }
+
+        if !modrinth::performance_mods(flavour).is_empty() {
+            let performance_mods_setting = SettingManifest::new_optional_value(
+                "performance_mods".to_string(),
+                "Performance Mods".to_string(),
+                format!(
+                    "Install the newest {} for this version from Modrinth",
+                    modrinth::performance_mods(flavour).join(", ")
+                ),
+                Some(ConfigurableValue::Boolean(false)),
+                ConfigurableValueType::Boolean,
+                None,
+                false,
+                true,
+            );
+            section_2_map.insert("performance_mods".to_string(), performance_mods_setting);
+        }
+
         let section_1 = SectionManifest::new(
             "section_1".to_string(),
             "Basic Settings".to_string(),
@@ -370,6 +606,59 @@ impl MinecraftInstance {
             .map(|s| s.to_string())
             .collect();
 
+        let build_version = setup_value
+            .get_unique_setting("build_version")
+            .and_then(|setting| setting.get_value())
+            .map(|v| v.try_as_unsigned_integer().unwrap() as i64);
+
+        let config_template = config_template::resolve(
+            &flavour,
+            setup_value
+                .get_unique_setting("config_template")
+                .and_then(|setting| setting.get_value())
+                .map(|v| v.try_as_enum().unwrap().as_str()),
+        )?
+        .map(|template| template.id.to_string());
+
+        let server_properties = match setup_value
+            .get_unique_setting("server_properties")
+            .and_then(|setting| setting.get_value())
+        {
+            Some(v) => config_template::parse_overrides(v.try_as_string().unwrap())?,
+            None => Vec::new(),
+        };
+
+        let performance_mods = setup_value
+            .get_unique_setting("performance_mods")
+            .and_then(|setting| setting.get_value())
+            .map(|v| v.try_as_boolean().unwrap())
+            .unwrap_or(false);
+
+        let jvm_preset = setup_value
+            .get_unique_setting("jvm_preset")
+            .and_then(|setting| setting.get_value())
+            .map(|v| v.try_as_enum().unwrap().clone())
+            .filter(|id| id != jvm_presets::NO_PRESET);
+
+        let accept_eula = setup_value
+            .get_unique_setting("accept_eula")
+            .and_then(|setting| setting.get_value())
+            .map(|v| v.try_as_boolean().unwrap())
+            .unwrap_or(false);
+
+        let flavour = match flavour {
+            FlavourKind::Paper => Flavour::Paper {
+                build_version: build_version.map(PaperBuildVersion),
+            },
+            FlavourKind::Purpur => Flavour::Purpur {
+                build_version: build_version.map(PurpurBuildVersion),
+            },
+            FlavourKind::Velocity => Flavour::Velocity {
+                build_version: build_version.map(VelocityBuildVersion),
+            },
+            _ => flavour.into(),
+        };
+
         Ok(SetupConfig {
             name,
             description,
@@ -378,10 +667,15 @@ impl MinecraftInstance {
             min_ram: Some(min_ram),
             max_ram: Some(max_ram),
             cmd_args,
-            flavour: flavour.into(),
+            flavour,
             auto_start: Some(setup_value.auto_start),
             restart_on_crash: Some(setup_value.restart_on_crash),
             backup_period: None,
+            config_template,
+            server_properties,
+            performance_mods,
+            jvm_preset,
+            accept_eula,
         })
     }
 
@@ -441,7 +735,6 @@ impl MinecraftInstance {
         let path_to_macros = path_to_instance.join("macros");
         let path_to_resources = path_to_instance.join("resources");
         let path_to_properties = path_to_instance.join("server.properties");
-        let path_to_runtimes = path_to_binaries().to_owned();
 
         let uuid = dot_lodestone_config.uuid().to_owned();
 
@@ -457,77 +750,48 @@ impl MinecraftInstance {
             .and(tokio::fs::create_dir_all(&path_to_resources.join("mods")).await)
             .and(tokio::fs::create_dir_all(&path_to_resources.join("worlds")).await)
             .and(tokio::fs::create_dir_all(&path_to_resources.join("defaults")).await)
-            .and(tokio::fs::write(&path_to_eula, "#generated by Lodestone\neula=true").await)
+            .and(tokio::fs::write(&path_to_eula, eula::eula_file(config.accept_eula)).await)
             .and(
-                tokio::fs::write(&path_to_properties, format!("server-port={}", config.port)).await,
+                tokio::fs::write(
+                    &path_to_properties,
+                    config_template::server_properties(&config)?,
+                )
+                .await,
             )
             .context("Could not create some files or directories for instance")
             .map_err(|e| {
                 error!("{e}");
                 e
             })?;
+        config_template::write_template_files(&config, &path_to_instance).await?;
 
         // Step 2: Download JRE
-        let (url, jre_major_version) = get_jre_url(config.version.as_str())
-            .await
-            .context("Could not get JRE URL")?;
-        if !path_to_runtimes
-            .join("java")
-            .join(format!("jre{}", jre_major_version))
-            .exists()
-        {
-            let downloaded = download_file(
-                &url,
-                &path_to_runtimes.join("java"),
-                None,
-                {
-                    let event_broadcaster = event_broadcaster.clone();
-                    &move |dl| {
-                        if let Some(total) = dl.total {
-                            event_broadcaster.send(Event::new_progression_event_update(
-                                progression_event_id,
-                                format!(
-                                    "2/4: Downloading JRE {}",
-                                    format_byte_download(dl.downloaded, total)
-                                ),
-                                (dl.step as f64 / total as f64) * 4.0,
-                            ));
-                        }
+        // a proxy's version isn't a Minecraft version, its Java is fixed instead
+        let jre_major_version = if config.flavour.is_proxy() {
+            proxy::PROXY_JAVA_MAJOR
+        } else {
+            get_java_major(config.version.as_str())
+                .await
+                .context("Could not get the Java version of this Minecraft version")?
+        };
+        jvm_presets::resolve(config.jvm_preset.as_deref(), jre_major_version)?;
+        if !crate::java_manager::managed_java(jre_major_version).is_file() {
+            crate::java_manager::ensure_runtime(jre_major_version, None, {
+                let event_broadcaster = event_broadcaster.clone();
+                &move |dl| {
+                    if let Some(total) = dl.total {
+                        event_broadcaster.send(Event::new_progression_event_update(
+                            progression_event_id,
+                            format!(
+                                "2/4: Downloading JRE {}",
+                                format_byte_download(dl.downloaded, total)
+                            ),
+                            (dl.step as f64 / total as f64) * 4.0,
+                        ));
                     }
-                },
-                true,
-            )
-            .await?;
-
-            let unzipped_content = unzip_file_async(
-                &downloaded,
-                UnzipOption::ToDir(path_to_runtimes.join("java")),
-            )
+                }
+            })
             .await?;
-            if unzipped_content.len() != 1 {
-                return Err(eyre!(
-                    "Expected only one file in the JRE archive, got {}",
-                    unzipped_content.len()
-                )
-                .into());
-            }
-
-            tokio::fs::remove_file(&downloaded).await.context(format!(
-                "Could not remove downloaded JRE file {}",
-                downloaded.display()
-            ))?;
-
-            tokio::fs::rename(
-                unzipped_content.iter().last().unwrap(),
-                path_to_runtimes
-                    .join("java")
-                    .join(format!("jre{}", jre_major_version)),
-            )
-            .await
-            .context(format!(
-                "Could not rename JRE directory {}",
-                unzipped_content.iter().last().unwrap().display()
-            ))?;
         } else {
             event_broadcaster.send(Event::new_progression_event_update(
                 progression_event_id,
@@ -551,6 +815,7 @@ impl MinecraftInstance {
             })?;
         let jar_name = match flavour {
             Flavour::Forge { .. } => "forge-installer.jar",
+            Flavour::NeoForge { .. } => "neoforge-installer.jar",
             _ => "server.jar",
         };
 
@@ -589,27 +854,19 @@ impl MinecraftInstance {
             true,
         )
         .await?;
-        let jre = path_to_runtimes
-            .join("java")
-            .join(format!("jre{}", jre_major_version))
-            .join(if std::env::consts::OS == "macos" {
-                "Contents/Home/bin"
-            } else {
-                "bin"
-            })
-            .join("java");
-        // Step 3 (part 2): Forge Setup
-        if let Flavour::Forge { .. } = flavour.clone() {
+        let jre = crate::java_manager::managed_java(jre_major_version);
+        // Step 3 (part 2): Forge and NeoForge Setup, both installers take the same flags
+        if let Flavour::Forge { .. } | Flavour::NeoForge { .. } = flavour.clone() {
             event_broadcaster.send(Event::new_progression_event_update(
                 progression_event_id,
-                "3/4: Installing Forge Server",
+                format!("3/4: Installing {} Server", flavour_name),
                 1.0,
             ));
 
             if !dont_spawn_terminal(
                 Command::new(&jre)
                     .arg("-jar")
-                    .arg(&path_to_instance.join("forge-installer.jar"))
+                    .arg(&path_to_instance.join(jar_name))
                     .arg("--installServer")
                     .arg(&path_to_instance)
                     .current_dir(&path_to_instance),
@@ -618,13 +875,18 @@ impl MinecraftInstance {
             .stdout(Stdio::null())
             .stdin(Stdio::null())
             .spawn()
-            .context("Failed to start forge-installer.jar")?
+            .context(format!("Failed to start {}", jar_name))?
             .wait()
             .await
-            .context("forge-installer.jar failed")?
+            .context(format!("{} failed", jar_name))?
             .success()
             {
-                return Err(eyre!("Failed to install forge server").into());
+                return Err(eyre!(
+                    "Failed to install {} server, {}.log in the instance has the installer's output",
+                    flavour_name,
+                    jar_name
+                )
+                .into());
             }
 
             tokio::fs::write(
@@ -642,6 +904,25 @@ impl MinecraftInstance {
             1.0,
         ));
 
+        // the server runs without them, so a mod Modrinth has no build of yet for a
+        // brand new version only costs a warning
+        if config.performance_mods {
+            let flavour = FlavourKind::from(&flavour);
+            if let Some(loader) = modrinth::loader_of(&flavour) {
+                for (project, e) in modrinth::install_projects(
+                    modrinth::performance_mods(&flavour),
+                    loader,
+                    &config.version,
+                    &path_to_instance.join("mods"),
+                )
+                .await
+                {
+                    warn!("Failed to install performance mod {}: {}", project, e);
+                }
+            }
+        }
+
+        let (default_min_ram, default_max_ram) = HostInfo::detect().default_ram();
         let restore_config = RestoreConfig {
             name: config.name,
             version: config.version,
@@ -649,14 +930,19 @@ impl MinecraftInstance {
             description: config.description.unwrap_or_default(),
             cmd_args: config.cmd_args,
             port: config.port,
-            min_ram: config.min_ram.unwrap_or(2048),
-            max_ram: config.max_ram.unwrap_or(4096),
+            min_ram: config.min_ram.unwrap_or(default_min_ram),
+            max_ram: config.max_ram.unwrap_or(default_max_ram),
             auto_start: config.auto_start.unwrap_or(false),
             restart_on_crash: config.restart_on_crash.unwrap_or(false),
             backup_period: config.backup_period,
             jre_major_version,
+            java_pinned: false,
+            jvm_preset: config.jvm_preset,
+            notes: InstanceNotes::default(),
             has_started: false,
             java_cmd: Some(jre.to_string_lossy().to_string()),
+            cpu_limit: None,
+            memory_limit: None,
         };
         // create config file
         tokio::fs::write(
@@ -697,7 +983,6 @@ impl MinecraftInstance {
         let path_to_macros = path_to_instance.join("macros");
         let path_to_resources = path_to_instance.join("resources");
         let path_to_properties = path_to_instance.join("server.properties");
-        let path_to_runtimes = path_to_binaries().clone();
         // if the properties file doesn't exist, create it
         if !path_to_properties.exists() {
             tokio::fs::write(
@@ -707,15 +992,10 @@ impl MinecraftInstance {
             .await
             .expect("failed to write to server.properties");
         };
-        let java_path = path_to_runtimes
-            .join("java")
-            .join(format!("jre{}", restore_config.jre_major_version))
-            .join(if std::env::consts::OS == "macos" {
-                "Contents/Home/bin"
-            } else {
-                "bin"
-            })
-            .join("java");
+        let java_path = match &restore_config.java_cmd {
+            Some(java_cmd) => PathBuf::from(java_cmd),
+            None => crate::java_manager::managed_java(restore_config.jre_major_version),
+        };
 
         let configurable_manifest = Arc::new(Mutex::new(Self::init_configurable_manifest(
             &restore_config,
@@ -741,7 +1021,6 @@ impl MinecraftInstance {
             path_to_resources,
             macro_executor,
             event_broadcaster,
-            path_to_runtimes,
             process: Arc::new(Mutex::new(None)),
             system: Arc::new(Mutex::new(sysinfo::System::new_all())),
             stdin: Arc::new(Mutex::new(None)),
@@ -771,6 +1050,13 @@ impl MinecraftInstance {
         Ok(())
     }
 
+    /// Sets the version back once the jar of the old one was put back in place
+    pub(crate) async fn restore_version(&self, version: String) -> Result<(), Error> {
+        self.follow_java_of(&version).await?;
+        self.config.lock().await.version = version;
+        self.write_config_to_file().await
+    }
+
     async fn read_properties(&mut self) -> Result<(), Error> {
         let properties = read_properties_from_path(&self.path_to_properties).await?;
         let mut lock = self.configurable_manifest.lock().await;
@@ -907,6 +1193,37 @@ impl MinecraftInstance {
             .context("Failed to send rcon command")?;
         Ok(a)
     }
+
+    /// Announces changelog entries that were posted while the server was offline
+    async fn announce_pending_changelog(&self) -> Result<(), Error> {
+        // taken before the server is told, the lock isn't held while it is
+        let pending = Changelog::load(&self.path_to_instance)
+            .await?
+            .take_pending_announcements()
+            .await?;
+        for entry in pending {
+            // `say` makes the title single line, older entries weren't validated
+            self.send_command(
+                &command_template::say(&format!("[Changelog] {}", entry.title)),
+                CausedBy::System,
+            )
+            .await?;
+        }
+        Ok(())
+    }
 }
 
-impl TInstance for MinecraftInstance {}
+impl TInstance for MinecraftInstance {
+    fn capabilities(&self) -> HashSet<Capability> {
+        HashSet::from([
+            Capability::SupportsPlayerList,
+            Capability::SupportsMacros,
+            Capability::SupportsBackups,
+            Capability::SupportsRcon,
+            Capability::SupportsWorlds,
+            Capability::SupportsResourcePacks,
+            Capability::SupportsCommandMetadata,
+            Capability::SupportsMods,
+        ])
+    }
+}
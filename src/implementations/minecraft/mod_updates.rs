@@ -0,0 +1,896 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::backup::pre_change::{ChangeDiff, ModFileChange};
+use crate::error::{Error, ErrorKind};
+use crate::prelude::path_to_tmp;
+use crate::traits::t_server::{State, TServer};
+use crate::traits::Capability;
+
+use super::modrinth::{self, ModrinthVersion};
+use super::resource_pack::sha1_file;
+use super::{FabricLoaderVersion, Flavour, FlavourKind, MinecraftInstance};
+
+/// rounds of looking up the dependencies of new dependencies before giving up
+const MAX_DEPENDENCY_DEPTH: usize = 4;
+
+const PINS_FILE_NAME: &str = ".lodestone_mod_pins.json";
+
+/// updates downloaded by `stage_mod_updates`, with the plan they belong to
+const STAGING_DIR: &str = ".lodestone_mod_updates";
+
+const STAGED_PLAN_FILE_NAME: &str = "plan.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ModChange {
+    pub project_id: String,
+    /// the file replaced, `None` for a dependency that's added
+    pub from_file: Option<String>,
+    pub from_version: Option<String>,
+    pub to_file: String,
+    pub to_version: String,
+    #[serde(skip)]
+    #[ts(skip)]
+    url: String,
+    /// the update this change is part of
+    #[serde(skip)]
+    #[ts(skip)]
+    cause: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct HeldBackUpdate {
+    pub project_id: String,
+    pub file: String,
+    pub to_version: String,
+    pub reason: String,
+}
+
+/// What updating the mods of an instance would do
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default)]
+#[ts(export)]
+pub struct ModUpdatePlan {
+    pub loader: String,
+    pub game_version: String,
+    pub changes: Vec<ModChange>,
+    /// updates left out as they would break another mod, the loader or the game version
+    pub held_back: Vec<HeldBackUpdate>,
+    /// problems of the installed mods that no update causes
+    pub problems: Vec<String>,
+    /// files Modrinth doesn't know, they are left as they are
+    pub unknown_files: Vec<String>,
+}
+
+/// A mod whose updates are held back, keeping the version it was pinned at
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ModPin {
+    pub project_id: String,
+    pub file: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct ModPins {
+    /// by project id
+    pins: BTreeMap<String, ModPin>,
+}
+
+impl ModPins {
+    async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(PINS_FILE_NAME);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse mod pins at {}", path.display()))?,
+        )
+    }
+
+    async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(PINS_FILE_NAME),
+            serde_json::to_string_pretty(self).context("Failed to serialize mod pins")?,
+        )
+        .await
+    }
+}
+
+/// Updates downloaded ahead of time, waiting to be applied
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct StagedModUpdates {
+    pub staged_at: i64,
+    pub plan: ModUpdatePlan,
+}
+
+struct InstalledMod {
+    file: String,
+    version: ModrinthVersion,
+}
+
+/// A mod as it would be once the plan is applied
+struct Planned<'a> {
+    version: &'a ModrinthVersion,
+    /// the update that changes or pulls in the mod, `None` if it stays as it is
+    cause: Option<&'a str>,
+}
+
+enum Resolution {
+    Resolved {
+        changes: Vec<ModChange>,
+        problems: Vec<String>,
+    },
+    /// projects to look up before the plan can be resolved
+    Missing(Vec<String>),
+}
+
+fn supports(version: &ModrinthVersion, loader: &str, game_version: &str) -> bool {
+    (version.loaders.is_empty() || version.loaders.iter().any(|l| l == loader))
+        && (version.game_versions.is_empty()
+            || version.game_versions.iter().any(|v| v == game_version))
+}
+
+/// Applies every update that isn't held back and checks the dependencies of the
+/// resulting set. The first conflict an update causes holds it back and the set is
+/// resolved again, conflicts no update causes are reported as problems.
+fn resolve<'a>(
+    installed: &'a [InstalledMod],
+    updates: &'a HashMap<String, ModrinthVersion>,
+    dependencies: &'a HashMap<String, Option<ModrinthVersion>>,
+    held_back: &mut BTreeMap<String, String>,
+) -> Resolution {
+    // installed mods by their file name, anything else by the file it would add
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    for version in dependencies.values().flatten() {
+        if let Some(file) = version.primary_file() {
+            names.insert(&version.project_id, &file.filename);
+        }
+    }
+    for installed_mod in installed {
+        names.insert(&installed_mod.version.project_id, &installed_mod.file);
+    }
+    let name = |project: &str| names.get(project).copied().unwrap_or(project).to_string();
+
+    loop {
+        let mut planned: BTreeMap<&str, Planned> = BTreeMap::new();
+        for installed_mod in installed {
+            let project = installed_mod.version.project_id.as_str();
+            let planned_mod = match updates.get(project) {
+                Some(update) if !held_back.contains_key(project) => Planned {
+                    version: update,
+                    cause: Some(project),
+                },
+                _ => Planned {
+                    version: &installed_mod.version,
+                    cause: None,
+                },
+            };
+            planned.insert(project, planned_mod);
+        }
+
+        // pull in required dependencies, which can have their own
+        let mut missing = Vec::new();
+        let mut queue: Vec<&str> = planned.keys().copied().collect();
+        while let Some(project) = queue.pop() {
+            let (version, cause) = (planned[project].version, planned[project].cause);
+            for dependency in &version.dependencies {
+                let other = match &dependency.project_id {
+                    Some(other) if dependency.dependency_type == "required" => other.as_str(),
+                    _ => continue,
+                };
+                if planned.contains_key(other) {
+                    continue;
+                }
+                match dependencies.get(other) {
+                    Some(Some(version)) => {
+                        planned.insert(other, Planned { version, cause });
+                        queue.push(other);
+                    }
+                    Some(None) => {}
+                    None => missing.push(other.to_string()),
+                }
+            }
+        }
+        if !missing.is_empty() {
+            missing.sort();
+            missing.dedup();
+            return Resolution::Missing(missing);
+        }
+
+        let mut problems = Vec::new();
+        let mut culprit = None;
+        for (&project, planned_mod) in &planned {
+            for dependency in &planned_mod.version.dependencies {
+                let other = match &dependency.project_id {
+                    Some(other) => other.as_str(),
+                    None => continue,
+                };
+                let other_planned = planned.get(other);
+                let problem = match (dependency.dependency_type.as_str(), other_planned) {
+                    ("required", None) => Some(format!(
+                        "{} requires {}, which has no version for this loader and game version",
+                        name(project),
+                        name(other)
+                    )),
+                    ("required", Some(other_planned)) => match &dependency.version_id {
+                        Some(id) if *id != other_planned.version.id => Some(format!(
+                            "{} requires another version of {} than {}",
+                            name(project),
+                            name(other),
+                            other_planned.version.version_number
+                        )),
+                        _ => None,
+                    },
+                    ("incompatible", Some(other_planned))
+                        if dependency
+                            .version_id
+                            .as_ref()
+                            .map_or(true, |id| *id == other_planned.version.id) =>
+                    {
+                        Some(format!(
+                            "{} is incompatible with {} {}",
+                            name(project),
+                            name(other),
+                            other_planned.version.version_number
+                        ))
+                    }
+                    _ => None,
+                };
+                let problem = match problem {
+                    Some(problem) => problem,
+                    None => continue,
+                };
+                match planned_mod
+                    .cause
+                    .or_else(|| other_planned.and_then(|o| o.cause))
+                {
+                    Some(cause) if culprit.is_none() => culprit = Some((cause, problem)),
+                    Some(_) => {}
+                    None => problems.push(problem),
+                }
+            }
+        }
+        if let Some((cause, reason)) = culprit {
+            held_back.insert(cause.to_string(), reason);
+            continue;
+        }
+
+        let mut changes = Vec::new();
+        for (project, planned_mod) in planned {
+            let (cause, file) = match (planned_mod.cause, planned_mod.version.primary_file()) {
+                (Some(cause), Some(file)) => (cause, file),
+                _ => continue,
+            };
+            let installed_mod = installed.iter().find(|m| m.version.project_id == project);
+            changes.push(ModChange {
+                project_id: project.to_string(),
+                from_file: installed_mod.map(|m| m.file.clone()),
+                from_version: installed_mod.map(|m| m.version.version_number.clone()),
+                to_file: file.filename.clone(),
+                to_version: planned_mod.version.version_number.clone(),
+                url: file.url.clone(),
+                cause: cause.to_string(),
+            });
+        }
+        return Resolution::Resolved { changes, problems };
+    }
+}
+
+/// The `fabricloader` requirement in the `fabric.mod.json` of a mod, `None` if it
+/// has none
+fn fabric_loader_requirement(path: &Path) -> Result<Option<Vec<String>>, Error> {
+    let file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut archive =
+        zip::ZipArchive::new(file).context(format!("{} is not a valid jar", path.display()))?;
+    let mut text = String::new();
+    match archive.by_name("fabric.mod.json") {
+        Ok(mut entry) => entry.read_to_string(&mut text).context(format!(
+            "Failed to read fabric.mod.json of {}",
+            path.display()
+        ))?,
+        Err(_) => return Ok(None),
+    };
+    let metadata: serde_json::Value = match serde_json::from_str(&text) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(None),
+    };
+    Ok(match &metadata["depends"]["fabricloader"] {
+        serde_json::Value::String(requirement) => Some(vec![requirement.clone()]),
+        serde_json::Value::Array(requirements) => Some(
+            requirements
+                .iter()
+                .filter_map(|r| r.as_str().map(str::to_string))
+                .collect(),
+        ),
+        _ => None,
+    })
+}
+
+/// Whether `loader_version` meets one of the requirements. Fabric separates the
+/// comparators of a range with spaces where semver expects commas. A requirement
+/// that can't be parsed is taken as met, the loader has the final word.
+fn meets_requirement(requirements: &[String], loader_version: &str) -> bool {
+    let loader_version = match semver::Version::parse(loader_version) {
+        Ok(version) => version,
+        Err(_) => return true,
+    };
+    requirements.iter().any(|requirement| {
+        let requirement = requirement
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(", ");
+        match semver::VersionReq::parse(&requirement) {
+            Ok(requirement) => requirement.matches(&loader_version),
+            Err(_) => true,
+        }
+    })
+}
+
+impl MinecraftInstance {
+    /// The Modrinth loader of the instance and the directory its mods or plugins are in
+    async fn mod_loader(&self) -> Result<(&'static str, PathBuf), Error> {
+        let flavour = FlavourKind::from(&self.config.lock().await.flavour);
+        let loader = modrinth::loader_of(&flavour)
+            .ok_or_else(|| Error::not_supported(Capability::SupportsMods))?;
+        let dir = match flavour {
+            FlavourKind::Fabric | FlavourKind::Forge | FlavourKind::NeoForge => "mods",
+            _ => "plugins",
+        };
+        Ok((loader, self.path_to_instance.join(dir)))
+    }
+
+    /// Resolves the plan, downloading the files it adds to `staging` if `download`
+    /// is set. The files of a Fabric instance are always downloaded, the loader
+    /// version they need is only in the jar.
+    async fn plan_mod_updates(
+        &self,
+        staging: &Path,
+        download: bool,
+    ) -> Result<ModUpdatePlan, Error> {
+        let (loader, dir) = self.mod_loader().await?;
+        let (flavour, game_version) = {
+            let config = self.config.lock().await;
+            (config.flavour.clone(), config.version.clone())
+        };
+        let fabric_loader_version = match flavour {
+            Flavour::Fabric {
+                loader_version: Some(FabricLoaderVersion(version)),
+                ..
+            } => Some(version),
+            _ => None,
+        };
+        let mut plan = ModUpdatePlan {
+            loader: loader.to_string(),
+            game_version: game_version.clone(),
+            ..Default::default()
+        };
+
+        let mut files = Vec::new();
+        if dir.is_dir() {
+            for path in crate::util::list_dir(&dir, Some(false)).await? {
+                if path
+                    .extension()
+                    .map_or(false, |extension| extension == "jar")
+                {
+                    let file = path.file_name().unwrap().to_string_lossy().to_string();
+                    files.push((file, sha1_file(&path)?));
+                }
+            }
+        }
+        if files.is_empty() {
+            return Ok(plan);
+        }
+        let hashes: Vec<String> = files.iter().map(|(_, hash)| hash.clone()).collect();
+        let mut versions = modrinth::get_versions_by_hash(&hashes).await?;
+        let mut installed = Vec::new();
+        for (file, hash) in files {
+            match versions.remove(&hash) {
+                Some(version) => installed.push(InstalledMod { file, version }),
+                None => plan.unknown_files.push(file),
+            }
+        }
+
+        let mut held_back = BTreeMap::new();
+        let mut updates = HashMap::new();
+        for (_, version) in modrinth::get_updates_by_hash(&hashes, loader, &game_version).await? {
+            if installed.iter().any(|m| m.version.id == version.id) {
+                continue;
+            }
+            if !supports(&version, loader, &game_version) {
+                held_back.insert(
+                    version.project_id.clone(),
+                    format!("Doesn't support {} {}", loader, game_version),
+                );
+            }
+            updates.insert(version.project_id.clone(), version);
+        }
+        for (project, pin) in ModPins::load(&self.path_to_instance).await?.pins {
+            if updates.contains_key(&project) {
+                held_back.insert(project, format!("Pinned to {}", pin.version));
+            }
+        }
+
+        let mut dependencies: HashMap<String, Option<ModrinthVersion>> = HashMap::new();
+        let mut depth = 0;
+        loop {
+            let (changes, problems) =
+                match resolve(&installed, &updates, &dependencies, &mut held_back) {
+                    Resolution::Resolved { changes, problems } => (changes, problems),
+                    Resolution::Missing(projects) => {
+                        depth += 1;
+                        for project in projects {
+                            let version = if depth > MAX_DEPENDENCY_DEPTH {
+                                None
+                            } else {
+                                match modrinth::get_latest_version(&project, loader, &game_version)
+                                    .await
+                                {
+                                    Ok(version) => Some(version),
+                                    Err(e) if matches!(e.kind, ErrorKind::NotFound) => None,
+                                    Err(e) => return Err(e),
+                                }
+                            };
+                            dependencies.insert(project, version);
+                        }
+                        continue;
+                    }
+                };
+            if !download && fabric_loader_version.is_none() {
+                plan.changes = changes;
+                plan.problems = problems;
+                break;
+            }
+            let mut too_new = None;
+            for change in &changes {
+                let path = staging.join(&change.to_file);
+                if !path.is_file() {
+                    crate::util::download_file(
+                        &change.url,
+                        staging,
+                        Some(&change.to_file),
+                        &|_| {},
+                        true,
+                    )
+                    .await?;
+                }
+                if let Some(loader_version) = &fabric_loader_version {
+                    match fabric_loader_requirement(&path)? {
+                        Some(requirement) if !meets_requirement(&requirement, loader_version) => {
+                            too_new = Some((
+                                change.cause.clone(),
+                                format!(
+                                    "{} {} needs Fabric Loader {}, the instance has {}",
+                                    change.to_file,
+                                    change.to_version,
+                                    requirement.join(" or "),
+                                    loader_version
+                                ),
+                            ));
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            match too_new {
+                Some((cause, reason)) => {
+                    held_back.insert(cause, reason);
+                }
+                None => {
+                    plan.changes = changes;
+                    plan.problems = problems;
+                    break;
+                }
+            }
+        }
+
+        for (project, reason) in held_back {
+            let (file, update) = match (
+                installed.iter().find(|m| m.version.project_id == project),
+                updates.get(&project),
+            ) {
+                (Some(installed_mod), Some(update)) => (installed_mod.file.clone(), update),
+                _ => continue,
+            };
+            plan.held_back.push(HeldBackUpdate {
+                project_id: project,
+                file,
+                to_version: update.version_number.clone(),
+                reason,
+            });
+        }
+        Ok(plan)
+    }
+
+    /// What `update_mods` would do, without changing anything
+    pub async fn mod_update_plan(&self) -> Result<ModUpdatePlan, Error> {
+        tokio::fs::create_dir_all(path_to_tmp())
+            .await
+            .context("Failed to create tmp dir")?;
+        let staging =
+            tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary directory")?;
+        self.plan_mod_updates(staging.path(), false).await
+    }
+
+    pub(crate) async fn check_stopped(&self) -> Result<(), Error> {
+        if self.state().await != State::Stopped {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Stop the instance before updating its mods"),
+            });
+        }
+        Ok(())
+    }
+
+    /// The files an applied plan replaced, for rolling it back. `None` if it changed
+    /// nothing.
+    pub(crate) async fn mod_change_diff(&self, plan: &ModUpdatePlan) -> Option<ChangeDiff> {
+        if plan.changes.is_empty() {
+            return None;
+        }
+        let (_, dir) = self.mod_loader().await.ok()?;
+        let relative = |file: &str| {
+            dir.strip_prefix(&self.path_to_instance)
+                .unwrap_or(&dir)
+                .join(file)
+                .to_string_lossy()
+                .replace('\\', "/")
+        };
+        Some(ChangeDiff::Mods {
+            files: plan
+                .changes
+                .iter()
+                .map(|change| ModFileChange {
+                    from_file: change.from_file.as_deref().map(relative),
+                    to_file: relative(&change.to_file),
+                })
+                .collect(),
+        })
+    }
+
+    /// Updates the mods or plugins of a stopped instance and adds the dependencies
+    /// the new versions need. Held back updates are left out, and nothing is
+    /// replaced before every new file is downloaded.
+    pub async fn update_mods(&self) -> Result<ModUpdatePlan, Error> {
+        self.check_stopped().await?;
+        let (_, dir) = self.mod_loader().await?;
+        tokio::fs::create_dir_all(path_to_tmp())
+            .await
+            .context("Failed to create tmp dir")?;
+        let staging =
+            tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary directory")?;
+        let plan = self.plan_mod_updates(staging.path(), true).await?;
+        apply_changes(&dir, staging.path(), &plan.changes).await?;
+        Ok(plan)
+    }
+
+    /// Downloads the updates into the instance's staging directory, replacing
+    /// whatever was staged before. Nothing is kept if there's nothing to update.
+    pub async fn stage_mod_updates(&self) -> Result<StagedModUpdates, Error> {
+        self.discard_staged_mod_updates().await?;
+        let staging = self.path_to_instance.join(STAGING_DIR);
+        crate::util::fs::create_dir_all(&staging).await?;
+        let staged = match self.plan_mod_updates(&staging, true).await {
+            Ok(plan) => StagedModUpdates {
+                staged_at: chrono::Utc::now().timestamp(),
+                plan,
+            },
+            Err(e) => {
+                crate::util::fs::remove_dir_all(&staging).await?;
+                return Err(e);
+            }
+        };
+        if staged.plan.changes.is_empty() {
+            crate::util::fs::remove_dir_all(&staging).await?;
+            return Ok(staged);
+        }
+        crate::util::fs::write_all(
+            staging.join(STAGED_PLAN_FILE_NAME),
+            serde_json::to_string_pretty(&staged)
+                .context("Failed to serialize staged mod updates")?,
+        )
+        .await?;
+        Ok(staged)
+    }
+
+    pub async fn staged_mod_updates(&self) -> Result<Option<StagedModUpdates>, Error> {
+        let path = self
+            .path_to_instance
+            .join(STAGING_DIR)
+            .join(STAGED_PLAN_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse staged mod updates at {}", path.display()),
+            )?,
+        ))
+    }
+
+    /// Applies what `stage_mod_updates` downloaded. The plan no longer holds if a
+    /// mod was replaced, removed or pinned since, so it has to be staged again.
+    pub async fn apply_staged_mod_updates(&self) -> Result<ModUpdatePlan, Error> {
+        self.check_stopped().await?;
+        let staged = self.staged_mod_updates().await?.ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("No mod updates are staged"),
+        })?;
+        let (_, dir) = self.mod_loader().await?;
+        let staging = self.path_to_instance.join(STAGING_DIR);
+        let pins = ModPins::load(&self.path_to_instance).await?;
+        for change in &staged.plan.changes {
+            let outdated = change.from_file.as_ref().map_or(false, |from_file| {
+                !dir.join(from_file).is_file() || pins.pins.contains_key(&change.project_id)
+            }) || !staging.join(&change.to_file).is_file();
+            if outdated {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!(
+                        "The mods changed since the updates were staged, check for updates again"
+                    ),
+                });
+            }
+        }
+        apply_changes(&dir, &staging, &staged.plan.changes).await?;
+        crate::util::fs::remove_dir_all(&staging).await?;
+        Ok(staged.plan)
+    }
+
+    pub async fn discard_staged_mod_updates(&self) -> Result<(), Error> {
+        let staging = self.path_to_instance.join(STAGING_DIR);
+        if staging.exists() {
+            crate::util::fs::remove_dir_all(&staging).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn mod_pins(&self) -> Result<Vec<ModPin>, Error> {
+        Ok(ModPins::load(&self.path_to_instance)
+            .await?
+            .pins
+            .into_values()
+            .collect())
+    }
+
+    /// Pins the mod installed as `file` at its current version
+    pub async fn pin_mod(&self, file: &str) -> Result<ModPin, Error> {
+        let (_, dir) = self.mod_loader().await?;
+        let path = dir.join(file);
+        if file.contains(['/', '\\']) || !path.is_file() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("{} is not installed", file),
+            });
+        }
+        let hash = sha1_file(&path)?;
+        let version = modrinth::get_versions_by_hash(&[hash.clone()])
+            .await?
+            .remove(&hash)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("{} is not on Modrinth, it is never updated", file),
+            })?;
+        let pin = ModPin {
+            project_id: version.project_id,
+            file: file.to_string(),
+            version: version.version_number,
+        };
+        let mut pins = ModPins::load(&self.path_to_instance).await?;
+        pins.pins.insert(pin.project_id.clone(), pin.clone());
+        pins.save(&self.path_to_instance).await?;
+        Ok(pin)
+    }
+
+    pub async fn unpin_mod(&self, project_id: &str) -> Result<ModPin, Error> {
+        let mut pins = ModPins::load(&self.path_to_instance).await?;
+        let pin = pins.pins.remove(project_id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("The mod is not pinned"),
+        })?;
+        pins.save(&self.path_to_instance).await?;
+        Ok(pin)
+    }
+}
+
+/// Moves the new files from `staging` into `dir`, removing the ones they replace
+async fn apply_changes(dir: &Path, staging: &Path, changes: &[ModChange]) -> Result<(), Error> {
+    crate::util::fs::create_dir_all(dir).await?;
+    for change in changes {
+        if let Some(from_file) = &change.from_file {
+            crate::util::fs::remove_file(dir.join(from_file)).await?;
+        }
+        crate::util::fs::rename(staging.join(&change.to_file), dir.join(&change.to_file)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::implementations::minecraft::modrinth::{ModrinthDependency, ModrinthFile};
+
+    fn version(
+        project: &str,
+        id: &str,
+        dependencies: &[(&str, Option<&str>, &str)],
+    ) -> ModrinthVersion {
+        ModrinthVersion {
+            id: id.to_string(),
+            project_id: project.to_string(),
+            version_number: id.to_string(),
+            version_type: "release".to_string(),
+            files: vec![ModrinthFile {
+                url: format!("https://cdn.modrinth.com/{}.jar", id),
+                filename: format!("{}.jar", id),
+                primary: true,
+            }],
+            dependencies: dependencies
+                .iter()
+                .map(|(project, version, dependency_type)| ModrinthDependency {
+                    project_id: Some(project.to_string()),
+                    version_id: version.map(str::to_string),
+                    dependency_type: dependency_type.to_string(),
+                })
+                .collect(),
+            loaders: vec!["fabric".to_string()],
+            game_versions: vec!["1.20.4".to_string()],
+        }
+    }
+
+    fn installed(
+        project: &str,
+        id: &str,
+        dependencies: &[(&str, Option<&str>, &str)],
+    ) -> InstalledMod {
+        InstalledMod {
+            file: format!("{}.jar", id),
+            version: version(project, id, dependencies),
+        }
+    }
+
+    #[test]
+    fn test_resolve_mod_updates() {
+        let installed = vec![
+            installed("api", "api-1", &[]),
+            // pinned to the installed api
+            installed("addon", "addon-1", &[("api", Some("api-1"), "required")]),
+            installed("lithium", "lithium-1", &[]),
+            installed("sodium", "sodium-1", &[]),
+        ];
+        let updates: HashMap<String, ModrinthVersion> = [
+            version("api", "api-2", &[]),
+            version("lithium", "lithium-2", &[("lib", None, "required")]),
+            version("sodium", "sodium-2", &[("lithium", None, "incompatible")]),
+        ]
+        .into_iter()
+        .map(|version| (version.project_id.clone(), version))
+        .collect();
+        let mut dependencies = HashMap::new();
+        let mut held_back = BTreeMap::new();
+
+        // lithium's new dependency has to be looked up first
+        match resolve(&installed, &updates, &dependencies, &mut held_back) {
+            Resolution::Missing(missing) => assert_eq!(missing, vec!["lib".to_string()]),
+            Resolution::Resolved { .. } => panic!("lib isn't known yet"),
+        }
+        dependencies.insert("lib".to_string(), Some(version("lib", "lib-1", &[])));
+
+        let (changes, problems) = match resolve(&installed, &updates, &dependencies, &mut held_back)
+        {
+            Resolution::Resolved { changes, problems } => (changes, problems),
+            Resolution::Missing(_) => panic!("everything is known"),
+        };
+        assert!(problems.is_empty());
+        assert_eq!(held_back.keys().collect::<Vec<_>>(), vec!["api", "sodium"]);
+        assert!(held_back["api"].contains("addon-1.jar"));
+        let changed: Vec<(&str, Option<&str>)> = changes
+            .iter()
+            .map(|c| (c.to_file.as_str(), c.from_file.as_deref()))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                ("lib-1.jar", None),
+                ("lithium-2.jar", Some("lithium-1.jar"))
+            ]
+        );
+        assert_eq!(changes[0].cause, "lithium");
+
+        // a dependency that doesn't exist for the version holds back what needs it
+        let mut held_back = BTreeMap::new();
+        dependencies.insert("lib".to_string(), None);
+        match resolve(&installed, &updates, &dependencies, &mut held_back) {
+            Resolution::Resolved { changes, .. } => assert!(changes.is_empty()),
+            Resolution::Missing(_) => panic!("everything is known"),
+        }
+        assert!(held_back.contains_key("lithium"));
+    }
+
+    #[test]
+    fn test_fabric_loader_requirement() {
+        assert!(meets_requirement(&[">=0.15.0".to_string()], "0.15.11"));
+        assert!(!meets_requirement(&[">=0.15.0".to_string()], "0.14.21"));
+        assert!(meets_requirement(&[">=0.14 <0.16".to_string()], "0.15.0"));
+        assert!(meets_requirement(
+            &[">=0.16".to_string(), "~0.14.21".to_string()],
+            "0.14.22"
+        ));
+        assert!(meets_requirement(&["*".to_string()], "0.14.0"));
+        assert!(!supports(
+            &version("lithium", "lithium-1", &[]),
+            "fabric",
+            "1.20.1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mod_pins() {
+        let instance = tempfile::tempdir().unwrap();
+        assert!(ModPins::load(instance.path())
+            .await
+            .unwrap()
+            .pins
+            .is_empty());
+        let pin = ModPin {
+            project_id: "lithium".to_string(),
+            file: "lithium-1.jar".to_string(),
+            version: "lithium-1".to_string(),
+        };
+        let mut pins = ModPins::default();
+        pins.pins.insert(pin.project_id.clone(), pin.clone());
+        pins.save(instance.path()).await.unwrap();
+        assert_eq!(
+            ModPins::load(instance.path()).await.unwrap().pins["lithium"],
+            pin
+        );
+
+        std::fs::write(instance.path().join(PINS_FILE_NAME), "not json").unwrap();
+        assert!(ModPins::load(instance.path()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_apply_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("lithium-1.jar"), "old").unwrap();
+        std::fs::write(staging.path().join("lithium-2.jar"), "new").unwrap();
+        let change = |from_file: Option<&str>, to_file: &str| ModChange {
+            project_id: "lithium".to_string(),
+            from_file: from_file.map(str::to_string),
+            from_version: from_file.map(|_| "lithium-1".to_string()),
+            to_file: to_file.to_string(),
+            to_version: "lithium-2".to_string(),
+            url: format!("https://cdn.modrinth.com/{}", to_file),
+            cause: "lithium".to_string(),
+        };
+        apply_changes(
+            dir.path(),
+            staging.path(),
+            &[change(Some("lithium-1.jar"), "lithium-2.jar")],
+        )
+        .await
+        .unwrap();
+        assert!(!dir.path().join("lithium-1.jar").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("lithium-2.jar")).unwrap(),
+            "new"
+        );
+
+        // nothing was staged for this one
+        assert!(
+            apply_changes(dir.path(), staging.path(), &[change(None, "lib-1.jar")])
+                .await
+                .is_err()
+        );
+    }
+}
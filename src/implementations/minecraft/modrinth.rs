@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, ErrorKind};
+
+use super::FlavourKind;
+
+/// Modrinth asks API clients to identify themselves
+const USER_AGENT: &str = concat!("Lodestone-Team/lodestone_core/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModrinthFile {
+    pub url: String,
+    pub filename: String,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModrinthDependency {
+    pub version_id: Option<String>,
+    pub project_id: Option<String>,
+    /// `required`, `optional`, `incompatible` or `embedded`
+    pub dependency_type: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModrinthVersion {
+    pub id: String,
+    pub project_id: String,
+    pub version_number: String,
+    pub version_type: String,
+    pub files: Vec<ModrinthFile>,
+    #[serde(default)]
+    pub dependencies: Vec<ModrinthDependency>,
+    #[serde(default)]
+    pub loaders: Vec<String>,
+    #[serde(default)]
+    pub game_versions: Vec<String>,
+}
+
+impl ModrinthVersion {
+    /// The file to install, versions can ship sources or dev jars next to it
+    pub fn primary_file(&self) -> Option<&ModrinthFile> {
+        self.files
+            .iter()
+            .find(|file| file.primary)
+            .or_else(|| self.files.first())
+    }
+}
+
+/// The loader Modrinth files a flavour's mods or plugins under
+pub fn loader_of(flavour: &FlavourKind) -> Option<&'static str> {
+    match flavour {
+        FlavourKind::Vanilla => None,
+        FlavourKind::Fabric => Some("fabric"),
+        FlavourKind::Paper => Some("paper"),
+        FlavourKind::Purpur => Some("purpur"),
+        FlavourKind::Spigot => Some("spigot"),
+        FlavourKind::Forge => Some("forge"),
+        FlavourKind::NeoForge => Some("neoforge"),
+        FlavourKind::Velocity => Some("velocity"),
+        FlavourKind::BungeeCord => Some("bungeecord"),
+    }
+}
+
+/// Versions of a project that run on `loader` and `game_version`, newest first
+pub async fn get_project_versions(
+    project: &str,
+    loader: &str,
+    game_version: &str,
+) -> Result<Vec<ModrinthVersion>, Error> {
+    let response = reqwest::Client::new()
+        .get(format!(
+            "https://api.modrinth.com/v2/project/{}/version",
+            project
+        ))
+        .header("User-Agent", USER_AGENT)
+        .query(&[
+            ("loaders", format!("[\"{}\"]", loader)),
+            ("game_versions", format!("[\"{}\"]", game_version)),
+        ])
+        .send()
+        .await
+        .context(format!(
+            "Failed to get versions of {} from Modrinth",
+            project
+        ))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Err(Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("There is no Modrinth project {}", project),
+        });
+    }
+    Ok(response
+        .error_for_status()
+        .context(format!(
+            "Failed to get versions of {} from Modrinth",
+            project
+        ))?
+        .json()
+        .await
+        .context(format!(
+            "Failed to get versions of {}, response is not valid json",
+            project
+        ))?)
+}
+
+/// The newest release of a project for `loader` and `game_version`, or its newest
+/// beta or alpha if it has no release for them
+pub async fn get_latest_version(
+    project: &str,
+    loader: &str,
+    game_version: &str,
+) -> Result<ModrinthVersion, Error> {
+    let versions = get_project_versions(project, loader, game_version).await?;
+    versions
+        .iter()
+        .find(|version| version.version_type == "release")
+        .or_else(|| versions.first())
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("{} has no version for {} {}", project, loader, game_version),
+        })
+}
+
+#[derive(Serialize)]
+struct HashQuery<'a> {
+    hashes: &'a [String],
+    algorithm: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loaders: Option<[&'a str; 1]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    game_versions: Option<[&'a str; 1]>,
+}
+
+async fn post_hashes(
+    endpoint: &str,
+    query: &HashQuery<'_>,
+) -> Result<HashMap<String, ModrinthVersion>, Error> {
+    Ok(reqwest::Client::new()
+        .post(format!("https://api.modrinth.com/v2/{}", endpoint))
+        .header("User-Agent", USER_AGENT)
+        .json(query)
+        .send()
+        .await
+        .context("Failed to look up files on Modrinth")?
+        .error_for_status()
+        .context("Failed to look up files on Modrinth")?
+        .json()
+        .await
+        .context("Failed to look up files on Modrinth, response is not valid json")?)
+}
+
+/// The versions files belong to, by their SHA-1. Files Modrinth doesn't host are
+/// left out.
+pub async fn get_versions_by_hash(
+    hashes: &[String],
+) -> Result<HashMap<String, ModrinthVersion>, Error> {
+    post_hashes(
+        "version_files",
+        &HashQuery {
+            hashes,
+            algorithm: "sha1",
+            loaders: None,
+            game_versions: None,
+        },
+    )
+    .await
+}
+
+/// The newest version for `loader` and `game_version` of the project each file
+/// belongs to, by the file's SHA-1
+pub async fn get_updates_by_hash(
+    hashes: &[String],
+    loader: &str,
+    game_version: &str,
+) -> Result<HashMap<String, ModrinthVersion>, Error> {
+    post_hashes(
+        "version_files/update",
+        &HashQuery {
+            hashes,
+            algorithm: "sha1",
+            loaders: Some([loader]),
+            game_versions: Some([game_version]),
+        },
+    )
+    .await
+}
+
+/// Server-side optimisation mods most Fabric servers end up installing by hand.
+/// None of them changes gameplay or needs a client-side counterpart.
+pub const FABRIC_PERFORMANCE_MODS: &[&str] = &["lithium", "ferrite-core", "krypton"];
+
+/// Mods offered at setup for a flavour, none where they don't load
+pub fn performance_mods(flavour: &FlavourKind) -> &'static [&'static str] {
+    match flavour {
+        FlavourKind::Fabric => FABRIC_PERFORMANCE_MODS,
+        _ => &[],
+    }
+}
+
+/// Downloads the newest version of each project into `dir`. A project that can't
+/// be installed doesn't stop the others, its error is returned with its slug.
+pub async fn install_projects(
+    projects: &[impl AsRef<str>],
+    loader: &str,
+    game_version: &str,
+    dir: &Path,
+) -> Vec<(String, Error)> {
+    let mut failed = Vec::new();
+    for project in projects {
+        let project = project.as_ref();
+        let result = match get_latest_version(project, loader, game_version).await {
+            Ok(version) => match version.primary_file() {
+                Some(file) => {
+                    crate::util::download_file(&file.url, dir, Some(&file.filename), &|_| {}, false)
+                        .await
+                        .map(|_| ())
+                }
+                None => Err(eyre!("{} has no file to download", project).into()),
+            },
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            failed.push((project.to_string(), e));
+        }
+    }
+    failed
+}
+
+#[test]
+fn test_performance_mods() {
+    assert_eq!(
+        performance_mods(&FlavourKind::Fabric),
+        FABRIC_PERFORMANCE_MODS
+    );
+    assert_eq!(loader_of(&FlavourKind::Fabric), Some("fabric"));
+    // Paper has its own optimisations and can't load Fabric mods
+    assert!(performance_mods(&FlavourKind::Paper).is_empty());
+    assert!(performance_mods(&FlavourKind::Vanilla).is_empty());
+}
@@ -0,0 +1,156 @@
+use std::io::Read;
+
+// deeper nesting than this is refused by the game as well
+const MAX_DEPTH: usize = 512;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err(format!(
+                "Unexpected end of data at byte {}, needed {} more bytes",
+                self.pos, len
+            ));
+        }
+        let ret = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(ret)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn read_len(&mut self) -> Result<usize, String> {
+        let b = self.take(4)?;
+        let len = i32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+        if len < 0 {
+            return Err(format!("Negative length {} at byte {}", len, self.pos - 4));
+        }
+        Ok(len as usize)
+    }
+
+    fn skip_payload(&mut self, tag_type: u8, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("NBT nested too deeply".to_string());
+        }
+        match tag_type {
+            1 => self.take(1).map(|_| ()),
+            2 => self.take(2).map(|_| ()),
+            3 | 5 => self.take(4).map(|_| ()),
+            4 | 6 => self.take(8).map(|_| ()),
+            7 => {
+                let len = self.read_len()?;
+                self.take(len).map(|_| ())
+            }
+            8 => {
+                let len = self.u16()? as usize;
+                self.take(len).map(|_| ())
+            }
+            9 => {
+                let element_type = self.u8()?;
+                let len = self.read_len()?;
+                if element_type == 0 && len > 0 {
+                    return Err(format!("Non-empty list of end tags at byte {}", self.pos));
+                }
+                for _ in 0..len {
+                    self.skip_payload(element_type, depth + 1)?;
+                }
+                Ok(())
+            }
+            10 => loop {
+                let child_type = self.u8()?;
+                if child_type == 0 {
+                    return Ok(());
+                }
+                let name_len = self.u16()? as usize;
+                self.take(name_len)?;
+                self.skip_payload(child_type, depth + 1)?;
+            },
+            11 => {
+                let len = self.read_len()?;
+                self.take(len.checked_mul(4).ok_or("Int array too long")?)
+                    .map(|_| ())
+            }
+            12 => {
+                let len = self.read_len()?;
+                self.take(len.checked_mul(8).ok_or("Long array too long")?)
+                    .map(|_| ())
+            }
+            t => Err(format!("Unknown tag type {} at byte {}", t, self.pos - 1)),
+        }
+    }
+}
+
+/// Checks that `data` is a well formed, uncompressed NBT document with a compound root
+pub fn validate(data: &[u8]) -> Result<(), String> {
+    let mut reader = Reader { data, pos: 0 };
+    let root_type = reader.u8()?;
+    if root_type != 10 {
+        return Err(format!(
+            "Root tag is of type {}, expected compound",
+            root_type
+        ));
+    }
+    let name_len = reader.u16()? as usize;
+    reader.take(name_len)?;
+    reader.skip_payload(root_type, 0)
+}
+
+/// Decompresses gzip NBT, as used by `level.dat` and player data, then validates it
+pub fn validate_gzip(data: &[u8]) -> Result<(), String> {
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(data)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| format!("Failed to decompress: {}", e))?;
+    validate(&decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // {"": {"a": 1b, "list": [1, 2] (int), "s": "hi"}}
+    fn sample() -> Vec<u8> {
+        let mut data = vec![10, 0, 0];
+        data.extend([1, 0, 1, b'a', 1]);
+        data.extend([9, 0, 4, b'l', b'i', b's', b't', 3, 0, 0, 0, 2]);
+        data.extend([0, 0, 0, 1, 0, 0, 0, 2]);
+        data.extend([8, 0, 1, b's', 0, 2, b'h', b'i']);
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn test_validate() {
+        let data = sample();
+        assert!(validate(&data).is_ok());
+        // truncated at every possible position
+        for len in 0..data.len() {
+            assert!(validate(&data[..len]).is_err(), "len {}", len);
+        }
+        let mut bad_type = data.clone();
+        bad_type[3] = 42;
+        assert!(validate(&bad_type).is_err());
+        assert!(validate(&[8, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_validate_gzip() {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&sample()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(validate_gzip(&compressed).is_ok());
+        assert!(validate_gzip(&compressed[..compressed.len() / 2]).is_err());
+    }
+}
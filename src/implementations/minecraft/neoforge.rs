@@ -0,0 +1,79 @@
+use color_eyre::eyre::{eyre, Context};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+#[derive(Deserialize)]
+struct MavenVersions {
+    versions: Vec<String>,
+}
+
+/// Every NeoForge release, oldest first
+pub async fn get_neoforge_versions() -> Result<Vec<String>, Error> {
+    let http = reqwest::Client::new();
+    let response: MavenVersions = serde_json::from_str(
+        http.get("https://maven.neoforged.net/api/maven/versions/releases/net/neoforged/neoforge")
+            .send()
+            .await
+            .context("Failed to get neoforge versions, http request failed")?
+            .text()
+            .await
+            .context("Failed to get neoforge versions, text conversion failed")?
+            .as_str(),
+    )
+    .context("Failed to get neoforge versions, response is not a version list")?;
+    Ok(response.versions)
+}
+
+/// NeoForge versions are the Minecraft version without the leading `1.`, `20.4.80`
+/// is a build for 1.20.4 and `21.0.10-beta` one for 1.21
+pub fn neoforge_minecraft_version(neoforge_version: &str) -> Option<String> {
+    let mut parts = neoforge_version.split('.');
+    let major: u32 = parts.next()?.parse().ok()?;
+    let minor: u32 = parts.next()?.parse().ok()?;
+    Some(if minor == 0 {
+        format!("1.{}", major)
+    } else {
+        format!("1.{}.{}", major, minor)
+    })
+}
+
+pub async fn get_neoforge_minecraft_versions() -> Result<Vec<String>, Error> {
+    let mut versions: Vec<String> = Vec::new();
+    for neoforge_version in get_neoforge_versions().await?.iter().rev() {
+        if let Some(version) = neoforge_minecraft_version(neoforge_version) {
+            if !versions.contains(&version) {
+                versions.push(version);
+            }
+        }
+    }
+    if versions.is_empty() {
+        return Err(eyre!("Failed to get neoforge versions, no release found").into());
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_neoforge_minecraft_version() {
+        assert_eq!(
+            neoforge_minecraft_version("20.4.80"),
+            Some("1.20.4".to_string())
+        );
+        assert_eq!(
+            neoforge_minecraft_version("21.0.10-beta"),
+            Some("1.21".to_string())
+        );
+        assert_eq!(neoforge_minecraft_version("invalid"), None);
+    }
+
+    #[tokio::test]
+    async fn test_get_neoforge_minecraft_versions() {
+        let versions = get_neoforge_minecraft_versions().await.unwrap();
+        assert!(versions.contains(&"1.20.4".to_string()));
+        assert!(!versions.contains(&"1.16.5".to_string()));
+    }
+}
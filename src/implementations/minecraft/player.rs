@@ -3,8 +3,11 @@ use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
+use crate::command_template;
+use crate::events::CausedBy;
 use crate::traits::t_player::Player;
 use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::traits::t_server::TServer;
 use crate::Error;
 
 use super::configurable::ServerPropertySetting;
@@ -69,4 +72,36 @@ impl TPlayerManagement for MinecraftInstance {
     async fn get_player_list(&self) -> Result<HashSet<Player>, Error> {
         Ok(self.players_manager.lock().await.clone().into())
     }
+
+    async fn kick_player(&self, player_name: &str, reason: &str) -> Result<(), Error> {
+        self.send_command(
+            &command_template::kick(player_name, reason),
+            CausedBy::System,
+        )
+        .await
+    }
+
+    async fn set_whitelist_enabled(&self, enabled: bool) -> Result<(), Error> {
+        self.send_command(
+            if enabled {
+                "whitelist on"
+            } else {
+                "whitelist off"
+            },
+            CausedBy::System,
+        )
+        .await
+    }
+
+    async fn set_whitelisted(&self, player_name: &str, whitelisted: bool) -> Result<(), Error> {
+        self.send_command(
+            &format!(
+                "whitelist {} {}",
+                if whitelisted { "add" } else { "remove" },
+                command_template::single_line(player_name)
+            ),
+            CausedBy::System,
+        )
+        .await
+    }
 }
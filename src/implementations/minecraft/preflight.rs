@@ -0,0 +1,325 @@
+use std::path::Path;
+
+use serde::Serialize;
+use sysinfo::{DiskExt, System, SystemExt};
+use tokio::sync::Mutex;
+use ts_rs::TS;
+
+use crate::port_manager::PortStatus;
+use crate::prelude::path_to_instances;
+use crate::util::format_byte;
+
+use super::proxy::PROXY_JAVA_MAJOR;
+use super::util::{get_jre_url, get_jre_url_for_major, get_server_jar_url};
+use super::{Flavour, SetupConfig};
+
+/// Below this much free space creation is bound to fail, the server jar, a JRE
+/// and a fresh world already take a few hundred megabytes
+const MIN_FREE_DISK: u64 = 1024 * 1024 * 1024;
+/// Below this much free space the instance won't have much room to grow
+const LOW_FREE_DISK: u64 = 5 * 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum PreflightCheckKind {
+    Version,
+    Port,
+    Java,
+    DiskSpace,
+    Ram,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, TS, PartialEq, Eq, PartialOrd, Ord)]
+#[ts(export)]
+pub enum PreflightStatus {
+    Pass,
+    /// creation will likely work, but the instance may run into trouble
+    Warn,
+    /// creation or the first start will fail
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct PreflightCheck {
+    pub kind: PreflightCheckKind,
+    pub status: PreflightStatus,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct PreflightReport {
+    /// the worst status of any check
+    pub status: PreflightStatus,
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    fn new(checks: Vec<PreflightCheck>) -> Self {
+        Self {
+            status: checks
+                .iter()
+                .map(|check| check.status)
+                .max()
+                .unwrap_or(PreflightStatus::Pass),
+            checks,
+        }
+    }
+}
+
+fn check(kind: PreflightCheckKind, status: PreflightStatus, message: String) -> PreflightCheck {
+    PreflightCheck {
+        kind,
+        status,
+        message,
+    }
+}
+
+async fn check_version(config: &SetupConfig) -> PreflightCheck {
+    let kind = PreflightCheckKind::Version;
+    if matches!(config.flavour, Flavour::Spigot) {
+        return check(
+            kind,
+            PreflightStatus::Fail,
+            "Spigot servers can't be set up automatically".to_string(),
+        );
+    }
+    match get_server_jar_url(&config.version, &config.flavour).await {
+        Some(_) => check(
+            kind,
+            PreflightStatus::Pass,
+            format!(
+                "{} {} is available",
+                config.flavour.to_string(),
+                config.version
+            ),
+        ),
+        None => check(
+            kind,
+            PreflightStatus::Fail,
+            format!(
+                "Could not find a {} server for version {}",
+                config.flavour.to_string(),
+                config.version
+            ),
+        ),
+    }
+}
+
+fn check_port(port: u32, status: &PortStatus) -> PreflightCheck {
+    let kind = PreflightCheckKind::Port;
+    if port == 0 || port > u16::MAX as u32 {
+        check(
+            kind,
+            PreflightStatus::Fail,
+            format!("{} is not a valid port", port),
+        )
+    } else if status.is_in_use {
+        check(
+            kind,
+            PreflightStatus::Fail,
+            format!("Port {} is in use by another process", port),
+        )
+    } else if status.is_allocated {
+        check(
+            kind,
+            PreflightStatus::Warn,
+            format!(
+                "Port {} is assigned to another instance, they can't run at the same time",
+                port
+            ),
+        )
+    } else {
+        check(
+            kind,
+            PreflightStatus::Pass,
+            format!("Port {} is free", port),
+        )
+    }
+}
+
+async fn check_java(config: &SetupConfig) -> PreflightCheck {
+    let kind = PreflightCheckKind::Java;
+    let version = &config.version;
+    let jre_url = if config.flavour.is_proxy() {
+        get_jre_url_for_major(PROXY_JAVA_MAJOR)
+    } else {
+        get_jre_url(version).await
+    };
+    match jre_url {
+        Some((_, major)) => {
+            if crate::java_manager::managed_java(major).is_file() {
+                check(
+                    kind,
+                    PreflightStatus::Pass,
+                    format!("Java {} is installed", major),
+                )
+            } else {
+                check(
+                    kind,
+                    PreflightStatus::Pass,
+                    format!("Java {} will be downloaded", major),
+                )
+            }
+        }
+        None => check(
+            kind,
+            PreflightStatus::Fail,
+            format!(
+                "No Java runtime for {} {} is available for {} {}",
+                config.flavour.to_string(),
+                version,
+                std::env::consts::OS,
+                std::env::consts::ARCH
+            ),
+        ),
+    }
+}
+
+/// Free space on the disk `path` is on, the one with the longest matching
+/// mount point
+pub(crate) fn available_space(system: &System, path: &Path) -> Option<u64> {
+    system
+        .disks()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())
+        .map(|disk| disk.available_space())
+}
+
+fn check_disk(available: Option<u64>) -> PreflightCheck {
+    let kind = PreflightCheckKind::DiskSpace;
+    match available {
+        Some(available) if available < MIN_FREE_DISK => check(
+            kind,
+            PreflightStatus::Fail,
+            format!("Only {} of disk space left", format_byte(available)),
+        ),
+        Some(available) if available < LOW_FREE_DISK => check(
+            kind,
+            PreflightStatus::Warn,
+            format!("Only {} of disk space left", format_byte(available)),
+        ),
+        Some(available) => check(
+            kind,
+            PreflightStatus::Pass,
+            format!("{} of disk space available", format_byte(available)),
+        ),
+        None => check(
+            kind,
+            PreflightStatus::Warn,
+            "Could not determine the free disk space".to_string(),
+        ),
+    }
+}
+
+/// `min_ram` and `max_ram` are in megabytes, `total` and `available` in bytes
+fn check_ram(
+    min_ram: Option<u32>,
+    max_ram: Option<u32>,
+    total: u64,
+    available: u64,
+) -> PreflightCheck {
+    let kind = PreflightCheckKind::Ram;
+    let (min_ram, max_ram) = match (min_ram, max_ram) {
+        (Some(min_ram), Some(max_ram)) => (min_ram, max_ram),
+        _ => {
+            return check(
+                kind,
+                PreflightStatus::Pass,
+                "No memory limits set".to_string(),
+            )
+        }
+    };
+    let max_bytes = max_ram as u64 * 1024 * 1024;
+    if min_ram > max_ram {
+        check(
+            kind,
+            PreflightStatus::Fail,
+            format!(
+                "Minimum RAM ({} MB) is above the maximum ({} MB), Java won't start",
+                min_ram, max_ram
+            ),
+        )
+    } else if max_bytes > total {
+        check(
+            kind,
+            PreflightStatus::Fail,
+            format!(
+                "Maximum RAM ({} MB) is more than the host has ({})",
+                max_ram,
+                format_byte(total)
+            ),
+        )
+    } else if max_bytes > available {
+        check(
+            kind,
+            PreflightStatus::Warn,
+            format!(
+                "Maximum RAM ({} MB) is more than is free right now ({})",
+                max_ram,
+                format_byte(available)
+            ),
+        )
+    } else {
+        check(
+            kind,
+            PreflightStatus::Pass,
+            format!("{} of RAM free", format_byte(available)),
+        )
+    }
+}
+
+/// Checks whether creating an instance from `config` is likely to work,
+/// without changing anything
+pub async fn preflight(
+    config: &SetupConfig,
+    port_status: PortStatus,
+    system: &Mutex<System>,
+) -> PreflightReport {
+    let (version, java) = tokio::join!(check_version(config), check_java(config));
+    let (available_disk, total_ram, available_ram) = {
+        let mut system = system.lock().await;
+        system.refresh_disks_list();
+        system.refresh_memory();
+        (
+            available_space(&system, path_to_instances()),
+            system.total_memory(),
+            system.available_memory(),
+        )
+    };
+    PreflightReport::new(vec![
+        version,
+        check_port(config.port, &port_status),
+        java,
+        check_disk(available_disk),
+        check_ram(config.min_ram, config.max_ram, total_ram, available_ram),
+    ])
+}
+
+#[test]
+fn test_preflight_checks() {
+    let gib = 1024 * 1024 * 1024;
+    assert_eq!(
+        check_ram(Some(2048), Some(1024), 8 * gib, 8 * gib).status,
+        PreflightStatus::Fail
+    );
+    assert_eq!(
+        check_ram(Some(1024), Some(16384), 8 * gib, 8 * gib).status,
+        PreflightStatus::Fail
+    );
+    assert_eq!(
+        check_ram(Some(1024), Some(4096), 8 * gib, 2 * gib).status,
+        PreflightStatus::Warn
+    );
+    assert_eq!(
+        check_disk(Some(100 * 1024 * 1024)).status,
+        PreflightStatus::Fail
+    );
+    let report = PreflightReport::new(vec![
+        check_disk(Some(2 * gib)),
+        check_ram(Some(1024), Some(2048), 8 * gib, 8 * gib),
+    ]);
+    assert_eq!(report.status, PreflightStatus::Warn);
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use color_eyre::eyre::{eyre, Context, ContextCompat};
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value as YamlValue};
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+use super::{Flavour, MinecraftInstance};
+
+/// Velocity 3.4 needs Java 21, which BungeeCord runs on as well
+pub const PROXY_JAVA_MAJOR: u64 = 21;
+
+pub const BUNGEECORD_JAR_URL: &str =
+    "https://ci.md-5.net/job/BungeeCord/lastSuccessfulBuild/artifact/bootstrap/target/BungeeCord.jar";
+
+/// A server the proxy sends players to, another instance on this core
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ProxyBackend {
+    pub uuid: InstanceUuid,
+    /// the server's name in the proxy's config, kept when the instance is renamed
+    /// so forced hosts and permissions referring to it keep working
+    pub name: String,
+    /// `host:port` the proxy connects to
+    pub address: String,
+}
+
+pub fn backend_address(port: u32) -> String {
+    format!("127.0.0.1:{}", port)
+}
+
+/// Every Velocity release, newest first
+pub async fn get_velocity_versions() -> Result<Vec<String>, Error> {
+    let http = reqwest::Client::new();
+    let response: serde_json::Value = serde_json::from_str(
+        http.get("https://api.papermc.io/v2/projects/velocity")
+            .send()
+            .await
+            .context("Failed to get velocity versions")?
+            .text()
+            .await
+            .context("Failed to get velocity versions")?
+            .as_str(),
+    )
+    .context("Failed to get velocity versions, response is not valid json")?;
+    let mut versions: Vec<String> = response
+        .get("versions")
+        .and_then(|versions| versions.as_array())
+        .context("Failed to get velocity versions, response does not contain versions")?
+        .iter()
+        .filter_map(|version| version.as_str().map(str::to_string))
+        .collect();
+    versions.reverse();
+    Ok(versions)
+}
+
+/// A server name both proxies accept, made from the instance name and not taken
+/// by another backend yet
+pub fn server_key(instance_name: &str, backends: &[ProxyBackend]) -> String {
+    let key: String = instance_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let key = match key.trim_matches('-') {
+        "" => "server".to_string(),
+        key => key.to_string(),
+    };
+    let mut candidate = key.clone();
+    let mut n = 2;
+    while backends.iter().any(|backend| backend.name == candidate) {
+        candidate = format!("{}-{}", key, n);
+        n += 1;
+    }
+    candidate
+}
+
+fn velocity_servers_table(backends: &[ProxyBackend]) -> String {
+    let mut table = "[servers]\n".to_string();
+    for backend in backends {
+        table.push_str(&format!("{} = \"{}\"\n", backend.name, backend.address));
+    }
+    table.push_str(&format!(
+        "try = [{}]\n\n",
+        backends
+            .iter()
+            .map(|backend| format!("\"{}\"", backend.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    ));
+    table
+}
+
+/// `velocity.toml` with the bind address and the `[servers]` table replaced and
+/// everything else left as it was. Velocity fills in what a new file leaves out.
+pub fn velocity_config(existing: Option<&str>, port: u32, backends: &[ProxyBackend]) -> String {
+    let existing = existing.unwrap_or("config-version = \"2.7\"\n\n[forced-hosts]\n");
+    let bind = format!("bind = \"0.0.0.0:{}\"\n", port);
+    let mut config = String::new();
+    let mut wrote_bind = false;
+    let mut wrote_servers = false;
+    let mut in_table = false;
+    let mut in_servers = false;
+    for line in existing.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') && !trimmed.starts_with("[[") && trimmed.ends_with(']') {
+            in_table = true;
+            in_servers = trimmed == "[servers]";
+            if in_servers {
+                config.push_str(&velocity_servers_table(backends));
+                wrote_servers = true;
+            }
+        }
+        if in_servers {
+            continue;
+        }
+        if !in_table
+            && trimmed
+                .strip_prefix("bind")
+                .map_or(false, |rest| rest.trim_start().starts_with('='))
+        {
+            config.push_str(&bind);
+            wrote_bind = true;
+            continue;
+        }
+        config.push_str(line);
+        config.push('\n');
+    }
+    if !wrote_bind {
+        // top level keys have to come before the first table
+        config.insert_str(0, &bind);
+    }
+    if !wrote_servers {
+        config.push('\n');
+        config.push_str(&velocity_servers_table(backends));
+    }
+    config
+}
+
+/// BungeeCord's `config.yml` with the first listener bound to `port`, trying the
+/// backends in order, and the servers replaced by the backends
+pub fn bungeecord_config(
+    existing: Option<&str>,
+    port: u32,
+    backends: &[ProxyBackend],
+) -> Result<String, Error> {
+    let mut config: Mapping = match existing {
+        Some(existing) if !existing.trim().is_empty() => {
+            serde_yaml::from_str(existing).context("Failed to parse config.yml")?
+        }
+        _ => Mapping::new(),
+    };
+    let mut listeners = config
+        .get("listeners")
+        .and_then(|listeners| listeners.as_sequence())
+        .cloned()
+        .unwrap_or_default();
+    if listeners.is_empty() {
+        listeners.push(YamlValue::Mapping(Mapping::new()));
+    }
+    if let YamlValue::Mapping(listener) = &mut listeners[0] {
+        listener.insert("host".into(), format!("0.0.0.0:{}", port).into());
+        listener.insert(
+            "priorities".into(),
+            YamlValue::Sequence(
+                backends
+                    .iter()
+                    .map(|backend| backend.name.clone().into())
+                    .collect(),
+            ),
+        );
+    }
+    config.insert("listeners".into(), YamlValue::Sequence(listeners));
+    let mut servers = Mapping::new();
+    for backend in backends {
+        let mut server = Mapping::new();
+        server.insert("address".into(), backend.address.clone().into());
+        server.insert("motd".into(), backend.name.clone().into());
+        server.insert("restricted".into(), false.into());
+        servers.insert(backend.name.clone().into(), YamlValue::Mapping(server));
+    }
+    config.insert("servers".into(), YamlValue::Mapping(servers));
+    Ok(serde_yaml::to_string(&config).context("Failed to serialize config.yml")?)
+}
+
+impl MinecraftInstance {
+    fn proxy_links_path(&self) -> PathBuf {
+        self.path_to_instance.join(".lodestone_proxy_links.json")
+    }
+
+    pub async fn is_proxy(&self) -> bool {
+        self.config.lock().await.flavour.is_proxy()
+    }
+
+    async fn read_proxy_backends(&self) -> Result<Vec<ProxyBackend>, Error> {
+        let path = self.proxy_links_path();
+        if !path.is_file() {
+            return Ok(Vec::new());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse proxy links at {}", path.display()))?,
+        )
+    }
+
+    pub async fn proxy_backends(&self) -> Result<Vec<ProxyBackend>, Error> {
+        if !self.is_proxy().await {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Only Velocity and BungeeCord instances have backends"),
+            });
+        }
+        self.read_proxy_backends().await
+    }
+
+    /// Saves the backends and writes them to the proxy's config. A running proxy
+    /// reloads it, though it only listens on a new port after a restart.
+    pub async fn set_proxy_backends(&self, backends: &[ProxyBackend]) -> Result<(), Error> {
+        let flavour = self.config.lock().await.flavour.clone();
+        if !flavour.is_proxy() {
+            return Err(Error {
+                kind: ErrorKind::UnsupportedOperation,
+                source: eyre!("Only Velocity and BungeeCord instances have backends"),
+            });
+        }
+        crate::util::fs::write_all(
+            self.proxy_links_path(),
+            serde_json::to_string_pretty(backends).context("Failed to serialize proxy links")?,
+        )
+        .await?;
+        self.write_proxy_config().await?;
+        if self.state().await == State::Running {
+            let reload = match flavour {
+                Flavour::BungeeCord => "greload",
+                _ => "velocity reload",
+            };
+            if let Err(e) = self.send_command(reload, CausedBy::System).await {
+                warn!(
+                    "[{}] Failed to reload the proxy config: {}",
+                    self.name().await,
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the port and the backends into the proxy's own config, before every
+    /// start so changes made while it was stopped apply. Does nothing for servers.
+    pub(super) async fn write_proxy_config(&self) -> Result<(), Error> {
+        let (flavour, port) = {
+            let config = self.config.lock().await;
+            (config.flavour.clone(), config.port)
+        };
+        let (path, content) = match flavour {
+            Flavour::Velocity { .. } => {
+                let path = self.path_to_instance.join("velocity.toml");
+                let existing = if path.is_file() {
+                    Some(crate::util::fs::read_to_string(&path).await?)
+                } else {
+                    None
+                };
+                let content = velocity_config(
+                    existing.as_deref(),
+                    port,
+                    &self.read_proxy_backends().await?,
+                );
+                (path, content)
+            }
+            Flavour::BungeeCord => {
+                let path = self.path_to_instance.join("config.yml");
+                let existing = if path.is_file() {
+                    Some(crate::util::fs::read_to_string(&path).await?)
+                } else {
+                    None
+                };
+                let content = bungeecord_config(
+                    existing.as_deref(),
+                    port,
+                    &self.read_proxy_backends().await?,
+                )?;
+                (path, content)
+            }
+            _ => return Ok(()),
+        };
+        crate::util::fs::write_all(path, content).await
+    }
+}
+
+/// Points the proxies at their backends' current ports and drops backends that
+/// were deleted. Cheap when nothing changed, so it can run often.
+pub async fn sync_proxy_links(instances: &HashMap<InstanceUuid, GameInstance>) {
+    let mut ports = HashMap::new();
+    let mut proxies = Vec::new();
+    for (uuid, instance) in instances.iter() {
+        if let GameInstance::MinecraftInstance(instance) = instance {
+            if instance.is_proxy().await {
+                proxies.push(instance.clone());
+            }
+            ports.insert(uuid.clone(), instance.port().await);
+        }
+    }
+    for proxy in proxies {
+        let linked = match proxy.read_proxy_backends().await {
+            Ok(linked) => linked,
+            Err(e) => {
+                warn!("[{}] {}", proxy.name().await, e);
+                continue;
+            }
+        };
+        let synced: Vec<ProxyBackend> = linked
+            .iter()
+            .filter_map(|backend| {
+                ports.get(&backend.uuid).map(|port| ProxyBackend {
+                    address: backend_address(*port),
+                    ..backend.clone()
+                })
+            })
+            .collect();
+        if synced == linked {
+            continue;
+        }
+        info!(
+            "Updating the backends of proxy {} to their current ports",
+            proxy.name().await
+        );
+        if let Err(e) = proxy.set_proxy_backends(&synced).await {
+            warn!(
+                "Failed to update the backends of proxy {}: {}",
+                proxy.name().await,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn backend(name: &str, port: u32) -> ProxyBackend {
+        ProxyBackend {
+            uuid: InstanceUuid::from(name.to_string()),
+            name: name.to_string(),
+            address: backend_address(port),
+        }
+    }
+
+    #[test]
+    fn test_server_key() {
+        assert_eq!(server_key("My SMP!", &[]), "my-smp");
+        assert_eq!(server_key("lobby", &[backend("lobby", 25566)]), "lobby-2");
+        assert_eq!(server_key("???", &[]), "server");
+    }
+
+    #[test]
+    fn test_velocity_config() {
+        let existing = "# comment\nbind = \"0.0.0.0:25577\"\nmotd = \"hi\"\n\n[servers]\n# the servers\nlobby = \"127.0.0.1:30066\"\ntry = [\n  \"lobby\"\n]\n\n[forced-hosts]\n\"lobby.example.com\" = [\"lobby\"]\n";
+        let config = velocity_config(
+            Some(existing),
+            25565,
+            &[backend("smp", 25566), backend("creative", 25567)],
+        );
+        assert_eq!(
+            config,
+            "# comment\nbind = \"0.0.0.0:25565\"\nmotd = \"hi\"\n\n[servers]\nsmp = \"127.0.0.1:25566\"\ncreative = \"127.0.0.1:25567\"\ntry = [\"smp\", \"creative\"]\n\n[forced-hosts]\n\"lobby.example.com\" = [\"lobby\"]\n"
+        );
+
+        let new = velocity_config(None, 25565, &[]);
+        assert!(new.starts_with("bind = \"0.0.0.0:25565\"\n"));
+        assert!(new.contains("[servers]\ntry = []\n"));
+    }
+
+    #[test]
+    fn test_bungeecord_config() {
+        let existing = "listeners:\n- host: 0.0.0.0:25577\n  max_players: 10\n  priorities:\n  - lobby\nservers:\n  lobby:\n    address: localhost:25565\nip_forward: false\n";
+        let config: Mapping = serde_yaml::from_str(
+            &bungeecord_config(Some(existing), 25565, &[backend("smp", 25566)]).unwrap(),
+        )
+        .unwrap();
+        let listener = &config["listeners"][0];
+        assert_eq!(listener["host"], YamlValue::from("0.0.0.0:25565"));
+        assert_eq!(listener["max_players"], YamlValue::from(10));
+        assert_eq!(listener["priorities"][0], YamlValue::from("smp"));
+        assert_eq!(
+            config["servers"]["smp"]["address"],
+            YamlValue::from("127.0.0.1:25566")
+        );
+        assert!(config["servers"].get("lobby").is_none());
+        assert_eq!(config["ip_forward"], YamlValue::from(false));
+    }
+}
@@ -0,0 +1,53 @@
+use color_eyre::eyre::{eyre, Context, ContextCompat};
+use serde_json::Value;
+
+use crate::error::Error;
+
+pub async fn get_purpur_minecraft_versions() -> Result<Vec<String>, Error> {
+    let http = reqwest::Client::new();
+
+    let response: Value = serde_json::from_str(
+        http.get("https://api.purpurmc.org/v2/purpur")
+            .send()
+            .await
+            .context("Failed to get purpur versions")?
+            .text()
+            .await
+            .context("Failed to get purpur versions")?
+            .as_str(),
+    )
+    .context("Failed to get purpur versions, response is not valid json")?;
+
+    let mut versions = response
+        .get("versions")
+        .context("Failed to get purpur versions, response does not contain versions")?
+        .as_array()
+        .context("Failed to get purpur versions Response is not an array")?
+        .iter()
+        .map(|version| {
+            version
+                .as_str()
+                .ok_or_else(|| {
+                    eyre!("Failed to get purpur versions. Version string is not a string").into()
+                })
+                .map(|version| version.to_string())
+        })
+        .collect::<Result<Vec<String>, Error>>()?;
+
+    versions.reverse();
+
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_get_purpur_minecraft_versions() {
+        let versions = get_purpur_minecraft_versions().await.unwrap();
+        assert!(versions.contains(&"1.19.4".to_string()));
+        assert!(versions.contains(&"1.16.5".to_string()));
+        assert!(!versions.contains(&"1.13".to_string()));
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::nbt;
+
+pub const SECTOR_SIZE: usize = 4096;
+pub const CHUNKS_PER_REGION: usize = 1024;
+pub const REGION_WIDTH_IN_BLOCKS: i64 = 512;
+
+/// Parses the region coordinates out of a file name such as `r.-1.2.mca`
+pub fn parse_region_coords(file_name: &str) -> Option<(i32, i32)> {
+    let mut parts = file_name
+        .strip_prefix("r.")?
+        .strip_suffix(".mca")?
+        .split('.');
+    let x = parts.next()?.parse().ok()?;
+    let z = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((x, z))
+}
+
+/// Whether any block of the region lies within `radius` blocks of the origin on both axes
+pub fn region_within_radius((x, z): (i32, i32), radius: u32) -> bool {
+    let radius = radius as i64;
+    let within = |c: i32| {
+        let min = c as i64 * REGION_WIDTH_IN_BLOCKS;
+        let max = min + REGION_WIDTH_IN_BLOCKS - 1;
+        max >= -radius && min <= radius
+    };
+    within(x) && within(z)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLocation {
+    /// offset in sectors from the start of the file
+    pub offset: u32,
+    /// length in sectors
+    pub sector_count: u8,
+}
+
+/// Header of an Anvil region file (`r.<x>.<z>.mca`).
+///
+/// A region file starts with a 4KiB table of chunk locations followed by a 4KiB table of
+/// chunk timestamps, each holding 1024 big-endian entries for the 32x32 chunks of the region.
+pub struct RegionHeader {
+    pub locations: Vec<Option<ChunkLocation>>,
+    pub timestamps: Vec<u32>,
+}
+
+impl RegionHeader {
+    pub fn parse(header: &[u8; SECTOR_SIZE * 2]) -> Self {
+        let mut locations = Vec::with_capacity(CHUNKS_PER_REGION);
+        let mut timestamps = Vec::with_capacity(CHUNKS_PER_REGION);
+        for i in 0..CHUNKS_PER_REGION {
+            let entry = &header[i * 4..i * 4 + 4];
+            let offset = u32::from_be_bytes([0, entry[0], entry[1], entry[2]]);
+            let sector_count = entry[3];
+            locations.push(if offset == 0 && sector_count == 0 {
+                None
+            } else {
+                Some(ChunkLocation {
+                    offset,
+                    sector_count,
+                })
+            });
+            let ts = &header[SECTOR_SIZE + i * 4..SECTOR_SIZE + i * 4 + 4];
+            timestamps.push(u32::from_be_bytes([ts[0], ts[1], ts[2], ts[3]]));
+        }
+        Self {
+            locations,
+            timestamps,
+        }
+    }
+
+    pub fn read_from(path: &Path) -> std::io::Result<Self> {
+        let mut header = [0_u8; SECTOR_SIZE * 2];
+        std::fs::File::open(path)?.read_exact(&mut header)?;
+        Ok(Self::parse(&header))
+    }
+
+    /// Latest chunk save time in the region, `None` if no chunk was ever saved
+    pub fn last_modified(&self) -> Option<i64> {
+        self.timestamps
+            .iter()
+            .zip(self.locations.iter())
+            .filter(|(_, location)| location.is_some())
+            .map(|(ts, _)| *ts as i64)
+            .max()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegionIssue {
+    /// the file is too short to hold the location and timestamp tables
+    TruncatedHeader,
+    Chunk {
+        index: usize,
+        reason: String,
+    },
+}
+
+fn check_chunk(data: &[u8], location: ChunkLocation) -> Result<(), String> {
+    if location.offset < 2 {
+        return Err("Chunk overlaps the region header".to_string());
+    }
+    let start = location.offset as usize * SECTOR_SIZE;
+    if start + 5 > data.len() {
+        return Err("Chunk starts beyond the end of the file".to_string());
+    }
+    let length = u32::from_be_bytes([
+        data[start],
+        data[start + 1],
+        data[start + 2],
+        data[start + 3],
+    ]) as usize;
+    if length == 0 {
+        return Err("Chunk has zero length".to_string());
+    }
+    if length + 4 > location.sector_count as usize * SECTOR_SIZE {
+        return Err("Chunk is longer than its allocated sectors".to_string());
+    }
+    if start + 4 + length > data.len() {
+        return Err("Chunk is truncated".to_string());
+    }
+    let compression = data[start + 4];
+    // the chunk is stored in an external .mcc file, which we don't check
+    if compression & 0x80 != 0 {
+        return Ok(());
+    }
+    let payload = &data[start + 5..start + 4 + length];
+    let mut decompressed = Vec::new();
+    match compression {
+        1 => {
+            flate2::read::GzDecoder::new(payload)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| format!("Failed to decompress chunk: {}", e))?;
+        }
+        2 => {
+            flate2::read::ZlibDecoder::new(payload)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| format!("Failed to decompress chunk: {}", e))?;
+        }
+        3 => decompressed.extend_from_slice(payload),
+        // LZ4 is opt-in since 1.20.5 and not supported here
+        4 => return Ok(()),
+        c => return Err(format!("Unknown compression type {}", c)),
+    }
+    nbt::validate(&decompressed).map_err(|e| format!("Invalid NBT: {}", e))
+}
+
+/// Checks every chunk of a region file for truncation and invalid NBT
+pub fn scan_region_file(path: &Path) -> std::io::Result<Vec<RegionIssue>> {
+    let data = std::fs::read(path)?;
+    // the game leaves empty region files behind when no chunk was saved
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    if data.len() < SECTOR_SIZE * 2 {
+        return Ok(vec![RegionIssue::TruncatedHeader]);
+    }
+    let mut header = [0_u8; SECTOR_SIZE * 2];
+    header.copy_from_slice(&data[..SECTOR_SIZE * 2]);
+    Ok(RegionHeader::parse(&header)
+        .locations
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, location)| {
+            check_chunk(&data, location?)
+                .err()
+                .map(|reason| RegionIssue::Chunk { index, reason })
+        })
+        .collect())
+}
+
+/// Removes chunks from the location table so the game regenerates them.
+/// The chunk data itself is left in place.
+pub fn clear_chunks(path: &Path, indices: &[usize]) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    for index in indices {
+        file.seek(SeekFrom::Start((index * 4) as u64))?;
+        file.write_all(&[0; 4])?;
+        file.seek(SeekFrom::Start((SECTOR_SIZE + index * 4) as u64))?;
+        file.write_all(&[0; 4])?;
+    }
+    Ok(())
+}
+
+/// Chunk coordinates of the chunk at `index` in the region at `region_coords`
+pub fn chunk_coords((x, z): (i32, i32), index: usize) -> (i32, i32) {
+    (x * 32 + (index % 32) as i32, z * 32 + (index / 32) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_region_coords() {
+        assert_eq!(parse_region_coords("r.0.0.mca"), Some((0, 0)));
+        assert_eq!(parse_region_coords("r.-1.12.mca"), Some((-1, 12)));
+        assert_eq!(parse_region_coords("r.0.0.mcr"), None);
+        assert_eq!(parse_region_coords("r.0.mca"), None);
+        assert_eq!(parse_region_coords("r.0.0.0.mca"), None);
+        assert_eq!(parse_region_coords("level.dat"), None);
+    }
+
+    #[test]
+    fn test_region_within_radius() {
+        assert!(region_within_radius((0, 0), 0));
+        assert!(region_within_radius((-1, -1), 1));
+        assert!(!region_within_radius((-1, 0), 0));
+        assert!(region_within_radius((1, 0), 512));
+        assert!(!region_within_radius((1, 0), 511));
+        assert!(!region_within_radius((0, 3), 1000));
+    }
+
+    #[test]
+    fn test_region_header() {
+        let mut header = [0_u8; SECTOR_SIZE * 2];
+        // chunk 0 at sector 2, 1 sector long, saved at t=100
+        header[0..4].copy_from_slice(&[0, 0, 2, 1]);
+        header[SECTOR_SIZE..SECTOR_SIZE + 4].copy_from_slice(&100_u32.to_be_bytes());
+        // chunk 5 at sector 3, saved at t=300
+        header[20..24].copy_from_slice(&[0, 0, 3, 1]);
+        header[SECTOR_SIZE + 20..SECTOR_SIZE + 24].copy_from_slice(&300_u32.to_be_bytes());
+        // stale timestamp of an absent chunk is ignored
+        header[SECTOR_SIZE + 40..SECTOR_SIZE + 44].copy_from_slice(&900_u32.to_be_bytes());
+        let header = RegionHeader::parse(&header);
+        assert_eq!(
+            header.locations[0],
+            Some(ChunkLocation {
+                offset: 2,
+                sector_count: 1
+            })
+        );
+        assert_eq!(header.locations[1], None);
+        assert_eq!(header.last_modified(), Some(300));
+    }
+
+    #[test]
+    fn test_scan_region_file() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("r.0.0.mca");
+        let mut data = vec![0_u8; SECTOR_SIZE * 4];
+        // chunk 0: valid uncompressed empty compound at sector 2
+        data[0..4].copy_from_slice(&[0, 0, 2, 1]);
+        let chunk = [0, 0, 0, 5, 3, 10, 0, 0, 0];
+        data[SECTOR_SIZE * 2..SECTOR_SIZE * 2 + 9].copy_from_slice(&chunk);
+        // chunk 1: claims more data than the file holds
+        data[4..8].copy_from_slice(&[0, 0, 3, 2]);
+        data[SECTOR_SIZE * 3..SECTOR_SIZE * 3 + 5].copy_from_slice(&[0, 0, 0x20, 0, 2]);
+        // chunk 2: overlaps the header
+        data[8..12].copy_from_slice(&[0, 0, 1, 1]);
+        std::fs::write(&path, &data).unwrap();
+
+        let issues = scan_region_file(&path).unwrap();
+        let indices: Vec<usize> = issues
+            .iter()
+            .map(|i| match i {
+                RegionIssue::Chunk { index, .. } => *index,
+                RegionIssue::TruncatedHeader => panic!("unexpected truncated header"),
+            })
+            .collect();
+        assert_eq!(indices, vec![1, 2]);
+
+        clear_chunks(&path, &indices).unwrap();
+        assert!(scan_region_file(&path).unwrap().is_empty());
+        assert!(RegionHeader::read_from(&path).unwrap().locations[0].is_some());
+
+        std::fs::write(&path, [0; 100]).unwrap();
+        assert_eq!(
+            scan_region_file(&path).unwrap(),
+            vec![RegionIssue::TruncatedHeader]
+        );
+    }
+
+    #[test]
+    fn test_chunk_coords() {
+        assert_eq!(chunk_coords((0, 0), 0), (0, 0));
+        assert_eq!(chunk_coords((0, 0), 33), (1, 1));
+        assert_eq!(chunk_coords((-1, 2), 31), (-1, 64));
+    }
+}
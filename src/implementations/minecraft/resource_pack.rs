@@ -0,0 +1,120 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+use super::configurable::ServerPropertySetting;
+use super::util::read_properties_from_path;
+use super::MinecraftInstance;
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct ResourcePackInfo {
+    pub sha1: String,
+    pub size: u64,
+    /// the `resource-pack` property, which may have been changed by hand since the upload
+    pub url: String,
+}
+
+/// Hex encoded SHA-1 of a file, the format expected by `resource-pack-sha1`
+pub fn sha1_file(path: &Path) -> Result<String, Error> {
+    let mut file =
+        std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0_u8; 64 * 1024];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .context(format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl MinecraftInstance {
+    pub fn path_to_resource_pack(&self) -> PathBuf {
+        self.path_to_instance.join("resource_pack.zip")
+    }
+
+    pub async fn resource_pack_info(&self) -> Result<Option<ResourcePackInfo>, Error> {
+        let path = self.path_to_resource_pack();
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let size = tokio::fs::metadata(&path)
+            .await
+            .context(format!("Failed to read metadata of {}", path.display()))?
+            .len();
+        let sha1 = tokio::task::spawn_blocking(move || sha1_file(&path))
+            .await
+            .context("Failed to hash resource pack")??;
+        let url = read_properties_from_path(&self.path_to_properties)
+            .await
+            .ok()
+            .and_then(|properties| properties.get("resource-pack").cloned())
+            .unwrap_or_default();
+        Ok(Some(ResourcePackInfo { sha1, size, url }))
+    }
+
+    /// Points `resource-pack` and `resource-pack-sha1` at the hosted pack,
+    /// adding the properties if server.properties doesn't have them yet
+    pub async fn set_resource_pack_properties(
+        &mut self,
+        url: String,
+        sha1: String,
+    ) -> Result<(), Error> {
+        if url.contains('\n') {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Resource pack url must not contain line breaks"),
+            });
+        }
+        let _ = self.read_properties().await;
+        {
+            let mut manifest = self.configurable_manifest.lock().await;
+            manifest.set_setting(
+                ServerPropertySetting::get_section_id(),
+                ServerPropertySetting::ResourcePack(url).into(),
+            )?;
+            manifest.set_setting(
+                ServerPropertySetting::get_section_id(),
+                ServerPropertySetting::ResourcePackSha1(sha1).into(),
+            )?;
+        }
+        self.sync_configurable_to_restore_config().await;
+        self.write_config_to_file().await?;
+        self.write_properties_to_file().await
+    }
+
+    pub async fn remove_resource_pack(&mut self) -> Result<(), Error> {
+        let path = self.path_to_resource_pack();
+        if !path.is_file() {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Instance has no hosted resource pack"),
+            });
+        }
+        crate::util::fs::remove_file(&path).await?;
+        self.set_resource_pack_properties(String::new(), String::new())
+            .await
+    }
+}
+
+#[test]
+fn test_sha1_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("pack.zip");
+    std::fs::write(&path, "abc").unwrap();
+    assert_eq!(
+        sha1_file(&path).unwrap(),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+}
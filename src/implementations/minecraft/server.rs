@@ -1,4 +1,3 @@
-use std::path::PathBuf;
 use std::process::Stdio;
 use std::time::Duration;
 
@@ -7,6 +6,7 @@ use sysinfo::{Pid, PidExt, ProcessExt, SystemExt};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::Command;
 
+use crate::cgroup::{self, ResourceLimits};
 use crate::error::{Error, ErrorKind};
 use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
 use crate::implementations::minecraft::line_parser::{
@@ -18,19 +18,22 @@ use crate::implementations::minecraft::util::name_to_uuid;
 use crate::macro_executor::SpawnResult;
 use crate::traits::t_configurable::TConfigurable;
 use crate::traits::t_macro::TaskEntry;
-use crate::traits::t_server::{MonitorReport, State, StateAction, TServer};
+use crate::traits::t_server::{InstanceOperation, MonitorReport, State, StateAction, TServer};
 
 use crate::types::Snowflake;
-use crate::util::{dont_spawn_terminal, list_dir};
+use crate::util::dont_spawn_terminal;
 
 use super::r#macro::{resolve_macro_invocation, MinecraftMainWorkerGenerator};
-use super::{Flavour, ForgeBuildVersion, MinecraftInstance};
-use tracing::{error, info, warn};
+use super::{forge, jvm_presets, Flavour, MinecraftInstance};
+use tracing::{error, info, warn, Instrument};
 
 #[async_trait::async_trait]
 impl TServer for MinecraftInstance {
+    #[tracing::instrument(skip_all, fields(instance = %self.uuid))]
     async fn start(&mut self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
         let config = self.config.lock().await.clone();
+        self.check_eula().await?;
+        self.write_proxy_config().await?;
         self.state.lock().await.try_transition(
             StateAction::UserStart,
             Some(&|state| {
@@ -125,110 +128,48 @@ impl TServer for MinecraftInstance {
             );
         }
 
-        let jre = if let Some(jre) = &config.java_cmd {
-            PathBuf::from(jre)
-        } else {
-            self.path_to_runtimes
-                .join("java")
-                .join(format!("jre{}", config.jre_major_version))
-                .join(if std::env::consts::OS == "macos" {
-                    "Contents/Home/bin"
-                } else {
-                    "bin"
-                })
-                .join("java")
-        };
+        let jre = self.java().await?;
+
+        let cmd_args: Vec<String> = config
+            .cmd_args
+            .iter()
+            .filter(|s| !s.is_empty())
+            .cloned()
+            .collect();
+        // a preset removed from the global settings leaves the instance on its own arguments
+        let jvm_args =
+            match jvm_presets::resolve(config.jvm_preset.as_deref(), config.jre_major_version) {
+                Ok(Some(preset)) => {
+                    jvm_presets::render(&preset, config.min_ram, config.max_ram, &cmd_args)
+                }
+                Ok(None) => cmd_args,
+                Err(e) => {
+                    warn!("[{}] Skipping the JVM preset: {}", config.name, e);
+                    cmd_args
+                }
+            };
 
         let mut server_start_command = Command::new(&jre);
         let server_start_command = server_start_command
             .arg(format!("-Xmx{}M", config.max_ram))
             .arg(format!("-Xms{}M", config.min_ram))
-            .args(
-                &config
-                    .cmd_args
-                    .iter()
-                    .filter(|s| !s.is_empty())
-                    .collect::<Vec<&String>>(),
-            );
+            .args(&jvm_args);
 
         let server_start_command = match &config.flavour {
-            Flavour::Forge { build_version } => {
-                let ForgeBuildVersion(build_version) = build_version
-                    .as_ref()
-                    .ok_or_else(|| eyre!("Forge version not found"))?;
-                let version_parts: Vec<&str> = config.version.split('.').collect();
-                let major_version: i32 = version_parts[1]
-                    .parse()
-                    .context("Unable to parse major Minecraft version for Forge")?;
-
-                if 17 <= major_version {
-                    let forge_args = match std::env::consts::OS {
-                        "windows" => "win_args.txt",
-                        _ => "unix_args.txt",
-                    };
-
-                    let mut full_forge_args = std::ffi::OsString::from("@");
-                    full_forge_args.push(
-                        self.path_to_instance
-                            .join("libraries")
-                            .join("net")
-                            .join("minecraftforge")
-                            .join("forge")
-                            .join(build_version.as_str())
-                            .join(forge_args)
-                            .into_os_string()
-                            .as_os_str(),
-                    );
-
-                    server_start_command.arg(full_forge_args)
-                } else if (7..=16).contains(&major_version) {
-                    let files = list_dir(&self.path_to_instance, Some(false))
-                        .await
-                        .context("Failed to find forge.jar")?;
-                    let forge_jar_name = files
-                        .iter()
-                        .find(|p| {
-                            p.extension().unwrap_or_default() == "jar"
-                                && p.file_name()
-                                    .unwrap_or_default()
-                                    .to_str()
-                                    .unwrap_or_default()
-                                    .starts_with(format!("forge-{}-", config.version,).as_str())
-                        })
-                        .ok_or_else(|| eyre!("Failed to find forge.jar"))?;
-                    server_start_command
-                        .arg("-jar")
-                        .arg(&self.path_to_instance.join(forge_jar_name))
-                } else {
-                    // 1.5 doesn't work due to JRE issues
-                    // 1.4 doesn't work since forge doesn't provide an installer
-                    let files = list_dir(&self.path_to_instance, Some(false))
-                        .await
-                        .context("Failed to find minecraftforge.jar")?;
-                    let server_jar_name = files
-                        .iter()
-                        .find(|p| {
-                            p.extension().unwrap_or_default() == "jar"
-                                && p.file_name()
-                                    .unwrap_or_default()
-                                    .to_str()
-                                    .unwrap_or_default()
-                                    .starts_with("minecraftforge")
-                        })
-                        .ok_or_else(|| eyre!("Failed to find minecraftforge.jar"))?;
-                    server_start_command
-                        .arg("-jar")
-                        .arg(&self.path_to_instance.join(server_jar_name))
-                }
-            }
+            Flavour::Forge { .. } | Flavour::NeoForge { .. } => server_start_command
+                .args(forge::launch_args(&self.path_to_instance, &config.version).await?),
             _ => server_start_command
                 .arg("-jar")
                 .arg(&self.path_to_instance.join("server.jar")),
         };
 
-        let server_start_command = server_start_command
-            .arg("nogui")
-            .current_dir(&self.path_to_instance);
+        // the proxies have no GUI to turn off
+        let server_start_command = if config.flavour.is_proxy() {
+            server_start_command
+        } else {
+            server_start_command.arg("nogui")
+        };
+        let server_start_command = server_start_command.current_dir(&self.path_to_instance);
 
         match dont_spawn_terminal(server_start_command)
             .stdout(Stdio::piped())
@@ -237,6 +178,19 @@ impl TServer for MinecraftInstance {
             .spawn()
         {
             Ok(mut proc) => {
+                let limits = ResourceLimits {
+                    cpu_limit: config.cpu_limit,
+                    memory_limit: config.memory_limit,
+                };
+                if let (false, Some(pid)) = (limits.is_unlimited(), proc.id()) {
+                    if let Err(e) = cgroup::apply(&self.uuid, pid, &limits) {
+                        warn!(
+                            "[{}] Running without resource limits, failed to apply them: {}",
+                            config.name.clone(),
+                            e
+                        );
+                    }
+                }
                 let stdin = proc.stdin.take().ok_or_else(|| {
                     error!(
                         "[{}] Failed to take stdin during startup",
@@ -409,6 +363,9 @@ impl TServer for MinecraftInstance {
                                             warn!("RCON is not enabled or misconfigured, skipping");
                                             self.rcon_conn.lock().await.take();
                                         }
+                                        if let Err(e) = self.announce_pending_changelog().await {
+                                            warn!("[{}] Failed to announce changelog: {}", name, e);
+                                        }
                                     }
                                     if let Some(system_msg) = parse_system_msg(&line) {
                                         let _ = event_broadcaster.send(Event {
@@ -547,6 +504,7 @@ impl TServer for MinecraftInstance {
             }
         }
     }
+    #[tracing::instrument(skip_all, fields(instance = %self.uuid))]
     async fn stop(&mut self, cause_by: CausedBy, block: bool) -> Result<(), Error> {
         let config = self.config.lock().await.clone();
 
@@ -575,7 +533,7 @@ impl TServer for MinecraftInstance {
                 error!("[{}] Failed to stop instance: stdin not available", name);
                 eyre!("Failed to stop instance: stdin not available")
             })?
-            .write_all(b"stop\n")
+            .write_all(format!("{}\n", config.flavour.stop_command()).as_bytes())
             .await
             .context("Failed to write to stdin")
             .map_err(|e| {
@@ -605,31 +563,32 @@ impl TServer for MinecraftInstance {
         }
     }
 
+    #[tracing::instrument(skip_all, fields(instance = %self.uuid))]
     async fn restart(&mut self, caused_by: CausedBy, block: bool) -> Result<(), Error> {
+        self.state().await.check(InstanceOperation::Restart)?;
         if block {
             self.stop(caused_by.clone(), block).await?;
             self.start(caused_by, block).await
         } else {
-            self.state
-                .lock()
-                .await
-                .try_new_state(StateAction::UserStop, None)?;
-
             let mut __self = self.clone();
-            tokio::task::spawn(async move {
-                self.stop(caused_by.clone(), true).await.unwrap();
-                self.start(caused_by, block).await.unwrap()
-            });
+            tokio::task::spawn(
+                async move {
+                    self.stop(caused_by.clone(), true).await.unwrap();
+                    self.start(caused_by, block).await.unwrap()
+                }
+                .in_current_span(),
+            );
             Ok(())
         }
     }
 
+    #[tracing::instrument(skip_all, fields(instance = %self.uuid))]
     async fn kill(&mut self, _cause_by: CausedBy) -> Result<(), Error> {
         let config = self.config.lock().await.clone();
 
-        if self.state().await == State::Stopped {
-            warn!("[{}] Instance is already stopped", config.name.clone());
-            return Err(eyre!("Instance is already stopped").into());
+        if let Err(e) = self.state().await.check(InstanceOperation::Kill) {
+            warn!("[{}] {}", config.name.clone(), e.source);
+            return Err(e);
         }
         self.process
             .lock()
@@ -658,12 +617,12 @@ impl TServer for MinecraftInstance {
 
     async fn send_command(&self, command: &str, cause_by: CausedBy) -> Result<(), Error> {
         let config = self.config.lock().await.clone();
-        if self.state().await == State::Stopped {
-            Err(eyre!("Instance is stopped").into())
+        if let Err(e) = self.state().await.check(InstanceOperation::SendCommand) {
+            Err(e)
         } else {
             match self.stdin.lock().await.as_mut() {
                 Some(stdin) => match {
-                    if command == "stop" {
+                    if command == config.flavour.stop_command() {
                         self.state.lock().await.try_new_state(
                             StateAction::UserStop,
                             Some(&|state| {
@@ -717,11 +676,27 @@ impl TServer for MinecraftInstance {
                 let memory_usage = proc.memory();
                 let disk_usage = proc.disk_usage();
                 let start_time = proc.start_time();
+                let limits = {
+                    let config = self.config.lock().await;
+                    ResourceLimits {
+                        cpu_limit: config.cpu_limit,
+                        memory_limit: config.memory_limit,
+                    }
+                };
+                // the kernel's count is what the memory limit is enforced against
+                let usage = if !limits.is_unlimited() && cgroup::is_supported() {
+                    cgroup::usage(&self.uuid)
+                } else {
+                    Default::default()
+                };
                 MonitorReport {
-                    memory_usage: Some(memory_usage),
+                    memory_usage: Some(usage.memory_current.unwrap_or(memory_usage)),
                     disk_usage: Some(disk_usage.into()),
                     cpu_usage: Some(cpu_usage),
                     start_time: Some(start_time),
+                    cpu_limit: limits.cpu_limit,
+                    memory_limit: limits.memory_limit.map(|mb| mb as u64 * 1024 * 1024),
+                    cpu_throttled_usec: usage.throttled_usec,
                 }
             } else {
                 MonitorReport::default()
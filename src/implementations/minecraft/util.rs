@@ -4,8 +4,10 @@ use serde_json::{self, Value};
 use std::{collections::BTreeMap, path::Path, str::FromStr};
 use tokio::io::AsyncBufReadExt;
 
+use super::neoforge::{get_neoforge_versions, neoforge_minecraft_version};
 use super::{
-    FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, PaperBuildVersion,
+    FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, NeoForgeBuildVersion,
+    PaperBuildVersion, PurpurBuildVersion, VelocityBuildVersion,
 };
 use crate::error::Error;
 
@@ -59,8 +61,18 @@ pub async fn get_server_jar_url(version: &str, flavour: &Flavour) -> Option<(Str
             installer_version,
         } => get_fabric_jar_url(version, loader_version, installer_version).await,
         Flavour::Paper { build_version } => get_paper_jar_url(version, build_version).await,
-        Flavour::Spigot => todo!(),
+        Flavour::Purpur { build_version } => get_purpur_jar_url(version, build_version).await,
+        // Spigot isn't distributed as a jar, it has to be built with BuildTools
+        Flavour::Spigot => None,
         Flavour::Forge { build_version } => get_forge_jar_url(version, build_version).await.ok(),
+        Flavour::NeoForge { build_version } => {
+            get_neoforge_jar_url(version, build_version).await.ok()
+        }
+        Flavour::Velocity { build_version } => get_velocity_jar_url(version, build_version).await,
+        Flavour::BungeeCord => Some((
+            super::proxy::BUNGEECORD_JAR_URL.to_string(),
+            Flavour::BungeeCord,
+        )),
     }
 }
 
@@ -257,16 +269,19 @@ pub async fn get_fabric_jar_url(
     ))
 }
 
-pub async fn get_paper_jar_url(
+/// Download url and number of a build of a PaperMC project, the newest stable one
+/// unless `build_version` is given
+async fn get_papermc_build(
+    project: &str,
     version: &str,
-    paper_build_version: &Option<PaperBuildVersion>,
-) -> Option<(String, Flavour)> {
+    build_version: Option<i64>,
+) -> Option<(String, i64)> {
     let client = reqwest::Client::new();
 
     let builds_text = client
         .get(format!(
-            "https://api.papermc.io/v2/projects/paper/versions/{}/builds/",
-            version
+            "https://api.papermc.io/v2/projects/{}/versions/{}/builds/",
+            project, version
         ))
         .send()
         .await
@@ -277,8 +292,8 @@ pub async fn get_paper_jar_url(
     let builds: serde_json::Value = serde_json::from_str(&builds_text).ok()?;
     let mut builds = builds.get("builds")?.as_array()?.iter();
 
-    let build = if let Some(PaperBuildVersion(b)) = paper_build_version {
-        builds.find(|build| build.get("build").unwrap().as_i64().unwrap().eq(b))?
+    let build = if let Some(b) = build_version {
+        builds.find(|build| build.get("build").unwrap().as_i64().unwrap().eq(&b))?
     } else {
         builds
             .filter(|build| {
@@ -300,7 +315,8 @@ pub async fn get_paper_jar_url(
 
     Some((
         format!(
-            "https://api.papermc.io/v2/projects/paper/versions/{}/builds/{}/downloads/{}",
+            "https://api.papermc.io/v2/projects/{}/versions/{}/builds/{}/downloads/{}",
+            project,
             version,
             build_version,
             build
@@ -309,12 +325,88 @@ pub async fn get_paper_jar_url(
                 .get("name")?
                 .as_str()?,
         ),
+        build_version,
+    ))
+}
+
+pub async fn get_paper_jar_url(
+    version: &str,
+    paper_build_version: &Option<PaperBuildVersion>,
+) -> Option<(String, Flavour)> {
+    let (url, build_version) = get_papermc_build(
+        "paper",
+        version,
+        paper_build_version.as_ref().map(|PaperBuildVersion(b)| *b),
+    )
+    .await?;
+    Some((
+        url,
         Flavour::Paper {
             build_version: Some(PaperBuildVersion(build_version)),
         },
     ))
 }
 
+pub async fn get_velocity_jar_url(
+    version: &str,
+    velocity_build_version: &Option<VelocityBuildVersion>,
+) -> Option<(String, Flavour)> {
+    let (url, build_version) = get_papermc_build(
+        "velocity",
+        version,
+        velocity_build_version
+            .as_ref()
+            .map(|VelocityBuildVersion(b)| *b),
+    )
+    .await?;
+    Some((
+        url,
+        Flavour::Velocity {
+            build_version: Some(VelocityBuildVersion(build_version)),
+        },
+    ))
+}
+
+pub async fn get_purpur_jar_url(
+    version: &str,
+    purpur_build_version: &Option<PurpurBuildVersion>,
+) -> Option<(String, Flavour)> {
+    let client = reqwest::Client::new();
+
+    let version_text = client
+        .get(format!("https://api.purpurmc.org/v2/purpur/{}", version))
+        .send()
+        .await
+        .ok()?
+        .text()
+        .await
+        .ok()?;
+    let version_json: serde_json::Value = serde_json::from_str(&version_text).ok()?;
+    let builds = version_json.get("builds")?;
+
+    // builds are numbers sent as strings, and only successful ones are listed
+    let build_version = if let Some(PurpurBuildVersion(b)) = purpur_build_version {
+        builds
+            .get("all")?
+            .as_array()?
+            .iter()
+            .filter_map(|build| build.as_str()?.parse::<i64>().ok())
+            .find(|build| build == b)?
+    } else {
+        builds.get("latest")?.as_str()?.parse::<i64>().ok()?
+    };
+
+    Some((
+        format!(
+            "https://api.purpurmc.org/v2/purpur/{}/{}/download",
+            version, build_version
+        ),
+        Flavour::Purpur {
+            build_version: Some(PurpurBuildVersion(build_version)),
+        },
+    ))
+}
+
 pub async fn get_forge_jar_url(
     version: &str,
     forge_build_version: &Option<ForgeBuildVersion>,
@@ -355,19 +447,39 @@ pub async fn get_forge_jar_url(
     ))
 }
 
-pub async fn get_jre_url(version: &str) -> Option<(String, u64)> {
-    let client = reqwest::Client::new();
-    let os = if std::env::consts::OS == "macos" {
-        "mac"
-    } else {
-        std::env::consts::OS
-    };
-    let arch = if std::env::consts::ARCH == "x86_64" {
-        "x64"
+pub async fn get_neoforge_jar_url(
+    version: &str,
+    neoforge_build_version: &Option<NeoForgeBuildVersion>,
+) -> Result<(String, Flavour), Error> {
+    let build = if let Some(NeoForgeBuildVersion(b)) = neoforge_build_version {
+        b.clone()
     } else {
-        std::env::consts::ARCH
+        get_neoforge_versions()
+            .await?
+            .into_iter()
+            .filter(|build| neoforge_minecraft_version(build).as_deref() == Some(version))
+            .last()
+            .context("Failed to get neoforge versions, no builds found for this version")?
     };
 
+    Ok((
+        format!(
+            "https://maven.neoforged.net/releases/net/neoforged/neoforge/{}/neoforge-{}-installer.jar",
+            build, build
+        ),
+        Flavour::NeoForge {
+            build_version: Some(NeoForgeBuildVersion(build)),
+        },
+    ))
+}
+
+pub async fn get_jre_url(version: &str) -> Option<(String, u64)> {
+    get_jre_url_for_major(get_java_major(version).await?)
+}
+
+/// The major Java version Mojang runs `version` on
+pub async fn get_java_major(version: &str) -> Option<u64> {
+    let client = reqwest::Client::new();
     let major_java_version = {
         let val = match serde_json::Value::from_str(
             client
@@ -413,6 +525,16 @@ pub async fn get_jre_url(version: &str) -> Option<(String, u64)> {
             val
         }
     };
+    Some(major_java_version)
+}
+
+pub fn get_jre_url_for_major(major_java_version: u64) -> Option<(String, u64)> {
+    let os = if std::env::consts::OS == "macos" {
+        "mac"
+    } else {
+        std::env::consts::OS
+    };
+    let arch = crate::host::jre_arch_for(major_java_version)?;
 
     Some((
         format!(
@@ -443,8 +565,9 @@ pub async fn name_to_uuid(name: impl AsRef<str>) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use crate::minecraft::{
-        util::{get_forge_jar_url, get_server_jar_url},
+        util::{get_forge_jar_url, get_neoforge_jar_url, get_server_jar_url},
         FabricInstallerVersion, FabricLoaderVersion, Flavour, ForgeBuildVersion, PaperBuildVersion,
+        PurpurBuildVersion,
     };
     use tokio;
 
@@ -512,11 +635,56 @@ mod tests {
         assert_eq!(super::get_paper_jar_url("1.19.3bruh", &None).await, None);
     }
 
+    #[tokio::test]
+    async fn test_get_velocity_jar_url() {
+        let (url, flavour) = super::get_velocity_jar_url("3.3.0-SNAPSHOT", &None)
+            .await
+            .unwrap();
+        assert!(url.starts_with(
+            "https://api.papermc.io/v2/projects/velocity/versions/3.3.0-SNAPSHOT/builds/"
+        ));
+        assert!(matches!(
+            flavour,
+            Flavour::Velocity {
+                build_version: Some(_)
+            }
+        ));
+        assert_eq!(super::get_velocity_jar_url("3.3.0bruh", &None).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_get_purpur_jar_url() {
+        assert_eq!(
+            super::get_purpur_jar_url("1.19.4", &Some(PurpurBuildVersion(1985))).await,
+            Some((
+                "https://api.purpurmc.org/v2/purpur/1.19.4/1985/download".to_string(),
+                Flavour::Purpur {
+                    build_version: Some(PurpurBuildVersion(1985))
+                }
+            ))
+        );
+        assert!(super::get_purpur_jar_url("1.19.4", &None).await.is_some());
+        assert_eq!(super::get_purpur_jar_url("1.19.3bruh", &None).await, None);
+    }
+
     #[tokio::test]
     async fn test_get_forge_jar_url() {
         get_forge_jar_url("1.18.2", &None).await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_get_neoforge_jar_url() {
+        let (url, flavour) = get_neoforge_jar_url("1.20.4", &None).await.unwrap();
+        assert!(url.ends_with("-installer.jar"));
+        assert!(matches!(
+            flavour,
+            Flavour::NeoForge {
+                build_version: Some(_)
+            }
+        ));
+        assert!(get_neoforge_jar_url("1.19.3bruh", &None).await.is_err());
+    }
+
     #[tokio::test]
     async fn test_get_server_jar_url() {
         assert_eq!(
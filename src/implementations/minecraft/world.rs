@@ -0,0 +1,785 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event};
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{InstanceOperation, State, TServer};
+use crate::util::{format_byte, scoped_join_win_safe};
+
+use super::MinecraftInstance;
+use super::{nbt, region};
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default)]
+#[ts(export)]
+pub struct WorldResetPolicy {
+    /// whether scheduled resets are enabled, manual resets are always allowed
+    pub enabled: bool,
+    /// seconds between scheduled resets
+    pub interval: Option<u32>,
+    /// directory relative to the instance root that is copied in as the new world,
+    /// if `None` the world is deleted and the server generates a new one
+    pub template: Option<String>,
+    /// move the old world into `world_archives` instead of deleting it
+    pub archive_old_world: bool,
+    pub last_reset: Option<i64>,
+}
+
+impl WorldResetPolicy {
+    pub fn is_due(&self, now: i64) -> bool {
+        match (self.enabled, self.interval) {
+            (true, Some(interval)) => match self.last_reset {
+                Some(last_reset) => now - last_reset >= interval as i64,
+                None => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Dimension {
+    /// e.g. `minecraft:the_nether`
+    pub id: String,
+    /// directories holding the dimension's data, relative to the instance root
+    pub paths: Vec<String>,
+    pub size: u64,
+    pub last_modified: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RegionPruneOptions {
+    /// dimension ids to prune, all dimensions if `None`
+    pub dimensions: Option<Vec<String>>,
+    /// prune regions with no chunk saved since this unix timestamp
+    pub not_modified_since: Option<i64>,
+    /// prune regions entirely outside this many blocks from the origin
+    pub outside_radius: Option<u32>,
+    /// only report what would be pruned
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct PrunedRegion {
+    pub dimension: String,
+    pub x: i32,
+    pub z: i32,
+    pub last_modified: Option<i64>,
+    /// combined size of the region, entities and poi files
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RegionPruneReport {
+    pub dry_run: bool,
+    pub regions: Vec<PrunedRegion>,
+    pub reclaimed_bytes: u64,
+}
+
+/// Directories holding `.mca` files for a dimension
+fn region_dirs(dimension_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    const REGION_DIR_NAMES: [&str; 3] = ["region", "entities", "poi"];
+    dimension_dirs
+        .iter()
+        .flat_map(|dir| {
+            let is_region_dir = dir
+                .file_name()
+                .map(|n| REGION_DIR_NAMES.iter().any(|r| n == *r))
+                .unwrap_or(false);
+            if is_region_dir {
+                vec![dir.clone()]
+            } else {
+                REGION_DIR_NAMES.iter().map(|r| dir.join(r)).collect()
+            }
+        })
+        .filter(|dir| dir.is_dir())
+        .collect()
+}
+
+fn prune_regions_blocking(
+    dimensions: Vec<(String, Vec<PathBuf>)>,
+    options: &RegionPruneOptions,
+) -> Result<RegionPruneReport, Error> {
+    let mut regions = Vec::new();
+    for (dimension, dirs) in dimensions {
+        let dirs = region_dirs(&dirs);
+        // the chunk data in `region` decides, `entities` and `poi` follow along
+        let main_region_dir = match dirs.iter().find(|d| d.ends_with("region")) {
+            Some(v) => v,
+            None => continue,
+        };
+        for entry in std::fs::read_dir(main_region_dir)
+            .context(format!(
+                "Failed to read region directory {}",
+                main_region_dir.display()
+            ))?
+            .filter_map(|e| e.ok())
+        {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let coords = match region::parse_region_coords(&file_name) {
+                Some(v) => v,
+                None => continue,
+            };
+            // a truncated header means the region holds no chunk
+            let last_modified = region::RegionHeader::read_from(&entry.path())
+                .ok()
+                .and_then(|h| h.last_modified());
+            let stale = options
+                .not_modified_since
+                .map(|cutoff| last_modified.map_or(true, |t| t < cutoff));
+            let outside = options
+                .outside_radius
+                .map(|radius| !region::region_within_radius(coords, radius));
+            if !(stale.unwrap_or(false) || outside.unwrap_or(false)) {
+                continue;
+            }
+            let mut size = 0;
+            for dir in dirs.iter() {
+                let path = dir.join(&file_name);
+                if let Ok(metadata) = std::fs::metadata(&path) {
+                    size += metadata.len();
+                    if !options.dry_run {
+                        std::fs::remove_file(&path)
+                            .context(format!("Failed to remove {}", path.display()))?;
+                    }
+                }
+            }
+            regions.push(PrunedRegion {
+                dimension: dimension.clone(),
+                x: coords.0,
+                z: coords.1,
+                last_modified,
+                size,
+            });
+        }
+    }
+    Ok(RegionPruneReport {
+        dry_run: options.dry_run,
+        reclaimed_bytes: regions.iter().map(|r| r.size).sum(),
+        regions,
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct WorldCorruption {
+    /// relative to the instance root
+    pub file: String,
+    /// chunk coordinates, `None` if the whole file is affected
+    pub chunk: Option<(i32, i32)>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct WorldScanReport {
+    pub scanned_files: u32,
+    pub corruptions: Vec<WorldCorruption>,
+    /// directory the damaged region files were copied to before their bad chunks were cleared
+    pub quarantine_dir: Option<String>,
+}
+
+fn scan_world_blocking(
+    path_to_instance: &Path,
+    level_dir: &Path,
+    dimensions: Vec<(String, Vec<PathBuf>)>,
+    quarantine_dir: Option<&Path>,
+) -> Result<WorldScanReport, Error> {
+    let relative = |path: &Path| {
+        path.strip_prefix(path_to_instance)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut scanned_files = 0;
+    let mut corruptions = Vec::new();
+
+    let level_dat = level_dir.join("level.dat");
+    if level_dat.is_file() {
+        scanned_files += 1;
+        let res = std::fs::read(&level_dat)
+            .map_err(|e| e.to_string())
+            .and_then(|data| nbt::validate_gzip(&data));
+        if let Err(reason) = res {
+            corruptions.push(WorldCorruption {
+                file: relative(&level_dat),
+                chunk: None,
+                reason,
+            });
+        }
+    }
+
+    for (_, dirs) in dimensions {
+        for dir in region_dirs(&dirs) {
+            for entry in std::fs::read_dir(&dir)
+                .context(format!("Failed to read region directory {}", dir.display()))?
+                .filter_map(|e| e.ok())
+            {
+                let path = entry.path();
+                let coords = match region::parse_region_coords(&entry.file_name().to_string_lossy())
+                {
+                    Some(v) => v,
+                    None => continue,
+                };
+                scanned_files += 1;
+                let issues = region::scan_region_file(&path)
+                    .context(format!("Failed to read region file {}", path.display()))?;
+                if issues.is_empty() {
+                    continue;
+                }
+                let mut bad_chunks = Vec::new();
+                for issue in issues {
+                    match issue {
+                        region::RegionIssue::TruncatedHeader => corruptions.push(WorldCorruption {
+                            file: relative(&path),
+                            chunk: None,
+                            reason: "Region header is truncated".to_string(),
+                        }),
+                        region::RegionIssue::Chunk { index, reason } => {
+                            bad_chunks.push(index);
+                            corruptions.push(WorldCorruption {
+                                file: relative(&path),
+                                chunk: Some(region::chunk_coords(coords, index)),
+                                reason,
+                            });
+                        }
+                    }
+                }
+                if let Some(quarantine_dir) = quarantine_dir {
+                    let dest = quarantine_dir.join(relative(&path));
+                    if let Some(parent) = dest.parent() {
+                        std::fs::create_dir_all(parent).context(format!(
+                            "Failed to create quarantine directory {}",
+                            parent.display()
+                        ))?;
+                    }
+                    std::fs::copy(&path, &dest)
+                        .context(format!("Failed to quarantine {}", path.display()))?;
+                    if bad_chunks.is_empty() {
+                        // nothing salvageable, the game recreates the file
+                        std::fs::remove_file(&path)
+                            .context(format!("Failed to remove {}", path.display()))?;
+                    } else {
+                        region::clear_chunks(&path, &bad_chunks).context(format!(
+                            "Failed to clear corrupted chunks in {}",
+                            path.display()
+                        ))?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(WorldScanReport {
+        scanned_files,
+        corruptions,
+        quarantine_dir: quarantine_dir.map(relative),
+    })
+}
+
+/// Total size in bytes and latest modification time of everything under `dir`
+fn dir_stats(dir: &Path) -> (u64, Option<i64>) {
+    let mut size = 0;
+    let mut last_modified: Option<i64> = None;
+    for entry in walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+    {
+        let metadata = match entry.metadata() {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+        if let Some(modified) = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        {
+            let modified = modified.as_secs() as i64;
+            last_modified = Some(last_modified.map_or(modified, |v| v.max(modified)));
+        }
+    }
+    (size, last_modified)
+}
+
+/// Directory of the world `name` in the instance. The name comes from `level-name`
+/// in `server.properties`, which anyone able to edit files can set to a path outside
+/// the instance or to the instance directory itself.
+fn world_dir(path_to_instance: &Path, name: &str) -> Result<PathBuf, Error> {
+    let path = Path::new(name);
+    if name.trim().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "Invalid level-name {}, it must be a directory inside the instance",
+                name
+            ),
+        });
+    }
+    scoped_join_win_safe(path_to_instance, path)
+}
+
+impl MinecraftInstance {
+    pub async fn level_name(&self) -> String {
+        self.configurable_manifest
+            .lock()
+            .await
+            .get_unique_setting_key("level-name")
+            .and_then(|v| v.get_value().map(|v| v.try_as_string().ok().cloned()))
+            .flatten()
+            .unwrap_or_else(|| "world".to_string())
+    }
+
+    /// Directories of the main world and, for Bukkit based servers, the nether and end worlds
+    pub async fn world_dirs(&self) -> Result<Vec<PathBuf>, Error> {
+        let level_name = self.level_name().await;
+        let mut ret = Vec::new();
+        for name in [
+            level_name.clone(),
+            format!("{}_nether", level_name),
+            format!("{}_the_end", level_name),
+        ] {
+            let dir = world_dir(&self.path_to_instance, &name)?;
+            if dir.is_dir() {
+                ret.push(dir);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Maps dimension ids to the directories holding their data.
+    ///
+    /// Vanilla keeps the nether and end inside the level directory while Bukkit based
+    /// servers split them into `<level>_nether` and `<level>_the_end`.
+    /// Datapack and modded dimensions live under `<level>/dimensions/<namespace>/<name>`.
+    async fn dimension_dirs(&self) -> Result<Vec<(String, Vec<PathBuf>)>, Error> {
+        let level_name = self.level_name().await;
+        let level_dir = world_dir(&self.path_to_instance, &level_name)?;
+        let mut ret = vec![
+            (
+                "minecraft:overworld".to_string(),
+                ["region", "entities", "poi"]
+                    .iter()
+                    .map(|dir| level_dir.join(dir))
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "minecraft:the_nether".to_string(),
+                vec![
+                    level_dir.join("DIM-1"),
+                    world_dir(&self.path_to_instance, &format!("{}_nether", level_name))?
+                        .join("DIM-1"),
+                ],
+            ),
+            (
+                "minecraft:the_end".to_string(),
+                vec![
+                    level_dir.join("DIM1"),
+                    world_dir(&self.path_to_instance, &format!("{}_the_end", level_name))?
+                        .join("DIM1"),
+                ],
+            ),
+        ];
+        if let Ok(namespaces) = std::fs::read_dir(level_dir.join("dimensions")) {
+            for namespace in namespaces.filter_map(|e| e.ok()) {
+                let namespace_name = namespace.file_name().to_string_lossy().to_string();
+                if let Ok(dimensions) = std::fs::read_dir(namespace.path()) {
+                    for dimension in dimensions.filter_map(|e| e.ok()) {
+                        if dimension.path().is_dir() {
+                            ret.push((
+                                format!(
+                                    "{}:{}",
+                                    namespace_name,
+                                    dimension.file_name().to_string_lossy()
+                                ),
+                                vec![dimension.path()],
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(ret
+            .into_iter()
+            .map(|(id, dirs)| {
+                (
+                    id,
+                    dirs.into_iter().filter(|d| d.is_dir()).collect::<Vec<_>>(),
+                )
+            })
+            .filter(|(_, dirs)| !dirs.is_empty())
+            .collect())
+    }
+
+    pub async fn list_dimensions(&self) -> Result<Vec<Dimension>, Error> {
+        let dimension_dirs = self.dimension_dirs().await?;
+        let path_to_instance = self.path_to_instance.clone();
+        tokio::task::spawn_blocking(move || {
+            dimension_dirs
+                .into_iter()
+                .map(|(id, dirs)| {
+                    let mut size = 0;
+                    let mut last_modified: Option<i64> = None;
+                    for dir in dirs.iter() {
+                        let (dir_size, dir_last_modified) = dir_stats(dir);
+                        size += dir_size;
+                        last_modified = last_modified.max(dir_last_modified);
+                    }
+                    Dimension {
+                        id,
+                        paths: dirs
+                            .iter()
+                            .map(|dir| {
+                                dir.strip_prefix(&path_to_instance)
+                                    .unwrap_or(dir)
+                                    .to_string_lossy()
+                                    .to_string()
+                            })
+                            .collect(),
+                        size,
+                        last_modified,
+                    }
+                })
+                .collect()
+        })
+        .await
+        .context("Failed to spawn blocking task")
+        .map_err(Into::into)
+    }
+
+    /// Deletes the data of a single dimension so the server regenerates it on the next start
+    pub async fn reset_dimension(&self, dimension_id: &str) -> Result<(), Error> {
+        self.state().await.check(InstanceOperation::ModifyWorld)?;
+        let (_, dirs) = self
+            .dimension_dirs()
+            .await?
+            .into_iter()
+            .find(|(id, _)| id == dimension_id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Dimension {} not found", dimension_id),
+            })?;
+        for dir in dirs {
+            crate::util::fs::remove_dir_all(dir).await?;
+        }
+        info!("[{}] Reset dimension {}", self.name().await, dimension_id);
+        Ok(())
+    }
+
+    /// Removes region files matching the prune options, or only reports them on a dry run
+    pub async fn prune_regions(
+        &self,
+        options: RegionPruneOptions,
+    ) -> Result<RegionPruneReport, Error> {
+        if !options.dry_run {
+            self.state().await.check(InstanceOperation::ModifyWorld)?;
+        }
+        if options.not_modified_since.is_none() && options.outside_radius.is_none() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("At least one pruning criterion is required"),
+            });
+        }
+        let dimensions: Vec<_> = self
+            .dimension_dirs()
+            .await?
+            .into_iter()
+            .filter(|(id, _)| {
+                options
+                    .dimensions
+                    .as_ref()
+                    .map_or(true, |dimensions| dimensions.contains(id))
+            })
+            .collect();
+        let report =
+            tokio::task::spawn_blocking(move || prune_regions_blocking(dimensions, &options))
+                .await
+                .context("Failed to spawn blocking task")??;
+        if !report.dry_run {
+            info!(
+                "[{}] Pruned {} regions, reclaimed {}",
+                self.name().await,
+                report.regions.len(),
+                format_byte(report.reclaimed_bytes)
+            );
+        }
+        Ok(report)
+    }
+
+    /// Scans `level.dat` and all region files for corruption.
+    ///
+    /// With `quarantine` set, damaged region files are copied to `world_quarantine` and
+    /// their corrupted chunks are dropped so the server regenerates them.
+    pub async fn scan_world(&self, quarantine: bool) -> Result<WorldScanReport, Error> {
+        if quarantine {
+            self.state().await.check(InstanceOperation::ModifyWorld)?;
+        }
+        let path_to_instance = self.path_to_instance.clone();
+        let level_dir = world_dir(&self.path_to_instance, &self.level_name().await)?;
+        let dimensions = self.dimension_dirs().await?;
+        let quarantine_dir = quarantine.then(|| {
+            self.path_to_instance
+                .join("world_quarantine")
+                .join(chrono::Utc::now().timestamp().to_string())
+        });
+        let report = tokio::task::spawn_blocking(move || {
+            scan_world_blocking(
+                &path_to_instance,
+                &level_dir,
+                dimensions,
+                quarantine_dir.as_deref(),
+            )
+        })
+        .await
+        .context("Failed to spawn blocking task")??;
+        if !report.corruptions.is_empty() {
+            warn!(
+                "[{}] World scan found {} corrupted entries",
+                self.name().await,
+                report.corruptions.len()
+            );
+        }
+        Ok(report)
+    }
+
+    pub async fn world_reset_policy(&self) -> Result<WorldResetPolicy, Error> {
+        let path = self.path_to_instance.join(".lodestone_world_reset.json");
+        if !path.is_file() {
+            return Ok(WorldResetPolicy::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse world reset policy at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn set_world_reset_policy(&self, policy: &WorldResetPolicy) -> Result<(), Error> {
+        if let Some(template) = &policy.template {
+            let template_path = scoped_join_win_safe(&self.path_to_instance, template)?;
+            if !template_path.is_dir() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Template world {} does not exist", template),
+                });
+            }
+        }
+        crate::util::fs::write_all(
+            self.path_to_instance.join(".lodestone_world_reset.json"),
+            serde_json::to_string_pretty(policy)
+                .context("Failed to serialize world reset policy")?,
+        )
+        .await
+    }
+
+    /// Stops the server if it's running, archives or deletes the current world,
+    /// copies in the template world if one is configured, then starts the server again
+    pub async fn reset_world(&mut self, caused_by: CausedBy) -> Result<(), Error> {
+        self.state().await.check(InstanceOperation::ResetWorld)?;
+        let mut policy = self.world_reset_policy().await?;
+        let now = chrono::Utc::now().timestamp();
+        // recorded up front so a failing reset isn't retried by the scheduler every tick
+        policy.last_reset = Some(now);
+        self.set_world_reset_policy(&policy).await?;
+        let name = self.name().await;
+        let (progression_start_event, event_id) = Event::new_progression_event_start(
+            format!("Resetting world of {name}"),
+            Some(4.0),
+            None,
+            caused_by.clone(),
+        );
+        self.event_broadcaster.send(progression_start_event);
+
+        let res: Result<(), Error> = async {
+            let was_running = self.state().await == State::Running;
+            if was_running {
+                self.event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        "Stopping server",
+                        1.0,
+                    ));
+                self.stop(caused_by.clone(), true).await?;
+            }
+
+            let level_name = self.level_name().await;
+            let world_dirs = self.world_dirs().await?;
+            if policy.archive_old_world {
+                self.event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        "Archiving old world",
+                        1.0,
+                    ));
+                let archive_dir = self
+                    .path_to_instance
+                    .join("world_archives")
+                    .join(format!("{}-{}", level_name, now));
+                crate::util::fs::create_dir_all(&archive_dir).await?;
+                for dir in world_dirs {
+                    if let Some(dir_name) = dir.file_name() {
+                        crate::util::fs::rename(&dir, archive_dir.join(dir_name)).await?;
+                    }
+                }
+            } else {
+                self.event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        "Deleting old world",
+                        1.0,
+                    ));
+                for dir in world_dirs {
+                    crate::util::fs::remove_dir_all(dir).await?;
+                }
+            }
+
+            if let Some(template) = &policy.template {
+                self.event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        "Copying template world",
+                        1.0,
+                    ));
+                let template_path = scoped_join_win_safe(&self.path_to_instance, template)?;
+                let world_path = world_dir(&self.path_to_instance, &level_name)?;
+                tokio::task::spawn_blocking(move || {
+                    let mut options = fs_extra::dir::CopyOptions::new();
+                    options.copy_inside = true;
+                    fs_extra::dir::copy(&template_path, &world_path, &options).context(format!(
+                        "Failed to copy template world from {}",
+                        template_path.display()
+                    ))
+                })
+                .await
+                .context("Failed to spawn blocking task")??;
+            }
+
+            if was_running {
+                self.event_broadcaster
+                    .send(Event::new_progression_event_update(
+                        &event_id,
+                        "Starting server",
+                        1.0,
+                    ));
+                self.start(caused_by, false).await?;
+            }
+            Ok(())
+        }
+        .await;
+
+        match &res {
+            Ok(_) => {
+                info!("[{}] World reset", name);
+                self.event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        true,
+                        Some("World reset"),
+                        None,
+                    ));
+            }
+            Err(e) => {
+                error!("[{}] Failed to reset world: {}", name, e);
+                self.event_broadcaster
+                    .send(Event::new_progression_event_end(
+                        event_id,
+                        false,
+                        Some(&format!("Failed to reset world: {}", e)),
+                        None,
+                    ));
+            }
+        }
+        res
+    }
+}
+
+#[test]
+fn test_world_reset_policy_is_due() {
+    let mut policy = WorldResetPolicy {
+        enabled: true,
+        interval: Some(60),
+        ..Default::default()
+    };
+    assert!(policy.is_due(0));
+    policy.last_reset = Some(100);
+    assert!(!policy.is_due(159));
+    assert!(policy.is_due(160));
+    policy.enabled = false;
+    assert!(!policy.is_due(1000));
+    policy.enabled = true;
+    policy.interval = None;
+    assert!(!policy.is_due(1000));
+}
+
+#[test]
+fn test_prune_regions() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let region_dir = temp_dir.path().join("region");
+    let entities_dir = temp_dir.path().join("entities");
+    std::fs::create_dir(&region_dir).unwrap();
+    std::fs::create_dir(&entities_dir).unwrap();
+    for name in ["r.0.0.mca", "r.5.0.mca"] {
+        std::fs::write(region_dir.join(name), [0; region::SECTOR_SIZE * 2]).unwrap();
+        std::fs::write(entities_dir.join(name), [0; 10]).unwrap();
+    }
+    let dimensions = vec![(
+        "minecraft:overworld".to_string(),
+        vec![region_dir.clone(), entities_dir.clone()],
+    )];
+    let mut options = RegionPruneOptions {
+        dimensions: None,
+        not_modified_since: None,
+        outside_radius: Some(1000),
+        dry_run: true,
+    };
+    let report = prune_regions_blocking(dimensions.clone(), &options).unwrap();
+    assert_eq!(report.regions.len(), 1);
+    assert_eq!((report.regions[0].x, report.regions[0].z), (5, 0));
+    assert_eq!(report.reclaimed_bytes, region::SECTOR_SIZE as u64 * 2 + 10);
+    assert!(region_dir.join("r.5.0.mca").exists());
+
+    options.dry_run = false;
+    prune_regions_blocking(dimensions, &options).unwrap();
+    assert!(!region_dir.join("r.5.0.mca").exists());
+    assert!(!entities_dir.join("r.5.0.mca").exists());
+    assert!(region_dir.join("r.0.0.mca").exists());
+}
+
+#[test]
+fn test_dir_stats() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    assert_eq!(dir_stats(temp_dir.path()).0, 0);
+    std::fs::create_dir(temp_dir.path().join("region")).unwrap();
+    std::fs::write(temp_dir.path().join("region").join("r.0.0.mca"), [0; 100]).unwrap();
+    std::fs::write(temp_dir.path().join("level.dat"), [0; 20]).unwrap();
+    let (size, last_modified) = dir_stats(temp_dir.path());
+    assert_eq!(size, 120);
+    assert!(last_modified.is_some());
+}
+
+#[test]
+fn test_world_dir() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let root = temp_dir.path();
+    assert_eq!(world_dir(root, "world").unwrap(), root.join("world"));
+    assert_eq!(
+        world_dir(root, "worlds/survival_nether").unwrap(),
+        root.join("worlds").join("survival_nether")
+    );
+    for name in ["", ".", "..", "../other_instance", "world/../..", "/etc"] {
+        assert!(world_dir(root, name).is_err(), "{} was accepted", name);
+    }
+}
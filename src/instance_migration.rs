@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::backup::{archive, pause_saving, resume_saving, BackupGuard, NOT_BACKED_UP};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, ProgressionEventID};
+use crate::prelude::{path_to_tmp, GameInstance};
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{InstanceOperation, State, TServer};
+use crate::types::InstanceUuid;
+use crate::util::rand_alphanumeric;
+
+/// Another Lodestone core instances can be migrated to
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct PeerCore {
+    /// assigned by the core when left empty
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    /// e.g. `https://node2.example.com:16662`
+    pub address: String,
+    /// token of a user allowed to create instances on that core, cleared when sent
+    /// to clients, sending it back empty keeps the stored one
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct PeerCoresSettings {
+    pub cores: Vec<PeerCore>,
+}
+
+impl PeerCoresSettings {
+    /// Validates the cores, assigns ids to new ones and keeps the tokens of `old`
+    /// for the ones sent back without a token
+    pub fn prepare(&mut self, old: &PeerCoresSettings) -> Result<(), Error> {
+        for core in self.cores.iter_mut() {
+            if core.name.trim().is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Core name is empty"),
+                });
+            }
+            let url = reqwest::Url::parse(&core.address).map_err(|e| Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid address of core {}: {}", core.name, e),
+            })?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Address of core {} must be http or https", core.name),
+                });
+            }
+            if core.id.is_empty() {
+                core.id = rand_alphanumeric(8);
+            }
+            if core.token.is_empty() {
+                core.token = old
+                    .get(&core.id)
+                    .map(|old| old.token.clone())
+                    .unwrap_or_default();
+            }
+            if core.token.is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Core {} has no token", core.name),
+                });
+            }
+        }
+        let mut ids: Vec<&str> = self.cores.iter().map(|core| core.id.as_str()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.len() != self.cores.len() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Core ids must be unique"),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, id: &str) -> Result<&PeerCore, Error> {
+        self.cores
+            .iter()
+            .find(|core| core.id == id)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("Core {} not found", id),
+            })
+    }
+
+    /// The cores with their tokens left out, to be sent to clients
+    pub fn redacted(&self) -> Self {
+        let mut ret = self.clone();
+        for core in ret.cores.iter_mut() {
+            core.token.clear();
+        }
+        ret
+    }
+}
+
+/// Sent by the migrating core once every file has been transferred
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationFinish {
+    /// name of the instance's directory on the migrating core
+    pub dir_name: String,
+    /// every file of the instance, anything else in the staged copy was deleted
+    /// on the migrating core after the first transfer
+    pub files: Vec<String>,
+    pub start: bool,
+}
+
+/// Where an instance being migrated to this core is put together
+pub fn path_to_staging(uuid: &InstanceUuid) -> PathBuf {
+    path_to_tmp().join("migrations").join(uuid.to_string())
+}
+
+/// Files and links under `root` relative to it, leaving out the top level entries
+/// in `exclude`
+fn list_tree(root: &Path, exclude: &[&str]) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(false)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() != 1 || !exclude.iter().any(|name| entry.file_name() == *name)
+        });
+    for entry in walker {
+        let entry = entry.context(format!("Failed to walk {}", root.display()))?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        ret.push(
+            entry
+                .path()
+                .strip_prefix(root)
+                .context("Entry outside of the instance directory")?
+                .to_string_lossy()
+                .replace('\\', "/"),
+        );
+    }
+    Ok(ret)
+}
+
+/// Deletes the files of the staged copy that are not in `files`
+pub fn prune_staged(staging: &Path, files: &[String]) -> Result<(), Error> {
+    let keep: HashSet<&str> = files.iter().map(|file| file.as_str()).collect();
+    for file in list_tree(staging, &[])? {
+        if !keep.contains(file.as_str()) {
+            let path = staging.join(&file);
+            std::fs::remove_file(&path).context(format!("Failed to remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `dir_name` is a plain directory name, so it can't point outside
+/// of the instances directory
+pub fn validate_dir_name(dir_name: &str) -> Result<(), Error> {
+    let mut components = Path::new(dir_name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(_)), None) => Ok(()),
+        _ => Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid instance directory name {}", dir_name),
+        }),
+    }
+}
+
+async fn send_archive(
+    client: &reqwest::Client,
+    peer: &PeerCore,
+    url: String,
+    archive: &Path,
+) -> Result<(), Error> {
+    let file = tokio::fs::File::open(archive)
+        .await
+        .context(format!("Failed to open {}", archive.display()))?;
+    let response = client
+        .put(url)
+        .bearer_auth(&peer.token)
+        .body(reqwest::Body::wrap_stream(
+            tokio_util::io::ReaderStream::new(file),
+        ))
+        .send()
+        .await
+        .context(format!("Failed to reach core {}", peer.name))?;
+    check_response(peer, response).await
+}
+
+async fn check_response(peer: &PeerCore, response: reqwest::Response) -> Result<(), Error> {
+    if response.status().is_success() {
+        return Ok(());
+    }
+    let status = response.status();
+    Err(Error {
+        kind: ErrorKind::Internal,
+        source: eyre!(
+            "Core {} refused the migration ({}): {}",
+            peer.name,
+            status,
+            response.text().await.unwrap_or_default().trim()
+        ),
+    })
+}
+
+async fn archive_instance(
+    root: &Path,
+    dest: &Path,
+    since: Option<SystemTime>,
+) -> Result<u64, Error> {
+    let (root, dest) = (root.to_owned(), dest.to_owned());
+    tokio::task::spawn_blocking(move || match since {
+        Some(since) => archive::create_archive_since(&root, &dest, NOT_BACKED_UP, since),
+        None => archive::create_archive(&root, &dest, NOT_BACKED_UP, None),
+    })
+    .await
+    .context("Failed to spawn blocking task")?
+}
+
+/// Copies an instance to `peer`. Offline migrations need the instance stopped.
+/// Live ones transfer it while it runs, then stop it and send the files changed
+/// in the meantime, so it is only down for the final sync. Backups are not
+/// transferred, and the instance is left stopped on this core either way.
+async fn migrate_instance_inner(
+    instance: &mut GameInstance,
+    peer: &PeerCore,
+    live: bool,
+    start_on_target: bool,
+    event_broadcaster: &EventBroadcaster,
+    event_id: &ProgressionEventID,
+    caused_by: CausedBy,
+) -> Result<(), Error> {
+    let uuid = instance.uuid().await;
+    let root = instance.path().await;
+    let dir_name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| eyre!("Instance directory has no name"))?;
+    let state = instance.state().await;
+    state.check(InstanceOperation::Migrate)?;
+    if state == State::Running && !live {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Stop the instance first, or migrate it live while it runs"),
+        });
+    }
+    let live = state == State::Running;
+    let base_url = format!(
+        "{}/api/v1/migration/{}",
+        peer.address.trim_end_matches('/'),
+        uuid
+    );
+    let client = reqwest::Client::new();
+    let temp_dir =
+        tempfile::tempdir_in(path_to_tmp()).context("Failed to create temporary directory")?;
+
+    event_broadcaster.send(Event::new_progression_event_update(
+        event_id,
+        "Archiving instance",
+        1.0,
+    ));
+    // files modified while the archive is made may or may not be in it, so the
+    // delta starts a little before
+    let started_at = SystemTime::now() - Duration::from_secs(5);
+    let full = temp_dir.path().join("full.tar.gz");
+    let paused = live && pause_saving(instance).await;
+    let res = archive_instance(&root, &full, None).await;
+    if paused {
+        resume_saving(instance).await;
+    }
+    res?;
+
+    event_broadcaster.send(Event::new_progression_event_update(
+        event_id,
+        format!("Transferring instance to {}", peer.name),
+        1.0,
+    ));
+    send_archive(&client, peer, format!("{}/stage", base_url), &full).await?;
+
+    if live {
+        event_broadcaster.send(Event::new_progression_event_update(
+            event_id,
+            "Stopping instance for the final sync",
+            1.0,
+        ));
+        instance.stop(caused_by.clone(), true).await?;
+        let delta = temp_dir.path().join("delta.tar.gz");
+        let res = match archive_instance(&root, &delta, Some(started_at)).await {
+            Ok(_) => send_archive(&client, peer, format!("{}/delta", base_url), &delta).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            // nothing runs on the target yet, so the instance goes back up here
+            if let Err(e) = instance.start(caused_by, false).await {
+                error!(
+                    "Failed to restart instance {} after a failed migration: {}",
+                    uuid, e
+                );
+            }
+            return Err(e);
+        }
+    }
+
+    event_broadcaster.send(Event::new_progression_event_update(
+        event_id,
+        format!("Starting instance on {}", peer.name),
+        1.0,
+    ));
+    let files = {
+        let root = root.clone();
+        tokio::task::spawn_blocking(move || list_tree(&root, NOT_BACKED_UP))
+            .await
+            .context("Failed to spawn blocking task")??
+    };
+    let response = client
+        .post(format!("{}/finish", base_url))
+        .bearer_auth(&peer.token)
+        .json(&MigrationFinish {
+            dir_name,
+            files,
+            start: start_on_target || live,
+        })
+        .send()
+        .await
+        .context(format!("Failed to reach core {}", peer.name))?;
+    check_response(peer, response).await
+}
+
+#[tracing::instrument(skip_all, fields(instance = tracing::field::Empty, peer = %peer.name, live = live))]
+pub async fn migrate_instance(
+    mut instance: GameInstance,
+    _guard: BackupGuard,
+    peer: PeerCore,
+    live: bool,
+    start_on_target: bool,
+    event_broadcaster: &EventBroadcaster,
+    caused_by: CausedBy,
+) -> Result<(), Error> {
+    let name = instance.name().await;
+    tracing::Span::current().record("instance", name.as_str());
+    let (progression_start_event, event_id) = Event::new_progression_event_start(
+        format!("Migrating {} to {}", name, peer.name),
+        Some(if live { 4.0 } else { 3.0 }),
+        None,
+        caused_by.clone(),
+    );
+    event_broadcaster.send(progression_start_event);
+    let res = migrate_instance_inner(
+        &mut instance,
+        &peer,
+        live,
+        start_on_target,
+        event_broadcaster,
+        &event_id,
+        caused_by,
+    )
+    .await;
+    match &res {
+        Ok(_) => {
+            info!("[{}] Migrated to {}", name, peer.name);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                true,
+                Some(&format!(
+                    "Migrated {} to {}, the copy on this core can be deleted once it runs there",
+                    name, peer.name
+                )),
+                None,
+            ));
+        }
+        Err(e) => {
+            error!("[{}] Failed to migrate to {}: {}", name, peer.name, e);
+            event_broadcaster.send(Event::new_progression_event_end(
+                event_id,
+                false,
+                Some(&format!(
+                    "Failed to migrate {} to {}: {}",
+                    name, peer.name, e
+                )),
+                None,
+            ));
+        }
+    }
+    res
+}
+
+#[test]
+fn test_prune_staged() {
+    let staging = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(staging.path().join("world/region")).unwrap();
+    std::fs::write(staging.path().join("world/region/r.0.0.mca"), b"old").unwrap();
+    std::fs::write(staging.path().join("world/level.dat"), b"level").unwrap();
+    std::fs::write(staging.path().join("server.properties"), b"motd=hi").unwrap();
+
+    prune_staged(
+        staging.path(),
+        &[
+            "world/level.dat".to_string(),
+            "server.properties".to_string(),
+        ],
+    )
+    .unwrap();
+    let mut left = list_tree(staging.path(), &[]).unwrap();
+    left.sort();
+    assert_eq!(left, vec!["server.properties", "world/level.dat"]);
+    assert!(validate_dir_name("survival-1a2b3c4d").is_ok());
+    assert!(validate_dir_name("../survival").is_err());
+    assert!(validate_dir_name("a/b").is_err());
+}
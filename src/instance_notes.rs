@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::{
+    auth::user_id::UserId,
+    error::{Error, ErrorKind},
+};
+
+// keep the history bounded so the config doesn't grow forever
+const MAX_NOTE_REVISIONS: usize = 50;
+const MAX_NOTE_LENGTH: usize = 100_000;
+/// where notes were kept before they became part of the instance config
+const LEGACY_FILE_NAME: &str = ".lodestone_notes.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct NoteRevision {
+    pub content: String,
+    pub author_id: UserId,
+    pub author_name: String,
+    pub time: i64,
+}
+
+/// Free-form documentation of an instance, kept in its config
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct InstanceNotes {
+    /// markdown content
+    pub content: String,
+    pub last_modified: Option<i64>,
+    /// newest revision first, does not include the current content
+    pub history: Vec<NoteRevision>,
+}
+
+impl InstanceNotes {
+    /// Replaces the content, keeping the previous one in the history.
+    /// Returns whether anything changed.
+    pub fn edit(
+        &mut self,
+        content: String,
+        author_id: UserId,
+        author_name: String,
+        now: i64,
+    ) -> Result<bool, Error> {
+        if content.chars().count() > MAX_NOTE_LENGTH {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Notes cannot be longer than {} characters", MAX_NOTE_LENGTH),
+            });
+        }
+        if self.content == content {
+            return Ok(false);
+        }
+        self.history.insert(
+            0,
+            NoteRevision {
+                content: std::mem::replace(&mut self.content, content),
+                author_id,
+                author_name,
+                time: now,
+            },
+        );
+        self.history.truncate(MAX_NOTE_REVISIONS);
+        self.last_modified = Some(now);
+        Ok(true)
+    }
+
+    /// Notes an older core kept in a file of their own, the file is removed
+    pub async fn take_legacy_file(path_to_instance: &Path) -> Result<Option<Self>, Error> {
+        let path = path_to_instance.join(LEGACY_FILE_NAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let notes = serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+            .context(format!("Failed to parse notes file at {}", path.display()))?;
+        crate::util::fs::remove_file(&path).await?;
+        Ok(Some(notes))
+    }
+}
+
+#[test]
+fn test_instance_notes() {
+    let author = || UserId::from("owner".to_string());
+    let mut notes = InstanceNotes::default();
+    assert!(notes
+        .edit("# Mods".to_string(), author(), "owner".to_string(), 1)
+        .unwrap());
+    assert!(!notes
+        .edit("# Mods".to_string(), author(), "owner".to_string(), 2)
+        .unwrap());
+    assert!(notes
+        .edit("# Plugins".to_string(), author(), "owner".to_string(), 3)
+        .unwrap());
+    assert_eq!(notes.last_modified, Some(3));
+    assert_eq!(notes.history.len(), 2);
+    assert_eq!(notes.history[0].content, "# Mods");
+
+    // the limit is in characters, not bytes
+    let at_limit = "é".repeat(MAX_NOTE_LENGTH);
+    assert!(notes
+        .edit(at_limit, author(), "owner".to_string(), 4)
+        .is_ok());
+    let too_long = "a".repeat(MAX_NOTE_LENGTH + 1);
+    assert!(notes
+        .edit(too_long, author(), "owner".to_string(), 5)
+        .is_err());
+    assert_eq!(notes.last_modified, Some(4));
+
+    let saved = serde_json::to_string(&notes).unwrap();
+    assert_eq!(
+        serde_json::from_str::<InstanceNotes>(&saved).unwrap(),
+        notes
+    );
+}
+
+#[tokio::test]
+async fn test_take_legacy_notes() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    assert_eq!(
+        InstanceNotes::take_legacy_file(temp_dir.path())
+            .await
+            .unwrap(),
+        None
+    );
+    std::fs::write(
+        temp_dir.path().join(LEGACY_FILE_NAME),
+        r#"{"content":"hello","last_modified":1,"history":[]}"#,
+    )
+    .unwrap();
+    let notes = InstanceNotes::take_legacy_file(temp_dir.path())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(notes.content, "hello");
+    assert!(!temp_dir.path().join(LEGACY_FILE_NAME).exists());
+}
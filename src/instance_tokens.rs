@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use ts_rs::TS;
+
+use crate::db::state::{self, StateStore};
+use crate::error::{Error, ErrorKind};
+use crate::types::InstanceUuid;
+use crate::util::rand_alphanumeric;
+
+/// tokens start with this so they're recognisable in plugin configs and leak scanners
+const TOKEN_PREFIX: &str = "lsi_";
+const MAX_TOKENS: usize = 32;
+/// last use is written at most this often, not on every request
+const LAST_USED_GRANULARITY_SECS: i64 = 60;
+/// where tokens were kept before they moved into the state store
+const LEGACY_FILE_NAME: &str = ".lodestone_api_tokens.json";
+
+lazy_static::lazy_static! {
+    /// One lock per instance, held from loading its tokens until they're saved
+    static ref TOKEN_LOCKS: std::sync::Mutex<HashMap<InstanceUuid, Arc<Mutex<()>>>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// Must be held around every load and save of an instance's tokens, so a save
+/// can't bring back a token revoked since the load
+pub async fn lock_tokens(uuid: &InstanceUuid) -> OwnedMutexGuard<()> {
+    let lock = TOKEN_LOCKS
+        .lock()
+        .unwrap()
+        .entry(uuid.clone())
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum TokenScope {
+    ReadState,
+    ReadPlayers,
+    /// only the commands listed on the token
+    SendCommands,
+    /// push reports as a companion plugin or mod
+    PushTelemetry,
+}
+
+/// A token for a companion plugin or mod of one instance, kept in the state store
+/// where file access to the instance can't plant one. Only a hash of the token is kept.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InstanceApiToken {
+    pub id: String,
+    pub name: String,
+    token_hash: String,
+    pub scopes: Vec<TokenScope>,
+    /// command names the token may send, matched like the console policy
+    pub commands: Vec<String>,
+    pub created_at: i64,
+    pub last_used: Option<i64>,
+}
+
+/// A token as shown on the settings page
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct InstanceApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub commands: Vec<String>,
+    pub created_at: i64,
+    pub last_used: Option<i64>,
+}
+
+impl From<&InstanceApiToken> for InstanceApiTokenInfo {
+    fn from(token: &InstanceApiToken) -> Self {
+        Self {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            scopes: token.scopes.clone(),
+            commands: token.commands.clone(),
+            created_at: token.created_at,
+            last_used: token.last_used,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NewInstanceApiToken {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    #[serde(default)]
+    pub commands: Vec<String>,
+}
+
+impl NewInstanceApiToken {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.name.trim().is_empty() || self.name.len() > 64 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Token name must be between 1 and 64 characters"),
+            });
+        }
+        if self.scopes.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("A token needs at least one scope"),
+            });
+        }
+        let sends_commands = self.scopes.contains(&TokenScope::SendCommands);
+        if sends_commands == self.commands.is_empty() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Commands must be listed exactly when the token may send commands"),
+            });
+        }
+        if self
+            .commands
+            .iter()
+            .any(|command| command.trim().is_empty() || command.contains(char::is_whitespace))
+        {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Commands are listed by name, like \"say\" or \"whitelist\""),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returned once when a token is created, the token can't be shown again
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct CreatedInstanceApiToken {
+    pub token: String,
+    pub info: InstanceApiTokenInfo,
+}
+
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct InstanceApiTokens {
+    pub tokens: Vec<InstanceApiToken>,
+}
+
+impl InstanceApiTokens {
+    /// Tokens of older cores are moved over from the instance directory
+    pub async fn load(
+        store: &StateStore,
+        uuid: &InstanceUuid,
+        path_to_instance: &Path,
+    ) -> Result<Self, Error> {
+        Ok(store
+            .get_instance(
+                state::INSTANCE_API_TOKENS,
+                uuid,
+                &path_to_instance.join(LEGACY_FILE_NAME),
+            )
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn save(&self, store: &StateStore, uuid: &InstanceUuid) -> Result<(), Error> {
+        store
+            .set_instance(state::INSTANCE_API_TOKENS, uuid, self)
+            .await
+    }
+
+    pub fn create(
+        &mut self,
+        new_token: NewInstanceApiToken,
+        now: i64,
+    ) -> Result<CreatedInstanceApiToken, Error> {
+        if self.tokens.len() >= MAX_TOKENS {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("An instance can have at most {} API tokens", MAX_TOKENS),
+            });
+        }
+        let token = format!("{}{}", TOKEN_PREFIX, rand_alphanumeric(40));
+        let api_token = InstanceApiToken {
+            id: rand_alphanumeric(12),
+            name: new_token.name.trim().to_string(),
+            token_hash: hash_token(&token),
+            scopes: new_token.scopes,
+            commands: new_token.commands,
+            created_at: now,
+            last_used: None,
+        };
+        let info = InstanceApiTokenInfo::from(&api_token);
+        self.tokens.push(api_token);
+        Ok(CreatedInstanceApiToken { token, info })
+    }
+
+    pub fn revoke(&mut self, id: &str) -> Result<(), Error> {
+        let count = self.tokens.len();
+        self.tokens.retain(|token| token.id != id);
+        if self.tokens.len() == count {
+            return Err(Error {
+                kind: ErrorKind::NotFound,
+                source: eyre!("API token not found"),
+            });
+        }
+        Ok(())
+    }
+
+    /// The token `token` belongs to, if it has `scope`. The second value is whether
+    /// its last use changed enough to be worth saving.
+    pub fn authenticate(
+        &mut self,
+        token: &str,
+        scope: TokenScope,
+        now: i64,
+    ) -> Result<(InstanceApiToken, bool), Error> {
+        let token_hash = hash_token(token);
+        let api_token = self
+            .tokens
+            .iter_mut()
+            .find(|api_token| api_token.token_hash == token_hash)
+            .ok_or_else(|| Error {
+                kind: ErrorKind::Unauthorized,
+                source: eyre!("Invalid API token"),
+            })?;
+        if !api_token.scopes.contains(&scope) {
+            return Err(Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!("The API token doesn't have the {:?} scope", scope),
+            });
+        }
+        let stale = api_token.last_used.map_or(true, |last_used| {
+            now - last_used >= LAST_USED_GRANULARITY_SECS
+        });
+        if stale {
+            api_token.last_used = Some(now);
+        }
+        Ok((api_token.clone(), stale))
+    }
+}
+
+#[test]
+fn test_instance_api_tokens() {
+    let mut tokens = InstanceApiTokens::default();
+    let new_token = NewInstanceApiToken {
+        name: "Discord bot".to_string(),
+        scopes: vec![TokenScope::ReadPlayers, TokenScope::SendCommands],
+        commands: vec!["say".to_string()],
+    };
+    assert!(new_token.validate().is_ok());
+    let created = tokens.create(new_token, 0).unwrap();
+    assert!(created.token.starts_with(TOKEN_PREFIX));
+    assert!(!serde_json::to_string(&tokens)
+        .unwrap()
+        .contains(&created.token));
+
+    let (token, stale) = tokens
+        .authenticate(&created.token, TokenScope::ReadPlayers, 100)
+        .unwrap();
+    assert_eq!(token.name, "Discord bot");
+    assert!(stale);
+    assert!(
+        !tokens
+            .authenticate(&created.token, TokenScope::ReadPlayers, 130)
+            .unwrap()
+            .1
+    );
+    assert!(tokens
+        .authenticate(&created.token, TokenScope::ReadState, 100)
+        .is_err());
+    assert!(tokens
+        .authenticate("lsi_wrong", TokenScope::ReadPlayers, 100)
+        .is_err());
+
+    tokens.revoke(&created.info.id).unwrap();
+    assert!(tokens
+        .authenticate(&created.token, TokenScope::ReadPlayers, 100)
+        .is_err());
+
+    assert!(NewInstanceApiToken {
+        name: "no commands".to_string(),
+        scopes: vec![TokenScope::SendCommands],
+        commands: Vec::new(),
+    }
+    .validate()
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_lock_tokens() {
+    let uuid = InstanceUuid::from("locked".to_string());
+    let guard = lock_tokens(&uuid).await;
+    // a second update of the same instance waits for the first
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(50), lock_tokens(&uuid))
+            .await
+            .is_err()
+    );
+    assert!(tokio::time::timeout(
+        std::time::Duration::from_millis(50),
+        lock_tokens(&InstanceUuid::from("other".to_string()))
+    )
+    .await
+    .is_ok());
+    drop(guard);
+    lock_tokens(&uuid).await;
+}
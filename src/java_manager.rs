@@ -0,0 +1,402 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::implementations::minecraft::util::get_jre_url_for_major;
+use crate::prelude::path_to_binaries;
+use crate::util::{download_file, unzip_file_async, DownloadProgress, UnzipOption};
+
+/// the runtimes Mojang ships with the launcher, by platform and component
+const MOJANG_RUNTIMES_URL: &str = "https://launchermeta.mojang.com/v1/products/java-runtime/2ec0cc96c44e5a76b9c8b7c39df7210883d12871/all.json";
+
+/// how long `java -version` may take before the runtime is skipped
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// one download at a time, two instances needing the same runtime would
+    /// otherwise both unpack it into the same directory
+    static ref DOWNLOAD_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum RuntimeVendor {
+    /// Eclipse Temurin from Adoptium
+    Temurin,
+    /// the runtimes of the Minecraft launcher
+    Mojang,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct JavaRuntime {
+    /// the `java` executable
+    pub path: String,
+    /// as printed by `java -version`, e.g. `17.0.9` or `1.8.0_392`
+    pub version: String,
+    pub major: u64,
+    /// downloaded by Lodestone rather than installed on the host
+    pub managed: bool,
+}
+
+pub fn path_to_managed_runtimes() -> PathBuf {
+    path_to_binaries().join("java")
+}
+
+/// The `java` executable of a runtime. macOS runtimes are bundles, the Mojang
+/// ones one level deeper.
+pub fn java_in(home: &Path) -> PathBuf {
+    let default = if std::env::consts::OS == "macos" {
+        "Contents/Home/bin"
+    } else {
+        "bin"
+    };
+    [default, "bin", "jre.bundle/Contents/Home/bin"]
+        .iter()
+        .map(|bin| home.join(bin))
+        .find(|bin| bin.join("java").is_file() || bin.join("java.exe").is_file())
+        .unwrap_or_else(|| home.join(default))
+        .join("java")
+}
+
+/// Where the runtime for Java `major` is downloaded to
+pub fn managed_java(major: u64) -> PathBuf {
+    java_in(&path_to_managed_runtimes().join(format!("jre{}", major)))
+}
+
+pub fn is_managed(java: &Path) -> bool {
+    java.starts_with(path_to_managed_runtimes())
+}
+
+/// The major version of a Java version string, `1.8.0_392` and `8u392` are both 8
+fn major_of(version: &str) -> Option<u64> {
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    match parts.next()?.parse().ok()? {
+        1 => parts.next()?.parse().ok(),
+        major => Some(major),
+    }
+}
+
+/// The version and its major from the output of `java -version`
+fn parse_java_version(output: &str) -> Option<(String, u64)> {
+    let line = output.lines().find(|line| line.contains(" version \""))?;
+    let version = line.split('"').nth(1)?;
+    Some((version.to_string(), major_of(version)?))
+}
+
+/// Runs `java -version` to find out which Java the executable is
+pub async fn probe(java: &Path) -> Result<JavaRuntime, Error> {
+    let not_java = || Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("{} is not a Java runtime", java.display()),
+    };
+    let output = tokio::time::timeout(
+        PROBE_TIMEOUT,
+        tokio::process::Command::new(java)
+            .arg("-version")
+            .stdin(Stdio::null())
+            .output(),
+    )
+    .await
+    .map_err(|_| not_java())?
+    .map_err(|_| not_java())?;
+    // the version goes to stderr, except for a few vendors
+    let (version, major) = parse_java_version(&String::from_utf8_lossy(&output.stderr))
+        .or_else(|| parse_java_version(&String::from_utf8_lossy(&output.stdout)))
+        .ok_or_else(not_java)?;
+    Ok(JavaRuntime {
+        path: java.to_string_lossy().to_string(),
+        version,
+        major,
+        managed: is_managed(java),
+    })
+}
+
+/// Directories holding one runtime per entry on the usual install locations
+fn system_runtime_dirs() -> Vec<PathBuf> {
+    let dirs: &[&str] = match std::env::consts::OS {
+        "linux" => &["/usr/lib/jvm", "/usr/java", "/opt/java"],
+        "macos" => &["/Library/Java/JavaVirtualMachines"],
+        "windows" => &[
+            "C:\\Program Files\\Java",
+            "C:\\Program Files\\Eclipse Adoptium",
+            "C:\\Program Files\\Microsoft",
+            "C:\\Program Files\\Zulu",
+        ],
+        _ => &[],
+    };
+    dirs.iter().map(PathBuf::from).collect()
+}
+
+/// The runtimes Lodestone downloaded and the ones found through `JAVA_HOME`,
+/// `PATH` and the usual install locations, newest Java first
+pub async fn detect_runtimes() -> Vec<JavaRuntime> {
+    let mut homes = Vec::new();
+    for dir in std::iter::once(path_to_managed_runtimes()).chain(system_runtime_dirs()) {
+        if dir.is_dir() {
+            homes.extend(
+                crate::util::list_dir(&dir, Some(true))
+                    .await
+                    .unwrap_or_default(),
+            );
+        }
+    }
+    if let Some(java_home) = std::env::var_os("JAVA_HOME") {
+        homes.push(PathBuf::from(java_home));
+    }
+    let mut candidates: Vec<PathBuf> = homes.iter().map(|home| java_in(home)).collect();
+    if let Some(path) = std::env::var_os("PATH") {
+        candidates.extend(std::env::split_paths(&path).map(|dir| dir.join("java")));
+    }
+    let mut seen = HashSet::new();
+    let mut runtimes = Vec::new();
+    for java in candidates {
+        let resolved = if cfg!(windows) {
+            java.with_extension("exe")
+        } else {
+            java.clone()
+        };
+        // `/usr/bin/java` is usually a link to one of the others
+        match std::fs::canonicalize(&resolved) {
+            Ok(canonical) if canonical.is_file() => {
+                if !seen.insert(canonical) {
+                    continue;
+                }
+            }
+            _ => continue,
+        }
+        match probe(&java).await {
+            Ok(runtime) => runtimes.push(runtime),
+            Err(e) => warn!("Skipping {}: {}", java.display(), e),
+        }
+    }
+    runtimes.sort_by(|a, b| b.major.cmp(&a.major).then_with(|| a.path.cmp(&b.path)));
+    runtimes
+}
+
+/// Downloads the runtime for Java `major` unless it already is, from Temurin if
+/// Adoptium builds it for this host and from Mojang otherwise. Returns its
+/// `java` executable.
+pub async fn ensure_runtime(
+    major: u64,
+    vendor: Option<RuntimeVendor>,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<PathBuf, Error> {
+    let _lock = DOWNLOAD_LOCK.lock().await;
+    let java = managed_java(major);
+    if java.is_file() {
+        return Ok(java);
+    }
+    let vendor = vendor.unwrap_or_else(|| match get_jre_url_for_major(major) {
+        Some(_) => RuntimeVendor::Temurin,
+        None => RuntimeVendor::Mojang,
+    });
+    let home = path_to_managed_runtimes().join(format!("jre{}", major));
+    crate::util::fs::create_dir_all(path_to_managed_runtimes()).await?;
+    info!("Downloading Java {} from {:?}", major, vendor);
+    match vendor {
+        RuntimeVendor::Temurin => download_temurin(major, &home, on_download).await?,
+        RuntimeVendor::Mojang => download_mojang(major, &home, on_download).await?,
+    }
+    Ok(managed_java(major))
+}
+
+async fn download_temurin(
+    major: u64,
+    home: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<(), Error> {
+    let (url, _) = get_jre_url_for_major(major).ok_or_else(|| Error {
+        kind: ErrorKind::UnsupportedOperation,
+        source: eyre!("Adoptium has no Java {} for this host", major),
+    })?;
+    let downloaded =
+        download_file(&url, &path_to_managed_runtimes(), None, on_download, true).await?;
+    let unzipped_content =
+        unzip_file_async(&downloaded, UnzipOption::ToDir(path_to_managed_runtimes())).await?;
+    crate::util::fs::remove_file(&downloaded).await?;
+    if unzipped_content.len() != 1 {
+        return Err(eyre!(
+            "Expected only one file in the JRE archive, got {}",
+            unzipped_content.len()
+        )
+        .into());
+    }
+    crate::util::fs::rename(unzipped_content.iter().last().unwrap(), home).await
+}
+
+/// Mojang's name for this host
+fn mojang_platform() -> Option<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => Some("linux"),
+        ("linux", "x86") => Some("linux-i386"),
+        ("macos", "x86_64") => Some("mac-os"),
+        ("macos", "aarch64") => Some("mac-os-arm64"),
+        ("windows", "x86_64") => Some("windows-x64"),
+        ("windows", "x86") => Some("windows-x86"),
+        ("windows", "aarch64") => Some("windows-arm64"),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+struct MojangRuntimeFile {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    executable: bool,
+    downloads: Option<MojangRuntimeDownloads>,
+    target: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MojangRuntimeDownloads {
+    raw: MojangRuntimeDownload,
+}
+
+#[derive(Deserialize)]
+struct MojangRuntimeDownload {
+    url: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct MojangRuntimeManifest {
+    files: std::collections::BTreeMap<String, MojangRuntimeFile>,
+}
+
+/// The manifest URL of the Mojang runtime for Java `major` on `platform`
+fn mojang_manifest_url(all: &serde_json::Value, platform: &str, major: u64) -> Option<String> {
+    all.get(platform)?
+        .as_object()?
+        .values()
+        .filter_map(|builds| builds.as_array()?.first())
+        .find(|build| {
+            build
+                .pointer("/version/name")
+                .and_then(|name| name.as_str())
+                .and_then(major_of)
+                == Some(major)
+        })?
+        .pointer("/manifest/url")?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Mojang's runtimes are lists of files rather than archives. They are fetched
+/// into a directory next to `home` that is only moved in place once complete.
+async fn download_mojang(
+    major: u64,
+    home: &Path,
+    on_download: &(dyn Fn(DownloadProgress) + Send + Sync),
+) -> Result<(), Error> {
+    let platform = mojang_platform().ok_or_else(|| Error {
+        kind: ErrorKind::UnsupportedOperation,
+        source: eyre!("Mojang has no Java runtimes for this host"),
+    })?;
+    let client = reqwest::Client::new();
+    let all: serde_json::Value = client
+        .get(MOJANG_RUNTIMES_URL)
+        .send()
+        .await
+        .context("Failed to fetch Mojang's Java runtimes")?
+        .json()
+        .await
+        .context("Failed to parse Mojang's Java runtimes")?;
+    let manifest_url = mojang_manifest_url(&all, platform, major).ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("Mojang has no Java {} for this host", major),
+    })?;
+    let manifest: MojangRuntimeManifest = client
+        .get(&manifest_url)
+        .send()
+        .await
+        .context("Failed to fetch the Java runtime manifest")?
+        .json()
+        .await
+        .context("Failed to parse the Java runtime manifest")?;
+    let partial = home.with_extension("partial");
+    if partial.exists() {
+        crate::util::fs::remove_dir_all(&partial).await?;
+    }
+    let total = manifest
+        .files
+        .values()
+        .filter_map(|file| file.downloads.as_ref())
+        .map(|downloads| downloads.raw.size)
+        .sum();
+    let mut downloaded = 0;
+    for (name, file) in &manifest.files {
+        let path = partial.join(name);
+        match (file.kind.as_str(), &file.downloads, &file.target) {
+            ("directory", _, _) => crate::util::fs::create_dir_all(&path).await?,
+            ("file", Some(downloads), _) => {
+                if let Some(parent) = path.parent() {
+                    crate::util::fs::create_dir_all(parent).await?;
+                }
+                let bytes = client
+                    .get(&downloads.raw.url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .context(format!("Failed to download {}", name))?
+                    .bytes()
+                    .await
+                    .context(format!("Failed to download {}", name))?;
+                crate::util::fs::write_all(&path, bytes).await?;
+                downloaded += downloads.raw.size;
+                on_download(DownloadProgress {
+                    total: Some(total),
+                    downloaded,
+                    step: downloads.raw.size,
+                    download_name: name.clone(),
+                });
+                #[cfg(unix)]
+                if file.executable {
+                    use std::os::unix::fs::PermissionsExt;
+                    tokio::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+                        .await
+                        .context(format!("Failed to make {} executable", path.display()))?;
+                }
+            }
+            #[cfg(unix)]
+            ("link", _, Some(target)) => {
+                if let Some(parent) = path.parent() {
+                    crate::util::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::symlink(target, &path)
+                    .await
+                    .context(format!("Failed to link {}", path.display()))?;
+            }
+            _ => {}
+        }
+    }
+    crate::util::fs::rename(&partial, home).await
+}
+
+#[test]
+fn test_parse_java_version() {
+    assert_eq!(
+        parse_java_version("openjdk version \"17.0.9\" 2023-10-17\nOpenJDK Runtime Environment Temurin-17.0.9+9 (build 17.0.9+9)"),
+        Some(("17.0.9".to_string(), 17))
+    );
+    assert_eq!(
+        parse_java_version("java version \"1.8.0_392\"\nJava(TM) SE Runtime Environment"),
+        Some(("1.8.0_392".to_string(), 8))
+    );
+    assert_eq!(
+        parse_java_version("openjdk version \"21\" 2023-09-19"),
+        Some(("21".to_string(), 21))
+    );
+    assert_eq!(parse_java_version("command not found"), None);
+    assert_eq!(major_of("8u51"), Some(8));
+}
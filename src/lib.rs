@@ -8,41 +8,85 @@ use crate::prelude::{
 use crate::traits::t_configurable::GameType;
 use crate::traits::t_server::State;
 use crate::{
-    db::write::write_event_to_db_task,
+    db::{
+        metrics::MetricsStore,
+        state::{self, StateStore},
+        write::write_event_to_db_task,
+    },
     global_settings::GlobalSettingsData,
     handlers::{
-        checks::get_checks_routes, core_info::get_core_info_routes, events::get_events_routes,
-        gateway::get_gateway_routes, global_fs::get_global_fs_routes,
+        alerts::get_alerts_routes, badges::get_badges_routes, checks::get_checks_routes,
+        core_info::get_core_info_routes, database_hosts::get_database_hosts_routes,
+        events::get_events_routes, gateway::get_gateway_routes,
+        global_bans::get_global_bans_routes, global_fs::get_global_fs_routes,
         global_settings::get_global_settings_routes, instance::*,
-        instance_config::get_instance_config_routes, instance_fs::get_instance_fs_routes,
-        instance_macro::get_instance_macro_routes, instance_players::get_instance_players_routes,
+        instance_api_tokens::get_instance_api_tokens_routes,
+        instance_backups::get_instance_backups_routes,
+        instance_changelog::get_instance_changelog_routes, instance_chat::get_instance_chat_routes,
+        instance_config::get_instance_config_routes, instance_console::get_instance_console_routes,
+        instance_crashes::get_instance_crashes_routes,
+        instance_databases::get_instance_databases_routes,
+        instance_definitions::get_instance_definitions_routes, instance_fs::get_instance_fs_routes,
+        instance_ip_access::get_instance_ip_access_routes,
+        instance_log_triggers::get_instance_log_triggers_routes,
+        instance_macro::get_instance_macro_routes,
+        instance_migration::get_instance_migration_routes, instance_mods::get_instance_mods_routes,
+        instance_notes::get_instance_notes_routes, instance_players::get_instance_players_routes,
+        instance_proxy::get_instance_proxy_routes,
+        instance_resource_pack::get_instance_resource_pack_routes,
         instance_server::get_instance_server_routes,
-        instance_setup_configs::get_instance_setup_config_routes, monitor::get_monitor_routes,
-        setup::get_setup_route, system::get_system_routes, users::get_user_routes,
+        instance_setup_configs::get_instance_setup_config_routes,
+        instance_votes::get_instance_votes_routes,
+        instance_vpn_check::get_instance_vpn_check_routes,
+        instance_web_map::get_instance_web_map_routes, instance_world::get_instance_world_routes,
+        java_runtimes::get_java_runtimes_routes, monitor::get_monitor_routes,
+        players::get_players_routes, presets::get_presets_routes,
+        scheduled_tasks::get_scheduled_tasks_routes, setup::get_setup_route,
+        system::get_system_routes, users::get_user_routes, webhooks::get_webhooks_routes,
     },
     util::rand_alphanumeric,
 };
 
+use afk::PlayerActivity;
+use alerts::AlertsManager;
 use auth::user::UsersManager;
 use axum::Router;
 
 use axum_server::tls_rustls::RustlsConfig;
+use backup::scheduler::BackupScheduler;
+use badges::BadgeCache;
+use ban_list::BanListManager;
 use clap::Parser;
 use color_eyre::eyre::Context;
 use color_eyre::Report;
+use companion::CompanionReports;
 use error::Error;
-use events::{CausedBy, Event};
+use events::webhooks::WebhooksManager;
+use events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use external_db::{
+    dump::dump_instance_databases, provision::DatabaseHostsManager, InstanceDatabases,
+};
+use firewall::{FirewallManager, IpAccessList};
 use futures::Future;
 use global_settings::GlobalSettings;
 use implementations::{generic, minecraft};
 use macro_executor::MacroExecutor;
+use player_positions::PlayerPositions;
 use port_manager::PortManager;
 use prelude::GameInstance;
+use prometheus::EventCounts;
+use request_metrics::RequestMetrics;
 use reqwest::{header, Method};
 use ringbuffer::{AllocRingBuffer, RingBufferWrite};
+use scheduler::SchedulerManager;
+use self_check::{self_check, CoreIssue};
 
 use semver::Version;
-use sqlx::{sqlite::SqliteConnectOptions, Pool};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode},
+    Pool,
+};
+use start_queue::StartQueue;
 use std::{
     collections::{HashMap, HashSet},
     net::SocketAddr,
@@ -52,6 +96,7 @@ use std::{
     time::Duration,
 };
 use sysinfo::{CpuExt, SystemExt};
+use tick_monitor::TickMonitor;
 use tokio::{
     select,
     sync::{broadcast::error::RecvError, Mutex, RwLock},
@@ -60,34 +105,85 @@ use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
 };
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter};
 use traits::{t_configurable::TConfigurable, t_server::MonitorReport, t_server::TServer};
 use types::{DotLodestoneConfig, InstanceUuid};
 use uuid::Uuid;
+use ws_sessions::WsSessions;
+mod afk;
+mod alerts;
 pub mod auth;
+mod backup;
+mod badges;
+mod ban_list;
+mod cgroup;
+mod changelog;
+mod chat_archive;
+mod chat_filter;
+pub mod cli;
+mod command_template;
+mod companion;
+mod console_history;
+mod console_log;
+mod console_policy;
+mod crash_loop;
+mod crash_reports;
 pub mod db;
 mod deno_ops;
+mod discord_bridge;
+mod email;
 pub mod error;
 mod event_broadcaster;
 mod events;
+mod external_db;
+mod firewall;
+mod flood_detection;
+mod geoip;
 pub mod global_settings;
 mod handlers;
+mod host;
 pub mod implementations;
+mod instance_migration;
+mod instance_notes;
+mod instance_tokens;
+mod java_manager;
+mod log_triggers;
 pub mod macro_executor;
+mod maintenance;
+mod metrics_history;
 mod migration;
 mod output_types;
+mod player_positions;
+mod player_sessions;
+mod playtime_ranks;
 mod port_manager;
 pub mod prelude;
+mod prometheus;
+mod request_metrics;
+mod reserved_slots;
+mod scheduler;
+mod self_check;
+mod service;
+mod start_queue;
+mod startup_triage;
 pub mod tauri_export;
+mod telemetry;
+mod tick_monitor;
 mod traits;
 pub mod types;
+mod uptime;
 pub mod util;
+mod votifier;
+mod vpn_detection;
+mod web_map;
+mod ws_sessions;
 
 #[derive(Clone)]
 pub struct AppState {
     instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    backup_scheduler: Arc<Mutex<BackupScheduler>>,
     users_manager: Arc<RwLock<UsersManager>>,
     events_buffer: Arc<Mutex<AllocRingBuffer<Event>>>,
     console_out_buffer: Arc<Mutex<HashMap<InstanceUuid, AllocRingBuffer<Event>>>>,
@@ -102,6 +198,26 @@ pub struct AppState {
     download_urls: Arc<Mutex<HashMap<String, PathBuf>>>,
     macro_executor: MacroExecutor,
     sqlite_pool: sqlx::SqlitePool,
+    state_store: StateStore,
+    metrics_store: MetricsStore,
+    database_hosts: Arc<Mutex<DatabaseHostsManager>>,
+    web_map_sessions: Arc<Mutex<HashMap<String, (InstanceUuid, i64)>>>,
+    firewall: Arc<Mutex<FirewallManager>>,
+    ban_list: Arc<Mutex<BanListManager>>,
+    alerts: Arc<Mutex<AlertsManager>>,
+    webhooks: Arc<Mutex<WebhooksManager>>,
+    scheduler: Arc<Mutex<SchedulerManager>>,
+    player_positions: Arc<Mutex<PlayerPositions>>,
+    player_activity: Arc<Mutex<PlayerActivity>>,
+    request_metrics: Arc<Mutex<RequestMetrics>>,
+    event_counts: Arc<Mutex<EventCounts>>,
+    badge_cache: Arc<Mutex<BadgeCache>>,
+    companion_reports: Arc<Mutex<CompanionReports>>,
+    tick_monitor: Arc<Mutex<TickMonitor>>,
+    ws_sessions: Arc<Mutex<WsSessions>>,
+    start_queue: Arc<Mutex<StartQueue>>,
+    /// found by the self check on startup
+    core_issues: Arc<Vec<CoreIssue>>,
 }
 async fn restore_instances(
     instances_path: &Path,
@@ -160,7 +276,7 @@ async fn restore_instances(
     Ok(ret)
 }
 
-fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
+fn setup_tracing(otlp_endpoint: Option<String>) -> tracing_appender::non_blocking::WorkerGuard {
     let file_appender =
         tracing_appender::rolling::hourly(lodestone_path().join("log"), "lodestone_core.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
@@ -180,7 +296,8 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
             .with_thread_ids(false)
             // Don't display the event's target (module path)
             .with_target(true)
-            .with_writer(std::io::stdout);
+            .with_writer(std::io::stdout)
+            .with_filter(EnvFilter::from("lodestone_core=debug"));
         let fmt_layer_file = tracing_subscriber::fmt::layer()
             // Use a more compact, abbreviated log format
             .compact()
@@ -193,12 +310,13 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
             // Don't display the event's target (module path)
             .with_target(true)
             .with_ansi(false)
-            .with_writer(non_blocking);
+            .with_writer(non_blocking)
+            .with_filter(EnvFilter::from("lodestone_core=debug"));
 
         tracing_subscriber::registry()
+            .with(telemetry::otlp_layer(otlp_endpoint))
             .with(fmt_layer_stdout)
             .with(fmt_layer_file)
-            .with(EnvFilter::from("lodestone_core=debug"))
             .init();
     }
 
@@ -235,6 +353,7 @@ fn setup_tracing() -> tracing_appender::non_blocking::WorkerGuard {
 
         tracing_subscriber::registry()
             // .with(ErrorLayer::default())
+            .with(telemetry::otlp_layer(otlp_endpoint))
             .with(fmt_layer_stdout)
             .with(fmt_layer_file)
             .init();
@@ -256,7 +375,17 @@ fn output_sys_info() {
         .first()
         .map_or_else(|| "Unknown CPU", |v| v.brand());
     let ram = sys.total_memory();
+    let host = host::HostInfo::detect();
     info!("  CPU: {cpu_name}");
+    info!(
+        "  Arch: {}{}",
+        host.arch,
+        if host.low_power {
+            ", low power host, new instances get smaller defaults"
+        } else {
+            ""
+        }
+    );
     info!(
         "  RAM: {ram} bytes ({ram_gb} GB)",
         ram = ram,
@@ -322,19 +451,18 @@ pub struct Args {
     pub is_desktop: bool,
     #[arg(short, long)]
     pub lodestone_path: Option<PathBuf>,
+    /// OTLP collector to export traces to, e.g. http://localhost:4317
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    /// Runs a command against the core, or its data directory when it's stopped,
+    /// instead of starting it
+    #[command(subcommand)]
+    pub command: Option<cli::Command>,
 }
 
-pub async fn run(
-    args: Args,
-) -> (
-    impl Future<Output = ()>,
-    AppState,
-    tracing_appender::non_blocking::WorkerGuard,
-) {
-    let _ = color_eyre::install().map_err(|e| {
-        error!("Failed to install color_eyre: {}", e);
-    });
-    let lodestone_path_ = if let Some(path) = args.lodestone_path {
+/// The `--lodestone-path` argument, else `LODESTONE_PATH`, else `~/.lodestone`
+pub fn resolve_lodestone_path(path: Option<PathBuf>) -> PathBuf {
+    if let Some(path) = path {
         path
     } else {
         PathBuf::from(match std::env::var("LODESTONE_PATH") {
@@ -348,12 +476,27 @@ pub async fn run(
                 .unwrap()
                 .to_string(),
         })
-    };
-    init_paths(lodestone_path_);
+    }
+}
+
+pub async fn run(
+    args: Args,
+) -> (
+    impl Future<Output = ()>,
+    AppState,
+    tracing_appender::non_blocking::WorkerGuard,
+) {
+    let _ = color_eyre::install().map_err(|e| {
+        error!("Failed to install color_eyre: {}", e);
+    });
+    init_paths(resolve_lodestone_path(args.lodestone_path));
     let lodestone_path = lodestone_path();
     info!("Lodestone path: {}", lodestone_path.display());
     std::env::set_current_dir(lodestone_path).unwrap();
-    let guard = setup_tracing();
+    let guard = setup_tracing(
+        args.otlp_endpoint
+            .or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()),
+    );
     if args.is_desktop {
         info!("Lodestone Core running in Tauri");
     }
@@ -371,18 +514,81 @@ pub async fn run(
 
     let (tx, _rx) = EventBroadcaster::new(512);
 
-    let mut users_manager = UsersManager::new(tx.clone(), HashMap::new(), path_to_users().clone());
+    // WAL so the event writer and the state store don't block each other's readers
+    let sqlite_pool = Pool::connect_with(
+        SqliteConnectOptions::from_str(&format!("sqlite://{}/data.db", path_to_stores().display()))
+            .unwrap()
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal),
+    )
+    .await
+    .unwrap();
+    let state_store = StateStore::new(sqlite_pool.clone()).await.unwrap();
+    state::init_state_store(state_store.clone());
+    let metrics_store = MetricsStore::new(sqlite_pool.clone()).await.unwrap();
+    let users_location = state_store
+        .location(state::USERS, path_to_users().clone())
+        .await;
+    let global_settings_location = state_store
+        .location(state::GLOBAL_SETTINGS, path_to_global_settings().clone())
+        .await;
+    let database_hosts_location = state_store
+        .location(
+            state::DATABASE_HOSTS,
+            path_to_stores().join("database_hosts.json"),
+        )
+        .await;
+    let ban_list_location = state_store
+        .location(
+            state::GLOBAL_BANS,
+            path_to_stores().join("global_bans.json"),
+        )
+        .await;
+    if let Err(e) = state_store.migrate_documents().await {
+        error!("Failed to migrate the state store: {}", e);
+    }
+
+    let mut users_manager = UsersManager::new(tx.clone(), HashMap::new(), users_location);
 
     users_manager.load_users().await.unwrap();
 
     let mut global_settings = GlobalSettings::new(
-        path_to_global_settings().clone(),
+        global_settings_location,
         tx.clone(),
         GlobalSettingsData::default(),
     );
 
     global_settings.load_from_file().await.unwrap();
 
+    let mut database_hosts = DatabaseHostsManager::new(database_hosts_location);
+
+    database_hosts.load_from_file().await.unwrap();
+
+    let mut ban_list = BanListManager::new(ban_list_location);
+
+    ban_list.load_from_file().await.unwrap();
+
+    let mut alerts = AlertsManager::new(state::StateLocation::Store {
+        store: state_store.clone(),
+        name: state::ALERTS,
+    });
+
+    alerts.load_from_file().await.unwrap();
+
+    let mut webhooks = WebhooksManager::new(state::StateLocation::Store {
+        store: state_store.clone(),
+        name: state::WEBHOOKS,
+    });
+
+    webhooks.load_from_file().await.unwrap();
+
+    let mut scheduler = SchedulerManager::new(state::StateLocation::Store {
+        store: state_store.clone(),
+        name: state::SCHEDULED_TASKS,
+    });
+
+    scheduler.load_from_file().await.unwrap();
+
     let first_time_setup_key = if !users_manager.as_ref().iter().any(|(_, user)| user.is_owner) {
         let key = rand_alphanumeric(16);
         // log the first time setup key in green so it's easy to find
@@ -400,7 +606,7 @@ pub async fn run(
         None
     };
     let macro_executor = MacroExecutor::new(tx.clone());
-    let mut instances = restore_instances(&path_to_instances, tx.clone(), macro_executor.clone())
+    let instances = restore_instances(&path_to_instances, tx.clone(), macro_executor.clone())
         .await
         .map_err(|e| {
             error!(
@@ -409,24 +615,23 @@ pub async fn run(
             );
         })
         .unwrap();
-    for (_, instance) in instances.iter_mut() {
-        if instance.auto_start().await {
-            info!("Auto starting instance {}", instance.name().await);
-            if let Err(e) = instance.start(CausedBy::System, false).await {
-                error!(
-                    "Failed to start instance {}: {:?}",
-                    instance.name().await,
-                    e
-                );
-            }
-        }
-    }
     let mut allocated_ports = HashSet::new();
     for (_, instance) in instances.iter() {
         allocated_ports.insert(instance.port().await);
     }
+    let mut port_manager = PortManager::new(allocated_ports);
+    let instance_registry = state_store.instance_registry().await.unwrap_or_else(|e| {
+        error!("Failed to read the instance registry: {}", e);
+        HashMap::new()
+    });
+    // before auto start, so no job can be using the temporary files it cleans up
+    let core_issues = self_check(&instances, &instance_registry, &mut port_manager).await;
+    if let Err(e) = state_store.record_instances(&instances).await {
+        error!("Failed to record the instances: {}", e);
+    }
     let shared_state = AppState {
         instances: Arc::new(Mutex::new(instances)),
+        backup_scheduler: Arc::new(Mutex::new(BackupScheduler::default())),
         users_manager: Arc::new(RwLock::new(users_manager)),
         events_buffer: Arc::new(Mutex::new(AllocRingBuffer::with_capacity(512))),
         console_out_buffer: Arc::new(Mutex::new(HashMap::new())),
@@ -434,24 +639,40 @@ pub async fn run(
         event_broadcaster: tx.clone(),
         uuid: Uuid::new_v4().to_string(),
         up_since: chrono::Utc::now().timestamp(),
-        port_manager: Arc::new(Mutex::new(PortManager::new(allocated_ports))),
+        port_manager: Arc::new(Mutex::new(port_manager)),
         first_time_setup_key: Arc::new(Mutex::new(first_time_setup_key)),
         system: Arc::new(Mutex::new(sysinfo::System::new_all())),
         download_urls: Arc::new(Mutex::new(HashMap::new())),
         global_settings: Arc::new(Mutex::new(global_settings)),
         macro_executor,
-        sqlite_pool: Pool::connect_with(
-            SqliteConnectOptions::from_str(&format!(
-                "sqlite://{}/data.db",
-                path_to_stores().display()
-            ))
-            .unwrap()
-            .create_if_missing(true),
-        )
-        .await
-        .unwrap(),
+        sqlite_pool,
+        state_store,
+        metrics_store,
+        database_hosts: Arc::new(Mutex::new(database_hosts)),
+        web_map_sessions: Arc::new(Mutex::new(HashMap::new())),
+        firewall: Arc::new(Mutex::new(FirewallManager::default())),
+        ban_list: Arc::new(Mutex::new(ban_list)),
+        alerts: Arc::new(Mutex::new(alerts)),
+        webhooks: Arc::new(Mutex::new(webhooks)),
+        scheduler: Arc::new(Mutex::new(scheduler)),
+        player_positions: Arc::new(Mutex::new(HashMap::new())),
+        player_activity: Arc::new(Mutex::new(HashMap::new())),
+        request_metrics: Arc::new(Mutex::new(RequestMetrics::default())),
+        event_counts: Arc::new(Mutex::new(EventCounts::default())),
+        badge_cache: Arc::new(Mutex::new(BadgeCache::default())),
+        companion_reports: Arc::new(Mutex::new(CompanionReports::default())),
+        tick_monitor: Arc::new(Mutex::new(TickMonitor::default())),
+        ws_sessions: Arc::new(Mutex::new(WsSessions::default())),
+        start_queue: Arc::new(Mutex::new(StartQueue::default())),
+        core_issues: Arc::new(core_issues),
     };
 
+    // bans may have been added or lifted while the core was down
+    {
+        let bans = shared_state.ban_list.lock().await.list();
+        ban_list::apply_bans(&shared_state.instances, &bans).await;
+    }
+
     let event_buffer_task = {
         let event_buffer = shared_state.events_buffer.clone();
         let console_out_buffer = shared_state.console_out_buffer.clone();
@@ -508,6 +729,275 @@ pub async fn run(
         }
     };
 
+    let proxy_links_task = {
+        let instances = shared_state.instances.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(10));
+            loop {
+                interval.tick().await;
+                let instances = instances.lock().await.clone();
+                minecraft::proxy::sync_proxy_links(&instances).await;
+            }
+        }
+    };
+
+    let world_reset_task = {
+        let instances = shared_state.instances.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let now = chrono::Utc::now().timestamp();
+                let minecraft_instances: Vec<minecraft::MinecraftInstance> = instances
+                    .lock()
+                    .await
+                    .values()
+                    .filter_map(|instance| match instance {
+                        GameInstance::MinecraftInstance(i) => Some(i.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                for mut instance in minecraft_instances {
+                    match instance.world_reset_policy().await {
+                        Ok(policy) if policy.is_due(now) => {
+                            info!(
+                                "Running scheduled world reset for {}",
+                                instance.name().await
+                            );
+                            let span = info_span!(
+                                "scheduled_world_reset",
+                                instance = %instance.uuid().await
+                            );
+                            tokio::spawn(
+                                async move {
+                                    let _ = instance.reset_world(CausedBy::System).await;
+                                }
+                                .instrument(span),
+                            );
+                        }
+                        Ok(_) => {}
+                        Err(e) => error!(
+                            "Failed to read world reset policy of {}: {}",
+                            instance.name().await,
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    };
+
+    let firewall_task = {
+        let instances = shared_state.instances.clone();
+        let global_settings = shared_state.global_settings.clone();
+        let firewall = shared_state.firewall.clone();
+        let event_broadcaster = shared_state.event_broadcaster.clone();
+        let mut event_receiver = tx.subscribe();
+        async move {
+            loop {
+                let event = match event_receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Firewall task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let (uuid, name, to) = match event.event_inner {
+                    EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid,
+                        instance_name,
+                        instance_event_inner: InstanceEventInner::StateTransition { to },
+                    }) => (instance_uuid, instance_name, to),
+                    _ => continue,
+                };
+                let result = match to {
+                    State::Starting => {
+                        let (port, path) = match instances.lock().await.get(&uuid) {
+                            Some(instance) => (instance.port().await, instance.path().await),
+                            None => continue,
+                        };
+                        let settings = global_settings.lock().await.firewall();
+                        match IpAccessList::load(&path).await {
+                            Ok(access) => {
+                                firewall
+                                    .lock()
+                                    .await
+                                    .open_instance_port(&uuid, port, &settings, &access)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    State::Stopped | State::Error => {
+                        firewall.lock().await.close_instance_port(&uuid).await
+                    }
+                    _ => continue,
+                };
+                match result {
+                    Ok(Some(message)) => {
+                        event_broadcaster.send(Event::new_system_message(uuid, name, message))
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Failed to update firewall for instance {}: {}", uuid, e);
+                        event_broadcaster.send(Event::new_system_message(
+                            uuid,
+                            name,
+                            format!("Failed to update firewall: {}", e),
+                        ));
+                    }
+                }
+            }
+        }
+    };
+
+    let flood_detection_task = flood_detection::flood_detection_task(
+        shared_state.instances.clone(),
+        shared_state.global_settings.clone(),
+        shared_state.firewall.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let player_session_task = player_sessions::player_session_task(
+        shared_state.instances.clone(),
+        shared_state.global_settings.clone(),
+        shared_state.ban_list.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let metrics_history_task = metrics_history::metrics_history_task(shared_state.clone());
+    let tick_monitor_task = tick_monitor::tick_monitor_task(shared_state.clone());
+    let alerts_task = alerts::alerts_task(shared_state.clone());
+    let restart_on_crash_task = crash_loop::restart_on_crash_task(shared_state.clone());
+    let crash_reports_task = crash_reports::crash_reports_task(shared_state.clone());
+    let startup_triage_task = startup_triage::startup_triage_task(shared_state.clone());
+
+    let event_count_task = prometheus::event_count_task(
+        shared_state.event_broadcaster.clone(),
+        shared_state.event_counts.clone(),
+    );
+
+    let uptime_task = uptime::uptime_task(
+        shared_state.instances.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let chat_archive_task = chat_archive::chat_archive_task(
+        shared_state.instances.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let console_log_task = console_log::console_log_task(
+        shared_state.instances.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let chat_filter_task = chat_filter::chat_filter_task(
+        shared_state.instances.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let log_triggers_task = log_triggers::log_triggers_task(
+        shared_state.instances.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let position_tracking_task = player_positions::position_tracking_task(
+        shared_state.instances.clone(),
+        shared_state.player_positions.clone(),
+    );
+
+    let afk_task = afk::afk_task(
+        shared_state.instances.clone(),
+        shared_state.player_positions.clone(),
+        shared_state.player_activity.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let reserved_slots_task = reserved_slots::reserved_slots_task(
+        shared_state.instances.clone(),
+        shared_state.player_activity.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let playtime_ranks_task = playtime_ranks::playtime_ranks_task(shared_state.instances.clone());
+
+    let votifier_task = votifier::votifier_task(
+        shared_state.instances.clone(),
+        shared_state.global_settings.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let discord_bridge_task = discord_bridge::discord_bridge_task(
+        shared_state.instances.clone(),
+        shared_state.state_store.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let discord_webhook_task = events::discord_webhook::discord_webhook_task(
+        shared_state.instances.clone(),
+        shared_state.global_settings.clone(),
+        shared_state.state_store.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let webhooks_task = events::webhooks::webhooks_task(
+        shared_state.webhooks.clone(),
+        shared_state.event_broadcaster.clone(),
+    );
+
+    let database_dump_task = {
+        let instances = shared_state.instances.clone();
+        let state_store = shared_state.state_store.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let now = chrono::Utc::now().timestamp();
+                let mut instance_paths = Vec::new();
+                for (uuid, instance) in instances.lock().await.iter() {
+                    instance_paths.push((uuid.clone(), instance.path().await));
+                }
+                for (uuid, path) in instance_paths {
+                    match InstanceDatabases::load(&state_store, &uuid, &path).await {
+                        Ok(config) if config.is_dump_due(now) => {
+                            if let Err(e) = dump_instance_databases(&state_store, &uuid, &path)
+                                .instrument(
+                                    info_span!("scheduled_database_dump", path = %path.display()),
+                                )
+                                .await
+                            {
+                                error!("Scheduled database dump failed: {}", e);
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => error!("Failed to read database config: {}", e),
+                    }
+                }
+            }
+        }
+    };
+
+    let backup_scheduler_task = backup::scheduler::backup_scheduler_task(
+        shared_state.instances.clone(),
+        shared_state.global_settings.clone(),
+        shared_state.backup_scheduler.clone(),
+    );
+
+    let scheduler_task = scheduler::scheduler_task(shared_state.clone());
+
+    let auto_start_task = {
+        let state = shared_state.clone();
+        async move {
+            // the tasks above subscribe to events when the select below first polls
+            // them, which has happened once this resumes
+            tokio::task::yield_now().await;
+            start_queue::auto_start(state).await;
+            std::future::pending::<()>().await
+        }
+    };
+
     let tls_config_result = RustlsConfig::from_pem_file(
         lodestone_path.join("tls").join("cert.pem"),
         lodestone_path.join("tls").join("key.pem"),
@@ -546,10 +1036,46 @@ pub async fn run(
                     .merge(get_setup_route(shared_state.clone()))
                     .merge(get_monitor_routes(shared_state.clone()))
                     .merge(get_instance_macro_routes(shared_state.clone()))
+                    .merge(get_instance_notes_routes(shared_state.clone()))
+                    .merge(get_instance_changelog_routes(shared_state.clone()))
+                    .merge(get_instance_world_routes(shared_state.clone()))
+                    .merge(get_instance_proxy_routes(shared_state.clone()))
+                    .merge(get_instance_databases_routes(shared_state.clone()))
+                    .merge(get_instance_resource_pack_routes(shared_state.clone()))
+                    .merge(get_instance_web_map_routes(shared_state.clone()))
+                    .merge(get_instance_ip_access_routes(shared_state.clone()))
+                    .merge(get_instance_vpn_check_routes(shared_state.clone()))
+                    .merge(get_players_routes(shared_state.clone()))
+                    .merge(get_instance_chat_routes(shared_state.clone()))
+                    .merge(get_instance_console_routes(shared_state.clone()))
+                    .merge(get_instance_votes_routes(shared_state.clone()))
+                    .merge(get_instance_backups_routes(shared_state.clone()))
+                    .merge(get_instance_migration_routes(shared_state.clone()))
+                    .merge(get_database_hosts_routes(shared_state.clone()))
+                    .merge(get_global_bans_routes(shared_state.clone()))
                     .merge(get_instance_fs_routes(shared_state.clone()))
                     .merge(get_global_fs_routes(shared_state.clone()))
                     .merge(get_global_settings_routes(shared_state.clone()))
                     .merge(get_gateway_routes(shared_state.clone()))
+                    .merge(get_badges_routes(shared_state.clone()))
+                    .merge(get_instance_api_tokens_routes(shared_state.clone()))
+                    .merge(get_instance_definitions_routes(shared_state.clone()))
+                    .merge(get_alerts_routes(shared_state.clone()))
+                    .merge(get_webhooks_routes(shared_state.clone()))
+                    .merge(get_presets_routes(shared_state.clone()))
+                    .merge(get_instance_log_triggers_routes(shared_state.clone()))
+                    .merge(get_instance_mods_routes(shared_state.clone()))
+                    .merge(get_scheduled_tasks_routes(shared_state.clone()))
+                    .merge(get_instance_crashes_routes(shared_state.clone()))
+                    .merge(get_java_runtimes_routes(shared_state.clone()))
+                    .layer(axum::middleware::from_fn_with_state(
+                        shared_state.clone(),
+                        maintenance::maintenance_guard,
+                    ))
+                    .layer(axum::middleware::from_fn_with_state(
+                        shared_state.clone(),
+                        request_metrics::track_requests,
+                    ))
                     .layer(cors)
                     .layer(trace);
                 let app = Router::new().nest("/api/v1", api_routes);
@@ -593,12 +1119,45 @@ pub async fn run(
                         .unwrap();
                     }
                 });
+                service::sd_notify("READY=1");
                 select! {
                     _ = write_to_db_task => info!("Write to db task exited"),
                     _ = event_buffer_task => info!("Event buffer task exited"),
                     _ = monitor_report_task => info!("Monitor report task exited"),
+                    _ = world_reset_task => info!("World reset task exited"),
+                    _ = proxy_links_task => info!("Proxy links task exited"),
+                    _ = database_dump_task => info!("Database dump task exited"),
+                    _ = backup_scheduler_task => info!("Backup scheduler task exited"),
+                    _ = scheduler_task => info!("Scheduler task exited"),
+                    _ = auto_start_task => info!("Auto start task exited"),
+                    _ = firewall_task => info!("Firewall task exited"),
+                    _ = flood_detection_task => info!("Flood detection task exited"),
+                    _ = player_session_task => info!("Player session task exited"),
+                    _ = metrics_history_task => info!("Metrics history task exited"),
+                    _ = tick_monitor_task => info!("Tick monitor task exited"),
+                    _ = alerts_task => info!("Alerts task exited"),
+                    _ = restart_on_crash_task => info!("Restart on crash task exited"),
+                    _ = crash_reports_task => info!("Crash reports task exited"),
+                    _ = startup_triage_task => info!("Startup triage task exited"),
+                    _ = event_count_task => info!("Event count task exited"),
+                    _ = uptime_task => info!("Uptime task exited"),
+                    _ = chat_archive_task => info!("Chat archive task exited"),
+                    _ = chat_filter_task => info!("Chat filter task exited"),
+                    _ = console_log_task => info!("Console log task exited"),
+                    _ = log_triggers_task => info!("Log triggers task exited"),
+                    _ = discord_bridge_task => info!("Discord bridge task exited"),
+                    _ = discord_webhook_task => info!("Discord webhook task exited"),
+                    _ = webhooks_task => info!("Webhooks task exited"),
+                    _ = position_tracking_task => info!("Position tracking task exited"),
+                    _ = afk_task => info!("AFK task exited"),
+                    _ = reserved_slots_task => info!("Reserved slots task exited"),
+                    _ = playtime_ranks_task => info!("Playtime ranks task exited"),
+                    _ = votifier_task => info!("Votifier task exited"),
+                    _ = service::watchdog_task() => info!("Watchdog task exited"),
                     _ = tokio::signal::ctrl_c() => info!("Ctrl+C received"),
+                    _ = service::terminate_signal() => info!("SIGTERM received"),
                 }
+                service::sd_notify("STOPPING=1");
                 info!("Shutting down web server");
                 axum_server_handle.shutdown();
                 info!("Signalling all instances to stop");
@@ -616,6 +1175,7 @@ pub async fn run(
                         );
                     }
                 }
+                telemetry::shutdown();
             }
         },
         shared_state,
@@ -0,0 +1,511 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::console_policy::validate_command;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_macro::TMacro;
+use crate::traits::t_server::TServer;
+use crate::types::InstanceUuid;
+
+const FILE_NAME: &str = ".lodestone_log_triggers.json";
+const STATS_FILE_NAME: &str = ".lodestone_log_trigger_stats.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum LogTriggerAction {
+    /// console command. `{line}` and the named groups of the pattern, e.g.
+    /// `{player}` for `(?P<player>\w+)`, are substituted.
+    Command {
+        command: String,
+    },
+    Macro {
+        macro_name: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// warning in the instance's event stream, which webhooks and Discord
+    /// notifications can forward. Substituted like a command.
+    Alert {
+        message: String,
+    },
+    Restart,
+}
+
+fn default_cooldown_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct LogTrigger {
+    /// assigned by the core
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub enabled: bool,
+    /// regex matched against each line of console output
+    pub pattern: String,
+    pub actions: Vec<LogTriggerAction>,
+    /// the trigger doesn't fire again within this many seconds, so its own
+    /// actions or a burst of the same line can't set it off in a loop
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,
+    /// hit statistics, maintained by the core in a file of their own
+    #[serde(default)]
+    pub hits: u64,
+    #[serde(default)]
+    pub last_hit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LogTriggers {
+    pub triggers: Vec<LogTrigger>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+struct TriggerHits {
+    hits: u64,
+    last_hit: Option<i64>,
+}
+
+/// Hit statistics by trigger id. Kept apart from the triggers, so recording a hit
+/// can't overwrite an edit made through the API in the meantime.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct LogTriggerStats {
+    triggers: HashMap<String, TriggerHits>,
+}
+
+impl LogTriggerStats {
+    fn of(triggers: &LogTriggers) -> Self {
+        Self {
+            triggers: triggers
+                .triggers
+                .iter()
+                .map(|trigger| {
+                    (
+                        trigger.id.clone(),
+                        TriggerHits {
+                            hits: trigger.hits,
+                            last_hit: trigger.last_hit,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(STATS_FILE_NAME);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse log trigger stats at {}", path.display()),
+            )?,
+        )
+    }
+
+    async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(STATS_FILE_NAME),
+            serde_json::to_string(self).context("Failed to serialize log trigger stats")?,
+        )
+        .await
+    }
+}
+
+impl LogTrigger {
+    fn compile(&self) -> Result<regex::Regex, Error> {
+        regex::Regex::new(&self.pattern).map_err(|e| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Invalid pattern: {}", e),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        let bad_request = |message: &str| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(message.to_string()),
+        };
+        if self.name.trim().is_empty() || self.name.len() > 64 {
+            return Err(bad_request("Name must be between 1 and 64 characters"));
+        }
+        self.compile()?;
+        if self.actions.is_empty() {
+            return Err(bad_request("A trigger needs at least one action"));
+        }
+        for action in &self.actions {
+            match action {
+                LogTriggerAction::Command { command } => {
+                    validate_command(command)?;
+                }
+                LogTriggerAction::Macro { macro_name, .. } if macro_name.is_empty() => {
+                    return Err(bad_request("Macro name is empty"))
+                }
+                _ => {}
+            }
+        }
+        if self.cooldown_secs == 0 || self.cooldown_secs > 7 * 24 * 60 * 60 {
+            return Err(bad_request("Cooldown must be between 1 second and a week"));
+        }
+        Ok(())
+    }
+
+    /// `line` and the named groups of the pattern if it matches
+    fn captures(&self, pattern: &regex::Regex, line: &str) -> Option<Vec<(String, String)>> {
+        let captures = pattern.captures(line)?;
+        let mut values = vec![("line".to_string(), line.to_string())];
+        for name in pattern.capture_names().flatten() {
+            let value = captures.name(name).map_or("", |m| m.as_str());
+            values.push((name.to_string(), value.to_string()));
+        }
+        Some(values)
+    }
+}
+
+impl LogTriggers {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(FILE_NAME);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let mut ret: Self =
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse log triggers at {}", path.display()),
+            )?;
+        let stats = LogTriggerStats::load(path_to_instance).await?;
+        for trigger in ret.triggers.iter_mut() {
+            let hits = stats.triggers.get(&trigger.id).copied().unwrap_or_default();
+            trigger.hits = hits.hits;
+            trigger.last_hit = hits.last_hit;
+        }
+        Ok(ret)
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(FILE_NAME),
+            serde_json::to_string_pretty(self).context("Failed to serialize log triggers")?,
+        )
+        .await
+    }
+}
+
+/// The triggers of an instance with their patterns compiled, reloaded when the
+/// file changes
+struct CompiledTriggers {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    triggers: LogTriggers,
+    /// in the order of `triggers`, `None` for an invalid pattern
+    patterns: Vec<Option<regex::Regex>>,
+}
+
+async fn modified(path_to_instance: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path_to_instance.join(FILE_NAME))
+        .await
+        .ok()?
+        .modified()
+        .ok()
+}
+
+impl CompiledTriggers {
+    async fn load(path_to_instance: PathBuf) -> Result<Self, Error> {
+        let modified = modified(&path_to_instance).await;
+        let triggers = LogTriggers::load(&path_to_instance).await?;
+        let patterns = triggers
+            .triggers
+            .iter()
+            .map(|trigger| trigger.compile().ok())
+            .collect();
+        Ok(Self {
+            path: path_to_instance,
+            modified,
+            triggers,
+            patterns,
+        })
+    }
+
+    /// Triggers `line` sets off with the values their actions are rendered with,
+    /// recording a hit on each. Triggers in their cooldown are skipped.
+    fn fire(&mut self, line: &str, now: i64) -> Vec<(LogTrigger, Vec<(String, String)>)> {
+        let mut fired = Vec::new();
+        for (trigger, pattern) in self.triggers.triggers.iter_mut().zip(&self.patterns) {
+            let pattern = match pattern {
+                Some(pattern) if trigger.enabled => pattern,
+                _ => continue,
+            };
+            if trigger.last_hit.map_or(false, |last_hit| {
+                now - last_hit < trigger.cooldown_secs as i64
+            }) {
+                continue;
+            }
+            if let Some(values) = trigger.captures(pattern, line) {
+                trigger.hits += 1;
+                trigger.last_hit = Some(now);
+                fired.push((trigger.clone(), values));
+            }
+        }
+        fired
+    }
+}
+
+async fn run_action(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    event_broadcaster: &EventBroadcaster,
+    instance_uuid: &InstanceUuid,
+    instance_name: &str,
+    action: &LogTriggerAction,
+    values: &[(&str, &str)],
+) -> Result<(), Error> {
+    let mut instance = instances
+        .lock()
+        .await
+        .get(instance_uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    match action {
+        LogTriggerAction::Command { command } => {
+            instance
+                .send_command(&command_template::render(command, values), CausedBy::System)
+                .await
+        }
+        LogTriggerAction::Macro { macro_name, args } => instance
+            .run_macro(macro_name, args.clone(), CausedBy::System)
+            .await
+            .map(|_| ()),
+        LogTriggerAction::Alert { message } => {
+            event_broadcaster.send(Event::new_instance_warning(
+                instance_uuid.clone(),
+                instance_name.to_string(),
+                command_template::render(message, values),
+            ));
+            Ok(())
+        }
+        LogTriggerAction::Restart => instance.restart(CausedBy::System, false).await,
+    }
+}
+
+/// Matches the console output of every instance against its log triggers and
+/// runs the actions of the ones that fire
+pub async fn log_triggers_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut compiled: HashMap<InstanceUuid, CompiledTriggers> = HashMap::new();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Log triggers task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let (uuid, instance_name, line) = match event.event_inner {
+            EventInner::InstanceEvent(InstanceEvent {
+                instance_uuid,
+                instance_name,
+                instance_event_inner: InstanceEventInner::InstanceOutput { message },
+            }) => (instance_uuid, instance_name, message),
+            _ => continue,
+        };
+        let path = match compiled.get(&uuid) {
+            Some(triggers) => triggers.path.clone(),
+            None => match instances.lock().await.get(&uuid) {
+                Some(instance) => instance.path().await,
+                None => continue,
+            },
+        };
+        let stale = match compiled.get(&uuid) {
+            Some(triggers) => triggers.modified != modified(&path).await,
+            None => true,
+        };
+        if stale {
+            match CompiledTriggers::load(path).await {
+                Ok(triggers) => {
+                    compiled.insert(uuid.clone(), triggers);
+                }
+                Err(e) => {
+                    error!("Failed to load log triggers of instance {}: {}", uuid, e);
+                    compiled.remove(&uuid);
+                    continue;
+                }
+            }
+        }
+        let triggers = match compiled.get_mut(&uuid) {
+            Some(triggers) => triggers,
+            None => continue,
+        };
+        let fired = triggers.fire(&line, chrono::Utc::now().timestamp());
+        if fired.is_empty() {
+            continue;
+        }
+        if let Err(e) = LogTriggerStats::of(&triggers.triggers)
+            .save(&triggers.path)
+            .await
+        {
+            error!(
+                "Failed to save log trigger stats of instance {}: {}",
+                uuid, e
+            );
+        }
+        for (trigger, values) in fired {
+            let values: Vec<(&str, &str)> = values
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            for action in &trigger.actions {
+                if let Err(e) = run_action(
+                    &instances,
+                    &event_broadcaster,
+                    &uuid,
+                    &instance_name,
+                    action,
+                    &values,
+                )
+                .await
+                {
+                    warn!(
+                        "Failed to run action of log trigger \"{}\" on instance {}: {}",
+                        trigger.name, uuid, e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_triggers() {
+        let trigger = |id: &str, pattern: &str| LogTrigger {
+            id: id.to_string(),
+            name: id.to_string(),
+            enabled: true,
+            pattern: pattern.to_string(),
+            actions: vec![LogTriggerAction::Command {
+                command: "say {player} {line}".to_string(),
+            }],
+            cooldown_secs: 60,
+            hits: 0,
+            last_hit: None,
+        };
+        let triggers = LogTriggers {
+            triggers: vec![
+                trigger("watchdog", "Server Watchdog"),
+                trigger("join", r"(?P<player>\w+) joined the game"),
+                trigger("invalid", "("),
+            ],
+        };
+        assert!(triggers.triggers[0].validate().is_ok());
+        assert!(triggers.triggers[2].validate().is_err());
+        let mut compiled = CompiledTriggers {
+            path: PathBuf::new(),
+            modified: None,
+            patterns: triggers
+                .triggers
+                .iter()
+                .map(|trigger| trigger.compile().ok())
+                .collect(),
+            triggers,
+        };
+
+        let fired = compiled.fire("[Server thread/INFO]: Steve joined the game", 100);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].0.id, "join");
+        assert!(fired[0]
+            .1
+            .contains(&("player".to_string(), "Steve".to_string())));
+        // in its cooldown
+        assert!(compiled
+            .fire("[Server thread/INFO]: Alex joined the game", 130)
+            .is_empty());
+        assert_eq!(
+            compiled
+                .fire("[Server thread/INFO]: Alex joined the game", 160)
+                .len(),
+            1
+        );
+        assert_eq!(compiled.triggers.triggers[1].hits, 2);
+        assert_eq!(compiled.triggers.triggers[1].last_hit, Some(160));
+        assert!(compiled.fire("nothing to see", 1000).is_empty());
+    }
+
+    #[test]
+    fn test_command_validation() {
+        let trigger = |command: &str| LogTrigger {
+            id: String::new(),
+            name: "trigger".to_string(),
+            enabled: true,
+            pattern: "Server Watchdog".to_string(),
+            actions: vec![LogTriggerAction::Command {
+                command: command.to_string(),
+            }],
+            cooldown_secs: 60,
+            hits: 0,
+            last_hit: None,
+        };
+        assert!(trigger("say {line}").validate().is_ok());
+        assert!(trigger("  ").validate().is_err());
+        assert!(trigger("say hi\nop attacker").validate().is_err());
+        assert!(trigger("say hi\u{0}op attacker").validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stats_are_kept_apart() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut triggers = LogTriggers {
+            triggers: vec![LogTrigger {
+                id: "watchdog".to_string(),
+                name: "watchdog".to_string(),
+                enabled: true,
+                pattern: "Server Watchdog".to_string(),
+                actions: vec![LogTriggerAction::Restart],
+                cooldown_secs: 60,
+                hits: 0,
+                last_hit: None,
+            }],
+        };
+        triggers.save(temp_dir.path()).await.unwrap();
+        triggers.triggers[0].hits = 3;
+        triggers.triggers[0].last_hit = Some(100);
+        LogTriggerStats::of(&triggers)
+            .save(temp_dir.path())
+            .await
+            .unwrap();
+
+        let loaded = LogTriggers::load(temp_dir.path()).await.unwrap();
+        assert_eq!(loaded.triggers[0].hits, 3);
+        assert_eq!(loaded.triggers[0].last_hit, Some(100));
+        // recording hits leaves the triggers file alone
+        let saved: LogTriggers = serde_json::from_str(
+            &std::fs::read_to_string(temp_dir.path().join(FILE_NAME)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(saved.triggers[0].hits, 0);
+    }
+}
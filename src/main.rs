@@ -5,6 +5,10 @@ use lodestone_core::Args;
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(command) = args.command.take() {
+        let lodestone_path = lodestone_core::resolve_lodestone_path(args.lodestone_path);
+        std::process::exit(lodestone_core::cli::run(command, lodestone_path).await);
+    }
     lodestone_core::run(args).await.0.await;
 }
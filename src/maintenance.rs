@@ -0,0 +1,99 @@
+use axum::extract::State;
+use axum::http::{header::AUTHORIZATION, Method, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::AppState;
+
+/// While enabled, only owners and admins can change anything through the API,
+/// everyone else can still look around and follow consoles
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct MaintenanceSettings {
+    pub enabled: bool,
+    /// shown to users whose changes are rejected
+    pub message: Option<String>,
+}
+
+impl MaintenanceSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(message) = &self.message {
+            if message.chars().count() > 200 || message.chars().any(|c| c.is_control()) {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Message must be a single line of at most 200 characters"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Calls that change something but keep working for everyone, so admins can
+/// still log in to turn maintenance off
+const ALWAYS_ALLOWED: &[&str] = &["/user/login", "/user/logout"];
+
+/// Whether a request is rejected for non admins during maintenance
+fn is_blocked(method: &Method, path: &str) -> bool {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return false;
+    }
+    let path = path.strip_prefix("/api/v1").unwrap_or(path);
+    !ALWAYS_ALLOWED.iter().any(|allowed| {
+        path.strip_prefix(allowed)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Rejects mutating calls of anyone but owners and admins while the core is in
+/// maintenance mode
+pub async fn maintenance_guard<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, Error> {
+    if !is_blocked(request.method(), request.uri().path()) {
+        return Ok(next.run(request).await);
+    }
+    let maintenance = state.global_settings.lock().await.maintenance();
+    if !maintenance.enabled {
+        return Ok(next.run(request).await);
+    }
+    let is_admin = match request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(token) => state
+            .users_manager
+            .read()
+            .await
+            .try_auth(token)
+            .map_or(false, |user| user.is_owner || user.is_admin),
+        None => false,
+    };
+    if is_admin {
+        return Ok(next.run(request).await);
+    }
+    Err(Error {
+        kind: ErrorKind::Maintenance,
+        source: match maintenance.message {
+            Some(message) => eyre!("The core is in maintenance mode: {}", message),
+            None => eyre!("The core is in maintenance mode, changes are disabled"),
+        },
+    })
+}
+
+#[test]
+fn test_is_blocked() {
+    assert!(!is_blocked(&Method::GET, "/api/v1/instance/list"));
+    assert!(!is_blocked(&Method::POST, "/api/v1/user/login"));
+    assert!(!is_blocked(&Method::POST, "/user/logout/abc"));
+    assert!(is_blocked(&Method::PUT, "/api/v1/instance/abc/start"));
+    assert!(is_blocked(&Method::POST, "/user/login_as"));
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use ringbuffer::RingBufferExt;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use ts_rs::TS;
+
+use crate::db::metrics::MetricSample;
+use crate::error::{Error, ErrorKind};
+use crate::traits::t_player::TPlayerManagement;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+use crate::AppState;
+
+/// queries may return at most this many points, pick a coarser resolution otherwise
+pub const MAX_POINTS: i64 = 2000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct MetricsHistorySettings {
+    /// seconds between two samples of a running instance
+    pub interval_secs: u64,
+    /// samples older than this many days are deleted
+    pub retention_days: u32,
+}
+
+impl Default for MetricsHistorySettings {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30,
+            retention_days: 30,
+        }
+    }
+}
+
+impl MetricsHistorySettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(5..=3600).contains(&self.interval_secs) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Sampling interval must be between 5 seconds and an hour"),
+            });
+        }
+        if !(1..=365).contains(&self.retention_days) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Metrics can be kept between 1 and 365 days"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The resolution of a query from `from` to `to`, in seconds. Picks one giving
+/// about 500 points if none is asked for.
+pub fn resolution_for(
+    from: i64,
+    to: i64,
+    resolution: Option<i64>,
+    interval_secs: u64,
+) -> Result<i64, Error> {
+    if to <= from {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The end of the range must be after its start"),
+        });
+    }
+    let resolution = resolution.unwrap_or_else(|| ((to - from) / 500).max(interval_secs as i64));
+    if resolution < 1 {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Resolution must be at least a second"),
+        });
+    }
+    if (to - from) / resolution > MAX_POINTS {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(
+                "The range would have more than {} points, pick a coarser resolution",
+                MAX_POINTS
+            ),
+        });
+    }
+    Ok(resolution)
+}
+
+/// Samples CPU, memory, player count and tick timings of every running instance
+/// into the metrics store, and prunes what's past the retention once an hour
+pub async fn metrics_history_task(state: AppState) {
+    let mut last_pruned = 0;
+    let mut settings_changes = state.global_settings.lock().await.subscribe();
+    loop {
+        let settings = settings_changes.borrow_and_update().metrics_history.clone();
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(settings.interval_secs)) => {}
+            // start over with the new interval instead of finishing the old one
+            Ok(()) = settings_changes.changed() => continue,
+        }
+        let now = chrono::Utc::now().timestamp();
+        let instances = state.instances.lock().await.clone();
+        let mut samples = Vec::new();
+        for (uuid, instance) in instances.iter() {
+            if instance.state().await != State::Running {
+                continue;
+            }
+            let report = state
+                .monitor_buffer
+                .lock()
+                .await
+                .get(uuid)
+                .and_then(|buffer| buffer.iter().last().cloned())
+                .unwrap_or_default();
+            let tick_stats = state.tick_monitor.lock().await.latest(uuid, now);
+            samples.push(MetricSample {
+                instance_uuid: uuid.clone(),
+                time: now,
+                cpu_usage: report.cpu_usage,
+                memory_usage: report.memory_usage,
+                player_count: instance.get_player_count().await.ok(),
+                tps: tick_stats.and_then(|stats| stats.tps),
+                mspt: tick_stats.and_then(|stats| stats.mspt),
+            });
+        }
+        if let Err(e) = state.metrics_store.write(&samples).await {
+            error!("Failed to record instance metrics: {}", e);
+        }
+        if now - last_pruned >= 60 * 60 {
+            last_pruned = now;
+            let uuids: Vec<InstanceUuid> = instances.keys().cloned().collect();
+            let before = now - settings.retention_days as i64 * 24 * 60 * 60;
+            if let Err(e) = state.metrics_store.prune(before, &uuids).await {
+                error!("Failed to prune instance metrics: {}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_resolution_for() {
+    assert_eq!(resolution_for(0, 3600, None, 30).unwrap(), 30);
+    assert_eq!(resolution_for(0, 7 * 86400, None, 30).unwrap(), 1209);
+    assert_eq!(resolution_for(0, 600, Some(60), 30).unwrap(), 60);
+    assert!(resolution_for(0, 86400, Some(1), 30).is_err());
+    assert!(resolution_for(100, 100, None, 30).is_err());
+    assert!(MetricsHistorySettings::default().validate().is_ok());
+}
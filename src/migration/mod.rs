@@ -1,3 +1,4 @@
+pub mod schema;
 mod v042_to_v044;
 pub mod v043_to_v044;
 
@@ -99,7 +100,8 @@ fn determine_legacy_version(lodestone_path: &Path) -> Result<Option<LegacyVersio
 /// If it is, then we are at v0.4.3 and thus migrate to 0.4.4 by creating the version file
 /// and rewrite all the `.lodestone_config` files to remove the `lodestone_version` field
 ///
-///
+/// Since 0.5 the formats of the core's own files are versioned instead, see `schema`.
+/// Those are upgraded after any legacy migration.
 
 pub fn migrate(lodestone_path: &Path) -> Result<(), Error> {
     let legacy_version = determine_legacy_version(lodestone_path)?;
@@ -117,6 +119,7 @@ pub fn migrate(lodestone_path: &Path) -> Result<(), Error> {
             info!("No migration needed");
         }
     }
+    let schemas = schema::migrate_schemas(lodestone_path);
     let version_path = lodestone_path.join(".lodestone_metadata.json");
     let version_file =
         std::fs::File::create(version_path).context("Failed to create version file")?;
@@ -127,6 +130,5 @@ pub fn migrate(lodestone_path: &Path) -> Result<(), Error> {
         },
     )
     .context("Failed to write version file")?;
-    Ok(())
+    schemas
 }
-
@@ -0,0 +1,196 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context};
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::error::Error;
+
+type Migration = fn(Value) -> Result<Value, Error>;
+
+/// A JSON file whose format is versioned by a top level `schema_version` field.
+/// Files written before formats were versioned have no such field and are
+/// version 0.
+pub struct VersionedFile {
+    name: &'static str,
+    /// `migrations[n]` upgrades version n to n + 1, so the current version is
+    /// the number of migrations
+    migrations: &'static [Migration],
+}
+
+pub static DOT_LODESTONE_CONFIG: VersionedFile = VersionedFile {
+    name: ".lodestone_config",
+    migrations: &[add_version],
+};
+
+pub static GLOBAL_SETTINGS: VersionedFile = VersionedFile {
+    name: "global settings",
+    migrations: &[add_version],
+};
+
+pub static USERS: VersionedFile = VersionedFile {
+    name: "users file",
+    migrations: &[users_v0_to_v1],
+};
+
+pub fn dot_lodestone_config_version() -> u32 {
+    DOT_LODESTONE_CONFIG.current_version()
+}
+
+pub fn global_settings_version() -> u32 {
+    GLOBAL_SETTINGS.current_version()
+}
+
+/// The first versioned format only added `schema_version`
+fn add_version(value: Value) -> Result<Value, Error> {
+    Ok(value)
+}
+
+/// The users file was a bare map of users, leaving no room for the version
+fn users_v0_to_v1(value: Value) -> Result<Value, Error> {
+    Ok(json!({ "users": value }))
+}
+
+impl VersionedFile {
+    pub fn current_version(&self) -> u32 {
+        self.migrations.len() as u32
+    }
+
+    fn version_of(&self, value: &Value) -> Result<u32, Error> {
+        match value.get("schema_version") {
+            None => Ok(0),
+            Some(version) => Ok(version
+                .as_u64()
+                .ok_or_else(|| eyre!("The {} has an invalid schema_version", self.name))?
+                as u32),
+        }
+    }
+
+    /// Upgrades `value` to the current version, returning it with the version it
+    /// was at
+    pub fn migrate(&self, mut value: Value) -> Result<(Value, u32), Error> {
+        let from = self.version_of(&value)?;
+        if from > self.current_version() {
+            return Err(eyre!(
+                "The {} is at version {}, written by a newer Lodestone Core than this one which reads up to version {}",
+                self.name,
+                from,
+                self.current_version()
+            )
+            .into());
+        }
+        for migration in &self.migrations[from as usize..] {
+            value = migration(value)?;
+        }
+        value
+            .as_object_mut()
+            .ok_or_else(|| eyre!("The {} is not a JSON object", self.name))?
+            .insert("schema_version".to_string(), json!(self.current_version()));
+        Ok((value, from))
+    }
+
+    /// Upgrades the file at `path` in place if it's older than the current
+    /// version, copying it to `<file name>.v<version>.bak` first. Returns the path
+    /// of that copy. Missing and empty files are left alone, the core creates them
+    /// in the current format. The file is untouched if any migration fails.
+    pub fn migrate_file(&self, path: &Path) -> Result<Option<PathBuf>, Error> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content =
+            std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        if content.trim().is_empty() {
+            return Ok(None);
+        }
+        let value: Value = serde_json::from_str(&content)
+            .context(format!("Failed to parse {}", path.display()))?;
+        let (value, from) = self.migrate(value)?;
+        if from == self.current_version() {
+            return Ok(None);
+        }
+        let file_name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        let backup = path.with_file_name(format!("{}.v{}.bak", file_name, from));
+        std::fs::copy(path, &backup).context(format!(
+            "Failed to back up {} to {}",
+            path.display(),
+            backup.display()
+        ))?;
+        let partial = path.with_file_name(format!("{}.partial", file_name));
+        std::fs::write(
+            &partial,
+            serde_json::to_string_pretty(&value).context("Failed to serialize migrated file")?,
+        )
+        .context(format!("Failed to write {}", partial.display()))?;
+        std::fs::rename(&partial, path).context(format!("Failed to replace {}", path.display()))?;
+        info!(
+            "Migrated {} from version {} to {}, the old file is at {}",
+            path.display(),
+            from,
+            self.current_version(),
+            backup.display()
+        );
+        Ok(Some(backup))
+    }
+}
+
+/// Upgrades the global settings, the users file and the `.lodestone_config` of
+/// every instance. A file that fails to migrate is left as it was and doesn't stop
+/// the others.
+pub fn migrate_schemas(lodestone_path: &Path) -> Result<(), Error> {
+    let mut files = vec![
+        (
+            &GLOBAL_SETTINGS,
+            lodestone_path.join("global_settings.json"),
+        ),
+        (&USERS, lodestone_path.join("stores").join("users.json")),
+    ];
+    if let Ok(entries) = std::fs::read_dir(lodestone_path.join("instances")) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            files.push((
+                &DOT_LODESTONE_CONFIG,
+                entry.path().join(".lodestone_config"),
+            ));
+        }
+    }
+    let mut failed = 0;
+    for (file, path) in files {
+        if let Err(e) = file.migrate_file(&path) {
+            error!("Failed to migrate {}: {}", path.display(), e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(eyre!("{} files could not be migrated", failed).into());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_migrate_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("users.json");
+    std::fs::write(&path, r#"{"some_user":{"username":"steve"}}"#).unwrap();
+
+    let backup = USERS.migrate_file(&path).unwrap().unwrap();
+    assert_eq!(backup, dir.path().join("users.json.v0.bak"));
+    let migrated: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(
+        migrated,
+        json!({ "schema_version": 1, "users": { "some_user": { "username": "steve" } } })
+    );
+    assert!(std::fs::read_to_string(&backup)
+        .unwrap()
+        .starts_with(r#"{"some_user""#));
+    assert_eq!(USERS.migrate_file(&path).unwrap(), None);
+
+    std::fs::write(&path, r#"{"schema_version":2,"users":{}}"#).unwrap();
+    assert!(USERS.migrate_file(&path).is_err());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        r#"{"schema_version":2,"users":{}}"#
+    );
+}
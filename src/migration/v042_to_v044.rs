@@ -23,8 +23,13 @@ impl From<RestoreConfigV042> for RestoreConfig {
             restart_on_crash: config.restart_on_crash,
             backup_period: config.backup_period,
             jre_major_version: config.jre_major_version,
+            java_pinned: false,
+            jvm_preset: None,
+            notes: Default::default(),
             has_started: config.has_started,
             java_cmd: None,
+            cpu_limit: None,
+            memory_limit: None,
         }
     }
 }
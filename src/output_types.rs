@@ -25,6 +25,11 @@ impl From<&Event> for ClientEvent {
             EventInner::InstanceEvent(i) => match i.instance_event_inner {
                 InstanceEventInner::InstanceError { .. } => EventLevel::Error,
                 InstanceEventInner::InstanceWarning { .. } => EventLevel::Warning,
+                InstanceEventInner::PerformanceDegraded { .. } => EventLevel::Warning,
+                InstanceEventInner::BackupFailed { .. } => EventLevel::Error,
+                InstanceEventInner::InstanceCrashLoop { .. } => EventLevel::Error,
+                InstanceEventInner::CrashCaptured { .. } => EventLevel::Error,
+                InstanceEventInner::StartupFailed { .. } => EventLevel::Error,
                 _ => EventLevel::Info,
             },
             EventInner::UserEvent(_) => EventLevel::Info,
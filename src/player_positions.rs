@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::implementations::minecraft::MinecraftInstance;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+/// Last known position of every online player, by instance and player name
+pub type PlayerPositions = HashMap<InstanceUuid, HashMap<String, PlayerPosition>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct PlayerPosition {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    /// e.g. `minecraft:the_nether`
+    pub dimension: String,
+    pub updated_at: i64,
+}
+
+/// Positions are queried over RCON, so `enable-rcon` must be on
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct PositionTrackingConfig {
+    pub enabled: bool,
+    pub interval_secs: u32,
+}
+
+impl Default for PositionTrackingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 10,
+        }
+    }
+}
+
+impl PositionTrackingConfig {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_position_tracking.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!(
+                    "Failed to parse position tracking config at {}",
+                    path.display()
+                ),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_position_tracking.json"),
+            serde_json::to_string_pretty(self)
+                .context("Failed to serialize position tracking config")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        // two commands per player per poll
+        if self.interval_secs < 2 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Interval must be at least 2 seconds"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The data part of a `data get entity` response,
+/// e.g. `Steve has the following entity data: [1.5d, 64.0d, -3.2d]`
+fn entity_data(response: &str) -> Option<&str> {
+    response
+        .split_once("entity data: ")
+        .map(|(_, data)| data.trim())
+}
+
+fn parse_pos(response: &str) -> Option<(f64, f64, f64)> {
+    let data = entity_data(response)?
+        .strip_prefix('[')?
+        .strip_suffix(']')?;
+    let mut coordinates = data
+        .split(',')
+        .map(|c| c.trim().trim_end_matches('d').parse::<f64>());
+    match (
+        coordinates.next()?,
+        coordinates.next()?,
+        coordinates.next()?,
+    ) {
+        (Ok(x), Ok(y), Ok(z)) => Some((x, y, z)),
+        _ => None,
+    }
+}
+
+fn parse_dimension(response: &str) -> Option<String> {
+    Some(entity_data(response)?.trim_matches('"').to_string())
+}
+
+async fn query_position(
+    instance: &MinecraftInstance,
+    player: &str,
+) -> Result<PlayerPosition, Error> {
+    let pos = instance
+        .send_rcon(&format!("data get entity {} Pos", player))
+        .await?;
+    let dimension = instance
+        .send_rcon(&format!("data get entity {} Dimension", player))
+        .await?;
+    let (x, y, z) = parse_pos(&pos).ok_or_else(|| eyre!("Unexpected response: {}", pos))?;
+    Ok(PlayerPosition {
+        x,
+        y,
+        z,
+        dimension: parse_dimension(&dimension)
+            .ok_or_else(|| eyre!("Unexpected response: {}", dimension))?,
+        updated_at: chrono::Utc::now().timestamp(),
+    })
+}
+
+async fn poll_instance(
+    instance: &MinecraftInstance,
+) -> Result<HashMap<String, PlayerPosition>, Error> {
+    let mut positions = HashMap::new();
+    let mut last_error = None;
+    for player in instance.get_player_list().await? {
+        let name = player.get_name();
+        match query_position(instance, &name).await {
+            Ok(position) => {
+                positions.insert(name, position);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    // a player who left since the list was taken fails on its own,
+    // nobody succeeding means RCON isn't working
+    match last_error {
+        Some(e) if positions.is_empty() => Err(e),
+        _ => Ok(positions),
+    }
+}
+
+/// Polls player positions of every Minecraft instance that opted in
+pub async fn position_tracking_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    player_positions: Arc<Mutex<PlayerPositions>>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last_polled: HashMap<InstanceUuid, i64> = HashMap::new();
+    // instances whose RCON failure was already logged, to not log it every poll
+    let mut failing: HashSet<InstanceUuid> = HashSet::new();
+    loop {
+        interval.tick().await;
+        let mut running = Vec::new();
+        for (uuid, instance) in instances.lock().await.iter() {
+            if let GameInstance::MinecraftInstance(instance) = instance {
+                if instance.state().await == State::Running {
+                    running.push((uuid.clone(), instance.clone()));
+                }
+            }
+        }
+        player_positions
+            .lock()
+            .await
+            .retain(|uuid, _| running.iter().any(|(u, _)| u == uuid));
+        let now = chrono::Utc::now().timestamp();
+        for (uuid, instance) in running {
+            let config = match PositionTrackingConfig::load(&instance.path().await).await {
+                Ok(config) if config.enabled => config,
+                Ok(_) => {
+                    player_positions.lock().await.remove(&uuid);
+                    continue;
+                }
+                Err(e) => {
+                    error!("Failed to load position tracking config of {}: {}", uuid, e);
+                    continue;
+                }
+            };
+            if last_polled
+                .get(&uuid)
+                .map_or(false, |last| now - last < config.interval_secs as i64)
+            {
+                continue;
+            }
+            last_polled.insert(uuid.clone(), now);
+            match poll_instance(&instance).await {
+                Ok(positions) => {
+                    failing.remove(&uuid);
+                    player_positions.lock().await.insert(uuid, positions);
+                }
+                Err(e) => {
+                    if failing.insert(uuid.clone()) {
+                        warn!("Failed to poll player positions of {}: {}", uuid, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_entity_data() {
+    assert_eq!(
+        parse_pos("Steve has the following entity data: [12.5d, 64.0d, -3.25d]"),
+        Some((12.5, 64.0, -3.25))
+    );
+    assert_eq!(parse_pos("No entity was found"), None);
+    assert_eq!(
+        parse_dimension("Steve has the following entity data: \"minecraft:the_nether\""),
+        Some("minecraft:the_nether".to_string())
+    );
+}
@@ -0,0 +1,642 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::ban_list::{sync_ban_files, BanListManager, GlobalBan};
+use crate::command_template;
+use crate::error::Error;
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::firewall::IpAccessList;
+use crate::geoip::{GeoInfo, GeoIpResolver};
+use crate::global_settings::GlobalSettings;
+use crate::implementations::minecraft::line_parser::{parse_player_login, parse_system_msg};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::{Player, TPlayer};
+use crate::traits::t_server::{State, TServer};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::vpn_detection::{check_address, VpnAction, VpnCheckConfig, VpnDetectionSettings};
+
+/// oldest sessions are dropped past this
+const MAX_SESSIONS: usize = 5000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct PlayerSession {
+    pub player_name: String,
+    pub player_uuid: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub geo: Option<GeoInfo>,
+    /// why the address was flagged as a VPN or proxy, if it was
+    #[serde(default)]
+    pub proxy: Option<String>,
+    pub joined_at: i64,
+    /// `None` while the player is online
+    pub left_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct AltDetectionSettings {
+    /// warn in the instance's event stream when a joining player's address
+    /// was used by other accounts on any instance
+    pub warn_on_join: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PlayerSessionLog {
+    pub sessions: Vec<PlayerSession>,
+}
+
+impl PlayerSessionLog {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_player_sessions.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse player sessions at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_player_sessions.json"),
+            serde_json::to_string(self).context("Failed to serialize player sessions")?,
+        )
+        .await
+    }
+
+    pub fn start(&mut self, session: PlayerSession) {
+        self.sessions.push(session);
+        if self.sessions.len() > MAX_SESSIONS {
+            let excess = self.sessions.len() - MAX_SESSIONS;
+            self.sessions.drain(..excess);
+        }
+    }
+
+    pub fn end(&mut self, player_name: &str, at: i64) {
+        for session in self.sessions.iter_mut().rev() {
+            if session.player_name == player_name && session.left_at.is_none() {
+                session.left_at = Some(at);
+            }
+        }
+    }
+
+    pub fn end_all(&mut self, at: i64) {
+        for session in self.sessions.iter_mut() {
+            if session.left_at.is_none() {
+                session.left_at = Some(at);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct AltAccount {
+    /// most recently used name
+    pub player_name: String,
+    pub player_uuid: Option<String>,
+    pub shared_ips: Vec<IpAddr>,
+    /// instances the account joined from a shared address
+    pub instances: Vec<InstanceUuid>,
+    pub last_seen: i64,
+}
+
+/// Accounts are told apart by uuid, or by name for offline mode servers
+pub(crate) fn account_key(session: &PlayerSession) -> String {
+    session
+        .player_uuid
+        .clone()
+        .unwrap_or_else(|| session.player_name.to_lowercase())
+}
+
+/// Other accounts that joined any instance from an address `player` (a name or uuid)
+/// has used, most recently seen first
+pub fn find_alt_accounts(
+    sessions: &[(InstanceUuid, PlayerSession)],
+    player: &str,
+) -> Vec<AltAccount> {
+    let is_player = |session: &PlayerSession| {
+        session.player_name.eq_ignore_ascii_case(player)
+            || session.player_uuid.as_deref() == Some(player)
+    };
+    let player_keys: HashSet<String> = sessions
+        .iter()
+        .filter(|(_, session)| is_player(session))
+        .map(|(_, session)| account_key(session))
+        .collect();
+    let player_ips: HashSet<IpAddr> = sessions
+        .iter()
+        .filter(|(_, session)| is_player(session))
+        .filter_map(|(_, session)| session.ip)
+        .collect();
+
+    let mut alts: HashMap<String, AltAccount> = HashMap::new();
+    for (instance_uuid, session) in sessions {
+        let ip = match session.ip {
+            Some(ip) if player_ips.contains(&ip) => ip,
+            _ => continue,
+        };
+        let key = account_key(session);
+        if player_keys.contains(&key) {
+            continue;
+        }
+        let alt = alts.entry(key).or_insert_with(|| AltAccount {
+            player_name: session.player_name.clone(),
+            player_uuid: session.player_uuid.clone(),
+            shared_ips: Vec::new(),
+            instances: Vec::new(),
+            last_seen: session.joined_at,
+        });
+        if session.joined_at >= alt.last_seen {
+            alt.last_seen = session.joined_at;
+            alt.player_name = session.player_name.clone();
+        }
+        if !alt.shared_ips.contains(&ip) {
+            alt.shared_ips.push(ip);
+        }
+        if !alt.instances.contains(instance_uuid) {
+            alt.instances.push(instance_uuid.clone());
+        }
+    }
+    let mut alts: Vec<AltAccount> = alts.into_values().collect();
+    alts.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+    alts
+}
+
+/// Sessions of all given instances, skipping logs that fail to load
+pub async fn load_sessions(
+    paths: Vec<(InstanceUuid, PathBuf)>,
+) -> Vec<(InstanceUuid, PlayerSession)> {
+    let mut sessions = Vec::new();
+    for (uuid, path) in paths {
+        match PlayerSessionLog::load(&path).await {
+            Ok(log) => sessions.extend(log.sessions.into_iter().map(|s| (uuid.clone(), s))),
+            Err(e) => error!("Failed to load player sessions of instance {}: {}", uuid, e),
+        }
+    }
+    sessions
+}
+
+async fn warn_alt_accounts(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    event_broadcaster: &EventBroadcaster,
+    instance_uuid: &InstanceUuid,
+    instance_name: &str,
+    joined: &[PlayerSession],
+) {
+    let mut paths = Vec::new();
+    for (uuid, instance) in instances.lock().await.iter() {
+        paths.push((uuid.clone(), instance.path().await));
+    }
+    let sessions = load_sessions(paths).await;
+    for session in joined {
+        let player = session
+            .player_uuid
+            .clone()
+            .unwrap_or_else(|| session.player_name.clone());
+        let alts = find_alt_accounts(&sessions, &player);
+        if alts.is_empty() {
+            continue;
+        }
+        event_broadcaster.send(Event::new_instance_warning(
+            instance_uuid.clone(),
+            instance_name.to_string(),
+            format!(
+                "{} has joined from the same address as {}",
+                session.player_name,
+                alts.iter()
+                    .map(|alt| alt.player_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ));
+    }
+}
+
+fn player_uuid(player: &Player) -> Option<String> {
+    match player {
+        Player::MinecraftPlayer(player) => player.uuid.clone(),
+        Player::GenericPlayer(_) => None,
+    }
+}
+
+async fn instance_path(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    uuid: &InstanceUuid,
+) -> Option<PathBuf> {
+    match instances.lock().await.get(uuid) {
+        Some(instance) => Some(instance.path().await),
+        None => None,
+    }
+}
+
+async fn update_log(
+    path_to_instance: Option<&Path>,
+    update: impl FnOnce(&mut PlayerSessionLog),
+) -> Result<(), Error> {
+    let path = match path_to_instance {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let mut log = PlayerSessionLog::load(path).await?;
+    update(&mut log);
+    log.save(path).await
+}
+
+async fn handle_flagged_player(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    event_broadcaster: &EventBroadcaster,
+    instance_uuid: &InstanceUuid,
+    instance_name: &str,
+    vpn_check: &VpnCheckConfig,
+    player_name: &str,
+    reason: &str,
+) {
+    match vpn_check.action {
+        VpnAction::Flag => event_broadcaster.send(Event::new_instance_warning(
+            instance_uuid.clone(),
+            instance_name.to_string(),
+            format!(
+                "{} may be connecting through a VPN or proxy: {}",
+                player_name, reason
+            ),
+        )),
+        VpnAction::Kick => {
+            let result = match instances.lock().await.get(instance_uuid) {
+                Some(instance) => {
+                    instance
+                        .send_command(
+                            &command_template::kick(player_name, &vpn_check.kick_message),
+                            CausedBy::System,
+                        )
+                        .await
+                }
+                None => return,
+            };
+            let message = match result {
+                Ok(_) => format!(
+                    "Kicked {} for connecting through a VPN or proxy: {}",
+                    player_name, reason
+                ),
+                Err(e) => format!(
+                    "Failed to kick {}, who may be connecting through a VPN or proxy: {}",
+                    player_name, e
+                ),
+            };
+            event_broadcaster.send(Event::new_instance_warning(
+                instance_uuid.clone(),
+                instance_name.to_string(),
+                message,
+            ));
+        }
+    }
+}
+
+async fn kick_banned_player(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    event_broadcaster: &EventBroadcaster,
+    instance_uuid: &InstanceUuid,
+    instance_name: &str,
+    player_name: &str,
+    ban: &GlobalBan,
+) {
+    let result = match instances.lock().await.get(instance_uuid) {
+        Some(instance) => {
+            instance
+                .send_command(
+                    &command_template::kick(player_name, &ban.reason),
+                    CausedBy::System,
+                )
+                .await
+        }
+        None => return,
+    };
+    let message = match result {
+        Ok(_) => format!(
+            "Kicked {}, who is on the global ban list: {}",
+            player_name, ban.reason
+        ),
+        Err(e) => format!(
+            "Failed to kick {}, who is on the global ban list: {}",
+            player_name, e
+        ),
+    };
+    event_broadcaster.send(Event::new_instance_warning(
+        instance_uuid.clone(),
+        instance_name.to_string(),
+        message,
+    ));
+}
+
+/// Kicks a player whose address the instance's IP access list rejects
+async fn kick_rejected_player(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    event_broadcaster: &EventBroadcaster,
+    instance_uuid: &InstanceUuid,
+    instance_name: &str,
+    player_name: &str,
+    reason: &str,
+) {
+    let result = match instances.lock().await.get(instance_uuid) {
+        Some(instance) => {
+            instance
+                .send_command(
+                    &command_template::kick(player_name, "You are not allowed on this server"),
+                    CausedBy::System,
+                )
+                .await
+        }
+        None => return,
+    };
+    let message = match result {
+        Ok(_) => format!("Kicked {}, {}", player_name, reason),
+        Err(e) => format!("Failed to kick {} ({}): {}", player_name, reason, e),
+    };
+    event_broadcaster.send(Event::new_instance_warning(
+        instance_uuid.clone(),
+        instance_name.to_string(),
+        message,
+    ));
+}
+
+/// Records player sessions of every instance, resolving the address each player
+/// logged in from against the configured GeoIP databases and checking it for VPNs
+/// if the instance opted in. Also enforces the global ban list and the instance's
+/// IP access list on join and syncs the bans into the ban files of instances that
+/// stopped.
+pub async fn player_session_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    ban_list: Arc<Mutex<BanListManager>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut resolver = GeoIpResolver::default();
+    // the login line with the address comes right before the join message
+    let mut login_addresses: HashMap<(InstanceUuid, String), IpAddr> = HashMap::new();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Player session task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let InstanceEvent {
+            instance_uuid,
+            instance_name,
+            instance_event_inner,
+        } = match event.event_inner {
+            EventInner::InstanceEvent(instance_event) => instance_event,
+            _ => continue,
+        };
+        let now = chrono::Utc::now().timestamp();
+        let result = match instance_event_inner {
+            InstanceEventInner::InstanceOutput { message } => {
+                if let Some((player_name, ip)) =
+                    parse_system_msg(&message).and_then(|msg| parse_player_login(&msg))
+                {
+                    login_addresses.insert((instance_uuid, player_name), ip);
+                }
+                continue;
+            }
+            InstanceEventInner::PlayerChange {
+                players_joined,
+                players_left,
+                ..
+            } => {
+                let path = instance_path(&instances, &instance_uuid).await;
+                let (vpn_settings, vpn_check, warn_alts) = if players_joined.is_empty() {
+                    (
+                        VpnDetectionSettings::default(),
+                        VpnCheckConfig::default(),
+                        false,
+                    )
+                } else {
+                    let global_settings = global_settings.lock().await;
+                    resolver.update_settings(&global_settings.geoip());
+                    let vpn_check = match &path {
+                        Some(path) => VpnCheckConfig::load(path).await.unwrap_or_else(|e| {
+                            error!("Failed to load VPN check config: {}", e);
+                            VpnCheckConfig::default()
+                        }),
+                        None => VpnCheckConfig::default(),
+                    };
+                    (
+                        global_settings.vpn_detection(),
+                        vpn_check,
+                        global_settings.alt_detection().warn_on_join,
+                    )
+                };
+                let ip_access = match &path {
+                    Some(path) if !players_joined.is_empty() => {
+                        IpAccessList::load(path).await.unwrap_or_else(|e| {
+                            error!("Failed to load IP access list: {}", e);
+                            IpAccessList::default()
+                        })
+                    }
+                    _ => IpAccessList::default(),
+                };
+                let mut sessions = Vec::new();
+                for player in players_joined {
+                    let player_name = player.get_name();
+                    let ip = login_addresses.remove(&(instance_uuid.clone(), player_name.clone()));
+                    let geo = ip.and_then(|ip| resolver.lookup(ip));
+                    let proxy = match ip {
+                        Some(ip) if vpn_check.enabled && !vpn_check.is_exempt(&player_name) => {
+                            check_address(&vpn_settings, ip, geo.as_ref()).await
+                        }
+                        _ => None,
+                    };
+                    sessions.push(PlayerSession {
+                        player_uuid: player_uuid(&player),
+                        geo,
+                        proxy,
+                        ip,
+                        player_name,
+                        joined_at: now,
+                        left_at: None,
+                    });
+                }
+                let result = update_log(path.as_deref(), |log| {
+                    for player in players_left.iter() {
+                        log.end(&player.get_name(), now);
+                    }
+                    for session in sessions.iter() {
+                        log.start(session.clone());
+                    }
+                })
+                .await;
+                if warn_alts && result.is_ok() {
+                    warn_alt_accounts(
+                        &instances,
+                        &event_broadcaster,
+                        &instance_uuid,
+                        &instance_name,
+                        &sessions,
+                    )
+                    .await;
+                }
+                for session in sessions {
+                    let ban = ban_list.lock().await.find_ban(
+                        &session.player_name,
+                        session.player_uuid.as_deref(),
+                        session.ip,
+                    );
+                    let rejection = session.ip.and_then(|ip| {
+                        ip_access.rejection(
+                            ip,
+                            session
+                                .geo
+                                .as_ref()
+                                .and_then(|geo| geo.country_code.as_deref()),
+                        )
+                    });
+                    if let Some(ban) = ban {
+                        kick_banned_player(
+                            &instances,
+                            &event_broadcaster,
+                            &instance_uuid,
+                            &instance_name,
+                            &session.player_name,
+                            &ban,
+                        )
+                        .await;
+                    } else if let Some(reason) = &rejection {
+                        kick_rejected_player(
+                            &instances,
+                            &event_broadcaster,
+                            &instance_uuid,
+                            &instance_name,
+                            &session.player_name,
+                            reason,
+                        )
+                        .await;
+                    } else if let Some(reason) = &session.proxy {
+                        handle_flagged_player(
+                            &instances,
+                            &event_broadcaster,
+                            &instance_uuid,
+                            &instance_name,
+                            &vpn_check,
+                            &session.player_name,
+                            reason,
+                        )
+                        .await;
+                    }
+                    event_broadcaster.send(Event {
+                        event_inner: EventInner::InstanceEvent(InstanceEvent {
+                            instance_uuid: instance_uuid.clone(),
+                            instance_name: instance_name.clone(),
+                            instance_event_inner: InstanceEventInner::PlayerSessionStarted {
+                                session,
+                            },
+                        }),
+                        details: "".to_string(),
+                        snowflake: Snowflake::default(),
+                        caused_by: CausedBy::System,
+                    });
+                }
+                result
+            }
+            InstanceEventInner::StateTransition {
+                to: State::Stopped | State::Error,
+            } => {
+                login_addresses.retain(|(uuid, _), _| *uuid != instance_uuid);
+                let path = instance_path(&instances, &instance_uuid).await;
+                let is_minecraft = matches!(
+                    instances.lock().await.get(&instance_uuid),
+                    Some(GameInstance::MinecraftInstance(_))
+                );
+                if let (Some(path), true) = (&path, is_minecraft) {
+                    let bans = ban_list.lock().await.list();
+                    if let Err(e) = sync_ban_files(path, &bans).await {
+                        error!(
+                            "Failed to sync ban files of instance {}: {}",
+                            instance_uuid, e
+                        );
+                    }
+                }
+                update_log(path.as_deref(), |log| log.end_all(now)).await
+            }
+            _ => continue,
+        };
+        if let Err(e) = result {
+            error!(
+                "Failed to record player sessions of instance {}: {}",
+                instance_uuid, e
+            );
+        }
+    }
+}
+
+#[test]
+fn test_player_session_log() {
+    let session = |name: &str, joined_at| PlayerSession {
+        player_name: name.to_string(),
+        player_uuid: None,
+        ip: Some("10.0.0.1".parse().unwrap()),
+        geo: None,
+        proxy: None,
+        joined_at,
+        left_at: None,
+    };
+    let mut log = PlayerSessionLog::default();
+    log.start(session("Steve", 1));
+    log.start(session("Alex", 2));
+    log.end("Steve", 3);
+    assert_eq!(log.sessions[0].left_at, Some(3));
+    assert_eq!(log.sessions[1].left_at, None);
+    log.end_all(4);
+    assert_eq!(log.sessions[1].left_at, Some(4));
+
+    for i in 0..MAX_SESSIONS {
+        log.start(session("Steve", i as i64));
+    }
+    assert_eq!(log.sessions.len(), MAX_SESSIONS);
+    assert_eq!(log.sessions[0].joined_at, 0);
+}
+
+#[test]
+fn test_find_alt_accounts() {
+    let instance_a = InstanceUuid::from("INSTANCE_a".to_string());
+    let instance_b = InstanceUuid::from("INSTANCE_b".to_string());
+    let session = |name: &str, uuid: &str, ip: &str, joined_at| PlayerSession {
+        player_name: name.to_string(),
+        player_uuid: Some(uuid.to_string()),
+        ip: Some(ip.parse().unwrap()),
+        geo: None,
+        proxy: None,
+        joined_at,
+        left_at: None,
+    };
+    let sessions = vec![
+        (instance_a.clone(), session("Steve", "1", "10.0.0.1", 1)),
+        (instance_a.clone(), session("Steve", "1", "10.0.0.2", 2)),
+        (instance_a.clone(), session("Alex", "2", "10.0.0.1", 3)),
+        (instance_b.clone(), session("Alex2", "2", "10.0.0.2", 4)),
+        (instance_b.clone(), session("Herobrine", "3", "10.0.0.3", 5)),
+    ];
+    let alts = find_alt_accounts(&sessions, "steve");
+    assert_eq!(alts.len(), 1);
+    assert_eq!(alts[0].player_name, "Alex2");
+    assert_eq!(alts[0].shared_ips.len(), 2);
+    assert_eq!(alts[0].instances, vec![instance_a, instance_b]);
+    assert_eq!(find_alt_accounts(&sessions, "2")[0].player_name, "Steve");
+    assert!(find_alt_accounts(&sessions, "Herobrine").is_empty());
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::console_policy::validate_command;
+use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
+use crate::player_sessions::{account_key, PlayerSession, PlayerSessionLog};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+use crate::util::rand_alphanumeric;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct PlaytimeRank {
+    /// assigned by the core when left empty
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    /// playtime on this instance needed for the rank
+    pub minutes: u32,
+    /// run once per player, `{player}` and `{rank}` are substituted,
+    /// e.g. `lp user {player} parent add {rank}`
+    pub commands: Vec<String>,
+}
+
+/// Playtime is summed from the instance's player sessions, so it only counts
+/// time since session logging started and the most recent sessions kept
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct PlaytimeRanksConfig {
+    pub enabled: bool,
+    pub ranks: Vec<PlaytimeRank>,
+}
+
+impl PlaytimeRanksConfig {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_playtime_ranks.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse playtime ranks at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_playtime_ranks.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize playtime ranks")?,
+        )
+        .await
+    }
+
+    /// Validates the ranks and assigns ids to new ones
+    pub fn prepare(&mut self) -> Result<(), Error> {
+        for rank in self.ranks.iter_mut() {
+            if rank.name.trim().is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Rank name is empty"),
+                });
+            }
+            if rank.commands.is_empty() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Rank {} has no commands", rank.name),
+                });
+            }
+            for command in rank.commands.iter_mut() {
+                *command = validate_command(command)?.to_string();
+            }
+            if rank.id.is_empty() {
+                rank.id = rand_alphanumeric(8);
+            }
+        }
+        let mut ids: Vec<&str> = self.ranks.iter().map(|rank| rank.id.as_str()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.len() != self.ranks.len() {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Rank ids must be unique"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Ranks already granted, by account (uuid, or lowercase name on offline mode
+/// servers) and rank id. Kept apart from the config so editing ranks keeps them.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct PlaytimeGrants {
+    granted: HashMap<String, Vec<String>>,
+}
+
+impl PlaytimeGrants {
+    async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_playtime_grants.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse playtime grants at {}", path.display()),
+            )?,
+        )
+    }
+
+    async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_playtime_grants.json"),
+            serde_json::to_string(self).context("Failed to serialize playtime grants")?,
+        )
+        .await
+    }
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct Playtime {
+    /// most recently used name
+    pub player_name: String,
+    pub player_uuid: Option<String>,
+    pub seconds: i64,
+    pub online: bool,
+}
+
+/// Playtime of every account in `sessions`, sessions still open count up to `now`
+pub fn playtime(sessions: &[PlayerSession], now: i64) -> Vec<Playtime> {
+    let mut by_account: HashMap<String, Playtime> = HashMap::new();
+    for session in sessions {
+        let entry = by_account
+            .entry(account_key(session))
+            .or_insert_with(|| Playtime {
+                player_name: session.player_name.clone(),
+                player_uuid: session.player_uuid.clone(),
+                seconds: 0,
+                online: false,
+            });
+        // sessions are in join order, so the last one has the current name
+        entry.player_name = session.player_name.clone();
+        entry.seconds += (session.left_at.unwrap_or(now) - session.joined_at).max(0);
+        entry.online |= session.left_at.is_none();
+    }
+    let mut ret: Vec<Playtime> = by_account.into_values().collect();
+    ret.sort_by(|a, b| b.seconds.cmp(&a.seconds));
+    ret
+}
+
+/// Ranks each online player has crossed the threshold of and not been granted yet.
+/// Offline players are left for when they are next online, so the commands can
+/// target them.
+fn due_ranks<'a>(
+    config: &'a PlaytimeRanksConfig,
+    grants: &PlaytimeGrants,
+    sessions: &[PlayerSession],
+    now: i64,
+) -> Vec<(String, String, &'a PlaytimeRank)> {
+    let mut due = Vec::new();
+    let mut keys: HashMap<String, String> = HashMap::new();
+    for session in sessions.iter().filter(|s| s.left_at.is_none()) {
+        keys.insert(session.player_name.clone(), account_key(session));
+    }
+    for player in playtime(sessions, now).into_iter().filter(|p| p.online) {
+        let key = match keys.get(&player.player_name) {
+            Some(key) => key.clone(),
+            None => continue,
+        };
+        let granted = grants.granted.get(&key);
+        for rank in config.ranks.iter() {
+            if player.seconds >= rank.minutes as i64 * 60
+                && !granted.map_or(false, |granted| granted.contains(&rank.id))
+            {
+                due.push((key.clone(), player.player_name.clone(), rank));
+            }
+        }
+    }
+    due
+}
+
+async fn grant_ranks(instance: &GameInstance) -> Result<(), Error> {
+    let path = instance.path().await;
+    let config = PlaytimeRanksConfig::load(&path).await?;
+    if !config.enabled || config.ranks.is_empty() {
+        return Ok(());
+    }
+    let sessions = PlayerSessionLog::load(&path).await?.sessions;
+    let mut grants = PlaytimeGrants::load(&path).await?;
+    let due = due_ranks(&config, &grants, &sessions, chrono::Utc::now().timestamp());
+    if due.is_empty() {
+        return Ok(());
+    }
+    for (key, player, rank) in due {
+        if command_template::validate_target(&player).is_err() {
+            continue;
+        }
+        for command in rank.commands.iter() {
+            let command =
+                command_template::render(command, &[("player", &player), ("rank", &rank.name)]);
+            instance.send_command(&command, CausedBy::System).await?;
+        }
+        info!(
+            "Granted playtime rank {} to {} on {}",
+            rank.name,
+            player,
+            instance.name().await
+        );
+        // recorded as soon as the commands went through, so a failure later on
+        // doesn't grant this rank again
+        grants.granted.entry(key).or_default().push(rank.id.clone());
+        grants.save(&path).await?;
+    }
+    Ok(())
+}
+
+/// Runs the commands of playtime ranks as online players reach them, once a minute
+pub async fn playtime_ranks_task(instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let mut running = Vec::new();
+        for (uuid, instance) in instances.lock().await.iter() {
+            if instance.state().await == State::Running {
+                running.push((uuid.clone(), instance.clone()));
+            }
+        }
+        for (uuid, instance) in running {
+            if let Err(e) = grant_ranks(&instance).await {
+                error!("Failed to grant playtime ranks of instance {}: {}", uuid, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_due_ranks() {
+    let session = |name: &str, joined_at: i64, left_at: Option<i64>| PlayerSession {
+        player_name: name.to_string(),
+        player_uuid: None,
+        ip: None,
+        geo: None,
+        proxy: None,
+        joined_at,
+        left_at,
+    };
+    let rank = |id: &str, minutes: u32| PlaytimeRank {
+        id: id.to_string(),
+        name: id.to_string(),
+        minutes,
+        commands: vec!["lp user {player} parent add {rank}".to_string()],
+    };
+    let config = PlaytimeRanksConfig {
+        enabled: true,
+        ranks: vec![rank("member", 60), rank("regular", 600)],
+    };
+    let sessions = vec![
+        session("Steve", 0, Some(3000)),
+        session("Alex", 0, Some(7200)),
+        session("Steve", 5000, None),
+    ];
+    let times = playtime(&sessions, 6000);
+    assert_eq!(times[0].player_name, "Alex");
+    assert_eq!(times[1].seconds, 4000);
+    assert!(times[1].online);
+
+    let mut grants = PlaytimeGrants::default();
+    // Alex is offline, Steve has 4000s
+    let due = due_ranks(&config, &grants, &sessions, 6000);
+    assert_eq!(due.len(), 1);
+    assert_eq!(
+        (due[0].1.as_str(), due[0].2.id.as_str()),
+        ("Steve", "member")
+    );
+    grants
+        .granted
+        .insert("steve".to_string(), vec!["member".to_string()]);
+    assert!(due_ranks(&config, &grants, &sessions, 6000).is_empty());
+}
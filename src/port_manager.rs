@@ -47,6 +47,12 @@ impl PortManager {
         self.allocated_ports.insert(port);
     }
 
+    pub fn allocated_ports(&self) -> Vec<u32> {
+        let mut ports: Vec<u32> = self.allocated_ports.iter().copied().collect();
+        ports.sort_unstable();
+        ports
+    }
+
     pub fn deallocate(&mut self, port: u32) {
         self.allocated_ports.remove(&port);
     }
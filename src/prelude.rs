@@ -101,6 +101,7 @@ use crate::generic::GenericInstance;
 use crate::minecraft::MinecraftInstance;
 #[enum_dispatch::enum_dispatch(
     TInstance,
+    TBackup,
     TConfigurable,
     TMacro,
     TPlayerManagement,
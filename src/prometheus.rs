@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+
+use color_eyre::eyre::eyre;
+use ringbuffer::RingBufferExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, EventLevel};
+use crate::output_types::ClientEvent;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::TPlayerManagement;
+use crate::traits::t_server::{State, TServer};
+use crate::AppState;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct PrometheusSettings {
+    /// serve `/metrics`, off unless turned on
+    pub enabled: bool,
+    /// scrapers have to send it as a bearer token, `None` leaves the endpoint open
+    pub token: Option<String>,
+}
+
+impl PrometheusSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(token) = &self.token {
+            if token.len() < 16 {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("The metrics token must be at least 16 characters"),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy for users other than the owner, it shows a token is set but not the token
+    pub fn redacted(&self) -> Self {
+        Self {
+            enabled: self.enabled,
+            token: self.token.as_ref().map(|_| String::new()),
+        }
+    }
+}
+
+/// Events broadcast since the core started, by type and level
+#[derive(Debug, Default)]
+pub struct EventCounts {
+    counts: HashMap<(&'static str, &'static str), u64>,
+}
+
+impl EventCounts {
+    pub fn record(&mut self, event: &ClientEvent) {
+        let event_type = match event.event_inner {
+            EventInner::InstanceEvent(_) => "instance",
+            EventInner::UserEvent(_) => "user",
+            EventInner::MacroEvent(_) => "macro",
+            EventInner::FSEvent(_) => "fs",
+            EventInner::ProgressionEvent(_) => "progression",
+        };
+        let level = match event.level {
+            EventLevel::Info => "info",
+            EventLevel::Warning => "warning",
+            EventLevel::Error => "error",
+        };
+        *self.counts.entry((event_type, level)).or_default() += 1;
+    }
+}
+
+pub async fn event_count_task(
+    event_broadcaster: EventBroadcaster,
+    event_counts: Arc<Mutex<EventCounts>>,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Event count task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        event_counts.lock().await.record(&ClientEvent::from(&event));
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prometheus text exposition format, one family at a time
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, metric_type);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect();
+        if labels.is_empty() {
+            let _ = writeln!(self.0, "{} {}", name, value);
+        } else {
+            let _ = writeln!(self.0, "{}{{{}}} {}", name, labels.join(","), value);
+        }
+    }
+}
+
+struct InstanceSample {
+    uuid: String,
+    name: String,
+    state: State,
+    players: Option<u32>,
+    max_players: Option<u32>,
+    cpu_usage: Option<f32>,
+    memory_usage: Option<u64>,
+}
+
+/// Everything `/metrics` exports, rendered for a scrape
+pub async fn render(state: &AppState) -> String {
+    let instances = state.instances.lock().await.clone();
+    let mut samples = Vec::new();
+    for (uuid, instance) in instances.iter() {
+        let report = state
+            .monitor_buffer
+            .lock()
+            .await
+            .get(uuid)
+            .and_then(|buffer| buffer.iter().last().cloned())
+            .unwrap_or_default();
+        samples.push(InstanceSample {
+            uuid: uuid.to_string(),
+            name: instance.name().await,
+            state: instance.state().await,
+            players: instance.get_player_count().await.ok(),
+            max_players: instance.get_max_player_count().await.ok(),
+            cpu_usage: report.cpu_usage,
+            memory_usage: report.memory_usage,
+        });
+    }
+    let event_counts = state.event_counts.lock().await.counts.clone();
+    let routes = state.request_metrics.lock().await.snapshot();
+
+    let mut out = Exposition::default();
+    out.family(
+        "lodestone_up_since_seconds",
+        "gauge",
+        "Unix time the core started",
+    );
+    out.sample("lodestone_up_since_seconds", &[], state.up_since);
+    write_instances(&mut out, &samples);
+
+    out.family(
+        "lodestone_events_total",
+        "counter",
+        "Events broadcast since the core started",
+    );
+    let mut event_counts: Vec<_> = event_counts.into_iter().collect();
+    event_counts.sort();
+    for ((event_type, level), count) in event_counts {
+        out.sample(
+            "lodestone_events_total",
+            &[("type", event_type), ("level", level)],
+            count,
+        );
+    }
+
+    out.family(
+        "lodestone_http_request_duration_seconds",
+        "summary",
+        "Time spent handling API requests by route",
+    );
+    for route in &routes {
+        let labels = [
+            ("method", route.method.as_str()),
+            ("route", route.route.as_str()),
+        ];
+        out.sample(
+            "lodestone_http_request_duration_seconds_sum",
+            &labels,
+            route.total_ms as f64 / 1000.0,
+        );
+        out.sample(
+            "lodestone_http_request_duration_seconds_count",
+            &labels,
+            route.count,
+        );
+    }
+    out.family(
+        "lodestone_http_request_errors_total",
+        "counter",
+        "API requests answered with a 4xx or 5xx status",
+    );
+    for route in &routes {
+        for (class, count) in [("4xx", route.client_errors), ("5xx", route.server_errors)] {
+            out.sample(
+                "lodestone_http_request_errors_total",
+                &[
+                    ("method", route.method.as_str()),
+                    ("route", route.route.as_str()),
+                    ("class", class),
+                ],
+                count,
+            );
+        }
+    }
+    out.0
+}
+
+fn write_instances(out: &mut Exposition, samples: &[InstanceSample]) {
+    out.family(
+        "lodestone_instance_state",
+        "gauge",
+        "1 for the state each instance is in",
+    );
+    for sample in samples {
+        for state in [
+            State::Starting,
+            State::Running,
+            State::Stopping,
+            State::Stopped,
+            State::Error,
+        ] {
+            let state_label = state.to_string().to_lowercase();
+            out.sample(
+                "lodestone_instance_state",
+                &[
+                    ("uuid", sample.uuid.as_str()),
+                    ("name", sample.name.as_str()),
+                    ("state", state_label.as_str()),
+                ],
+                (sample.state == state) as u8,
+            );
+        }
+    }
+    let gauges: [(&str, &str, fn(&InstanceSample) -> Option<f64>); 4] = [
+        ("lodestone_instance_players", "Players online", |sample| {
+            sample.players.map(f64::from)
+        }),
+        ("lodestone_instance_max_players", "Player slots", |sample| {
+            sample.max_players.map(f64::from)
+        }),
+        (
+            "lodestone_instance_cpu_usage_percent",
+            "CPU used by the instance's process, as a share of the whole host",
+            |sample| sample.cpu_usage.map(f64::from),
+        ),
+        (
+            "lodestone_instance_memory_bytes",
+            "Memory used by the instance's process",
+            |sample| sample.memory_usage.map(|bytes| bytes as f64),
+        ),
+    ];
+    for (name, help, value) in gauges {
+        out.family(name, "gauge", help);
+        for sample in samples {
+            if let Some(value) = value(sample) {
+                out.sample(
+                    name,
+                    &[
+                        ("uuid", sample.uuid.as_str()),
+                        ("name", sample.name.as_str()),
+                    ],
+                    value,
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_exposition() {
+    let mut out = Exposition::default();
+    write_instances(
+        &mut out,
+        &[InstanceSample {
+            uuid: "abc".to_string(),
+            name: "My \"SMP\"".to_string(),
+            state: State::Running,
+            players: Some(3),
+            max_players: Some(20),
+            cpu_usage: None,
+            memory_usage: Some(1024),
+        }],
+    );
+    let text = out.0;
+    assert!(text.contains(
+        "lodestone_instance_state{uuid=\"abc\",name=\"My \\\"SMP\\\"\",state=\"running\"} 1\n"
+    ));
+    assert!(text.contains("state=\"stopped\"} 0\n"));
+    assert!(text.contains("lodestone_instance_players{uuid=\"abc\",name=\"My \\\"SMP\\\"\"} 3\n"));
+    assert!(!text.contains("lodestone_instance_cpu_usage_percent{"));
+    assert!(text.contains("# TYPE lodestone_instance_memory_bytes gauge\n"));
+
+    assert!(PrometheusSettings {
+        enabled: true,
+        token: Some("short".to_string()),
+    }
+    .validate()
+    .is_err());
+}
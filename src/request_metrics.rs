@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::AppState;
+
+tokio::task_local! {
+    /// Locks the request being handled waited on, and for how long
+    static LOCK_WAITS: Arc<std::sync::Mutex<Vec<(String, Duration)>>>;
+}
+
+/// Locks `mutex`, noting the wait against the current request so slow requests
+/// can tell which lock held them up
+pub async fn timed_lock<'a, T>(name: &str, mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    let start = Instant::now();
+    let guard = mutex.lock().await;
+    let waited = start.elapsed();
+    if waited >= Duration::from_millis(1) {
+        let _ = LOCK_WAITS.try_with(|waits| {
+            waits.lock().unwrap().push((name.to_string(), waited));
+        });
+    }
+    guard
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct SlowRequestSettings {
+    /// requests taking longer are logged, 0 turns the log off
+    pub threshold_ms: u64,
+}
+
+impl Default for SlowRequestSettings {
+    fn default() -> Self {
+        Self { threshold_ms: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq, Default)]
+#[ts(export)]
+pub struct RouteStats {
+    pub method: String,
+    /// route template, e.g. `/api/v1/instance/:uuid/start`
+    pub route: String,
+    pub count: u64,
+    /// responses with a 4xx status
+    pub client_errors: u64,
+    /// responses with a 5xx status
+    pub server_errors: u64,
+    pub total_ms: u64,
+    pub max_ms: u64,
+    /// requests over the slow request threshold
+    pub slow: u64,
+}
+
+/// Counters by route since the core started
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    routes: HashMap<(String, String), RouteStats>,
+}
+
+impl RequestMetrics {
+    pub fn record(
+        &mut self,
+        method: &str,
+        route: &str,
+        status: u16,
+        elapsed: Duration,
+        slow: bool,
+    ) {
+        let stats = self
+            .routes
+            .entry((method.to_string(), route.to_string()))
+            .or_insert_with(|| RouteStats {
+                method: method.to_string(),
+                route: route.to_string(),
+                ..Default::default()
+            });
+        let ms = elapsed.as_millis() as u64;
+        stats.count += 1;
+        stats.total_ms += ms;
+        stats.max_ms = stats.max_ms.max(ms);
+        match status {
+            400..=499 => stats.client_errors += 1,
+            500..=599 => stats.server_errors += 1,
+            _ => {}
+        }
+        if slow {
+            stats.slow += 1;
+        }
+    }
+
+    /// Every route seen so far, the ones that took the most time in total first
+    pub fn snapshot(&self) -> Vec<RouteStats> {
+        let mut ret: Vec<RouteStats> = self.routes.values().cloned().collect();
+        ret.sort_by(|a, b| b.total_ms.cmp(&a.total_ms));
+        ret
+    }
+}
+
+/// Records the latency and status of every request by route, and logs the ones
+/// over the slow request threshold along with the locks they waited on
+pub async fn track_requests<B>(
+    State(state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    // unmatched paths are lumped together, they'd make a key per uuid otherwise
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched| matched.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let waits = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let start = Instant::now();
+    let response = LOCK_WAITS.scope(waits.clone(), next.run(request)).await;
+    let elapsed = start.elapsed();
+
+    let threshold_ms = state
+        .global_settings
+        .lock()
+        .await
+        .slow_requests()
+        .threshold_ms;
+    let slow = threshold_ms > 0 && elapsed >= Duration::from_millis(threshold_ms);
+    let status = response.status().as_u16();
+    if slow {
+        let waits: Vec<String> = waits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, waited)| format!("{} lock for {}ms", name, waited.as_millis()))
+            .collect();
+        warn!(
+            "Slow request: {} {} took {}ms with status {}{}",
+            method,
+            path,
+            elapsed.as_millis(),
+            status,
+            if waits.is_empty() {
+                String::new()
+            } else {
+                format!(", waited on the {}", waits.join(", "))
+            }
+        );
+    }
+    state
+        .request_metrics
+        .lock()
+        .await
+        .record(&method, &route, status, elapsed, slow);
+    response
+}
+
+#[test]
+fn test_request_metrics() {
+    let mut metrics = RequestMetrics::default();
+    let route = "/api/v1/instance/:uuid/start";
+    metrics.record("PUT", route, 200, Duration::from_millis(30), false);
+    metrics.record("PUT", route, 500, Duration::from_millis(2500), true);
+    metrics.record("GET", "/api/v1/info", 200, Duration::from_millis(5), false);
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.len(), 2);
+    assert_eq!(snapshot[0].route, route);
+    assert_eq!(snapshot[0].count, 2);
+    assert_eq!(snapshot[0].max_ms, 2500);
+    assert_eq!(snapshot[0].server_errors, 1);
+    assert_eq!(snapshot[0].slow, 1);
+}
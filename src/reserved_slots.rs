@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::afk::{ActivityStatus, PlayerActivity};
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::types::InstanceUuid;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum ReservedSlotMode {
+    /// kick players to keep the reserved slots free, AFK players first
+    Kick,
+    /// turn the whitelist on, with the online and priority players on it, while only
+    /// reserved slots are left. Not for servers that use a whitelist otherwise.
+    Whitelist,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ReservedSlotsConfig {
+    pub enabled: bool,
+    pub reserved_slots: u32,
+    /// staff, donors and anyone else who can always join, case-insensitive
+    pub priority_players: Vec<String>,
+    pub mode: ReservedSlotMode,
+    /// with `Kick`, also kick the latest player to join if nobody is AFK
+    pub kick_active_players: bool,
+    pub kick_message: String,
+}
+
+impl Default for ReservedSlotsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            reserved_slots: 1,
+            priority_players: Vec::new(),
+            mode: ReservedSlotMode::Kick,
+            kick_active_players: false,
+            kick_message: "This slot is reserved, please try again later".to_string(),
+        }
+    }
+}
+
+impl ReservedSlotsConfig {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_reserved_slots.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!(
+                    "Failed to parse reserved slots config at {}",
+                    path.display()
+                ),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_reserved_slots.json"),
+            serde_json::to_string_pretty(self)
+                .context("Failed to serialize reserved slots config")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.reserved_slots == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("At least one slot must be reserved"),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_priority(&self, player_name: &str) -> bool {
+        self.priority_players
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(player_name))
+    }
+}
+
+/// Players to kick so that `reserved_slots` slots are free: AFK players longest AFK
+/// first, then the latest joiners if `kick_active_players` is set. Priority players
+/// are never picked.
+fn players_to_kick(
+    config: &ReservedSlotsConfig,
+    online: &[String],
+    just_joined: &[String],
+    activity: Option<&HashMap<String, ActivityStatus>>,
+    max_players: u32,
+) -> Vec<String> {
+    let free = max_players.saturating_sub(online.len() as u32);
+    let needed = config.reserved_slots.saturating_sub(free) as usize;
+    if needed == 0 {
+        return Vec::new();
+    }
+    let mut afk: Vec<(i64, &String)> = online
+        .iter()
+        .filter(|name| !config.is_priority(name))
+        .filter_map(|name| {
+            activity
+                .and_then(|activity| activity.get(name))
+                .and_then(|status| status.afk_since)
+                .map(|since| (since, name))
+        })
+        .collect();
+    afk.sort();
+    let mut to_kick: Vec<String> = afk.into_iter().map(|(_, name)| name.clone()).collect();
+    if config.kick_active_players {
+        to_kick.extend(
+            just_joined
+                .iter()
+                .filter(|name| !config.is_priority(name) && !to_kick.contains(name))
+                .cloned()
+                .collect::<Vec<_>>(),
+        );
+    }
+    to_kick.truncate(needed);
+    to_kick
+}
+
+async fn enforce(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    player_activity: &Mutex<PlayerActivity>,
+    event_broadcaster: &EventBroadcaster,
+    whitelisted: &mut HashMap<InstanceUuid, Vec<String>>,
+    uuid: &InstanceUuid,
+    just_joined: Vec<String>,
+) -> Result<(), Error> {
+    let instance = match instances.lock().await.get(uuid) {
+        Some(instance) => instance.clone(),
+        None => return Ok(()),
+    };
+    let config = ReservedSlotsConfig::load(&instance.path().await).await?;
+    if !config.enabled {
+        return Ok(());
+    }
+    let online: Vec<String> = instance
+        .get_player_list()
+        .await?
+        .iter()
+        .map(|p| p.get_name())
+        .collect();
+    let max_players = instance.get_max_player_count().await?;
+    match config.mode {
+        ReservedSlotMode::Kick => {
+            let to_kick = players_to_kick(
+                &config,
+                &online,
+                &just_joined,
+                player_activity.lock().await.get(uuid),
+                max_players,
+            );
+            for player in to_kick {
+                instance.kick_player(&player, &config.kick_message).await?;
+                event_broadcaster.send(Event::new_instance_warning(
+                    uuid.clone(),
+                    instance.name().await,
+                    format!("Kicked {} to keep a reserved slot free", player),
+                ));
+            }
+        }
+        ReservedSlotMode::Whitelist => {
+            let public_slots = max_players.saturating_sub(config.reserved_slots);
+            let is_restricted = whitelisted.contains_key(uuid);
+            if !is_restricted && online.len() as u32 >= public_slots {
+                let names: HashSet<String> = online
+                    .iter()
+                    .chain(config.priority_players.iter())
+                    .cloned()
+                    .collect();
+                for name in names.iter() {
+                    instance.set_whitelisted(name, true).await?;
+                }
+                instance.set_whitelist_enabled(true).await?;
+                whitelisted.insert(uuid.clone(), names.into_iter().collect());
+            } else if is_restricted && (online.len() as u32) < public_slots {
+                instance.set_whitelist_enabled(false).await?;
+                for name in whitelisted.remove(uuid).unwrap_or_default() {
+                    instance.set_whitelisted(&name, false).await?;
+                }
+            } else if is_restricted {
+                // players who got in through a reserved slot
+                let names = whitelisted.entry(uuid.clone()).or_default();
+                for name in just_joined.iter() {
+                    if !names.contains(name) {
+                        instance.set_whitelisted(name, true).await?;
+                        names.push(name.clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps each instance's reserved slots free as players join and leave
+pub async fn reserved_slots_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    player_activity: Arc<Mutex<PlayerActivity>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    // players each instance's whitelist was opened to, to close it again
+    let mut whitelisted: HashMap<InstanceUuid, Vec<String>> = HashMap::new();
+    loop {
+        let event = match event_receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => {
+                warn!("Reserved slots task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if let EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid,
+            instance_event_inner: InstanceEventInner::PlayerChange { players_joined, .. },
+            ..
+        }) = event.event_inner
+        {
+            let just_joined = players_joined.iter().map(|p| p.get_name()).collect();
+            if let Err(e) = enforce(
+                &instances,
+                &player_activity,
+                &event_broadcaster,
+                &mut whitelisted,
+                &instance_uuid,
+                just_joined,
+            )
+            .await
+            {
+                error!(
+                    "Failed to enforce reserved slots of instance {}: {}",
+                    instance_uuid, e
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_players_to_kick() {
+    let config = ReservedSlotsConfig {
+        enabled: true,
+        reserved_slots: 2,
+        priority_players: vec!["Admin".to_string()],
+        kick_active_players: true,
+        ..Default::default()
+    };
+    let online: Vec<String> = ["Admin", "Steve", "Alex", "Notch"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let mut activity = HashMap::new();
+    for (name, afk_since) in [("Admin", Some(1)), ("Steve", Some(20)), ("Alex", None)] {
+        let mut status = ActivityStatus::new(0);
+        status.afk_since = afk_since;
+        activity.insert(name.to_string(), status);
+    }
+    // 4 of 5 slots taken, one more must be freed: the AFK non-priority player
+    assert_eq!(
+        players_to_kick(&config, &online, &["Notch".to_string()], Some(&activity), 5),
+        vec!["Steve".to_string()]
+    );
+    // full, Steve and then the newest player
+    assert_eq!(
+        players_to_kick(&config, &online, &["Notch".to_string()], Some(&activity), 4),
+        vec!["Steve".to_string(), "Notch".to_string()]
+    );
+    assert!(players_to_kick(&config, &online, &[], Some(&activity), 6).is_empty());
+}
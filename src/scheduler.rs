@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use color_eyre::eyre::eyre;
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+use ts_rs::TS;
+
+use crate::backup::pre_change::{PendingChange, RiskyChange};
+use crate::backup::{BackupGuard, BackupMetadata};
+use crate::console_policy::validate_command;
+use crate::db::state::StateLocation;
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event};
+use crate::prelude::GameInstance;
+use crate::traits::t_backup::TBackup;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_macro::TMacro;
+use crate::traits::t_server::{State, TServer};
+use crate::traits::Capability;
+use crate::types::InstanceUuid;
+use crate::AppState;
+
+/// Tasks are checked every second so restart warnings go out on time
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const MAX_WARNINGS: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum ScheduledAction {
+    /// `say Restarting in 5m` goes out each of `warn_before_secs` ahead of the
+    /// restart. An instance that isn't running is left alone.
+    Restart {
+        #[serde(default)]
+        warn_before_secs: Vec<u64>,
+    },
+    Command {
+        command: String,
+    },
+    Macro {
+        macro_name: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    Backup,
+    /// downloads the mod or plugin updates to a staging area, where they wait to
+    /// be applied. With `auto_apply` they are applied right away if the instance
+    /// is stopped.
+    StageModUpdates {
+        #[serde(default)]
+        auto_apply: bool,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct ScheduledTask {
+    pub id: String,
+    pub name: String,
+    pub enabled: bool,
+    pub instance_uuid: InstanceUuid,
+    /// cron expression with seconds in UTC, e.g. `0 0 4 * * *` for 4am every day
+    pub schedule: String,
+    pub action: ScheduledAction,
+    pub created_by: String,
+    pub last_run: Option<i64>,
+    /// why the last run failed, `None` if it succeeded
+    pub last_error: Option<String>,
+    /// filled in when the task is listed
+    #[serde(default)]
+    pub next_run: Option<i64>,
+}
+
+#[derive(Debug, Clone, Deserialize, TS)]
+#[ts(export)]
+pub struct NewScheduledTask {
+    pub name: String,
+    pub enabled: bool,
+    /// required when the task isn't created through an instance's routes
+    pub instance_uuid: Option<InstanceUuid>,
+    pub schedule: String,
+    pub action: ScheduledAction,
+}
+
+fn parse_schedule(schedule: &str) -> Result<Schedule, Error> {
+    Schedule::from_str(schedule).map_err(|e| Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("Invalid schedule: {}", e),
+    })
+}
+
+impl NewScheduledTask {
+    pub fn validate(&self) -> Result<(), Error> {
+        let bad_request = |message: &str| Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!(message.to_string()),
+        };
+        if self.name.trim().is_empty() || self.name.len() > 64 {
+            return Err(bad_request("Task name must be between 1 and 64 characters"));
+        }
+        if parse_schedule(&self.schedule)?
+            .upcoming(Utc)
+            .next()
+            .is_none()
+        {
+            return Err(bad_request("The schedule never runs again"));
+        }
+        match &self.action {
+            ScheduledAction::Restart { warn_before_secs } => {
+                validate_warnings(warn_before_secs)?;
+            }
+            ScheduledAction::Command { command } => {
+                validate_command(command)?;
+            }
+            ScheduledAction::Macro { macro_name, .. } => {
+                if macro_name.is_empty() {
+                    return Err(bad_request("Macro name is empty"));
+                }
+            }
+            ScheduledAction::Backup | ScheduledAction::StageModUpdates { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+impl ScheduledTask {
+    pub fn from_new(
+        new_task: NewScheduledTask,
+        id: String,
+        instance_uuid: InstanceUuid,
+        created_by: String,
+    ) -> Self {
+        Self {
+            id,
+            name: new_task.name,
+            enabled: new_task.enabled,
+            instance_uuid,
+            schedule: new_task.schedule,
+            action: new_task.action,
+            created_by,
+            last_run: None,
+            last_error: None,
+            next_run: None,
+        }
+    }
+
+    fn next_run_after(&self, now: DateTime<Utc>) -> Option<i64> {
+        if !self.enabled {
+            return None;
+        }
+        parse_schedule(&self.schedule)
+            .ok()?
+            .after(&now)
+            .next()
+            .map(|next| next.timestamp())
+    }
+}
+
+/// Whether `schedule` has a run in `(from, to]`
+fn runs_between(schedule: &Schedule, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+    schedule
+        .after(&from)
+        .next()
+        .map_or(false, |next| next <= to)
+}
+
+/// Restart warnings, in seconds ahead of the restart
+pub(crate) fn validate_warnings(warn_before_secs: &[u64]) -> Result<(), Error> {
+    if warn_before_secs.len() > MAX_WARNINGS {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("A restart can have at most {} warnings", MAX_WARNINGS),
+        });
+    }
+    if warn_before_secs
+        .iter()
+        .any(|secs| *secs == 0 || *secs > 60 * 60)
+    {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Warnings must be between 1 second and an hour ahead"),
+        });
+    }
+    Ok(())
+}
+
+/// `5m`, `1m` or `10s`, as in `say Restarting in 5m`
+pub(crate) fn countdown(secs: u64) -> String {
+    if secs % 3600 == 0 {
+        format!("{}h", secs / 3600)
+    } else if secs % 60 == 0 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+pub struct SchedulerManager {
+    location: StateLocation,
+    tasks: HashMap<String, ScheduledTask>,
+}
+
+impl SchedulerManager {
+    pub fn new(location: impl Into<StateLocation>) -> Self {
+        Self {
+            location: location.into(),
+            tasks: HashMap::new(),
+        }
+    }
+
+    pub async fn load_from_file(&mut self) -> Result<(), Error> {
+        self.tasks = self.location.read().await?.unwrap_or_default();
+        Ok(())
+    }
+
+    async fn write_to_file(&self) -> Result<(), Error> {
+        self.location.write(&self.tasks).await
+    }
+
+    /// Sorted by the next run, with `next_run` filled in
+    pub fn list(&self) -> Vec<ScheduledTask> {
+        let now = Utc::now();
+        let mut tasks: Vec<ScheduledTask> = self
+            .tasks
+            .values()
+            .cloned()
+            .map(|mut task| {
+                task.next_run = task.next_run_after(now);
+                task
+            })
+            .collect();
+        tasks.sort_by_key(|task| (task.next_run.is_none(), task.next_run, task.name.clone()));
+        tasks
+    }
+
+    pub fn get(&self, id: &str) -> Result<ScheduledTask, Error> {
+        let mut task = self.tasks.get(id).cloned().ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Scheduled task not found"),
+        })?;
+        task.next_run = task.next_run_after(Utc::now());
+        Ok(task)
+    }
+
+    /// Adds the task, or replaces the one with the same id
+    pub async fn set_task(&mut self, mut task: ScheduledTask) -> Result<ScheduledTask, Error> {
+        task.next_run = None;
+        let old = self.tasks.insert(task.id.clone(), task.clone());
+        if let Err(e) = self.write_to_file().await {
+            match old {
+                Some(old) => self.tasks.insert(task.id.clone(), old),
+                None => self.tasks.remove(&task.id),
+            };
+            return Err(e);
+        }
+        self.get(&task.id)
+    }
+
+    pub async fn remove_task(&mut self, id: &str) -> Result<ScheduledTask, Error> {
+        let task = self.tasks.remove(id).ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Scheduled task not found"),
+        })?;
+        if let Err(e) = self.write_to_file().await {
+            self.tasks.insert(task.id.clone(), task);
+            return Err(e);
+        }
+        Ok(task)
+    }
+
+    async fn record_run(&mut self, id: &str, time: i64, result: Result<(), Error>) {
+        let task = match self.tasks.get_mut(id) {
+            Some(task) => task,
+            // deleted while it ran
+            None => return,
+        };
+        task.last_run = Some(time);
+        task.last_error = result.err().map(|e| e.source.to_string());
+        if let Err(e) = self.write_to_file().await {
+            error!("Failed to save scheduled tasks: {}", e);
+        }
+    }
+}
+
+async fn run_action(state: &AppState, task: &ScheduledTask) -> Result<(), Error> {
+    let mut instance = state
+        .instances
+        .lock()
+        .await
+        .get(&task.instance_uuid)
+        .cloned()
+        .ok_or_else(|| Error {
+            kind: ErrorKind::NotFound,
+            source: eyre!("Instance not found"),
+        })?;
+    match &task.action {
+        ScheduledAction::Restart { .. } => {
+            if instance.state().await != State::Running {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("The instance wasn't running"),
+                });
+            }
+            instance.restart(CausedBy::System, true).await
+        }
+        ScheduledAction::Command { command } => {
+            instance.send_command(command, CausedBy::System).await
+        }
+        ScheduledAction::Macro { macro_name, args } => instance
+            .run_macro(macro_name, args.clone(), CausedBy::System)
+            .await
+            .map(|_| ()),
+        ScheduledAction::Backup => {
+            let guard = BackupGuard::acquire(&task.instance_uuid)?;
+            let remotes = state.global_settings.lock().await.backup_remotes();
+            instance
+                .create_backup(
+                    guard,
+                    BackupMetadata::default(),
+                    None,
+                    &remotes,
+                    CausedBy::System,
+                )
+                .await
+                .map(|_| ())
+        }
+        ScheduledAction::StageModUpdates { auto_apply } => {
+            let instance = match &instance {
+                GameInstance::MinecraftInstance(instance) => instance,
+                _ => return Err(Error::not_supported(Capability::SupportsMods)),
+            };
+            let changes = instance.stage_mod_updates().await?.plan.changes.len();
+            if changes == 0 {
+                return Ok(());
+            }
+            if *auto_apply && instance.state().await == State::Stopped {
+                let change = PendingChange::begin(
+                    state,
+                    &GameInstance::from(instance.clone()),
+                    RiskyChange::ModUpdate,
+                    CausedBy::System,
+                )
+                .await?;
+                let res = instance.apply_staged_mod_updates().await;
+                let diff = match &res {
+                    Ok(plan) => instance.mod_change_diff(plan).await,
+                    Err(_) => None,
+                };
+                change.finish(&res, diff).await;
+                return res.map(|_| ());
+            }
+            state.event_broadcaster.send(Event::new_system_message(
+                task.instance_uuid.clone(),
+                instance.name().await,
+                format!("{} mod updates are staged and wait to be applied", changes),
+            ));
+            Ok(())
+        }
+    }
+}
+
+async fn send_warning(state: &AppState, task: &ScheduledTask, secs: u64) {
+    let instance = state
+        .instances
+        .lock()
+        .await
+        .get(&task.instance_uuid)
+        .cloned();
+    if let Some(instance) = instance {
+        if instance.state().await == State::Running {
+            if let Err(e) = instance
+                .send_command(
+                    &format!("say Restarting in {}", countdown(secs)),
+                    CausedBy::System,
+                )
+                .await
+            {
+                error!(
+                    "Failed to warn of scheduled restart \"{}\": {}",
+                    task.name, e
+                );
+            }
+        }
+    }
+}
+
+/// Runs the tasks that came due since the last check. The first check only
+/// starts the clock, so runs missed while the core was down aren't caught up on.
+pub async fn scheduler_task(state: AppState) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last_checked = Utc::now();
+    loop {
+        interval.tick().await;
+        let now = Utc::now();
+        let tasks = state.scheduler.lock().await.list();
+        for task in tasks.into_iter().filter(|task| task.enabled) {
+            let schedule = match parse_schedule(&task.schedule) {
+                Ok(schedule) => schedule,
+                Err(_) => continue,
+            };
+            if let ScheduledAction::Restart { warn_before_secs } = &task.action {
+                for secs in warn_before_secs {
+                    let ahead = chrono::Duration::seconds(*secs as i64);
+                    if runs_between(&schedule, last_checked + ahead, now + ahead) {
+                        send_warning(&state, &task, *secs).await;
+                    }
+                }
+            }
+            if !runs_between(&schedule, last_checked, now) {
+                continue;
+            }
+            info!("Running scheduled task \"{}\"", task.name);
+            let state = state.clone();
+            tokio::spawn(async move {
+                let result = run_action(&state, &task).await;
+                if let Err(e) = &result {
+                    error!("Scheduled task \"{}\" failed: {}", task.name, e);
+                }
+                state
+                    .scheduler
+                    .lock()
+                    .await
+                    .record_run(&task.id, now.timestamp(), result)
+                    .await;
+            });
+        }
+        last_checked = now;
+    }
+}
+
+#[test]
+fn test_scheduled_tasks() {
+    let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+    let nightly = parse_schedule("0 0 4 * * *").unwrap();
+    assert!(runs_between(
+        &nightly,
+        at("2024-01-01T03:59:59Z"),
+        at("2024-01-01T04:00:00Z")
+    ));
+    assert!(!runs_between(
+        &nightly,
+        at("2024-01-01T04:00:00Z"),
+        at("2024-01-01T04:00:01Z")
+    ));
+    // the 5 minute warning of the 4am restart
+    let ahead = chrono::Duration::seconds(300);
+    assert!(runs_between(
+        &nightly,
+        at("2024-01-01T03:54:59Z") + ahead,
+        at("2024-01-01T03:55:00Z") + ahead
+    ));
+    assert_eq!(countdown(300), "5m");
+    assert_eq!(countdown(10), "10s");
+    assert_eq!(countdown(90), "90s");
+    assert_eq!(countdown(3600), "1h");
+
+    let task = |schedule: &str, action: ScheduledAction| NewScheduledTask {
+        name: "Nightly restart".to_string(),
+        enabled: true,
+        instance_uuid: None,
+        schedule: schedule.to_string(),
+        action,
+    };
+    let restart = |warn_before_secs: Vec<u64>| ScheduledAction::Restart { warn_before_secs };
+    assert!(task("0 0 4 * * *", restart(vec![300, 60, 10]))
+        .validate()
+        .is_ok());
+    assert!(task("every night", restart(vec![])).validate().is_err());
+    assert!(task("0 0 4 * * *", restart(vec![0])).validate().is_err());
+    assert!(task(
+        "0 0 4 * * *",
+        ScheduledAction::Command {
+            command: "say hi\nstop".to_string()
+        }
+    )
+    .validate()
+    .is_err());
+    assert!(task(
+        "0 0 4 * * *",
+        ScheduledAction::Command {
+            command: "say hi\u{1b}stop".to_string()
+        }
+    )
+    .validate()
+    .is_err());
+    let stage: ScheduledAction = serde_json::from_str(r#"{"type":"StageModUpdates"}"#).unwrap();
+    assert_eq!(
+        stage,
+        ScheduledAction::StageModUpdates { auto_apply: false }
+    );
+    // a year long past
+    assert!(task("0 0 4 1 1 * 2000", ScheduledAction::Backup)
+        .validate()
+        .is_err());
+}
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tracing::{info, warn};
+use ts_rs::TS;
+
+use crate::backup::incremental::path_to_trees;
+use crate::backup::path_to_backups;
+use crate::port_manager::PortManager;
+use crate::prelude::{
+    path_to_binaries, path_to_instances, path_to_stores, path_to_tmp, GameInstance,
+};
+use crate::traits::t_configurable::TConfigurable;
+use crate::types::{DotLodestoneConfig, InstanceUuid};
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum CoreIssueKind {
+    /// a directory the core needs didn't exist
+    MissingDirectory,
+    /// left behind by a job the core was interrupted in
+    LeftoverTempFiles,
+    /// an instance directory without a `.lodestone_config`
+    MissingInstanceConfig,
+    /// a `.lodestone_config` that couldn't be parsed
+    InvalidInstanceConfig,
+    /// the config parsed but the instance failed to load, the logs have the reason
+    InstanceNotRestored,
+    /// an instance loaded on the last startup whose directory is gone
+    InstanceDirectoryMissing { instance: InstanceUuid },
+    /// a port held in the allocator with no instance using it
+    OrphanedPort { port: u32 },
+    /// instances configured with the same port, only one of them can run at a time
+    PortConflict {
+        port: u32,
+        instances: Vec<InstanceUuid>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct CoreIssue {
+    pub kind: CoreIssueKind,
+    pub message: String,
+    pub path: Option<String>,
+    /// fixed by the check itself, reported so the fix isn't a surprise
+    pub repaired: bool,
+}
+
+impl CoreIssue {
+    fn new(kind: CoreIssueKind, message: String, path: Option<&Path>, repaired: bool) -> Self {
+        Self {
+            kind,
+            message,
+            path: path.map(|path| path.display().to_string()),
+            repaired,
+        }
+    }
+}
+
+/// Recreates the directories the core can't work without
+fn check_directories(issues: &mut Vec<CoreIssue>) {
+    for dir in [
+        path_to_instances(),
+        path_to_binaries(),
+        path_to_stores(),
+        path_to_tmp(),
+    ] {
+        if dir.is_dir() {
+            continue;
+        }
+        let repaired = std::fs::create_dir_all(dir).is_ok();
+        issues.push(CoreIssue::new(
+            CoreIssueKind::MissingDirectory,
+            if repaired {
+                format!("{} was missing and has been created", dir.display())
+            } else {
+                format!("{} is missing and could not be created", dir.display())
+            },
+            Some(dir),
+            repaired,
+        ));
+    }
+}
+
+/// Temporary files of jobs that can't be resumed, found in `path_to_tmp` and in the
+/// `backups` directory of each instance. Incoming migrations are kept, their staged
+/// files let a retried migration skip what was transferred already.
+fn leftover_temp_files(instance_dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    let list = |dir: &Path| -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+            .unwrap_or_default()
+    };
+    ret.extend(
+        list(path_to_tmp())
+            .into_iter()
+            .filter(|path| path.file_name().map_or(true, |name| name != "migrations")),
+    );
+    for instance_dir in instance_dirs {
+        let backups_dir = path_to_backups(instance_dir);
+        for dir in [path_to_trees(&backups_dir), backups_dir] {
+            ret.extend(list(&dir).into_iter().filter(|path| {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                // archives and trees in progress, and the temporary directories of restores
+                name.ends_with(".partial") || (name.starts_with(".tmp") && path.is_dir())
+            }));
+        }
+    }
+    ret
+}
+
+fn check_temp_files(instance_dirs: &[PathBuf], issues: &mut Vec<CoreIssue>) {
+    for path in leftover_temp_files(instance_dirs) {
+        let res = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        issues.push(CoreIssue::new(
+            CoreIssueKind::LeftoverTempFiles,
+            match &res {
+                Ok(_) => "Removed files left by an interrupted job".to_string(),
+                Err(e) => format!("Failed to remove files left by an interrupted job: {}", e),
+            },
+            Some(&path),
+            res.is_ok(),
+        ));
+    }
+}
+
+/// Instance directories that didn't turn into an instance
+async fn check_instances(
+    instances: &HashMap<InstanceUuid, GameInstance>,
+    instance_dirs: &[PathBuf],
+    issues: &mut Vec<CoreIssue>,
+) {
+    let mut loaded = HashSet::new();
+    for instance in instances.values() {
+        loaded.insert(instance.path().await);
+    }
+    for dir in instance_dirs {
+        if loaded.contains(dir) {
+            continue;
+        }
+        let config_path = dir.join(".lodestone_config");
+        if !config_path.is_file() {
+            issues.push(CoreIssue::new(
+                CoreIssueKind::MissingInstanceConfig,
+                "Instance directory has no .lodestone_config, restore it from a backup or remove the directory".to_string(),
+                Some(dir),
+                false,
+            ));
+            continue;
+        }
+        let parsed = std::fs::read_to_string(&config_path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| {
+                serde_json::from_str::<DotLodestoneConfig>(&s).map_err(|e| e.to_string())
+            });
+        issues.push(match parsed {
+            Err(e) => CoreIssue::new(
+                CoreIssueKind::InvalidInstanceConfig,
+                format!("Could not parse .lodestone_config: {}", e),
+                Some(&config_path),
+                false,
+            ),
+            Ok(_) => CoreIssue::new(
+                CoreIssueKind::InstanceNotRestored,
+                "Instance could not be loaded, see the core logs for the reason".to_string(),
+                Some(dir),
+                false,
+            ),
+        });
+    }
+}
+
+/// Instances recorded on the last startup that have disappeared since
+fn check_registry(
+    instances: &HashMap<InstanceUuid, GameInstance>,
+    registry: &HashMap<InstanceUuid, PathBuf>,
+    issues: &mut Vec<CoreIssue>,
+) {
+    let mut missing: Vec<_> = registry
+        .iter()
+        .filter(|(uuid, path)| !instances.contains_key(*uuid) && !path.exists())
+        .collect();
+    missing.sort_by(|a, b| a.1.cmp(b.1));
+    for (uuid, path) in missing {
+        issues.push(CoreIssue::new(
+            CoreIssueKind::InstanceDirectoryMissing {
+                instance: uuid.clone(),
+            },
+            "The directory of an instance was removed outside of Lodestone, restore it from a backup if that wasn't intended".to_string(),
+            Some(path),
+            false,
+        ));
+    }
+}
+
+async fn check_ports(
+    instances: &HashMap<InstanceUuid, GameInstance>,
+    port_manager: &mut PortManager,
+    issues: &mut Vec<CoreIssue>,
+) {
+    let mut uuids_by_port: HashMap<u32, Vec<InstanceUuid>> = HashMap::new();
+    for (uuid, instance) in instances.iter() {
+        uuids_by_port
+            .entry(instance.port().await)
+            .or_default()
+            .push(uuid.clone());
+    }
+    for port in port_manager.allocated_ports() {
+        if !uuids_by_port.contains_key(&port) {
+            port_manager.deallocate(port);
+            issues.push(CoreIssue::new(
+                CoreIssueKind::OrphanedPort { port },
+                format!(
+                    "Port {} was allocated to no instance and has been freed",
+                    port
+                ),
+                None,
+                true,
+            ));
+        }
+    }
+    let mut conflicts: Vec<_> = uuids_by_port
+        .into_iter()
+        .filter(|(_, uuids)| uuids.len() > 1)
+        .collect();
+    conflicts.sort_by_key(|(port, _)| *port);
+    for (port, instances) in conflicts {
+        let message = format!(
+            "{} instances use port {}, change the port of all but one of them",
+            instances.len(),
+            port
+        );
+        issues.push(CoreIssue::new(
+            CoreIssueKind::PortConflict { port, instances },
+            message,
+            None,
+            false,
+        ));
+    }
+}
+
+/// Runs the integrity checks of the core, fixing what can be fixed safely. Meant to
+/// run once on startup, before any job could be using the temporary files it removes.
+/// `registry` is the instances recorded in the state store on the last startup.
+pub async fn self_check(
+    instances: &HashMap<InstanceUuid, GameInstance>,
+    registry: &HashMap<InstanceUuid, PathBuf>,
+    port_manager: &mut PortManager,
+) -> Vec<CoreIssue> {
+    let mut issues = Vec::new();
+    check_directories(&mut issues);
+    let instance_dirs: Vec<PathBuf> = std::fs::read_dir(path_to_instances())
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|path| path.is_dir())
+                .collect()
+        })
+        .unwrap_or_default();
+    check_temp_files(&instance_dirs, &mut issues);
+    check_instances(instances, &instance_dirs, &mut issues).await;
+    check_registry(instances, registry, &mut issues);
+    check_ports(instances, port_manager, &mut issues).await;
+    for issue in issues.iter() {
+        if issue.repaired {
+            info!("Self check: {}", issue.message);
+        } else {
+            warn!(
+                "Self check: {}{}",
+                issue.message,
+                issue
+                    .path
+                    .as_ref()
+                    .map(|path| format!(" ({})", path))
+                    .unwrap_or_default()
+            );
+        }
+    }
+    issues
+}
+
+#[test]
+fn test_check_ports() {
+    let mut port_manager = PortManager::new(HashSet::from([25565, 25566]));
+    let mut issues = Vec::new();
+    futures::executor::block_on(check_ports(&HashMap::new(), &mut port_manager, &mut issues));
+    assert_eq!(issues.len(), 2);
+    assert!(issues.iter().all(|issue| issue.repaired));
+    assert!(port_manager.allocated_ports().is_empty());
+}
+
+#[test]
+fn test_check_registry() {
+    let dir = tempfile::tempdir().unwrap();
+    let registry = HashMap::from([
+        (
+            InstanceUuid::from("kept".to_string()),
+            dir.path().to_owned(),
+        ),
+        (
+            InstanceUuid::from("gone".to_string()),
+            dir.path().join("gone"),
+        ),
+    ]);
+    let mut issues = Vec::new();
+    check_registry(&HashMap::new(), &registry, &mut issues);
+    assert_eq!(issues.len(), 1);
+    assert_eq!(
+        issues[0].kind,
+        CoreIssueKind::InstanceDirectoryMissing {
+            instance: InstanceUuid::from("gone".to_string())
+        }
+    );
+}
@@ -0,0 +1,381 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::util::dont_spawn_terminal;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum ServiceManager {
+    Systemd,
+    Launchd,
+    /// a scheduled task run at boot, the core can't answer the service control
+    /// manager so it can't be a Windows service proper
+    WindowsTask,
+}
+
+impl ServiceManager {
+    pub fn detect() -> Option<Self> {
+        match std::env::consts::OS {
+            "linux" if Path::new("/run/systemd/system").is_dir() => Some(Self::Systemd),
+            "macos" => Some(Self::Launchd),
+            "windows" => Some(Self::WindowsTask),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, TS, Default)]
+#[ts(export)]
+pub struct InstallService {
+    /// the account the core runs as, the one running it now if left empty. Ignored by
+    /// the Windows task, which runs as SYSTEM.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// only generate the service, to review it or install it by hand
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize, TS)]
+#[ts(export)]
+pub struct InstalledService {
+    pub manager: ServiceManager,
+    /// where the service definition is, or would be, installed
+    pub path: String,
+    pub content: String,
+    pub installed: bool,
+    /// what to do next, the core running now isn't the one the service starts
+    pub next_steps: Vec<String>,
+}
+
+const SERVICE_NAME: &str = "lodestone";
+const LAUNCHD_LABEL: &str = "cc.lodestone.core";
+
+fn quote(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
+
+/// Restarts the core when it crashes but not when it was stopped. The core tells
+/// systemd when it's ready and pings the watchdog, so a hung core is restarted too.
+pub fn systemd_unit(exe: &Path, lodestone_path: &Path, user: Option<&str>) -> String {
+    let mut unit = format!(
+        "[Unit]
+Description=Lodestone Core
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={} --is-cli --lodestone-path {}
+WorkingDirectory={}
+Restart=on-failure
+RestartSec=5
+WatchdogSec=60
+# instances are stopped gracefully on SIGTERM, which can take a while
+TimeoutStopSec=120
+KillMode=mixed
+",
+        quote(exe),
+        quote(lodestone_path),
+        lodestone_path.display(),
+    );
+    if let Some(user) = user {
+        unit.push_str(&format!("User={}\n", user));
+    }
+    unit.push_str("\n[Install]\nWantedBy=multi-user.target\n");
+    unit
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Kept alive unless it exited cleanly, launchd has no readiness or watchdog
+pub fn launchd_plist(exe: &Path, lodestone_path: &Path, user: Option<&str>) -> String {
+    let exe = xml_escape(&exe.display().to_string());
+    let lodestone_path = xml_escape(&lodestone_path.display().to_string());
+    let user = user
+        .map(|user| {
+            format!(
+                "    <key>UserName</key>\n    <string>{}</string>\n",
+                xml_escape(user)
+            )
+        })
+        .unwrap_or_default();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{exe}</string>
+        <string>--is-cli</string>
+        <string>--lodestone-path</string>
+        <string>{lodestone_path}</string>
+    </array>
+    <key>WorkingDirectory</key>
+    <string>{lodestone_path}</string>
+{user}    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>5</integer>
+</dict>
+</plist>
+"#
+    )
+}
+
+/// Arguments of the `schtasks` call registering the core to start at boot
+pub fn windows_task_args(exe: &Path, lodestone_path: &Path) -> Vec<String> {
+    vec![
+        "/Create".to_string(),
+        "/F".to_string(),
+        "/TN".to_string(),
+        "Lodestone Core".to_string(),
+        "/SC".to_string(),
+        "ONSTART".to_string(),
+        "/RU".to_string(),
+        "SYSTEM".to_string(),
+        "/RL".to_string(),
+        "HIGHEST".to_string(),
+        "/TR".to_string(),
+        format!(
+            "{} --is-cli --lodestone-path {}",
+            quote(exe),
+            quote(lodestone_path)
+        ),
+    ]
+}
+
+async fn run(program: &str, args: &[String]) -> Result<(), Error> {
+    let output = dont_spawn_terminal(Command::new(program).args(args))
+        .output()
+        .await
+        .context(format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn write_definition(path: &Path, content: &str) -> Result<(), Error> {
+    tokio::fs::write(path, content).await.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            Error {
+                kind: ErrorKind::PermissionDenied,
+                source: eyre!(
+                    "The core isn't allowed to write {}, run the install as an administrator or install the generated file by hand",
+                    path.display()
+                ),
+            }
+        } else {
+            eyre!("Failed to write {}: {}", path.display(), e).into()
+        }
+    })
+}
+
+/// Generates a service starting this core's binary on this data directory at boot
+/// and, unless it's a dry run, installs and enables it. The service isn't started,
+/// the core running now would have to be stopped first.
+pub async fn install_service(
+    lodestone_path: &Path,
+    request: InstallService,
+) -> Result<InstalledService, Error> {
+    let manager = ServiceManager::detect().ok_or_else(|| Error {
+        kind: ErrorKind::UnsupportedOperation,
+        source: eyre!("No supported service manager found, only systemd, launchd and the Windows task scheduler are"),
+    })?;
+    let exe = std::env::current_exe().context("Failed to find the core's executable")?;
+    let user = request
+        .user
+        .or_else(|| std::env::var("USER").ok())
+        .filter(|user| !user.is_empty() && user != "root");
+    if let Some(user) = &user {
+        if user.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Invalid user name"),
+            });
+        }
+    }
+    let (path, content, next_steps) = match manager {
+        ServiceManager::Systemd => (
+            PathBuf::from(format!("/etc/systemd/system/{}.service", SERVICE_NAME)),
+            systemd_unit(&exe, lodestone_path, user.as_deref()),
+            vec![
+                "Stop the core running now".to_string(),
+                format!("sudo systemctl start {}", SERVICE_NAME),
+                format!("journalctl -u {} -f to follow its logs", SERVICE_NAME),
+            ],
+        ),
+        ServiceManager::Launchd => (
+            PathBuf::from(format!("/Library/LaunchDaemons/{}.plist", LAUNCHD_LABEL)),
+            launchd_plist(&exe, lodestone_path, user.as_deref()),
+            vec![
+                "Stop the core running now".to_string(),
+                format!("sudo launchctl kickstart system/{}", LAUNCHD_LABEL),
+            ],
+        ),
+        ServiceManager::WindowsTask => (
+            PathBuf::from("Lodestone Core"),
+            format!(
+                "schtasks {}",
+                windows_task_args(&exe, lodestone_path).join(" ")
+            ),
+            vec![
+                "Stop the core running now".to_string(),
+                "schtasks /Run /TN \"Lodestone Core\"".to_string(),
+            ],
+        ),
+    };
+    if request.dry_run {
+        return Ok(InstalledService {
+            manager,
+            path: path.display().to_string(),
+            content,
+            installed: false,
+            next_steps,
+        });
+    }
+    match manager {
+        ServiceManager::Systemd => {
+            write_definition(&path, &content).await?;
+            run("systemctl", &["daemon-reload".to_string()]).await?;
+            run(
+                "systemctl",
+                &["enable".to_string(), SERVICE_NAME.to_string()],
+            )
+            .await?;
+        }
+        ServiceManager::Launchd => {
+            write_definition(&path, &content).await?;
+            run(
+                "launchctl",
+                &[
+                    "bootstrap".to_string(),
+                    "system".to_string(),
+                    path.display().to_string(),
+                ],
+            )
+            .await?;
+        }
+        ServiceManager::WindowsTask => {
+            run("schtasks", &windows_task_args(&exe, lodestone_path)).await?;
+        }
+    }
+    info!("Installed the {:?} service at {}", manager, path.display());
+    Ok(InstalledService {
+        manager,
+        path: path.display().to_string(),
+        content,
+        installed: true,
+        next_steps,
+    })
+}
+
+/// Sends a state to systemd if it started the core with `Type=notify`. Does nothing
+/// otherwise, or for abstract sockets which std can't address on every toolchain.
+pub fn sd_notify(state: &str) {
+    #[cfg(unix)]
+    {
+        let socket = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(socket) => PathBuf::from(socket),
+            None => return,
+        };
+        if socket.to_string_lossy().starts_with('@') {
+            debug!("Abstract notify sockets aren't supported");
+            return;
+        }
+        let res = std::os::unix::net::UnixDatagram::unbound()
+            .and_then(|datagram| datagram.send_to(state.as_bytes(), &socket));
+        if let Err(e) = res {
+            warn!("Failed to notify systemd: {}", e);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Resolves when the core is asked to stop by SIGTERM, as service managers do
+pub async fn terminate_signal() {
+    #[cfg(unix)]
+    {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+                return;
+            }
+            Err(e) => warn!("Failed to listen for SIGTERM: {}", e),
+        }
+    }
+    futures::future::pending::<()>().await
+}
+
+/// How often to ping the watchdog, half the timeout systemd gave us
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // meant for another process if the pid is set and isn't ours
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid != std::process::id().to_string() {
+            return None;
+        }
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Tells systemd the core is alive for as long as it runs, if it set a watchdog.
+/// Runs in the main loop, so a core too stuck to poll it gets restarted.
+pub async fn watchdog_task() {
+    match watchdog_interval() {
+        Some(interval) => {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                sd_notify("WATCHDOG=1");
+            }
+        }
+        None => futures::future::pending::<()>().await,
+    }
+}
+
+#[test]
+fn test_service_definitions() {
+    let exe = Path::new("/opt/lodestone/main");
+    let lodestone_path = Path::new("/home/steve/.lodestone");
+    let unit = systemd_unit(exe, lodestone_path, Some("steve"));
+    assert!(unit.contains(
+        "ExecStart=\"/opt/lodestone/main\" --is-cli --lodestone-path \"/home/steve/.lodestone\"\n"
+    ));
+    assert!(unit.contains("Type=notify\n"));
+    assert!(unit.contains("User=steve\n"));
+    assert!(!systemd_unit(exe, lodestone_path, None).contains("User="));
+
+    let plist = launchd_plist(exe, Path::new("/Users/a&b/.lodestone"), None);
+    assert!(plist.contains("<string>/Users/a&amp;b/.lodestone</string>"));
+    assert!(!plist.contains("UserName"));
+}
@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::events::CausedBy;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+use crate::AppState;
+
+/// How the instances set to auto start are brought up when the core boots, so a
+/// host with many of them isn't crushed by every JVM starting at once
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct StartQueueSettings {
+    /// instances allowed to be starting at the same time
+    pub max_concurrent: u32,
+    /// seconds between two starts, even when a slot is free
+    pub delay_secs: u64,
+    /// seconds an instance may take to start before its slot goes to the next one
+    pub start_timeout_secs: u64,
+}
+
+impl Default for StartQueueSettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 2,
+            delay_secs: 5,
+            start_timeout_secs: 300,
+        }
+    }
+}
+
+impl StartQueueSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_concurrent == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("At least one instance must be allowed to start at a time"),
+            });
+        }
+        if self.delay_secs > 600 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Delay between starts can be at most 10 minutes"),
+            });
+        }
+        if self.start_timeout_secs < 10 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Start timeout must be at least 10 seconds"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Where an instance goes in the start queue, kept in the instance's directory
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default, PartialEq, Eq)]
+#[ts(export)]
+pub struct AutoStartPriority {
+    /// higher starts first, instances with the same priority start oldest first
+    pub priority: i32,
+}
+
+impl AutoStartPriority {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_auto_start.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse auto start priority at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_auto_start.json"),
+            serde_json::to_string_pretty(self)
+                .context("Failed to serialize auto start priority")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(-100..=100).contains(&self.priority) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Auto start priority must be between -100 and 100"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Highest priority first, then oldest first
+fn start_order(instances: &mut [(i32, i64, InstanceUuid)]) {
+    instances.sort_by(|(a_priority, a_created, _), (b_priority, b_created, _)| {
+        b_priority.cmp(a_priority).then(a_created.cmp(b_created))
+    });
+}
+
+/// Instances waiting for their turn to auto start, first in line first
+#[derive(Default)]
+pub struct StartQueue {
+    queued: VecDeque<InstanceUuid>,
+}
+
+impl StartQueue {
+    pub fn push(&mut self, uuid: InstanceUuid) {
+        if !self.queued.contains(&uuid) {
+            self.queued.push_back(uuid);
+        }
+    }
+
+    fn pop(&mut self) -> Option<InstanceUuid> {
+        self.queued.pop_front()
+    }
+
+    /// 1 for the next instance to start, `None` if the instance isn't queued
+    pub fn position(&self, uuid: &InstanceUuid) -> Option<u32> {
+        self.queued
+            .iter()
+            .position(|queued| queued == uuid)
+            .map(|position| position as u32 + 1)
+    }
+
+    pub fn remove(&mut self, uuid: &InstanceUuid) {
+        self.queued.retain(|queued| queued != uuid);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+/// What the start queue drives, instances in the core and fakes in tests
+#[async_trait]
+trait Launcher: Clone + Send + Sync + 'static {
+    /// the next instance in line, `None` once the queue is empty
+    async fn next(&self) -> Option<InstanceUuid>;
+    /// whether the instance was started and now takes a slot
+    async fn start(&self, uuid: &InstanceUuid) -> bool;
+    async fn is_starting(&self, uuid: &InstanceUuid) -> bool;
+    async fn is_queue_empty(&self) -> bool;
+}
+
+#[async_trait]
+impl Launcher for AppState {
+    async fn next(&self) -> Option<InstanceUuid> {
+        self.start_queue.lock().await.pop()
+    }
+
+    async fn start(&self, uuid: &InstanceUuid) -> bool {
+        // deleted or started by hand while it was waiting
+        let mut instance = match self.instances.lock().await.get(uuid) {
+            Some(instance) => instance.clone(),
+            None => return false,
+        };
+        if instance.state().await != State::Stopped {
+            return false;
+        }
+        info!("Auto starting instance {}", instance.name().await);
+        if let Err(e) = instance.start(CausedBy::System, false).await {
+            error!(
+                "Failed to start instance {}: {:?}",
+                instance.name().await,
+                e
+            );
+            return false;
+        }
+        true
+    }
+
+    async fn is_starting(&self, uuid: &InstanceUuid) -> bool {
+        let instance = self.instances.lock().await.get(uuid).cloned();
+        match instance {
+            Some(instance) => instance.state().await == State::Starting,
+            None => false,
+        }
+    }
+
+    async fn is_queue_empty(&self) -> bool {
+        self.start_queue.lock().await.is_empty()
+    }
+}
+
+/// Starts instances until the queue is empty, `max_concurrent` at a time and at
+/// least `delay` apart. An instance holds its slot until it's no longer starting
+/// or `timeout` has passed, checked every `poll`.
+async fn drain_queue<L: Launcher>(
+    launcher: L,
+    max_concurrent: usize,
+    delay: Duration,
+    timeout: Duration,
+    poll: Duration,
+) {
+    let slots = Arc::new(Semaphore::new(max_concurrent));
+    loop {
+        let slot = match slots.clone().acquire_owned().await {
+            Ok(slot) => slot,
+            Err(_) => break,
+        };
+        let uuid = match launcher.next().await {
+            Some(uuid) => uuid,
+            None => break,
+        };
+        if !launcher.start(&uuid).await {
+            continue;
+        }
+        tokio::spawn({
+            let launcher = launcher.clone();
+            async move {
+                let started = tokio::time::timeout(timeout, async {
+                    while launcher.is_starting(&uuid).await {
+                        tokio::time::sleep(poll).await;
+                    }
+                })
+                .await;
+                if started.is_err() {
+                    warn!(
+                        "Instance {} is still starting after {} seconds, starting the next one",
+                        uuid,
+                        timeout.as_secs()
+                    );
+                }
+                drop(slot);
+            }
+        });
+        if launcher.is_queue_empty().await {
+            break;
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Queues the instances set to auto start by their priority, and starts them as
+/// slots free up. An instance holds its slot until it's running, has failed or timed out.
+pub async fn auto_start(state: AppState) {
+    let mut auto_start = Vec::new();
+    for (uuid, instance) in state.instances.lock().await.iter() {
+        if !instance.auto_start().await {
+            continue;
+        }
+        let priority = match AutoStartPriority::load(&instance.path().await).await {
+            Ok(priority) => priority.priority,
+            Err(e) => {
+                warn!(
+                    "Failed to read auto start priority of {}, using the default: {:?}",
+                    instance.name().await,
+                    e
+                );
+                0
+            }
+        };
+        auto_start.push((priority, instance.creation_time().await, uuid.clone()));
+    }
+    if auto_start.is_empty() {
+        return;
+    }
+    start_order(&mut auto_start);
+    {
+        let mut queue = state.start_queue.lock().await;
+        for (_, _, uuid) in auto_start {
+            queue.push(uuid);
+        }
+    }
+    let settings = state.global_settings.lock().await.start_queue();
+    drain_queue(
+        state,
+        settings.max_concurrent as usize,
+        Duration::from_secs(settings.delay_secs),
+        Duration::from_secs(settings.start_timeout_secs),
+        Duration::from_secs(1),
+    )
+    .await;
+}
+
+#[test]
+fn test_start_queue() {
+    let mut queue = StartQueue::default();
+    let a = InstanceUuid::from("a".to_string());
+    let b = InstanceUuid::from("b".to_string());
+    queue.push(a.clone());
+    queue.push(b.clone());
+    queue.push(a.clone());
+    assert_eq!(queue.position(&a), Some(1));
+    assert_eq!(queue.position(&b), Some(2));
+    assert_eq!(queue.pop(), Some(a.clone()));
+    assert_eq!(queue.position(&a), None);
+    assert_eq!(queue.position(&b), Some(1));
+    queue.remove(&b);
+    assert!(queue.is_empty());
+
+    let c = InstanceUuid::from("c".to_string());
+    let mut order = vec![(0, 1, a.clone()), (10, 3, b.clone()), (0, 2, c.clone())];
+    start_order(&mut order);
+    let order: Vec<_> = order.into_iter().map(|(_, _, uuid)| uuid).collect();
+    assert_eq!(order, vec![b, a, c]);
+    assert!(AutoStartPriority { priority: 101 }.validate().is_err());
+
+    assert!(StartQueueSettings::default().validate().is_ok());
+    assert!(StartQueueSettings {
+        max_concurrent: 0,
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+}
+
+/// Records when each instance starts, and stays starting for `starting_for`
+#[cfg(test)]
+#[derive(Clone)]
+struct FakeLauncher {
+    queue: Arc<std::sync::Mutex<VecDeque<InstanceUuid>>>,
+    started: Arc<std::sync::Mutex<Vec<(InstanceUuid, tokio::time::Instant)>>>,
+    starting_for: Duration,
+    fails: Option<InstanceUuid>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Launcher for FakeLauncher {
+    async fn next(&self) -> Option<InstanceUuid> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    async fn start(&self, uuid: &InstanceUuid) -> bool {
+        if self.fails.as_ref() == Some(uuid) {
+            return false;
+        }
+        self.started
+            .lock()
+            .unwrap()
+            .push((uuid.clone(), tokio::time::Instant::now()));
+        true
+    }
+
+    async fn is_starting(&self, uuid: &InstanceUuid) -> bool {
+        self.started
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(started, at)| started == uuid && at.elapsed() < self.starting_for)
+    }
+
+    async fn is_queue_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+}
+
+#[tokio::test]
+async fn test_drain_queue() {
+    let uuids: Vec<InstanceUuid> = ["a", "b", "c", "d"]
+        .iter()
+        .map(|id| InstanceUuid::from(id.to_string()))
+        .collect();
+    let launcher = FakeLauncher {
+        queue: Arc::new(std::sync::Mutex::new(uuids.iter().cloned().collect())),
+        started: Arc::new(std::sync::Mutex::new(Vec::new())),
+        starting_for: Duration::from_secs(5),
+        fails: Some(uuids[1].clone()),
+    };
+    let begin = tokio::time::Instant::now();
+    drain_queue(
+        launcher.clone(),
+        2,
+        Duration::from_millis(50),
+        Duration::from_millis(300),
+        Duration::from_millis(10),
+    )
+    .await;
+    let started = launcher.started.lock().unwrap().clone();
+    let order: Vec<_> = started.iter().map(|(uuid, _)| uuid.clone()).collect();
+    // b failed to start and gave its slot straight to c
+    assert_eq!(
+        order,
+        vec![uuids[0].clone(), uuids[2].clone(), uuids[3].clone()]
+    );
+    let millis = |i: usize| (started[i].1 - begin).as_millis();
+    assert!(millis(0) < 50);
+    // the delay applies even though a slot is free
+    assert!((50..300).contains(&millis(1)));
+    // both slots are taken until a times out
+    assert!(millis(2) >= 300);
+}
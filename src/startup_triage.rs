@@ -0,0 +1,296 @@
+use std::collections::{HashMap, VecDeque};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::traits::t_server::State;
+use crate::types::{InstanceUuid, Snowflake};
+use crate::AppState;
+
+/// console lines kept while an instance starts, and attached to the failure
+const LOG_TAIL_LINES: usize = 200;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum StartupFailureKind {
+    PortInUse,
+    WrongJavaVersion,
+    MissingDependency,
+    CorruptedWorld,
+    EulaNotAccepted,
+}
+
+/// Something to do about a failure, for the dashboard to offer
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(tag = "type")]
+#[ts(export)]
+pub enum SuggestedAction {
+    ChangePort,
+    /// pin the instance to a runtime of this major Java version or newer
+    SwitchJava {
+        major: u64,
+    },
+    InstallMod {
+        mod_id: String,
+    },
+    RestoreBackup,
+    AcceptEula,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct StartupDiagnosis {
+    pub kind: StartupFailureKind,
+    pub summary: String,
+    /// the log line that gave it away
+    pub evidence: String,
+    pub suggested_actions: Vec<SuggestedAction>,
+}
+
+lazy_static! {
+    static ref PORT_IN_USE: Regex =
+        Regex::new(r"(?i)failed to bind to port|address already in use").unwrap();
+    /// class file versions are the Java major plus 44
+    static ref CLASS_VERSION: Regex = Regex::new(
+        r"UnsupportedClassVersionError.*class file version (\d+)\.\d+.*up to (\d+)\.\d+"
+    )
+    .unwrap();
+    static ref REQUIRES_JAVA: Regex =
+        Regex::new(r"(?i)(?:requires|needs) java (\d+)(?: or (?:above|newer|later))?").unwrap();
+    /// Fabric: `requires version 0.88 or later of mod 'Fabric API' (fabric-api), which is missing!`
+    static ref FABRIC_MISSING: Regex = Regex::new(
+        r"requires .*?(?:\(([\w.-]+)\)|of (?:mod )?'?([\w.-]+)'?),? which is missing"
+    )
+    .unwrap();
+    /// Forge: `Mod ID: 'geckolib', Requested by: 'x', Expected range: '[4.2,)', Actual version: '[MISSING]'`
+    static ref FORGE_MISSING: Regex =
+        Regex::new(r"Mod ID: '([\w.-]+)'.*Actual version: '\[MISSING\]'").unwrap();
+    static ref CORRUPTED_WORLD: Regex = Regex::new(
+        r"(?i)failed to load (?:level|world)|exception reading .*level\.dat|couldn't load (?:chunk|region)|error loading world|level\.dat.*(?:corrupt|invalid)"
+    )
+    .unwrap();
+    static ref EULA: Regex = Regex::new(r"(?i)you need to agree to the eula").unwrap();
+}
+
+fn diagnosis(
+    kind: StartupFailureKind,
+    summary: String,
+    evidence: &str,
+    suggested_actions: Vec<SuggestedAction>,
+) -> StartupDiagnosis {
+    StartupDiagnosis {
+        kind,
+        summary,
+        evidence: evidence.trim().to_string(),
+        suggested_actions,
+    }
+}
+
+/// The diagnosis of one log line, if it matches a known failure
+fn diagnose_line(line: &str) -> Option<StartupDiagnosis> {
+    if PORT_IN_USE.is_match(line) {
+        return Some(diagnosis(
+            StartupFailureKind::PortInUse,
+            "The port is already in use, another server may be running on it".to_string(),
+            line,
+            vec![SuggestedAction::ChangePort],
+        ));
+    }
+    if let Some(captures) = CLASS_VERSION.captures(line) {
+        let needed: u64 = captures[1].parse().ok()?;
+        let running: u64 = captures[2].parse().ok()?;
+        return Some(diagnosis(
+            StartupFailureKind::WrongJavaVersion,
+            format!(
+                "The server needs Java {} but runs on Java {}",
+                needed.saturating_sub(44),
+                running.saturating_sub(44)
+            ),
+            line,
+            vec![SuggestedAction::SwitchJava {
+                major: needed.saturating_sub(44),
+            }],
+        ));
+    }
+    if let Some(captures) = REQUIRES_JAVA.captures(line) {
+        let major = captures[1].parse().ok()?;
+        return Some(diagnosis(
+            StartupFailureKind::WrongJavaVersion,
+            format!("The server needs Java {}", major),
+            line,
+            vec![SuggestedAction::SwitchJava { major }],
+        ));
+    }
+    let missing = FABRIC_MISSING
+        .captures(line)
+        .and_then(|captures| captures.get(1).or_else(|| captures.get(2)))
+        .or_else(|| {
+            FORGE_MISSING
+                .captures(line)
+                .and_then(|captures| captures.get(1))
+        });
+    if let Some(mod_id) = missing {
+        let mod_id = mod_id.as_str().to_string();
+        return Some(diagnosis(
+            StartupFailureKind::MissingDependency,
+            format!("A mod needs {}, which isn't installed", mod_id),
+            line,
+            vec![SuggestedAction::InstallMod { mod_id }],
+        ));
+    }
+    if CORRUPTED_WORLD.is_match(line) {
+        return Some(diagnosis(
+            StartupFailureKind::CorruptedWorld,
+            "The world could not be loaded, it may be corrupted".to_string(),
+            line,
+            vec![SuggestedAction::RestoreBackup],
+        ));
+    }
+    if EULA.is_match(line) {
+        return Some(diagnosis(
+            StartupFailureKind::EulaNotAccepted,
+            "The Minecraft EULA has not been accepted".to_string(),
+            line,
+            vec![SuggestedAction::AcceptEula],
+        ));
+    }
+    None
+}
+
+/// Every known failure in the log, once each. Missing dependencies are listed
+/// per mod.
+pub fn diagnose(log: &[String]) -> Vec<StartupDiagnosis> {
+    let mut diagnoses: Vec<StartupDiagnosis> = Vec::new();
+    for diagnosis in log.iter().filter_map(|line| diagnose_line(line)) {
+        let seen = diagnoses.iter().any(|seen| {
+            seen.kind == diagnosis.kind
+                && (diagnosis.kind != StartupFailureKind::MissingDependency
+                    || seen.suggested_actions == diagnosis.suggested_actions)
+        });
+        if !seen {
+            diagnoses.push(diagnosis);
+        }
+    }
+    diagnoses
+}
+
+/// Keeps the console of starting instances and, when one stops before it got
+/// running, sends a `StartupFailed` event with what went wrong
+pub async fn startup_triage_task(state: AppState) {
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut last_states: HashMap<InstanceUuid, State> = HashMap::new();
+    let mut logs: HashMap<InstanceUuid, VecDeque<String>> = HashMap::new();
+    loop {
+        let (uuid, instance_name, inner) = match event_receiver.recv().await {
+            Ok(event) => match event.event_inner {
+                EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid,
+                    instance_name,
+                    instance_event_inner,
+                }) => (instance_uuid, instance_name, instance_event_inner),
+                _ => continue,
+            },
+            Err(RecvError::Lagged(_)) => {
+                warn!("Startup triage task lagged");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let to = match inner {
+            InstanceEventInner::InstanceOutput { message } => {
+                if let Some(log) = logs.get_mut(&uuid) {
+                    if log.len() == LOG_TAIL_LINES {
+                        log.pop_front();
+                    }
+                    log.push_back(message);
+                }
+                continue;
+            }
+            InstanceEventInner::StateTransition { to } => to,
+            _ => continue,
+        };
+        let previous = last_states.insert(uuid.clone(), to);
+        match to {
+            State::Starting => {
+                logs.insert(uuid, VecDeque::new());
+            }
+            State::Stopped | State::Error if previous == Some(State::Starting) => {
+                let log_tail: Vec<String> = logs.remove(&uuid).unwrap_or_default().into();
+                let diagnoses = diagnose(&log_tail);
+                state.event_broadcaster.send(Event {
+                    details: match diagnoses.first() {
+                        Some(diagnosis) => {
+                            format!("{} failed to start: {}", instance_name, diagnosis.summary)
+                        }
+                        None => format!("{} failed to start", instance_name),
+                    },
+                    snowflake: Snowflake::default(),
+                    event_inner: EventInner::InstanceEvent(InstanceEvent {
+                        instance_uuid: uuid,
+                        instance_name,
+                        instance_event_inner: InstanceEventInner::StartupFailed {
+                            diagnoses,
+                            log_tail,
+                        },
+                    }),
+                    caused_by: CausedBy::System,
+                });
+            }
+            _ => {
+                logs.remove(&uuid);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_diagnose_startup_failures() {
+    let log: Vec<String> = [
+        "[Server thread/WARN]: **** FAILED TO BIND TO PORT!",
+        "[Server thread/WARN]: The exception was: java.net.BindException: Address already in use",
+        "Exception in thread \"main\" java.lang.UnsupportedClassVersionError: net/minecraft/server/Main has been compiled by a more recent version of the Java Runtime (class file version 65.0), this version of the Java Runtime only recognizes class file versions up to 61.0",
+        " - Mod 'Sodium Extra' (sodium-extra) 0.5.1 requires any version of mod 'sodium', which is missing!",
+        " - Mod 'Lithium' (lithium) 0.11.2 requires version 0.88 or later of mod 'Fabric API' (fabric-api), which is missing!",
+        "\tMod ID: 'geckolib', Requested by: 'mowziesmobs', Expected range: '[4.2,)', Actual version: '[MISSING]'",
+        "[Server thread/ERROR]: Exception reading ./world/level.dat",
+        "[main/INFO]: You need to agree to the EULA in order to run the server. Go to eula.txt for more info.",
+        "[Server thread/INFO]: Done (3.2s)! For help, type \"help\"",
+    ]
+    .iter()
+    .map(|line| line.to_string())
+    .collect();
+    let diagnoses = diagnose(&log);
+    let actions: Vec<SuggestedAction> = diagnoses
+        .iter()
+        .flat_map(|diagnosis| diagnosis.suggested_actions.clone())
+        .collect();
+    assert_eq!(
+        actions,
+        vec![
+            SuggestedAction::ChangePort,
+            SuggestedAction::SwitchJava { major: 21 },
+            SuggestedAction::InstallMod {
+                mod_id: "sodium".to_string()
+            },
+            SuggestedAction::InstallMod {
+                mod_id: "fabric-api".to_string()
+            },
+            SuggestedAction::InstallMod {
+                mod_id: "geckolib".to_string()
+            },
+            SuggestedAction::RestoreBackup,
+            SuggestedAction::AcceptEula,
+        ]
+    );
+    assert_eq!(
+        diagnoses[1].summary,
+        "The server needs Java 21 but runs on Java 17"
+    );
+    assert!(diagnose(&["[Server thread/INFO]: Starting minecraft server".to_string()]).is_empty());
+}
@@ -0,0 +1,62 @@
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Spans exported over OTLP: the core's own, plus the per-request spans of the
+/// HTTP trace layer so a handler can be followed into the instance it touched
+const EXPORT_FILTER: &str = "lodestone_core=debug,tower_http=debug";
+
+/// Exports spans to an OTLP collector over gRPC when an endpoint is configured,
+/// e.g. `http://localhost:4317`. Nothing is exported otherwise.
+pub fn otlp_layer<S>(endpoint: Option<String>) -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = endpoint.filter(|endpoint| !endpoint.trim().is_empty())?;
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint.clone()),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", "lodestone_core"),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])))
+        .install_batch(opentelemetry::runtime::Tokio);
+    match tracer {
+        Ok(tracer) => {
+            // the subscriber isn't set up yet, so this can't go through tracing
+            println!("Exporting traces to {}", endpoint);
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(EnvFilter::from(EXPORT_FILTER)),
+            )
+        }
+        Err(e) => {
+            eprintln!("Failed to set up trace export to {}: {}", endpoint, e);
+            None
+        }
+    }
+}
+
+/// Sends out the spans still waiting in the batch exporter
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+#[tokio::test]
+async fn test_otlp_layer() {
+    type Registry = tracing_subscriber::Registry;
+    assert!(otlp_layer::<Registry>(None).is_none());
+    assert!(otlp_layer::<Registry>(Some("  ".to_string())).is_none());
+    // a collector that isn't up yet is fine, the exporter connects lazily
+    assert!(otlp_layer::<Registry>(Some("http://localhost:4317".to_string())).is_some());
+    assert!(otlp_layer::<Registry>(Some("not a uri".to_string())).is_none());
+    shutdown();
+}
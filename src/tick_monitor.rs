@@ -0,0 +1,424 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use fancy_regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::console_policy::validate_command;
+use crate::error::{Error, ErrorKind};
+use crate::events::{CausedBy, Event, EventInner, InstanceEvent, InstanceEventInner};
+use crate::implementations::minecraft::line_parser::parse_system_msg;
+use crate::traits::t_configurable::{Game, TConfigurable};
+use crate::traits::t_server::{State, TServer};
+use crate::types::{InstanceUuid, Snowflake};
+use crate::AppState;
+
+/// tick timings older than this are no longer shown
+const STATS_STALE_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq)]
+#[ts(export)]
+pub struct TickMonitorSettings {
+    /// a `PerformanceDegraded` event is sent when TPS drops below this
+    pub degraded_tps: f32,
+    /// sent to running Minecraft instances to get their tick timings into the
+    /// log, like `tps` on Paper or `tick query` on 1.20.3+. `None` only picks up
+    /// timings someone else asked for.
+    pub poll_command: Option<String>,
+    pub poll_interval_secs: u64,
+}
+
+impl Default for TickMonitorSettings {
+    fn default() -> Self {
+        Self {
+            degraded_tps: 15.0,
+            poll_command: None,
+            poll_interval_secs: 60,
+        }
+    }
+}
+
+impl TickMonitorSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !(self.degraded_tps > 0.0 && self.degraded_tps <= 100.0) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("The degraded TPS threshold must be between 0 and 100"),
+            });
+        }
+        if let Some(poll_command) = &self.poll_command {
+            validate_command(poll_command)?;
+        }
+        if !(10..=3600).contains(&self.poll_interval_secs) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Poll interval must be between 10 seconds and an hour"),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Tick timings read from one line, either may be missing
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TickSample {
+    pub tps: Option<f32>,
+    /// milliseconds per tick
+    pub mspt: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Expecting {
+    SparkTps,
+    SparkDurations,
+    TickQuery { target_rate: f32 },
+}
+
+fn first_number(text: &str) -> Option<f32> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .find(|part| !part.is_empty())?
+        .trim_start_matches('*')
+        .parse()
+        .ok()
+}
+
+/// Tick timings from the output of Paper's `tps`, spark's `spark tps`, vanilla's
+/// `tick query` and the "Can't keep up!" watchdog warning. Some of these span
+/// several lines, so a parser is kept per instance.
+#[derive(Debug, Default)]
+pub struct TickParser {
+    expecting: Option<Expecting>,
+}
+
+impl TickParser {
+    pub fn parse(&mut self, line: &str) -> Option<TickSample> {
+        lazy_static! {
+            static ref FORMATTING: Regex = Regex::new(r"\x1b\[[0-9;]*m|§.").unwrap();
+            static ref TARGET_RATE: Regex =
+                Regex::new(r"^Target tick rate: ([\d.]+) per second").unwrap();
+            static ref AVERAGE_TICK: Regex =
+                Regex::new(r"^Average time per tick: ([\d.]+)ms").unwrap();
+            static ref CANT_KEEP_UP: Regex =
+                Regex::new(r"^Can't keep up!.* Running \d+ms or (\d+) ticks behind").unwrap();
+        }
+        let stripped = FORMATTING.replace_all(line, "");
+        let message = parse_system_msg(&stripped).unwrap_or_else(|| stripped.to_string());
+        let message = message.trim();
+        let expecting = self.expecting.take();
+        let capture = |regex: &Regex| -> Option<f32> {
+            regex.captures(message).ok()??.get(1)?.as_str().parse().ok()
+        };
+
+        if let Some(rest) = message.strip_prefix("TPS from last ") {
+            let (_, values) = rest.split_once(':')?;
+            return match first_number(values) {
+                Some(tps) => Some(TickSample {
+                    tps: Some(tps),
+                    mspt: None,
+                }),
+                // spark puts the values on the next line
+                None => {
+                    self.expecting = Some(Expecting::SparkTps);
+                    None
+                }
+            };
+        }
+        if message.starts_with("Tick durations") {
+            self.expecting = Some(Expecting::SparkDurations);
+            return None;
+        }
+        if let Some(target_rate) = capture(&TARGET_RATE) {
+            self.expecting = Some(Expecting::TickQuery { target_rate });
+            return None;
+        }
+        if let Some(mspt) = capture(&AVERAGE_TICK) {
+            let target_rate = match expecting {
+                Some(Expecting::TickQuery { target_rate }) => target_rate,
+                _ => 20.0,
+            };
+            return Some(TickSample {
+                tps: Some(target_rate.min(1000.0 / mspt.max(f32::EPSILON))),
+                mspt: Some(mspt),
+            });
+        }
+        if let Some(ticks_behind) = capture(&CANT_KEEP_UP) {
+            // warned about at most every 15 seconds, so this is only a rough average
+            return Some(TickSample {
+                tps: Some((20.0 - ticks_behind / 15.0).max(0.0)),
+                mspt: None,
+            });
+        }
+        match expecting? {
+            Expecting::SparkTps => first_number(message).map(|tps| TickSample {
+                tps: Some(tps),
+                mspt: None,
+            }),
+            // min/med/95%ile/max of the last 10 seconds, then of the last minute
+            Expecting::SparkDurations => {
+                let median = message.split(';').next()?.split('/').nth(1)?;
+                Some(TickSample {
+                    tps: None,
+                    mspt: Some(median.trim().parse().ok()?),
+                })
+            }
+            Expecting::TickQuery { target_rate } => {
+                self.expecting = Some(Expecting::TickQuery { target_rate });
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct TickStats {
+    pub tps: Option<f32>,
+    pub mspt: Option<f32>,
+    pub updated_at: i64,
+}
+
+/// The latest tick timings of each instance, from its log or its companion
+#[derive(Default)]
+pub struct TickMonitor {
+    stats: HashMap<InstanceUuid, TickStats>,
+    degraded: HashSet<InstanceUuid>,
+}
+
+impl TickMonitor {
+    pub fn latest(&self, uuid: &InstanceUuid, now: i64) -> Option<TickStats> {
+        self.stats
+            .get(uuid)
+            .filter(|stats| now - stats.updated_at < STATS_STALE_SECS)
+            .copied()
+    }
+
+    /// Returns the TPS if it just dropped below `degraded_tps`, it has to recover
+    /// before that's reported again
+    pub fn record(
+        &mut self,
+        uuid: InstanceUuid,
+        sample: TickSample,
+        now: i64,
+        degraded_tps: f32,
+    ) -> Option<f32> {
+        let previous = self.latest(&uuid, now);
+        self.stats.insert(
+            uuid.clone(),
+            TickStats {
+                tps: sample.tps.or(previous.and_then(|stats| stats.tps)),
+                mspt: sample.mspt.or(previous.and_then(|stats| stats.mspt)),
+                updated_at: now,
+            },
+        );
+        let tps = sample.tps?;
+        if tps >= degraded_tps {
+            self.degraded.remove(&uuid);
+            None
+        } else if self.degraded.insert(uuid) {
+            Some(tps)
+        } else {
+            None
+        }
+    }
+
+    pub fn clear(&mut self, uuid: &InstanceUuid) {
+        self.stats.remove(uuid);
+        self.degraded.remove(uuid);
+    }
+}
+
+/// Records tick timings of an instance and sends a `PerformanceDegraded` event
+/// if its TPS just dropped below the threshold
+pub async fn record_tick_sample(state: &AppState, uuid: &InstanceUuid, sample: TickSample) {
+    let degraded_tps = state
+        .global_settings
+        .lock()
+        .await
+        .tick_monitor()
+        .degraded_tps;
+    let tps = match state.tick_monitor.lock().await.record(
+        uuid.clone(),
+        sample,
+        chrono::Utc::now().timestamp(),
+        degraded_tps,
+    ) {
+        Some(tps) => tps,
+        None => return,
+    };
+    let instance_name = match state.instances.lock().await.get(uuid) {
+        Some(instance) => instance.name().await,
+        None => return,
+    };
+    state.event_broadcaster.send(Event {
+        event_inner: EventInner::InstanceEvent(InstanceEvent {
+            instance_uuid: uuid.clone(),
+            instance_name,
+            instance_event_inner: InstanceEventInner::PerformanceDegraded {
+                tps,
+                threshold: degraded_tps,
+            },
+        }),
+        details: format!("TPS dropped to {:.1}", tps),
+        snowflake: Snowflake::default(),
+        caused_by: CausedBy::System,
+    });
+}
+
+/// Reads tick timings from instance output, and sends the poll command to
+/// running Minecraft instances if one is set
+pub async fn tick_monitor_task(state: AppState) {
+    let mut event_receiver = state.event_broadcaster.subscribe();
+    let mut parsers: HashMap<InstanceUuid, TickParser> = HashMap::new();
+    let mut check_interval = tokio::time::interval(Duration::from_secs(10));
+    let mut last_polled = 0;
+    loop {
+        tokio::select! {
+            _ = check_interval.tick() => {
+                let settings = state.global_settings.lock().await.tick_monitor();
+                let poll_command = match settings.poll_command {
+                    Some(poll_command) => poll_command,
+                    None => continue,
+                };
+                let now = chrono::Utc::now().timestamp();
+                if now - last_polled < settings.poll_interval_secs as i64 {
+                    continue;
+                }
+                last_polled = now;
+                let instances = state.instances.lock().await.clone();
+                for (uuid, instance) in instances.iter() {
+                    if !matches!(instance.game_type().await, Game::MinecraftJava { .. })
+                        || instance.state().await != State::Running
+                    {
+                        continue;
+                    }
+                    if let Err(e) = instance.send_command(&poll_command, CausedBy::System).await {
+                        error!("Failed to poll tick timings of instance {}: {}", uuid, e);
+                    }
+                }
+            }
+            event = event_receiver.recv() => {
+                let instance_event = match event {
+                    Ok(Event {
+                        event_inner: EventInner::InstanceEvent(instance_event),
+                        ..
+                    }) => instance_event,
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Tick monitor task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let uuid = instance_event.instance_uuid;
+                match instance_event.instance_event_inner {
+                    InstanceEventInner::InstanceOutput { message } => {
+                        let sample = parsers.entry(uuid.clone()).or_default().parse(&message);
+                        if let Some(sample) = sample {
+                            record_tick_sample(&state, &uuid, sample).await;
+                        }
+                    }
+                    InstanceEventInner::StateTransition { to: State::Stopped } => {
+                        parsers.remove(&uuid);
+                        state.tick_monitor.lock().await.clear(&uuid);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tick_parser() {
+    let mut parser = TickParser::default();
+    assert_eq!(
+        parser.parse("[12:00:00 INFO]: §6TPS from last 1m, 5m, 15m: §a*20.0, §a19.5, §a19.9"),
+        Some(TickSample {
+            tps: Some(20.0),
+            mspt: None,
+        })
+    );
+
+    assert_eq!(
+        parser.parse("[12:00:00 INFO]: TPS from last 5s, 10s, 1m, 5m, 15m:"),
+        None
+    );
+    assert_eq!(
+        parser
+            .parse("[12:00:00 INFO]:  *20.0, 18.2, 19.0, 20.0, 20.0")
+            .unwrap()
+            .tps,
+        Some(20.0)
+    );
+    parser.parse("[12:00:00 INFO]: Tick durations (min/med/95%ile/max ms) from last 10s, 1m:");
+    assert_eq!(
+        parser
+            .parse("[12:00:00 INFO]:  0.8/1.3/2.4/16.3;  0.6/1.3/2.2/53.7")
+            .unwrap()
+            .mspt,
+        Some(1.3)
+    );
+
+    parser.parse("[12:00:00] [Server thread/INFO]: Target tick rate: 20.0 per second.");
+    assert_eq!(
+        parser.parse(
+            "[12:00:00] [Server thread/INFO]: Average time per tick: 100.0ms (Target: 50.0ms)"
+        ),
+        Some(TickSample {
+            tps: Some(10.0),
+            mspt: Some(100.0),
+        })
+    );
+    assert_eq!(
+        parser
+            .parse("[12:00:00] [Server thread/WARN]: Can't keep up! Is the server overloaded? Running 5000ms or 100 ticks behind")
+            .unwrap()
+            .tps,
+        Some(20.0 - 100.0 / 15.0)
+    );
+    assert_eq!(parser.parse("[12:00:00 INFO]: <Steve> 20.0"), None);
+}
+
+#[test]
+fn test_tick_monitor() {
+    let mut monitor = TickMonitor::default();
+    let uuid = InstanceUuid::from("a".to_string());
+    let sample = |tps| TickSample {
+        tps: Some(tps),
+        mspt: None,
+    };
+    assert_eq!(monitor.record(uuid.clone(), sample(19.0), 0, 15.0), None);
+    assert_eq!(
+        monitor.record(uuid.clone(), sample(12.0), 1, 15.0),
+        Some(12.0)
+    );
+    assert_eq!(monitor.record(uuid.clone(), sample(10.0), 2, 15.0), None);
+    monitor.record(
+        uuid.clone(),
+        TickSample {
+            tps: None,
+            mspt: Some(80.0),
+        },
+        3,
+        15.0,
+    );
+    assert_eq!(
+        monitor.latest(&uuid, 3),
+        Some(TickStats {
+            tps: Some(10.0),
+            mspt: Some(80.0),
+            updated_at: 3,
+        })
+    );
+    assert_eq!(monitor.record(uuid.clone(), sample(20.0), 4, 15.0), None);
+    assert_eq!(
+        monitor.record(uuid.clone(), sample(5.0), 5, 15.0),
+        Some(5.0)
+    );
+    assert_eq!(monitor.latest(&uuid, 5 + STATS_STALE_SECS), None);
+}
@@ -10,10 +10,11 @@ use self::t_configurable::Game;
 use self::t_player::Player;
 use self::t_server::State;
 use self::{
-    t_configurable::TConfigurable, t_macro::TMacro, t_player::TPlayerManagement,
+    t_backup::TBackup, t_configurable::TConfigurable, t_macro::TMacro, t_player::TPlayerManagement,
     t_resource::TResourceManagement, t_server::TServer,
 };
 
+pub mod t_backup;
 pub mod t_configurable;
 pub mod t_macro;
 pub mod t_player;
@@ -37,15 +38,61 @@ pub struct InstanceInfo {
     pub player_count: Option<u32>,
     pub max_player_count: Option<u32>,
     pub player_list: Option<HashSet<Player>>,
+    pub capabilities: HashSet<Capability>,
+    /// place in the queue of instances waiting to auto start, 1 starts next
+    pub start_queue_position: Option<u32>,
+    /// latest ticks per second, from the log or a companion plugin
+    pub tps: Option<f32>,
+    /// latest milliseconds per tick
+    pub mspt: Option<f32>,
 }
-use crate::minecraft::MinecraftInstance;
+
+/// Optional features an instance may support, so clients know which pages to
+/// show and handlers can turn requests down cleanly
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, TS, PartialEq, Eq, Hash)]
+#[ts(export)]
+pub enum Capability {
+    SupportsPlayerList,
+    SupportsMacros,
+    SupportsBackups,
+    SupportsRcon,
+    SupportsWorlds,
+    SupportsResourcePacks,
+    SupportsCommandMetadata,
+    SupportsMods,
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Capability::SupportsPlayerList => "player lists",
+            Capability::SupportsMacros => "macros",
+            Capability::SupportsBackups => "backups",
+            Capability::SupportsRcon => "RCON",
+            Capability::SupportsWorlds => "world management",
+            Capability::SupportsResourcePacks => "resource packs",
+            Capability::SupportsCommandMetadata => "command metadata",
+            Capability::SupportsMods => "mods",
+        })
+    }
+}
+use crate::error::Error;
 use crate::generic::GenericInstance;
+use crate::minecraft::MinecraftInstance;
 use crate::prelude::GameInstance;
 use crate::types::InstanceUuid;
 #[async_trait]
 #[enum_dispatch::enum_dispatch]
 pub trait TInstance:
-    TConfigurable + TMacro + TPlayerManagement + TResourceManagement + TServer + Sync + Send + Clone
+    TBackup
+    + TConfigurable
+    + TMacro
+    + TPlayerManagement
+    + TResourceManagement
+    + TServer
+    + Sync
+    + Send
+    + Clone
 {
     async fn get_instance_info(&self) -> InstanceInfo {
         InstanceInfo {
@@ -63,6 +110,50 @@ pub trait TInstance:
             player_count: self.get_player_count().await.ok(),
             max_player_count: self.get_max_player_count().await.ok(),
             player_list: self.get_player_list().await.ok(),
+            capabilities: self.capabilities(),
+            start_queue_position: None,
+            tps: None,
+            mspt: None,
         }
     }
+
+    fn capabilities(&self) -> HashSet<Capability> {
+        HashSet::new()
+    }
+
+    fn require_capability(&self, capability: Capability) -> Result<(), Error> {
+        check_capability(&self.capabilities(), capability)
+    }
+}
+
+fn check_capability(
+    capabilities: &HashSet<Capability>,
+    capability: Capability,
+) -> Result<(), Error> {
+    if capabilities.contains(&capability) {
+        Ok(())
+    } else {
+        Err(Error::not_supported(capability))
+    }
+}
+
+#[test]
+fn test_check_capability() {
+    let capabilities = HashSet::from([Capability::SupportsPlayerList]);
+    assert!(check_capability(&capabilities, Capability::SupportsPlayerList).is_ok());
+    let error = check_capability(&capabilities, Capability::SupportsMacros).unwrap_err();
+    assert!(matches!(
+        error.kind,
+        crate::error::ErrorKind::OperationNotSupported {
+            capability: Capability::SupportsMacros
+        }
+    ));
+    assert_eq!(
+        error.source.to_string(),
+        "This instance does not support macros"
+    );
+    assert_eq!(
+        serde_json::to_string(&Capability::SupportsBackups).unwrap(),
+        r#""SupportsBackups""#
+    );
 }
@@ -0,0 +1,53 @@
+use async_trait::async_trait;
+
+use crate::backup::remote::BackupRemotesSettings;
+use crate::backup::{BackupEntry, BackupGuard, BackupMetadata};
+use crate::error::Error;
+use crate::events::CausedBy;
+use crate::traits::Capability;
+
+#[async_trait]
+#[enum_dispatch::enum_dispatch]
+pub trait TBackup {
+    /// Newest first
+    async fn list_backups(&self) -> Result<Vec<BackupEntry>, Error> {
+        Err(Error::not_supported(Capability::SupportsBackups))
+    }
+
+    /// Progress and the outcome are reported through progression events
+    async fn create_backup(
+        &self,
+        _guard: BackupGuard,
+        _metadata: BackupMetadata,
+        _created_by: Option<String>,
+        _remotes: &BackupRemotesSettings,
+        _caused_by: CausedBy,
+    ) -> Result<BackupEntry, Error> {
+        Err(Error::not_supported(Capability::SupportsBackups))
+    }
+
+    async fn delete_backup(&self, _guard: BackupGuard, _backup_id: &str) -> Result<(), Error> {
+        Err(Error::not_supported(Capability::SupportsBackups))
+    }
+
+    /// Stops the instance while its files are swapped if it's running, and
+    /// starts it again afterwards
+    async fn restore_backup(
+        &self,
+        _guard: BackupGuard,
+        _backup_id: &str,
+        _caused_by: CausedBy,
+    ) -> Result<BackupEntry, Error> {
+        Err(Error::not_supported(Capability::SupportsBackups))
+    }
+
+    /// Deletes the backups the backup policy no longer keeps and returns them
+    async fn prune_backups(&self, _guard: BackupGuard) -> Result<Vec<BackupEntry>, Error> {
+        Err(Error::not_supported(Capability::SupportsBackups))
+    }
+
+    /// When scheduled backups are taken, `None` if they are off
+    async fn backup_schedule(&self) -> Result<Option<cron::Schedule>, Error> {
+        Ok(None)
+    }
+}
@@ -571,6 +571,31 @@ impl SetupValue {
         }
         None
     }
+
+    /// Replaces the value of a setting wherever it is, or adds it to `section_id`
+    pub fn set_unique_setting(
+        &mut self,
+        section_id: &str,
+        setting_id: &str,
+        value: ConfigurableValue,
+    ) {
+        for section in self.setting_sections.values_mut() {
+            if let Some(setting) = section.settings.get_mut(setting_id) {
+                setting.value = Some(value);
+                return;
+            }
+        }
+        self.setting_sections
+            .entry(section_id.to_string())
+            .or_insert_with(|| SectionManifestValue {
+                settings: IndexMap::new(),
+            })
+            .settings
+            .insert(
+                setting_id.to_string(),
+                SettingManifestValue { value: Some(value) },
+            );
+    }
 }
 
 // A setting manifest indicates if the instance has implemented functionalities for smart, lodestone controlled feature
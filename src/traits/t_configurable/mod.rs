@@ -10,9 +10,11 @@ use ts_rs::TS;
 
 use self::manifest::ConfigurableManifest;
 use self::manifest::ConfigurableValue;
+use crate::cgroup::ResourceLimits;
 use crate::error::Error;
 use crate::error::ErrorKind;
 use crate::implementations::minecraft::Flavour;
+use crate::instance_notes::InstanceNotes;
 use crate::traits::GameInstance;
 use crate::traits::GenericInstance;
 use crate::traits::MinecraftInstance;
@@ -25,14 +27,18 @@ use crate::types::InstanceUuid;
 pub enum MinecraftVariant {
     Vanilla,
     Forge,
+    NeoForge,
     Fabric,
     Paper,
+    Purpur,
     Spigot,
+    Velocity,
+    BungeeCord,
     Other { name: String },
 }
 
 /// The type of game this instance is
-/// 
+///
 /// Meant to be consumed by frontend to display the correct icon
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, TS, EnumKind)]
 #[enum_kind(GameType, derive(Serialize, Deserialize, TS))]
@@ -66,12 +72,24 @@ impl From<Flavour> for Game {
             Flavour::Paper { .. } => Self::MinecraftJava {
                 variant: MinecraftVariant::Paper,
             },
+            Flavour::Purpur { .. } => Self::MinecraftJava {
+                variant: MinecraftVariant::Purpur,
+            },
             Flavour::Spigot => Self::MinecraftJava {
                 variant: MinecraftVariant::Spigot,
             },
             Flavour::Forge { .. } => Self::MinecraftJava {
                 variant: MinecraftVariant::Forge,
             },
+            Flavour::NeoForge { .. } => Self::MinecraftJava {
+                variant: MinecraftVariant::NeoForge,
+            },
+            Flavour::Velocity { .. } => Self::MinecraftJava {
+                variant: MinecraftVariant::Velocity,
+            },
+            Flavour::BungeeCord => Self::MinecraftJava {
+                variant: MinecraftVariant::BungeeCord,
+            },
         }
     }
 }
@@ -91,6 +109,21 @@ pub trait TConfigurable {
     /// does start when lodestone starts
     async fn auto_start(&self) -> bool;
     async fn restart_on_crash(&self) -> bool;
+    /// in CPU cores, `None` if the instance may use all of them
+    async fn cpu_limit(&self) -> Option<f32> {
+        None
+    }
+    /// in MB for the whole process, `None` if unlimited
+    async fn memory_limit(&self) -> Option<u32> {
+        None
+    }
+    /// id of the JVM flag preset the instance starts with
+    async fn jvm_preset(&self) -> Option<String> {
+        None
+    }
+    async fn notes(&self) -> InstanceNotes {
+        InstanceNotes::default()
+    }
     // setters
     async fn set_name(&mut self, name: String) -> Result<(), Error>;
     async fn set_description(&mut self, description: String) -> Result<(), Error>;
@@ -112,6 +145,25 @@ pub trait TConfigurable {
             source: eyre!("This instance does not support setting restart on crash"),
         })
     }
+    /// Applied the next time the instance starts
+    async fn set_resource_limits(&mut self, _limits: ResourceLimits) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support resource limits"),
+        })
+    }
+    async fn set_jvm_preset(&mut self, _jvm_preset: Option<String>) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support JVM presets"),
+        })
+    }
+    async fn set_notes(&mut self, _notes: InstanceNotes) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("This instance does not support notes"),
+        })
+    }
     async fn set_backup_period(&mut self, _backup_period: Option<u32>) -> Result<(), Error> {
         Err(Error {
             kind: ErrorKind::UnsupportedOperation,
@@ -63,4 +63,26 @@ pub trait TPlayerManagement {
             source: eyre!("Setting max player count is unsupported for this instance"),
         })
     }
+
+    async fn kick_player(&self, _player_name: &str, _reason: &str) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Kicking players is unsupported for this instance"),
+        })
+    }
+
+    /// Only whitelisted players can join while the whitelist is enabled
+    async fn set_whitelist_enabled(&self, _enabled: bool) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Whitelisting is unsupported for this instance"),
+        })
+    }
+
+    async fn set_whitelisted(&self, _player_name: &str, _whitelisted: bool) -> Result<(), Error> {
+        Err(Error {
+            kind: ErrorKind::UnsupportedOperation,
+            source: eyre!("Whitelisting is unsupported for this instance"),
+        })
+    }
 }
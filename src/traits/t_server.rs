@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 
 use ts_rs::TS;
 
+use crate::error::ErrorKind;
 use crate::events::CausedBy;
 use crate::Error;
 
@@ -25,6 +26,44 @@ pub enum StateAction {
     InstanceStop,
 }
 
+/// Operations whose validity depends on the state of the instance, see
+/// `State::allows`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceOperation {
+    Start,
+    Stop,
+    Restart,
+    Kill,
+    SendCommand,
+    Delete,
+    ChangeVersion,
+    /// Editing world files in place, the server must not have them open
+    ModifyWorld,
+    /// Stops the instance itself if it's running, but can't take over while it
+    /// is starting or stopping
+    ResetWorld,
+    RestoreBackup,
+    Migrate,
+}
+
+impl InstanceOperation {
+    fn verb(&self) -> &'static str {
+        match self {
+            InstanceOperation::Start => "start",
+            InstanceOperation::Stop => "stop",
+            InstanceOperation::Restart => "restart",
+            InstanceOperation::Kill => "kill",
+            InstanceOperation::SendCommand => "send a command to",
+            InstanceOperation::Delete => "delete",
+            InstanceOperation::ChangeVersion => "change the version of",
+            InstanceOperation::ModifyWorld => "modify the world of",
+            InstanceOperation::ResetWorld => "reset the world of",
+            InstanceOperation::RestoreBackup => "restore a backup of",
+            InstanceOperation::Migrate => "migrate",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
 pub struct DiskUsage {
@@ -52,6 +91,12 @@ pub struct MonitorReport {
     pub disk_usage: Option<DiskUsage>,
     pub cpu_usage: Option<f32>,
     pub start_time: Option<u64>,
+    /// in CPU cores, set when the instance runs under a CPU limit
+    pub cpu_limit: Option<f32>,
+    /// in bytes, set when the instance runs under a memory limit
+    pub memory_limit: Option<u64>,
+    /// time the process was held back by its CPU limit, in microseconds
+    pub cpu_throttled_usec: Option<u64>,
 }
 
 impl ToString for State {
@@ -67,37 +112,62 @@ impl ToString for State {
 }
 
 impl State {
+    /// The one table of which operations each state allows
+    pub fn allows(&self, operation: InstanceOperation) -> bool {
+        use InstanceOperation::*;
+        match self {
+            State::Starting => matches!(operation, Kill | SendCommand),
+            State::Running => matches!(
+                operation,
+                Stop | Restart | Kill | SendCommand | ResetWorld | RestoreBackup | Migrate
+            ),
+            State::Stopping => matches!(operation, Kill),
+            State::Stopped => matches!(
+                operation,
+                Start | Delete | ChangeVersion | ModifyWorld | ResetWorld | RestoreBackup | Migrate
+            ),
+            State::Error => matches!(
+                operation,
+                Start | Kill | Delete | ChangeVersion | ModifyWorld | ResetWorld | RestoreBackup
+            ),
+        }
+    }
+
+    pub fn check(&self, operation: InstanceOperation) -> Result<(), Error> {
+        if self.allows(operation) {
+            Ok(())
+        } else {
+            Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!(
+                    "Cannot {} an instance that is {}",
+                    operation.verb(),
+                    match self {
+                        State::Error => "in an error state".to_string(),
+                        _ => self.to_string().to_lowercase(),
+                    }
+                ),
+            })
+        }
+    }
+
     pub fn try_new_state(
         &self,
         action: StateAction,
         on_transit: Option<&dyn Fn(State)>,
     ) -> Result<State, Error> {
-        let state = match (*self, action) {
-            (State::Starting, StateAction::UserStart) => {
-                Err(eyre!("Cannot start an instance that is already starting"))
-            }
-            (State::Starting, StateAction::UserStop) => {
-                Err(eyre!("Cannot stop an instance that is starting"))
-            }
-            (_, StateAction::InstanceStart) => Ok(State::Running),
-            (_, StateAction::InstanceStop) => Ok(State::Stopped),
-            (State::Running, StateAction::UserStart) => {
-                Err(eyre!("Cannot start an instance that is already running"))
+        let state = match action {
+            StateAction::UserStart => {
+                self.check(InstanceOperation::Start)?;
+                State::Starting
             }
-            (State::Running, StateAction::UserStop) => Ok(State::Stopping),
-            (State::Stopping, StateAction::UserStart) => {
-                Err(eyre!("Cannot start an instance that is stopping"))
+            StateAction::UserStop => {
+                self.check(InstanceOperation::Stop)?;
+                State::Stopping
             }
-            (State::Stopping, StateAction::UserStop) => {
-                Err(eyre!("Cannot stop an instance that is already stopping"))
-            }
-            (State::Stopped, StateAction::UserStart) => Ok(State::Starting),
-            (State::Stopped, StateAction::UserStop) => {
-                Err(eyre!("Cannot stop an instance that is already stopped"))
-            }
-            (State::Error, StateAction::UserStart) => todo!(),
-            (State::Error, StateAction::UserStop) => todo!(),
-        }?;
+            StateAction::InstanceStart => State::Running,
+            StateAction::InstanceStop => State::Stopped,
+        };
         if let Some(on_transit) = on_transit {
             on_transit(state);
         }
@@ -128,3 +198,55 @@ pub trait TServer {
     async fn send_command(&self, command: &str, caused_by: CausedBy) -> Result<(), Error>;
     async fn monitor(&self) -> MonitorReport;
 }
+
+#[test]
+fn test_state_table() {
+    assert_eq!(
+        State::Stopped
+            .try_new_state(StateAction::UserStart, None)
+            .unwrap(),
+        State::Starting
+    );
+    assert_eq!(
+        State::Error
+            .try_new_state(StateAction::UserStart, None)
+            .unwrap(),
+        State::Starting
+    );
+    assert_eq!(
+        State::Running
+            .try_new_state(StateAction::UserStop, None)
+            .unwrap(),
+        State::Stopping
+    );
+    assert!(State::Starting
+        .try_new_state(StateAction::UserStart, None)
+        .is_err());
+    assert!(State::Starting
+        .try_new_state(StateAction::UserStop, None)
+        .is_err());
+    assert_eq!(
+        State::Stopping
+            .try_new_state(StateAction::InstanceStop, None)
+            .unwrap(),
+        State::Stopped
+    );
+
+    let err = State::Stopping
+        .check(InstanceOperation::Delete)
+        .unwrap_err();
+    assert!(matches!(err.kind, ErrorKind::BadRequest));
+    assert_eq!(
+        err.source.to_string(),
+        "Cannot delete an instance that is stopping"
+    );
+    assert!(State::Running.check(InstanceOperation::Delete).is_err());
+    assert!(State::Stopped.check(InstanceOperation::Delete).is_ok());
+    assert!(State::Starting.check(InstanceOperation::Kill).is_ok());
+    assert!(State::Starting
+        .check(InstanceOperation::RestoreBackup)
+        .is_err());
+    assert!(State::Stopped
+        .check(InstanceOperation::SendCommand)
+        .is_err());
+}
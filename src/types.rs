@@ -1,5 +1,6 @@
 use std::fmt::Display;
 
+use crate::migration::schema::dot_lodestone_config_version;
 use crate::migration::DotLodestoneConfigV043;
 use crate::traits::t_configurable::GameType;
 use crate::{
@@ -9,7 +10,7 @@ use serde::{Deserialize, Serialize};
 use serde_aux::prelude::*;
 use ts_rs::TS;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, TS, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, TS, Copy)]
 #[ts(export)]
 #[serde(into = "String")]
 #[derive(sqlx::Type)]
@@ -20,7 +21,7 @@ pub struct Snowflake(
     i64,
 );
 
-#[derive(Deserialize, Clone, Debug, TS)]
+#[derive(Deserialize, Clone, Debug, TS, PartialEq)]
 #[ts(export)]
 pub struct TimeRange {
     pub start: i64,
@@ -130,6 +131,15 @@ pub struct DotLodestoneConfig {
     game_type: GameType,
     uuid: InstanceUuid,
     creation_time: i64,
+    /// stable id the client created the instance with, see `create_minecraft_instance`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    /// always the current version in memory, older files are migrated on startup
+    #[serde(
+        default = "crate::migration::schema::dot_lodestone_config_version",
+        skip_deserializing
+    )]
+    schema_version: u32,
 }
 
 impl From<RestoreConfigV042> for DotLodestoneConfig {
@@ -145,6 +155,8 @@ impl From<RestoreConfigV042> for DotLodestoneConfig {
             game_type,
             uuid: config.uuid,
             creation_time: config.creation_time,
+            client_id: None,
+            schema_version: dot_lodestone_config_version(),
         }
     }
 }
@@ -155,6 +167,8 @@ impl From<DotLodestoneConfigV043> for DotLodestoneConfig {
             game_type: config.game_type,
             uuid: config.uuid,
             creation_time: config.creation_time,
+            client_id: None,
+            schema_version: dot_lodestone_config_version(),
         }
     }
 }
@@ -165,9 +179,16 @@ impl DotLodestoneConfig {
             game_type,
             uuid,
             creation_time: chrono::Utc::now().timestamp(),
+            client_id: None,
+            schema_version: dot_lodestone_config_version(),
         }
     }
 
+    pub fn with_client_id(mut self, client_id: Option<String>) -> Self {
+        self.client_id = client_id;
+        self
+    }
+
     pub fn uuid(&self) -> &InstanceUuid {
         &self.uuid
     }
@@ -178,6 +199,10 @@ impl DotLodestoneConfig {
     pub fn game_type(&self) -> &GameType {
         &self.game_type
     }
+
+    pub fn client_id(&self) -> Option<&str> {
+        self.client_id.as_deref()
+    }
 }
 
 #[test]
@@ -190,3 +215,18 @@ fn test_instance_uuid() {
     let uuid2: InstanceUuid = serde_json::from_str(&uuid_str).unwrap();
     assert_eq!(uuid1, uuid2);
 }
+
+#[test]
+fn test_dot_lodestone_config_client_id() {
+    let config = DotLodestoneConfig::new(InstanceUuid::default(), GameType::MinecraftJava);
+    let json = serde_json::to_value(&config).unwrap();
+    // files of instances created without one stay as they were
+    assert!(json.get("client_id").is_none());
+    let parsed: DotLodestoneConfig = serde_json::from_value(json).unwrap();
+    assert_eq!(parsed.client_id(), None);
+
+    let config = config.with_client_id(Some("terraform-survival".to_string()));
+    let parsed: DotLodestoneConfig =
+        serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+    assert_eq!(parsed.client_id(), Some("terraform-survival"));
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+use ts_rs::TS;
+
+use crate::error::Error;
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{EventInner, InstanceEvent, InstanceEventInner};
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+
+const DAY_SECS: i64 = 24 * 60 * 60;
+/// intervals that ended longer ago than the longest report are dropped
+const KEEP_SECS: i64 = 30 * DAY_SECS;
+/// how often running instances note that they're still up, so the time the core
+/// was down can be told apart from uptime after a crash of the core
+const CHECK_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[ts(export)]
+pub enum Availability {
+    Up,
+    /// stopped on purpose, or not started
+    Down,
+    /// stopped by a crash, until it's started again
+    Crashed,
+}
+
+impl Availability {
+    /// `None` for the states in between, which keep the availability they came from
+    fn from_state(state: State) -> Option<Self> {
+        match state {
+            State::Running => Some(Self::Up),
+            State::Stopped => Some(Self::Down),
+            State::Error => Some(Self::Crashed),
+            State::Starting | State::Stopping => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct UptimeInterval {
+    pub availability: Availability,
+    pub start: i64,
+    /// `None` for the current interval
+    pub end: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct UptimeLog {
+    pub intervals: Vec<UptimeInterval>,
+    /// last time the core saw the instance, the current interval ends here if the
+    /// core goes away without recording it
+    pub checked_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct AvailabilityWindow {
+    pub window_secs: i64,
+    /// part of the window the instance was tracked for, less than the window for
+    /// new instances
+    pub tracked_secs: i64,
+    pub up_secs: i64,
+    pub down_secs: i64,
+    pub crashed_secs: i64,
+    /// percentage of the tracked time the instance was up, `None` if not tracked
+    pub availability: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq)]
+#[ts(export)]
+pub struct UptimeReport {
+    pub current: Option<UptimeInterval>,
+    pub day: AvailabilityWindow,
+    pub week: AvailabilityWindow,
+    pub month: AvailabilityWindow,
+}
+
+impl UptimeLog {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_uptime.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse uptime log at {}", path.display()))?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_uptime.json"),
+            serde_json::to_string(self).context("Failed to serialize uptime log")?,
+        )
+        .await
+    }
+
+    pub fn record(&mut self, availability: Availability, at: i64) {
+        self.checked_at = Some(at);
+        if let Some(current) = self.intervals.last_mut() {
+            if current.end.is_none() {
+                if current.availability == availability {
+                    return;
+                }
+                current.end = Some(at.max(current.start));
+            }
+        }
+        self.intervals.push(UptimeInterval {
+            availability,
+            start: at,
+            end: None,
+        });
+        self.intervals
+            .retain(|interval| interval.end.map_or(true, |end| end > at - KEEP_SECS));
+    }
+
+    /// Called when the core starts. Whatever the instance was doing ended when the
+    /// core last saw it, and it's been down since.
+    pub fn resume(&mut self, now: i64) {
+        let current = match self.intervals.last() {
+            Some(current) if current.end.is_none() => current,
+            Some(_) => return,
+            None => {
+                self.record(Availability::Down, now);
+                return;
+            }
+        };
+        if current.availability != Availability::Down {
+            let last_seen = self.checked_at.unwrap_or(current.start).min(now);
+            self.record(Availability::Down, last_seen);
+        }
+        self.checked_at = Some(now);
+    }
+
+    pub fn window(&self, now: i64, window_secs: i64) -> AvailabilityWindow {
+        let window_start = now - window_secs;
+        let (mut up_secs, mut down_secs, mut crashed_secs) = (0, 0, 0);
+        for interval in &self.intervals {
+            let start = interval.start.max(window_start);
+            let end = interval.end.unwrap_or(now).min(now);
+            if end <= start {
+                continue;
+            }
+            match interval.availability {
+                Availability::Up => up_secs += end - start,
+                Availability::Down => down_secs += end - start,
+                Availability::Crashed => crashed_secs += end - start,
+            }
+        }
+        let tracked_secs = up_secs + down_secs + crashed_secs;
+        AvailabilityWindow {
+            window_secs,
+            tracked_secs,
+            up_secs,
+            down_secs,
+            crashed_secs,
+            availability: (tracked_secs > 0).then(|| (up_secs * 100) as f64 / tracked_secs as f64),
+        }
+    }
+
+    pub fn report(&self, now: i64) -> UptimeReport {
+        UptimeReport {
+            current: self
+                .intervals
+                .last()
+                .filter(|interval| interval.end.is_none())
+                .cloned(),
+            day: self.window(now, DAY_SECS),
+            week: self.window(now, 7 * DAY_SECS),
+            month: self.window(now, KEEP_SECS),
+        }
+    }
+}
+
+async fn update_log(path_to_instance: &Path, update: impl FnOnce(&mut UptimeLog)) {
+    let result = async {
+        let mut log = UptimeLog::load(path_to_instance).await?;
+        update(&mut log);
+        log.save(path_to_instance).await
+    }
+    .await;
+    if let Err(e) = result {
+        error!(
+            "Failed to update uptime log at {}: {}",
+            path_to_instance.display(),
+            e
+        );
+    }
+}
+
+/// Records every instance's state changes into its uptime log
+pub async fn uptime_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let mut paths = Vec::new();
+    for instance in instances.lock().await.values() {
+        paths.push(instance.path().await);
+    }
+    let now = chrono::Utc::now().timestamp();
+    for path in paths {
+        update_log(&path, |log| log.resume(now)).await;
+    }
+    let mut check_interval = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL_SECS));
+    loop {
+        tokio::select! {
+            _ = check_interval.tick() => {
+                let mut running = Vec::new();
+                for instance in instances.lock().await.values() {
+                    if instance.state().await != State::Stopped {
+                        running.push(instance.path().await);
+                    }
+                }
+                let now = chrono::Utc::now().timestamp();
+                for path in running {
+                    update_log(&path, |log| log.checked_at = Some(now)).await;
+                }
+            }
+            event = event_receiver.recv() => {
+                let (instance_uuid, to) = match event {
+                    Ok(event) => match event.event_inner {
+                        EventInner::InstanceEvent(InstanceEvent {
+                            instance_uuid,
+                            instance_event_inner: InstanceEventInner::StateTransition { to },
+                            ..
+                        }) => (instance_uuid, to),
+                        _ => continue,
+                    },
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Uptime task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let availability = match Availability::from_state(to) {
+                    Some(availability) => availability,
+                    None => continue,
+                };
+                let path = match instances.lock().await.get(&instance_uuid) {
+                    Some(instance) => instance.path().await,
+                    None => continue,
+                };
+                let now = chrono::Utc::now().timestamp();
+                update_log(&path, |log| log.record(availability, now)).await;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_uptime_log() {
+    let mut log = UptimeLog::default();
+    log.record(Availability::Up, 0);
+    log.record(Availability::Up, 100);
+    log.record(Availability::Crashed, 300);
+    log.record(Availability::Up, 400);
+    assert_eq!(log.intervals.len(), 3);
+    let window = log.window(1000, DAY_SECS);
+    assert_eq!(window.up_secs, 900);
+    assert_eq!(window.crashed_secs, 100);
+    assert_eq!(window.availability, Some(90.0));
+    // only the last 500 seconds
+    assert_eq!(log.window(1000, 500).up_secs, 500);
+
+    // the core went away at 1500 and came back at 2000
+    log.checked_at = Some(1500);
+    log.resume(2000);
+    let window = log.window(2000, DAY_SECS);
+    assert_eq!(window.up_secs, 1400);
+    assert_eq!(window.down_secs, 500);
+    assert_eq!(
+        log.report(2000).current.map(|current| current.availability),
+        Some(Availability::Down)
+    );
+
+    assert_eq!(UptimeLog::default().window(0, DAY_SECS).availability, None);
+}
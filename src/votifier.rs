@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{error, info, warn};
+use ts_rs::TS;
+
+use crate::command_template;
+use crate::console_policy::validate_command;
+use crate::error::{Error, ErrorKind};
+use crate::event_broadcaster::EventBroadcaster;
+use crate::events::{CausedBy, EventInner, InstanceEvent, InstanceEventInner};
+use crate::global_settings::GlobalSettings;
+use crate::prelude::GameInstance;
+use crate::traits::t_configurable::TConfigurable;
+use crate::traits::t_player::{TPlayer, TPlayerManagement};
+use crate::traits::t_server::{State, TServer};
+use crate::types::InstanceUuid;
+use crate::util::rand_alphanumeric;
+
+/// Votifier v2 messages start with this, v1 (RSA) votes are not supported
+const PROTOCOL_MAGIC: u16 = 0x733A;
+
+/// oldest votes are dropped past this
+const MAX_VOTES: usize = 5000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The vote listener shared by every instance. Voting sites are given the core's
+/// address, this port and the token of the instance the vote is for.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct VotifierSettings {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for VotifierSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 8192,
+        }
+    }
+}
+
+impl VotifierSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.port == 0 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Port must not be 0"),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct VoteRewardsConfig {
+    pub enabled: bool,
+    /// votes signed with this token are for this instance
+    pub token: String,
+    /// `{player}` and `{service}` are substituted
+    pub commands: Vec<String>,
+    /// said in chat on every vote, with the same placeholders
+    pub broadcast: Option<String>,
+    /// run the commands when the player is next online instead of dropping the reward
+    pub queue_offline: bool,
+}
+
+impl Default for VoteRewardsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            token: rand_alphanumeric(26),
+            commands: Vec::new(),
+            broadcast: Some("{player} voted on {service}, thank you!".to_string()),
+            queue_offline: true,
+        }
+    }
+}
+
+impl VoteRewardsConfig {
+    /// The config, saving a default one first so its token stays the same
+    pub async fn load_or_init(path_to_instance: &Path) -> Result<Self, Error> {
+        if path_to_instance.join(".lodestone_votifier.json").is_file() {
+            return Self::load(path_to_instance).await;
+        }
+        let config = Self::default();
+        config.save(path_to_instance).await?;
+        Ok(config)
+    }
+
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_votifier.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse vote rewards config at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_votifier.json"),
+            serde_json::to_string_pretty(self)
+                .context("Failed to serialize vote rewards config")?,
+        )
+        .await
+    }
+
+    pub fn validate(&mut self) -> Result<(), Error> {
+        if self.token.len() < 16 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Token must be at least 16 characters"),
+            });
+        }
+        for command in self.commands.iter_mut() {
+            *command = validate_command(command)?.to_string();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct Vote {
+    pub service_name: String,
+    pub username: String,
+    /// address the player voted from, as reported by the site
+    pub address: String,
+    pub received_at: i64,
+    /// waiting for the player to be online
+    pub pending: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct VoteLog {
+    pub votes: Vec<Vote>,
+}
+
+impl VoteLog {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_votes.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?)
+                .context(format!("Failed to parse votes at {}", path.display()))?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_votes.json"),
+            serde_json::to_string(self).context("Failed to serialize votes")?,
+        )
+        .await
+    }
+
+    fn push(&mut self, vote: Vote) {
+        self.votes.push(vote);
+        if self.votes.len() > MAX_VOTES {
+            let excess = self.votes.len() - MAX_VOTES;
+            self.votes.drain(..excess);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct VoterCount {
+    pub username: String,
+    pub votes: u32,
+}
+
+#[derive(Debug, Clone, Serialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct VoteStats {
+    pub total: u32,
+    pub by_service: HashMap<String, u32>,
+    /// most votes first
+    pub top_voters: Vec<VoterCount>,
+    pub pending: u32,
+}
+
+/// Statistics of the votes received at or after `since`
+pub fn vote_stats(votes: &[Vote], since: i64, top: usize) -> VoteStats {
+    let mut by_service: HashMap<String, u32> = HashMap::new();
+    let mut by_voter: HashMap<String, VoterCount> = HashMap::new();
+    let mut total = 0;
+    let mut pending = 0;
+    for vote in votes.iter().filter(|vote| vote.received_at >= since) {
+        total += 1;
+        if vote.pending {
+            pending += 1;
+        }
+        *by_service.entry(vote.service_name.clone()).or_default() += 1;
+        by_voter
+            .entry(vote.username.to_lowercase())
+            .or_insert_with(|| VoterCount {
+                username: vote.username.clone(),
+                votes: 0,
+            })
+            .votes += 1;
+    }
+    let mut top_voters: Vec<VoterCount> = by_voter.into_values().collect();
+    top_voters.sort_by(|a, b| {
+        b.votes
+            .cmp(&a.votes)
+            .then_with(|| a.username.cmp(&b.username))
+    });
+    top_voters.truncate(top);
+    VoteStats {
+        total,
+        by_service,
+        top_voters,
+        pending,
+    }
+}
+
+#[derive(Deserialize)]
+struct VoteMessage {
+    payload: String,
+    signature: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VotePayload {
+    service_name: String,
+    username: String,
+    #[serde(default)]
+    address: String,
+    challenge: String,
+}
+
+fn protocol_error(message: &str) -> Error {
+    Error {
+        kind: ErrorKind::BadRequest,
+        source: eyre!("{}", message),
+    }
+}
+
+/// Parses the JSON body of a vote, checking it answers `challenge`.
+/// Returns the vote, the payload the signature is over and the signature.
+fn parse_vote(message: &str, challenge: &str) -> Result<(VotePayload, Vec<u8>, String), Error> {
+    let message: VoteMessage =
+        serde_json::from_str(message).map_err(|_| protocol_error("Malformed vote message"))?;
+    let signature = base64::decode(&message.signature)
+        .map_err(|_| protocol_error("Signature is not valid base64"))?;
+    let payload: VotePayload = serde_json::from_str(&message.payload)
+        .map_err(|_| protocol_error("Malformed vote payload"))?;
+    if payload.challenge != challenge {
+        return Err(protocol_error("Challenge is not valid"));
+    }
+    if command_template::validate_target(&payload.username).is_err()
+        || payload.username.starts_with('@')
+    {
+        return Err(protocol_error("Username is not valid"));
+    }
+    Ok((payload, signature, message.payload))
+}
+
+fn verify_signature(token: &str, payload: &str, signature: &[u8]) -> bool {
+    let mut mac = match HmacSha256::new_from_slice(token.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(payload.as_bytes());
+    mac.verify_slice(signature).is_ok()
+}
+
+/// The instance whose token the vote is signed with
+async fn find_instance(
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+    payload: &str,
+    signature: &[u8],
+) -> Option<InstanceUuid> {
+    let mut paths = Vec::new();
+    for (uuid, instance) in instances.lock().await.iter() {
+        paths.push((uuid.clone(), instance.path().await));
+    }
+    for (uuid, path) in paths {
+        match VoteRewardsConfig::load(&path).await {
+            Ok(config) if config.enabled && verify_signature(&config.token, payload, signature) => {
+                return Some(uuid)
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to load vote rewards config of {}: {}", uuid, e),
+        }
+    }
+    None
+}
+
+async fn receive_vote(
+    stream: &mut TcpStream,
+    challenge: &str,
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+) -> Result<(InstanceUuid, Vote), Error> {
+    let magic = stream
+        .read_u16()
+        .await
+        .context("Failed to read vote message")?;
+    if magic != PROTOCOL_MAGIC {
+        return Err(protocol_error("Only Votifier v2 is supported"));
+    }
+    let length = stream
+        .read_u16()
+        .await
+        .context("Failed to read vote message")?;
+    let mut message = vec![0; length as usize];
+    stream
+        .read_exact(&mut message)
+        .await
+        .context("Failed to read vote message")?;
+    let message =
+        String::from_utf8(message).map_err(|_| protocol_error("Vote message is not UTF-8"))?;
+    let (vote, signature, payload) = parse_vote(&message, challenge)?;
+    let uuid = find_instance(instances, &payload, &signature)
+        .await
+        .ok_or_else(|| protocol_error("Signature is not valid (invalid token?)"))?;
+    Ok((
+        uuid,
+        Vote {
+            service_name: vote.service_name,
+            username: vote.username,
+            address: vote.address,
+            received_at: chrono::Utc::now().timestamp(),
+            pending: false,
+        },
+    ))
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    instances: &Mutex<HashMap<InstanceUuid, GameInstance>>,
+) -> Result<(InstanceUuid, Vote), Error> {
+    let challenge = rand_alphanumeric(24);
+    stream
+        .write_all(format!("VOTIFIER 2 {}\n", challenge).as_bytes())
+        .await
+        .context("Failed to send handshake")?;
+    let result = receive_vote(&mut stream, &challenge, instances).await;
+    let response = match &result {
+        Ok(_) => serde_json::json!({"status": "ok"}),
+        Err(e) => serde_json::json!({
+            "status": "error",
+            "cause": "InvalidVote",
+            "error": e.source.to_string(),
+        }),
+    };
+    stream
+        .write_all(format!("{}\r\n", response).as_bytes())
+        .await
+        .context("Failed to send response")?;
+    result
+}
+
+async fn run_rewards(
+    instance: &GameInstance,
+    config: &VoteRewardsConfig,
+    vote: &Vote,
+) -> Result<(), Error> {
+    let values = [
+        ("player", vote.username.as_str()),
+        ("service", vote.service_name.as_str()),
+    ];
+    for command in config.commands.iter() {
+        instance
+            .send_command(
+                &command_template::render(command, &values),
+                CausedBy::System,
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn is_online(instance: &GameInstance, player: &str) -> bool {
+    instance.state().await == State::Running
+        && instance.get_player_list().await.map_or(false, |players| {
+            players
+                .iter()
+                .any(|p| p.get_name().eq_ignore_ascii_case(player))
+        })
+}
+
+async fn deliver_vote(instance: &GameInstance, mut vote: Vote) -> Result<(), Error> {
+    let path = instance.path().await;
+    let config = VoteRewardsConfig::load(&path).await?;
+    let broadcast = match instance.state().await {
+        State::Running => config.broadcast.as_ref(),
+        _ => None,
+    };
+    if let Some(broadcast) = broadcast {
+        let message = command_template::render(
+            broadcast,
+            &[
+                ("player", vote.username.as_str()),
+                ("service", vote.service_name.as_str()),
+            ],
+        );
+        instance
+            .send_command(&command_template::say(&message), CausedBy::System)
+            .await?;
+    }
+    if is_online(instance, &vote.username).await {
+        run_rewards(instance, &config, &vote).await?;
+    } else {
+        vote.pending = config.queue_offline;
+    }
+    info!(
+        "Vote from {} on {} for {}",
+        vote.username,
+        vote.service_name,
+        instance.name().await
+    );
+    let mut log = VoteLog::load(&path).await?;
+    log.push(vote);
+    log.save(&path).await
+}
+
+/// Runs the rewards of votes queued while `player` was offline
+async fn deliver_pending(instance: &GameInstance, player: &str) -> Result<(), Error> {
+    let path = instance.path().await;
+    let mut log = VoteLog::load(&path).await?;
+    if !log
+        .votes
+        .iter()
+        .any(|vote| vote.pending && vote.username.eq_ignore_ascii_case(player))
+    {
+        return Ok(());
+    }
+    let config = VoteRewardsConfig::load(&path).await?;
+    for vote in log.votes.iter_mut() {
+        if vote.pending && vote.username.eq_ignore_ascii_case(player) {
+            run_rewards(instance, &config, vote).await?;
+            vote.pending = false;
+        }
+    }
+    log.save(&path).await
+}
+
+async fn accept(listener: &Option<(u16, TcpListener)>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some((_, listener)) => listener.accept().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Listens for Votifier v2 votes while enabled in the global settings and
+/// hands each vote to the instance it is signed for. Votes and deliveries of
+/// queued rewards are handled one at a time so the vote logs aren't raced.
+pub async fn votifier_task(
+    instances: Arc<Mutex<HashMap<InstanceUuid, GameInstance>>>,
+    global_settings: Arc<Mutex<GlobalSettings>>,
+    event_broadcaster: EventBroadcaster,
+) {
+    let mut event_receiver = event_broadcaster.subscribe();
+    let (vote_sender, mut vote_receiver) = mpsc::unbounded_channel::<(InstanceUuid, Vote)>();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    let mut settings_changes = global_settings.lock().await.subscribe();
+    let mut listener: Option<(u16, TcpListener)> = None;
+    // port that failed to bind, to not log it every tick
+    let mut failed_port = None;
+    loop {
+        tokio::select! {
+            Ok(()) = settings_changes.changed() => {
+                // a new interval ticks right away, so the listener follows the settings now
+                interval = tokio::time::interval(Duration::from_secs(5));
+            }
+            _ = interval.tick() => {
+                let settings = global_settings.lock().await.votifier();
+                let wanted = if settings.enabled { Some(settings.port) } else { None };
+                if listener.as_ref().map(|(port, _)| *port) == wanted {
+                    continue;
+                }
+                listener = None;
+                let port = match wanted {
+                    Some(port) => port,
+                    None => {
+                        failed_port = None;
+                        continue;
+                    }
+                };
+                match TcpListener::bind(("0.0.0.0", port)).await {
+                    Ok(bound) => {
+                        info!("Listening for votes on port {}", port);
+                        failed_port = None;
+                        listener = Some((port, bound));
+                    }
+                    Err(e) => {
+                        if failed_port != Some(port) {
+                            error!("Failed to listen for votes on port {}: {}", port, e);
+                            failed_port = Some(port);
+                        }
+                    }
+                }
+            }
+            accepted = accept(&listener) => {
+                let (stream, address) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept vote connection: {}", e);
+                        continue;
+                    }
+                };
+                let instances = instances.clone();
+                let vote_sender = vote_sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(
+                        Duration::from_secs(10),
+                        handle_connection(stream, &instances),
+                    )
+                    .await
+                    {
+                        Ok(Ok(vote)) => {
+                            let _ = vote_sender.send(vote);
+                        }
+                        Ok(Err(e)) => warn!("Rejected vote from {}: {}", address, e),
+                        Err(_) => warn!("Vote connection from {} timed out", address),
+                    }
+                });
+            }
+            Some((uuid, vote)) = vote_receiver.recv() => {
+                let instance = match instances.lock().await.get(&uuid) {
+                    Some(instance) => instance.clone(),
+                    None => continue,
+                };
+                if let Err(e) = deliver_vote(&instance, vote).await {
+                    error!("Failed to deliver vote to instance {}: {}", uuid, e);
+                }
+            }
+            event = event_receiver.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => {
+                        warn!("Votifier task lagged");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if let EventInner::InstanceEvent(InstanceEvent {
+                    instance_uuid,
+                    instance_event_inner: InstanceEventInner::PlayerChange { players_joined, .. },
+                    ..
+                }) = event.event_inner
+                {
+                    let instance = match instances.lock().await.get(&instance_uuid) {
+                        Some(instance) => instance.clone(),
+                        None => continue,
+                    };
+                    for player in players_joined {
+                        if let Err(e) = deliver_pending(&instance, &player.get_name()).await {
+                            error!(
+                                "Failed to deliver queued votes to instance {}: {}",
+                                instance_uuid, e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign(token: &str, payload: &str) -> String {
+        let mut mac = HmacSha256::new_from_slice(token.as_bytes()).unwrap();
+        mac.update(payload.as_bytes());
+        base64::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn test_parse_vote() {
+        let payload = serde_json::json!({
+            "serviceName": "TopG",
+            "username": "Steve",
+            "address": "127.0.0.1",
+            "timestamp": 1_700_000_000_000i64,
+            "challenge": "abc",
+        })
+        .to_string();
+        let message = serde_json::json!({
+            "payload": payload,
+            "signature": sign("secret-token", &payload),
+        })
+        .to_string();
+        let (vote, signature, signed) = parse_vote(&message, "abc").unwrap();
+        assert_eq!(vote.username, "Steve");
+        assert!(verify_signature("secret-token", &signed, &signature));
+        assert!(!verify_signature("other-token", &signed, &signature));
+        assert!(parse_vote(&message, "xyz").is_err());
+    }
+
+    #[test]
+    fn test_vote_stats() {
+        let vote = |service: &str, username: &str, received_at: i64| Vote {
+            service_name: service.to_string(),
+            username: username.to_string(),
+            address: String::new(),
+            received_at,
+            pending: false,
+        };
+        let votes = vec![
+            vote("TopG", "Steve", 10),
+            vote("PMC", "steve", 20),
+            vote("TopG", "Alex", 30),
+            vote("TopG", "Notch", 0),
+        ];
+        let stats = vote_stats(&votes, 5, 2);
+        assert_eq!(stats.total, 3);
+        assert_eq!(stats.by_service["TopG"], 2);
+        assert_eq!(stats.top_voters[0].votes, 2);
+        assert_eq!(stats.top_voters[1].username, "Alex");
+    }
+}
@@ -0,0 +1,287 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+use crate::firewall::{parse_source, source_contains};
+use crate::geoip::GeoInfo;
+
+/// Where to look up whether an address belongs to a VPN or proxy. Local lists are checked
+/// first, the external API only if none of them match.
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct VpnDetectionSettings {
+    /// addresses or CIDR ranges of known VPN and proxy exits
+    pub blocked_ranges: Vec<String>,
+    pub blocked_asns: Vec<u32>,
+    /// case-insensitive substrings of the AS organization, requires a GeoIP ASN database
+    pub organization_keywords: Vec<String>,
+    /// queried with `{ip}` replaced by the player's address,
+    /// e.g. `https://proxycheck.io/v2/{ip}?vpn=1`
+    pub api_url: Option<String>,
+    /// JSON pointer to the verdict in the API response, `{ip}` is replaced as well.
+    /// `true`, a non-zero number, or "yes"/"true" count as a VPN
+    pub api_verdict_pointer: String,
+    pub api_timeout_secs: u32,
+}
+
+impl Default for VpnDetectionSettings {
+    fn default() -> Self {
+        Self {
+            blocked_ranges: Vec::new(),
+            blocked_asns: Vec::new(),
+            organization_keywords: vec!["vpn".to_string(), "proxy".to_string()],
+            api_url: None,
+            api_verdict_pointer: "/{ip}/proxy".to_string(),
+            api_timeout_secs: 3,
+        }
+    }
+}
+
+impl VpnDetectionSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        for range in self.blocked_ranges.iter() {
+            if parse_source(range).is_none() {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("Invalid address or CIDR range: {}", range),
+                });
+            }
+        }
+        if let Some(api_url) = &self.api_url {
+            if !api_url.contains("{ip}") {
+                return Err(Error {
+                    kind: ErrorKind::BadRequest,
+                    source: eyre!("API url must contain {{ip}}"),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub enum VpnAction {
+    /// warn in the instance's event stream
+    Flag,
+    Kick,
+}
+
+/// Per-instance opt-in, stored next to the instance's other lodestone configs
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct VpnCheckConfig {
+    pub enabled: bool,
+    pub action: VpnAction,
+    pub kick_message: String,
+    /// player names that are never checked
+    pub exempt_players: Vec<String>,
+}
+
+impl Default for VpnCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            action: VpnAction::Flag,
+            kick_message: "Connecting through a VPN or proxy is not allowed".to_string(),
+            exempt_players: Vec::new(),
+        }
+    }
+}
+
+impl VpnCheckConfig {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_vpn_check.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse VPN check config at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_vpn_check.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize VPN check config")?,
+        )
+        .await
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        // the message is passed to the kick command verbatim
+        if self.kick_message.contains(['\n', '\r']) {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Kick message must be a single line"),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_exempt(&self, player_name: &str) -> bool {
+        self.exempt_players
+            .iter()
+            .any(|exempt| exempt.eq_ignore_ascii_case(player_name))
+    }
+}
+
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        // unique local addresses, fc00::/7
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+fn check_locally(
+    settings: &VpnDetectionSettings,
+    ip: IpAddr,
+    geo: Option<&GeoInfo>,
+) -> Option<String> {
+    if let Some(range) = settings
+        .blocked_ranges
+        .iter()
+        .find(|range| source_contains(range, ip))
+    {
+        return Some(format!("address is in blocked range {}", range));
+    }
+    let geo = geo?;
+    if let Some(asn) = geo.asn.filter(|asn| settings.blocked_asns.contains(asn)) {
+        return Some(format!("AS{} is blocked", asn));
+    }
+    let organization = geo.as_organization.as_ref()?.to_lowercase();
+    settings
+        .organization_keywords
+        .iter()
+        .find(|keyword| !keyword.is_empty() && organization.contains(&keyword.to_lowercase()))
+        .map(|keyword| format!("AS organization matches \"{}\"", keyword))
+}
+
+fn is_truthy(value: &serde_json::Value) -> bool {
+    match value {
+        serde_json::Value::Bool(b) => *b,
+        serde_json::Value::Number(n) => n.as_f64().map(|n| n != 0.0).unwrap_or(false),
+        serde_json::Value::String(s) => {
+            s.eq_ignore_ascii_case("yes") || s.eq_ignore_ascii_case("true")
+        }
+        _ => false,
+    }
+}
+
+async fn check_api(settings: &VpnDetectionSettings, ip: IpAddr) -> Result<bool, Error> {
+    let api_url = match &settings.api_url {
+        Some(api_url) => api_url.replace("{ip}", &ip.to_string()),
+        None => return Ok(false),
+    };
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(&api_url)
+        .timeout(Duration::from_secs(settings.api_timeout_secs as u64))
+        .send()
+        .await
+        .context("Failed to reach VPN detection API")?
+        .json()
+        .await
+        .context("Failed to parse VPN detection API response")?;
+    Ok(response
+        .pointer(
+            &settings
+                .api_verdict_pointer
+                .replace("{ip}", &ip.to_string()),
+        )
+        .map(is_truthy)
+        .unwrap_or(false))
+}
+
+/// The reason the address was flagged, `None` if it looks like a regular connection.
+/// API errors are logged and don't flag the player.
+pub async fn check_address(
+    settings: &VpnDetectionSettings,
+    ip: IpAddr,
+    geo: Option<&GeoInfo>,
+) -> Option<String> {
+    if is_local(ip) {
+        return None;
+    }
+    if let Some(reason) = check_locally(settings, ip, geo) {
+        return Some(reason);
+    }
+    match check_api(settings, ip).await {
+        Ok(true) => Some("flagged by the VPN detection API".to_string()),
+        Ok(false) => None,
+        Err(e) => {
+            warn!("VPN check for {} failed: {}", ip, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_address() {
+        let settings = VpnDetectionSettings {
+            blocked_ranges: vec!["203.0.113.0/24".to_string()],
+            blocked_asns: vec![64500],
+            ..Default::default()
+        };
+        let geo = |asn, org: &str| GeoInfo {
+            asn: Some(asn),
+            as_organization: Some(org.to_string()),
+            ..Default::default()
+        };
+        assert!(
+            check_address(&settings, "203.0.113.7".parse().unwrap(), None)
+                .await
+                .is_some()
+        );
+        assert!(check_address(
+            &settings,
+            "198.51.100.1".parse().unwrap(),
+            Some(&geo(64500, "Example"))
+        )
+        .await
+        .is_some());
+        assert!(check_address(
+            &settings,
+            "198.51.100.1".parse().unwrap(),
+            Some(&geo(64501, "Example VPN Ltd"))
+        )
+        .await
+        .is_some());
+        assert!(check_address(
+            &settings,
+            "198.51.100.1".parse().unwrap(),
+            Some(&geo(64501, "Example Broadband"))
+        )
+        .await
+        .is_none());
+        // private addresses are never flagged
+        let settings = VpnDetectionSettings {
+            blocked_ranges: vec!["0.0.0.0/0".to_string()],
+            ..Default::default()
+        };
+        assert!(
+            check_address(&settings, "192.168.1.2".parse().unwrap(), None)
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_is_truthy() {
+        let response = serde_json::json!({"1.2.3.4": {"proxy": "yes"}, "score": 0});
+        assert!(is_truthy(response.pointer("/1.2.3.4/proxy").unwrap()));
+        assert!(!is_truthy(response.pointer("/score").unwrap()));
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::error::{Error, ErrorKind};
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, Default)]
+#[ts(export)]
+pub struct WebMapConfig {
+    pub enabled: bool,
+    /// port the map plugin's web server listens on, detected from the plugin config if
+    /// `None`. Only the detected port is accepted.
+    pub port: Option<u16>,
+    /// serve the map without a session key
+    pub public: bool,
+}
+
+impl WebMapConfig {
+    pub async fn load(path_to_instance: &Path) -> Result<Self, Error> {
+        let path = path_to_instance.join(".lodestone_web_map.json");
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        Ok(
+            serde_json::from_str(&crate::util::fs::read_to_string(&path).await?).context(
+                format!("Failed to parse web map config at {}", path.display()),
+            )?,
+        )
+    }
+
+    pub async fn save(&self, path_to_instance: &Path) -> Result<(), Error> {
+        crate::util::fs::write_all(
+            path_to_instance.join(".lodestone_web_map.json"),
+            serde_json::to_string_pretty(self).context("Failed to serialize web map config")?,
+        )
+        .await
+    }
+}
+
+/// First `key: value` or `key=value` line of a config file, parsed as a port
+fn find_port(config: &str, key: &str) -> Option<u16> {
+    config.lines().find_map(|line| {
+        let value = line.trim().strip_prefix(key)?.trim_start();
+        let value = value
+            .strip_prefix(':')
+            .or_else(|| value.strip_prefix('='))?;
+        value
+            .trim()
+            .trim_matches('"')
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    })
+}
+
+/// Port of the first installed map plugin, falling back to the plugin's default port
+/// if its config doesn't set one
+pub fn detect_web_map_port(path_to_instance: &Path) -> Option<u16> {
+    // (config file, key, default port)
+    let candidates = [
+        ("plugins/dynmap/configuration.txt", "webserver-port", 8123),
+        ("plugins/BlueMap/webserver.conf", "port", 8100),
+        ("config/bluemap/webserver.conf", "port", 8100),
+        ("plugins/squaremap/config.yml", "port", 8080),
+        ("config/squaremap/config.yml", "port", 8080),
+    ];
+    candidates
+        .iter()
+        .find_map(|(config_path, key, default_port)| {
+            let config = std::fs::read_to_string(path_to_instance.join(config_path)).ok()?;
+            Some(find_port(&config, key).unwrap_or(*default_port))
+        })
+}
+
+/// The port the proxy may forward to. The proxy reaches anything on localhost, so
+/// only the port of an installed map plugin is allowed, never one of the core's
+/// or of an instance.
+pub fn allowed_port(
+    configured: Option<u16>,
+    detected: Option<u16>,
+    taken: &[u32],
+) -> Result<u16, Error> {
+    let port = detected.ok_or_else(|| Error {
+        kind: ErrorKind::NotFound,
+        source: eyre!("No web map plugin found"),
+    })?;
+    if configured.map_or(false, |configured| configured != port) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("The web map port must be the map plugin's port {}", port),
+        });
+    }
+    // 16662 is the core's own api
+    if port as u32 == 16_662 || taken.contains(&(port as u32)) {
+        return Err(Error {
+            kind: ErrorKind::BadRequest,
+            source: eyre!("Port {} belongs to the core or an instance", port),
+        });
+    }
+    Ok(port)
+}
+
+#[test]
+fn test_allowed_port() {
+    assert_eq!(allowed_port(None, Some(8123), &[25565]).unwrap(), 8123);
+    assert_eq!(allowed_port(Some(8123), Some(8123), &[]).unwrap(), 8123);
+    assert!(matches!(
+        allowed_port(Some(5432), Some(8123), &[]).unwrap_err().kind,
+        ErrorKind::BadRequest
+    ));
+    assert!(allowed_port(Some(8123), None, &[]).is_err());
+    assert!(allowed_port(None, Some(25565), &[25565]).is_err());
+    assert!(allowed_port(None, Some(16662), &[]).is_err());
+}
+
+#[test]
+fn test_detect_web_map_port() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    assert_eq!(detect_web_map_port(temp_dir.path()), None);
+
+    let bluemap = temp_dir.path().join("plugins/BlueMap");
+    std::fs::create_dir_all(&bluemap).unwrap();
+    std::fs::write(
+        bluemap.join("webserver.conf"),
+        "# The port\n# port: 1\nenabled: true\nport: 8200\n",
+    )
+    .unwrap();
+    assert_eq!(detect_web_map_port(temp_dir.path()), Some(8200));
+
+    let dynmap = temp_dir.path().join("plugins/dynmap");
+    std::fs::create_dir_all(&dynmap).unwrap();
+    std::fs::write(
+        dynmap.join("configuration.txt"),
+        "webserver-bindaddress: 0.0.0.0\n",
+    )
+    .unwrap();
+    assert_eq!(detect_web_map_port(temp_dir.path()), Some(8123));
+}
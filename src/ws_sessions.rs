@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Receiver;
+use tokio::time::Instant;
+use ts_rs::TS;
+
+use crate::auth::user_id::UserId;
+use crate::error::{Error, ErrorKind};
+use crate::events::Event;
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct WebsocketSettings {
+    /// seconds between pings sent to clients
+    pub heartbeat_interval_secs: u64,
+    /// seconds without hearing from a client before its connection is closed
+    pub idle_timeout_secs: u64,
+    /// seconds a dropped stream is kept around for its client to resume it
+    pub resume_window_secs: u64,
+}
+
+impl Default for WebsocketSettings {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: 30,
+            idle_timeout_secs: 90,
+            resume_window_secs: 120,
+        }
+    }
+}
+
+impl WebsocketSettings {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.heartbeat_interval_secs < 5 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Heartbeat interval must be at least 5 seconds"),
+            });
+        }
+        if self.idle_timeout_secs <= self.heartbeat_interval_secs {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Idle timeout must be longer than the heartbeat interval"),
+            });
+        }
+        if self.resume_window_secs > 3600 {
+            return Err(Error {
+                kind: ErrorKind::BadRequest,
+                source: eyre!("Resume window must be at most an hour"),
+            });
+        }
+        Ok(())
+    }
+}
+
+struct DetachedStream {
+    uid: UserId,
+    receiver: Receiver<Event>,
+    detached_at: Instant,
+}
+
+/// Event receivers of websockets that dropped, kept so a client reconnecting
+/// within the resume window gets the events it missed in the meantime
+#[derive(Default)]
+pub struct WsSessions {
+    detached: HashMap<String, DetachedStream>,
+}
+
+impl WsSessions {
+    fn prune(&mut self, window: Duration) {
+        self.detached
+            .retain(|_, stream| stream.detached_at.elapsed() < window);
+    }
+
+    pub fn detach(
+        &mut self,
+        session_id: String,
+        uid: UserId,
+        receiver: Receiver<Event>,
+        window: Duration,
+    ) {
+        self.prune(window);
+        if window.is_zero() {
+            return;
+        }
+        self.detached.insert(
+            session_id,
+            DetachedStream {
+                uid,
+                receiver,
+                detached_at: Instant::now(),
+            },
+        );
+    }
+
+    /// Takes back the receiver of a dropped stream, only for the user it belonged to
+    pub fn resume(
+        &mut self,
+        session_id: &str,
+        uid: &UserId,
+        window: Duration,
+    ) -> Option<Receiver<Event>> {
+        self.prune(window);
+        if self.detached.get(session_id)?.uid != *uid {
+            return None;
+        }
+        self.detached
+            .remove(session_id)
+            .map(|stream| stream.receiver)
+    }
+}
+
+#[test]
+fn test_resume() {
+    let (tx, rx) = tokio::sync::broadcast::channel::<Event>(16);
+    let window = Duration::from_secs(60);
+    let mut sessions = WsSessions::default();
+    let owner = UserId::from("owner".to_string());
+    sessions.detach("abc".to_string(), owner.clone(), rx, window);
+    assert!(sessions
+        .resume("abc", &UserId::from("other".to_string()), window)
+        .is_none());
+    assert!(sessions.resume("abc", &owner, window).is_some());
+    assert!(sessions.resume("abc", &owner, window).is_none());
+    drop(tx);
+}